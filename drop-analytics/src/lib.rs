@@ -4,6 +4,7 @@ mod moose_impl;
 #[cfg(feature = "moose_file")]
 mod file_impl;
 mod mock_impl;
+mod sampled_impl;
 
 use std::sync::{Arc, Mutex, Weak};
 
@@ -161,3 +162,10 @@ pub fn init_moose(
 pub fn moose_mock() -> Arc<dyn Moose> {
     Arc::new(mock_impl::MockImpl)
 }
+
+/// Wraps the given implementation so that only the `rate` fraction of the
+/// transfers report the per file events. The sampling is deterministic per
+/// transfer. All of the other events are always passed through
+pub fn sampled(inner: Arc<dyn Moose>, rate: f64) -> Arc<dyn Moose> {
+    Arc::new(sampled_impl::SampledImpl::new(inner, rate))
+}
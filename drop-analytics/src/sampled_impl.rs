@@ -0,0 +1,97 @@
+use std::sync::Arc;
+
+/// Forwards the events to the inner implementation, dropping the high-volume
+/// file events of the transfers which are not sampled in
+pub struct SampledImpl {
+    inner: Arc<dyn super::Moose>,
+    rate: f64,
+}
+
+impl SampledImpl {
+    pub fn new(inner: Arc<dyn super::Moose>, rate: f64) -> Self {
+        Self { inner, rate }
+    }
+
+    /// The decision depends only on the transfer ID so that all of the events
+    /// of a single transfer are either reported or dropped together
+    fn includes(&self, transfer_id: &str) -> bool {
+        if self.rate >= 1.0 {
+            return true;
+        }
+        if self.rate <= 0.0 || self.rate.is_nan() {
+            return false;
+        }
+
+        (fnv1a(transfer_id.as_bytes()) as f64 / u64::MAX as f64) < self.rate
+    }
+}
+
+// The std hasher is not guaranteed to be stable between releases, hence the
+// custom one
+fn fnv1a(bytes: &[u8]) -> u64 {
+    const OFFSET_BASIS: u64 = 0xcbf29ce484222325;
+    const PRIME: u64 = 0x100000001b3;
+
+    bytes.iter().fold(OFFSET_BASIS, |hash, &byte| {
+        (hash ^ byte as u64).wrapping_mul(PRIME)
+    })
+}
+
+impl super::Moose for SampledImpl {
+    fn event_init(&self, data: crate::InitEventData) {
+        self.inner.event_init(data)
+    }
+
+    fn event_transfer_intent(&self, data: crate::TransferIntentEventData) {
+        self.inner.event_transfer_intent(data)
+    }
+
+    fn event_transfer_state(&self, data: crate::TransferStateEventData) {
+        self.inner.event_transfer_state(data)
+    }
+
+    fn event_transfer_file(&self, data: crate::TransferFileEventData) {
+        if self.includes(&data.transfer_id) {
+            self.inner.event_transfer_file(data)
+        }
+    }
+
+    fn developer_exception(&self, data: crate::DeveloperExceptionEventData) {
+        self.inner.developer_exception(data)
+    }
+
+    fn developer_exception_with_value(&self, data: crate::DeveloperExceptionWithValueEventData) {
+        self.inner.developer_exception_with_value(data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sampling_decision_is_stable() {
+        let ids: Vec<_> = (0..1000)
+            .map(|_| uuid::Uuid::new_v4().to_string())
+            .collect();
+
+        let sampled = SampledImpl::new(crate::moose_mock(), 0.3);
+        let first: Vec<_> = ids.iter().map(|id| sampled.includes(id)).collect();
+
+        let sampled = SampledImpl::new(crate::moose_mock(), 0.3);
+        let second: Vec<_> = ids.iter().map(|id| sampled.includes(id)).collect();
+        assert_eq!(first, second);
+
+        let included = first.iter().filter(|inc| **inc).count();
+        assert!((200..400).contains(&included), "included: {included}");
+
+        // Known values, guards against the hash changing between versions
+        assert_eq!(fnv1a(b""), 0xcbf29ce484222325);
+        assert_eq!(fnv1a(b"a"), 0xaf63dc4c8601ec8c);
+
+        let all = SampledImpl::new(crate::moose_mock(), 1.0);
+        let none = SampledImpl::new(crate::moose_mock(), 0.0);
+        assert!(ids.iter().all(|id| all.includes(id)));
+        assert!(ids.iter().all(|id| !none.includes(id)));
+    }
+}
@@ -1,6 +1,10 @@
 pub mod http;
 
 use base64::{engine::general_purpose::STANDARD_NO_PAD as BASE64, Engine};
+use chacha20poly1305::{
+    aead::{Aead, Payload},
+    ChaCha20Poly1305,
+};
 use rand::RngCore;
 
 const AUTH_SCHEME: &str = "drop";
@@ -17,6 +21,10 @@ pub struct Nonce(pub [u8; NONCE_LEN]);
 pub use x25519_dalek::{PublicKey, StaticSecret as SecretKey};
 
 const DOMAIN_STRING: &str = "libdrop-auth";
+const CONTENT_KEY_DOMAIN_STRING: &str = "libdrop-content";
+pub const CONTENT_KEY_LENGTH: usize = 32;
+const CONTENT_NONCE_LENGTH: usize = 12;
+const CONTENT_TAG_LENGTH: usize = 16;
 
 impl Nonce {
     pub fn generate_as_client() -> Self {
@@ -120,6 +128,81 @@ fn create_tag(secret: &SecretKey, pubkey: &PublicKey, nonce: Nonce) -> Option<Ve
     Some(tag)
 }
 
+/// Derives the symmetric key for encrypting the file contents of the given
+/// transfer. Both peers arrive at the same key using their own secret and the
+/// other side's public key
+pub fn derive_content_key(
+    secret: &SecretKey,
+    peer_pubkey: &PublicKey,
+    transfer_id: &[u8],
+) -> Option<[u8; CONTENT_KEY_LENGTH]> {
+    use hmac::{Hmac, Mac};
+    use sha2::Sha256;
+
+    type HmacSha256 = Hmac<Sha256>;
+
+    let shared_secret = secret.diffie_hellman(peer_pubkey);
+
+    let mut hmac = HmacSha256::new_from_slice(shared_secret.as_bytes()).ok()?;
+    hmac.update(CONTENT_KEY_DOMAIN_STRING.as_bytes());
+    hmac.update(transfer_id);
+
+    Some(hmac.finalize().into_bytes().into())
+}
+
+/// Encrypts the file contents with ChaCha20-Poly1305. Each sealed message
+/// carries its own random nonce followed by the ciphertext and the
+/// authentication tag
+pub struct ContentCipher(ChaCha20Poly1305);
+
+impl ContentCipher {
+    /// The number of bytes a sealed message is longer than its plaintext
+    pub const OVERHEAD: usize = CONTENT_NONCE_LENGTH + CONTENT_TAG_LENGTH;
+
+    pub fn new(key: &[u8; CONTENT_KEY_LENGTH]) -> Self {
+        use chacha20poly1305::KeyInit;
+
+        Self(ChaCha20Poly1305::new(key.into()))
+    }
+
+    pub fn seal(&self, aad: &[u8], plaintext: &[u8]) -> Vec<u8> {
+        let mut nonce = [0u8; CONTENT_NONCE_LENGTH];
+        rand::thread_rng().fill_bytes(&mut nonce);
+
+        let ciphertext = self
+            .0
+            .encrypt(
+                &nonce.into(),
+                Payload {
+                    msg: plaintext,
+                    aad,
+                },
+            )
+            .expect("The plaintext should never exceed the cipher limits");
+
+        nonce.into_iter().chain(ciphertext).collect()
+    }
+
+    /// Returns `None` in case the message was tampered with or the associated
+    /// data does not match the one it was sealed with
+    pub fn open(&self, aad: &[u8], sealed: &[u8]) -> Option<Vec<u8>> {
+        if sealed.len() < Self::OVERHEAD {
+            return None;
+        }
+
+        let (nonce, ciphertext) = sealed.split_at(CONTENT_NONCE_LENGTH);
+        self.0
+            .decrypt(
+                nonce.into(),
+                Payload {
+                    msg: ciphertext,
+                    aad,
+                },
+            )
+            .ok()
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -185,4 +268,72 @@ mod tests {
             create_tag(&bob_secret, &alice_public, nonce)
         );
     }
+
+    #[test]
+    fn content_key_derivation() {
+        let alice_public = PublicKey::from(ALICE_PUB_KEY);
+        let alice_secret = SecretKey::from(ALICE_PRIV_KEY);
+
+        let bob_public = PublicKey::from(BOB_PUB_KEY);
+        let bob_secret = SecretKey::from(BOB_PRIV_KEY);
+
+        let charlie_public = PublicKey::from(CHARLIE_PUB_KEY);
+
+        let key = derive_content_key(&alice_secret, &bob_public, b"xfer1");
+        assert_eq!(
+            key,
+            derive_content_key(&bob_secret, &alice_public, b"xfer1")
+        );
+
+        // The key is bound to both the peers and the transfer
+        assert_ne!(
+            key,
+            derive_content_key(&alice_secret, &charlie_public, b"xfer1")
+        );
+        assert_ne!(
+            key,
+            derive_content_key(&alice_secret, &bob_public, b"xfer2")
+        );
+
+        // And is distinct from the authentication tag
+        let mut nonce = [0; NONCE_LEN];
+        nonce[..5].copy_from_slice(b"xfer1");
+        assert_ne!(
+            key.map(Vec::from),
+            create_tag(&alice_secret, &bob_public, Nonce(nonce))
+        );
+    }
+
+    #[test]
+    fn content_encryption() {
+        let cipher = ContentCipher::new(&[7; CONTENT_KEY_LENGTH]);
+
+        let sealed = cipher.seal(b"chunk-1", b"hello");
+        assert_eq!(sealed.len(), 5 + ContentCipher::OVERHEAD);
+        assert_eq!(
+            cipher.open(b"chunk-1", &sealed).as_deref(),
+            Some(&b"hello"[..])
+        );
+
+        // Each message gets a fresh nonce
+        assert_ne!(sealed, cipher.seal(b"chunk-1", b"hello"));
+
+        // Tampered ciphertext, tag and nonce are all rejected
+        for idx in [0, CONTENT_NONCE_LENGTH, sealed.len() - 1] {
+            let mut tampered = sealed.clone();
+            tampered[idx] ^= 1;
+            assert_eq!(cipher.open(b"chunk-1", &tampered), None);
+        }
+
+        // As is the message moved to another position or sealed with another key
+        assert_eq!(cipher.open(b"chunk-2", &sealed), None);
+        assert_eq!(
+            ContentCipher::new(&[8; CONTENT_KEY_LENGTH]).open(b"chunk-1", &sealed),
+            None
+        );
+        assert_eq!(
+            cipher.open(b"chunk-1", &sealed[..ContentCipher::OVERHEAD - 1]),
+            None
+        );
+    }
 }
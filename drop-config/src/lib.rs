@@ -1,4 +1,4 @@
-use std::time::Duration;
+use std::{fmt, time::Duration};
 
 #[derive(Debug, Clone, Default)]
 pub struct Config {
@@ -16,7 +16,84 @@ pub struct DropConfig {
     // If set the checksum events will be emited for every checksum_events_granularity bytes
     // Default value is 256KB.
     pub checksum_events_granularity: u64,
+    // How many bytes are read at once when computing the file checksums. Must be a power of two.
+    // Independent of the checksum events granularity and of the transfer chunk size. Default
+    // value is 256KB.
+    pub checksum_chunk_size: usize,
+    // Progress events are emited every progress_events_granularity bytes, both
+    // for downloads and uploads. Default value is 64KB.
+    pub progress_events_granularity: u64,
+    // At most one progress event per file is emited within this interval, the suppressed ones
+    // are coalesced into the next. Zero disables the coalescing
+    pub progress_events_interval: Duration,
     pub connection_retries: u32,
+    // How long the storage operations wait for a database locked by another connection
+    pub storage_busy_timeout: Duration,
+    // How long the whole connection establishment, including the WS upgrade, may take before
+    // the attempt is abandoned and retried later
+    pub connect_timeout: Duration,
+    // How long the websocket waits for a message to be sent before the connection is dropped
+    pub ws_send_timeout: Duration,
+    // How long the websocket waits for any message from the peer before the connection is
+    // considered dead
+    pub ws_recv_timeout: Duration,
+    // Truncate the received file names that are too long for the filesystem instead of failing
+    // the download
+    pub truncate_long_filenames: bool,
+    // How often the websocket pings are sent to the peer
+    pub ping_interval: Duration,
+    // Fraction of the ping interval by which each ping is randomly delayed or advanced so that
+    // the pings of simultaneous transfers do not align. Default value is 0.1 (±10%).
+    pub ping_jitter: f64,
+    // What to do with the received file names containing illegal or control characters
+    pub filename_sanitization: FilenameSanitization,
+    // Place all of the received files directly in the destination directory, ignoring the
+    // sender's directory structure. This changes the on-disk layout and the reported final paths
+    pub flatten_incoming: bool,
+    // If set, the incoming transfers whose total declared size exceeds this many bytes are
+    // rejected during the handshake, before anything is stored
+    pub max_accept_bytes: Option<u64>,
+    // Which of the addresses the peer's hostname resolves to are used for the connection
+    pub peer_resolution: PeerResolution,
+    // Maximum number of the incoming transfers a single peer can have at once. Any further
+    // transfers from that peer are closed right after the handshake. Zero means no limit
+    pub max_transfers_per_peer: usize,
+    // Allow falling back to the V1 and V2 protocols when connecting to an older peer. These
+    // protocols lack the client authentication
+    pub allow_legacy_protocols: bool,
+    // How many times in a row the upload of a file may be retried without sending a single chunk
+    // before the file is failed. If `None`, the upload is retried indefinitely
+    pub max_file_retries: Option<u32>,
+    // The source of the received files recorded in the platform quarantine metadata. Occurrences
+    // of `{peer}` are replaced with the peer address. If `None`, the peer address is used
+    pub quarantine_origin: Option<String>,
+    // If set, the incoming transfers whose files all stay untouched for this long are rejected
+    // and forgotten. Downloading or rejecting any of the files stops the timer
+    pub incoming_offer_ttl: Option<Duration>,
+    // The device name and the application version advertised to the peers, for display only
+    pub device_name: Option<String>,
+    pub app_version: Option<String>,
+    // Reserve the disk space for the whole file when the download starts, reducing the
+    // fragmentation and running out of space upfront. Ignored where not supported
+    pub preallocate_downloads: bool,
+    // What to do when a file with the same name already exists in the destination directory
+    pub existing_file_policy: ExistingFilePolicy,
+    // The received files whose subpath has more components than this fail to download
+    pub max_path_components: usize,
+    // What to do when the file being uploaded gets modified before it is fully sent
+    pub on_source_modified: SourceModifiedPolicy,
+    // Download again the completed files of the resumed transfers that no longer exist at their
+    // final location. The peer might refuse to send them once more
+    pub redownload_missing: bool,
+    // Attach the checksums of the files cached from the earlier transfers to the outgoing transfer
+    // request, so that the receiver can verify these files without asking for the checksums first
+    pub always_send_checksums: bool,
+    // How much of the sensitive data, e.g. the file paths or the peer addresses, is masked in
+    // the logs
+    pub log_redaction: LogRedaction,
+    // Encrypt the contents of the sent files with the key derived from the peers' keys, for the
+    // peers supporting it. Others receive the files unencrypted
+    pub encrypt_contents: bool,
 }
 
 impl Default for DropConfig {
@@ -27,11 +104,225 @@ impl Default for DropConfig {
             storage_path: "libdrop.sqlite".to_string(),
             checksum_events_size_threshold: None,
             checksum_events_granularity: 256 * 1024,
+            checksum_chunk_size: CHECKSUM_CHUNK_SIZE,
+            progress_events_granularity: 64 * 1024,
+            progress_events_interval: Duration::ZERO,
             connection_retries: 5,
+            storage_busy_timeout: STORAGE_BUSY_TIMEOUT,
+            connect_timeout: CONNECT_TIMEOUT,
+            ws_send_timeout: WS_SEND_TIMEOUT,
+            ws_recv_timeout: TRANFER_IDLE_LIFETIME,
+            truncate_long_filenames: false,
+            ping_interval: PING_INTERVAL,
+            ping_jitter: PING_JITTER,
+            filename_sanitization: FilenameSanitization::default(),
+            flatten_incoming: false,
+            max_accept_bytes: None,
+            peer_resolution: PeerResolution::default(),
+            max_transfers_per_peer: 0,
+            allow_legacy_protocols: false,
+            max_file_retries: None,
+            quarantine_origin: None,
+            incoming_offer_ttl: None,
+            device_name: None,
+            app_version: None,
+            preallocate_downloads: false,
+            existing_file_policy: ExistingFilePolicy::default(),
+            max_path_components: MAX_PATH_COMPONENTS,
+            on_source_modified: SourceModifiedPolicy::default(),
+            redownload_missing: false,
+            always_send_checksums: false,
+            log_redaction: LogRedaction::default(),
+            encrypt_contents: false,
         }
     }
 }
 
+impl DropConfig {
+    /// Rejects the settings that make no sense and clamps the ones that would
+    /// stall or degrade the transfers to the nearest working value. Returns
+    /// the description of every adjustment made, so the caller can warn about
+    /// them
+    pub fn validate(&mut self) -> Result<Vec<String>, ConfigError> {
+        if self.progress_events_granularity == 0 {
+            return Err(ConfigError::ZeroProgressGranularity);
+        }
+
+        if !self.checksum_chunk_size.is_power_of_two() {
+            return Err(ConfigError::ChecksumChunkNotPowerOfTwo);
+        }
+
+        if self.ws_send_timeout.is_zero() || self.ws_recv_timeout.is_zero() {
+            return Err(ConfigError::ZeroWsTimeout);
+        }
+
+        if self.connect_timeout.is_zero() {
+            return Err(ConfigError::ZeroConnectTimeout);
+        }
+
+        if self.ping_interval.is_zero() {
+            return Err(ConfigError::ZeroPingInterval);
+        }
+
+        if self.max_accept_bytes == Some(0) {
+            return Err(ConfigError::ZeroMaxAcceptBytes);
+        }
+
+        if self.incoming_offer_ttl == Some(Duration::ZERO) {
+            return Err(ConfigError::ZeroOfferTtl);
+        }
+
+        if self.max_path_components == 0 {
+            return Err(ConfigError::ZeroPathComponents);
+        }
+
+        let mut clamped = Vec::new();
+
+        // Zero granularity would announce the same offset forever
+        if self.checksum_events_granularity == 0 {
+            clamped.push(format!(
+                "Checksum events granularity cannot be zero, using the checksum chunk size of {} \
+                 bytes",
+                self.checksum_chunk_size
+            ));
+            self.checksum_events_granularity = self.checksum_chunk_size as _;
+        }
+
+        // There is no point in reading more at once than the biggest transfer accepted
+        if let Some(max) = self.max_accept_bytes {
+            let max = usize::try_from(max).unwrap_or(usize::MAX);
+
+            if self.checksum_chunk_size > max {
+                let chunk = max.checked_next_power_of_two().unwrap_or(max);
+                let chunk = if chunk > max { chunk / 2 } else { chunk };
+
+                clamped.push(format!(
+                    "Checksum chunk size of {} bytes exceeds the max accepted bytes of {max}, \
+                     using {chunk} bytes",
+                    self.checksum_chunk_size
+                ));
+                self.checksum_chunk_size = chunk;
+            }
+        }
+
+        if !(0.0..=MAX_PING_JITTER).contains(&self.ping_jitter) {
+            let jitter = if self.ping_jitter.is_nan() {
+                PING_JITTER
+            } else {
+                self.ping_jitter.clamp(0.0, MAX_PING_JITTER)
+            };
+
+            clamped.push(format!(
+                "Ping jitter of {} is out of the 0..={MAX_PING_JITTER} range, using {jitter}",
+                self.ping_jitter
+            ));
+            self.ping_jitter = jitter;
+        }
+
+        // A ping must be sent well before the peer is considered dead, otherwise the idle
+        // connections are dropped and retried over and over
+        let latest_ping = self.ping_interval.mul_f64(1.0 + self.ping_jitter);
+        if latest_ping >= self.ws_recv_timeout {
+            let interval = self.ws_recv_timeout.div_f64(2.0 * (1.0 + self.ping_jitter));
+
+            clamped.push(format!(
+                "Ping interval of {:?} would let the connection idle out after {:?}, using {:?}",
+                self.ping_interval, self.ws_recv_timeout, interval
+            ));
+            self.ping_interval = interval;
+        }
+
+        Ok(clamped)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConfigError {
+    ZeroProgressGranularity,
+    ChecksumChunkNotPowerOfTwo,
+    ZeroWsTimeout,
+    ZeroConnectTimeout,
+    ZeroPingInterval,
+    ZeroMaxAcceptBytes,
+    ZeroOfferTtl,
+    ZeroPathComponents,
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let msg = match self {
+            Self::ZeroProgressGranularity => "Progress events granularity cannot be zero",
+            Self::ChecksumChunkNotPowerOfTwo => {
+                "Checksum chunk size must be a positive power of two"
+            }
+            Self::ZeroWsTimeout => "Websocket timeouts must be positive",
+            Self::ZeroConnectTimeout => "Connect timeout must be positive",
+            Self::ZeroPingInterval => "Ping interval must be positive",
+            Self::ZeroMaxAcceptBytes => "Max accepted bytes cannot be zero",
+            Self::ZeroOfferTtl => "Incoming offer TTL must be positive",
+            Self::ZeroPathComponents => "Max path components cannot be zero",
+        };
+
+        f.write_str(msg)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum PeerResolution {
+    // Connect to the first IPv4 address, falling back to IPv6 if there is none
+    PreferIpv4,
+    // Connect to the first IPv6 address, falling back to IPv4 if there is none
+    PreferIpv6,
+    // Race the connections to all of the addresses, alternating the address families (RFC 8305)
+    #[default]
+    HappyEyeballs,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum FilenameSanitization {
+    // Fail the download of the file
+    Reject,
+    // Replace the offending characters with a placeholder and continue
+    #[default]
+    Sanitize,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ExistingFilePolicy {
+    // Save the file under the first free name, e.g. `file(1).txt`
+    #[default]
+    Rename,
+    // Replace the existing file
+    Overwrite,
+    // Keep the existing file and report it as the downloaded one
+    Skip,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SourceModifiedPolicy {
+    // Fail the file
+    #[default]
+    Fail,
+    // Send the file again. The receiver keeps only the part of the file that still matches the
+    // source. The peers before protocol v11 request it only after reconnecting, so the connection
+    // is dropped for them. Limited by `max_file_retries`
+    Restart,
+    // Send a copy of the file taken into a temporary location when the upload starts
+    SnapshotAtStart,
+}
+
+// The levels are ordered, each one masks everything the previous one does
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LogRedaction {
+    // Log as is. The release builds mask the file paths regardless
+    #[default]
+    None,
+    // Mask the file paths, names and ids
+    Paths,
+    // Additionally replace the peer addresses with their salted hashes
+    PathsAndPeers,
+}
+
 #[derive(Debug, Clone, Default)]
 pub struct MooseConfig {
     pub event_path: String,
@@ -41,7 +332,177 @@ pub struct MooseConfig {
 pub const PORT: u16 = 49111;
 pub const TRANFER_IDLE_LIFETIME: Duration = Duration::new(60, 0);
 pub const PING_INTERVAL: Duration = Duration::new(30, 0);
+pub const PING_JITTER: f64 = 0.1;
+pub const MAX_PING_JITTER: f64 = 0.5;
 pub const MAX_UPLOADS_IN_FLIGHT: usize = 4;
 pub const MAX_REQUESTS_PER_SEC: u32 = 50;
 pub const WS_SEND_TIMEOUT: Duration = Duration::new(20, 0);
+pub const CONNECT_TIMEOUT: Duration = Duration::new(30, 0);
 pub const FIRST_RETRY_AFTER: Duration = Duration::new(1, 0);
+pub const STORAGE_BUSY_TIMEOUT: Duration = Duration::new(5, 0);
+pub const CHECKSUM_CHUNK_SIZE: usize = 256 * 1024;
+pub const MAX_PATH_COMPONENTS: usize = 64;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_is_valid() {
+        let mut config = DropConfig::default();
+        assert!(config
+            .validate()
+            .expect("Default config is invalid")
+            .is_empty());
+    }
+
+    #[test]
+    fn rejects_nonsense() {
+        let cases = [
+            (
+                DropConfig {
+                    progress_events_granularity: 0,
+                    ..Default::default()
+                },
+                ConfigError::ZeroProgressGranularity,
+            ),
+            (
+                DropConfig {
+                    checksum_chunk_size: 1000,
+                    ..Default::default()
+                },
+                ConfigError::ChecksumChunkNotPowerOfTwo,
+            ),
+            (
+                DropConfig {
+                    checksum_chunk_size: 0,
+                    ..Default::default()
+                },
+                ConfigError::ChecksumChunkNotPowerOfTwo,
+            ),
+            (
+                DropConfig {
+                    ws_send_timeout: Duration::ZERO,
+                    ..Default::default()
+                },
+                ConfigError::ZeroWsTimeout,
+            ),
+            (
+                DropConfig {
+                    ws_recv_timeout: Duration::ZERO,
+                    ..Default::default()
+                },
+                ConfigError::ZeroWsTimeout,
+            ),
+            (
+                DropConfig {
+                    connect_timeout: Duration::ZERO,
+                    ..Default::default()
+                },
+                ConfigError::ZeroConnectTimeout,
+            ),
+            (
+                DropConfig {
+                    ping_interval: Duration::ZERO,
+                    ..Default::default()
+                },
+                ConfigError::ZeroPingInterval,
+            ),
+            (
+                DropConfig {
+                    max_accept_bytes: Some(0),
+                    ..Default::default()
+                },
+                ConfigError::ZeroMaxAcceptBytes,
+            ),
+            (
+                DropConfig {
+                    incoming_offer_ttl: Some(Duration::ZERO),
+                    ..Default::default()
+                },
+                ConfigError::ZeroOfferTtl,
+            ),
+            (
+                DropConfig {
+                    max_path_components: 0,
+                    ..Default::default()
+                },
+                ConfigError::ZeroPathComponents,
+            ),
+        ];
+
+        for (mut config, expected) in cases {
+            assert_eq!(config.validate(), Err(expected));
+        }
+    }
+
+    #[test]
+    fn clamps_checksum_events_granularity() {
+        let mut config = DropConfig {
+            checksum_events_granularity: 0,
+            ..Default::default()
+        };
+
+        assert_eq!(config.validate().unwrap().len(), 1);
+        assert_eq!(
+            config.checksum_events_granularity,
+            config.checksum_chunk_size as u64
+        );
+    }
+
+    #[test]
+    fn clamps_checksum_chunk_to_max_accept_bytes() {
+        let mut config = DropConfig {
+            max_accept_bytes: Some(100_000),
+            ..Default::default()
+        };
+
+        assert_eq!(config.validate().unwrap().len(), 1);
+        assert_eq!(config.checksum_chunk_size, 64 * 1024);
+
+        let mut config = DropConfig {
+            max_accept_bytes: Some(64 * 1024),
+            ..Default::default()
+        };
+
+        assert_eq!(config.validate().unwrap().len(), 1);
+        assert_eq!(config.checksum_chunk_size, 64 * 1024);
+
+        let mut config = DropConfig {
+            max_accept_bytes: Some(CHECKSUM_CHUNK_SIZE as u64),
+            ..Default::default()
+        };
+
+        assert!(config.validate().unwrap().is_empty());
+        assert_eq!(config.checksum_chunk_size, CHECKSUM_CHUNK_SIZE);
+    }
+
+    #[test]
+    fn clamps_ping_jitter() {
+        for (jitter, expected) in [(-0.5, 0.0), (2.0, MAX_PING_JITTER), (f64::NAN, PING_JITTER)] {
+            let mut config = DropConfig {
+                ping_jitter: jitter,
+                ..Default::default()
+            };
+
+            assert_eq!(config.validate().unwrap().len(), 1);
+            assert_eq!(config.ping_jitter, expected);
+        }
+    }
+
+    #[test]
+    fn clamps_ping_interval_to_recv_timeout() {
+        let mut config = DropConfig {
+            ping_interval: Duration::from_secs(120),
+            ws_recv_timeout: Duration::from_secs(60),
+            ping_jitter: 0.0,
+            ..Default::default()
+        };
+
+        assert_eq!(config.validate().unwrap().len(), 1);
+        assert_eq!(config.ping_interval, Duration::from_secs(30));
+
+        // Already clamped
+        assert!(config.validate().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,147 @@
+use std::{fs, io, path::Path};
+
+/// Renames the file, falling back to copy and remove in case the destination
+/// lies on a different filesystem. A partially copied destination is removed
+/// when copying fails
+pub fn move_file(src: &Path, dst: &Path) -> io::Result<()> {
+    move_file_with(src, dst, |src, dst| fs::rename(src, dst))
+}
+
+/// Moves the file onto the destination, replacing the existing one. The
+/// destination is left untouched when the move fails
+pub fn replace_file(src: &Path, dst: &Path) -> io::Result<()> {
+    replace_file_with(src, dst, |src, dst| fs::rename(src, dst))
+}
+
+/// Whether the rename failed because the destination lies on a different
+/// filesystem than the source
+#[cfg(unix)]
+pub fn crosses_devices(err: &io::Error) -> bool {
+    err.raw_os_error() == Some(libc::EXDEV)
+}
+
+/// Whether the rename failed because the destination lies on a different
+/// filesystem than the source
+#[cfg(windows)]
+pub fn crosses_devices(err: &io::Error) -> bool {
+    use winapi::shared::winerror::ERROR_NOT_SAME_DEVICE;
+
+    err.raw_os_error() == Some(ERROR_NOT_SAME_DEVICE as i32)
+}
+
+fn move_file_with(
+    src: &Path,
+    dst: &Path,
+    rename: impl FnOnce(&Path, &Path) -> io::Result<()>,
+) -> io::Result<()> {
+    match rename(src, dst) {
+        Err(err) if crosses_devices(&err) => {
+            if let Err(err) = fs::copy(src, dst) {
+                let _ = fs::remove_file(dst);
+                return Err(err);
+            }
+            fs::remove_file(src)
+        }
+        res => res,
+    }
+}
+
+fn replace_file_with(
+    src: &Path,
+    dst: &Path,
+    rename: impl FnOnce(&Path, &Path) -> io::Result<()>,
+) -> io::Result<()> {
+    match rename(src, dst) {
+        Err(err) if crosses_devices(&err) => {
+            // The copy is staged next to the destination so that it replaces
+            // the existing file with a single rename
+            let mut name = dst.file_name().unwrap_or_default().to_os_string();
+            name.push(".dropdl-replace");
+            let staged = dst.with_file_name(name);
+
+            let mut file = fs::OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(&staged)?;
+            let res = fs::File::open(src)
+                .and_then(|mut src| io::copy(&mut src, &mut file))
+                .and_then(|_| fs::rename(&staged, dst));
+            drop(file);
+
+            if let Err(err) = res {
+                let _ = fs::remove_file(&staged);
+                return Err(err);
+            }
+            fs::remove_file(src)
+        }
+        res => res,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cross_device(_: &Path, _: &Path) -> io::Result<()> {
+        #[cfg(unix)]
+        return Err(io::Error::from_raw_os_error(libc::EXDEV));
+        #[cfg(windows)]
+        return Err(io::Error::from_raw_os_error(
+            winapi::shared::winerror::ERROR_NOT_SAME_DEVICE as i32,
+        ));
+    }
+
+    #[test]
+    fn cross_device_errors() {
+        assert!(crosses_devices(
+            &cross_device(Path::new(""), Path::new("")).unwrap_err()
+        ));
+        assert!(!crosses_devices(&io::Error::from(io::ErrorKind::NotFound)));
+    }
+
+    #[test]
+    fn moving_file_across_devices() {
+        let dir = tempfile::tempdir().unwrap();
+        let src = dir.path().join("src");
+        let dst = dir.path().join("dst");
+        fs::write(&src, b"abc").unwrap();
+
+        // Copied over and the source removed when the rename cannot be done
+        move_file_with(&src, &dst, cross_device).unwrap();
+        assert_eq!(fs::read(&dst).unwrap(), b"abc");
+        assert!(!src.exists());
+
+        // Other failures are reported as they are
+        let err = move_file_with(&dst, &src, |_, _| {
+            Err(io::Error::from(io::ErrorKind::PermissionDenied))
+        })
+        .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+        assert!(dst.exists());
+
+        // Nothing is left behind when copying fails
+        let missing = dir.path().join("missing");
+        move_file_with(&missing, &src, cross_device).unwrap_err();
+        assert!(!src.exists());
+    }
+
+    #[test]
+    fn replacing_file_across_devices() {
+        let dir = tempfile::tempdir().unwrap();
+        let src = dir.path().join("src");
+        let dst = dir.path().join("dst");
+        fs::write(&src, b"abc").unwrap();
+        fs::write(&dst, b"existing").unwrap();
+
+        replace_file_with(&src, &dst, cross_device).unwrap();
+        assert_eq!(fs::read(&dst).unwrap(), b"abc");
+        assert!(!src.exists());
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+
+        // The existing file stays intact when copying fails
+        let missing = dir.path().join("missing");
+        replace_file_with(&missing, &dst, cross_device).unwrap_err();
+        assert_eq!(fs::read(&dst).unwrap(), b"abc");
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+}
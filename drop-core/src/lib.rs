@@ -1,3 +1,4 @@
+pub mod fs;
 mod status;
 
 pub use status::Status;
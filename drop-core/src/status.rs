@@ -25,6 +25,9 @@ pub enum Status {
     ConnectionClosedByPeer = 38,
     TooManyRequests = 39,
     PermissionDenied = 40,
+    InsufficientStorage = 41,
+    PeerRejectedSize = 42,
+    RetriesExceeded = 43,
 }
 
 impl serde::Serialize for Status {
@@ -65,6 +68,9 @@ impl From<u32> for Status {
             38 => ConnectionClosedByPeer,
             39 => TooManyRequests,
             40 => PermissionDenied,
+            41 => InsufficientStorage,
+            42 => PeerRejectedSize,
+            43 => RetriesExceeded,
             _unknown => IoError, /* Use IO error because we have no clue what it is. This
                                   * shouldn't happen */
         }
@@ -0,0 +1,10 @@
+-- Add migration script here
+
+CREATE TABLE IF NOT EXISTS outgoing_checksums (
+  path TEXT NOT NULL, -- file path or content URI
+  limit_bytes INTEGER NOT NULL,
+  size INTEGER NOT NULL,
+  mtime INTEGER NOT NULL, -- milliseconds since UNIX epoch
+  checksum BLOB NOT NULL,
+  PRIMARY KEY(path, limit_bytes)
+);
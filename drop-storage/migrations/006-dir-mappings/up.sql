@@ -0,0 +1,9 @@
+-- Add migration script here
+
+CREATE TABLE IF NOT EXISTS incoming_dir_mappings (
+  transfer_id TEXT NOT NULL,
+  path TEXT NOT NULL, -- destination directory joined with the first subpath component
+  mapped_name TEXT NOT NULL,
+  PRIMARY KEY(transfer_id, path),
+  FOREIGN KEY(transfer_id) REFERENCES transfers(id) ON DELETE CASCADE ON UPDATE CASCADE
+);
@@ -0,0 +1,11 @@
+-- Add migration script here
+
+-- Bytes exchanged with the peer, accumulated once per file when it reaches the terminal state.
+-- Not tied to the transfers so that it survives purging them
+CREATE TABLE IF NOT EXISTS peer_usage (
+  peer TEXT PRIMARY KEY NOT NULL,
+  bytes_sent INTEGER NOT NULL DEFAULT 0,
+  bytes_received INTEGER NOT NULL DEFAULT 0,
+  updated_at TIMESTAMP NOT NULL DEFAULT(STRFTIME('%Y-%m-%d %H:%M:%f', 'NOW')),
+  CHECK(bytes_sent >= 0 AND bytes_received >= 0)
+);
@@ -0,0 +1,23 @@
+-- Add migration script here
+
+-- Insertion sequence of the transfers. Unlike created_at it does not depend on the device clock,
+-- so the transfers keep their order even when the clock jumps backward
+ALTER TABLE transfers ADD COLUMN seq INTEGER NOT NULL DEFAULT 0;
+
+-- The existing transfers are numbered in a single pass in the order of their creation
+CREATE TEMP TABLE transfer_seq_backfill (
+  transfer_rowid INTEGER PRIMARY KEY,
+  seq INTEGER NOT NULL
+);
+
+INSERT INTO transfer_seq_backfill (transfer_rowid, seq)
+  SELECT rowid, ROW_NUMBER() OVER (ORDER BY created_at, rowid) FROM transfers;
+
+UPDATE transfers SET seq = (
+  SELECT seq FROM transfer_seq_backfill WHERE transfer_rowid = transfers.rowid
+);
+
+DROP TABLE transfer_seq_backfill;
+
+-- The next sequence number is taken as MAX(seq) + 1, it must never repeat
+CREATE UNIQUE INDEX IF NOT EXISTS transfers_seq_idx ON transfers (seq);
@@ -0,0 +1,4 @@
+-- Add migration script here
+
+-- Whether the file was saved under a different name than requested because of a name collision
+ALTER TABLE incoming_path_completed_states ADD COLUMN was_renamed INTEGER NOT NULL DEFAULT FALSE CHECK (was_renamed IN (FALSE, TRUE));
@@ -0,0 +1,4 @@
+-- Add migration script here
+
+-- Why the transfer was canceled: 0 - user, 1 - shutdown, 2 - replaced, 3 - error
+ALTER TABLE transfer_cancel_states ADD COLUMN cause INTEGER NOT NULL DEFAULT 0 CHECK (cause IN (0, 1, 2, 3));
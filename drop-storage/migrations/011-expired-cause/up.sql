@@ -0,0 +1,20 @@
+-- Add migration script here
+
+-- The check constraint cannot be altered in place, the table is recreated to allow the
+-- 4 - expired cause
+CREATE TABLE transfer_cancel_states_new (
+  id INTEGER PRIMARY KEY AUTOINCREMENT,
+  transfer_id TEXT NOT NULL,
+  by_peer INTEGER NOT NULL,
+  created_at TIMESTAMP NOT NULL DEFAULT(STRFTIME('%Y-%m-%d %H:%M:%f', 'NOW')),
+  cause INTEGER NOT NULL DEFAULT 0 CHECK (cause IN (0, 1, 2, 3, 4)),
+  FOREIGN KEY(transfer_id) REFERENCES transfers(id) ON DELETE CASCADE ON UPDATE CASCADE
+  CHECK(by_peer = 0 OR by_peer = 1)
+);
+
+INSERT INTO transfer_cancel_states_new (id, transfer_id, by_peer, created_at, cause)
+SELECT id, transfer_id, by_peer, created_at, cause FROM transfer_cancel_states;
+
+DROP TABLE transfer_cancel_states;
+
+ALTER TABLE transfer_cancel_states_new RENAME TO transfer_cancel_states;
@@ -0,0 +1,12 @@
+-- Add migration script here
+
+-- Arbitrary labels attached by the app, removed along with the transfer once it's hard-deleted
+CREATE TABLE IF NOT EXISTS transfer_tags (
+  transfer_id TEXT NOT NULL,
+  tag TEXT NOT NULL,
+  created_at TIMESTAMP NOT NULL DEFAULT(STRFTIME('%Y-%m-%d %H:%M:%f', 'NOW')),
+  PRIMARY KEY(transfer_id, tag),
+  FOREIGN KEY(transfer_id) REFERENCES transfers(id) ON DELETE CASCADE ON UPDATE CASCADE
+);
+
+CREATE INDEX IF NOT EXISTS transfer_tags_tag ON transfer_tags(tag);
@@ -0,0 +1,4 @@
+-- Add migration script here
+
+-- The throughput cap of the transfer in bytes per second, NULL meaning no cap
+ALTER TABLE transfers ADD COLUMN rate_limit INTEGER DEFAULT NULL CHECK (rate_limit > 0);
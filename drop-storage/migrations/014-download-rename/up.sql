@@ -0,0 +1,4 @@
+-- Add migration script here
+
+-- The filename chosen by the receiver in place of the sender's one, NULL meaning no override
+ALTER TABLE sync_incoming_files_inflight ADD COLUMN rename_to TEXT DEFAULT NULL;
@@ -0,0 +1,14 @@
+-- Add migration script here
+
+-- The checksums of the blocks of the partially downloaded files, recorded as the blocks
+-- get written so that resuming the download does not need to hash them again
+CREATE TABLE IF NOT EXISTS incoming_block_checksums (
+  transfer_id TEXT NOT NULL,
+  path_hash TEXT NOT NULL,
+  block_size INTEGER NOT NULL,
+  block_idx INTEGER NOT NULL,
+  checksum BLOB NOT NULL,
+
+  FOREIGN KEY(transfer_id) REFERENCES transfers(id) ON DELETE CASCADE ON UPDATE CASCADE
+  PRIMARY KEY(transfer_id, path_hash, block_idx)
+);
@@ -0,0 +1,10 @@
+-- Add migration script here
+
+-- Every directory the files were downloaded into. Outlives the transfers so that the temporary
+-- files left behind by the removed transfers can still be found
+CREATE TABLE IF NOT EXISTS temp_base_dirs (
+  base_dir TEXT PRIMARY KEY NOT NULL
+);
+
+INSERT OR IGNORE INTO temp_base_dirs (base_dir)
+SELECT DISTINCT base_dir FROM incoming_path_pending_states;
@@ -0,0 +1,12 @@
+-- Add migration script here
+
+-- The whole transfer rejected with a reason, either locally or by the peer
+CREATE TABLE IF NOT EXISTS transfer_reject_states (
+  id INTEGER PRIMARY KEY AUTOINCREMENT,
+  transfer_id TEXT NOT NULL,
+  by_peer INTEGER NOT NULL,
+  reason TEXT NOT NULL,
+  created_at TIMESTAMP NOT NULL DEFAULT(STRFTIME('%Y-%m-%d %H:%M:%f', 'NOW')),
+  FOREIGN KEY(transfer_id) REFERENCES transfers(id) ON DELETE CASCADE ON UPDATE CASCADE
+  CHECK(by_peer = 0 OR by_peer = 1)
+);
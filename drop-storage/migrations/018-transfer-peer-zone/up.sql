@@ -0,0 +1,5 @@
+-- Add migration script here
+
+-- The zone of the link-local IPv6 peer address as given by the user, either the interface name or
+-- its index. It's resolved anew on each connection since the interface indices change across reboots
+ALTER TABLE transfers ADD COLUMN peer_zone TEXT;
@@ -0,0 +1,4 @@
+-- Add migration script here
+
+-- Whether the post completion hook removed the downloaded file, the final path being where the file was placed
+ALTER TABLE incoming_path_completed_states ADD COLUMN deleted INTEGER NOT NULL DEFAULT FALSE CHECK (deleted IN (FALSE, TRUE));
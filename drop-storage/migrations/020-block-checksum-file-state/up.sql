@@ -0,0 +1,6 @@
+-- Add migration script here
+
+-- The size and modification time of the temporary file recorded when its download stops. The block
+-- checksums are trusted on resume only as long as the file is left exactly as it was
+ALTER TABLE incoming_block_checksums ADD COLUMN file_size INTEGER;
+ALTER TABLE incoming_block_checksums ADD COLUMN file_mtime INTEGER;
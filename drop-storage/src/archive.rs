@@ -0,0 +1,434 @@
+//! The transfer history archive, used for backing up and restoring the
+//! history on another device.
+//!
+//! The archive is a gzip stream starting with the `MAGIC` bytes, the major
+//! and the minor format version, each as a little endian `u32`. It is
+//! followed by sections, each being the name length (`u16`), the name, the
+//! payload length (`u64`) and the payload. The minor version is bumped
+//! whenever the format is extended. Readers skip the unknown sections by their
+//! length as well as the unknown columns of the tables, so that the archives
+//! of any minor version can be imported. The major version is bumped only on
+//! the changes the older readers cannot handle. Only the metadata is archived,
+//! never the contents of the files.
+
+use std::{
+    collections::HashSet,
+    io::{self, Read, Write},
+};
+
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+use rusqlite::{types::Value, Connection};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::{error::Error, types::TransferType, Result};
+
+const MAGIC: &[u8; 4] = b"LDHA";
+const ARCHIVE_MAJOR_VERSION: u32 = 1;
+const ARCHIVE_MINOR_VERSION: u32 = 1;
+
+const TABLE_SECTION_PREFIX: &str = "table/";
+const MANIFEST_SECTION: &str = "manifest";
+
+// The tables making up the transfer history, in the order of the
+// dependencies. The sync tables are left out as the restored transfers are
+// not resumed
+const HISTORY_TABLES: &[&str] = &[
+    "transfers",
+    "transfer_cancel_states",
+    "transfer_failed_states",
+    "transfer_reject_states",
+    "transfer_tags",
+    "outgoing_paths",
+    "incoming_paths",
+    "outgoing_path_started_states",
+    "outgoing_path_paused_states",
+    "outgoing_path_failed_states",
+    "outgoing_path_completed_states",
+    "outgoing_path_reject_states",
+    "incoming_path_pending_states",
+    "incoming_path_started_states",
+    "incoming_path_paused_states",
+    "incoming_path_failed_states",
+    "incoming_path_completed_states",
+    "incoming_path_reject_states",
+];
+
+/// A file referenced by the archived history. The downloaded files removed by
+/// the post completion hook are not listed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ArchiveManifestEntry {
+    pub transfer_id: Uuid,
+    pub file_id: String,
+    pub direction: TransferType,
+    /// The final path of the downloaded file or the URI of the sent one
+    pub location: String,
+    pub size: i64,
+    /// The checksum of the downloaded file, if it was recorded
+    pub checksum: Option<Vec<u8>>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct ManifestRecord {
+    transfer_id: Uuid,
+    file_id: String,
+    outgoing: bool,
+    location: String,
+    size: i64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    checksum: Option<Vec<u8>>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct TableRecord {
+    columns: Vec<String>,
+    rows: Vec<Vec<serde_json::Value>>,
+}
+
+pub(crate) fn export(conn: &Connection, writer: impl Write) -> Result<()> {
+    let mut writer = GzEncoder::new(writer, Compression::default());
+    writer.write_all(MAGIC)?;
+    writer.write_all(&ARCHIVE_MAJOR_VERSION.to_le_bytes())?;
+    writer.write_all(&ARCHIVE_MINOR_VERSION.to_le_bytes())?;
+
+    for table in HISTORY_TABLES {
+        let record = dump_table(conn, table)?;
+        write_section(
+            &mut writer,
+            &format!("{TABLE_SECTION_PREFIX}{table}"),
+            &serde_json::to_vec(&record).map_err(invalid_data)?,
+        )?;
+    }
+
+    let manifest = manifest(conn)?;
+    write_section(
+        &mut writer,
+        MANIFEST_SECTION,
+        &serde_json::to_vec(&manifest).map_err(invalid_data)?,
+    )?;
+
+    writer.finish()?;
+    Ok(())
+}
+
+pub(crate) fn import(conn: &Connection, reader: impl Read) -> Result<Vec<ArchiveManifestEntry>> {
+    let has_history: bool =
+        conn.query_row("SELECT EXISTS(SELECT 1 FROM transfers)", [], |r| r.get(0))?;
+    if has_history {
+        return Err(Error::HistoryNotEmpty);
+    }
+
+    let mut reader = GzDecoder::new(reader);
+
+    let mut header = [0; 12];
+    reader.read_exact(&mut header)?;
+    if &header[..4] != MAGIC {
+        return Err(Error::InvalidArchive("Not a history archive".into()));
+    }
+    let major = u32::from_le_bytes(header[4..8].try_into().expect("Slice of 4 bytes"));
+    let minor = u32::from_le_bytes(header[8..].try_into().expect("Slice of 4 bytes"));
+    if major != ARCHIVE_MAJOR_VERSION {
+        return Err(Error::InvalidArchive(format!(
+            "Unsupported major version {major}"
+        )));
+    }
+    // Any minor version can be read, the newer ones only add sections which
+    // are skipped
+    if minor == 0 {
+        return Err(Error::InvalidArchive(format!(
+            "Unsupported version {major}.{minor}"
+        )));
+    }
+
+    let mut manifest = Vec::new();
+    while let Some((name, len)) = read_section_header(&mut reader)? {
+        // Only the known history tables are restored, the archive cannot
+        // write anywhere else
+        let table = name
+            .strip_prefix(TABLE_SECTION_PREFIX)
+            .and_then(|table| HISTORY_TABLES.iter().find(|known| **known == table));
+
+        if name != MANIFEST_SECTION && table.is_none() {
+            skip_section(&mut reader, &name, len)?;
+            continue;
+        }
+
+        let payload = read_section_payload(&mut reader, &name, len)?;
+
+        if name == MANIFEST_SECTION {
+            let records: Vec<ManifestRecord> =
+                serde_json::from_slice(&payload).map_err(invalid_archive)?;
+            manifest.extend(records.into_iter().map(|r| ArchiveManifestEntry {
+                transfer_id: r.transfer_id,
+                file_id: r.file_id,
+                direction: if r.outgoing {
+                    TransferType::Outgoing
+                } else {
+                    TransferType::Incoming
+                },
+                location: r.location,
+                size: r.size,
+                checksum: r.checksum,
+            }));
+            continue;
+        }
+
+        let Some(table) = table else {
+            continue;
+        };
+
+        let record: TableRecord = serde_json::from_slice(&payload).map_err(invalid_archive)?;
+        restore_table(conn, table, record)?;
+    }
+
+    Ok(manifest)
+}
+
+fn dump_table(conn: &Connection, table: &str) -> Result<TableRecord> {
+    let mut stmt = conn.prepare(&format!("SELECT * FROM {table} ORDER BY rowid"))?;
+    let columns: Vec<String> = stmt.column_names().into_iter().map(Into::into).collect();
+
+    let rows = stmt
+        .query_map([], |r| {
+            (0..columns.len())
+                .map(|i| r.get::<_, Value>(i).map(value_to_json))
+                .collect()
+        })?
+        .collect::<std::result::Result<_, _>>()?;
+
+    Ok(TableRecord { columns, rows })
+}
+
+fn restore_table(conn: &Connection, table: &str, record: TableRecord) -> Result<()> {
+    let known: HashSet<String> = conn
+        .prepare(&format!("PRAGMA table_info({table})"))?
+        .query_map([], |r| r.get("name"))?
+        .collect::<std::result::Result<_, _>>()?;
+
+    // The columns added by the newer schema versions are dropped
+    let kept: Vec<usize> = (0..record.columns.len())
+        .filter(|&i| known.contains(&record.columns[i]))
+        .collect();
+    if kept.is_empty() {
+        return Ok(());
+    }
+
+    let columns: Vec<&str> = kept.iter().map(|&i| record.columns[i].as_str()).collect();
+    let mut stmt = conn.prepare(&format!(
+        "INSERT INTO {table} ({}) VALUES ({})",
+        columns.join(", "),
+        vec!["?"; columns.len()].join(", ")
+    ))?;
+
+    for row in record.rows {
+        let values = kept
+            .iter()
+            .map(|&i| row.get(i).map_or(Ok(Value::Null), json_to_value))
+            .collect::<Result<Vec<_>>>()?;
+
+        stmt.execute(rusqlite::params_from_iter(values))?;
+    }
+
+    Ok(())
+}
+
+fn manifest(conn: &Connection) -> Result<Vec<ManifestRecord>> {
+    let records = conn
+        .prepare(
+            r#"
+            SELECT p.transfer_id, p.path_hash, FALSE AS outgoing, s.final_path AS location,
+                p.bytes, p.checksum
+            FROM incoming_path_completed_states s
+            INNER JOIN incoming_paths p ON p.id = s.path_id
+            WHERE NOT s.deleted
+            UNION ALL
+            SELECT transfer_id, path_hash, TRUE, uri, bytes, NULL
+            FROM outgoing_paths
+            "#,
+        )?
+        .query_map([], |r| {
+            let transfer_id: String = r.get("transfer_id")?;
+
+            Ok(ManifestRecord {
+                transfer_id: Uuid::parse_str(&transfer_id)
+                    .map_err(|_| rusqlite::Error::InvalidQuery)?,
+                file_id: r.get("path_hash")?,
+                outgoing: r.get("outgoing")?,
+                location: r.get("location")?,
+                size: r.get("bytes")?,
+                checksum: r.get("checksum")?,
+            })
+        })?
+        .collect::<std::result::Result<_, _>>()?;
+
+    Ok(records)
+}
+
+fn write_section(writer: &mut impl Write, name: &str, payload: &[u8]) -> io::Result<()> {
+    let name_len = u16::try_from(name.len()).map_err(invalid_data)?;
+
+    writer.write_all(&name_len.to_le_bytes())?;
+    writer.write_all(name.as_bytes())?;
+    writer.write_all(&(payload.len() as u64).to_le_bytes())?;
+    writer.write_all(payload)
+}
+
+// Returns the section name and the payload length, `None` once the stream
+// ends at the section boundary
+fn read_section_header(reader: &mut impl Read) -> Result<Option<(String, u64)>> {
+    let mut name_len = [0; 2];
+    match reader.read_exact(&mut name_len) {
+        Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        res => res?,
+    }
+
+    let mut name = vec![0; u16::from_le_bytes(name_len) as usize];
+    reader.read_exact(&mut name)?;
+    let name = String::from_utf8(name).map_err(invalid_archive)?;
+
+    let mut payload_len = [0; 8];
+    reader.read_exact(&mut payload_len)?;
+
+    Ok(Some((name, u64::from_le_bytes(payload_len))))
+}
+
+fn read_section_payload(reader: &mut impl Read, name: &str, len: u64) -> Result<Vec<u8>> {
+    let mut payload = Vec::new();
+    reader.take(len).read_to_end(&mut payload)?;
+    if payload.len() as u64 != len {
+        return Err(Error::InvalidArchive(format!("Truncated section {name}")));
+    }
+
+    Ok(payload)
+}
+
+// The unknown sections are not loaded into memory
+fn skip_section(reader: &mut impl Read, name: &str, len: u64) -> Result<()> {
+    if io::copy(&mut reader.take(len), &mut io::sink())? != len {
+        return Err(Error::InvalidArchive(format!("Truncated section {name}")));
+    }
+
+    Ok(())
+}
+
+// The blobs are stored as arrays of bytes, all other values map directly
+fn value_to_json(value: Value) -> serde_json::Value {
+    match value {
+        Value::Null => serde_json::Value::Null,
+        Value::Integer(i) => i.into(),
+        Value::Real(f) => f.into(),
+        Value::Text(s) => s.into(),
+        Value::Blob(b) => b.into(),
+    }
+}
+
+fn json_to_value(value: &serde_json::Value) -> Result<Value> {
+    let value = match value {
+        serde_json::Value::Null => Value::Null,
+        serde_json::Value::Bool(b) => Value::Integer(*b as _),
+        serde_json::Value::Number(n) => match n.as_i64() {
+            Some(i) => Value::Integer(i),
+            None => Value::Real(n.as_f64().unwrap_or_default()),
+        },
+        serde_json::Value::String(s) => Value::Text(s.clone()),
+        serde_json::Value::Array(bytes) => Value::Blob(
+            bytes
+                .iter()
+                .map(|b| b.as_u64().and_then(|b| u8::try_from(b).ok()))
+                .collect::<Option<_>>()
+                .ok_or_else(|| Error::InvalidArchive("Malformed blob".into()))?,
+        ),
+        serde_json::Value::Object(_) => {
+            return Err(Error::InvalidArchive("Unexpected object value".into()))
+        }
+    };
+
+    Ok(value)
+}
+
+fn invalid_data(err: impl std::error::Error + Send + Sync + 'static) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, err)
+}
+
+fn invalid_archive(err: impl std::fmt::Display) -> Error {
+    Error::InvalidArchive(err.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        types::{TransferFiles, TransferIncomingPath},
+        Storage, TransferInfo, TransferSort,
+    };
+
+    // Rewrites the archive as if written by another version, with a section
+    // this version does not know about
+    fn versioned_archive(archive: &[u8], major: u32, minor: u32) -> io::Cursor<Vec<u8>> {
+        let mut raw = Vec::new();
+        GzDecoder::new(archive).read_to_end(&mut raw).unwrap();
+        raw[4..8].copy_from_slice(&major.to_le_bytes());
+        raw[8..12].copy_from_slice(&minor.to_le_bytes());
+
+        let mut extra = Vec::new();
+        write_section(&mut extra, "future/section", &[0xff; 1000]).unwrap();
+        raw.splice(12..12, extra);
+
+        let mut writer = GzEncoder::new(Vec::new(), Compression::default());
+        writer.write_all(&raw).unwrap();
+        io::Cursor::new(writer.finish().unwrap())
+    }
+
+    #[tokio::test]
+    async fn importing_newer_archive() {
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+        let storage = Storage::new(logger.clone(), ":memory:").unwrap();
+
+        let id: Uuid = "23e488a4-0521-11ee-be56-0242ac120002".parse().unwrap();
+        storage
+            .insert_transfer(&TransferInfo {
+                id,
+                peer: "1.2.3.4".to_string(),
+                files: TransferFiles::Incoming(vec![TransferIncomingPath {
+                    file_id: "id1".to_string(),
+                    relative_path: "a.txt".to_string(),
+                    size: 1024,
+                }]),
+            })
+            .await;
+        storage
+            .insert_incoming_path_completed_state(id, "id1", "/home/a.txt", false, false)
+            .await;
+
+        let archive = storage.export_archive(Vec::new()).await.unwrap();
+
+        let restored = Storage::new(logger.clone(), ":memory:").unwrap();
+        let manifest = restored
+            .import_archive(versioned_archive(&archive, 1, 2))
+            .await
+            .unwrap();
+        assert_eq!(manifest.len(), 1);
+        assert_eq!(manifest[0].location, "/home/a.txt");
+
+        let transfers = restored.transfers_since(0, TransferSort::default()).await;
+        assert_eq!(transfers.len(), 1);
+        assert_eq!(transfers[0].id, id);
+
+        // There was never a version before the first one
+        let restored = Storage::new(logger.clone(), ":memory:").unwrap();
+        assert!(matches!(
+            restored
+                .import_archive(versioned_archive(&archive, 1, 0))
+                .await,
+            Err(Error::InvalidArchive(_))
+        ));
+
+        // The incompatible format is refused no matter the minor version
+        assert!(matches!(
+            restored
+                .import_archive(versioned_archive(&archive, 2, 1))
+                .await,
+            Err(Error::InvalidArchive(_))
+        ));
+    }
+}
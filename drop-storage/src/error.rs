@@ -10,4 +10,14 @@ pub enum Error {
     InvalidUri(String),
     #[error("IO error: {0}")]
     Io(#[from] std::io::Error),
+    #[error("Database schema version {0} is newer than the latest supported {1}")]
+    SchemaTooNew(i32, i32),
+    #[error("Database is not writable: {0}")]
+    StorageNotWritable(String),
+    #[error("Database already exists at the destination")]
+    DestinationExists,
+    #[error("Transfer history is not empty")]
+    HistoryNotEmpty,
+    #[error("Invalid history archive: {0}")]
+    InvalidArchive(String),
 }
@@ -1,3 +1,4 @@
+mod archive;
 pub mod error;
 pub mod sync;
 pub mod types;
@@ -7,15 +8,17 @@ use std::{
         hash_map::Entry::{Occupied, Vacant},
         HashMap,
     },
-    io,
-    path::Path,
+    fs, io,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, SystemTime},
     vec,
 };
 
 use include_dir::{include_dir, Dir};
-use rusqlite::{params, Connection, OpenFlags, Transaction};
+use rusqlite::{params, Connection, OpenFlags, OptionalExtension, Transaction};
 use rusqlite_migration::Migrations;
-use slog::{debug, error, trace, warn, Logger};
+use slog::{debug, error, info, trace, warn, Logger};
 use tokio::sync::Mutex;
 use types::{
     DbTransferType, FileSyncState, IncomingFileToRetry, IncomingPath, IncomingPathStateEvent,
@@ -26,15 +29,33 @@ use types::{
 use uuid::Uuid;
 
 use crate::error::Error;
-pub use crate::types::{FileChecksum, FinishedIncomingFile, OutgoingTransferToRetry, TransferInfo};
+pub use crate::{
+    archive::ArchiveManifestEntry,
+    types::{
+        CancelCause, CompletedIncomingFile, FileChecksum, FileManifestEntry, FileStateKind,
+        FinishedIncomingFile, ImportedIncoming, IncomingDirMapping, OutgoingTransferToRetry,
+        PeerSummary, PeerUsage, StateEvent, StoredTransferState, TransferFilter, TransferInfo,
+        TransferSort,
+    },
+};
 
 type Result<T> = std::result::Result<T, Error>;
 type QueryResult<T> = std::result::Result<T, rusqlite::Error>;
 
+/// Callback invoked with the operation name whenever a database error is
+/// swallowed by the storage
+pub type ErrorCallback = Arc<dyn Fn(&str, &Error) + Send + Sync>;
+
+/// Wall clock used for the transfer creation timestamps
+pub type Clock = Arc<dyn Fn() -> SystemTime + Send + Sync>;
+
 // SQLite storage wrapper
 pub struct Storage {
-    conn: Mutex<Connection>,
+    // Shared with the blocking tasks working on the database
+    conn: Arc<Mutex<Connection>>,
     logger: Logger,
+    on_error: ErrorCallback,
+    clock: Clock,
 }
 
 const MIGRATIONS_DIR: Dir = include_dir!("$CARGO_MANIFEST_DIR/migrations");
@@ -79,10 +100,151 @@ fn prepare_sqlite_file(_: &str) -> io::Result<OpenFlags> {
     Ok(OpenFlags::default())
 }
 
+// Insertion sequence of the next transfer, independent of the device clock
+// How long the locked database is retried unless set with
+// `Storage::with_busy_timeout()`
+const DEFAULT_BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+const NEXT_TRANSFER_SEQ: &str = "(SELECT IFNULL(MAX(seq), 0) + 1 FROM transfers)";
+
+// Columns of the `transfers t` row used for classifying the transfer state
+const TRANSFER_STATE_COLUMNS: &str = r#"
+    EXISTS(SELECT 1 FROM transfer_cancel_states WHERE transfer_id = t.id)
+        OR EXISTS(SELECT 1 FROM transfer_reject_states WHERE transfer_id = t.id) AS canceled,
+    EXISTS(SELECT 1 FROM transfer_failed_states WHERE transfer_id = t.id) AS failed,
+    NOT EXISTS(
+        SELECT 1 FROM incoming_paths ip
+        WHERE ip.transfer_id = t.id
+            AND ip.id NOT IN(SELECT path_id FROM incoming_path_completed_states)
+            AND ip.id NOT IN(SELECT path_id FROM incoming_path_reject_states)
+            AND ip.id NOT IN(SELECT path_id FROM incoming_path_failed_states)
+    ) AND NOT EXISTS(
+        SELECT 1 FROM outgoing_paths op
+        WHERE op.transfer_id = t.id
+            AND op.id NOT IN(SELECT path_id FROM outgoing_path_completed_states)
+            AND op.id NOT IN(SELECT path_id FROM outgoing_path_reject_states)
+            AND op.id NOT IN(SELECT path_id FROM outgoing_path_failed_states)
+    ) AS completed
+"#;
+
+fn stored_transfer_state_from_row(r: &rusqlite::Row) -> QueryResult<StoredTransferState> {
+    let state = if r.get("canceled")? {
+        StoredTransferState::Canceled
+    } else if r.get("failed")? {
+        StoredTransferState::Failed
+    } else if r.get("completed")? {
+        StoredTransferState::Completed
+    } else {
+        StoredTransferState::InProgress
+    };
+
+    Ok(state)
+}
+
+// The blocking database task is never cancelled, it fails only by panicking
+fn join_error(err: tokio::task::JoinError) -> Error {
+    Error::InternalError(format!("Database task failed: {err}"))
+}
+
+// Formats the timestamp the same way the `created_at` columns store it so the
+// two can be compared as text
+fn format_timestamp_millis(millis: i64) -> String {
+    chrono::DateTime::from_timestamp_millis(millis)
+        .unwrap_or_default()
+        .format("%Y-%m-%d %H:%M:%S%.3f")
+        .to_string()
+}
+
+// rusqlite_migration keeps track of the applied migrations in `user_version`
+fn schema_version(conn: &Connection) -> QueryResult<i32> {
+    conn.query_row("PRAGMA user_version", [], |row| row.get(0))
+}
+
+// Inserts a throwaway transfer and rolls it back. Otherwise a read-only
+// filesystem or a full disk only shows up as a logged error on the first insert
+fn check_writable(conn: &mut Connection) -> Result<()> {
+    let not_writable = |err: rusqlite::Error| Error::StorageNotWritable(err.to_string());
+
+    let tx = conn.transaction().map_err(not_writable)?;
+    tx.execute(
+        "INSERT INTO transfers (id, peer, is_outgoing) VALUES (?1, '', 0)",
+        params![Uuid::new_v4().to_string()],
+    )
+    .map_err(not_writable)?;
+    tx.rollback()?;
+
+    Ok(())
+}
+
+/// Moves the database closed beforehand to a new location along with its
+/// journal files, e.g. `-wal` and `-shm` in the WAL mode. Nothing is moved if
+/// any of the files already exists at the destination. If moving any of the
+/// files fails, the ones already moved are put back
+pub fn migrate_storage(logger: &Logger, from: &str, to: &str) -> Result<()> {
+    const SIDECARS: &[&str] = &["-wal", "-shm", "-journal"];
+
+    if from == ":memory:" || to == ":memory:" {
+        return Err(Error::InternalError(
+            "In-memory database cannot be migrated".into(),
+        ));
+    }
+
+    let files: Vec<(PathBuf, PathBuf)> = std::iter::once("")
+        .chain(SIDECARS.iter().copied())
+        .map(|suffix| {
+            (
+                format!("{from}{suffix}").into(),
+                format!("{to}{suffix}").into(),
+            )
+        })
+        .collect();
+
+    if files.iter().any(|(_, dst)| dst.exists()) {
+        return Err(Error::DestinationExists);
+    }
+    if !Path::new(from).is_file() {
+        return Err(io::Error::from(io::ErrorKind::NotFound).into());
+    }
+    if let Some(parent) = Path::new(to).parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    let mut moved = Vec::new();
+    for (src, dst) in &files {
+        if !src.exists() {
+            continue;
+        }
+
+        if let Err(err) = drop_core::fs::move_file(src, dst) {
+            error!(logger, "Failed to move the database file: {err}");
+
+            for (src, dst) in moved.into_iter().rev() {
+                if let Err(err) = drop_core::fs::move_file(dst, src) {
+                    warn!(logger, "Failed to restore the database file: {err}");
+                }
+            }
+            return Err(err.into());
+        }
+
+        moved.push((src, dst));
+    }
+
+    info!(logger, "Database migrated, {} files moved", moved.len());
+    Ok(())
+}
+
 impl Storage {
     pub fn new(logger: Logger, path: &str) -> Result<Self> {
         let flags = prepare_sqlite_file(path)?;
         let mut conn = Connection::open_with_flags(path, flags)?;
+        conn.busy_timeout(DEFAULT_BUSY_TIMEOUT)?;
+
+        // Downgrades are not supported, the migrations would fail anyway
+        let version = schema_version(&conn)?;
+        let latest = MIGRATIONS_DIR.dirs().count() as i32;
+        if version > latest {
+            return Err(Error::SchemaTooNew(version, latest));
+        }
 
         Migrations::from_directory(&MIGRATIONS_DIR)
             .map_err(|e| {
@@ -91,12 +253,97 @@ impl Storage {
             .to_latest(&mut conn)
             .map_err(|e| Error::InternalError(format!("Failed to run migrations: {e}")))?;
 
+        let version = schema_version(&conn)?;
+        info!(logger, "Database schema version: {version}");
+
+        if path != ":memory:" {
+            check_writable(&mut conn)?;
+        }
+
         Ok(Self {
             logger,
-            conn: Mutex::new(conn),
+            conn: Arc::new(Mutex::new(conn)),
+            on_error: Arc::new(|_, _| ()),
+            clock: Arc::new(SystemTime::now),
         })
     }
 
+    /// Set how long the operations on a database locked by another
+    /// connection are retried before failing with `SQLITE_BUSY`
+    pub fn with_busy_timeout(mut self, timeout: Duration) -> Result<Self> {
+        Arc::get_mut(&mut self.conn)
+            .expect("The connection is not shared before the storage is built")
+            .get_mut()
+            .busy_timeout(timeout)?;
+        Ok(self)
+    }
+
+    /// Set the callback reporting the database errors that are otherwise only
+    /// logged
+    pub fn with_error_callback(
+        mut self,
+        on_error: impl Fn(&str, &Error) + Send + Sync + 'static,
+    ) -> Self {
+        self.on_error = Arc::new(on_error);
+        self
+    }
+
+    /// Set the clock used for the `created_at` timestamp of the new transfers.
+    /// It only affects filtering by timestamp, the transfers are always ordered
+    /// by insertion
+    pub fn with_clock(mut self, clock: impl Fn() -> SystemTime + Send + Sync + 'static) -> Self {
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    fn now(&self) -> String {
+        let millis = match (self.clock)().duration_since(SystemTime::UNIX_EPOCH) {
+            Ok(elapsed) => elapsed.as_millis() as i64,
+            Err(err) => -(err.duration().as_millis() as i64),
+        };
+
+        format_timestamp_millis(millis)
+    }
+
+    /// Runs a set of operations within a single database transaction. The
+    /// transaction is committed when the closure returns `Ok` and rolled back
+    /// otherwise, so concurrent readers never observe a partial result. The
+    /// storage is locked for the whole duration of the closure
+    pub async fn with_transaction<T>(
+        &self,
+        f: impl FnOnce(&StorageTx<'_>) -> Result<T>,
+    ) -> Result<T> {
+        trace!(self.logger, "Running storage transaction");
+
+        let task = async {
+            let mut conn = self.conn.lock().await;
+            let tx = StorageTx {
+                conn: conn.transaction()?,
+                storage: self,
+            };
+
+            let res = f(&tx)?;
+            tx.conn.commit()?;
+
+            Ok(res)
+        };
+
+        let res = task.await;
+        if let Err(e) = &res {
+            error!(self.logger, "Storage transaction rolled back"; "error" => %e);
+            (self.on_error)("with_transaction", e);
+        }
+        res
+    }
+
+    /// Returns the current database schema version, that is the number of
+    /// migrations applied
+    pub async fn schema_version(&self) -> Result<i32> {
+        let conn = self.conn.lock().await;
+        let version = schema_version(&conn)?;
+        Ok(version)
+    }
+
     pub async fn insert_transfer(&self, transfer: &TransferInfo) -> Option<()> {
         let transfer_type_int = match &transfer.files {
             TransferFiles::Incoming(_) => TransferType::Incoming as u32,
@@ -116,9 +363,11 @@ impl Storage {
             let conn = conn.transaction()?;
 
             let inserted = conn.execute(
-                "INSERT INTO transfers (id, peer, is_outgoing) VALUES (?1, ?2, ?3) ON CONFLICT DO \
-                 NOTHING",
-                params![tid, transfer.peer, transfer_type_int],
+                &format!(
+                    "INSERT INTO transfers (id, peer, is_outgoing, created_at, seq) VALUES (?1, \
+                     ?2, ?3, ?4, {NEXT_TRANSFER_SEQ}) ON CONFLICT DO NOTHING"
+                ),
+                params![tid, transfer.peer, transfer_type_int, self.now()],
             )?;
 
             if inserted < 1 {
@@ -134,7 +383,7 @@ impl Storage {
                     );
 
                     for file in files {
-                        Self::insert_incoming_path(&self.logger, &conn, transfer.id, file);
+                        self.insert_incoming_path(&conn, transfer.id, file);
                     }
 
                     true
@@ -147,7 +396,7 @@ impl Storage {
                     );
 
                     for file in files {
-                        Self::insert_outgoing_path(&self.logger, &conn, transfer.id, file);
+                        self.insert_outgoing_path(&conn, transfer.id, file);
                     }
 
                     false
@@ -164,6 +413,7 @@ impl Storage {
         match task.await {
             Err(e) => {
                 error!(self.logger, "Failed to insert transfer"; "error" => %e);
+                (self.on_error)("insert_transfer", &e);
                 // DB error. Let's pretend the transfer was inserted
                 Some(())
             }
@@ -180,6 +430,7 @@ impl Storage {
 
         if let Err(e) = task.await {
             error!(self.logger, "Failed to update transfer sync states"; "error" => %e);
+            (self.on_error)("update_transfer_sync_states", &e);
         }
     }
 
@@ -193,6 +444,7 @@ impl Storage {
             Ok(state) => state,
             Err(e) => {
                 error!(self.logger, "Failed to get transfer sync state"; "error" => %e);
+                (self.on_error)("transfer_sync_state", &e);
                 None
             }
         }
@@ -208,11 +460,78 @@ impl Storage {
             Ok(state) => state,
             Err(e) => {
                 error!(self.logger, "Failed to clear transfer sync state"; "error" => %e);
+                (self.on_error)("transfer_sync_clear", &e);
                 None
             }
         }
     }
 
+    /// Flips the failed files of the transfer back to the resumable state so
+    /// that they can be retried. When the transfer was canceled because of an
+    /// error it becomes active again, the transfers canceled for any other
+    /// reason are left intact. Returns the number of files reset
+    pub async fn reset_failed_files(&self, transfer_id: Uuid) -> usize {
+        let tid = transfer_id.to_string();
+
+        trace!(
+            self.logger,
+            "Resetting failed files";
+            "transfer_id" => &tid,
+        );
+
+        let task = async {
+            let mut conn = self.conn.lock().await;
+            let conn = conn.transaction()?;
+
+            let Some(xfer) = sync::transfer_state(&conn, transfer_id)? else {
+                return Ok(0);
+            };
+
+            if let sync::TransferState::Canceled = xfer.local_state {
+                let cause = conn
+                    .query_row(
+                        r#"
+                        SELECT cause FROM transfer_cancel_states
+                        WHERE transfer_id = ?1
+                        ORDER BY created_at DESC, id DESC
+                        LIMIT 1
+                        "#,
+                        params![tid],
+                        |r| r.get::<_, CancelCause>(0),
+                    )
+                    .optional()?;
+
+                if !matches!(cause, Some(CancelCause::Error)) {
+                    return Ok(0);
+                }
+            }
+
+            let count = sync::reset_failed_files(&conn, transfer_id)?;
+            if count > 0 {
+                if let sync::TransferState::Canceled = xfer.local_state {
+                    sync::transfer_set_local_state(
+                        &conn,
+                        transfer_id,
+                        sync::TransferState::Active,
+                    )?;
+                }
+            }
+
+            conn.commit()?;
+
+            Ok::<_, Error>(count)
+        };
+
+        match task.await {
+            Ok(count) => count,
+            Err(e) => {
+                error!(self.logger, "Failed to reset failed files"; "error" => %e);
+                (self.on_error)("reset_failed_files", &e);
+                0
+            }
+        }
+    }
+
     pub async fn outgoing_file_sync_state(
         &self,
         transfer_id: Uuid,
@@ -282,6 +601,7 @@ impl Storage {
             Ok(state) => state,
             Err(e) => {
                 error!(self.logger, "Failed to get outgoing file sync state"; "error" => %e);
+                (self.on_error)("outgoing_file_sync_state", &e);
                 None
             }
         }
@@ -301,6 +621,34 @@ impl Storage {
 
         if let Err(e) = task.await {
             error!(self.logger, "Failed to update outgoing file sync states"; "error" => %e);
+            (self.on_error)("update_outgoing_file_sync_states", &e);
+        }
+    }
+
+    /// Updates the sync states of multiple outgoing files within a single
+    /// database transaction
+    pub async fn update_outgoing_files_sync_states(
+        &self,
+        transfer_id: Uuid,
+        file_ids: &[&str],
+        local: sync::FileState,
+    ) {
+        let task = async {
+            let mut conn = self.conn.lock().await;
+            let conn = conn.transaction()?;
+
+            for file_id in file_ids {
+                sync::outgoing_file_set_local_state(&conn, transfer_id, file_id, local)?;
+            }
+
+            conn.commit()?;
+
+            Ok::<(), Error>(())
+        };
+
+        if let Err(e) = task.await {
+            error!(self.logger, "Failed to update outgoing files sync states"; "error" => %e);
+            (self.on_error)("update_outgoing_files_sync_states", &e);
         }
     }
 
@@ -372,6 +720,7 @@ impl Storage {
             Ok(state) => state,
             Err(e) => {
                 error!(self.logger, "Failed to get incoming file sync state"; "error" => %e);
+                (self.on_error)("incoming_file_sync_state", &e);
                 None
             }
         }
@@ -391,6 +740,7 @@ impl Storage {
 
         if let Err(e) = task.await {
             error!(self.logger, "Failed to update incoming file sync states"; "error" => %e);
+            (self.on_error)("update_incoming_file_sync_states", &e);
         }
     }
 
@@ -404,29 +754,122 @@ impl Storage {
             Ok(state) => state,
             Err(e) => {
                 error!(self.logger, "Failed to stop incoming file sync state"; "error" => %e);
+                (self.on_error)("stop_incoming_file", &e);
                 None
             }
         }
     }
 
+    /// Terminates and stops multiple incoming files within a single database
+    /// transaction
+    pub async fn stop_incoming_files(&self, transfer_id: Uuid, file_ids: &[&str]) {
+        let task = async {
+            let mut conn = self.conn.lock().await;
+            let conn = conn.transaction()?;
+
+            for file_id in file_ids {
+                sync::incoming_file_set_local_state(
+                    &conn,
+                    transfer_id,
+                    file_id,
+                    sync::FileState::Terminal,
+                )?;
+                sync::stop_incoming_file(&conn, transfer_id, file_id)?;
+            }
+
+            conn.commit()?;
+
+            Result::Ok(())
+        };
+
+        if let Err(e) = task.await {
+            error!(self.logger, "Failed to stop incoming files"; "error" => %e);
+            (self.on_error)("stop_incoming_files", &e);
+        }
+    }
+
     pub async fn start_incoming_file(&self, transfer_id: Uuid, file_id: &str, base_dir: &str) {
+        self.start_incoming_files(transfer_id, &[(file_id, None)], base_dir)
+            .await
+    }
+
+    /// Starts multiple incoming files within a single database transaction.
+    /// Each file comes with the optional name overriding the sender's one
+    pub async fn start_incoming_files(
+        &self,
+        transfer_id: Uuid,
+        files: &[(&str, Option<&str>)],
+        base_dir: &str,
+    ) {
         let task = async {
-            let conn = self.conn.lock().await;
+            let mut conn = self.conn.lock().await;
+            let conn = conn.transaction()?;
 
-            if sync::start_incoming_file(&conn, transfer_id, file_id, base_dir)?.is_some() {
-                Self::insert_incoming_path_pending_state(&conn, transfer_id, file_id, base_dir)?;
+            for &(file_id, rename_to) in files {
+                if sync::start_incoming_file(&conn, transfer_id, file_id, base_dir, rename_to)?
+                    .is_some()
+                {
+                    Self::insert_incoming_path_pending_state(
+                        &conn,
+                        transfer_id,
+                        file_id,
+                        base_dir,
+                    )?;
+                }
             }
 
+            conn.commit()?;
+
             Result::Ok(())
         };
 
         if let Err(e) = task.await {
             error!(self.logger, "Failed to start incoming file sync state"; "error" => %e);
+            (self.on_error)("start_incoming_files", &e);
+        }
+    }
+
+    /// Moves the in-flight incoming file into a different base directory
+    /// within a single database transaction
+    pub async fn redirect_incoming_file(&self, transfer_id: Uuid, file_id: &str, base_dir: &str) {
+        let task = async {
+            let mut conn = self.conn.lock().await;
+            let conn = conn.transaction()?;
+
+            Self::redirect_incoming_file_in(&conn, transfer_id, file_id, base_dir)?;
+
+            conn.commit()?;
+
+            Result::Ok(())
+        };
+
+        if let Err(e) = task.await {
+            error!(self.logger, "Failed to redirect incoming file"; "error" => %e);
+            (self.on_error)("redirect_incoming_file", &e);
+        }
+    }
+
+    fn redirect_incoming_file_in(
+        conn: &Connection,
+        transfer_id: Uuid,
+        file_id: &str,
+        base_dir: &str,
+    ) -> Result<()> {
+        // The chosen name goes along with the file
+        let rename_to = sync::incoming_file_rename(conn, transfer_id, file_id)?;
+
+        sync::stop_incoming_file(conn, transfer_id, file_id)?;
+        if sync::start_incoming_file(conn, transfer_id, file_id, base_dir, rename_to.as_deref())?
+            .is_some()
+        {
+            Self::insert_incoming_path_pending_state(conn, transfer_id, file_id, base_dir)?;
         }
+
+        Ok(())
     }
 
     fn insert_incoming_path(
-        logger: &Logger,
+        &self,
         conn: &Transaction<'_>,
         transfer_id: Uuid,
         path: &TransferIncomingPath,
@@ -444,12 +887,13 @@ impl Storage {
         };
 
         if let Err(e) = task() {
-            error!(logger, "Failed to insert incoming path"; "error" => %e);
+            error!(self.logger, "Failed to insert incoming path"; "error" => %e);
+            (self.on_error)("insert_incoming_path", &e);
         }
     }
 
     fn insert_outgoing_path(
-        logger: &Logger,
+        &self,
         conn: &Transaction<'_>,
         transfer_id: Uuid,
         path: &TransferOutgoingPath,
@@ -470,7 +914,8 @@ impl Storage {
         };
 
         if let Err(e) = task() {
-            error!(logger, "Failed to insert outgoing path"; "error" => %e);
+            error!(self.logger, "Failed to insert outgoing path"; "error" => %e);
+            (self.on_error)("insert_outgoing_path", &e);
         }
     }
 
@@ -486,19 +931,29 @@ impl Storage {
 
         let task = async {
             let conn = self.conn.lock().await;
-            conn.execute(
-                "UPDATE incoming_paths SET checksum = ?3 WHERE transfer_id = ?1 AND path_hash = ?2",
-                params![tid, file_id, checksum],
-            )?;
-
-            Ok::<(), Error>(())
+            Self::save_checksum_in(&conn, &tid, file_id, checksum)
         };
 
         if let Err(e) = task.await {
             error!(self.logger, "Failed to save checksum"; "error" => %e);
+            (self.on_error)("save_checksum", &e);
         }
     }
 
+    fn save_checksum_in(
+        conn: &Connection,
+        tid: &str,
+        file_id: &str,
+        checksum: &[u8],
+    ) -> Result<()> {
+        conn.execute(
+            "UPDATE incoming_paths SET checksum = ?3 WHERE transfer_id = ?1 AND path_hash = ?2",
+            params![tid, file_id, checksum],
+        )?;
+
+        Ok(())
+    }
+
     pub async fn fetch_checksums(&self, transfer_id: Uuid) -> Vec<FileChecksum> {
         let tid = transfer_id.to_string();
         trace!(
@@ -528,334 +983,446 @@ impl Storage {
             Ok(out) => out,
             Err(e) => {
                 error!(self.logger, "Failed to fetch checksums"; "error" => %e);
+                (self.on_error)("fetch_checksums", &e);
                 vec![]
             }
         }
     }
 
-    pub async fn insert_transfer_failed_state(&self, transfer_id: Uuid, error: u32) {
+    /// Fetch the stored checksum of a single incoming file. Returns `None` if
+    /// the checksum is not known or the file does not exist
+    pub async fn file_checksum(&self, transfer_id: Uuid, file_id: &str) -> Option<Vec<u8>> {
         let tid = transfer_id.to_string();
-
         trace!(
             self.logger,
-            "Inserting transfer failed state";
+            "Fetching file checksum";
             "transfer_id" => &tid,
-            "error" => error);
+            "file_id" => file_id);
 
         let task = async {
             let conn = self.conn.lock().await;
-            conn.execute(
-                "INSERT INTO transfer_failed_states (transfer_id, status_code) VALUES (?1, ?2)",
-                params![tid, error],
-            )?;
+            let checksum = conn
+                .query_row(
+                    "SELECT checksum FROM incoming_paths WHERE transfer_id = ?1 AND path_hash = \
+                     ?2",
+                    params![tid, file_id],
+                    |row| row.get(0),
+                )
+                .optional()?
+                .flatten();
 
-            Ok::<(), Error>(())
+            Ok::<Option<Vec<u8>>, Error>(checksum)
         };
 
-        if let Err(e) = task.await {
-            error!(self.logger, "Failed to insert transfer failed state"; "error" => %e);
+        match task.await {
+            Ok(checksum) => checksum,
+            Err(e) => {
+                error!(self.logger, "Failed to fetch file checksum"; "error" => %e);
+                (self.on_error)("file_checksum", &e);
+                None
+            }
         }
     }
 
-    pub async fn insert_transfer_cancel_state(&self, transfer_id: Uuid, by_peer: bool) {
+    /// Records the checksum of the block of the partially downloaded file
+    /// once the whole block is written
+    pub async fn save_block_checksum(
+        &self,
+        transfer_id: Uuid,
+        file_id: &str,
+        block_size: u64,
+        block_idx: u64,
+        checksum: &[u8],
+    ) {
         let tid = transfer_id.to_string();
-
         trace!(
             self.logger,
-            "Inserting transfer cancel state";
+            "Saving block checksum";
             "transfer_id" => &tid,
-            "by_peer" => by_peer);
+            "file_id" => file_id,
+            "block_idx" => block_idx);
 
         let task = async {
             let conn = self.conn.lock().await;
             conn.execute(
-                "INSERT INTO transfer_cancel_states (transfer_id, by_peer) VALUES (?1, ?2)",
-                params![tid, by_peer],
+                "INSERT OR REPLACE INTO incoming_block_checksums (transfer_id, path_hash, \
+                 block_size, block_idx, checksum) VALUES (?1, ?2, ?3, ?4, ?5)",
+                params![tid, file_id, block_size as i64, block_idx as i64, checksum],
             )?;
 
             Ok::<(), Error>(())
         };
 
         if let Err(e) = task.await {
-            error!(self.logger, "Failed to insert transfer cancel state"; "error" => %e);
+            error!(self.logger, "Failed to save block checksum"; "error" => %e);
+            (self.on_error)("save_block_checksum", &e);
         }
     }
 
-    fn insert_incoming_path_pending_state(
-        conn: &Connection,
-        transfer_id: Uuid,
-        path_id: &str,
-        base_dir: &str,
-    ) -> Result<()> {
-        let tid = transfer_id.to_string();
-
-        conn.execute(
-            r#"
-            INSERT INTO incoming_path_pending_states (path_id, base_dir)
-            SELECT id, ?3
-            FROM incoming_paths WHERE transfer_id = ?1 AND path_hash = ?2
-            "#,
-            params![tid, path_id, base_dir],
-        )?;
-
-        Ok(())
-    }
-
-    pub async fn insert_outgoing_path_started_state(
+    /// Records the size and modification time of the partially downloaded
+    /// file as its download stops, describing the file the block checksums
+    /// were recorded for
+    pub async fn set_block_checksums_file_state(
         &self,
         transfer_id: Uuid,
-        path_id: &str,
-        bytes_sent: i64,
+        file_id: &str,
+        file_size: u64,
+        file_mtime: i64,
     ) {
         let tid = transfer_id.to_string();
-
         trace!(
             self.logger,
-            "Inserting outgoing path started state";
+            "Setting block checksums file state";
             "transfer_id" => &tid,
-            "path_id" => path_id);
+            "file_id" => file_id,
+            "file_size" => file_size,
+            "file_mtime" => file_mtime);
 
         let task = async {
             let conn = self.conn.lock().await;
             conn.execute(
-                r#"
-                INSERT INTO outgoing_path_started_states (path_id, bytes_sent)
-                SELECT id, ?3
-                FROM outgoing_paths WHERE transfer_id = ?1 AND path_hash = ?2
-                "#,
-                params![tid, path_id, bytes_sent],
+                "UPDATE incoming_block_checksums SET file_size = ?3, file_mtime = ?4 WHERE \
+                 transfer_id = ?1 AND path_hash = ?2",
+                params![tid, file_id, file_size as i64, file_mtime],
             )?;
 
             Ok::<(), Error>(())
         };
 
         if let Err(e) = task.await {
-            error!(self.logger, "Failed to insert outgoing path started state"; "error" => %e);
+            error!(self.logger, "Failed to set block checksums file state"; "error" => %e);
+            (self.on_error)("set_block_checksums_file_state", &e);
         }
     }
 
-    pub async fn insert_incoming_path_started_state(
+    /// Fetch the checksums of the consecutive blocks of the partially
+    /// downloaded file, starting with the first one. The ones recorded for a
+    /// different block size are skipped. None are returned unless they were
+    /// recorded for the file of the given size and modification time
+    pub async fn fetch_block_checksums(
         &self,
         transfer_id: Uuid,
-        path_id: &str,
-        bytes_received: i64,
-    ) {
+        file_id: &str,
+        block_size: u64,
+        file_size: u64,
+        file_mtime: i64,
+    ) -> Vec<Vec<u8>> {
         let tid = transfer_id.to_string();
-
         trace!(
-        self.logger,
-        "Inserting incoming path started state";
-        "transfer_id" => &tid,
-        "path_id" => path_id,
-        );
+            self.logger,
+            "Fetching block checksums";
+            "transfer_id" => &tid,
+            "file_id" => file_id);
 
         let task = async {
             let conn = self.conn.lock().await;
-            conn.execute(
-                r#"
-                INSERT INTO incoming_path_started_states (path_id, bytes_received)
-                SELECT id, ?3
-                FROM incoming_paths WHERE transfer_id = ?1 AND path_hash = ?2
-                "#,
-                params![tid, path_id, bytes_received],
-            )?;
+            let rows = conn
+                .prepare(
+                    "SELECT block_idx, checksum, file_size, file_mtime FROM \
+                     incoming_block_checksums WHERE transfer_id = ?1 AND path_hash = ?2 AND \
+                     block_size = ?3 ORDER BY block_idx",
+                )?
+                .query_map(params![tid, file_id, block_size as i64], |row| {
+                    Ok((
+                        row.get::<_, i64>(0)?,
+                        row.get::<_, Vec<u8>>(1)?,
+                        row.get::<_, Option<i64>>(2)?,
+                        row.get::<_, Option<i64>>(3)?,
+                    ))
+                })?
+                .collect::<QueryResult<Vec<_>>>()?;
 
-            Ok::<(), Error>(())
+            // The file was modified after the download stopped, or the state
+            // was never recorded
+            let state = (Some(file_size as i64), Some(file_mtime));
+            if rows
+                .iter()
+                .any(|(_, _, size, mtime)| (*size, *mtime) != state)
+            {
+                return Ok(Vec::new());
+            }
+
+            // Only the prefix without gaps is of any use
+            let out = rows
+                .into_iter()
+                .enumerate()
+                .take_while(|(idx, (block_idx, ..))| *idx as i64 == *block_idx)
+                .map(|(_, (_, checksum, ..))| checksum)
+                .collect();
+
+            Ok::<Vec<_>, Error>(out)
         };
 
-        if let Err(e) = task.await {
-            error!(self.logger, "Failed to insert incoming path started state"; "error" => %e);
+        match task.await {
+            Ok(out) => out,
+            Err(e) => {
+                error!(self.logger, "Failed to fetch block checksums"; "error" => %e);
+                (self.on_error)("fetch_block_checksums", &e);
+                vec![]
+            }
         }
     }
 
-    pub async fn insert_incoming_path_failed_state(
-        &self,
-        transfer_id: Uuid,
-        path_id: &str,
-        error: u32,
-        bytes_received: i64,
-    ) {
+    /// Removes the checksums of the blocks starting with `from_idx`, e.g. when
+    /// the partially downloaded file gets truncated
+    pub async fn remove_block_checksums(&self, transfer_id: Uuid, file_id: &str, from_idx: u64) {
         let tid = transfer_id.to_string();
-
         trace!(
             self.logger,
-            "Inserting incoming path failed state";
+            "Removing block checksums";
             "transfer_id" => &tid,
-            "path_id" => path_id,
-            "error" => error,
-            "bytes_received" => bytes_received);
+            "file_id" => file_id,
+            "from_idx" => from_idx);
 
         let task = async {
             let conn = self.conn.lock().await;
             conn.execute(
-                r#"
-                INSERT INTO incoming_path_failed_states (path_id, status_code, bytes_received)
-                SELECT id, ?3, ?4
-                FROM incoming_paths WHERE transfer_id = ?1 AND path_hash = ?2
-                "#,
-                params![tid, path_id, error, bytes_received],
+                "DELETE FROM incoming_block_checksums WHERE transfer_id = ?1 AND path_hash = ?2 \
+                 AND block_idx >= ?3",
+                params![tid, file_id, from_idx as i64],
             )?;
 
             Ok::<(), Error>(())
         };
 
         if let Err(e) = task.await {
-            error!(self.logger, "Failed to insert incoming path failed state"; "error" => %e);
+            error!(self.logger, "Failed to remove block checksums"; "error" => %e);
+            (self.on_error)("remove_block_checksums", &e);
         }
     }
 
-    pub async fn insert_outgoing_path_failed_state(
+    /// Fetch the cached checksum of the first `limit` bytes of an outgoing
+    /// file, `path` being the file URI as stored with the transfer. Entries
+    /// recorded for a different file size or modification time are considered
+    /// stale and ignored.
+    pub async fn fetch_outgoing_checksum(
         &self,
-        transfer_id: Uuid,
-        path_id: &str,
-        error: u32,
-        bytes_sent: i64,
+        path: &str,
+        size: i64,
+        mtime: i64,
+        limit: i64,
+    ) -> Option<Vec<u8>> {
+        trace!(
+            self.logger,
+            "Fetching outgoing checksum";
+            "path" => path,
+            "limit" => limit,
+        );
+
+        let task = async {
+            let conn = self.conn.lock().await;
+
+            let checksum = conn
+                .query_row(
+                    "SELECT checksum FROM outgoing_checksums WHERE path = ?1 AND limit_bytes = ?2 \
+                     AND size = ?3 AND mtime = ?4",
+                    params![path, limit, size, mtime],
+                    |row| row.get(0),
+                )
+                .optional()?;
+
+            Ok::<Option<Vec<u8>>, Error>(checksum)
+        };
+
+        match task.await {
+            Ok(checksum) => checksum,
+            Err(e) => {
+                error!(self.logger, "Failed to fetch outgoing checksum"; "error" => %e);
+                (self.on_error)("fetch_outgoing_checksum", &e);
+                None
+            }
+        }
+    }
+
+    pub async fn save_outgoing_checksum(
+        &self,
+        path: &str,
+        size: i64,
+        mtime: i64,
+        limit: i64,
+        checksum: &[u8],
     ) {
-        let tid = transfer_id.to_string();
         trace!(
             self.logger,
-            "Inserting outgoing path failed state";
-            "transfer_id" => &tid,
-            "path_id" => path_id,
-            "error" => error,
-            "bytes_sent" => bytes_sent);
+            "Saving outgoing checksum";
+            "path" => path,
+            "limit" => limit,
+        );
 
         let task = async {
             let conn = self.conn.lock().await;
             conn.execute(
-                r#"
-                INSERT INTO outgoing_path_failed_states (path_id, status_code, bytes_sent)
-                SELECT id, ?3, ?4
-                FROM outgoing_paths WHERE transfer_id = ?1 AND path_hash = ?2
-                "#,
-                params![tid, path_id, error, bytes_sent],
+                "INSERT OR REPLACE INTO outgoing_checksums (path, limit_bytes, size, mtime, \
+                 checksum) VALUES (?1, ?2, ?3, ?4, ?5)",
+                params![path, limit, size, mtime, checksum],
             )?;
 
             Ok::<(), Error>(())
         };
 
         if let Err(e) = task.await {
-            error!(self.logger, "Failed to insert outgoing path failed state"; "error" => %e);
+            error!(self.logger, "Failed to save outgoing checksum"; "error" => %e);
+            (self.on_error)("save_outgoing_checksum", &e);
         }
     }
 
-    pub async fn insert_outgoing_path_completed_state(&self, transfer_id: Uuid, path_id: &str) {
+    pub async fn insert_transfer_failed_state(&self, transfer_id: Uuid, error: u32) {
         let tid = transfer_id.to_string();
+
         trace!(
             self.logger,
-            "Inserting outgoing path completed state";
+            "Inserting transfer failed state";
             "transfer_id" => &tid,
-            "path_id" => path_id);
+            "error" => error);
 
         let task = async {
             let conn = self.conn.lock().await;
             conn.execute(
-                r#"
-                INSERT INTO outgoing_path_completed_states (path_id)
-                SELECT id
-                FROM outgoing_paths WHERE transfer_id = ?1 AND path_hash = ?2
-                "#,
-                params![tid, path_id],
+                "INSERT INTO transfer_failed_states (transfer_id, status_code) VALUES (?1, ?2)",
+                params![tid, error],
             )?;
 
             Ok::<(), Error>(())
         };
 
         if let Err(e) = task.await {
-            error!(self.logger, "Failed to insert outgoing path completed state"; "error" => %e);
+            error!(self.logger, "Failed to insert transfer failed state"; "error" => %e);
+            (self.on_error)("insert_transfer_failed_state", &e);
         }
     }
 
-    pub async fn insert_incoming_path_completed_state(
+    pub async fn insert_transfer_reject_state(
         &self,
         transfer_id: Uuid,
-        path_id: &str,
-        final_path: &str,
+        by_peer: bool,
+        reason: &str,
     ) {
         let tid = transfer_id.to_string();
+
         trace!(
             self.logger,
-            "Inserting incoming path completed state";
+            "Inserting transfer reject state";
             "transfer_id" => &tid,
-            "path_id" => path_id,
-            "final_path" => final_path);
+            "by_peer" => by_peer,
+            "reason" => reason);
 
         let task = async {
             let conn = self.conn.lock().await;
             conn.execute(
-                r#"
-                INSERT INTO incoming_path_completed_states (path_id, final_path)
-                SELECT id, ?3
-                FROM incoming_paths WHERE transfer_id = ?1 AND path_hash = ?2
-                "#,
-                params![tid, path_id, final_path],
+                "INSERT INTO transfer_reject_states (transfer_id, by_peer, reason) VALUES (?1, \
+                 ?2, ?3)",
+                params![tid, by_peer, reason],
             )?;
 
             Ok::<(), Error>(())
         };
 
         if let Err(e) = task.await {
-            error!(self.logger, "Failed to insert incoming path completed state"; "error" => %e);
+            error!(self.logger, "Failed to insert transfer reject state"; "error" => %e);
+            (self.on_error)("insert_transfer_reject_state", &e);
         }
     }
 
-    pub async fn insert_outgoing_path_reject_state(
+    pub async fn insert_transfer_cancel_state(
         &self,
         transfer_id: Uuid,
-        path_id: &str,
         by_peer: bool,
-        bytes_sent: i64,
+        cause: CancelCause,
     ) {
         let tid = transfer_id.to_string();
 
+        trace!(
+            self.logger,
+            "Inserting transfer cancel state";
+            "transfer_id" => &tid,
+            "by_peer" => by_peer,
+            "cause" => ?cause);
+
         let task = async {
             let conn = self.conn.lock().await;
             conn.execute(
-                r#"
-                INSERT INTO outgoing_path_reject_states (path_id, by_peer, bytes_sent)
-                SELECT id, ?3, ?4
-                FROM outgoing_paths WHERE transfer_id = ?1 AND path_hash = ?2
-                "#,
-                params![tid, path_id, by_peer, bytes_sent],
+                "INSERT INTO transfer_cancel_states (transfer_id, by_peer, cause) VALUES (?1, ?2, \
+                 ?3)",
+                params![tid, by_peer, cause],
             )?;
 
             Ok::<(), Error>(())
         };
 
         if let Err(e) = task.await {
-            error!(self.logger, "Failed to insert outgoing path reject state"; "error" => %e);
+            error!(self.logger, "Failed to insert transfer cancel state"; "error" => %e);
+            (self.on_error)("insert_transfer_cancel_state", &e);
         }
     }
 
-    pub async fn insert_incoming_path_reject_state(
-        &self,
+    fn insert_incoming_path_pending_state(
+        conn: &Connection,
         transfer_id: Uuid,
         path_id: &str,
-        by_peer: bool,
-        bytes_received: i64,
-    ) {
+        base_dir: &str,
+    ) -> Result<()> {
         let tid = transfer_id.to_string();
 
-        let task = async {
-            let conn = self.conn.lock().await;
-            conn.execute(
-                r#"
-                INSERT INTO incoming_path_reject_states (path_id, by_peer, bytes_received)
-                SELECT id, ?3, ?4
-                FROM incoming_paths WHERE transfer_id = ?1 AND path_hash = ?2
-                "#,
-                params![tid, path_id, by_peer, bytes_received],
-            )?;
+        conn.execute(
+            r#"
+            INSERT INTO incoming_path_pending_states (path_id, base_dir)
+            SELECT id, ?3
+            FROM incoming_paths WHERE transfer_id = ?1 AND path_hash = ?2
+            "#,
+            params![tid, path_id, base_dir],
+        )?;
 
-            Ok::<(), Error>(())
+        conn.execute(
+            "INSERT OR IGNORE INTO temp_base_dirs (base_dir) VALUES (?1)",
+            params![base_dir],
+        )?;
+
+        Ok(())
+    }
+
+    /// Adds the bytes of the file reaching the terminal state to the peer
+    /// usage. Must be called before the terminal state is inserted. Files which
+    /// are terminated already are skipped so that nothing is counted twice.
+    /// When `bytes` is `None` the whole file size is used
+    fn add_peer_usage(
+        conn: &Connection,
+        transfer_id: Uuid,
+        path_id: &str,
+        is_incoming: bool,
+        bytes: Option<i64>,
+    ) -> Result<()> {
+        let tid = transfer_id.to_string();
+
+        let (prefix, bytes_sent, bytes_received) = if is_incoming {
+            ("incoming", "0", "COALESCE(?3, p.bytes)")
+        } else {
+            ("outgoing", "COALESCE(?3, p.bytes)", "0")
         };
 
-        if let Err(e) = task.await {
-            error!(self.logger, "Failed to insert incoming path reject state"; "error" => %e);
-        }
+        conn.execute(
+            &format!(
+                r#"
+            INSERT INTO peer_usage (peer, bytes_sent, bytes_received)
+            SELECT t.peer, {bytes_sent}, {bytes_received}
+            FROM {prefix}_paths p
+            INNER JOIN transfers t ON t.id = p.transfer_id
+            WHERE p.transfer_id = ?1 AND p.path_hash = ?2
+                AND NOT EXISTS (SELECT 1 FROM {prefix}_path_completed_states WHERE path_id = p.id)
+                AND NOT EXISTS (SELECT 1 FROM {prefix}_path_failed_states WHERE path_id = p.id)
+                AND NOT EXISTS (SELECT 1 FROM {prefix}_path_reject_states WHERE path_id = p.id)
+            ON CONFLICT(peer) DO UPDATE SET
+                bytes_sent = bytes_sent + excluded.bytes_sent,
+                bytes_received = bytes_received + excluded.bytes_received,
+                updated_at = excluded.updated_at
+            "#
+            ),
+            params![tid, path_id, bytes],
+        )?;
+
+        Ok(())
     }
 
-    pub async fn insert_outgoing_path_paused_state(
+    pub async fn insert_outgoing_path_started_state(
         &self,
         transfer_id: Uuid,
         path_id: &str,
@@ -863,11 +1430,17 @@ impl Storage {
     ) {
         let tid = transfer_id.to_string();
 
+        trace!(
+            self.logger,
+            "Inserting outgoing path started state";
+            "transfer_id" => &tid,
+            "path_id" => path_id);
+
         let task = async {
             let conn = self.conn.lock().await;
             conn.execute(
                 r#"
-                INSERT INTO outgoing_path_paused_states (path_id, bytes_sent)
+                INSERT INTO outgoing_path_started_states (path_id, bytes_sent)
                 SELECT id, ?3
                 FROM outgoing_paths WHERE transfer_id = ?1 AND path_hash = ?2
                 "#,
@@ -878,11 +1451,12 @@ impl Storage {
         };
 
         if let Err(e) = task.await {
-            error!(self.logger, "Failed to insert outgoing path paused state"; "error" => %e);
+            error!(self.logger, "Failed to insert outgoing path started state"; "error" => %e);
+            (self.on_error)("insert_outgoing_path_started_state", &e);
         }
     }
 
-    pub async fn insert_incoming_path_paused_state(
+    pub async fn insert_incoming_path_started_state(
         &self,
         transfer_id: Uuid,
         path_id: &str,
@@ -890,11 +1464,18 @@ impl Storage {
     ) {
         let tid = transfer_id.to_string();
 
+        trace!(
+        self.logger,
+        "Inserting incoming path started state";
+        "transfer_id" => &tid,
+        "path_id" => path_id,
+        );
+
         let task = async {
             let conn = self.conn.lock().await;
             conn.execute(
                 r#"
-                INSERT INTO incoming_path_paused_states (path_id, bytes_received)
+                INSERT INTO incoming_path_started_states (path_id, bytes_received)
                 SELECT id, ?3
                 FROM incoming_paths WHERE transfer_id = ?1 AND path_hash = ?2
                 "#,
@@ -905,1343 +1486,4843 @@ impl Storage {
         };
 
         if let Err(e) = task.await {
-            error!(self.logger, "Failed to insert incoming path paused state"; "error" => %e);
+            error!(self.logger, "Failed to insert incoming path started state"; "error" => %e);
+            (self.on_error)("insert_incoming_path_started_state", &e);
         }
     }
 
-    pub async fn purge_transfers_until(&self, until_timestamp: i64) {
+    pub async fn insert_incoming_path_failed_state(
+        &self,
+        transfer_id: Uuid,
+        path_id: &str,
+        error: u32,
+        bytes_received: i64,
+    ) {
+        let tid = transfer_id.to_string();
+
         trace!(
             self.logger,
-            "Purging transfers until timestamp";
-            "until_timestamp" => until_timestamp);
+            "Inserting incoming path failed state";
+            "transfer_id" => &tid,
+            "path_id" => path_id,
+            "error" => error,
+            "bytes_received" => bytes_received);
 
         let task = async {
-            let conn = self.conn.lock().await;
+            let mut conn = self.conn.lock().await;
+            let conn = conn.transaction()?;
+
+            Self::add_peer_usage(&conn, transfer_id, path_id, true, Some(bytes_received))?;
             conn.execute(
                 r#"
-                UPDATE transfers SET is_deleted = TRUE
-                WHERE created_at < datetime(?1, 'unixepoch')
-                    AND (
-                        id IN(SELECT transfer_id FROM transfer_cancel_states) OR
-                        id IN(SELECT transfer_id FROM transfer_failed_states)
-                    )
+                INSERT INTO incoming_path_failed_states (path_id, status_code, bytes_received)
+                SELECT id, ?3, ?4
+                FROM incoming_paths WHERE transfer_id = ?1 AND path_hash = ?2
                 "#,
-                params![until_timestamp],
+                params![tid, path_id, error, bytes_received],
             )?;
 
+            conn.commit()?;
+
             Ok::<(), Error>(())
         };
 
         if let Err(e) = task.await {
-            error!(self.logger, "Failed to purge transfers"; "error" => %e);
+            error!(self.logger, "Failed to insert incoming path failed state"; "error" => %e);
+            (self.on_error)("insert_incoming_path_failed_state", &e);
         }
     }
 
-    pub async fn purge_transfers(&self, transfer_ids: &[String]) {
+    /// Replaces the URI of the outgoing file, e.g. when the original one can no
+    /// longer be opened
+    pub async fn update_outgoing_path_uri(&self, transfer_id: Uuid, path_id: &str, uri: &url::Url) {
+        let tid = transfer_id.to_string();
         trace!(
             self.logger,
-            "Purging transfers";
-            "transfer_ids" => format!("{:?}", transfer_ids));
+            "Updating outgoing path uri";
+            "transfer_id" => &tid,
+            "path_id" => path_id);
 
         let task = async {
             let conn = self.conn.lock().await;
+            Self::update_outgoing_path_uri_in(&conn, &tid, path_id, uri)
+        };
 
-            for id in transfer_ids {
-                let count = conn.execute(
-                    r#"
-                    UPDATE transfers SET is_deleted = TRUE
-                    WHERE id = ?1
-                        AND (
-                            id IN(SELECT transfer_id FROM transfer_cancel_states) OR
-                            id IN(SELECT transfer_id FROM transfer_failed_states)
-                        )
-                    "#,
-                    params![id],
-                )?;
+        if let Err(e) = task.await {
+            error!(self.logger, "Failed to update outgoing path uri"; "error" => %e);
+            (self.on_error)("update_outgoing_path_uri", &e);
+        }
+    }
 
-                if count < 1 {
-                    warn!(
-                        self.logger,
-                        "Failed to purge transfer: {id}. It may not be in the terminal state"
-                    );
-                }
-            }
+    fn update_outgoing_path_uri_in(
+        conn: &Connection,
+        tid: &str,
+        path_id: &str,
+        uri: &url::Url,
+    ) -> Result<()> {
+        conn.execute(
+            "UPDATE outgoing_paths SET uri = ?3 WHERE transfer_id = ?1 AND path_hash = ?2",
+            params![tid, path_id, uri.as_str()],
+        )?;
+
+        Ok(())
+    }
+
+    /// Records the final size of the file whose size was not known up front,
+    /// e.g. the streamed one, once all of its bytes are sent or received
+    pub async fn update_path_size(&self, transfer_id: Uuid, path_id: &str, bytes: i64) {
+        let tid = transfer_id.to_string();
+        trace!(
+            self.logger,
+            "Updating path size";
+            "transfer_id" => &tid,
+            "path_id" => path_id,
+            "bytes" => bytes);
+
+        let task = async {
+            let conn = self.conn.lock().await;
+
+            conn.execute(
+                "UPDATE incoming_paths SET bytes = ?3 WHERE transfer_id = ?1 AND path_hash = ?2",
+                params![tid, path_id, bytes],
+            )?;
+            conn.execute(
+                "UPDATE outgoing_paths SET bytes = ?3 WHERE transfer_id = ?1 AND path_hash = ?2",
+                params![tid, path_id, bytes],
+            )?;
 
             Ok::<(), Error>(())
         };
 
         if let Err(e) = task.await {
-            error!(self.logger, "Failed to purge transfers"; "error" => %e);
+            error!(self.logger, "Failed to update path size"; "error" => %e);
+            (self.on_error)("update_path_size", &e);
         }
     }
 
-    pub async fn outgoing_transfers_to_resume(&self) -> Vec<OutgoingTransferToRetry> {
+    pub async fn insert_outgoing_path_failed_state(
+        &self,
+        transfer_id: Uuid,
+        path_id: &str,
+        error: u32,
+        bytes_sent: i64,
+    ) {
+        let tid = transfer_id.to_string();
+        trace!(
+            self.logger,
+            "Inserting outgoing path failed state";
+            "transfer_id" => &tid,
+            "path_id" => path_id,
+            "error" => error,
+            "bytes_sent" => bytes_sent);
+
         let task = async {
             let mut conn = self.conn.lock().await;
             let conn = conn.transaction()?;
 
-            let rec_transfers = sync::transfers_to_resume(&conn, TransferType::Outgoing)?;
-
-            let mut out = Vec::with_capacity(rec_transfers.len());
-            for rec_transfer in rec_transfers {
-                let files = conn
-                    .prepare(
-                        r#"
-                    SELECT relative_path, uri, path_hash, bytes 
-                    FROM outgoing_paths 
-                    WHERE transfer_id = ?1
-                    "#,
-                    )?
-                    .query_map(params![rec_transfer.tid], |r| {
-                        Ok((
-                            r.get("path_hash")?,
-                            r.get::<_, String>("uri")?,
-                            r.get("relative_path")?,
-                            r.get("bytes")?,
-                        ))
-                    })?
-                    .map(|row| {
-                        let (file_id, uri, subpath, size) = row?;
-                        Ok(OutgoingFileToRetry {
-                            file_id,
-                            uri: uri.parse()?,
-                            subpath,
-                            size,
-                        })
-                    })
-                    .collect::<Result<_>>()?;
-
-                out.push(OutgoingTransferToRetry {
-                    uuid: rec_transfer.tid.parse().map_err(|err| {
-                        crate::Error::InternalError(format!("Failed to parse UUID: {err}"))
-                    })?,
-                    peer: rec_transfer.peer,
-                    files,
-                });
-            }
+            Self::add_peer_usage(&conn, transfer_id, path_id, false, Some(bytes_sent))?;
+            conn.execute(
+                r#"
+                INSERT INTO outgoing_path_failed_states (path_id, status_code, bytes_sent)
+                SELECT id, ?3, ?4
+                FROM outgoing_paths WHERE transfer_id = ?1 AND path_hash = ?2
+                "#,
+                params![tid, path_id, error, bytes_sent],
+            )?;
 
             conn.commit()?;
 
-            Ok::<Vec<_>, Error>(out)
+            Ok::<(), Error>(())
         };
 
-        match task.await {
-            Ok(transfers) => transfers,
-            Err(e) => {
-                error!(self.logger, "Failed to get outgoing transfers to resume"; "error" => %e);
-                vec![]
-            }
+        if let Err(e) = task.await {
+            error!(self.logger, "Failed to insert outgoing path failed state"; "error" => %e);
+            (self.on_error)("insert_outgoing_path_failed_state", &e);
         }
     }
 
-    pub async fn incoming_transfers_to_resume(&self) -> Vec<IncomingTransferToRetry> {
+    pub async fn insert_outgoing_path_completed_state(&self, transfer_id: Uuid, path_id: &str) {
+        let tid = transfer_id.to_string();
+        trace!(
+            self.logger,
+            "Inserting outgoing path completed state";
+            "transfer_id" => &tid,
+            "path_id" => path_id);
+
         let task = async {
             let mut conn = self.conn.lock().await;
             let conn = conn.transaction()?;
 
-            let rec_transfers = sync::transfers_to_resume(&conn, TransferType::Incoming)?;
-
-            let mut out = Vec::with_capacity(rec_transfers.len());
-            for rec_transfer in rec_transfers {
-                let files = conn
-                    .prepare(
-                        r#"
-                    SELECT relative_path, path_hash, bytes 
-                    FROM incoming_paths 
-                    WHERE transfer_id = ?1
-                    "#,
-                    )?
-                    .query_map(params![rec_transfer.tid], |r| {
-                        Ok(IncomingFileToRetry {
-                            file_id: r.get("path_hash")?,
-                            subpath: r.get("relative_path")?,
-                            size: r.get("bytes")?,
-                        })
-                    })?
-                    .collect::<QueryResult<_>>()?;
-
-                out.push(IncomingTransferToRetry {
-                    uuid: rec_transfer.tid.parse().map_err(|err| {
-                        crate::Error::InternalError(format!("Failed to parse UUID: {err}"))
-                    })?,
-                    peer: rec_transfer.peer,
-                    files,
-                });
-            }
+            Self::add_peer_usage(&conn, transfer_id, path_id, false, None)?;
+            conn.execute(
+                r#"
+                INSERT INTO outgoing_path_completed_states (path_id)
+                SELECT id
+                FROM outgoing_paths WHERE transfer_id = ?1 AND path_hash = ?2
+                "#,
+                params![tid, path_id],
+            )?;
 
             conn.commit()?;
-            Ok::<Vec<_>, Error>(out)
+
+            Ok::<(), Error>(())
         };
 
-        match task.await {
-            Ok(transfers) => transfers,
-            Err(e) => {
-                error!(self.logger, "Failed to get incoming transfers to resume"; "error" => %e);
-                vec![]
-            }
+        if let Err(e) = task.await {
+            error!(self.logger, "Failed to insert outgoing path completed state"; "error" => %e);
+            (self.on_error)("insert_outgoing_path_completed_state", &e);
         }
     }
 
-    pub async fn incoming_files_to_resume(&self, transfer_id: Uuid) -> Vec<sync::FileInFlight> {
+    pub async fn insert_incoming_path_completed_state(
+        &self,
+        transfer_id: Uuid,
+        path_id: &str,
+        final_path: &str,
+        was_renamed: bool,
+        deleted: bool,
+    ) {
+        let tid = transfer_id.to_string();
+        trace!(
+            self.logger,
+            "Inserting incoming path completed state";
+            "transfer_id" => &tid,
+            "path_id" => path_id,
+            "final_path" => final_path,
+            "was_renamed" => was_renamed,
+            "deleted" => deleted);
+
         let task = async {
-            let conn = self.conn.lock().await;
-            sync::incoming_files_in_flight(&conn, transfer_id)
+            let mut conn = self.conn.lock().await;
+            let conn = conn.transaction()?;
+
+            Self::add_peer_usage(&conn, transfer_id, path_id, true, None)?;
+            conn.execute(
+                r#"
+                INSERT INTO incoming_path_completed_states (path_id, final_path, was_renamed, deleted)
+                SELECT id, ?3, ?4, ?5
+                FROM incoming_paths WHERE transfer_id = ?1 AND path_hash = ?2
+                "#,
+                params![tid, path_id, final_path, was_renamed, deleted],
+            )?;
+
+            conn.commit()?;
+
+            Ok::<(), Error>(())
         };
 
-        match task.await {
-            Ok(files) => files,
-            Err(e) => {
-                error!(self.logger, "Failed to get incoming files to resume"; "error" => %e);
-                vec![]
-            }
+        if let Err(e) = task.await {
+            error!(self.logger, "Failed to insert incoming path completed state"; "error" => %e);
+            (self.on_error)("insert_incoming_path_completed_state", &e);
         }
     }
 
-    pub async fn finished_incoming_files(&self, transfer_id: Uuid) -> Vec<FinishedIncomingFile> {
+    pub async fn insert_outgoing_path_reject_state(
+        &self,
+        transfer_id: Uuid,
+        path_id: &str,
+        by_peer: bool,
+        bytes_sent: i64,
+    ) {
+        let tid = transfer_id.to_string();
+
         let task = async {
-            let conn = self.conn.lock().await;
+            let mut conn = self.conn.lock().await;
+            let conn = conn.transaction()?;
 
-            let paths = conn
-                .prepare(
-                    r#"
-                SELECT relative_path as subpath, final_path
-                FROM incoming_paths ip
-                INNER JOIN incoming_path_completed_states ipcs ON ip.id = ipcs.path_id
-                WHERE transfer_id = ?1
+            Self::add_peer_usage(&conn, transfer_id, path_id, false, Some(bytes_sent))?;
+            conn.execute(
+                r#"
+                INSERT INTO outgoing_path_reject_states (path_id, by_peer, bytes_sent)
+                SELECT id, ?3, ?4
+                FROM outgoing_paths WHERE transfer_id = ?1 AND path_hash = ?2
                 "#,
-                )?
-                .query_map(params![transfer_id.to_string()], |r| {
-                    Ok(FinishedIncomingFile {
-                        subpath: r.get("subpath")?,
-                        final_path: r.get("final_path")?,
-                    })
-                })?
-                .collect::<QueryResult<_>>()?;
+                params![tid, path_id, by_peer, bytes_sent],
+            )?;
 
-            Ok::<Vec<_>, Error>(paths)
+            conn.commit()?;
+
+            Ok::<(), Error>(())
         };
 
-        match task.await {
-            Ok(paths) => paths,
-            Err(e) => {
-                error!(self.logger, "Failed to get finished incoming files"; "error" => %e);
-                vec![]
-            }
+        if let Err(e) = task.await {
+            error!(self.logger, "Failed to insert outgoing path reject state"; "error" => %e);
+            (self.on_error)("insert_outgoing_path_reject_state", &e);
         }
     }
 
-    pub async fn transfers_since(&self, since_timestamp: i64) -> Vec<Transfer> {
-        // Collect transfers since a given timestamp.
-        // This performs 3 queries, fetching by insertion order:
-        // 1. transfers with their states.
-        // 2. outgoing paths with their states
-        // 3. incoming paths with their states
-        // Because a single query is used for transfers and their states
-        // (the same applies to paths as well), a hashmap is used to collect states for
-        // each transfer. For each state their transfer is taken from a hash map
-        // (or inserted), and this transfers state list is appended.
-        // For transfers, their rowid is selected as well and used to sort the
-        // transfers. Because its not part of `Transfer` structure, a tuple is
-        // used as hashmap value.
-        trace!(
-        self.logger,
-        "Fetching transfers since timestamp";
-        "since_timestamp" => since_timestamp);
-
-        let task = async {
-            let mut conn = self.conn.lock().await;
-            let mut transfers_map: HashMap<Uuid, (u64, Transfer)> = HashMap::new();
-            let tx = conn.transaction()?;
-            // transfer_cancel_states.by_peer shares a type with
+    pub async fn insert_incoming_path_reject_state(
+        &self,
+        transfer_id: Uuid,
+        path_id: &str,
+        by_peer: bool,
+        bytes_received: i64,
+    ) {
+        let tid = transfer_id.to_string();
+
+        let task = async {
+            let mut conn = self.conn.lock().await;
+            let conn = conn.transaction()?;
+
+            Self::add_peer_usage(&conn, transfer_id, path_id, true, Some(bytes_received))?;
+            conn.execute(
+                r#"
+                INSERT INTO incoming_path_reject_states (path_id, by_peer, bytes_received)
+                SELECT id, ?3, ?4
+                FROM incoming_paths WHERE transfer_id = ?1 AND path_hash = ?2
+                "#,
+                params![tid, path_id, by_peer, bytes_received],
+            )?;
+
+            conn.commit()?;
+
+            Ok::<(), Error>(())
+        };
+
+        if let Err(e) = task.await {
+            error!(self.logger, "Failed to insert incoming path reject state"; "error" => %e);
+            (self.on_error)("insert_incoming_path_reject_state", &e);
+        }
+    }
+
+    pub async fn insert_outgoing_path_paused_state(
+        &self,
+        transfer_id: Uuid,
+        path_id: &str,
+        bytes_sent: i64,
+    ) {
+        let tid = transfer_id.to_string();
+
+        let task = async {
+            let conn = self.conn.lock().await;
+            conn.execute(
+                r#"
+                INSERT INTO outgoing_path_paused_states (path_id, bytes_sent)
+                SELECT id, ?3
+                FROM outgoing_paths WHERE transfer_id = ?1 AND path_hash = ?2
+                "#,
+                params![tid, path_id, bytes_sent],
+            )?;
+
+            Ok::<(), Error>(())
+        };
+
+        if let Err(e) = task.await {
+            error!(self.logger, "Failed to insert outgoing path paused state"; "error" => %e);
+            (self.on_error)("insert_outgoing_path_paused_state", &e);
+        }
+    }
+
+    pub async fn insert_incoming_path_paused_state(
+        &self,
+        transfer_id: Uuid,
+        path_id: &str,
+        bytes_received: i64,
+    ) {
+        let tid = transfer_id.to_string();
+
+        let task = async {
+            let conn = self.conn.lock().await;
+            conn.execute(
+                r#"
+                INSERT INTO incoming_path_paused_states (path_id, bytes_received)
+                SELECT id, ?3
+                FROM incoming_paths WHERE transfer_id = ?1 AND path_hash = ?2
+                "#,
+                params![tid, path_id, bytes_received],
+            )?;
+
+            Ok::<(), Error>(())
+        };
+
+        if let Err(e) = task.await {
+            error!(self.logger, "Failed to insert incoming path paused state"; "error" => %e);
+            (self.on_error)("insert_incoming_path_paused_state", &e);
+        }
+    }
+
+    pub async fn purge_transfers_until(&self, until_timestamp: i64) {
+        trace!(
+            self.logger,
+            "Purging transfers until timestamp";
+            "until_timestamp" => until_timestamp);
+
+        let task = async {
+            let conn = self.conn.lock().await;
+            conn.execute(
+                r#"
+                UPDATE transfers SET is_deleted = TRUE
+                WHERE created_at < datetime(?1, 'unixepoch')
+                    AND (
+                        id IN(SELECT transfer_id FROM transfer_cancel_states) OR
+                        id IN(SELECT transfer_id FROM transfer_failed_states) OR
+                        id IN(SELECT transfer_id FROM transfer_reject_states)
+                    )
+                "#,
+                params![until_timestamp],
+            )?;
+
+            Ok::<(), Error>(())
+        };
+
+        if let Err(e) = task.await {
+            error!(self.logger, "Failed to purge transfers"; "error" => %e);
+            (self.on_error)("purge_transfers_until", &e);
+        }
+    }
+
+    pub async fn purge_transfers(&self, transfer_ids: &[String]) {
+        trace!(
+            self.logger,
+            "Purging transfers";
+            "transfer_ids" => format!("{:?}", transfer_ids));
+
+        let task = async {
+            let conn = self.conn.lock().await;
+            self.purge_transfers_in(&conn, transfer_ids)
+        };
+
+        if let Err(e) = task.await {
+            error!(self.logger, "Failed to purge transfers"; "error" => %e);
+            (self.on_error)("purge_transfers", &e);
+        }
+    }
+
+    fn purge_transfers_in(&self, conn: &Connection, transfer_ids: &[String]) -> Result<()> {
+        for id in transfer_ids {
+            let count = conn.execute(
+                r#"
+                UPDATE transfers SET is_deleted = TRUE
+                WHERE id = ?1
+                    AND (
+                        id IN(SELECT transfer_id FROM transfer_cancel_states) OR
+                        id IN(SELECT transfer_id FROM transfer_failed_states) OR
+                        id IN(SELECT transfer_id FROM transfer_reject_states)
+                    )
+                "#,
+                params![id],
+            )?;
+
+            if count < 1 {
+                warn!(
+                    self.logger,
+                    "Failed to purge transfer: {id}. It may not be in the terminal state"
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    pub async fn outgoing_transfers_to_resume(&self) -> Vec<OutgoingTransferToRetry> {
+        let task = async {
+            let mut conn = self.conn.lock().await;
+            let conn = conn.transaction()?;
+
+            let rec_transfers = sync::transfers_to_resume(&conn, TransferType::Outgoing)?;
+
+            let mut out = Vec::with_capacity(rec_transfers.len());
+            for rec_transfer in rec_transfers {
+                let files = conn
+                    .prepare(
+                        r#"
+                    SELECT relative_path, uri, path_hash, bytes 
+                    FROM outgoing_paths 
+                    WHERE transfer_id = ?1
+                    "#,
+                    )?
+                    .query_map(params![rec_transfer.tid], |r| {
+                        Ok((
+                            r.get("path_hash")?,
+                            r.get::<_, String>("uri")?,
+                            r.get("relative_path")?,
+                            r.get("bytes")?,
+                        ))
+                    })?
+                    .map(|row| {
+                        let (file_id, uri, subpath, size) = row?;
+                        Ok(OutgoingFileToRetry {
+                            file_id,
+                            uri: uri.parse()?,
+                            subpath,
+                            size,
+                        })
+                    })
+                    .collect::<Result<_>>()?;
+
+                out.push(OutgoingTransferToRetry {
+                    uuid: rec_transfer.tid.parse().map_err(|err| {
+                        crate::Error::InternalError(format!("Failed to parse UUID: {err}"))
+                    })?,
+                    peer: rec_transfer.peer,
+                    created_at: rec_transfer.created_at,
+                    files,
+                });
+            }
+
+            conn.commit()?;
+
+            Ok::<Vec<_>, Error>(out)
+        };
+
+        match task.await {
+            Ok(transfers) => transfers,
+            Err(e) => {
+                error!(self.logger, "Failed to get outgoing transfers to resume"; "error" => %e);
+                (self.on_error)("outgoing_transfers_to_resume", &e);
+                vec![]
+            }
+        }
+    }
+
+    /// Returns the number of the incoming and outgoing transfers to resume,
+    /// without loading them
+    pub async fn count_transfers_to_resume(&self) -> (usize, usize) {
+        let task = async {
+            let conn = self.conn.lock().await;
+            sync::count_transfers_to_resume(&conn)
+        };
+
+        match task.await {
+            Ok(counts) => counts,
+            Err(e) => {
+                error!(self.logger, "Failed to count transfers to resume"; "error" => %e);
+                (self.on_error)("count_transfers_to_resume", &e);
+                (0, 0)
+            }
+        }
+    }
+
+    pub async fn incoming_transfers_to_resume(&self) -> Vec<IncomingTransferToRetry> {
+        let task = async {
+            let mut conn = self.conn.lock().await;
+            let conn = conn.transaction()?;
+
+            let rec_transfers = sync::transfers_to_resume(&conn, TransferType::Incoming)?;
+
+            let mut out = Vec::with_capacity(rec_transfers.len());
+            for rec_transfer in rec_transfers {
+                let files = conn
+                    .prepare(
+                        r#"
+                    SELECT relative_path, path_hash, bytes 
+                    FROM incoming_paths 
+                    WHERE transfer_id = ?1
+                    "#,
+                    )?
+                    .query_map(params![rec_transfer.tid], |r| {
+                        Ok(IncomingFileToRetry {
+                            file_id: r.get("path_hash")?,
+                            subpath: r.get("relative_path")?,
+                            size: r.get("bytes")?,
+                        })
+                    })?
+                    .collect::<QueryResult<_>>()?;
+
+                out.push(IncomingTransferToRetry {
+                    uuid: rec_transfer.tid.parse().map_err(|err| {
+                        crate::Error::InternalError(format!("Failed to parse UUID: {err}"))
+                    })?,
+                    peer: rec_transfer.peer,
+                    created_at: rec_transfer.created_at,
+                    files,
+                });
+            }
+
+            conn.commit()?;
+            Ok::<Vec<_>, Error>(out)
+        };
+
+        match task.await {
+            Ok(transfers) => transfers,
+            Err(e) => {
+                error!(self.logger, "Failed to get incoming transfers to resume"; "error" => %e);
+                (self.on_error)("incoming_transfers_to_resume", &e);
+                vec![]
+            }
+        }
+    }
+
+    pub async fn incoming_files_to_resume(&self, transfer_id: Uuid) -> Vec<sync::FileInFlight> {
+        let task = async {
+            let conn = self.conn.lock().await;
+            sync::incoming_files_in_flight(&conn, transfer_id)
+        };
+
+        match task.await {
+            Ok(files) => files,
+            Err(e) => {
+                error!(self.logger, "Failed to get incoming files to resume"; "error" => %e);
+                (self.on_error)("incoming_files_to_resume", &e);
+                vec![]
+            }
+        }
+    }
+
+    pub async fn finished_incoming_files(&self, transfer_id: Uuid) -> Vec<FinishedIncomingFile> {
+        let task = async {
+            let conn = self.conn.lock().await;
+
+            let paths = conn
+                .prepare(
+                    r#"
+                SELECT relative_path as subpath, final_path, was_renamed
+                FROM incoming_paths ip
+                INNER JOIN incoming_path_completed_states ipcs ON ip.id = ipcs.path_id
+                WHERE transfer_id = ?1
+                "#,
+                )?
+                .query_map(params![transfer_id.to_string()], |r| {
+                    Ok(FinishedIncomingFile {
+                        subpath: r.get("subpath")?,
+                        final_path: r.get("final_path")?,
+                        was_renamed: r.get("was_renamed")?,
+                    })
+                })?
+                .collect::<QueryResult<_>>()?;
+
+            Ok::<Vec<_>, Error>(paths)
+        };
+
+        match task.await {
+            Ok(paths) => paths,
+            Err(e) => {
+                error!(self.logger, "Failed to get finished incoming files"; "error" => %e);
+                (self.on_error)("finished_incoming_files", &e);
+                vec![]
+            }
+        }
+    }
+
+    pub async fn insert_incoming_dir_mapping(
+        &self,
+        transfer_id: Uuid,
+        path: &str,
+        mapped_name: &str,
+    ) {
+        let tid = transfer_id.to_string();
+
+        trace!(
+            self.logger,
+            "Inserting incoming dir mapping";
+            "transfer_id" => &tid,
+            "mapped_name" => mapped_name,
+        );
+
+        let task = async {
+            let conn = self.conn.lock().await;
+            conn.execute(
+                "INSERT OR REPLACE INTO incoming_dir_mappings (transfer_id, path, mapped_name) \
+                 VALUES (?1, ?2, ?3)",
+                params![tid, path, mapped_name],
+            )?;
+
+            Ok::<(), Error>(())
+        };
+
+        if let Err(e) = task.await {
+            error!(self.logger, "Failed to insert incoming dir mapping"; "error" => %e);
+            (self.on_error)("insert_incoming_dir_mapping", &e);
+        }
+    }
+
+    pub async fn incoming_dir_mappings(&self, transfer_id: Uuid) -> Vec<IncomingDirMapping> {
+        let task = async {
+            let conn = self.conn.lock().await;
+
+            let mappings = conn
+                .prepare(
+                    "SELECT path, mapped_name FROM incoming_dir_mappings WHERE transfer_id = ?1",
+                )?
+                .query_map(params![transfer_id.to_string()], |r| {
+                    Ok(IncomingDirMapping {
+                        path: r.get("path")?,
+                        mapped_name: r.get("mapped_name")?,
+                    })
+                })?
+                .collect::<QueryResult<_>>()?;
+
+            Ok::<Vec<_>, Error>(mappings)
+        };
+
+        match task.await {
+            Ok(mappings) => mappings,
+            Err(e) => {
+                error!(self.logger, "Failed to get incoming dir mappings"; "error" => %e);
+                (self.on_error)("incoming_dir_mappings", &e);
+                vec![]
+            }
+        }
+    }
+
+    pub async fn completed_incoming_file(
+        &self,
+        transfer_id: Uuid,
+        file_id: &str,
+    ) -> Option<CompletedIncomingFile> {
+        let task = async {
+            let conn = self.conn.lock().await;
+
+            let file = conn
+                .query_row(
+                    r#"
+                SELECT final_path, deleted, checksum
+                FROM incoming_paths ip
+                INNER JOIN incoming_path_completed_states ipcs ON ip.id = ipcs.path_id
+                WHERE transfer_id = ?1 AND path_hash = ?2
+                ORDER BY ipcs.created_at DESC
+                LIMIT 1
+                "#,
+                    params![transfer_id.to_string(), file_id],
+                    |r| {
+                        Ok(CompletedIncomingFile {
+                            final_path: r.get("final_path")?,
+                            deleted: r.get("deleted")?,
+                            checksum: r.get("checksum")?,
+                        })
+                    },
+                )
+                .optional()?;
+
+            Ok::<_, Error>(file)
+        };
+
+        match task.await {
+            Ok(file) => file,
+            Err(e) => {
+                error!(self.logger, "Failed to get completed incoming file"; "error" => %e);
+                (self.on_error)("completed_incoming_file", &e);
+                None
+            }
+        }
+    }
+
+    /// Returns the number of bytes sent to and received from the peer. Only
+    /// the files that reached the terminal state are accounted
+    pub async fn peer_usage(&self, peer: &str) -> PeerUsage {
+        let task = async {
+            let conn = self.conn.lock().await;
+
+            let usage = conn
+                .query_row(
+                    r#"
+                SELECT bytes_sent, bytes_received, updated_at
+                FROM peer_usage
+                WHERE peer = ?1
+                "#,
+                    params![peer],
+                    |r| {
+                        Ok(PeerUsage {
+                            bytes_sent: r.get("bytes_sent")?,
+                            bytes_received: r.get("bytes_received")?,
+                            updated_at: r.get("updated_at")?,
+                        })
+                    },
+                )
+                .optional()?;
+
+            Ok::<_, Error>(usage)
+        };
+
+        match task.await {
+            Ok(usage) => usage.unwrap_or_default(),
+            Err(e) => {
+                error!(self.logger, "Failed to get peer usage"; "error" => %e);
+                (self.on_error)("peer_usage", &e);
+                PeerUsage::default()
+            }
+        }
+    }
+
+    pub async fn reset_peer_usage(&self, peer: &str) {
+        let task = async {
+            let conn = self.conn.lock().await;
+            conn.execute("DELETE FROM peer_usage WHERE peer = ?1", params![peer])?;
+
+            Ok::<(), Error>(())
+        };
+
+        if let Err(e) = task.await {
+            error!(self.logger, "Failed to reset peer usage"; "error" => %e);
+            (self.on_error)("reset_peer_usage", &e);
+        }
+    }
+
+    /// Returns the peers of the non-deleted transfers along with the number of
+    /// the transfers, most recently seen peers first
+    pub async fn peers_summary(&self) -> Vec<PeerSummary> {
+        trace!(self.logger, "Fetching peers summary");
+
+        let task = async {
+            let conn = self.conn.lock().await;
+
+            let peers = conn
+                .prepare(
+                    r#"
+                SELECT peer, COUNT(*) AS transfer_count, MAX(created_at) AS last_transfer_at
+                FROM transfers
+                WHERE NOT is_deleted
+                GROUP BY peer
+                ORDER BY last_transfer_at DESC, MAX(seq) DESC
+                "#,
+                )?
+                .query_map([], |r| {
+                    Ok(PeerSummary {
+                        peer: r.get("peer")?,
+                        transfer_count: r.get("transfer_count")?,
+                        last_transfer_at: r.get("last_transfer_at")?,
+                    })
+                })?
+                .collect::<QueryResult<Vec<_>>>()?;
+
+            Ok::<_, Error>(peers)
+        };
+
+        match task.await {
+            Ok(peers) => peers,
+            Err(e) => {
+                error!(self.logger, "Failed to get peers summary"; "error" => %e);
+                (self.on_error)("peers_summary", &e);
+                vec![]
+            }
+        }
+    }
+
+    /// Returns the state of the transfer as recorded in the database or `None`
+    /// if the transfer is not known. A transfer is completed once all of its
+    /// files reached the terminal state
+    pub async fn stored_transfer_state(&self, transfer_id: Uuid) -> Option<StoredTransferState> {
+        let task = async {
+            let conn = self.conn.lock().await;
+
+            let state = conn
+                .query_row(
+                    &format!(
+                        r#"
+                SELECT {TRANSFER_STATE_COLUMNS}
+                FROM transfers t
+                WHERE t.id = ?1 AND NOT t.is_deleted
+                "#
+                    ),
+                    params![transfer_id.to_string()],
+                    stored_transfer_state_from_row,
+                )
+                .optional()?;
+
+            Ok::<_, Error>(state)
+        };
+
+        match task.await {
+            Ok(state) => state,
+            Err(e) => {
+                error!(self.logger, "Failed to get stored transfer state"; "error" => %e);
+                (self.on_error)("stored_transfer_state", &e);
+                None
+            }
+        }
+    }
+
+    /// Returns the IDs of the transfers created since the given timestamp
+    /// whose state matches any of the given filters, in the requested order.
+    /// The state is classified the same way as in `stored_transfer_state()`
+    pub async fn transfers_by_state(
+        &self,
+        states: &[TransferFilter],
+        since_timestamp: i64,
+        sort: TransferSort,
+    ) -> Vec<Uuid> {
+        trace!(
+        self.logger,
+        "Fetching transfers by state";
+        "states" => ?states,
+        "since_timestamp" => since_timestamp,
+        "sort" => ?sort);
+
+        if states.is_empty() {
+            return vec![];
+        }
+
+        let condition = states
+            .iter()
+            .map(|filter| format!("({})", filter.condition()))
+            .collect::<Vec<_>>()
+            .join(" OR ");
+
+        let task = async {
+            let conn = self.conn.lock().await;
+
+            let ids = conn
+                .prepare(&format!(
+                    r#"
+                SELECT t.id FROM (
+                    SELECT t.id, t.peer, t.seq, {TRANSFER_STATE_COLUMNS}
+                    FROM transfers t
+                    WHERE NOT t.is_deleted AND t.created_at >= datetime(?1, 'unixepoch')
+                ) t
+                WHERE {condition}
+                ORDER BY {}
+                "#,
+                    sort.order_by()
+                ))?
+                .query_map(params![since_timestamp], |r| {
+                    Uuid::parse_str(&r.get::<_, String>("id")?)
+                        .map_err(|_| rusqlite::Error::InvalidQuery)
+                })?
+                .collect::<QueryResult<_>>()?;
+
+            Ok::<Vec<_>, Error>(ids)
+        };
+
+        match task.await {
+            Ok(ids) => ids,
+            Err(e) => {
+                error!(self.logger, "Failed to get transfers by state"; "error" => %e);
+                (self.on_error)("transfers_by_state", &e);
+                vec![]
+            }
+        }
+    }
+
+    pub async fn transfers_since(&self, since_timestamp: i64, sort: TransferSort) -> Vec<Transfer> {
+        // Collect transfers since a given timestamp.
+        // This performs 3 queries, fetching by insertion order:
+        // 1. transfers with their states.
+        // 2. outgoing paths with their states
+        // 3. incoming paths with their states
+        // Because a single query is used for transfers and their states
+        // (the same applies to paths as well), a hashmap is used to collect states for
+        // each transfer. For each state their transfer is taken from a hash map
+        // (or inserted), and this transfers state list is appended.
+        // The transfers are fetched in the requested order and the position of
+        // their first row is used to sort them afterwards. Because its not part
+        // of `Transfer` structure, a tuple is used as hashmap value.
+        trace!(
+        self.logger,
+        "Fetching transfers since timestamp";
+        "since_timestamp" => since_timestamp,
+        "sort" => ?sort);
+
+        let task = async {
+            let mut conn = self.conn.lock().await;
+            let mut transfers_map: HashMap<Uuid, (usize, Transfer)> = HashMap::new();
+            let tx = conn.transaction()?;
+            // transfer_cancel_states.by_peer shares a type with
             // transfer_failed_states.status_code and transfer_cancel_states.
             // created_at with transfer_failed_states.created_at therefore the
-            // same column can be used for them.
+            // same column can be used for them. The cancel cause has no
+            // counterpart in the failed states, the reject states keep the
+            // reason in its column.
             let _ = tx
+                .prepare(&format!(
+                    r#"
+                WITH ts AS  (
+                    select 1, id, transfer_id, by_peer, cause, created_at from transfer_cancel_states
+                    union all
+                    select 2, id, transfer_id, status_code, null, created_at from transfer_failed_states
+                    union all
+                    select 3, id, transfer_id, by_peer, reason, created_at from transfer_reject_states
+                )
+                select t.id, t.peer, t.is_outgoing, t.created_at, t.is_deleted, ts.* from transfers t
+                    left join ts on ts.transfer_id = t.id
+                    where not t.is_deleted and t.created_at >= datetime(?1, 'unixepoch')
+                    order by {}
+                "#,
+                    sort.order_by()
+                ))?
+                .query_map(params![since_timestamp], |row| {
+                    let id = Uuid::parse_str(row.get::<_, String>(0)?.as_str())
+                        .map_err(|_| rusqlite::Error::InvalidQuery)?;
+                    let position = transfers_map.len();
+                    let transfer: &mut Transfer = &mut match transfers_map.entry(id) {
+                        Occupied(e) => e.into_mut(),
+                        Vacant(k) => {
+                            let transfer_type = match row.get::<_, u32>(2)? {
+                                0 => DbTransferType::Incoming(vec![]),
+                                1 => DbTransferType::Outgoing(vec![]),
+                                _ => unreachable!(),
+                            };
+                            let t = Transfer {
+                                id,
+                                peer_id: row.get(1)?,
+                                transfer_type,
+                                created_at: row.get(3)?,
+                                states: vec![],
+                                tags: vec![],
+                            };
+                            k.insert((position, t))
+                        }
+                    }
+                    .1;
+                    let status_type: Option<i64> = row.get(5)?;
+                    match status_type {
+                        Some(1) => transfer.states.push(TransferStateEvent {
+                            transfer_id: transfer.id,
+                            created_at: row.get(10)?,
+                            data: types::TransferStateEventData::Cancel {
+                                by_peer: row.get(8)?,
+                                cause: row.get(9)?,
+                            },
+                        }),
+                        Some(2) => transfer.states.push(TransferStateEvent {
+                            transfer_id: transfer.id,
+                            created_at: row.get(10)?,
+                            data: types::TransferStateEventData::Failed {
+                                status_code: row.get(8)?,
+                            },
+                        }),
+                        Some(3) => transfer.states.push(TransferStateEvent {
+                            transfer_id: transfer.id,
+                            created_at: row.get(10)?,
+                            data: types::TransferStateEventData::Rejected {
+                                by_peer: row.get(8)?,
+                                reason: row.get(9)?,
+                            },
+                        }),
+                        Some(other) => warn!(
+                                        self.logger,
+                                        "Unexpected union member identifier for transfer state";
+                                        "identifier" => other),
+                        None => {
+                            // This was a transfer without any states.
+                        }
+                    }
+                    Ok(())
+                })?
+                .count();
+
+            let mut outgoing_paths: HashMap<i64, OutgoingPath> = HashMap::new();
+            // Here is the same situation as before - because the columns after created_at
+            // are all integers, they can be shared.
+            let _ = tx.prepare(r#"
+            WITH ops AS (
+                select 1, path_id, created_at, bytes_sent, null from outgoing_path_started_states
+                union all
+                select 2, path_id, created_at, status_code, bytes_sent from outgoing_path_failed_states
+                union all
+                select 3, path_id, created_at, null, null from outgoing_path_completed_states
+                union all
+                select 4, path_id, created_at, by_peer, bytes_sent from outgoing_path_reject_states
+                union all
+                select 5, path_id, created_at, bytes_sent, null from outgoing_path_paused_states
+            )
+            SELECT op.*, ops.*, op.rowid from outgoing_paths op
+                left join ops on ops.path_id = op.id
+                left join transfers t on t.id = op.transfer_id and not t.is_deleted and t.created_at >= datetime(?1, 'unixepoch')
+                where not op.is_deleted
+            "#)?.query_map(params![since_timestamp], |row| {
+                let path_id: i64 = row.get(0)?;
+                let path = match outgoing_paths.entry(path_id) {
+                    Occupied(p) => p.into_mut(),
+                    Vacant(e) => {
+                        let transfer_id: String = row.get(1)?;
+                        let mut res = OutgoingPath {
+                            id: *e.key(),
+                            transfer_id: Uuid::parse_str(&transfer_id).map_err(|_| rusqlite::Error::InvalidQuery)?,
+                            content_uri: None,
+                            base_path: None,
+                            relative_path: row.get(2)?,
+                            file_id: row.get(4)?,
+                            bytes: row.get(5)?,
+                            bytes_sent: 0,
+                            created_at: row.get(6)?,
+                            states: vec![],
+                        };
+                        let uri_str: String = row.get(3)?;
+                        let uri = url::Url::parse(&uri_str).map_err(|_| rusqlite::Error::InvalidQuery)?; // Error handling like uuid
+
+                        match uri.scheme() {
+                            "content" => res.content_uri = Some(uri),
+                            // The in-memory files have no location to report
+                            "memory" => (),
+                            "file" => {
+                                let mut path = uri.to_file_path().map_err(|_| rusqlite::Error::InvalidQuery)?; // Error handling like uuid
+
+                                let count = Path::new(&res.relative_path).components().count();
+                                for _ in 0..count {
+                                    path.pop();
+                                }
+
+                                res.base_path = Some(path);
+                            }
+                            unknown => {
+                                warn!(
+                                        self.logger,
+                                        "Unexpected URI scheme when decoding transfer outgoing path's base_path";
+                                        "scheme" => unknown,
+                                    "uri" => uri.to_string());
+                                return Err(rusqlite::Error::InvalidQuery);
+                            }
+                        }
+                        e.insert(res)
+                    }
+                };
+
+                let opt_status_type: Option<i32> = row.get(8)?;
+                if let Some(status_type) = opt_status_type {
+                    let created_at = row.get(10)?;
+                    match status_type {
+                        1 => path.states.push(OutgoingPathStateEvent {
+                            path_id,
+                            created_at,
+                            data: OutgoingPathStateEventData::Started {
+                                bytes_sent: row.get(11)?,
+                            },
+                        }),
+                        2 => path.states.push(OutgoingPathStateEvent {
+                            path_id,
+                            created_at,
+                            data: OutgoingPathStateEventData::Failed {
+                                status_code: row.get(11)?,
+                                bytes_sent: row.get(12)?,
+                            },
+                        }),
+                        3 => path.states.push(OutgoingPathStateEvent {
+                            path_id,
+                            created_at,
+                            data: OutgoingPathStateEventData::Completed,
+                        }),
+                        4 => path.states.push(OutgoingPathStateEvent {
+                            path_id,
+                            created_at,
+                            data: OutgoingPathStateEventData::Rejected {
+                                by_peer: row.get(11)?,
+                                bytes_sent: row.get(12)?,
+                            },
+                        }),
+                        5 => path.states.push(OutgoingPathStateEvent {
+                            path_id,
+                            created_at,
+                            data: OutgoingPathStateEventData::Paused {
+                                bytes_sent: row.get(11)?
+                            },
+                        }),
+                        other => warn!(
+                                        self.logger,
+                                        "Unexpected union member identifier for outgoing path status";
+                                        "identifier" => other)
+                    }
+                }
+
+                Ok(())
+            })?.count();
+
+            for (_, mut path) in outgoing_paths {
+                path.states.sort_by(|a, b| a.created_at.cmp(&b.created_at));
+
+                path.bytes_sent = path.states.last().map_or(0, |state| match state.data {
+                    OutgoingPathStateEventData::Started { bytes_sent } => bytes_sent,
+                    OutgoingPathStateEventData::Failed { bytes_sent, .. } => bytes_sent,
+                    OutgoingPathStateEventData::Completed => path.bytes,
+                    OutgoingPathStateEventData::Rejected { bytes_sent, .. } => bytes_sent,
+                    OutgoingPathStateEventData::Paused { bytes_sent } => bytes_sent,
+                });
+                if let Some((_, t)) = transfers_map.get_mut(&path.transfer_id) {
+                    if let DbTransferType::Outgoing(pp) = &mut t.transfer_type {
+                        pp.push(path)
+                    }
+                }
+            }
+
+            let mut incoming_paths: HashMap<i64, IncomingPath> = HashMap::new();
+            // And this is more interesting - base_ir and final_patch are text type. For
+            // these fields a separate column will be used.
+            let _ = tx.prepare(r#"
+            WITH ips AS (
+                select 1, path_id, created_at, null, null, base_dir from incoming_path_pending_states
+                union all
+                select 2, path_id, created_at, bytes_received, null, null from incoming_path_started_states
+                union all
+                select 3, path_id, created_at, status_code, bytes_received, null from incoming_path_failed_states
+                union all
+                select 4, path_id, created_at, was_renamed, deleted, final_path from incoming_path_completed_states
+                union all
+                select 5, path_id, created_at, by_peer, bytes_received, null from incoming_path_reject_states
+                union all
+                select 6, path_id, created_at, bytes_received, null, null from incoming_path_paused_states
+            )
+            SELECT ip.*, ips.* from incoming_paths ip
+                left join ips on ips.path_id = ip.id
+                left join transfers t on t.id = ip.transfer_id and not t.is_deleted and t.created_at >= datetime(?1, 'unixepoch')
+                where not ip.is_deleted
+                order by ip.rowid
+            "#)?.query_map(params![since_timestamp], |row| {
+                let path_id: i64 = row.get(0)?;
+                let path = match incoming_paths.entry(path_id) {
+                    Occupied(p) => p.into_mut(),
+                    Vacant(e) => {
+                        let transfer_id: String = row.get(1)?;
+                        let res = IncomingPath {
+                            id: *e.key(),
+                            transfer_id: Uuid::parse_str(&transfer_id).map_err(|_| rusqlite::Error::InvalidQuery)?,
+                            relative_path: row.get(2)?,
+                            file_id: row.get(3)?,
+                            bytes: row.get(4)?,
+                            bytes_received: 0,
+                            created_at: row.get(5)?,
+                            states: vec![],
+                        };
+                        e.insert(res)
+                    }
+                };
+
+                let opt_status_type: Option<i32> = row.get(8)?;
+                if let Some(status_type) = opt_status_type {
+                    let created_at = row.get(10)?;
+                    match status_type {
+                        1 => path.states.push(IncomingPathStateEvent {
+                            path_id,
+                            created_at,
+                            data: IncomingPathStateEventData::Pending {
+                                base_dir: row.get(13)?
+                            },
+                        }),
+                        2 => path.states.push(IncomingPathStateEvent {
+                            path_id,
+                            created_at,
+                            data: IncomingPathStateEventData::Started {
+                                bytes_received: row.get(11)?
+                            },
+                        }),
+                        3 => path.states.push(IncomingPathStateEvent {
+                            path_id,
+                            created_at,
+                            data: IncomingPathStateEventData::Failed {
+                                status_code: row.get(11)?,
+                                bytes_received: row.get(12)?,
+                            },
+                        }),
+                        4 => path.states.push(IncomingPathStateEvent {
+                            path_id,
+                            created_at,
+                            data: IncomingPathStateEventData::Completed {
+                                final_path: row.get(13)?,
+                                was_renamed: row.get(11)?,
+                                deleted: row.get(12)?,
+                            },
+                        }),
+                        5 => path.states.push(IncomingPathStateEvent {
+                            path_id,
+                            created_at,
+                            data: IncomingPathStateEventData::Rejected {
+                                by_peer: row.get(11)?,
+                                bytes_received: row.get(12)?,
+                            },
+                        }),
+                        6 => path.states.push(IncomingPathStateEvent {
+                            path_id,
+                            created_at,
+                            data: IncomingPathStateEventData::Paused {
+                                bytes_received: row.get(11)?
+                            },
+                        }),
+                        _ => {}
+                    }
+                }
+
+                Ok(())
+            })?.count();
+
+            for (_, mut path) in incoming_paths {
+                path.states.sort_by(|a, b| a.created_at.cmp(&b.created_at));
+
+                path.bytes_received = path
+                    .states
+                    .iter()
+                    .rev()
+                    .find_map(|state| match state.data {
+                        IncomingPathStateEventData::Pending { .. } => None,
+                        IncomingPathStateEventData::Started { bytes_received, .. } => {
+                            Some(bytes_received)
+                        }
+                        IncomingPathStateEventData::Failed { bytes_received, .. } => {
+                            Some(bytes_received)
+                        }
+                        IncomingPathStateEventData::Completed { .. } => Some(path.bytes),
+                        IncomingPathStateEventData::Rejected { bytes_received, .. } => {
+                            Some(bytes_received)
+                        }
+                        IncomingPathStateEventData::Paused { bytes_received } => {
+                            Some(bytes_received)
+                        }
+                    })
+                    .unwrap_or(0);
+
+                if let Some((_, t)) = transfers_map.get_mut(&path.transfer_id) {
+                    if let DbTransferType::Incoming(ip) = &mut t.transfer_type {
+                        ip.push(path)
+                    }
+                }
+            }
+            for (id, (_, transfer)) in &mut transfers_map {
+                transfer.tags = Self::transfer_tags_in(&tx, &id.to_string())?;
+            }
+
+            drop(tx);
+            drop(conn);
+            let mut transfers: Vec<(usize, Transfer)> = transfers_map.into_values().collect();
+            transfers.sort_by_key(|rt| rt.0);
+            let mut transfers: Vec<Transfer> = transfers.into_iter().map(|rt| rt.1).collect();
+            for transfer in &mut transfers {
+                transfer
+                    .states
+                    .sort_by(|a, b| a.created_at.cmp(&b.created_at));
+                match transfer.transfer_type {
+                    DbTransferType::Incoming(ref mut p) => p.sort_by_key(|ip| ip.id),
+                    DbTransferType::Outgoing(ref mut p) => p.sort_by_key(|op| op.id),
+                };
+            }
+            Ok::<Vec<_>, Error>(transfers)
+        };
+
+        match task.await {
+            Ok(transfers) => transfers,
+            Err(e) => {
+                error!(self.logger, "Failed to get transfers since timestamp"; "error" => %e);
+                (self.on_error)("transfers_since", &e);
+                vec![]
+            }
+        }
+    }
+
+    /// Returns the transfer and file states of the given transfer recorded
+    /// after the `after_created_at` cursor (milliseconds since the UNIX
+    /// epoch), ordered by their creation time. Passing the `created_at` of
+    /// the last seen event as the cursor yields only the newer ones
+    pub async fn transfer_events_since(
+        &self,
+        transfer_id: Uuid,
+        after_created_at: i64,
+    ) -> Vec<StateEvent> {
+        trace!(
+            self.logger,
+            "Fetching transfer events since cursor";
+            "transfer_id" => %transfer_id,
+            "after_created_at" => after_created_at);
+
+        let task = async {
+            let conn = self.conn.lock().await;
+            let tid = transfer_id.to_string();
+            let cursor = format_timestamp_millis(after_created_at);
+            let mut events = Vec::new();
+
+            conn.prepare(
+                r#"
+                SELECT * FROM (
+                    SELECT 1, created_at, by_peer, cause FROM transfer_cancel_states
+                        WHERE transfer_id = ?1
+                    UNION ALL
+                    SELECT 2, created_at, status_code, null FROM transfer_failed_states
+                        WHERE transfer_id = ?1
+                    UNION ALL
+                    SELECT 3, created_at, by_peer, reason FROM transfer_reject_states
+                        WHERE transfer_id = ?1
+                )
+                WHERE created_at > ?2
+                ORDER BY created_at
+                "#,
+            )?
+            .query_map(params![tid, cursor], |row| {
+                let data = match row.get::<_, i64>(0)? {
+                    1 => types::TransferStateEventData::Cancel {
+                        by_peer: row.get(2)?,
+                        cause: row.get(3)?,
+                    },
+                    3 => types::TransferStateEventData::Rejected {
+                        by_peer: row.get(2)?,
+                        reason: row.get(3)?,
+                    },
+                    _ => types::TransferStateEventData::Failed {
+                        status_code: row.get(2)?,
+                    },
+                };
+
+                Ok(StateEvent::Transfer(TransferStateEvent {
+                    transfer_id,
+                    created_at: row.get(1)?,
+                    data,
+                }))
+            })?
+            .try_for_each(|ev| {
+                events.push(ev?);
+                Ok::<(), Error>(())
+            })?;
+
+            conn.prepare(
+                r#"
+                WITH ops AS (
+                    SELECT 1 AS kind, path_id, created_at, bytes_sent AS a, null AS b FROM outgoing_path_started_states
+                    UNION ALL
+                    SELECT 2, path_id, created_at, status_code, bytes_sent FROM outgoing_path_failed_states
+                    UNION ALL
+                    SELECT 3, path_id, created_at, null, null FROM outgoing_path_completed_states
+                    UNION ALL
+                    SELECT 4, path_id, created_at, by_peer, bytes_sent FROM outgoing_path_reject_states
+                    UNION ALL
+                    SELECT 5, path_id, created_at, bytes_sent, null FROM outgoing_path_paused_states
+                )
+                SELECT ops.kind, ops.path_id, ops.created_at, ops.a, ops.b FROM ops
+                    INNER JOIN outgoing_paths op ON op.id = ops.path_id
+                    WHERE op.transfer_id = ?1 AND NOT op.is_deleted AND ops.created_at > ?2
+                    ORDER BY ops.created_at
+                "#,
+            )?
+            .query_map(params![tid, cursor], |row| {
+                let data = match row.get::<_, i64>(0)? {
+                    1 => OutgoingPathStateEventData::Started {
+                        bytes_sent: row.get(3)?,
+                    },
+                    2 => OutgoingPathStateEventData::Failed {
+                        status_code: row.get(3)?,
+                        bytes_sent: row.get(4)?,
+                    },
+                    3 => OutgoingPathStateEventData::Completed,
+                    4 => OutgoingPathStateEventData::Rejected {
+                        by_peer: row.get(3)?,
+                        bytes_sent: row.get(4)?,
+                    },
+                    _ => OutgoingPathStateEventData::Paused {
+                        bytes_sent: row.get(3)?,
+                    },
+                };
+
+                Ok(StateEvent::OutgoingPath(OutgoingPathStateEvent {
+                    path_id: row.get(1)?,
+                    created_at: row.get(2)?,
+                    data,
+                }))
+            })?
+            .try_for_each(|ev| {
+                events.push(ev?);
+                Ok::<(), Error>(())
+            })?;
+
+            conn.prepare(
+                r#"
+                WITH ips AS (
+                    SELECT 1 AS kind, path_id, created_at, null AS a, null AS b, base_dir AS c FROM incoming_path_pending_states
+                    UNION ALL
+                    SELECT 2, path_id, created_at, bytes_received, null, null FROM incoming_path_started_states
+                    UNION ALL
+                    SELECT 3, path_id, created_at, status_code, bytes_received, null FROM incoming_path_failed_states
+                    UNION ALL
+                    SELECT 4, path_id, created_at, was_renamed, deleted, final_path FROM incoming_path_completed_states
+                    UNION ALL
+                    SELECT 5, path_id, created_at, by_peer, bytes_received, null FROM incoming_path_reject_states
+                    UNION ALL
+                    SELECT 6, path_id, created_at, bytes_received, null, null FROM incoming_path_paused_states
+                )
+                SELECT ips.kind, ips.path_id, ips.created_at, ips.a, ips.b, ips.c FROM ips
+                    INNER JOIN incoming_paths ip ON ip.id = ips.path_id
+                    WHERE ip.transfer_id = ?1 AND NOT ip.is_deleted AND ips.created_at > ?2
+                    ORDER BY ips.created_at
+                "#,
+            )?
+            .query_map(params![tid, cursor], |row| {
+                let data = match row.get::<_, i64>(0)? {
+                    1 => IncomingPathStateEventData::Pending {
+                        base_dir: row.get(5)?,
+                    },
+                    2 => IncomingPathStateEventData::Started {
+                        bytes_received: row.get(3)?,
+                    },
+                    3 => IncomingPathStateEventData::Failed {
+                        status_code: row.get(3)?,
+                        bytes_received: row.get(4)?,
+                    },
+                    4 => IncomingPathStateEventData::Completed {
+                        final_path: row.get(5)?,
+                        was_renamed: row.get(3)?,
+                        deleted: row.get(4)?,
+                    },
+                    5 => IncomingPathStateEventData::Rejected {
+                        by_peer: row.get(3)?,
+                        bytes_received: row.get(4)?,
+                    },
+                    _ => IncomingPathStateEventData::Paused {
+                        bytes_received: row.get(3)?,
+                    },
+                };
+
+                Ok(StateEvent::IncomingPath(IncomingPathStateEvent {
+                    path_id: row.get(1)?,
+                    created_at: row.get(2)?,
+                    data,
+                }))
+            })?
+            .try_for_each(|ev| {
+                events.push(ev?);
+                Ok::<(), Error>(())
+            })?;
+
+            // The sort is stable so the events recorded at the same time keep
+            // the transfer, outgoing, incoming order
+            events.sort_by_key(StateEvent::created_at);
+            Ok::<Vec<_>, Error>(events)
+        };
+
+        match task.await {
+            Ok(events) => events,
+            Err(e) => {
+                error!(self.logger, "Failed to get transfer events since cursor"; "error" => %e);
+                (self.on_error)("transfer_events_since", &e);
+                vec![]
+            }
+        }
+    }
+
+    /// Attaches the tag to the non-deleted transfer. Tagging the transfer
+    /// again with the same tag is a no-op. Returns `None` if there's no such
+    /// transfer
+    pub async fn add_transfer_tag(&self, transfer_id: Uuid, tag: &str) -> Option<()> {
+        let tid = transfer_id.to_string();
+
+        trace!(
+            self.logger,
+            "Adding transfer tag";
+            "transfer_id" => &tid,
+            "tag" => tag,
+        );
+
+        let task = async {
+            let conn = self.conn.lock().await;
+
+            let count = conn.execute(
+                r#"
+                INSERT OR IGNORE INTO transfer_tags (transfer_id, tag)
+                SELECT id, ?2 FROM transfers WHERE id = ?1 AND NOT is_deleted
+                "#,
+                params![tid, tag],
+            )?;
+
+            if count > 0 {
+                return Ok::<Option<()>, Error>(Some(()));
+            }
+
+            let exists = conn
+                .query_row(
+                    "SELECT 1 FROM transfers WHERE id = ?1 AND NOT is_deleted",
+                    params![tid],
+                    |_| Ok(()),
+                )
+                .optional()?;
+
+            Ok(exists)
+        };
+
+        match task.await {
+            Ok(res) => res,
+            Err(e) => {
+                error!(self.logger, "Failed to add transfer tag"; "error" => %e);
+                (self.on_error)("add_transfer_tag", &e);
+                None
+            }
+        }
+    }
+
+    /// Detaches the tag from the transfer. Returns `None` if the transfer
+    /// wasn't tagged with it
+    pub async fn remove_transfer_tag(&self, transfer_id: Uuid, tag: &str) -> Option<()> {
+        let tid = transfer_id.to_string();
+
+        trace!(
+            self.logger,
+            "Removing transfer tag";
+            "transfer_id" => &tid,
+            "tag" => tag,
+        );
+
+        let task = async {
+            let conn = self.conn.lock().await;
+
+            let count = conn.execute(
+                "DELETE FROM transfer_tags WHERE transfer_id = ?1 AND tag = ?2",
+                params![tid, tag],
+            )?;
+
+            Ok::<_, Error>((count > 0).then_some(()))
+        };
+
+        match task.await {
+            Ok(res) => res,
+            Err(e) => {
+                error!(self.logger, "Failed to remove transfer tag"; "error" => %e);
+                (self.on_error)("remove_transfer_tag", &e);
+                None
+            }
+        }
+    }
+
+    /// Returns the tags of the transfer in the order they were added
+    pub async fn transfer_tags(&self, transfer_id: Uuid) -> Vec<String> {
+        let task = async {
+            let conn = self.conn.lock().await;
+            Self::transfer_tags_in(&conn, &transfer_id.to_string())
+        };
+
+        match task.await {
+            Ok(tags) => tags,
+            Err(e) => {
+                error!(self.logger, "Failed to get transfer tags"; "error" => %e);
+                (self.on_error)("transfer_tags", &e);
+                vec![]
+            }
+        }
+    }
+
+    fn transfer_tags_in(conn: &Connection, transfer_id: &str) -> Result<Vec<String>> {
+        let tags = conn
+            .prepare(
+                r#"
+                SELECT tag FROM transfer_tags
+                WHERE transfer_id = ?1
+                ORDER BY created_at, rowid
+                "#,
+            )?
+            .query_map(params![transfer_id], |r| r.get(0))?
+            .collect::<QueryResult<_>>()?;
+
+        Ok(tags)
+    }
+
+    /// Returns the IDs of the non-deleted transfers tagged with the given tag,
+    /// oldest first
+    pub async fn transfers_with_tag(&self, tag: &str) -> Vec<Uuid> {
+        trace!(self.logger, "Fetching transfers with tag"; "tag" => tag);
+
+        let task = async {
+            let conn = self.conn.lock().await;
+
+            let ids = conn
+                .prepare(
+                    r#"
+                SELECT t.id
+                FROM transfer_tags tt
+                INNER JOIN transfers t ON t.id = tt.transfer_id
+                WHERE tt.tag = ?1 AND NOT t.is_deleted
+                ORDER BY t.created_at, t.rowid
+                "#,
+                )?
+                .query_map(params![tag], |r| {
+                    let id: String = r.get(0)?;
+                    Uuid::parse_str(&id).map_err(|_| rusqlite::Error::InvalidQuery)
+                })?
+                .collect::<QueryResult<_>>()?;
+
+            Ok::<Vec<_>, Error>(ids)
+        };
+
+        match task.await {
+            Ok(ids) => ids,
+            Err(e) => {
+                error!(self.logger, "Failed to get transfers with tag"; "error" => %e);
+                (self.on_error)("transfers_with_tag", &e);
+                vec![]
+            }
+        }
+    }
+
+    /// Sets the throughput cap of the transfer in bytes per second, `None`
+    /// removing it. Returns `None` if there's no such transfer
+    pub async fn set_transfer_rate_limit(
+        &self,
+        transfer_id: Uuid,
+        limit: Option<u64>,
+    ) -> Option<()> {
+        let tid = transfer_id.to_string();
+
+        trace!(
+            self.logger,
+            "Setting transfer rate limit";
+            "transfer_id" => &tid,
+            "limit" => limit,
+        );
+
+        let task = async {
+            let conn = self.conn.lock().await;
+
+            let count = conn.execute(
+                "UPDATE transfers SET rate_limit = ?2 WHERE id = ?1 AND NOT is_deleted",
+                params![tid, limit.map(|limit| limit.min(i64::MAX as u64) as i64)],
+            )?;
+
+            Ok::<_, Error>((count > 0).then_some(()))
+        };
+
+        match task.await {
+            Ok(res) => res,
+            Err(e) => {
+                error!(self.logger, "Failed to set transfer rate limit"; "error" => %e);
+                (self.on_error)("set_transfer_rate_limit", &e);
+                None
+            }
+        }
+    }
+
+    /// Returns the throughput cap of the transfer in bytes per second, if set
+    pub async fn transfer_rate_limit(&self, transfer_id: Uuid) -> Option<u64> {
+        let task = async {
+            let conn = self.conn.lock().await;
+
+            let limit = conn
+                .query_row(
+                    "SELECT rate_limit FROM transfers WHERE id = ?1",
+                    params![transfer_id.to_string()],
+                    |r| r.get::<_, Option<i64>>(0),
+                )
+                .optional()?
+                .flatten();
+
+            Ok::<_, Error>(limit.map(|limit| limit as u64))
+        };
+
+        match task.await {
+            Ok(limit) => limit,
+            Err(e) => {
+                error!(self.logger, "Failed to get transfer rate limit"; "error" => %e);
+                (self.on_error)("transfer_rate_limit", &e);
+                None
+            }
+        }
+    }
+
+    /// Sets the zone used to reach the link-local IPv6 peer of the transfer.
+    /// Returns `None` if there's no such transfer
+    pub async fn set_transfer_zone(&self, transfer_id: Uuid, zone: &str) -> Option<()> {
+        let tid = transfer_id.to_string();
+
+        trace!(
+            self.logger,
+            "Setting transfer peer zone";
+            "transfer_id" => &tid,
+            "zone" => zone,
+        );
+
+        let task = async {
+            let conn = self.conn.lock().await;
+
+            let count = conn.execute(
+                "UPDATE transfers SET peer_zone = ?2 WHERE id = ?1 AND NOT is_deleted",
+                params![tid, zone],
+            )?;
+
+            Ok::<_, Error>((count > 0).then_some(()))
+        };
+
+        match task.await {
+            Ok(res) => res,
+            Err(e) => {
+                error!(self.logger, "Failed to set transfer peer zone"; "error" => %e);
+                (self.on_error)("set_transfer_zone", &e);
+                None
+            }
+        }
+    }
+
+    /// Returns the zone of the link-local IPv6 peer of the transfer as it was
+    /// set, if there's any
+    pub async fn transfer_zone(&self, transfer_id: Uuid) -> Option<String> {
+        let task = async {
+            let conn = self.conn.lock().await;
+
+            let zone = conn
+                .query_row(
+                    "SELECT peer_zone FROM transfers WHERE id = ?1",
+                    params![transfer_id.to_string()],
+                    |r| r.get(0),
+                )
+                .optional()?;
+
+            Ok::<_, Error>(zone.flatten())
+        };
+
+        match task.await {
+            Ok(zone) => zone,
+            Err(e) => {
+                error!(self.logger, "Failed to get transfer peer zone"; "error" => %e);
+                (self.on_error)("transfer_zone", &e);
+                None
+            }
+        }
+    }
+
+    /// Returns the files of the transfer with just the kind of their latest
+    /// state, without fetching the whole state history like
+    /// `transfers_since()` does. The states recorded at the same time are
+    /// ordered by the file lifecycle
+    pub async fn transfer_file_manifest(&self, transfer_id: Uuid) -> Vec<FileManifestEntry> {
+        trace!(
+            self.logger,
+            "Fetching file manifest";
+            "transfer_id" => transfer_id.to_string());
+
+        let task = async {
+            let conn = self.conn.lock().await;
+
+            let files = conn
+                .prepare(&format!(
+                    r#"
+                WITH ips AS (
+                    SELECT path_id, created_at, {pending} AS kind FROM incoming_path_pending_states
+                    UNION ALL
+                    SELECT path_id, created_at, {started} FROM incoming_path_started_states
+                    UNION ALL
+                    SELECT path_id, created_at, {paused} FROM incoming_path_paused_states
+                    UNION ALL
+                    SELECT path_id, created_at, {failed} FROM incoming_path_failed_states
+                    UNION ALL
+                    SELECT path_id, created_at, {completed} FROM incoming_path_completed_states
+                    UNION ALL
+                    SELECT path_id, created_at, {rejected} FROM incoming_path_reject_states
+                ), ops AS (
+                    SELECT path_id, created_at, {started} AS kind FROM outgoing_path_started_states
+                    UNION ALL
+                    SELECT path_id, created_at, {paused} FROM outgoing_path_paused_states
+                    UNION ALL
+                    SELECT path_id, created_at, {failed} FROM outgoing_path_failed_states
+                    UNION ALL
+                    SELECT path_id, created_at, {completed} FROM outgoing_path_completed_states
+                    UNION ALL
+                    SELECT path_id, created_at, {rejected} FROM outgoing_path_reject_states
+                )
+                SELECT {incoming} AS direction, p.path_hash, p.relative_path, p.bytes, p.rowid AS seq,
+                    (SELECT kind FROM ips WHERE ips.path_id = p.id
+                        ORDER BY ips.created_at DESC, ips.kind DESC LIMIT 1) AS kind
+                FROM incoming_paths p
+                WHERE p.transfer_id = ?1 AND NOT p.is_deleted
+                UNION ALL
+                SELECT {outgoing}, p.path_hash, p.relative_path, p.bytes, p.rowid,
+                    (SELECT kind FROM ops WHERE ops.path_id = p.id
+                        ORDER BY ops.created_at DESC, ops.kind DESC LIMIT 1)
+                FROM outgoing_paths p
+                WHERE p.transfer_id = ?1 AND NOT p.is_deleted
+                ORDER BY direction, seq
+                "#,
+                    pending = FileStateKind::Pending as u8,
+                    started = FileStateKind::Started as u8,
+                    paused = FileStateKind::Paused as u8,
+                    failed = FileStateKind::Failed as u8,
+                    completed = FileStateKind::Completed as u8,
+                    rejected = FileStateKind::Rejected as u8,
+                    incoming = TransferType::Incoming as u32,
+                    outgoing = TransferType::Outgoing as u32,
+                ))?
+                .query_map(params![transfer_id.to_string()], |r| {
+                    let direction = if r.get::<_, u32>("direction")? == TransferType::Outgoing as u32
+                    {
+                        TransferType::Outgoing
+                    } else {
+                        TransferType::Incoming
+                    };
+
+                    Ok(FileManifestEntry {
+                        file_id: r.get("path_hash")?,
+                        subpath: r.get("relative_path")?,
+                        size: r.get("bytes")?,
+                        direction,
+                        latest_state_kind: r.get("kind")?,
+                    })
+                })?
+                .collect::<QueryResult<_>>()?;
+
+            Ok::<Vec<_>, Error>(files)
+        };
+
+        match task.await {
+            Ok(files) => files,
+            Err(e) => {
+                error!(self.logger, "Failed to get file manifest"; "error" => %e);
+                (self.on_error)("transfer_file_manifest", &e);
+                vec![]
+            }
+        }
+    }
+
+    pub async fn remove_transfer_file(&self, transfer_id: Uuid, file_id: &str) -> Option<()> {
+        let tid = transfer_id.to_string();
+
+        trace!(
+            self.logger,
+            "Removing transfer file";
+            "transfer_id" => &tid,
+            "file_id" => file_id,
+        );
+
+        let task = async {
+            let conn = self.conn.lock().await;
+
+            let mut count = 0;
+            count += conn
+                .prepare(
+                    r#"
+                UPDATE outgoing_paths
+                SET is_deleted = TRUE
+                WHERE transfer_id = ?1
+                    AND path_hash = ?2
+                    AND (
+                        id IN(SELECT path_id FROM outgoing_path_reject_states) OR
+                        id IN(SELECT path_id FROM outgoing_path_failed_states) OR
+                        id IN(SELECT path_id FROM outgoing_path_completed_states)
+                    )
+            "#,
+                )?
+                .execute(params![tid, file_id])?;
+            count += conn
+                .prepare(
+                    r#"
+                UPDATE incoming_paths
+                SET is_deleted = TRUE
+                WHERE transfer_id = ?1
+                    AND path_hash = ?2
+                    AND (
+                        id IN(SELECT path_id FROM incoming_path_reject_states) OR
+                        id IN(SELECT path_id FROM incoming_path_failed_states) OR
+                        id IN(SELECT path_id FROM incoming_path_completed_states)
+                    )
+            "#,
+                )?
+                .execute(params![tid, file_id])?;
+
+            match count {
+                0 => Ok::<Option<()>, Error>(None),
+                1 => Ok(Some(())),
+                _ => {
+                    warn!(
+                        self.logger,
+                        "Deleted a file from both outgoing and incoming paths"
+                    );
+                    Ok(Some(()))
+                }
+            }
+        };
+
+        match task.await {
+            Ok(res) => res,
+            Err(e) => {
+                error!(self.logger, "Failed to remove transfer file"; "error" => %e);
+                (self.on_error)("remove_transfer_file", &e);
+                None
+            }
+        }
+    }
+
+    pub async fn fetch_temp_locations(&self, transfer_id: Uuid) -> Vec<TempFileLocation> {
+        let tid = transfer_id.to_string();
+
+        trace!(
+            self.logger,
+            "Fetching temporary file locations";
+            "transfer_id" => &tid
+        );
+
+        let task = async {
+            let conn = self.conn.lock().await;
+
+            let out = conn
+                .prepare(
+                    r#"
+                SELECT DISTINCT path_hash, base_dir
+                FROM incoming_paths ip
+                INNER JOIN incoming_path_pending_states ipss ON ip.id = ipss.path_id 
+                WHERE transfer_id = ?1
+                "#,
+                )?
+                .query_map(params![tid], |row| {
+                    Ok(TempFileLocation {
+                        file_id: row.get("path_hash")?,
+                        base_path: row.get("base_dir")?,
+                    })
+                })?
+                .collect::<QueryResult<_>>()?;
+
+            Ok::<Vec<_>, Error>(out)
+        };
+
+        match task.await {
+            Ok(res) => res,
+            Err(e) => {
+                error!(self.logger, "Failed to fetch temporary file locations"; "error" => %e);
+                (self.on_error)("fetch_temp_locations", &e);
+                vec![]
+            }
+        }
+    }
+
+    pub async fn fetch_base_dirs_for_file(&self, transfer_id: Uuid, file_id: &str) -> Vec<String> {
+        let tid = transfer_id.to_string();
+
+        trace!(
+            self.logger,
+            "Fetching temporary file locations";
+            "transfer_id" => &tid
+        );
+
+        let task = async {
+            let conn = self.conn.lock().await;
+
+            let out = conn
                 .prepare(
                     r#"
-                WITH ts AS  (
-                    select 1, id, transfer_id, by_peer, created_at from transfer_cancel_states
-                    union all
-                    select 2, id, transfer_id, status_code, created_at from transfer_failed_states
+                SELECT DISTINCT base_dir
+                FROM incoming_paths ip
+                INNER JOIN incoming_path_pending_states ipss ON ip.id = ipss.path_id 
+                WHERE transfer_id = ?1 AND path_hash = ?2
+                "#,
+                )?
+                .query_map(params![tid, file_id], |row| row.get("base_dir"))?
+                .collect::<QueryResult<_>>()?;
+
+            Ok::<Vec<_>, Error>(out)
+        };
+
+        match task.await {
+            Ok(res) => res,
+            Err(e) => {
+                error!(self.logger, "Failed to fetch temporary file locations for {file_id}"; "error" => %e);
+                (self.on_error)("fetch_base_dirs_for_file", &e);
+                vec![]
+            }
+        }
+    }
+
+    /// Fetch every directory the files were downloaded into, including the
+    /// ones of the transfers removed since
+    pub async fn fetch_temp_base_dirs(&self) -> Vec<String> {
+        trace!(self.logger, "Fetching all temporary file base directories");
+
+        let task = async {
+            let conn = self.conn.lock().await;
+
+            let out = conn
+                .prepare(
+                    r#"
+                SELECT base_dir
+                FROM temp_base_dirs
+                "#,
+                )?
+                .query_map(params![], |row| row.get("base_dir"))?
+                .collect::<QueryResult<_>>()?;
+
+            Ok::<Vec<_>, Error>(out)
+        };
+
+        match task.await {
+            Ok(res) => res,
+            Err(e) => {
+                error!(self.logger, "Failed to fetch temporary file base directories"; "error" => %e);
+                (self.on_error)("fetch_temp_base_dirs", &e);
+                vec![]
+            }
+        }
+    }
+
+    pub async fn cleanup_garbage_transfers(&self) -> usize {
+        trace!(self.logger, "Removing garbage transfers");
+
+        let task = async {
+            let mut conn = self.conn.lock().await;
+            let tx = conn.transaction()?;
+
+            // The foreign keys are not enforced on every connection, the tags
+            // are removed explicitly not to outlive their transfers
+            tx.execute(
+                r#"
+                DELETE FROM transfer_tags WHERE transfer_id IN (
+                    SELECT t.id
+                    FROM transfers t
+                    LEFT JOIN sync_transfer st ON t.id = st.transfer_id
+                    WHERE t.is_deleted AND st.sync_id IS NULL
+                )
+                "#,
+                params![],
+            )?;
+
+            let count = tx.execute(
+                r#"
+                DELETE FROM transfers WHERE id IN (
+                    SELECT t.id 
+                    FROM transfers t
+                    LEFT JOIN sync_transfer st ON t.id = st.transfer_id
+                    WHERE t.is_deleted AND st.sync_id IS NULL                    
+                )
+                "#,
+                params![],
+            )?;
+
+            // The checksums are keyed by the file URI rather than the
+            // transfer, they go away once no remaining transfer sends the file
+            let mut checksums = tx.execute(
+                r#"
+                DELETE FROM outgoing_checksums WHERE path NOT IN (
+                    SELECT op.uri
+                    FROM outgoing_paths op
+                    INNER JOIN transfers t ON t.id = op.transfer_id
+                )
+                "#,
+                params![],
+            )?;
+
+            // The entries of the file recorded before it was last modified
+            // are stale, the fetching only skips them
+            checksums += tx.execute(
+                r#"
+                DELETE FROM outgoing_checksums WHERE EXISTS (
+                    SELECT 1
+                    FROM outgoing_checksums newer
+                    WHERE newer.path = outgoing_checksums.path
+                        AND newer.mtime > outgoing_checksums.mtime
+                )
+                "#,
+                params![],
+            )?;
+
+            tx.commit()?;
+
+            debug!(
+                self.logger,
+                "Removed {count} garbage transfers and {checksums} outgoing checksums"
+            );
+            Result::Ok(count)
+        };
+
+        match task.await {
+            Err(err) => {
+                error!(self.logger, "Failed to remove garbage transfers: {err}");
+                (self.on_error)("cleanup_garbage_transfers", &err);
+                0
+            }
+            Ok(count) => count,
+        }
+    }
+
+    /// Removes all but the `keep_last` most recent started and paused states
+    /// of every path. These accumulate with each reconnection of the resumable
+    /// transfers. The terminal states are never touched. Returns the number of
+    /// removed rows
+    pub async fn prune_intermediate_states(&self, keep_last: usize) -> usize {
+        const TABLES: &[&str] = &[
+            "incoming_path_started_states",
+            "incoming_path_paused_states",
+            "outgoing_path_started_states",
+            "outgoing_path_paused_states",
+        ];
+
+        trace!(
+            self.logger,
+            "Pruning intermediate path states";
+            "keep_last" => keep_last);
+
+        let task = async {
+            let mut conn = self.conn.lock().await;
+            let conn = conn.transaction()?;
+
+            let mut count = 0;
+            for table in TABLES {
+                count += conn.execute(
+                    &format!(
+                        r#"
+                    DELETE FROM {table} WHERE rowid NOT IN (
+                        SELECT s.rowid
+                        FROM {table} s
+                        WHERE s.path_id = {table}.path_id
+                        ORDER BY s.rowid DESC
+                        LIMIT ?1
+                    )
+                    "#
+                    ),
+                    params![keep_last],
+                )?;
+            }
+
+            conn.commit()?;
+
+            debug!(self.logger, "Removed {count} intermediate path states");
+            Result::Ok(count)
+        };
+
+        match task.await {
+            Err(err) => {
+                error!(
+                    self.logger,
+                    "Failed to prune intermediate path states: {err}"
+                );
+                (self.on_error)("prune_intermediate_states", &err);
+                0
+            }
+            Ok(count) => count,
+        }
+    }
+
+    /// Writes the transfer history along with the manifest of the files it
+    /// references into a compressed archive. The file contents are not
+    /// included. Returns the writer once the archive is complete
+    pub async fn export_archive<W>(&self, writer: W) -> Result<W>
+    where
+        W: io::Write + Send + 'static,
+    {
+        trace!(self.logger, "Exporting history archive");
+
+        let conn = self.conn.clone().lock_owned().await;
+        let task = tokio::task::spawn_blocking(move || {
+            let mut writer = writer;
+            archive::export(&conn, &mut writer)?;
+            Ok::<_, Error>(writer)
+        });
+
+        match task.await.unwrap_or_else(|err| Err(join_error(err))) {
+            Err(err) => {
+                error!(self.logger, "Failed to export history archive: {err}");
+                (self.on_error)("export_archive", &err);
+                Err(err)
+            }
+            Ok(writer) => Ok(writer),
+        }
+    }
+
+    /// Restores the transfer history from the archive written by
+    /// `export_archive()`. The history of this database must be empty. Returns
+    /// the manifest of the files referenced by the restored history
+    pub async fn import_archive(
+        &self,
+        reader: impl io::Read + Send + 'static,
+    ) -> Result<Vec<ArchiveManifestEntry>> {
+        trace!(self.logger, "Importing history archive");
+
+        let mut conn = self.conn.clone().lock_owned().await;
+        let task = tokio::task::spawn_blocking(move || {
+            let conn = conn.transaction()?;
+
+            let manifest = archive::import(&conn, reader)?;
+
+            conn.commit()?;
+            Ok::<_, Error>(manifest)
+        });
+
+        match task.await.unwrap_or_else(|err| Err(join_error(err))) {
+            Err(err) => {
+                error!(self.logger, "Failed to import history archive: {err}");
+                (self.on_error)("import_archive", &err);
+                Err(err)
+            }
+            Ok(manifest) => Ok(manifest),
+        }
+    }
+
+    /// Registers the downloads finished by another libdrop instance so that
+    /// they show up in the history. The records whose `final_path` does not
+    /// exist or which conflict with an already known transfer or file are
+    /// skipped. Returns the number of imported files
+    pub async fn import_completed_incoming(&self, records: &[ImportedIncoming]) -> usize {
+        trace!(
+            self.logger,
+            "Importing completed incoming files";
+            "count" => records.len());
+
+        let task = async {
+            let mut conn = self.conn.lock().await;
+            let conn = conn.transaction()?;
+
+            let count = self.import_completed_incoming_in(&conn, records)?;
+
+            conn.commit()?;
+            Ok::<_, Error>(count)
+        };
+
+        match task.await {
+            Err(err) => {
+                error!(
+                    self.logger,
+                    "Failed to import completed incoming files: {err}"
+                );
+                (self.on_error)("import_completed_incoming", &err);
+                0
+            }
+            Ok(count) => count,
+        }
+    }
+
+    fn import_completed_incoming_in(
+        &self,
+        conn: &Connection,
+        records: &[ImportedIncoming],
+    ) -> Result<usize> {
+        let mut count = 0;
+        for record in records {
+            let tid = record.transfer_id.to_string();
+
+            if !Path::new(&record.final_path).is_file() {
+                warn!(
+                    self.logger,
+                    "Skipping import of the file missing on disk";
+                    "transfer_id" => &tid,
+                    "file_id" => &record.file_id);
+                continue;
+            }
+
+            conn.execute(
+                &format!(
+                    "INSERT INTO transfers (id, peer, is_outgoing, created_at, seq) VALUES \
+                     (?1, ?2, ?3, ?4, {NEXT_TRANSFER_SEQ}) ON CONFLICT DO NOTHING"
+                ),
+                params![tid, record.peer, TransferType::Incoming as u32, self.now()],
+            )?;
+
+            let transfer_matches: bool = conn.query_row(
+                r#"
+                SELECT EXISTS(
+                    SELECT 1 FROM transfers
+                    WHERE id = ?1 AND peer = ?2 AND NOT is_outgoing AND NOT is_deleted
+                )
+                "#,
+                params![tid, record.peer],
+                |r| r.get(0),
+            )?;
+
+            let inserted = if transfer_matches {
+                conn.execute(
+                    r#"
+                INSERT INTO incoming_paths (transfer_id, relative_path, path_hash, bytes, checksum)
+                VALUES (?1, ?2, ?3, ?4, ?5) ON CONFLICT DO NOTHING
+                "#,
+                    params![
+                        tid,
+                        record.relative_path,
+                        record.file_id,
+                        record.size,
+                        record.checksum
+                    ],
+                )?
+            } else {
+                0
+            };
+
+            if inserted < 1 {
+                warn!(
+                    self.logger,
+                    "Skipping import of the file conflicting with the existing one";
+                    "transfer_id" => &tid,
+                    "file_id" => &record.file_id);
+                continue;
+            }
+
+            conn.execute(
+                "INSERT INTO incoming_path_completed_states (path_id, final_path) VALUES (?1, \
+                 ?2)",
+                params![conn.last_insert_rowid(), record.final_path],
+            )?;
+
+            count += 1;
+        }
+
+        debug!(self.logger, "Imported {count} completed incoming files");
+        Ok(count)
+    }
+}
+
+/// Handle to the transaction opened with [`Storage::with_transaction`]. The
+/// operations fail instead of swallowing the errors so that the whole
+/// transaction can be rolled back
+pub struct StorageTx<'a> {
+    conn: Transaction<'a>,
+    storage: &'a Storage,
+}
+
+impl StorageTx<'_> {
+    pub fn purge_transfers(&self, transfer_ids: &[String]) -> Result<()> {
+        self.storage.purge_transfers_in(&self.conn, transfer_ids)
+    }
+
+    pub fn save_checksum(&self, transfer_id: Uuid, file_id: &str, checksum: &[u8]) -> Result<()> {
+        Storage::save_checksum_in(&self.conn, &transfer_id.to_string(), file_id, checksum)
+    }
+
+    pub fn update_outgoing_path_uri(
+        &self,
+        transfer_id: Uuid,
+        path_id: &str,
+        uri: &url::Url,
+    ) -> Result<()> {
+        Storage::update_outgoing_path_uri_in(&self.conn, &transfer_id.to_string(), path_id, uri)
+    }
+
+    pub fn redirect_incoming_file(
+        &self,
+        transfer_id: Uuid,
+        file_id: &str,
+        base_dir: &str,
+    ) -> Result<()> {
+        Storage::redirect_incoming_file_in(&self.conn, transfer_id, file_id, base_dir)
+    }
+
+    pub fn import_completed_incoming(&self, records: &[ImportedIncoming]) -> Result<usize> {
+        self.storage
+            .import_completed_incoming_in(&self.conn, records)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_insert_transfer() {
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+        let storage = Storage::new(logger, ":memory:").unwrap();
+
+        let transfer_id_1: Uuid = "23e488a4-0521-11ee-be56-0242ac120002".parse().unwrap();
+        let transfer_id_2: Uuid = "23e48d7c-0521-11ee-be56-0242ac120002".parse().unwrap();
+
+        {
+            let transfer = TransferInfo {
+                id: transfer_id_1,
+                peer: "1.2.3.4".to_string(),
+                files: TransferFiles::Incoming(vec![
+                    TransferIncomingPath {
+                        file_id: "id1".to_string(),
+                        relative_path: "1".to_string(),
+                        size: 1024,
+                    },
+                    TransferIncomingPath {
+                        file_id: "id2".to_string(),
+                        relative_path: "2".to_string(),
+                        size: 2048,
+                    },
+                ]),
+            };
+
+            storage.insert_transfer(&transfer).await;
+        }
+
+        {
+            let transfer = TransferInfo {
+                id: transfer_id_2,
+                peer: "5.6.7.8".to_string(),
+                files: TransferFiles::Outgoing(vec![
+                    TransferOutgoingPath {
+                        file_id: "id3".to_string(),
+                        size: 1024,
+                        uri: "file:///dir".parse().unwrap(),
+                        relative_path: "3".to_string(),
+                    },
+                    TransferOutgoingPath {
+                        file_id: "id4".to_string(),
+                        relative_path: "4".to_string(),
+                        uri: "file:///dir".parse().unwrap(),
+                        size: 2048,
+                    },
+                ]),
+            };
+
+            storage.insert_transfer(&transfer).await;
+        }
+
+        {
+            let transfers = storage.transfers_since(0, TransferSort::CreatedAsc).await;
+            assert_eq!(transfers.len(), 2);
+
+            let incoming_transfer = &transfers[0];
+            let outgoing_transfer = &transfers[1];
+
+            assert_eq!(incoming_transfer.id, transfer_id_1);
+            assert_eq!(outgoing_transfer.id, transfer_id_2);
+
+            assert_eq!(incoming_transfer.peer_id, "1.2.3.4".to_string());
+            assert_eq!(outgoing_transfer.peer_id, "5.6.7.8".to_string());
+        }
+
+        storage
+            .purge_transfers(&[transfer_id_1.to_string(), transfer_id_2.to_string()])
+            .await;
+
+        // Because the transfers haven't reached the terminal state
+        let transfers = storage.transfers_since(0, TransferSort::CreatedAsc).await;
+        assert_eq!(transfers.len(), 2);
+
+        storage
+            .insert_transfer_cancel_state(transfer_id_1, false, CancelCause::User)
+            .await;
+        storage
+            .insert_transfer_failed_state(transfer_id_2, 42)
+            .await;
+
+        storage
+            .purge_transfers(&[transfer_id_1.to_string(), transfer_id_2.to_string()])
+            .await;
+
+        let transfers = storage.transfers_since(0, TransferSort::CreatedAsc).await;
+        assert_eq!(transfers.len(), 0);
+    }
+
+    #[tokio::test]
+    async fn remove_outgoing_file() {
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+        let storage = Storage::new(logger, ":memory:").unwrap();
+
+        let transfer_id: Uuid = "23e488a4-0521-11ee-be56-0242ac120002".parse().unwrap();
+
+        let transfer = TransferInfo {
+            id: transfer_id,
+            peer: "5.6.7.8".to_string(),
+            files: TransferFiles::Outgoing(vec![
+                TransferOutgoingPath {
+                    file_id: "id1".to_string(),
+                    size: 1024,
+                    uri: "file:///dir".parse().unwrap(),
+                    relative_path: "1".to_string(),
+                },
+                TransferOutgoingPath {
+                    file_id: "id2".to_string(),
+                    size: 1024,
+                    uri: "file:///dir".parse().unwrap(),
+                    relative_path: "2".to_string(),
+                },
+                TransferOutgoingPath {
+                    file_id: "id3".to_string(),
+                    size: 1024,
+                    uri: "file:///dir".parse().unwrap(),
+                    relative_path: "3".to_string(),
+                },
+                TransferOutgoingPath {
+                    file_id: "id4".to_string(),
+                    relative_path: "4".to_string(),
+                    uri: "file:///dir".parse().unwrap(),
+                    size: 2048,
+                },
+            ]),
+        };
+
+        storage.insert_transfer(&transfer).await;
+        storage
+            .insert_outgoing_path_failed_state(transfer_id, "id1", 1, 123)
+            .await;
+        storage
+            .insert_outgoing_path_completed_state(transfer_id, "id2")
+            .await;
+        storage
+            .insert_outgoing_path_reject_state(transfer_id, "id3", false, 246)
+            .await;
+
+        let transfers = storage.transfers_since(0, TransferSort::CreatedAsc).await;
+        assert_eq!(transfers.len(), 1);
+
+        let paths = match &transfers[0].transfer_type {
+            DbTransferType::Outgoing(out) => out,
+            _ => panic!("Unexpected transfer type"),
+        };
+        assert_eq!(paths.len(), 4);
+
+        assert!(storage
+            .remove_transfer_file(transfer_id, "id1")
+            .await
+            .is_some());
+        assert!(storage
+            .remove_transfer_file(transfer_id, "id2")
+            .await
+            .is_some());
+        assert!(storage
+            .remove_transfer_file(transfer_id, "id3")
+            .await
+            .is_some());
+        assert!(storage
+            .remove_transfer_file(transfer_id, "id4")
+            .await
+            .is_none());
+
+        let transfers = storage.transfers_since(0, TransferSort::CreatedAsc).await;
+        assert_eq!(transfers.len(), 1);
+
+        let paths = match &transfers[0].transfer_type {
+            DbTransferType::Outgoing(out) => out,
+            _ => panic!("Unexpected transfer type"),
+        };
+        assert_eq!(paths.len(), 1); // 1 since we removed one of them
+        assert_eq!(paths[0].file_id, "id4");
+    }
+
+    #[tokio::test]
+    async fn remove_incoming_file() {
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+        let storage = Storage::new(logger, ":memory:").unwrap();
+
+        let transfer_id: Uuid = "23e488a4-0521-11ee-be56-0242ac120002".parse().unwrap();
+
+        let transfer = TransferInfo {
+            id: transfer_id,
+            peer: "5.6.7.8".to_string(),
+            files: TransferFiles::Incoming(vec![
+                TransferIncomingPath {
+                    file_id: "id1".to_string(),
+                    size: 1024,
+                    relative_path: "1".to_string(),
+                },
+                TransferIncomingPath {
+                    file_id: "id2".to_string(),
+                    size: 1024,
+                    relative_path: "2".to_string(),
+                },
+                TransferIncomingPath {
+                    file_id: "id3".to_string(),
+                    size: 1024,
+                    relative_path: "3".to_string(),
+                },
+                TransferIncomingPath {
+                    file_id: "id4".to_string(),
+                    relative_path: "4".to_string(),
+                    size: 2048,
+                },
+            ]),
+        };
+
+        storage.insert_transfer(&transfer).await;
+        storage
+            .insert_incoming_path_failed_state(transfer_id, "id1", 1, 123)
+            .await;
+        storage
+            .insert_incoming_path_completed_state(transfer_id, "id2", "/recv/id2", false, false)
+            .await;
+        storage
+            .insert_incoming_path_reject_state(transfer_id, "id3", false, 246)
+            .await;
+
+        let transfers = storage.transfers_since(0, TransferSort::CreatedAsc).await;
+        assert_eq!(transfers.len(), 1);
+
+        let paths = match &transfers[0].transfer_type {
+            DbTransferType::Incoming(inc) => inc,
+            _ => panic!("Unexpected transfer type"),
+        };
+        assert_eq!(paths.len(), 4);
+
+        assert!(storage
+            .remove_transfer_file(transfer_id, "id1")
+            .await
+            .is_some());
+        assert!(storage
+            .remove_transfer_file(transfer_id, "id2")
+            .await
+            .is_some());
+        assert!(storage
+            .remove_transfer_file(transfer_id, "id3")
+            .await
+            .is_some());
+        assert!(storage
+            .remove_transfer_file(transfer_id, "id4")
+            .await
+            .is_none());
+
+        let transfers = storage.transfers_since(0, TransferSort::CreatedAsc).await;
+        assert_eq!(transfers.len(), 1);
+
+        let paths = match &transfers[0].transfer_type {
+            DbTransferType::Incoming(inc) => inc,
+            _ => panic!("Unexpected transfer type"),
+        };
+
+        assert_eq!(paths.len(), 1); // 1 since we removed one of them
+        assert_eq!(paths[0].file_id, "id4");
+    }
+
+    #[tokio::test]
+    async fn check_storage_api() {
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+        let storage = Storage::new(logger, ":memory:").unwrap();
+
+        let transfer1_id: Uuid = "23e488a4-0521-11ee-be56-0242ac120002".parse().unwrap();
+
+        let transfer = TransferInfo {
+            id: transfer1_id,
+            peer: "5.6.7.8".to_string(),
+            files: TransferFiles::Incoming(vec![
+                TransferIncomingPath {
+                    file_id: "idi1".to_string(),
+                    size: 1024,
+                    relative_path: "1".to_string(),
+                },
+                TransferIncomingPath {
+                    file_id: "idi2".to_string(),
+                    size: 1024,
+                    relative_path: "2".to_string(),
+                },
+                TransferIncomingPath {
+                    file_id: "idi3".to_string(),
+                    size: 1024,
+                    relative_path: "3".to_string(),
+                },
+                TransferIncomingPath {
+                    file_id: "idi4".to_string(),
+                    relative_path: "4".to_string(),
+                    size: 2048,
+                },
+            ]),
+        };
+
+        storage.insert_transfer(&transfer).await;
+        storage
+            .insert_incoming_path_failed_state(transfer1_id, "idi1", 1, 123)
+            .await;
+        storage
+            .start_incoming_file(transfer1_id, "idi2", "/recv/idi2")
+            .await;
+        storage
+            .insert_incoming_path_completed_state(transfer1_id, "idi2", "/recv/idi2", false, false)
+            .await;
+        storage
+            .insert_incoming_path_reject_state(transfer1_id, "idi3", false, 234)
+            .await;
+        storage
+            .insert_incoming_path_started_state(transfer1_id, "idi4", 12345)
+            .await;
+
+        let transfer2_id: Uuid = "f333302e-584b-42f8-9f66-6a5ef400297d".parse().unwrap();
+
+        let transfer = TransferInfo {
+            id: transfer2_id,
+            peer: "1.2.3.4".to_string(),
+            files: TransferFiles::Outgoing(vec![
+                TransferOutgoingPath {
+                    file_id: "ido1".to_string(),
+                    relative_path: "1".to_string(),
+                    uri: "file:///dir/1".parse().unwrap(),
+                    size: 1024,
+                },
+                TransferOutgoingPath {
+                    file_id: "ido2".to_string(),
+                    relative_path: "2".to_string(),
+                    uri: "file:///dir/2".parse().unwrap(),
+                    size: 1024,
+                },
+                TransferOutgoingPath {
+                    file_id: "ido3".to_string(),
+                    relative_path: "3".to_string(),
+                    uri: "file:///dir/3".parse().unwrap(),
+                    size: 1024,
+                },
+                TransferOutgoingPath {
+                    file_id: "ido4".to_string(),
+                    relative_path: "4".to_string(),
+                    uri: "file:///dir/4".parse().unwrap(),
+                    size: 2048,
+                },
+            ]),
+        };
+
+        storage.insert_transfer(&transfer).await;
+        storage
+            .insert_outgoing_path_failed_state(transfer2_id, "ido1", 1, 123)
+            .await;
+        storage
+            .insert_outgoing_path_completed_state(transfer2_id, "ido2")
+            .await;
+        storage
+            .insert_outgoing_path_reject_state(transfer2_id, "ido3", false, 234)
+            .await;
+        storage
+            .insert_outgoing_path_started_state(transfer2_id, "ido4", 12345)
+            .await;
+
+        let transfers = storage.transfers_since(0, TransferSort::CreatedAsc).await;
+        assert_eq!(transfers.len(), 2);
+
+        assert_eq!(transfers[0].id, transfer1_id);
+        assert_eq!(transfers[0].peer_id, "5.6.7.8");
+        assert_eq!(transfers[0].states.len(), 0);
+
+        match &transfers[0].transfer_type {
+            DbTransferType::Incoming(inc) => {
+                assert_eq!(inc[0].transfer_id, transfer1_id);
+                assert_eq!(inc[0].relative_path, "1");
+                assert_eq!(inc[0].bytes, 1024);
+                assert_eq!(inc[0].bytes_received, 123);
+                assert_eq!(inc[0].file_id, "idi1");
+                assert_eq!(inc[0].states.len(), 1);
+
+                assert!(matches!(
+                    inc[0].states[0].data,
+                    IncomingPathStateEventData::Failed {
+                        status_code: 1,
+                        bytes_received: 123
+                    }
+                ));
+
+                assert_eq!(inc[1].transfer_id, transfer1_id);
+                assert_eq!(inc[1].relative_path, "2");
+                assert_eq!(inc[1].bytes, 1024);
+                assert_eq!(inc[1].bytes_received, 1024);
+                assert_eq!(inc[1].file_id, "idi2");
+                assert_eq!(inc[1].states.len(), 2);
+
+                assert!(matches!(
+                    &inc[1].states[0].data,
+                    IncomingPathStateEventData::Pending{
+                        base_dir,
+                    } if base_dir == "/recv/idi2",
+                ));
+                assert!(matches!(
+                    &inc[1].states[1].data,
+                    IncomingPathStateEventData::Completed {
+                        final_path,
+                        was_renamed: false,
+                        deleted: false,
+                    } if final_path == "/recv/idi2"
+                ));
+
+                assert_eq!(inc[2].transfer_id, transfer1_id);
+                assert_eq!(inc[2].relative_path, "3");
+                assert_eq!(inc[2].bytes, 1024);
+                assert_eq!(inc[2].bytes_received, 234);
+                assert_eq!(inc[2].file_id, "idi3");
+                assert_eq!(inc[2].states.len(), 1);
+
+                assert!(matches!(
+                    inc[2].states[0].data,
+                    IncomingPathStateEventData::Rejected {
+                        by_peer: false,
+                        bytes_received: 234
+                    }
+                ));
+
+                assert_eq!(inc[3].transfer_id, transfer1_id);
+                assert_eq!(inc[3].relative_path, "4");
+                assert_eq!(inc[3].bytes, 2048);
+                assert_eq!(inc[3].bytes_received, 12345);
+                assert_eq!(inc[3].file_id, "idi4");
+                assert_eq!(inc[3].states.len(), 1);
+
+                assert!(matches!(
+                    &inc[3].states[0].data,
+                    IncomingPathStateEventData::Started {
+                        bytes_received: 12345
+                    }
+                ));
+            }
+            _ => panic!("Unexpected transfer type"),
+        };
+
+        assert_eq!(transfers[1].id, transfer2_id);
+        assert_eq!(transfers[1].peer_id, "1.2.3.4");
+        assert_eq!(transfers[1].states.len(), 0);
+
+        match &transfers[1].transfer_type {
+            DbTransferType::Outgoing(inc) => {
+                assert_eq!(inc[0].transfer_id, transfer2_id);
+                assert_eq!(inc[0].relative_path, "1");
+                assert_eq!(inc[0].bytes, 1024);
+                assert_eq!(inc[0].bytes_sent, 123);
+                assert_eq!(inc[0].file_id, "ido1");
+                assert_eq!(inc[0].base_path.as_deref(), Some(Path::new("/dir")));
+                assert!(inc[0].content_uri.is_none());
+                assert_eq!(inc[0].states.len(), 1);
+
+                assert!(matches!(
+                    inc[0].states[0].data,
+                    OutgoingPathStateEventData::Failed {
+                        status_code: 1,
+                        bytes_sent: 123
+                    }
+                ));
+
+                assert_eq!(inc[1].transfer_id, transfer2_id);
+                assert_eq!(inc[1].relative_path, "2");
+                assert_eq!(inc[1].bytes, 1024);
+                assert_eq!(inc[1].bytes_sent, 1024);
+                assert_eq!(inc[1].file_id, "ido2");
+                assert_eq!(inc[1].base_path.as_deref(), Some(Path::new("/dir")));
+                assert!(inc[1].content_uri.is_none());
+                assert_eq!(inc[1].states.len(), 1);
+
+                assert!(matches!(
+                    inc[1].states[0].data,
+                    OutgoingPathStateEventData::Completed
+                ));
+
+                assert_eq!(inc[2].transfer_id, transfer2_id);
+                assert_eq!(inc[2].relative_path, "3");
+                assert_eq!(inc[2].bytes, 1024);
+                assert_eq!(inc[2].bytes_sent, 234);
+                assert_eq!(inc[2].file_id, "ido3");
+                assert_eq!(inc[2].base_path.as_deref(), Some(Path::new("/dir")));
+                assert!(inc[2].content_uri.is_none());
+                assert_eq!(inc[2].states.len(), 1);
+
+                assert!(matches!(
+                    inc[2].states[0].data,
+                    OutgoingPathStateEventData::Rejected {
+                        by_peer: false,
+                        bytes_sent: 234
+                    }
+                ));
+
+                assert_eq!(inc[3].transfer_id, transfer2_id);
+                assert_eq!(inc[3].relative_path, "4");
+                assert_eq!(inc[3].bytes, 2048);
+                assert_eq!(inc[3].bytes_sent, 12345);
+                assert_eq!(inc[3].file_id, "ido4");
+                assert_eq!(inc[3].base_path.as_deref(), Some(Path::new("/dir")));
+                assert!(inc[3].content_uri.is_none());
+                assert_eq!(inc[3].states.len(), 1);
+
+                assert!(matches!(
+                    inc[3].states[0].data,
+                    OutgoingPathStateEventData::Started { bytes_sent: 12345 }
+                ));
+            }
+            _ => panic!("Unexpected transfer type"),
+        };
+    }
+
+    #[tokio::test]
+    async fn removing_garbage_transfers() {
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+        let storage = Storage::new(logger, ":memory:").unwrap();
+
+        let transfer_id_1: Uuid = "23e488a4-0521-11ee-be56-0242ac120002".parse().unwrap();
+        let transfer_id_2: Uuid = "23e48d7c-0521-11ee-be56-0242ac120002".parse().unwrap();
+
+        let transfer = TransferInfo {
+            id: transfer_id_1,
+            peer: "1.2.3.4".to_string(),
+            files: TransferFiles::Incoming(vec![]),
+        };
+        storage.insert_transfer(&transfer).await;
+
+        let transfer = TransferInfo {
+            id: transfer_id_2,
+            peer: "5.6.7.8".to_string(),
+            files: TransferFiles::Outgoing(vec![]),
+        };
+        storage.insert_transfer(&transfer).await;
+
+        // Transfers need to be termiated before any purging is allowed
+        storage
+            .insert_transfer_cancel_state(transfer_id_1, false, CancelCause::User)
+            .await;
+        storage
+            .insert_transfer_cancel_state(transfer_id_2, false, CancelCause::User)
+            .await;
+
+        // No garbage to collect
+        let count = storage.cleanup_garbage_transfers().await;
+        assert_eq!(count, 0);
+
+        storage.purge_transfers(&[transfer_id_1.to_string()]).await;
+
+        // Still the transfer was not synced
+        let count = storage.cleanup_garbage_transfers().await;
+        assert_eq!(count, 0);
+
+        let cleared = storage.transfer_sync_clear(transfer_id_1).await;
+        assert!(cleared.is_some());
+
+        // Now the transfer can be garbage collected
+        let count = storage.cleanup_garbage_transfers().await;
+        assert_eq!(count, 1);
+
+        let count = storage.cleanup_garbage_transfers().await;
+        assert_eq!(count, 0);
+
+        // Ensure we haven't deleted the second transfer
+        let transfers = storage.transfers_since(0, TransferSort::CreatedAsc).await;
+        assert_eq!(transfers.len(), 1);
+        assert_eq!(transfers[0].id, transfer_id_2);
+    }
+
+    #[tokio::test]
+    async fn pruning_outgoing_checksums() {
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+        let storage = Storage::new(logger, ":memory:").unwrap();
+
+        let kept: Uuid = "23e488a4-0521-11ee-be56-0242ac120002".parse().unwrap();
+        let removed: Uuid = "23e48d7c-0521-11ee-be56-0242ac120002".parse().unwrap();
+
+        let outgoing = |id, uris: &[&str]| TransferInfo {
+            id,
+            peer: "5.6.7.8".to_string(),
+            files: TransferFiles::Outgoing(
+                uris.iter()
+                    .enumerate()
+                    .map(|(i, uri)| TransferOutgoingPath {
+                        file_id: format!("id{i}"),
+                        relative_path: format!("{i}"),
+                        uri: uri.parse().unwrap(),
+                        size: 1024,
+                    })
+                    .collect(),
+            ),
+        };
+
+        // The shared file stays cached as long as any transfer sends it
+        storage
+            .insert_transfer(&outgoing(kept, &["file:///dir/shared"]))
+            .await;
+        storage
+            .insert_transfer(&outgoing(
+                removed,
+                &["file:///dir/shared", "file:///dir/removed"],
+            ))
+            .await;
+
+        for uri in ["file:///dir/shared", "file:///dir/removed"] {
+            storage
+                .save_outgoing_checksum(uri, 1024, 0, 1024, &[1; 32])
+                .await;
+        }
+
+        storage
+            .insert_transfer_cancel_state(removed, false, CancelCause::User)
+            .await;
+        storage.purge_transfers(&[removed.to_string()]).await;
+
+        // Purged but not removed yet
+        assert_eq!(storage.cleanup_garbage_transfers().await, 0);
+        assert!(storage
+            .fetch_outgoing_checksum("file:///dir/removed", 1024, 0, 1024)
+            .await
+            .is_some());
+
+        storage.transfer_sync_clear(removed).await;
+        assert_eq!(storage.cleanup_garbage_transfers().await, 1);
+
+        assert!(storage
+            .fetch_outgoing_checksum("file:///dir/shared", 1024, 0, 1024)
+            .await
+            .is_some());
+        assert!(storage
+            .fetch_outgoing_checksum("file:///dir/removed", 1024, 0, 1024)
+            .await
+            .is_none());
+
+        // The entries recorded before the file was modified are pruned too
+        storage
+            .save_outgoing_checksum("file:///dir/shared", 1024, 1, 512, &[2; 32])
+            .await;
+        storage.cleanup_garbage_transfers().await;
+
+        assert!(storage
+            .fetch_outgoing_checksum("file:///dir/shared", 1024, 0, 1024)
+            .await
+            .is_none());
+        assert!(storage
+            .fetch_outgoing_checksum("file:///dir/shared", 1024, 1, 512)
+            .await
+            .is_some());
+    }
+
+    #[tokio::test]
+    async fn outgoing_checksum_cache() {
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+        let storage = Storage::new(logger, ":memory:").unwrap();
+
+        let path = "/dir/file";
+        let checksum = [7u8; 32];
+
+        assert!(storage
+            .fetch_outgoing_checksum(path, 1024, 100, 512)
+            .await
+            .is_none());
+
+        storage
+            .save_outgoing_checksum(path, 1024, 100, 512, &checksum)
+            .await;
+
+        assert_eq!(
+            storage
+                .fetch_outgoing_checksum(path, 1024, 100, 512)
+                .await
+                .as_deref(),
+            Some(&checksum[..])
+        );
+
+        // Different limit is a different entry
+        assert!(storage
+            .fetch_outgoing_checksum(path, 1024, 100, 1024)
+            .await
+            .is_none());
+
+        // Modified file invalidates the cache
+        assert!(storage
+            .fetch_outgoing_checksum(path, 1024, 101, 512)
+            .await
+            .is_none());
+
+        // Fetching is read-only, the stale entry is left for the pruning
+        assert!(storage
+            .fetch_outgoing_checksum(path, 1024, 100, 512)
+            .await
+            .is_some());
+    }
+
+    #[tokio::test]
+    async fn incoming_block_checksums() {
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+        let storage = Storage::new(logger, ":memory:").unwrap();
+
+        let transfer_id: Uuid = "23e488a4-0521-11ee-be56-0242ac120002".parse().unwrap();
+        storage
+            .insert_transfer(&TransferInfo {
+                id: transfer_id,
+                peer: "5.6.7.8".to_string(),
+                files: TransferFiles::Incoming(vec![TransferIncomingPath {
+                    file_id: "id1".to_string(),
+                    size: 4096,
+                    relative_path: "1".to_string(),
+                }]),
+            })
+            .await;
+
+        for idx in [0, 1, 3] {
+            storage
+                .save_block_checksum(transfer_id, "id1", 1024, idx, &[idx as u8; 32])
+                .await;
+        }
+
+        // Not trusted until the state of the file is known
+        assert!(storage
+            .fetch_block_checksums(transfer_id, "id1", 1024, 4096, 100)
+            .await
+            .is_empty());
+        storage
+            .set_block_checksums_file_state(transfer_id, "id1", 4096, 100)
+            .await;
+
+        // The blocks following a gap are not usable
+        assert_eq!(
+            storage
+                .fetch_block_checksums(transfer_id, "id1", 1024, 4096, 100)
+                .await,
+            vec![vec![0; 32], vec![1; 32]]
+        );
+        assert!(storage
+            .fetch_block_checksums(transfer_id, "id1", 2048, 4096, 100)
+            .await
+            .is_empty());
+
+        // Overwritten once the block is written again
+        storage
+            .save_block_checksum(transfer_id, "id1", 1024, 1, &[9; 32])
+            .await;
+        storage
+            .save_block_checksum(transfer_id, "id1", 1024, 2, &[2; 32])
+            .await;
+        storage
+            .set_block_checksums_file_state(transfer_id, "id1", 4096, 100)
+            .await;
+        assert_eq!(
+            storage
+                .fetch_block_checksums(transfer_id, "id1", 1024, 4096, 100)
+                .await,
+            vec![vec![0; 32], vec![9; 32], vec![2; 32], vec![3; 32]]
+        );
+
+        storage.remove_block_checksums(transfer_id, "id1", 1).await;
+        assert_eq!(
+            storage
+                .fetch_block_checksums(transfer_id, "id1", 1024, 4096, 100)
+                .await,
+            vec![vec![0; 32]]
+        );
+
+        // Nothing is trusted once the file changes
+        for (size, mtime) in [(4097, 100), (4096, 101)] {
+            assert!(storage
+                .fetch_block_checksums(transfer_id, "id1", 1024, size, mtime)
+                .await
+                .is_empty());
+        }
+    }
+
+    #[tokio::test]
+    async fn schema_version() {
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+        let latest = MIGRATIONS_DIR.dirs().count() as i32;
+
+        let storage = Storage::new(logger.clone(), ":memory:").unwrap();
+        assert_eq!(storage.schema_version().await.unwrap(), latest);
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("schema-version.sqlite");
+        let path = path.to_str().unwrap();
+
+        {
+            let conn = Connection::open(path).unwrap();
+            conn.pragma_update(None, "user_version", latest + 1)
+                .unwrap();
+        }
+
+        let result = Storage::new(logger, path);
+
+        assert!(matches!(
+            result,
+            Err(Error::SchemaTooNew(version, supported)) if version == latest + 1 && supported == latest
+        ));
+    }
+
+    #[test]
+    fn not_writable() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        Migrations::from_directory(&MIGRATIONS_DIR)
+            .unwrap()
+            .to_latest(&mut conn)
+            .unwrap();
+
+        assert!(check_writable(&mut conn).is_ok());
+        // The throwaway row is not left behind
+        let count: i64 = conn
+            .query_row("SELECT COUNT(*) FROM transfers", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(count, 0);
+
+        conn.pragma_update(None, "query_only", true).unwrap();
+        assert!(matches!(
+            check_writable(&mut conn),
+            Err(Error::StorageNotWritable(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn completed_incoming_file() {
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+        let storage = Storage::new(logger, ":memory:").unwrap();
+
+        let transfer_id: Uuid = "23e488a4-0521-11ee-be56-0242ac120002".parse().unwrap();
+
+        let transfer = TransferInfo {
+            id: transfer_id,
+            peer: "5.6.7.8".to_string(),
+            files: TransferFiles::Incoming(vec![
+                TransferIncomingPath {
+                    file_id: "id1".to_string(),
+                    size: 1024,
+                    relative_path: "1".to_string(),
+                },
+                TransferIncomingPath {
+                    file_id: "id2".to_string(),
+                    size: 1024,
+                    relative_path: "2".to_string(),
+                },
+            ]),
+        };
+
+        storage.insert_transfer(&transfer).await;
+        storage.save_checksum(transfer_id, "id1", &[1u8; 32]).await;
+        storage
+            .insert_incoming_path_completed_state(transfer_id, "id1", "/recv/1", false, false)
+            .await;
+
+        let file = storage
+            .completed_incoming_file(transfer_id, "id1")
+            .await
+            .unwrap();
+        assert_eq!(file.final_path, "/recv/1");
+        assert_eq!(file.checksum.as_deref(), Some(&[1u8; 32][..]));
+
+        // Not completed yet
+        assert!(storage
+            .completed_incoming_file(transfer_id, "id2")
+            .await
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn single_file_checksum() {
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+        let storage = Storage::new(logger, ":memory:").unwrap();
+
+        let transfer_id: Uuid = "23e488a4-0521-11ee-be56-0242ac120002".parse().unwrap();
+
+        let transfer = TransferInfo {
+            id: transfer_id,
+            peer: "5.6.7.8".to_string(),
+            files: TransferFiles::Incoming(vec![
+                TransferIncomingPath {
+                    file_id: "id1".to_string(),
+                    size: 1024,
+                    relative_path: "1".to_string(),
+                },
+                TransferIncomingPath {
+                    file_id: "id2".to_string(),
+                    size: 1024,
+                    relative_path: "2".to_string(),
+                },
+            ]),
+        };
+
+        storage.insert_transfer(&transfer).await;
+        storage.save_checksum(transfer_id, "id1", &[1u8; 32]).await;
+
+        assert_eq!(
+            storage.file_checksum(transfer_id, "id1").await.as_deref(),
+            Some(&[1u8; 32][..])
+        );
+        // Checksum not known
+        assert!(storage.file_checksum(transfer_id, "id2").await.is_none());
+        // No such file
+        assert!(storage.file_checksum(transfer_id, "id3").await.is_none());
+    }
+
+    #[tokio::test]
+    async fn cancel_and_reject_by_peer() {
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+        let storage = Storage::new(logger, ":memory:").unwrap();
+
+        let incoming_id: Uuid = "23e488a4-0521-11ee-be56-0242ac120002".parse().unwrap();
+        let outgoing_id: Uuid = "23e48d7c-0521-11ee-be56-0242ac120002".parse().unwrap();
+
+        storage
+            .insert_transfer(&TransferInfo {
+                id: incoming_id,
+                peer: "1.2.3.4".to_string(),
+                files: TransferFiles::Incoming(vec![TransferIncomingPath {
+                    file_id: "id1".to_string(),
+                    relative_path: "1".to_string(),
+                    size: 1024,
+                }]),
+            })
+            .await;
+        storage
+            .insert_transfer(&TransferInfo {
+                id: outgoing_id,
+                peer: "5.6.7.8".to_string(),
+                files: TransferFiles::Outgoing(vec![TransferOutgoingPath {
+                    file_id: "id2".to_string(),
+                    relative_path: "2".to_string(),
+                    uri: "file:///dir/2".parse().unwrap(),
+                    size: 1024,
+                }]),
+            })
+            .await;
+
+        storage
+            .insert_incoming_path_reject_state(incoming_id, "id1", true, 0)
+            .await;
+        storage
+            .insert_transfer_cancel_state(incoming_id, true, CancelCause::Expired)
+            .await;
+        storage
+            .insert_outgoing_path_reject_state(outgoing_id, "id2", false, 0)
+            .await;
+        storage
+            .insert_transfer_cancel_state(outgoing_id, false, CancelCause::Shutdown)
+            .await;
+
+        let transfers = storage.transfers_since(0, TransferSort::CreatedAsc).await;
+        assert_eq!(transfers.len(), 2);
+
+        for transfer in transfers {
+            let expected = transfer.id == incoming_id;
+            let expected_cause = if expected {
+                CancelCause::Expired
+            } else {
+                CancelCause::Shutdown
+            };
+
+            assert!(matches!(
+                transfer.states[..],
+                [TransferStateEvent {
+                    data: types::TransferStateEventData::Cancel { by_peer, cause },
+                    ..
+                }] if by_peer == expected && cause == expected_cause
+            ));
+
+            match transfer.transfer_type {
+                DbTransferType::Incoming(paths) => {
+                    assert!(matches!(
+                        paths[0].states[..],
+                        [IncomingPathStateEvent {
+                            data: IncomingPathStateEventData::Rejected { by_peer, .. },
+                            ..
+                        }] if by_peer
+                    ));
+                }
+                DbTransferType::Outgoing(paths) => {
+                    assert!(matches!(
+                        paths[0].states[..],
+                        [OutgoingPathStateEvent {
+                            data: OutgoingPathStateEventData::Rejected { by_peer, .. },
+                            ..
+                        }] if !by_peer
+                    ));
+                }
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn reject_transfer_with_reason() {
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+        let storage = Storage::new(logger, ":memory:").unwrap();
+
+        let transfer_id: Uuid = "23e488a4-0521-11ee-be56-0242ac120002".parse().unwrap();
+
+        storage
+            .insert_transfer(&TransferInfo {
+                id: transfer_id,
+                peer: "1.2.3.4".to_string(),
+                files: TransferFiles::Incoming(vec![TransferIncomingPath {
+                    file_id: "id1".to_string(),
+                    relative_path: "1".to_string(),
+                    size: 1024,
+                }]),
+            })
+            .await;
+        storage
+            .insert_incoming_path_reject_state(transfer_id, "id1", false, 0)
+            .await;
+        storage
+            .insert_transfer_reject_state(transfer_id, false, "Not expected")
+            .await;
+
+        let transfers = storage.transfers_since(0, TransferSort::CreatedAsc).await;
+        assert_eq!(transfers.len(), 1);
+        assert!(matches!(
+            &transfers[0].states[..],
+            [TransferStateEvent {
+                data: types::TransferStateEventData::Rejected { by_peer: false, reason },
+                ..
+            }] if reason == "Not expected"
+        ));
+
+        let events = storage.transfer_events_since(transfer_id, 0).await;
+        assert!(events.iter().any(|ev| matches!(
+            ev,
+            StateEvent::Transfer(TransferStateEvent {
+                data: types::TransferStateEventData::Rejected { reason, .. },
+                ..
+            }) if reason == "Not expected"
+        )));
+
+        // The rejected transfer is terminated and can be purged
+        assert_eq!(
+            storage.stored_transfer_state(transfer_id).await,
+            Some(StoredTransferState::Canceled)
+        );
+        storage.purge_transfers(&[transfer_id.to_string()]).await;
+        assert!(storage
+            .transfers_since(0, TransferSort::CreatedAsc)
+            .await
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn error_callback() {
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+        let failed = Arc::new(std::sync::Mutex::new(Vec::new()));
+
+        let storage = Storage::new(logger, ":memory:")
+            .unwrap()
+            .with_error_callback({
+                let failed = failed.clone();
+                move |op, _| failed.lock().unwrap().push(op.to_string())
+            });
+
+        storage
+            .conn
+            .lock()
+            .await
+            .execute("DROP TABLE outgoing_checksums", [])
+            .unwrap();
+
+        assert!(storage
+            .fetch_outgoing_checksum("/dir/1", 1024, 100, 512)
+            .await
+            .is_none());
+
+        assert_eq!(*failed.lock().unwrap(), ["fetch_outgoing_checksum"]);
+    }
+
+    #[tokio::test]
+    async fn busy_timeout() {
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+        const LOCK_TIME: Duration = Duration::from_millis(200);
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("busy.sqlite");
+        let path = path.to_str().unwrap().to_string();
+        let failed = Arc::new(std::sync::Mutex::new(Vec::new()));
+
+        let storage = Storage::new(logger.clone(), &path)
+            .unwrap()
+            .with_busy_timeout(Duration::from_secs(5))
+            .unwrap()
+            .with_error_callback({
+                let failed = failed.clone();
+                move |op, _| failed.lock().unwrap().push(op.to_string())
+            });
+
+        // Another writer holds the write lock for a while
+        let (locked_tx, locked_rx) = std::sync::mpsc::channel();
+        let holder = std::thread::spawn({
+            let path = path.clone();
+            move || {
+                let conn = Connection::open(path).unwrap();
+                conn.execute_batch("BEGIN IMMEDIATE").unwrap();
+                locked_tx.send(()).unwrap();
+                std::thread::sleep(LOCK_TIME);
+                conn.execute_batch("COMMIT").unwrap();
+            }
+        });
+        locked_rx.recv().unwrap();
+
+        let transfer_id: Uuid = "23e488a4-0521-11ee-be56-0242ac120002".parse().unwrap();
+        let start = std::time::Instant::now();
+        storage
+            .insert_transfer(&TransferInfo {
+                id: transfer_id,
+                peer: "1.2.3.4".to_string(),
+                files: TransferFiles::Incoming(vec![]),
+            })
+            .await;
+        let elapsed = start.elapsed();
+
+        holder.join().unwrap();
+
+        // The write waits for the lock instead of failing with SQLITE_BUSY
+        assert!(failed.lock().unwrap().is_empty());
+        assert!(elapsed >= LOCK_TIME / 2);
+        assert!(storage.stored_transfer_state(transfer_id).await.is_some());
+    }
+
+    #[tokio::test]
+    async fn stored_transfer_state() {
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+        let storage = Storage::new(logger, ":memory:").unwrap();
+
+        let transfer_id: Uuid = "23e488a4-0521-11ee-be56-0242ac120002".parse().unwrap();
+
+        assert_eq!(storage.stored_transfer_state(transfer_id).await, None);
+
+        storage
+            .insert_transfer(&TransferInfo {
+                id: transfer_id,
+                peer: "1.2.3.4".to_string(),
+                files: TransferFiles::Incoming(vec![
+                    TransferIncomingPath {
+                        file_id: "id1".to_string(),
+                        relative_path: "1".to_string(),
+                        size: 1024,
+                    },
+                    TransferIncomingPath {
+                        file_id: "id2".to_string(),
+                        relative_path: "2".to_string(),
+                        size: 1024,
+                    },
+                ]),
+            })
+            .await;
+
+        assert_eq!(
+            storage.stored_transfer_state(transfer_id).await,
+            Some(StoredTransferState::InProgress)
+        );
+
+        storage
+            .insert_incoming_path_completed_state(transfer_id, "id1", "/recv/1", false, false)
+            .await;
+        assert_eq!(
+            storage.stored_transfer_state(transfer_id).await,
+            Some(StoredTransferState::InProgress)
+        );
+
+        storage
+            .insert_incoming_path_reject_state(transfer_id, "id2", false, 0)
+            .await;
+        assert_eq!(
+            storage.stored_transfer_state(transfer_id).await,
+            Some(StoredTransferState::Completed)
+        );
+
+        storage
+            .insert_transfer_cancel_state(transfer_id, false, CancelCause::User)
+            .await;
+        assert_eq!(
+            storage.stored_transfer_state(transfer_id).await,
+            Some(StoredTransferState::Canceled)
+        );
+
+        storage.purge_transfers(&[transfer_id.to_string()]).await;
+        assert_eq!(storage.stored_transfer_state(transfer_id).await, None);
+    }
+
+    #[tokio::test]
+    async fn transfers_by_state() {
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+        let storage = Storage::new(logger, ":memory:").unwrap();
+
+        let active: Uuid = "23e488a4-0521-11ee-be56-0242ac120002".parse().unwrap();
+        let completed: Uuid = "23e488a4-0521-11ee-be56-0242ac120003".parse().unwrap();
+        let failed: Uuid = "23e488a4-0521-11ee-be56-0242ac120004".parse().unwrap();
+        let canceled: Uuid = "23e488a4-0521-11ee-be56-0242ac120005".parse().unwrap();
+
+        for id in [active, completed, failed, canceled] {
+            storage
+                .insert_transfer(&TransferInfo {
+                    id,
+                    peer: "1.2.3.4".to_string(),
+                    files: TransferFiles::Incoming(vec![TransferIncomingPath {
+                        file_id: "id1".to_string(),
+                        relative_path: "1".to_string(),
+                        size: 1024,
+                    }]),
+                })
+                .await;
+        }
+
+        storage
+            .insert_incoming_path_completed_state(completed, "id1", "/recv/1", false, false)
+            .await;
+        storage.insert_transfer_failed_state(failed, 1).await;
+        storage
+            .insert_transfer_cancel_state(canceled, true, CancelCause::User)
+            .await;
+
+        assert_eq!(
+            storage
+                .transfers_by_state(&[TransferFilter::Active], 0, TransferSort::CreatedAsc)
+                .await,
+            vec![active]
+        );
+        assert_eq!(
+            storage
+                .transfers_by_state(&[TransferFilter::Completed], 0, TransferSort::CreatedAsc)
+                .await,
+            vec![completed]
+        );
+        assert_eq!(
+            storage
+                .transfers_by_state(
+                    &[TransferFilter::Failed, TransferFilter::Canceled],
+                    0,
+                    TransferSort::CreatedAsc
                 )
-                select t.*, ts.*, t.rowid from transfers t
-                    left join ts on ts.transfer_id = t.id
-                    where not t.is_deleted and t.created_at >= datetime(?1, 'unixepoch')
-                "#,
-                )?
-                .query_map(params![since_timestamp], |row| {
-                    let id = Uuid::parse_str(row.get::<_, String>(0)?.as_str())
-                        .map_err(|_| rusqlite::Error::InvalidQuery)?;
-                    let rowid: u64 = row.get(10)?;
-                    let transfer: &mut Transfer = &mut match transfers_map.entry(id) {
-                        Occupied(e) => e.into_mut(),
-                        Vacant(k) => {
-                            let transfer_type = match row.get::<_, u32>(2)? {
-                                0 => DbTransferType::Incoming(vec![]),
-                                1 => DbTransferType::Outgoing(vec![]),
-                                _ => unreachable!(),
-                            };
-                            let t = Transfer {
-                                id,
-                                peer_id: row.get(1)?,
-                                transfer_type,
-                                created_at: row.get(3)?,
-                                states: vec![],
-                            };
-                            k.insert((rowid, t))
-                        }
-                    }
-                    .1;
-                    let status_type: Option<i64> = row.get(5)?;
-                    match status_type {
-                        Some(1) => transfer.states.push(TransferStateEvent {
-                            transfer_id: transfer.id,
-                            created_at: row.get(9)?,
-                            data: types::TransferStateEventData::Cancel {
-                                by_peer: row.get(8)?,
-                            },
-                        }),
-                        Some(2) => transfer.states.push(TransferStateEvent {
-                            transfer_id: transfer.id,
-                            created_at: row.get(9)?,
-                            data: types::TransferStateEventData::Failed {
-                                status_code: row.get(8)?,
-                            },
-                        }),
-                        Some(other) => warn!(
-                                        self.logger,
-                                        "Unexpected union member identifier for transfer state";
-                                        "identifier" => other),
-                        None => {
-                            // This was a transfer without any states.
-                        }
-                    }
-                    Ok(())
-                })?
-                .count();
+                .await,
+            vec![failed, canceled]
+        );
+        assert!(storage
+            .transfers_by_state(&[], 0, TransferSort::CreatedAsc)
+            .await
+            .is_empty());
 
-            let mut outgoing_paths: HashMap<i64, OutgoingPath> = HashMap::new();
-            // Here is the same situation as before - because the columns after created_at
-            // are all integers, they can be shared.
-            let _ = tx.prepare(r#"
-            WITH ops AS (
-                select 1, path_id, created_at, bytes_sent, null from outgoing_path_started_states
-                union all
-                select 2, path_id, created_at, status_code, bytes_sent from outgoing_path_failed_states
-                union all
-                select 3, path_id, created_at, null, null from outgoing_path_completed_states
-                union all
-                select 4, path_id, created_at, by_peer, bytes_sent from outgoing_path_reject_states
-                union all
-                select 5, path_id, created_at, bytes_sent, null from outgoing_path_paused_states
-            )
-            SELECT op.*, ops.*, op.rowid from outgoing_paths op
-                left join ops on ops.path_id = op.id
-                left join transfers t on t.id = op.transfer_id and not t.is_deleted and t.created_at >= datetime(?1, 'unixepoch')
-                where not op.is_deleted
-            "#)?.query_map(params![since_timestamp], |row| {
-                let path_id: i64 = row.get(0)?;
-                let path = match outgoing_paths.entry(path_id) {
-                    Occupied(p) => p.into_mut(),
-                    Vacant(e) => {
-                        let transfer_id: String = row.get(1)?;
-                        let mut res = OutgoingPath {
-                            id: *e.key(),
-                            transfer_id: Uuid::parse_str(&transfer_id).map_err(|_| rusqlite::Error::InvalidQuery)?,
-                            content_uri: None,
-                            base_path: None,
-                            relative_path: row.get(2)?,
-                            file_id: row.get(4)?,
-                            bytes: row.get(5)?,
-                            bytes_sent: 0,
-                            created_at: row.get(6)?,
-                            states: vec![],
-                        };
-                        let uri_str: String = row.get(3)?;
-                        let uri = url::Url::parse(&uri_str).map_err(|_| rusqlite::Error::InvalidQuery)?; // Error handling like uuid
+        // The transfers created at or after the timestamp are returned
+        {
+            let conn = storage.conn.lock().await;
+            for (id, created_at) in [(active, 1000), (completed, 2000)] {
+                conn.execute(
+                    "UPDATE transfers SET created_at = datetime(?1, 'unixepoch') WHERE id = ?2",
+                    params![created_at, id.to_string()],
+                )
+                .unwrap();
+            }
+        }
 
-                        match uri.scheme() {
-                            "content" => res.content_uri = Some(uri),
-                            "file" => {
-                                let mut path = uri.to_file_path().map_err(|_| rusqlite::Error::InvalidQuery)?; // Error handling like uuid
+        let all = [
+            TransferFilter::Active,
+            TransferFilter::Completed,
+            TransferFilter::Failed,
+            TransferFilter::Canceled,
+        ];
+        assert_eq!(
+            storage
+                .transfers_by_state(&all, 1000, TransferSort::CreatedAsc)
+                .await
+                .len(),
+            4
+        );
+        assert_eq!(
+            storage
+                .transfers_by_state(&all, 1001, TransferSort::CreatedAsc)
+                .await
+                .len(),
+            3
+        );
+        assert_eq!(
+            storage
+                .transfers_by_state(&[TransferFilter::Completed], 2000, TransferSort::CreatedAsc)
+                .await,
+            vec![completed]
+        );
+        assert!(storage
+            .transfers_by_state(&[TransferFilter::Completed], 2001, TransferSort::CreatedAsc)
+            .await
+            .is_empty());
+    }
 
-                                let count = Path::new(&res.relative_path).components().count();
-                                for _ in 0..count {
-                                    path.pop();
-                                }
+    #[tokio::test]
+    async fn peer_usage() {
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+        let storage = Storage::new(logger, ":memory:").unwrap();
 
-                                res.base_path = Some(path);
-                            }
-                            unknown => {
-                                warn!(
-                                        self.logger,
-                                        "Unexpected URI scheme when decoding transfer outgoing path's base_path";
-                                        "scheme" => unknown,
-                                    "uri" => uri.to_string());
-                                return Err(rusqlite::Error::InvalidQuery);
-                            }
-                        }
-                        e.insert(res)
-                    }
-                };
+        let incoming: Uuid = "23e488a4-0521-11ee-be56-0242ac120002".parse().unwrap();
+        let outgoing: Uuid = "23e488a4-0521-11ee-be56-0242ac120003".parse().unwrap();
 
-                let opt_status_type: Option<i32> = row.get(8)?;
-                if let Some(status_type) = opt_status_type {
-                    let created_at = row.get(10)?;
-                    match status_type {
-                        1 => path.states.push(OutgoingPathStateEvent {
-                            path_id,
-                            created_at,
-                            data: OutgoingPathStateEventData::Started {
-                                bytes_sent: row.get(11)?,
-                            },
-                        }),
-                        2 => path.states.push(OutgoingPathStateEvent {
-                            path_id,
-                            created_at,
-                            data: OutgoingPathStateEventData::Failed {
-                                status_code: row.get(11)?,
-                                bytes_sent: row.get(12)?,
-                            },
-                        }),
-                        3 => path.states.push(OutgoingPathStateEvent {
-                            path_id,
-                            created_at,
-                            data: OutgoingPathStateEventData::Completed,
-                        }),
-                        4 => path.states.push(OutgoingPathStateEvent {
-                            path_id,
-                            created_at,
-                            data: OutgoingPathStateEventData::Rejected {
-                                by_peer: row.get(11)?,
-                                bytes_sent: row.get(12)?,
-                            },
-                        }),
-                        5 => path.states.push(OutgoingPathStateEvent {
-                            path_id,
-                            created_at,
-                            data: OutgoingPathStateEventData::Paused {
-                                bytes_sent: row.get(11)?
-                            },
-                        }),
-                        other => warn!(
-                                        self.logger,
-                                        "Unexpected union member identifier for outgoing path status";
-                                        "identifier" => other)
-                    }
-                }
+        storage
+            .insert_transfer(&TransferInfo {
+                id: incoming,
+                peer: "1.2.3.4".to_string(),
+                files: TransferFiles::Incoming(vec![
+                    TransferIncomingPath {
+                        file_id: "id1".to_string(),
+                        relative_path: "1".to_string(),
+                        size: 1024,
+                    },
+                    TransferIncomingPath {
+                        file_id: "id2".to_string(),
+                        relative_path: "2".to_string(),
+                        size: 2048,
+                    },
+                ]),
+            })
+            .await;
+
+        storage
+            .insert_transfer(&TransferInfo {
+                id: outgoing,
+                peer: "1.2.3.4".to_string(),
+                files: TransferFiles::Outgoing(vec![TransferOutgoingPath {
+                    file_id: "id3".to_string(),
+                    relative_path: "3".to_string(),
+                    uri: "file:///dir".parse().unwrap(),
+                    size: 4096,
+                }]),
+            })
+            .await;
 
-                Ok(())
-            })?.count();
+        assert_eq!(storage.peer_usage("1.2.3.4").await, PeerUsage::default());
 
-            for (_, mut path) in outgoing_paths {
-                path.states.sort_by(|a, b| a.created_at.cmp(&b.created_at));
+        storage
+            .insert_incoming_path_completed_state(incoming, "id1", "/recv/1", false, false)
+            .await;
+        storage
+            .insert_incoming_path_failed_state(incoming, "id2", 1, 100)
+            .await;
+        storage
+            .insert_outgoing_path_reject_state(outgoing, "id3", true, 512)
+            .await;
 
-                path.bytes_sent = path.states.last().map_or(0, |state| match state.data {
-                    OutgoingPathStateEventData::Started { bytes_sent } => bytes_sent,
-                    OutgoingPathStateEventData::Failed { bytes_sent, .. } => bytes_sent,
-                    OutgoingPathStateEventData::Completed => path.bytes,
-                    OutgoingPathStateEventData::Rejected { bytes_sent, .. } => bytes_sent,
-                    OutgoingPathStateEventData::Paused { bytes_sent } => bytes_sent,
-                });
-                if let Some((_, t)) = transfers_map.get_mut(&path.transfer_id) {
-                    if let DbTransferType::Outgoing(pp) = &mut t.transfer_type {
-                        pp.push(path)
-                    }
-                }
-            }
+        // Terminal states stored again, e.g. on resume, must not be counted twice
+        storage
+            .insert_incoming_path_completed_state(incoming, "id1", "/recv/1", false, false)
+            .await;
+        storage
+            .insert_outgoing_path_completed_state(outgoing, "id3")
+            .await;
 
-            let mut incoming_paths: HashMap<i64, IncomingPath> = HashMap::new();
-            // And this is more interesting - base_ir and final_patch are text type. For
-            // these fields a separate column will be used.
-            let _ = tx.prepare(r#"
-            WITH ips AS (
-                select 1, path_id, created_at, null, null, base_dir from incoming_path_pending_states
-                union all
-                select 2, path_id, created_at, bytes_received, null, null from incoming_path_started_states
-                union all
-                select 3, path_id, created_at, status_code, bytes_received, null from incoming_path_failed_states
-                union all
-                select 4, path_id, created_at, null, null, final_path from incoming_path_completed_states
-                union all
-                select 5, path_id, created_at, by_peer, bytes_received, null from incoming_path_reject_states
-                union all
-                select 6, path_id, created_at, bytes_received, null, null from incoming_path_paused_states
-            )
-            SELECT ip.*, ips.* from incoming_paths ip
-                left join ips on ips.path_id = ip.id
-                left join transfers t on t.id = ip.transfer_id and not t.is_deleted and t.created_at >= datetime(?1, 'unixepoch')
-                where not ip.is_deleted
-                order by ip.rowid
-            "#)?.query_map(params![since_timestamp], |row| {
-                let path_id: i64 = row.get(0)?;
-                let path = match incoming_paths.entry(path_id) {
-                    Occupied(p) => p.into_mut(),
-                    Vacant(e) => {
-                        let transfer_id: String = row.get(1)?;
-                        let res = IncomingPath {
-                            id: *e.key(),
-                            transfer_id: Uuid::parse_str(&transfer_id).map_err(|_| rusqlite::Error::InvalidQuery)?,
-                            relative_path: row.get(2)?,
-                            file_id: row.get(3)?,
-                            bytes: row.get(4)?,
-                            bytes_received: 0,
-                            created_at: row.get(5)?,
-                            states: vec![],
-                        };
-                        e.insert(res)
-                    }
-                };
+        let usage = storage.peer_usage("1.2.3.4").await;
+        assert_eq!(usage.bytes_sent, 512);
+        assert_eq!(usage.bytes_received, 1124);
+        assert!(usage.updated_at.is_some());
 
-                let opt_status_type: Option<i32> = row.get(8)?;
-                if let Some(status_type) = opt_status_type {
-                    let created_at = row.get(10)?;
-                    match status_type {
-                        1 => path.states.push(IncomingPathStateEvent {
-                            path_id,
-                            created_at,
-                            data: IncomingPathStateEventData::Pending {
-                                base_dir: row.get(13)?
-                            },
-                        }),
-                        2 => path.states.push(IncomingPathStateEvent {
-                            path_id,
-                            created_at,
-                            data: IncomingPathStateEventData::Started {
-                                bytes_received: row.get(11)?
-                            },
-                        }),
-                        3 => path.states.push(IncomingPathStateEvent {
-                            path_id,
-                            created_at,
-                            data: IncomingPathStateEventData::Failed {
-                                status_code: row.get(11)?,
-                                bytes_received: row.get(12)?,
-                            },
-                        }),
-                        4 => path.states.push(IncomingPathStateEvent {
-                            path_id,
-                            created_at,
-                            data: IncomingPathStateEventData::Completed {
-                                final_path: row.get(13)?
-                            },
-                        }),
-                        5 => path.states.push(IncomingPathStateEvent {
-                            path_id,
-                            created_at,
-                            data: IncomingPathStateEventData::Rejected {
-                                by_peer: row.get(11)?,
-                                bytes_received: row.get(12)?,
-                            },
-                        }),
-                        6 => path.states.push(IncomingPathStateEvent {
-                            path_id,
-                            created_at,
-                            data: IncomingPathStateEventData::Paused {
-                                bytes_received: row.get(11)?
-                            },
-                        }),
-                        _ => {}
-                    }
-                }
+        assert_eq!(storage.peer_usage("5.6.7.8").await, PeerUsage::default());
 
-                Ok(())
-            })?.count();
+        storage.reset_peer_usage("1.2.3.4").await;
+        assert_eq!(storage.peer_usage("1.2.3.4").await, PeerUsage::default());
+    }
 
-            for (_, mut path) in incoming_paths {
-                path.states.sort_by(|a, b| a.created_at.cmp(&b.created_at));
+    #[tokio::test]
+    async fn prune_intermediate_states() {
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+        let storage = Storage::new(logger, ":memory:").unwrap();
 
-                path.bytes_received = path
-                    .states
-                    .iter()
-                    .rev()
-                    .find_map(|state| match state.data {
-                        IncomingPathStateEventData::Pending { .. } => None,
-                        IncomingPathStateEventData::Started { bytes_received, .. } => {
-                            Some(bytes_received)
-                        }
-                        IncomingPathStateEventData::Failed { bytes_received, .. } => {
-                            Some(bytes_received)
-                        }
-                        IncomingPathStateEventData::Completed { .. } => Some(path.bytes),
-                        IncomingPathStateEventData::Rejected { bytes_received, .. } => {
-                            Some(bytes_received)
-                        }
-                        IncomingPathStateEventData::Paused { bytes_received } => {
-                            Some(bytes_received)
-                        }
-                    })
-                    .unwrap_or(0);
+        let transfer_id: Uuid = "23e488a4-0521-11ee-be56-0242ac120002".parse().unwrap();
 
-                if let Some((_, t)) = transfers_map.get_mut(&path.transfer_id) {
-                    if let DbTransferType::Incoming(ip) = &mut t.transfer_type {
-                        ip.push(path)
-                    }
-                }
-            }
-            drop(tx);
-            drop(conn);
-            let mut transfers: Vec<(u64, Transfer)> = transfers_map.into_values().collect();
-            transfers.sort_by_key(|rt| rt.0);
-            let mut transfers: Vec<Transfer> = transfers.into_iter().map(|rt| rt.1).collect();
-            for transfer in &mut transfers {
-                transfer
-                    .states
-                    .sort_by(|a, b| a.created_at.cmp(&b.created_at));
-                match transfer.transfer_type {
-                    DbTransferType::Incoming(ref mut p) => p.sort_by_key(|ip| ip.id),
-                    DbTransferType::Outgoing(ref mut p) => p.sort_by_key(|op| op.id),
-                };
-            }
-            Ok::<Vec<_>, Error>(transfers)
-        };
+        storage
+            .insert_transfer(&TransferInfo {
+                id: transfer_id,
+                peer: "1.2.3.4".to_string(),
+                files: TransferFiles::Incoming(vec![
+                    TransferIncomingPath {
+                        file_id: "id1".to_string(),
+                        relative_path: "1".to_string(),
+                        size: 1024,
+                    },
+                    TransferIncomingPath {
+                        file_id: "id2".to_string(),
+                        relative_path: "2".to_string(),
+                        size: 1024,
+                    },
+                ]),
+            })
+            .await;
 
-        match task.await {
-            Ok(transfers) => transfers,
-            Err(e) => {
-                error!(self.logger, "Failed to get transfers since timestamp"; "error" => %e);
-                vec![]
+        for bytes in [0, 100, 200] {
+            for file_id in ["id1", "id2"] {
+                storage
+                    .insert_incoming_path_started_state(transfer_id, file_id, bytes)
+                    .await;
+                storage
+                    .insert_incoming_path_paused_state(transfer_id, file_id, bytes + 50)
+                    .await;
             }
         }
-    }
-
-    pub async fn remove_transfer_file(&self, transfer_id: Uuid, file_id: &str) -> Option<()> {
-        let tid = transfer_id.to_string();
+        storage
+            .insert_incoming_path_completed_state(transfer_id, "id1", "/recv/1", false, false)
+            .await;
 
-        trace!(
-            self.logger,
-            "Removing transfer file";
-            "transfer_id" => &tid,
-            "file_id" => file_id,
-        );
+        assert_eq!(storage.prune_intermediate_states(1).await, 8);
+        assert_eq!(storage.prune_intermediate_states(1).await, 0);
+
+        let conn = storage.conn.lock().await;
+        let started: Vec<i64> = conn
+            .prepare("SELECT bytes_received FROM incoming_path_started_states ORDER BY rowid")
+            .unwrap()
+            .query_map([], |r| r.get(0))
+            .unwrap()
+            .collect::<QueryResult<_>>()
+            .unwrap();
+        assert_eq!(started, vec![200, 200]);
+
+        let paused: Vec<i64> = conn
+            .prepare("SELECT bytes_received FROM incoming_path_paused_states ORDER BY rowid")
+            .unwrap()
+            .query_map([], |r| r.get(0))
+            .unwrap()
+            .collect::<QueryResult<_>>()
+            .unwrap();
+        assert_eq!(paused, vec![250, 250]);
+
+        let completed: i64 = conn
+            .query_row(
+                "SELECT count(*) FROM incoming_path_completed_states",
+                [],
+                |r| r.get(0),
+            )
+            .unwrap();
+        assert_eq!(completed, 1);
+    }
 
-        let task = async {
-            let conn = self.conn.lock().await;
+    #[tokio::test]
+    async fn transfers_sort() {
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+        let storage = Storage::new(logger, ":memory:").unwrap();
 
-            let mut count = 0;
-            count += conn
-                .prepare(
-                    r#"
-                UPDATE outgoing_paths
-                SET is_deleted = TRUE
-                WHERE transfer_id = ?1
-                    AND path_hash = ?2
-                    AND (
-                        id IN(SELECT path_id FROM outgoing_path_reject_states) OR
-                        id IN(SELECT path_id FROM outgoing_path_failed_states) OR
-                        id IN(SELECT path_id FROM outgoing_path_completed_states)
-                    )
-            "#,
-                )?
-                .execute(params![tid, file_id])?;
-            count += conn
-                .prepare(
-                    r#"
-                UPDATE incoming_paths
-                SET is_deleted = TRUE
-                WHERE transfer_id = ?1
-                    AND path_hash = ?2
-                    AND (
-                        id IN(SELECT path_id FROM incoming_path_reject_states) OR
-                        id IN(SELECT path_id FROM incoming_path_failed_states) OR
-                        id IN(SELECT path_id FROM incoming_path_completed_states)
-                    )
-            "#,
-                )?
-                .execute(params![tid, file_id])?;
+        let first: Uuid = "23e488a4-0521-11ee-be56-0242ac120002".parse().unwrap();
+        let second: Uuid = "23e488a4-0521-11ee-be56-0242ac120003".parse().unwrap();
+        let third: Uuid = "23e488a4-0521-11ee-be56-0242ac120004".parse().unwrap();
 
-            match count {
-                0 => Ok::<Option<()>, Error>(None),
-                1 => Ok(Some(())),
-                _ => {
-                    warn!(
-                        self.logger,
-                        "Deleted a file from both outgoing and incoming paths"
-                    );
-                    Ok(Some(()))
-                }
-            }
-        };
+        for (id, peer) in [(first, "5.6.7.8"), (second, "1.2.3.4"), (third, "5.6.7.8")] {
+            storage
+                .insert_transfer(&TransferInfo {
+                    id,
+                    peer: peer.to_string(),
+                    files: TransferFiles::Incoming(vec![TransferIncomingPath {
+                        file_id: "id1".to_string(),
+                        relative_path: "1".to_string(),
+                        size: 1024,
+                    }]),
+                })
+                .await;
+        }
+        storage
+            .insert_transfer_cancel_state(first, true, CancelCause::User)
+            .await;
 
-        match task.await {
-            Ok(res) => res,
-            Err(e) => {
-                error!(self.logger, "Failed to remove transfer file"; "error" => %e);
-                None
-            }
+        for (sort, expected) in [
+            (TransferSort::CreatedAsc, [first, second, third]),
+            (TransferSort::CreatedDesc, [third, second, first]),
+            (TransferSort::PeerThenCreated, [second, first, third]),
+        ] {
+            let ids: Vec<Uuid> = storage
+                .transfers_since(0, sort)
+                .await
+                .into_iter()
+                .map(|t| t.id)
+                .collect();
+            assert_eq!(ids, expected);
+
+            let ids = storage
+                .transfers_by_state(&[TransferFilter::Active, TransferFilter::Canceled], 0, sort)
+                .await;
+            assert_eq!(ids, expected);
         }
     }
 
-    pub async fn fetch_temp_locations(&self, transfer_id: Uuid) -> Vec<TempFileLocation> {
-        let tid = transfer_id.to_string();
+    #[tokio::test]
+    async fn transfers_order_with_clock_skew() {
+        use std::sync::atomic::{AtomicU64, Ordering};
 
-        trace!(
-            self.logger,
-            "Fetching temporary file locations";
-            "transfer_id" => &tid
-        );
+        let now = Arc::new(AtomicU64::new(2000));
+        let clock = now.clone();
 
-        let task = async {
-            let conn = self.conn.lock().await;
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+        let storage = Storage::new(logger, ":memory:")
+            .unwrap()
+            .with_clock(move || {
+                SystemTime::UNIX_EPOCH + Duration::from_secs(clock.load(Ordering::SeqCst))
+            });
+
+        let first: Uuid = "23e488a4-0521-11ee-be56-0242ac120002".parse().unwrap();
+        let second: Uuid = "23e488a4-0521-11ee-be56-0242ac120003".parse().unwrap();
+
+        for (id, secs) in [(first, 2000), (second, 1000)] {
+            now.store(secs, Ordering::SeqCst);
+            storage
+                .insert_transfer(&TransferInfo {
+                    id,
+                    peer: "1.2.3.4".to_string(),
+                    files: TransferFiles::Incoming(vec![TransferIncomingPath {
+                        file_id: "id1".to_string(),
+                        relative_path: "1".to_string(),
+                        size: 1024,
+                    }]),
+                })
+                .await;
+        }
 
-            let out = conn
-                .prepare(
-                    r#"
-                SELECT DISTINCT path_hash, base_dir
-                FROM incoming_paths ip
-                INNER JOIN incoming_path_pending_states ipss ON ip.id = ipss.path_id 
-                WHERE transfer_id = ?1
-                "#,
-                )?
-                .query_map(params![tid], |row| {
-                    Ok(TempFileLocation {
-                        file_id: row.get("path_hash")?,
-                        base_path: row.get("base_dir")?,
-                    })
-                })?
-                .collect::<QueryResult<_>>()?;
+        // The clock went backward but the insertion order is kept
+        let transfers = storage.transfers_since(0, TransferSort::CreatedAsc).await;
+        let ids: Vec<Uuid> = transfers.iter().map(|t| t.id).collect();
+        assert_eq!(ids, [first, second]);
+        assert_eq!(transfers[0].created_at.and_utc().timestamp(), 2000);
+        assert_eq!(transfers[1].created_at.and_utc().timestamp(), 1000);
 
-            Ok::<Vec<_>, Error>(out)
-        };
+        let ids = storage
+            .transfers_by_state(&[TransferFilter::Active], 0, TransferSort::CreatedDesc)
+            .await;
+        assert_eq!(ids, [second, first]);
 
-        match task.await {
-            Ok(res) => res,
-            Err(e) => {
-                error!(self.logger, "Failed to fetch temporary file locations"; "error" => %e);
-                vec![]
-            }
-        }
+        // The timestamp is still used for filtering
+        let ids: Vec<Uuid> = storage
+            .transfers_since(1500, TransferSort::CreatedAsc)
+            .await
+            .into_iter()
+            .map(|t| t.id)
+            .collect();
+        assert_eq!(ids, [first]);
     }
 
-    pub async fn fetch_base_dirs_for_file(&self, transfer_id: Uuid, file_id: &str) -> Vec<String> {
-        let tid = transfer_id.to_string();
+    #[tokio::test]
+    async fn renamed_incoming_files() {
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+        let storage = Storage::new(logger, ":memory:").unwrap();
 
-        trace!(
-            self.logger,
-            "Fetching temporary file locations";
-            "transfer_id" => &tid
-        );
+        let transfer_id: Uuid = "23e488a4-0521-11ee-be56-0242ac120002".parse().unwrap();
 
-        let task = async {
-            let conn = self.conn.lock().await;
+        storage
+            .insert_transfer(&TransferInfo {
+                id: transfer_id,
+                peer: "5.6.7.8".to_string(),
+                files: TransferFiles::Incoming(vec![
+                    TransferIncomingPath {
+                        file_id: "id1".to_string(),
+                        size: 1024,
+                        relative_path: "a.txt".to_string(),
+                    },
+                    TransferIncomingPath {
+                        file_id: "id2".to_string(),
+                        size: 1024,
+                        relative_path: "b.txt".to_string(),
+                    },
+                ]),
+            })
+            .await;
+        storage
+            .insert_incoming_path_completed_state(transfer_id, "id1", "/recv/a.txt", false, false)
+            .await;
+        storage
+            .insert_incoming_path_completed_state(transfer_id, "id2", "/recv/b(1).txt", true, false)
+            .await;
 
-            let out = conn
-                .prepare(
-                    r#"
-                SELECT DISTINCT base_dir
-                FROM incoming_paths ip
-                INNER JOIN incoming_path_pending_states ipss ON ip.id = ipss.path_id 
-                WHERE transfer_id = ?1 AND path_hash = ?2
-                "#,
-                )?
-                .query_map(params![tid, file_id], |row| row.get("base_dir"))?
-                .collect::<QueryResult<_>>()?;
+        let mut files: Vec<_> = storage
+            .finished_incoming_files(transfer_id)
+            .await
+            .into_iter()
+            .map(|f| (f.final_path, f.was_renamed))
+            .collect();
+        files.sort();
+        assert_eq!(
+            files,
+            [
+                ("/recv/a.txt".to_string(), false),
+                ("/recv/b(1).txt".to_string(), true)
+            ]
+        );
 
-            Ok::<Vec<_>, Error>(out)
+        let transfers = storage.transfers_since(0, TransferSort::CreatedAsc).await;
+        let DbTransferType::Incoming(paths) = &transfers[0].transfer_type else {
+            panic!("Expected incoming transfer");
         };
-
-        match task.await {
-            Ok(res) => res,
-            Err(e) => {
-                error!(self.logger, "Failed to fetch temporary file locations for {file_id}"; "error" => %e);
-                vec![]
-            }
-        }
+        let renamed: Vec<_> = paths
+            .iter()
+            .flat_map(|path| {
+                path.states.iter().filter_map(|state| match &state.data {
+                    IncomingPathStateEventData::Completed { was_renamed, .. } => {
+                        Some((path.file_id.as_str(), *was_renamed))
+                    }
+                    _ => None,
+                })
+            })
+            .collect();
+        assert_eq!(renamed, [("id1", false), ("id2", true)]);
     }
 
-    pub async fn cleanup_garbage_transfers(&self) -> usize {
-        trace!(self.logger, "Removing garbage transfers");
+    #[tokio::test]
+    async fn deleted_incoming_files() {
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+        let storage = Storage::new(logger, ":memory:").unwrap();
 
-        let task = async {
-            let conn = self.conn.lock().await;
+        let transfer_id: Uuid = "23e488a4-0521-11ee-be56-0242ac120002".parse().unwrap();
+        storage
+            .insert_transfer(&TransferInfo {
+                id: transfer_id,
+                peer: "5.6.7.8".to_string(),
+                files: TransferFiles::Incoming(vec![TransferIncomingPath {
+                    file_id: "id1".to_string(),
+                    size: 1024,
+                    relative_path: "a.txt".to_string(),
+                }]),
+            })
+            .await;
+        storage
+            .insert_incoming_path_completed_state(transfer_id, "id1", "/recv/a.txt", false, true)
+            .await;
 
-            let count = conn.execute(
-                r#"
-                DELETE FROM transfers WHERE id IN (
-                    SELECT t.id 
-                    FROM transfers t
-                    LEFT JOIN sync_transfer st ON t.id = st.transfer_id
-                    WHERE t.is_deleted AND st.sync_id IS NULL                    
-                )
-                "#,
-                params![],
-            )?;
+        // The location the file was placed at is kept along with the deletion
+        let file = storage
+            .completed_incoming_file(transfer_id, "id1")
+            .await
+            .unwrap();
+        assert_eq!(file.final_path, "/recv/a.txt");
+        assert!(file.deleted);
 
-            debug!(self.logger, "Removed {count} garbage transfers");
-            Result::Ok(count)
+        let transfers = storage.transfers_since(0, TransferSort::CreatedAsc).await;
+        let DbTransferType::Incoming(paths) = &transfers[0].transfer_type else {
+            panic!("Expected incoming transfer");
         };
-
-        match task.await {
-            Err(err) => {
-                error!(self.logger, "Failed to remove garbage transfers: {err}");
-                0
-            }
-            Ok(count) => count,
-        }
+        assert!(matches!(
+            &paths[0].states[..],
+            [IncomingPathStateEvent {
+                data: IncomingPathStateEventData::Completed { deleted: true, .. },
+                ..
+            }]
+        ));
     }
-}
-
-#[cfg(test)]
-mod tests {
-    use super::*;
 
     #[tokio::test]
-    async fn test_insert_transfer() {
+    async fn transfer_file_manifest() {
         let logger = slog::Logger::root(slog::Discard, slog::o!());
         let storage = Storage::new(logger, ":memory:").unwrap();
 
-        let transfer_id_1: Uuid = "23e488a4-0521-11ee-be56-0242ac120002".parse().unwrap();
-        let transfer_id_2: Uuid = "23e48d7c-0521-11ee-be56-0242ac120002".parse().unwrap();
+        let incoming_id: Uuid = "23e488a4-0521-11ee-be56-0242ac120002".parse().unwrap();
+        let outgoing_id: Uuid = "23e48d7c-0521-11ee-be56-0242ac120002".parse().unwrap();
 
-        {
-            let transfer = TransferInfo {
-                id: transfer_id_1,
-                peer: "1.2.3.4".to_string(),
+        storage
+            .insert_transfer(&TransferInfo {
+                id: incoming_id,
+                peer: "5.6.7.8".to_string(),
                 files: TransferFiles::Incoming(vec![
                     TransferIncomingPath {
                         file_id: "id1".to_string(),
-                        relative_path: "1".to_string(),
                         size: 1024,
+                        relative_path: "dir/a.txt".to_string(),
                     },
                     TransferIncomingPath {
                         file_id: "id2".to_string(),
-                        relative_path: "2".to_string(),
                         size: 2048,
+                        relative_path: "dir/b.txt".to_string(),
                     },
-                ]),
-            };
-
-            storage.insert_transfer(&transfer).await;
-        }
-
-        {
-            let transfer = TransferInfo {
-                id: transfer_id_2,
-                peer: "5.6.7.8".to_string(),
-                files: TransferFiles::Outgoing(vec![
-                    TransferOutgoingPath {
+                    TransferIncomingPath {
                         file_id: "id3".to_string(),
-                        size: 1024,
-                        uri: "file:///dir".parse().unwrap(),
-                        relative_path: "3".to_string(),
-                    },
-                    TransferOutgoingPath {
-                        file_id: "id4".to_string(),
-                        relative_path: "4".to_string(),
-                        uri: "file:///dir".parse().unwrap(),
-                        size: 2048,
+                        size: 4096,
+                        relative_path: "c.txt".to_string(),
                     },
                 ]),
-            };
+            })
+            .await;
+        storage
+            .insert_transfer(&TransferInfo {
+                id: outgoing_id,
+                peer: "5.6.7.8".to_string(),
+                files: TransferFiles::Outgoing(vec![TransferOutgoingPath {
+                    file_id: "id4".to_string(),
+                    size: 512,
+                    relative_path: "d.txt".to_string(),
+                    uri: "file:///tmp/d.txt".parse().unwrap(),
+                }]),
+            })
+            .await;
 
-            storage.insert_transfer(&transfer).await;
-        }
+        storage
+            .start_incoming_file(incoming_id, "id1", "/recv")
+            .await;
+        storage
+            .insert_incoming_path_started_state(incoming_id, "id1", 0)
+            .await;
+        storage
+            .insert_incoming_path_completed_state(
+                incoming_id,
+                "id1",
+                "/recv/dir/a.txt",
+                false,
+                false,
+            )
+            .await;
+        storage
+            .insert_incoming_path_reject_state(incoming_id, "id2", false, 0)
+            .await;
 
-        {
-            let transfers = storage.transfers_since(0).await;
-            assert_eq!(transfers.len(), 2);
+        let manifest = storage.transfer_file_manifest(incoming_id).await;
+        assert_eq!(
+            manifest,
+            [
+                FileManifestEntry {
+                    file_id: "id1".to_string(),
+                    subpath: "dir/a.txt".to_string(),
+                    size: 1024,
+                    direction: TransferType::Incoming,
+                    latest_state_kind: Some(FileStateKind::Completed),
+                },
+                FileManifestEntry {
+                    file_id: "id2".to_string(),
+                    subpath: "dir/b.txt".to_string(),
+                    size: 2048,
+                    direction: TransferType::Incoming,
+                    latest_state_kind: Some(FileStateKind::Rejected),
+                },
+                FileManifestEntry {
+                    file_id: "id3".to_string(),
+                    subpath: "c.txt".to_string(),
+                    size: 4096,
+                    direction: TransferType::Incoming,
+                    latest_state_kind: None,
+                },
+            ]
+        );
 
-            let incoming_transfer = &transfers[0];
-            let outgoing_transfer = &transfers[1];
+        let manifest = storage.transfer_file_manifest(outgoing_id).await;
+        assert_eq!(manifest.len(), 1);
+        assert_eq!(manifest[0].direction, TransferType::Outgoing);
+        assert_eq!(manifest[0].latest_state_kind, None);
 
-            assert_eq!(incoming_transfer.id, transfer_id_1);
-            assert_eq!(outgoing_transfer.id, transfer_id_2);
+        storage
+            .insert_outgoing_path_started_state(outgoing_id, "id4", 0)
+            .await;
+        storage
+            .insert_outgoing_path_paused_state(outgoing_id, "id4", 256)
+            .await;
 
-            assert_eq!(incoming_transfer.peer_id, "1.2.3.4".to_string());
-            assert_eq!(outgoing_transfer.peer_id, "5.6.7.8".to_string());
+        let manifest = storage.transfer_file_manifest(outgoing_id).await;
+        assert_eq!(manifest[0].latest_state_kind, Some(FileStateKind::Paused));
+
+        assert!(storage
+            .transfer_file_manifest(Uuid::new_v4())
+            .await
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn peers_summary() {
+        use std::sync::atomic::{AtomicU64, Ordering};
+
+        let now = Arc::new(AtomicU64::new(0));
+        let clock = now.clone();
+
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+        let storage = Storage::new(logger, ":memory:")
+            .unwrap()
+            .with_clock(move || {
+                SystemTime::UNIX_EPOCH + Duration::from_secs(clock.load(Ordering::SeqCst))
+            });
+
+        let transfers = [
+            ("23e488a4-0521-11ee-be56-0242ac120002", "1.2.3.4", 1000),
+            ("23e488a4-0521-11ee-be56-0242ac120003", "5.6.7.8", 2000),
+            ("23e488a4-0521-11ee-be56-0242ac120004", "1.2.3.4", 3000),
+            ("23e488a4-0521-11ee-be56-0242ac120005", "9.9.9.9", 4000),
+        ];
+
+        for (id, peer, secs) in transfers {
+            now.store(secs, Ordering::SeqCst);
+            storage
+                .insert_transfer(&TransferInfo {
+                    id: id.parse().unwrap(),
+                    peer: peer.to_string(),
+                    files: TransferFiles::Incoming(vec![TransferIncomingPath {
+                        file_id: "id1".to_string(),
+                        relative_path: "1".to_string(),
+                        size: 1024,
+                    }]),
+                })
+                .await;
         }
 
+        let deleted: Uuid = "23e488a4-0521-11ee-be56-0242ac120005".parse().unwrap();
         storage
-            .purge_transfers(&[transfer_id_1.to_string(), transfer_id_2.to_string()])
+            .insert_transfer_cancel_state(deleted, false, CancelCause::User)
             .await;
+        storage.purge_transfers(&[deleted.to_string()]).await;
+
+        let peers = storage.peers_summary().await;
+        let peers: Vec<_> = peers
+            .iter()
+            .map(|p| {
+                (
+                    p.peer.as_str(),
+                    p.transfer_count,
+                    p.last_transfer_at.and_utc().timestamp(),
+                )
+            })
+            .collect();
+        assert_eq!(peers, [("1.2.3.4", 2, 3000), ("5.6.7.8", 1, 2000)]);
+    }
 
-        // Because the transfers haven't reached the terminal state
-        let transfers = storage.transfers_since(0).await;
-        assert_eq!(transfers.len(), 2);
+    #[tokio::test]
+    async fn import_completed_incoming() {
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+        let storage = Storage::new(logger, ":memory:").unwrap();
+
+        let dir = tempfile::tempdir().unwrap();
+        let existing = dir.path().join("a.txt");
+        std::fs::write(&existing, b"a").unwrap();
+        let existing = existing.to_str().unwrap();
+        let missing = dir.path().join("missing.txt");
+        let missing = missing.to_str().unwrap();
+
+        let transfer_id: Uuid = "23e488a4-0521-11ee-be56-0242ac120002".parse().unwrap();
+        let record = |file_id: &str, peer: &str, final_path: &str| ImportedIncoming {
+            transfer_id,
+            peer: peer.to_string(),
+            file_id: file_id.to_string(),
+            relative_path: format!("{file_id}.txt"),
+            size: 1,
+            final_path: final_path.to_string(),
+            checksum: Some(vec![1; 32]),
+        };
+
+        let imported = storage
+            .import_completed_incoming(&[
+                record("id1", "1.2.3.4", existing),
+                record("id2", "1.2.3.4", missing),
+                // Conflicts with the already imported file and transfer
+                record("id1", "1.2.3.4", existing),
+                record("id3", "5.6.7.8", existing),
+            ])
+            .await;
+        assert_eq!(imported, 1);
+
+        let transfers = storage.transfers_since(0, TransferSort::CreatedAsc).await;
+        assert_eq!(transfers.len(), 1);
+        assert_eq!(transfers[0].peer_id, "1.2.3.4");
+        let DbTransferType::Incoming(paths) = &transfers[0].transfer_type else {
+            panic!("Expected incoming transfer");
+        };
+        assert_eq!(paths.len(), 1);
+        assert_eq!(paths[0].file_id, "id1");
+        assert_eq!(paths[0].bytes_received, 1);
+        assert!(matches!(
+            &paths[0].states[..],
+            [IncomingPathStateEvent {
+                data: IncomingPathStateEventData::Completed {
+                    final_path,
+                    was_renamed: false,
+                    deleted: false,
+                },
+                ..
+            }] if final_path == existing
+        ));
+
+        let checksums = storage.fetch_checksums(transfer_id).await;
+        assert_eq!(checksums[0].checksum, Some(vec![1; 32]));
+
+        assert_eq!(
+            storage.stored_transfer_state(transfer_id).await,
+            Some(StoredTransferState::Completed)
+        );
+    }
+
+    #[tokio::test]
+    async fn transaction_commits_or_rolls_back() {
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+        let storage = Storage::new(logger, ":memory:").unwrap();
+
+        let transfer_id: Uuid = "23e488a4-0521-11ee-be56-0242ac120002".parse().unwrap();
 
         storage
-            .insert_transfer_cancel_state(transfer_id_1, false)
+            .insert_transfer(&TransferInfo {
+                id: transfer_id,
+                peer: "5.6.7.8".to_string(),
+                files: TransferFiles::Incoming(vec![
+                    TransferIncomingPath {
+                        file_id: "id1".to_string(),
+                        size: 1024,
+                        relative_path: "1".to_string(),
+                    },
+                    TransferIncomingPath {
+                        file_id: "id2".to_string(),
+                        size: 1024,
+                        relative_path: "2".to_string(),
+                    },
+                ]),
+            })
             .await;
-        storage
-            .insert_transfer_failed_state(transfer_id_2, 42)
+
+        let res = storage
+            .with_transaction(|tx| {
+                tx.save_checksum(transfer_id, "id1", &[1u8; 32])?;
+                Err::<(), _>(Error::InternalError("abort".to_string()))
+            })
             .await;
+        assert!(res.is_err());
+        // Rolled back
+        assert!(storage.file_checksum(transfer_id, "id1").await.is_none());
 
         storage
-            .purge_transfers(&[transfer_id_1.to_string(), transfer_id_2.to_string()])
-            .await;
+            .with_transaction(|tx| {
+                tx.save_checksum(transfer_id, "id1", &[1u8; 32])?;
+                tx.save_checksum(transfer_id, "id2", &[2u8; 32])
+            })
+            .await
+            .unwrap();
 
-        let transfers = storage.transfers_since(0).await;
-        assert_eq!(transfers.len(), 0);
+        assert_eq!(
+            storage.file_checksum(transfer_id, "id1").await.as_deref(),
+            Some(&[1u8; 32][..])
+        );
+        assert_eq!(
+            storage.file_checksum(transfer_id, "id2").await.as_deref(),
+            Some(&[2u8; 32][..])
+        );
     }
 
     #[tokio::test]
-    async fn remove_outgoing_file() {
+    async fn count_transfers_to_resume() {
         let logger = slog::Logger::root(slog::Discard, slog::o!());
         let storage = Storage::new(logger, ":memory:").unwrap();
 
-        let transfer_id: Uuid = "23e488a4-0521-11ee-be56-0242ac120002".parse().unwrap();
+        assert_eq!(storage.count_transfers_to_resume().await, (0, 0));
 
-        let transfer = TransferInfo {
-            id: transfer_id,
-            peer: "5.6.7.8".to_string(),
-            files: TransferFiles::Outgoing(vec![
-                TransferOutgoingPath {
-                    file_id: "id1".to_string(),
-                    size: 1024,
-                    uri: "file:///dir".parse().unwrap(),
-                    relative_path: "1".to_string(),
-                },
-                TransferOutgoingPath {
-                    file_id: "id2".to_string(),
-                    size: 1024,
-                    uri: "file:///dir".parse().unwrap(),
-                    relative_path: "2".to_string(),
-                },
-                TransferOutgoingPath {
-                    file_id: "id3".to_string(),
-                    size: 1024,
-                    uri: "file:///dir".parse().unwrap(),
-                    relative_path: "3".to_string(),
-                },
-                TransferOutgoingPath {
-                    file_id: "id4".to_string(),
-                    relative_path: "4".to_string(),
-                    uri: "file:///dir".parse().unwrap(),
-                    size: 2048,
-                },
-            ]),
+        let incoming = |id: &str| TransferInfo {
+            id: id.parse().unwrap(),
+            peer: "1.2.3.4".to_string(),
+            files: TransferFiles::Incoming(vec![TransferIncomingPath {
+                file_id: "id1".to_string(),
+                relative_path: "1".to_string(),
+                size: 1024,
+            }]),
         };
 
-        storage.insert_transfer(&transfer).await;
+        let incoming_id: Uuid = "23e488a4-0521-11ee-be56-0242ac120002".parse().unwrap();
         storage
-            .insert_outgoing_path_failed_state(transfer_id, "id1", 1, 123)
+            .insert_transfer(&incoming(&incoming_id.to_string()))
             .await;
         storage
-            .insert_outgoing_path_completed_state(transfer_id, "id2")
+            .insert_transfer(&incoming("23e48d7c-0521-11ee-be56-0242ac120002"))
             .await;
         storage
-            .insert_outgoing_path_reject_state(transfer_id, "id3", false, 246)
+            .insert_transfer(&TransferInfo {
+                id: "23e48e9e-0521-11ee-be56-0242ac120002".parse().unwrap(),
+                peer: "5.6.7.8".to_string(),
+                files: TransferFiles::Outgoing(vec![TransferOutgoingPath {
+                    file_id: "id2".to_string(),
+                    relative_path: "2".to_string(),
+                    uri: "file:///dir/2".parse().unwrap(),
+                    size: 1024,
+                }]),
+            })
             .await;
 
-        let transfers = storage.transfers_since(0).await;
-        assert_eq!(transfers.len(), 1);
-
-        let paths = match &transfers[0].transfer_type {
-            DbTransferType::Outgoing(out) => out,
-            _ => panic!("Unexpected transfer type"),
-        };
-        assert_eq!(paths.len(), 4);
-
-        assert!(storage
-            .remove_transfer_file(transfer_id, "id1")
-            .await
-            .is_some());
-        assert!(storage
-            .remove_transfer_file(transfer_id, "id2")
-            .await
-            .is_some());
-        assert!(storage
-            .remove_transfer_file(transfer_id, "id3")
-            .await
-            .is_some());
-        assert!(storage
-            .remove_transfer_file(transfer_id, "id4")
-            .await
-            .is_none());
-
-        let transfers = storage.transfers_since(0).await;
-        assert_eq!(transfers.len(), 1);
+        assert_eq!(storage.count_transfers_to_resume().await, (2, 1));
 
-        let paths = match &transfers[0].transfer_type {
-            DbTransferType::Outgoing(out) => out,
-            _ => panic!("Unexpected transfer type"),
-        };
-        assert_eq!(paths.len(), 1); // 1 since we removed one of them
-        assert_eq!(paths[0].file_id, "id4");
+        storage.transfer_sync_clear(incoming_id).await;
+        assert_eq!(storage.count_transfers_to_resume().await, (1, 1));
     }
 
     #[tokio::test]
-    async fn remove_incoming_file() {
+    async fn resumed_transfers_creation_time() {
+        use std::sync::atomic::{AtomicU64, Ordering};
+
+        let now = Arc::new(AtomicU64::new(1000));
+        let clock = now.clone();
+
         let logger = slog::Logger::root(slog::Discard, slog::o!());
-        let storage = Storage::new(logger, ":memory:").unwrap();
+        let storage = Storage::new(logger, ":memory:")
+            .unwrap()
+            .with_clock(move || {
+                SystemTime::UNIX_EPOCH + Duration::from_secs(clock.load(Ordering::SeqCst))
+            });
 
-        let transfer_id: Uuid = "23e488a4-0521-11ee-be56-0242ac120002".parse().unwrap();
+        let incoming: Uuid = "23e488a4-0521-11ee-be56-0242ac120002".parse().unwrap();
+        let outgoing: Uuid = "23e488a4-0521-11ee-be56-0242ac120003".parse().unwrap();
 
-        let transfer = TransferInfo {
-            id: transfer_id,
-            peer: "5.6.7.8".to_string(),
-            files: TransferFiles::Incoming(vec![
-                TransferIncomingPath {
+        storage
+            .insert_transfer(&TransferInfo {
+                id: incoming,
+                peer: "1.2.3.4".to_string(),
+                files: TransferFiles::Incoming(vec![TransferIncomingPath {
                     file_id: "id1".to_string(),
-                    size: 1024,
                     relative_path: "1".to_string(),
-                },
-                TransferIncomingPath {
-                    file_id: "id2".to_string(),
-                    size: 1024,
-                    relative_path: "2".to_string(),
-                },
-                TransferIncomingPath {
-                    file_id: "id3".to_string(),
                     size: 1024,
-                    relative_path: "3".to_string(),
-                },
-                TransferIncomingPath {
-                    file_id: "id4".to_string(),
-                    relative_path: "4".to_string(),
-                    size: 2048,
-                },
-            ]),
-        };
-
-        storage.insert_transfer(&transfer).await;
-        storage
-            .insert_incoming_path_failed_state(transfer_id, "id1", 1, 123)
-            .await;
-        storage
-            .insert_incoming_path_completed_state(transfer_id, "id2", "/recv/id2")
+                }]),
+            })
             .await;
+
+        now.store(2000, Ordering::SeqCst);
         storage
-            .insert_incoming_path_reject_state(transfer_id, "id3", false, 246)
+            .insert_transfer(&TransferInfo {
+                id: outgoing,
+                peer: "5.6.7.8".to_string(),
+                files: TransferFiles::Outgoing(vec![TransferOutgoingPath {
+                    file_id: "id2".to_string(),
+                    relative_path: "2".to_string(),
+                    uri: "file:///dir/2".parse().unwrap(),
+                    size: 1024,
+                }]),
+            })
             .await;
 
-        let transfers = storage.transfers_since(0).await;
+        let transfers = storage.incoming_transfers_to_resume().await;
         assert_eq!(transfers.len(), 1);
+        assert_eq!(transfers[0].uuid, incoming);
+        assert_eq!(transfers[0].created_at.and_utc().timestamp(), 1000);
 
-        let paths = match &transfers[0].transfer_type {
-            DbTransferType::Incoming(inc) => inc,
-            _ => panic!("Unexpected transfer type"),
-        };
-        assert_eq!(paths.len(), 4);
-
-        assert!(storage
-            .remove_transfer_file(transfer_id, "id1")
-            .await
-            .is_some());
-        assert!(storage
-            .remove_transfer_file(transfer_id, "id2")
-            .await
-            .is_some());
-        assert!(storage
-            .remove_transfer_file(transfer_id, "id3")
-            .await
-            .is_some());
-        assert!(storage
-            .remove_transfer_file(transfer_id, "id4")
-            .await
-            .is_none());
-
-        let transfers = storage.transfers_since(0).await;
+        let transfers = storage.outgoing_transfers_to_resume().await;
         assert_eq!(transfers.len(), 1);
-
-        let paths = match &transfers[0].transfer_type {
-            DbTransferType::Incoming(inc) => inc,
-            _ => panic!("Unexpected transfer type"),
-        };
-
-        assert_eq!(paths.len(), 1); // 1 since we removed one of them
-        assert_eq!(paths[0].file_id, "id4");
+        assert_eq!(transfers[0].uuid, outgoing);
+        assert_eq!(transfers[0].created_at.and_utc().timestamp(), 2000);
     }
 
     #[tokio::test]
-    async fn check_storage_api() {
+    async fn reset_failed_files() {
         let logger = slog::Logger::root(slog::Discard, slog::o!());
         let storage = Storage::new(logger, ":memory:").unwrap();
 
-        let transfer1_id: Uuid = "23e488a4-0521-11ee-be56-0242ac120002".parse().unwrap();
+        let outgoing: Uuid = "23e488a4-0521-11ee-be56-0242ac120002".parse().unwrap();
+        let incoming: Uuid = "23e488a4-0521-11ee-be56-0242ac120003".parse().unwrap();
 
-        let transfer = TransferInfo {
-            id: transfer1_id,
-            peer: "5.6.7.8".to_string(),
-            files: TransferFiles::Incoming(vec![
-                TransferIncomingPath {
-                    file_id: "idi1".to_string(),
-                    size: 1024,
-                    relative_path: "1".to_string(),
-                },
-                TransferIncomingPath {
-                    file_id: "idi2".to_string(),
-                    size: 1024,
-                    relative_path: "2".to_string(),
-                },
-                TransferIncomingPath {
-                    file_id: "idi3".to_string(),
-                    size: 1024,
-                    relative_path: "3".to_string(),
-                },
-                TransferIncomingPath {
-                    file_id: "idi4".to_string(),
-                    relative_path: "4".to_string(),
-                    size: 2048,
-                },
-            ]),
+        let path = |file_id: &str| TransferOutgoingPath {
+            file_id: file_id.to_string(),
+            relative_path: file_id.to_string(),
+            uri: format!("file:///dir/{file_id}").parse().unwrap(),
+            size: 1024,
         };
 
-        storage.insert_transfer(&transfer).await;
         storage
-            .insert_incoming_path_failed_state(transfer1_id, "idi1", 1, 123)
+            .insert_transfer(&TransferInfo {
+                id: outgoing,
+                peer: "1.2.3.4".to_string(),
+                files: TransferFiles::Outgoing(vec![path("id1"), path("id2"), path("id3")]),
+            })
             .await;
         storage
-            .start_incoming_file(transfer1_id, "idi2", "/recv/idi2")
+            .insert_transfer(&TransferInfo {
+                id: incoming,
+                peer: "5.6.7.8".to_string(),
+                files: TransferFiles::Incoming(vec![TransferIncomingPath {
+                    file_id: "id4".to_string(),
+                    relative_path: "4".to_string(),
+                    size: 1024,
+                }]),
+            })
             .await;
+
         storage
-            .insert_incoming_path_completed_state(transfer1_id, "idi2", "/recv/idi2")
+            .insert_outgoing_path_failed_state(outgoing, "id1", 1, 100)
             .await;
         storage
-            .insert_incoming_path_reject_state(transfer1_id, "idi3", false, 234)
+            .insert_outgoing_path_completed_state(outgoing, "id2")
+            .await;
+        for file_id in ["id1", "id2"] {
+            storage
+                .update_outgoing_file_sync_states(outgoing, file_id, sync::FileState::Terminal)
+                .await;
+        }
+
+        storage
+            .insert_incoming_path_failed_state(incoming, "id4", 1, 100)
             .await;
         storage
-            .insert_incoming_path_started_state(transfer1_id, "idi4", 12345)
+            .update_incoming_file_sync_states(incoming, "id4", sync::FileState::Terminal)
             .await;
 
-        let transfer2_id: Uuid = "f333302e-584b-42f8-9f66-6a5ef400297d".parse().unwrap();
+        // The transfer canceled by the user is not retried
+        storage
+            .update_transfer_sync_states(outgoing, sync::TransferState::Canceled)
+            .await;
+        storage
+            .insert_transfer_cancel_state(outgoing, false, CancelCause::User)
+            .await;
+        assert_eq!(storage.reset_failed_files(outgoing).await, 0);
 
-        let transfer = TransferInfo {
-            id: transfer2_id,
-            peer: "1.2.3.4".to_string(),
-            files: TransferFiles::Outgoing(vec![
-                TransferOutgoingPath {
-                    file_id: "ido1".to_string(),
-                    relative_path: "1".to_string(),
-                    uri: "file:///dir/1".parse().unwrap(),
-                    size: 1024,
-                },
-                TransferOutgoingPath {
-                    file_id: "ido2".to_string(),
-                    relative_path: "2".to_string(),
-                    uri: "file:///dir/2".parse().unwrap(),
-                    size: 1024,
-                },
-                TransferOutgoingPath {
-                    file_id: "ido3".to_string(),
-                    relative_path: "3".to_string(),
-                    uri: "file:///dir/3".parse().unwrap(),
-                    size: 1024,
-                },
-                TransferOutgoingPath {
-                    file_id: "ido4".to_string(),
-                    relative_path: "4".to_string(),
-                    uri: "file:///dir/4".parse().unwrap(),
-                    size: 2048,
-                },
-            ]),
-        };
+        storage
+            .insert_transfer_cancel_state(outgoing, false, CancelCause::Error)
+            .await;
+        assert_eq!(storage.reset_failed_files(outgoing).await, 1);
+        assert!(matches!(
+            storage
+                .transfer_sync_state(outgoing)
+                .await
+                .unwrap()
+                .local_state,
+            sync::TransferState::Active
+        ));
+        assert!(matches!(
+            storage
+                .outgoing_file_sync_state(outgoing, "id1")
+                .await
+                .unwrap()
+                .sync,
+            sync::FileState::Alive
+        ));
+        assert!(matches!(
+            storage
+                .outgoing_file_sync_state(outgoing, "id2")
+                .await
+                .unwrap()
+                .sync,
+            sync::FileState::Terminal
+        ));
+        assert_eq!(storage.reset_failed_files(outgoing).await, 0);
+
+        assert_eq!(storage.reset_failed_files(incoming).await, 1);
+        assert!(matches!(
+            storage
+                .incoming_file_sync_state(incoming, "id4")
+                .await
+                .unwrap()
+                .sync,
+            sync::FileState::Alive
+        ));
+
+        let unknown: Uuid = "23e488a4-0521-11ee-be56-0242ac120004".parse().unwrap();
+        assert_eq!(storage.reset_failed_files(unknown).await, 0);
+    }
+
+    #[tokio::test]
+    async fn migrate_storage() {
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+
+        let dir = tempfile::tempdir().unwrap();
+        let from = dir.path().join("old.sqlite");
+        let from = from.to_str().unwrap();
+        let to = dir.path().join("moved").join("new.sqlite");
+        let to = to.to_str().unwrap();
+
+        let transfer_id: Uuid = "23e488a4-0521-11ee-be56-0242ac120002".parse().unwrap();
+        {
+            let storage = Storage::new(logger.clone(), from).unwrap();
+            storage
+                .insert_transfer(&TransferInfo {
+                    id: transfer_id,
+                    peer: "1.2.3.4".to_string(),
+                    files: TransferFiles::Incoming(vec![]),
+                })
+                .await;
+        }
+        // Leftover of a database in the WAL mode
+        std::fs::write(format!("{from}-wal"), "wal").unwrap();
+
+        super::migrate_storage(&logger, from, to).unwrap();
+        assert!(!Path::new(from).exists());
+        assert_eq!(std::fs::read_to_string(format!("{to}-wal")).unwrap(), "wal");
+        std::fs::remove_file(format!("{to}-wal")).unwrap();
+
+        let storage = Storage::new(logger.clone(), to).unwrap();
+        assert_eq!(
+            storage.stored_transfer_state(transfer_id).await,
+            Some(StoredTransferState::Completed)
+        );
+        drop(storage);
+
+        // The existing database is never overwritten
+        std::fs::write(from, "").unwrap();
+        assert!(matches!(
+            super::migrate_storage(&logger, from, to),
+            Err(Error::DestinationExists)
+        ));
+        assert!(Path::new(from).exists());
+    }
 
-        storage.insert_transfer(&transfer).await;
-        storage
-            .insert_outgoing_path_failed_state(transfer2_id, "ido1", 1, 123)
-            .await;
+    #[tokio::test]
+    async fn renamed_incoming_file_resume() {
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+        let storage = Storage::new(logger, ":memory:").unwrap();
+
+        let transfer_id: Uuid = "23e488a4-0521-11ee-be56-0242ac120002".parse().unwrap();
         storage
-            .insert_outgoing_path_completed_state(transfer2_id, "ido2")
+            .insert_transfer(&TransferInfo {
+                id: transfer_id,
+                peer: "5.6.7.8".to_string(),
+                files: TransferFiles::Incoming(vec![
+                    TransferIncomingPath {
+                        file_id: "id1".to_string(),
+                        size: 1024,
+                        relative_path: "a.txt".to_string(),
+                    },
+                    TransferIncomingPath {
+                        file_id: "id2".to_string(),
+                        size: 1024,
+                        relative_path: "b.txt".to_string(),
+                    },
+                ]),
+            })
             .await;
+
         storage
-            .insert_outgoing_path_reject_state(transfer2_id, "ido3", false, 234)
+            .start_incoming_files(
+                transfer_id,
+                &[("id1", Some("renamed.txt")), ("id2", None)],
+                "/recv",
+            )
             .await;
+        // The name survives moving the file into another directory
         storage
-            .insert_outgoing_path_started_state(transfer2_id, "ido4", 12345)
+            .redirect_incoming_file(transfer_id, "id1", "/other")
             .await;
 
-        let transfers = storage.transfers_since(0).await;
-        assert_eq!(transfers.len(), 2);
-
-        assert_eq!(transfers[0].id, transfer1_id);
-        assert_eq!(transfers[0].peer_id, "5.6.7.8");
-        assert_eq!(transfers[0].states.len(), 0);
+        let mut files = storage.incoming_files_to_resume(transfer_id).await;
+        files.sort_by(|a, b| a.file_id.cmp(&b.file_id));
 
-        match &transfers[0].transfer_type {
-            DbTransferType::Incoming(inc) => {
-                assert_eq!(inc[0].transfer_id, transfer1_id);
-                assert_eq!(inc[0].relative_path, "1");
-                assert_eq!(inc[0].bytes, 1024);
-                assert_eq!(inc[0].bytes_received, 123);
-                assert_eq!(inc[0].file_id, "idi1");
-                assert_eq!(inc[0].states.len(), 1);
+        assert_eq!(files.len(), 2);
+        assert_eq!(files[0].file_id, "id1");
+        assert_eq!(files[0].base_dir, "/other");
+        assert_eq!(files[0].rename_to.as_deref(), Some("renamed.txt"));
+        assert_eq!(files[1].file_id, "id2");
+        assert_eq!(files[1].rename_to, None);
+    }
 
-                assert!(matches!(
-                    inc[0].states[0].data,
-                    IncomingPathStateEventData::Failed {
-                        status_code: 1,
-                        bytes_received: 123
-                    }
-                ));
+    #[tokio::test]
+    async fn transfer_events_since() {
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+        let storage = Storage::new(logger, ":memory:").unwrap();
 
-                assert_eq!(inc[1].transfer_id, transfer1_id);
-                assert_eq!(inc[1].relative_path, "2");
-                assert_eq!(inc[1].bytes, 1024);
-                assert_eq!(inc[1].bytes_received, 1024);
-                assert_eq!(inc[1].file_id, "idi2");
-                assert_eq!(inc[1].states.len(), 2);
+        let transfer_id: Uuid = "23e488a4-0521-11ee-be56-0242ac120002".parse().unwrap();
+        storage
+            .insert_transfer(&TransferInfo {
+                id: transfer_id,
+                peer: "5.6.7.8".to_string(),
+                files: TransferFiles::Incoming(vec![TransferIncomingPath {
+                    file_id: "id1".to_string(),
+                    size: 1024,
+                    relative_path: "a.txt".to_string(),
+                }]),
+            })
+            .await;
 
-                assert!(matches!(
-                    &inc[1].states[0].data,
-                    IncomingPathStateEventData::Pending{
-                        base_dir,
-                    } if base_dir == "/recv/idi2",
-                ));
-                assert!(matches!(
-                    &inc[1].states[1].data,
-                    IncomingPathStateEventData::Completed {
-                        final_path
-                    } if final_path == "/recv/idi2"
-                ));
+        storage
+            .start_incoming_file(transfer_id, "id1", "/recv")
+            .await;
+        storage
+            .insert_incoming_path_started_state(transfer_id, "id1", 0)
+            .await;
 
-                assert_eq!(inc[2].transfer_id, transfer1_id);
-                assert_eq!(inc[2].relative_path, "3");
-                assert_eq!(inc[2].bytes, 1024);
-                assert_eq!(inc[2].bytes_received, 234);
-                assert_eq!(inc[2].file_id, "idi3");
-                assert_eq!(inc[2].states.len(), 1);
+        let events = storage.transfer_events_since(transfer_id, 0).await;
+        assert_eq!(events.len(), 2);
+        assert!(matches!(
+            events[0],
+            StateEvent::IncomingPath(IncomingPathStateEvent {
+                data: IncomingPathStateEventData::Pending { .. },
+                ..
+            })
+        ));
+        assert!(events
+            .windows(2)
+            .all(|w| w[0].created_at() <= w[1].created_at()));
+
+        let cursor = events[1].created_at().and_utc().timestamp_millis();
+        assert!(storage
+            .transfer_events_since(transfer_id, cursor)
+            .await
+            .is_empty());
 
-                assert!(matches!(
-                    inc[2].states[0].data,
-                    IncomingPathStateEventData::Rejected {
-                        by_peer: false,
-                        bytes_received: 234
-                    }
-                ));
+        // Make sure the new states land after the cursor
+        std::thread::sleep(Duration::from_millis(5));
 
-                assert_eq!(inc[3].transfer_id, transfer1_id);
-                assert_eq!(inc[3].relative_path, "4");
-                assert_eq!(inc[3].bytes, 2048);
-                assert_eq!(inc[3].bytes_received, 12345);
-                assert_eq!(inc[3].file_id, "idi4");
-                assert_eq!(inc[3].states.len(), 1);
+        storage
+            .insert_incoming_path_completed_state(transfer_id, "id1", "/recv/a.txt", false, false)
+            .await;
+        storage
+            .insert_transfer_cancel_state(transfer_id, false, CancelCause::User)
+            .await;
 
-                assert!(matches!(
-                    &inc[3].states[0].data,
-                    IncomingPathStateEventData::Started {
-                        bytes_received: 12345
-                    }
-                ));
-            }
-            _ => panic!("Unexpected transfer type"),
-        };
+        let events = storage.transfer_events_since(transfer_id, cursor).await;
+        assert_eq!(events.len(), 2);
+        assert!(events.iter().any(|ev| matches!(
+            ev,
+            StateEvent::IncomingPath(IncomingPathStateEvent {
+                data: IncomingPathStateEventData::Completed { .. },
+                ..
+            })
+        )));
+        assert!(events.iter().any(|ev| matches!(
+            ev,
+            StateEvent::Transfer(TransferStateEvent {
+                data: types::TransferStateEventData::Cancel { by_peer: false, .. },
+                ..
+            })
+        )));
+        assert!(events[0].created_at() <= events[1].created_at());
+
+        let other: Uuid = "23e48d7c-0521-11ee-be56-0242ac120002".parse().unwrap();
+        assert!(storage.transfer_events_since(other, 0).await.is_empty());
+    }
 
-        assert_eq!(transfers[1].id, transfer2_id);
-        assert_eq!(transfers[1].peer_id, "1.2.3.4");
-        assert_eq!(transfers[1].states.len(), 0);
+    #[tokio::test]
+    async fn transfer_tags() {
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+        let storage = Storage::new(logger, ":memory:").unwrap();
 
-        match &transfers[1].transfer_type {
-            DbTransferType::Outgoing(inc) => {
-                assert_eq!(inc[0].transfer_id, transfer2_id);
-                assert_eq!(inc[0].relative_path, "1");
-                assert_eq!(inc[0].bytes, 1024);
-                assert_eq!(inc[0].bytes_sent, 123);
-                assert_eq!(inc[0].file_id, "ido1");
-                assert_eq!(inc[0].base_path.as_deref(), Some(Path::new("/dir")));
-                assert!(inc[0].content_uri.is_none());
-                assert_eq!(inc[0].states.len(), 1);
+        let first: Uuid = "23e488a4-0521-11ee-be56-0242ac120002".parse().unwrap();
+        let second: Uuid = "23e488a4-0521-11ee-be56-0242ac120003".parse().unwrap();
+        let unknown: Uuid = "23e488a4-0521-11ee-be56-0242ac120004".parse().unwrap();
 
-                assert!(matches!(
-                    inc[0].states[0].data,
-                    OutgoingPathStateEventData::Failed {
-                        status_code: 1,
-                        bytes_sent: 123
-                    }
-                ));
+        for id in [first, second] {
+            storage
+                .insert_transfer(&TransferInfo {
+                    id,
+                    peer: "1.2.3.4".to_string(),
+                    files: TransferFiles::Incoming(vec![TransferIncomingPath {
+                        file_id: "id1".to_string(),
+                        relative_path: "1".to_string(),
+                        size: 1024,
+                    }]),
+                })
+                .await;
+        }
 
-                assert_eq!(inc[1].transfer_id, transfer2_id);
-                assert_eq!(inc[1].relative_path, "2");
-                assert_eq!(inc[1].bytes, 1024);
-                assert_eq!(inc[1].bytes_sent, 1024);
-                assert_eq!(inc[1].file_id, "ido2");
-                assert_eq!(inc[1].base_path.as_deref(), Some(Path::new("/dir")));
-                assert!(inc[1].content_uri.is_none());
-                assert_eq!(inc[1].states.len(), 1);
+        assert!(storage.add_transfer_tag(first, "work").await.is_some());
+        assert!(storage.add_transfer_tag(first, "photos").await.is_some());
+        assert!(storage.add_transfer_tag(first, "work").await.is_some());
+        assert!(storage.add_transfer_tag(second, "work").await.is_some());
+        assert!(storage.add_transfer_tag(unknown, "work").await.is_none());
 
-                assert!(matches!(
-                    inc[1].states[0].data,
-                    OutgoingPathStateEventData::Completed
-                ));
+        assert_eq!(storage.transfer_tags(first).await, ["work", "photos"]);
+        assert_eq!(storage.transfer_tags(second).await, ["work"]);
+        assert!(storage.transfer_tags(unknown).await.is_empty());
 
-                assert_eq!(inc[2].transfer_id, transfer2_id);
-                assert_eq!(inc[2].relative_path, "3");
-                assert_eq!(inc[2].bytes, 1024);
-                assert_eq!(inc[2].bytes_sent, 234);
-                assert_eq!(inc[2].file_id, "ido3");
-                assert_eq!(inc[2].base_path.as_deref(), Some(Path::new("/dir")));
-                assert!(inc[2].content_uri.is_none());
-                assert_eq!(inc[2].states.len(), 1);
+        assert_eq!(storage.transfers_with_tag("work").await, [first, second]);
+        assert_eq!(storage.transfers_with_tag("photos").await, [first]);
+        assert!(storage.transfers_with_tag("music").await.is_empty());
 
-                assert!(matches!(
-                    inc[2].states[0].data,
-                    OutgoingPathStateEventData::Rejected {
-                        by_peer: false,
-                        bytes_sent: 234
-                    }
-                ));
+        let transfers = storage.transfers_since(0, TransferSort::default()).await;
+        assert_eq!(transfers.len(), 2);
+        for transfer in transfers {
+            let expected = if transfer.id == first {
+                &["work", "photos"][..]
+            } else {
+                &["work"][..]
+            };
+            assert_eq!(transfer.tags, expected);
+        }
 
-                assert_eq!(inc[3].transfer_id, transfer2_id);
-                assert_eq!(inc[3].relative_path, "4");
-                assert_eq!(inc[3].bytes, 2048);
-                assert_eq!(inc[3].bytes_sent, 12345);
-                assert_eq!(inc[3].file_id, "ido4");
-                assert_eq!(inc[3].base_path.as_deref(), Some(Path::new("/dir")));
-                assert!(inc[3].content_uri.is_none());
-                assert_eq!(inc[3].states.len(), 1);
+        assert!(storage.remove_transfer_tag(second, "work").await.is_some());
+        assert!(storage.remove_transfer_tag(second, "work").await.is_none());
+        assert_eq!(storage.transfers_with_tag("work").await, [first]);
 
-                assert!(matches!(
-                    inc[3].states[0].data,
-                    OutgoingPathStateEventData::Started { bytes_sent: 12345 }
-                ));
-            }
-            _ => panic!("Unexpected transfer type"),
-        };
+        // The purged transfers are no longer found by their tags
+        storage
+            .insert_transfer_cancel_state(first, false, CancelCause::User)
+            .await;
+        storage.purge_transfers(&[first.to_string()]).await;
+        assert!(storage.transfers_with_tag("work").await.is_empty());
+        assert!(storage.add_transfer_tag(first, "music").await.is_none());
+
+        // And the tags go away once the transfer is removed for good
+        storage.transfer_sync_clear(first).await;
+        assert_eq!(storage.cleanup_garbage_transfers().await, 1);
+        assert!(storage.transfer_tags(first).await.is_empty());
     }
 
     #[tokio::test]
-    async fn removing_garbage_transfers() {
+    async fn history_archive_round_trip() {
         let logger = slog::Logger::root(slog::Discard, slog::o!());
-        let storage = Storage::new(logger, ":memory:").unwrap();
-
-        let transfer_id_1: Uuid = "23e488a4-0521-11ee-be56-0242ac120002".parse().unwrap();
-        let transfer_id_2: Uuid = "23e48d7c-0521-11ee-be56-0242ac120002".parse().unwrap();
-
-        let transfer = TransferInfo {
-            id: transfer_id_1,
-            peer: "1.2.3.4".to_string(),
-            files: TransferFiles::Incoming(vec![]),
-        };
-        storage.insert_transfer(&transfer).await;
+        let storage = Storage::new(logger.clone(), ":memory:").unwrap();
 
-        let transfer = TransferInfo {
-            id: transfer_id_2,
-            peer: "5.6.7.8".to_string(),
-            files: TransferFiles::Outgoing(vec![]),
-        };
-        storage.insert_transfer(&transfer).await;
+        let incoming: Uuid = "23e488a4-0521-11ee-be56-0242ac120002".parse().unwrap();
+        let outgoing: Uuid = "23e488a4-0521-11ee-be56-0242ac120003".parse().unwrap();
 
-        // Transfers need to be termiated before any purging is allowed
         storage
-            .insert_transfer_cancel_state(transfer_id_1, false)
+            .insert_transfer(&TransferInfo {
+                id: incoming,
+                peer: "1.2.3.4".to_string(),
+                files: TransferFiles::Incoming(vec![
+                    TransferIncomingPath {
+                        file_id: "id1".to_string(),
+                        relative_path: "a.txt".to_string(),
+                        size: 1024,
+                    },
+                    TransferIncomingPath {
+                        file_id: "id2".to_string(),
+                        relative_path: "b.txt".to_string(),
+                        size: 2048,
+                    },
+                ]),
+            })
             .await;
         storage
-            .insert_transfer_cancel_state(transfer_id_2, false)
+            .insert_transfer(&TransferInfo {
+                id: outgoing,
+                peer: "5.6.7.8".to_string(),
+                files: TransferFiles::Outgoing(vec![TransferOutgoingPath {
+                    file_id: "id3".to_string(),
+                    relative_path: "c.txt".to_string(),
+                    uri: "file:///dir/c.txt".parse().unwrap(),
+                    size: 512,
+                }]),
+            })
             .await;
 
-        // No garbage to collect
-        let count = storage.cleanup_garbage_transfers().await;
-        assert_eq!(count, 0);
-
-        storage.purge_transfers(&[transfer_id_1.to_string()]).await;
+        storage.save_checksum(incoming, "id1", &[1, 2, 3]).await;
+        storage
+            .insert_incoming_path_completed_state(incoming, "id1", "/home/a.txt", false, false)
+            .await;
+        storage
+            .insert_incoming_path_reject_state(incoming, "id2", false, 0)
+            .await;
+        storage
+            .insert_outgoing_path_started_state(outgoing, "id3", 0)
+            .await;
+        storage
+            .insert_transfer_cancel_state(outgoing, true, CancelCause::User)
+            .await;
+        storage.add_transfer_tag(incoming, "backup").await.unwrap();
 
-        // Still the transfer was not synced
-        let count = storage.cleanup_garbage_transfers().await;
-        assert_eq!(count, 0);
+        let archive = storage.export_archive(Vec::new()).await.unwrap();
 
-        let cleared = storage.transfer_sync_clear(transfer_id_1).await;
-        assert!(cleared.is_some());
+        let restored = Storage::new(logger, ":memory:").unwrap();
+        let mut manifest = restored
+            .import_archive(io::Cursor::new(archive.clone()))
+            .await
+            .unwrap();
+        manifest.sort_by(|a, b| a.file_id.cmp(&b.file_id));
+
+        assert_eq!(
+            manifest,
+            [
+                ArchiveManifestEntry {
+                    transfer_id: incoming,
+                    file_id: "id1".to_string(),
+                    direction: TransferType::Incoming,
+                    location: "/home/a.txt".to_string(),
+                    size: 1024,
+                    checksum: Some(vec![1, 2, 3]),
+                },
+                ArchiveManifestEntry {
+                    transfer_id: outgoing,
+                    file_id: "id3".to_string(),
+                    direction: TransferType::Outgoing,
+                    location: "file:///dir/c.txt".to_string(),
+                    size: 512,
+                    checksum: None,
+                },
+            ]
+        );
 
-        // Now the transfer can be garbage collected
-        let count = storage.cleanup_garbage_transfers().await;
-        assert_eq!(count, 1);
+        let transfers = restored.transfers_since(0, TransferSort::default()).await;
+        assert_eq!(transfers.len(), 2);
 
-        let count = storage.cleanup_garbage_transfers().await;
-        assert_eq!(count, 0);
+        let history = |transfers: Vec<Transfer>| serde_json::to_value(transfers).unwrap();
+        assert_eq!(
+            history(transfers),
+            history(storage.transfers_since(0, TransferSort::default()).await)
+        );
 
-        // Ensure we haven't deleted the second transfer
-        let transfers = storage.transfers_since(0).await;
-        assert_eq!(transfers.len(), 1);
-        assert_eq!(transfers[0].id, transfer_id_2);
+        // The history is restored into an empty database only
+        assert!(matches!(
+            restored.import_archive(io::Cursor::new(archive)).await,
+            Err(Error::HistoryNotEmpty)
+        ));
+
+        let fresh =
+            Storage::new(slog::Logger::root(slog::Discard, slog::o!()), ":memory:").unwrap();
+        assert!(matches!(
+            fresh.import_archive(&b"not an archive"[..]).await,
+            Err(Error::Io(_))
+        ));
     }
 }
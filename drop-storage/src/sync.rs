@@ -1,3 +1,4 @@
+use chrono::NaiveDateTime;
 use rusqlite::{params, types::FromSql, Connection, OptionalExtension, ToSql};
 use uuid::Uuid;
 
@@ -60,6 +61,7 @@ pub struct Transfer {
 pub struct FileInFlight {
     pub base_dir: String,
     pub file_id: String,
+    pub rename_to: Option<String>,
 }
 
 pub(super) fn insert_transfer(
@@ -215,7 +217,7 @@ pub(super) fn incoming_files_in_flight(
     let res = conn
         .prepare(
             r#"
-        SELECT sifi.base_dir, ip.path_hash
+        SELECT sifi.base_dir, ip.path_hash, sifi.rename_to
         FROM sync_incoming_files sif
         INNER JOIN sync_incoming_files_inflight sifi USING(sync_id, path_id)
         INNER JOIN sync_transfer st USING(sync_id)
@@ -228,6 +230,7 @@ pub(super) fn incoming_files_in_flight(
             Ok(FileInFlight {
                 base_dir: r.get(0)?,
                 file_id: r.get(1)?,
+                rename_to: r.get(2)?,
             })
         })?
         .collect::<QueryResult<_>>()?;
@@ -265,24 +268,50 @@ pub(super) fn start_incoming_file(
     transfer_id: Uuid,
     file_id: &str,
     base_dir: &str,
+    rename_to: Option<&str>,
 ) -> super::Result<Option<()>> {
     let tid = transfer_id.to_string();
 
     let count = conn.execute(
         r#"
-        INSERT INTO sync_incoming_files_inflight (sync_id, path_id, base_dir)
-        SELECT sif.sync_id, sif.path_id, ?3
+        INSERT INTO sync_incoming_files_inflight (sync_id, path_id, base_dir, rename_to)
+        SELECT sif.sync_id, sif.path_id, ?3, ?4
         FROM sync_incoming_files sif
         INNER JOIN sync_transfer st USING(sync_id)
         INNER JOIN incoming_paths ip ON ip.id = sif.path_id
         WHERE st.transfer_id = ?1 AND ip.path_hash = ?2
         "#,
-        params![tid, file_id, base_dir],
+        params![tid, file_id, base_dir, rename_to],
     )?;
 
     Ok(if count > 0 { Some(()) } else { None })
 }
 
+pub(super) fn incoming_file_rename(
+    conn: &Connection,
+    transfer_id: Uuid,
+    file_id: &str,
+) -> super::Result<Option<String>> {
+    let tid = transfer_id.to_string();
+
+    let res = conn
+        .query_row(
+            r#"
+            SELECT sifi.rename_to
+            FROM sync_incoming_files_inflight sifi
+            INNER JOIN sync_transfer st USING(sync_id)
+            INNER JOIN incoming_paths ip ON ip.id = sifi.path_id
+            WHERE st.transfer_id = ?1 AND ip.path_hash = ?2
+            "#,
+            params![tid, file_id],
+            |r| r.get(0),
+        )
+        .optional()?
+        .flatten();
+
+    Ok(res)
+}
+
 pub(super) fn incoming_file_local_state(
     conn: &Connection,
     transfer_id: Uuid,
@@ -334,9 +363,48 @@ pub(super) fn incoming_file_set_local_state(
     Ok(if count > 0 { Some(()) } else { None })
 }
 
+/// Flips the failed files of the transfer back to `Alive`. The files which
+/// were completed or rejected after failing are left terminated. Returns the
+/// number of files reset
+pub(super) fn reset_failed_files(conn: &Connection, transfer_id: Uuid) -> super::Result<usize> {
+    let tid = transfer_id.to_string();
+
+    let mut count = 0;
+    for prefix in ["outgoing", "incoming"] {
+        let table = if prefix == "outgoing" {
+            "sync_outgoing_files"
+        } else {
+            "sync_incoming_files"
+        };
+
+        count += conn.execute(
+            &format!(
+                r#"
+            UPDATE {table}
+            SET local_state = ?2
+            WHERE ROWID IN (
+                SELECT sf.ROWID
+                FROM {table} sf
+                INNER JOIN sync_transfer st USING(sync_id)
+                INNER JOIN {prefix}_paths p ON p.transfer_id = st.transfer_id AND sf.path_id = p.id
+                WHERE st.transfer_id = ?1 AND sf.local_state = ?3
+                    AND EXISTS (SELECT 1 FROM {prefix}_path_failed_states WHERE path_id = p.id)
+                    AND NOT EXISTS (SELECT 1 FROM {prefix}_path_completed_states WHERE path_id = p.id)
+                    AND NOT EXISTS (SELECT 1 FROM {prefix}_path_reject_states WHERE path_id = p.id)
+            )
+            "#
+            ),
+            params![tid, FileState::Alive, FileState::Terminal],
+        )?;
+    }
+
+    Ok(count)
+}
+
 pub(super) struct RecTransfer {
     pub tid: String,
     pub peer: String,
+    pub created_at: NaiveDateTime,
 }
 
 pub(super) fn transfers_to_resume(
@@ -346,7 +414,7 @@ pub(super) fn transfers_to_resume(
     let res = conn
         .prepare(
             r#"
-            SELECT t.id as tid, peer
+            SELECT t.id as tid, peer, t.created_at
             FROM transfers t
             INNER JOIN sync_transfer st ON st.transfer_id = t.id
             WHERE t.is_outgoing = ?1
@@ -356,9 +424,27 @@ pub(super) fn transfers_to_resume(
             Ok(RecTransfer {
                 tid: r.get("tid")?,
                 peer: r.get("peer")?,
+                created_at: r.get("created_at")?,
             })
         })?
         .collect::<QueryResult<_>>()?;
 
     Ok(res)
 }
+
+/// Returns the number of the incoming and outgoing transfers to resume
+pub(super) fn count_transfers_to_resume(conn: &Connection) -> crate::Result<(usize, usize)> {
+    let res = conn.query_row(
+        r#"
+        SELECT
+            COALESCE(SUM(NOT t.is_outgoing), 0),
+            COALESCE(SUM(t.is_outgoing), 0)
+        FROM transfers t
+        INNER JOIN sync_transfer st ON st.transfer_id = t.id
+        "#,
+        [],
+        |r| Ok((r.get(0)?, r.get(1)?)),
+    )?;
+
+    Ok(res)
+}
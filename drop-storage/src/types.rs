@@ -1,6 +1,7 @@
 use std::path::PathBuf;
 
 use chrono::NaiveDateTime;
+use rusqlite::{types::FromSql, ToSql};
 use serde::Serialize;
 
 use crate::sync;
@@ -43,7 +44,12 @@ pub enum IncomingPathStateEventData {
         bytes_received: i64,
     },
     #[serde(rename = "completed")]
-    Completed { final_path: String },
+    Completed {
+        final_path: String,
+        was_renamed: bool,
+        /// The post completion hook removed the file from `final_path`
+        deleted: bool,
+    },
     #[serde(rename = "rejected")]
     Rejected { by_peer: bool, bytes_received: i64 },
     #[serde(rename = "paused")]
@@ -70,13 +76,50 @@ pub struct IncomingPathStateEvent {
     pub data: IncomingPathStateEventData,
 }
 
+/// Why the transfer was canceled
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, strum::FromRepr)]
+#[serde(rename_all = "snake_case")]
+#[repr(u8)]
+pub enum CancelCause {
+    /// Canceled on the user request
+    #[default]
+    User = 0,
+    /// Canceled because the application is shutting down
+    Shutdown = 1,
+    /// Canceled because another transfer took its place
+    Replaced = 2,
+    /// Canceled because of an error
+    Error = 3,
+    /// Canceled because the incoming offer was not acted upon in time
+    Expired = 4,
+}
+
+impl ToSql for CancelCause {
+    fn to_sql(&self) -> rusqlite::Result<rusqlite::types::ToSqlOutput<'_>> {
+        Ok((*self as u8).into())
+    }
+}
+
+impl FromSql for CancelCause {
+    fn column_result(value: rusqlite::types::ValueRef<'_>) -> rusqlite::types::FromSqlResult<Self> {
+        let x = value.as_i64()?;
+
+        x.try_into()
+            .ok()
+            .and_then(Self::from_repr)
+            .ok_or(rusqlite::types::FromSqlError::OutOfRange(x))
+    }
+}
+
 #[derive(Serialize)]
 #[serde(tag = "state")]
 pub enum TransferStateEventData {
     #[serde(rename = "cancel")]
-    Cancel { by_peer: bool },
+    Cancel { by_peer: bool, cause: CancelCause },
     #[serde(rename = "failed")]
     Failed { status_code: i64 },
+    #[serde(rename = "rejected")]
+    Rejected { by_peer: bool, reason: String },
 }
 
 #[derive(Serialize)]
@@ -89,7 +132,24 @@ pub struct TransferStateEvent {
     pub data: TransferStateEventData,
 }
 
-#[derive(Copy, Clone)]
+/// A single state row of either the transfer or one of its files
+pub enum StateEvent {
+    Transfer(TransferStateEvent),
+    IncomingPath(IncomingPathStateEvent),
+    OutgoingPath(OutgoingPathStateEvent),
+}
+
+impl StateEvent {
+    pub fn created_at(&self) -> NaiveDateTime {
+        match self {
+            Self::Transfer(ev) => ev.created_at,
+            Self::IncomingPath(ev) => ev.created_at,
+            Self::OutgoingPath(ev) => ev.created_at,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
 #[repr(u32)]
 pub enum TransferType {
     Incoming = 0,
@@ -120,6 +180,18 @@ pub struct TransferInfo {
     pub files: TransferFiles,
 }
 
+/// The download finished by another libdrop instance, carried over on the
+/// device migration
+pub struct ImportedIncoming {
+    pub transfer_id: TransferId,
+    pub peer: String,
+    pub file_id: FileId,
+    pub relative_path: String,
+    pub size: i64,
+    pub final_path: String,
+    pub checksum: Option<Vec<u8>>,
+}
+
 pub struct FileChecksum {
     pub file_id: FileId,
     pub checksum: Option<Vec<u8>>,
@@ -134,12 +206,93 @@ pub struct IncomingFileToRetry {
 pub struct IncomingTransferToRetry {
     pub uuid: uuid::Uuid,
     pub peer: String,
+    pub created_at: NaiveDateTime,
     pub files: Vec<IncomingFileToRetry>,
 }
 
 pub struct FinishedIncomingFile {
     pub subpath: String,
     pub final_path: String,
+    /// The file was saved under a different name because of a name collision
+    pub was_renamed: bool,
+}
+
+pub struct IncomingDirMapping {
+    pub path: String,
+    pub mapped_name: String,
+}
+
+pub struct CompletedIncomingFile {
+    pub final_path: String,
+    /// The post completion hook removed the file from `final_path`
+    pub deleted: bool,
+    pub checksum: Option<Vec<u8>>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PeerUsage {
+    pub bytes_sent: i64,
+    pub bytes_received: i64,
+    pub updated_at: Option<NaiveDateTime>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PeerSummary {
+    pub peer: String,
+    pub transfer_count: i64,
+    pub last_transfer_at: NaiveDateTime,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StoredTransferState {
+    InProgress,
+    Canceled,
+    Failed,
+    Completed,
+}
+
+/// Filter used for querying the transfers by their state
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransferFilter {
+    Active,
+    Completed,
+    Failed,
+    Canceled,
+}
+
+impl TransferFilter {
+    /// The condition on the `canceled`, `failed` and `completed` columns,
+    /// classifying the state the same way as `StoredTransferState`
+    pub(crate) fn condition(self) -> &'static str {
+        match self {
+            Self::Canceled => "canceled",
+            Self::Failed => "NOT canceled AND failed",
+            Self::Completed => "NOT canceled AND NOT failed AND completed",
+            Self::Active => "NOT canceled AND NOT failed AND NOT completed",
+        }
+    }
+}
+
+/// Order in which the transfer queries return the transfers
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum TransferSort {
+    CreatedAsc,
+    #[default]
+    CreatedDesc,
+    PeerThenCreated,
+}
+
+impl TransferSort {
+    /// The `ORDER BY` terms for the `transfers t` table. The insertion
+    /// sequence is used instead of the timestamp so that the order survives the
+    /// device clock jumping backward
+    pub(crate) fn order_by(self) -> &'static str {
+        match self {
+            Self::CreatedAsc => "t.seq",
+            Self::CreatedDesc => "t.seq DESC",
+            Self::PeerThenCreated => "t.peer, t.seq",
+        }
+    }
 }
 
 pub struct OutgoingFileToRetry {
@@ -152,9 +305,44 @@ pub struct OutgoingFileToRetry {
 pub struct OutgoingTransferToRetry {
     pub uuid: uuid::Uuid,
     pub peer: String,
+    pub created_at: NaiveDateTime,
     pub files: Vec<OutgoingFileToRetry>,
 }
 
+/// The kind of the latest state recorded for a file
+#[derive(Debug, Clone, Copy, PartialEq, Eq, strum::FromRepr)]
+#[repr(u8)]
+pub enum FileStateKind {
+    Pending = 0,
+    Started = 1,
+    Paused = 2,
+    Failed = 3,
+    Completed = 4,
+    Rejected = 5,
+}
+
+impl FromSql for FileStateKind {
+    fn column_result(value: rusqlite::types::ValueRef<'_>) -> rusqlite::types::FromSqlResult<Self> {
+        let x = value.as_i64()?;
+
+        x.try_into()
+            .ok()
+            .and_then(Self::from_repr)
+            .ok_or(rusqlite::types::FromSqlError::OutOfRange(x))
+    }
+}
+
+/// A file of the transfer without its state history
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileManifestEntry {
+    pub file_id: FileId,
+    pub subpath: String,
+    pub size: i64,
+    pub direction: TransferType,
+    /// `None` if no state was recorded for the file yet
+    pub latest_state_kind: Option<FileStateKind>,
+}
+
 pub struct TempFileLocation {
     pub file_id: String,
     pub base_path: String,
@@ -183,6 +371,8 @@ pub struct Transfer {
     pub created_at: NaiveDateTime,
     pub peer_id: String,
     pub states: Vec<TransferStateEvent>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
     #[serde(flatten)]
     pub transfer_type: DbTransferType,
 }
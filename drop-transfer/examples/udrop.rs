@@ -13,7 +13,7 @@ use clap::{arg, command, value_parser, ArgAction, Command};
 use drop_auth::{PUBLIC_KEY_LENGTH, SECRET_KEY_LENGTH};
 use drop_config::DropConfig;
 use drop_storage::Storage;
-use drop_transfer::{auth, file, Event, File, OutgoingTransfer, Service, Transfer};
+use drop_transfer::{auth, file, Event, OutgoingTransfer, Service, Transfer};
 use slog::{o, Drain, Logger};
 use slog_scope::info;
 use tokio::sync::mpsc;
@@ -66,6 +66,20 @@ fn print_event(ev: &Event) {
         Event::RequestQueued(xfer) => {
             info!("[EVENT] RequestQueued {}: {:?}", xfer.id(), xfer.files(),);
         }
+        Event::IncomingTransferCreated(xfer) => {
+            info!(
+                "[EVENT] IncomingTransferCreated {}: {:?}",
+                xfer.id(),
+                xfer.files(),
+            );
+        }
+        Event::OutgoingTransferCreated(xfer) => {
+            info!(
+                "[EVENT] OutgoingTransferCreated {}: {:?}",
+                xfer.id(),
+                xfer.files(),
+            );
+        }
         Event::FileUploadStarted(xfer, file, offset) => {
             info!(
                 "[EVENT] FileUploadStarted {}: {:?}, offset {offset}",
@@ -97,18 +111,37 @@ fn print_event(ev: &Event) {
                 xfid, file, status
             );
         }
-        Event::IncomingTransferCanceled(xfer, by_peer) => {
+        Event::IncomingTransferCanceled(xfer, by_peer, cause) => {
             info!(
-                "[EVENT] IncomingTransferCanceled {}, by peer? {}",
+                "[EVENT] IncomingTransferCanceled {}, by peer? {}, cause: {:?}",
                 xfer.id(),
-                by_peer
+                by_peer,
+                cause
+            );
+        }
+        Event::IncomingTransferRejected { transfer, reason } => {
+            info!(
+                "[EVENT] IncomingTransferRejected {}, reason: {}",
+                transfer.id(),
+                reason
             );
         }
-        Event::OutgoingTransferCanceled(xfer, by_peer) => {
+        Event::IncomingTransferExpired(xfer) => {
+            info!("[EVENT] IncomingTransferExpired {}", xfer.id());
+        }
+        Event::OutgoingTransferCanceled(xfer, by_peer, cause) => {
             info!(
-                "[EVENT] OutgoingTransferCanceled {}, by peer? {}",
+                "[EVENT] OutgoingTransferCanceled {}, by peer? {}, cause: {:?}",
                 xfer.id(),
-                by_peer
+                by_peer,
+                cause
+            );
+        }
+        Event::OutgoingTransferRejected { transfer, reason } => {
+            info!(
+                "[EVENT] OutgoingTransferRejected {}, reason: {}",
+                transfer.id(),
+                reason
             );
         }
         Event::OutgoingTransferFailed(xfer, err, by_peer) => {
@@ -148,6 +181,15 @@ fn print_event(ev: &Event) {
             file_id,
             transferred,
         } => info!("[EVENT] FileUploadThrottled {transfer_id}: {file_id}, progress: {transferred}"),
+        Event::IncomingTransferInsufficientStorage {
+            transfer_id,
+            base_dir,
+            required,
+            available,
+        } => info!(
+            "[EVENT] IncomingTransferInsufficientStorage {transfer_id}: {base_dir}, required: \
+             {required}, available: {available}"
+        ),
         Event::FinalizeChecksumStarted {
             transfer_id,
             file_id,
@@ -186,11 +228,42 @@ fn print_event(ev: &Event) {
             "[EVENT] OutgoingTransferDeferred {}: error: {error}",
             transfer.id()
         ),
+        Event::TransferPaused { transfer_id } => info!("[EVENT] TransferPaused {transfer_id}"),
+        Event::TransferResumed { transfer_id } => info!("[EVENT] TransferResumed {transfer_id}"),
+        Event::ProtocolDowngraded {
+            transfer_id,
+            from,
+            to,
+        } => info!("[EVENT] ProtocolDowngraded {transfer_id}: from v{from} to v{to}"),
+        Event::ListenAddressChanged { addr } => info!("[EVENT] ListenAddressChanged: {addr}"),
+        Event::PeerInfo {
+            transfer_id,
+            device_name,
+            app_version,
+        } => info!("[EVENT] PeerInfo {transfer_id}: device: {device_name:?}, app: {app_version:?}"),
+        Event::TransferSummary {
+            transfer_id,
+            succeeded,
+            failed,
+            rejected,
+            total_bytes,
+            duration_secs,
+        } => info!(
+            "[EVENT] TransferSummary {transfer_id}: succeeded: {succeeded}, failed: {failed}, \
+             rejected: {rejected}, bytes: {total_bytes}, duration: {duration_secs}s"
+        ),
         Event::FileDownloadPending {
             transfer_id,
             file_id,
             base_dir,
         } => info!("[EVENT] FileDownloadPending {transfer_id}: {file_id}, base_dir: {base_dir}"),
+        Event::FileDownloadMissing {
+            transfer_id,
+            file_id,
+            final_path,
+        } => info!(
+            "[EVENT] FileDownloadMissing {transfer_id}: {file_id}, final_path: {final_path:?}"
+        ),
     }
 }
 
@@ -219,11 +292,13 @@ async fn listen(
                         .context("Failed to cancled transfer")?;
                 }
 
-                for file in xfer.files().values() {
-                    service
-                        .download(xfid, file.id(), &out_dir.to_string_lossy())
-                        .await
-                        .context("Cannot issue download call")?;
+                let failed = service
+                    .download_all(xfid, &out_dir.to_string_lossy())
+                    .await
+                    .context("Cannot issue download call")?;
+
+                if let Some((file_id, err)) = failed.into_iter().next() {
+                    anyhow::bail!("Cannot download file {file_id}: {err}");
                 }
             }
             Event::FileDownloadStarted(xfer, file, _, _) => {
@@ -282,12 +357,21 @@ async fn listen(
                     }
                 }
             }
-            Event::IncomingTransferCanceled(xfer, _) => {
+            Event::IncomingTransferCanceled(xfer, ..) => {
                 active_file_downloads.remove(&xfer.id());
             }
-            Event::OutgoingTransferCanceled(xfer, _) => {
+            Event::IncomingTransferRejected { transfer, .. } => {
+                active_file_downloads.remove(&transfer.id());
+            }
+            Event::IncomingTransferExpired(xfer) => {
+                active_file_downloads.remove(&xfer.id());
+            }
+            Event::OutgoingTransferCanceled(xfer, ..) => {
                 active_file_downloads.remove(&xfer.id());
             }
+            Event::OutgoingTransferRejected { transfer, .. } => {
+                active_file_downloads.remove(&transfer.id());
+            }
             _ => (),
         }
     }
@@ -369,9 +453,12 @@ async fn main() -> anyhow::Result<()> {
             .get_many::<String>("FILE")
             .context("Missing path list")?
         {
-            files
-                .gather_from_path(path)
-                .context("Cannot build transfer from the files provided")?;
+            if path == "-" {
+                files.gather_from_reader("stdin", std::io::stdin(), None)
+            } else {
+                files.gather_from_path(path)
+            }
+            .context("Cannot build transfer from the files provided")?;
         }
 
         Some(OutgoingTransfer::new(*addr, files.take(), &config)?)
@@ -394,7 +481,12 @@ async fn main() -> anyhow::Result<()> {
     };
 
     let storage_file = matches.get_one::<String>("storage").unwrap();
-    let storage = Arc::new(Storage::new(logger.clone(), storage_file).unwrap());
+    let storage = Arc::new(
+        Storage::new(logger.clone(), storage_file)
+            .unwrap()
+            .with_busy_timeout(config.storage_busy_timeout)
+            .unwrap(),
+    );
 
     let mut service = Service::start(
         addr,
@@ -407,6 +499,12 @@ async fn main() -> anyhow::Result<()> {
         Instant::now(),
         #[cfg(unix)]
         None,
+        #[cfg(unix)]
+        None,
+        None,
+        None,
+        Arc::new(drop_transfer::SystemClock),
+        true,
     )
     .await
     .context("Failed to start service")?;
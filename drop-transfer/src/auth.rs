@@ -84,6 +84,20 @@ impl Context {
         })
     }
 
+    /// Creates the cipher for the file contents of the transfer exchanged with
+    /// the peer. Both sides arrive at the same key
+    pub fn content_cipher(
+        &self,
+        peer_ip: IpAddr,
+        transfer_id: uuid::Uuid,
+    ) -> Option<drop_auth::ContentCipher> {
+        tokio::task::block_in_place(|| {
+            let public = (self.public)(peer_ip)?;
+            let key = drop_auth::derive_content_key(&self.secret, &public, transfer_id.as_bytes())?;
+            Some(drop_auth::ContentCipher::new(&key))
+        })
+    }
+
     pub fn create_servers_auth_header(
         &self,
         peer_ip: IpAddr,
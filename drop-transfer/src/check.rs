@@ -4,6 +4,7 @@ use std::{
     sync::Arc,
 };
 
+use drop_storage::CancelCause;
 use hyper::StatusCode;
 use slog::{debug, info, Logger};
 use tokio_util::sync::CancellationToken;
@@ -30,8 +31,11 @@ pub(crate) fn spawn(
 
     tokio::spawn(async move {
         let _guard = guard;
-        let mut backoff =
-            utils::RetryTrigger::new(refresh_trigger, state.config.connection_retries);
+        let mut backoff = utils::RetryTrigger::new(
+            refresh_trigger,
+            state.config.connection_retries,
+            state.clock.clone(),
+        );
 
         let task = async {
             loop {
@@ -40,7 +44,10 @@ pub(crate) fn spawn(
                     info!(logger, "Transfer {} is gone. Clearing", xfer.id());
 
                     if let Some(state) = state.transfer_manager.incoming_remove(xfer.id()).await {
-                        state.xfer_events.cancel(true).await
+                        state
+                            .xfer_events
+                            .cancel(true, CancelCause::User, state.stats())
+                            .await
                     }
 
                     break;
@@ -74,7 +81,7 @@ async fn run(state: &State, xfer: &Arc<IncomingTransfer>, logger: &Logger) -> Co
 
 async fn ask_server(state: &State, xfer: &IncomingTransfer, logger: &Logger) -> ControlFlow<()> {
     let mut connector = hyper::client::HttpConnector::new();
-    connector.set_local_address(Some(state.addr));
+    connector.set_local_address(Some(state.addr()));
 
     let client = hyper::Client::builder().build::<_, hyper::Body>(connector);
 
@@ -0,0 +1,121 @@
+use std::time::{Duration, Instant, SystemTime};
+
+use futures::future::BoxFuture;
+
+/// Source of time for the time dependent features, replaceable so that they
+/// can be tested deterministically
+pub trait Clock: Send + Sync {
+    /// The wall clock time
+    fn now(&self) -> SystemTime;
+    /// The monotonic time
+    fn instant(&self) -> Instant;
+    /// Completes once the given duration elapses on this clock
+    fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()>;
+}
+
+/// The clock backed by the system time and the tokio timer
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+
+    fn instant(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()> {
+        Box::pin(tokio::time::sleep(duration))
+    }
+}
+
+#[cfg(test)]
+pub(crate) use mock::MockClock;
+
+#[cfg(test)]
+mod mock {
+    use std::{
+        sync::Mutex,
+        time::{Duration, Instant, SystemTime},
+    };
+
+    use futures::future::BoxFuture;
+    use tokio::sync::oneshot;
+
+    /// The clock standing still until advanced manually
+    pub struct MockClock {
+        inner: Mutex<Inner>,
+    }
+
+    struct Inner {
+        now: SystemTime,
+        base: Instant,
+        elapsed: Duration,
+        sleepers: Vec<(Duration, oneshot::Sender<()>)>,
+    }
+
+    impl MockClock {
+        pub fn new(now: SystemTime) -> Self {
+            Self {
+                inner: Mutex::new(Inner {
+                    now,
+                    base: Instant::now(),
+                    elapsed: Duration::ZERO,
+                    sleepers: Vec::new(),
+                }),
+            }
+        }
+
+        /// Moves the time forward, waking up the sleeps that elapsed
+        pub fn advance(&self, duration: Duration) {
+            let mut inner = self.inner.lock().expect("Poisoned lock");
+            inner.now += duration;
+            inner.elapsed += duration;
+
+            let elapsed = inner.elapsed;
+            let (due, pending) = std::mem::take(&mut inner.sleepers)
+                .into_iter()
+                .partition(|(deadline, _)| *deadline <= elapsed);
+            inner.sleepers = pending;
+
+            for (_, waker) in due {
+                let _ = waker.send(());
+            }
+        }
+    }
+
+    impl super::Clock for MockClock {
+        fn now(&self) -> SystemTime {
+            self.inner.lock().expect("Poisoned lock").now
+        }
+
+        fn instant(&self) -> Instant {
+            let inner = self.inner.lock().expect("Poisoned lock");
+            inner.base + inner.elapsed
+        }
+
+        fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()> {
+            let mut inner = self.inner.lock().expect("Poisoned lock");
+
+            let Some(deadline) = inner.elapsed.checked_add(duration) else {
+                return Box::pin(std::future::pending());
+            };
+
+            if duration.is_zero() {
+                return Box::pin(std::future::ready(()));
+            }
+
+            let (tx, rx) = oneshot::channel();
+            inner.sleepers.push((deadline, tx));
+
+            Box::pin(async move {
+                // The clock being dropped means the time won't move anymore
+                if rx.await.is_err() {
+                    std::future::pending::<()>().await;
+                }
+            })
+        }
+    }
+}
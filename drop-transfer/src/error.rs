@@ -1,9 +1,13 @@
-use std::io::{Error as IoError, ErrorKind};
+use std::{
+    fmt,
+    io::{Error as IoError, ErrorKind},
+    net::IpAddr,
+};
 
 use drop_analytics::MOOSE_STATUS_SUCCESS;
 use tokio_tungstenite::tungstenite;
 
-use crate::manager::FileTerminalState;
+use crate::{manager::FileTerminalState, FileId};
 
 #[derive(thiserror::Error, Debug)]
 pub enum Error {
@@ -55,9 +59,125 @@ pub enum Error {
     ConnectionClosedByPeer,
     #[error("Peer responded with too many requests status")]
     TooManyRequests,
+    #[error("Insufficient storage space")]
+    InsufficientStorage,
+    #[error("Transfer does not match the existing one: {}", display_mismatches(.details))]
+    TransferMismatch { details: Vec<MismatchDetail> },
+    #[error("Transfer ID is in use already by peer {existing}, offered again by {current}")]
+    TransferUuidConflict { existing: IpAddr, current: IpAddr },
+    #[error("Peer rejected the transfer because of its size")]
+    PeerRejectedSize,
+    #[error("File upload failed {0} times in a row")]
+    RetriesExceeded(u32),
+}
+
+/// Describes how the resumed transfer differs from the one known already
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MismatchDetail {
+    FileCount {
+        current: usize,
+        existing: usize,
+    },
+    /// The file is known but the peer did not offer it this time
+    Missing(FileId),
+    /// The peer offered a file that is not known
+    Unexpected(FileId),
+    Size {
+        file: FileId,
+        current: u64,
+        existing: u64,
+    },
+    MimeType {
+        file: FileId,
+        current: String,
+        existing: String,
+    },
+}
+
+impl fmt::Display for MismatchDetail {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::FileCount { current, existing } => {
+                write!(f, "file count {current}, expected {existing}")
+            }
+            Self::Missing(file) => write!(f, "missing file {file:?}"),
+            Self::Unexpected(file) => write!(f, "unexpected file {file:?}"),
+            Self::Size {
+                file,
+                current,
+                existing,
+            } => write!(f, "file {file:?} size {current}, expected {existing}"),
+            Self::MimeType {
+                file,
+                current,
+                existing,
+            } => write!(f, "file {file:?} mime type {current}, expected {existing}"),
+        }
+    }
+}
+
+fn display_mismatches(details: &[MismatchDetail]) -> String {
+    details
+        .iter()
+        .map(ToString::to_string)
+        .collect::<Vec<_>>()
+        .join(", ")
 }
 
 impl Error {
+    /// Copies the error for the case it has to be reported in several places.
+    /// The errors of the underlying libraries are not cloneable and are
+    /// replaced by their description, the status and the OS error code stay
+    /// the same
+    pub fn duplicate(&self) -> Self {
+        fn dup_io(err: &IoError) -> IoError {
+            match err.raw_os_error() {
+                Some(code) => IoError::from_raw_os_error(code),
+                None => IoError::new(err.kind(), err.to_string()),
+            }
+        }
+
+        match self {
+            Error::Canceled => Error::Canceled,
+            Error::BadPath(msg) => Error::BadPath(msg.clone()),
+            Error::BadFile => Error::BadFile,
+            Error::BadTransfer => Error::BadTransfer,
+            Error::BadTransferState(msg) => Error::BadTransferState(msg.clone()),
+            Error::BadFileId => Error::BadFileId,
+            Error::MismatchedSize => Error::MismatchedSize,
+            Error::UnexpectedData => Error::UnexpectedData,
+            Error::Io(err) => Error::Io(dup_io(err)),
+            Error::DirectoryNotExpected => Error::DirectoryNotExpected,
+            Error::TransferLimitsExceeded => Error::TransferLimitsExceeded,
+            Error::InvalidArgument => Error::InvalidArgument,
+            Error::WsServer(err) => Error::Io(IoError::new(ErrorKind::Other, err.to_string())),
+            Error::WsClient(tungstenite::Error::Io(err)) => Error::Io(dup_io(err)),
+            Error::WsClient(err) => Error::Io(IoError::new(ErrorKind::Other, err.to_string())),
+            Error::AddrInUse => Error::AddrInUse,
+            Error::FileModified => Error::FileModified,
+            Error::FilenameTooLong => Error::FilenameTooLong,
+            Error::AuthenticationFailed => Error::AuthenticationFailed,
+            Error::StorageError(err) => {
+                Error::StorageError(drop_storage::error::Error::InternalError(err.to_string()))
+            }
+            Error::ChecksumMismatch => Error::ChecksumMismatch,
+            Error::FileStateMismatch(state) => Error::FileStateMismatch(*state),
+            Error::EmptyTransfer => Error::EmptyTransfer,
+            Error::ConnectionClosedByPeer => Error::ConnectionClosedByPeer,
+            Error::TooManyRequests => Error::TooManyRequests,
+            Error::InsufficientStorage => Error::InsufficientStorage,
+            Error::TransferMismatch { details } => Error::TransferMismatch {
+                details: details.clone(),
+            },
+            Error::TransferUuidConflict { existing, current } => Error::TransferUuidConflict {
+                existing: *existing,
+                current: *current,
+            },
+            Error::PeerRejectedSize => Error::PeerRejectedSize,
+            Error::RetriesExceeded(count) => Error::RetriesExceeded(*count),
+        }
+    }
+
     pub fn os_err_code(&self) -> Option<i32> {
         match self {
             Error::Io(ioerr) => ioerr.raw_os_error().map(|c| c as _),
@@ -108,6 +228,11 @@ impl From<&Error> for drop_core::Status {
             Error::EmptyTransfer => Status::EmptyTransfer,
             Error::ConnectionClosedByPeer => Status::ConnectionClosedByPeer,
             Error::TooManyRequests => Status::TooManyRequests,
+            Error::InsufficientStorage => Status::InsufficientStorage,
+            Error::TransferMismatch { .. } => Status::BadTransferState,
+            Error::TransferUuidConflict { .. } => Status::BadTransferState,
+            Error::PeerRejectedSize => Status::PeerRejectedSize,
+            Error::RetriesExceeded(_) => Status::RetriesExceeded,
         }
     }
 }
@@ -1,5 +1,6 @@
-use std::{path::Path, sync::Arc};
+use std::{net::IpAddr, path::Path, sync::Arc};
 
+use drop_storage::CancelCause;
 use uuid::Uuid;
 
 use crate::{
@@ -12,7 +13,12 @@ use crate::{
 #[derive(Debug)]
 pub struct DownloadSuccess {
     pub id: FileId,
+    // The actual location on disk, after resolving the name collisions
     pub final_path: Hidden<Box<Path>>,
+    // The name collision made the file land under a different name
+    pub renamed: bool,
+    // The post completion hook removed the file, it's no longer at `final_path`
+    pub deleted: bool,
 }
 
 #[derive(Debug)]
@@ -20,6 +26,9 @@ pub enum Event {
     RequestReceived(Arc<IncomingTransfer>),
     RequestQueued(Arc<OutgoingTransfer>),
 
+    IncomingTransferCreated(Arc<IncomingTransfer>),
+    OutgoingTransferCreated(Arc<OutgoingTransfer>),
+
     FileUploadStarted(Arc<OutgoingTransfer>, FileId, u64),
     FileDownloadStarted(Arc<IncomingTransfer>, FileId, String, u64),
 
@@ -29,6 +38,13 @@ pub enum Event {
         base_dir: String,
     },
 
+    // The completed file no longer exists at the location it was downloaded to
+    FileDownloadMissing {
+        transfer_id: Uuid,
+        file_id: FileId,
+        final_path: Hidden<Box<Path>>,
+    },
+
     FileUploadProgress(Arc<OutgoingTransfer>, FileId, u64),
     FileDownloadProgress(Arc<IncomingTransfer>, FileId, u64),
 
@@ -64,16 +80,76 @@ pub enum Event {
         transferred: u64,
     },
 
-    IncomingTransferCanceled(Arc<IncomingTransfer>, bool),
-    OutgoingTransferCanceled(Arc<OutgoingTransfer>, bool),
+    IncomingTransferInsufficientStorage {
+        transfer_id: Uuid,
+        base_dir: String,
+        required: u64,
+        available: u64,
+    },
+
+    IncomingTransferCanceled(Arc<IncomingTransfer>, bool, CancelCause),
+    IncomingTransferRejected {
+        transfer: Arc<IncomingTransfer>,
+        reason: String,
+    },
+    // None of the files were downloaded or rejected within the configured
+    // `incoming_offer_ttl`, the whole transfer was rejected
+    IncomingTransferExpired(Arc<IncomingTransfer>),
+    OutgoingTransferCanceled(Arc<OutgoingTransfer>, bool, CancelCause),
+    // The peer rejected the whole transfer with the given reason
+    OutgoingTransferRejected {
+        transfer: Arc<OutgoingTransfer>,
+        reason: String,
+    },
 
     OutgoingTransferFailed(Arc<OutgoingTransfer>, Error, bool),
 
+    // Emitted once right after the transfer terminates, whatever the reason
+    TransferSummary {
+        transfer_id: Uuid,
+        succeeded: u32,
+        failed: u32,
+        rejected: u32,
+        total_bytes: u64,
+        duration_secs: u64,
+    },
+
+    // Emitted for every transfer on `Service::pause_all()` and
+    // `Service::resume_all()`
+    TransferPaused {
+        transfer_id: Uuid,
+    },
+    TransferResumed {
+        transfer_id: Uuid,
+    },
+
+    // The WS server listens on the new address after `Service::rebind()`
+    ListenAddressChanged {
+        addr: IpAddr,
+    },
+
     OutgoingTransferDeferred {
         transfer: Arc<OutgoingTransfer>,
         error: Error,
     },
 
+    // The display only information the peer advertised on connection. The
+    // fields are `None` when the peer did not advertise them or its protocol
+    // version does not support it
+    PeerInfo {
+        transfer_id: Uuid,
+        device_name: Option<String>,
+        app_version: Option<String>,
+    },
+
+    // The peer does not support the latest protocol version and the outgoing
+    // transfer connected with an older one
+    ProtocolDowngraded {
+        transfer_id: Uuid,
+        from: i32,
+        to: i32,
+    },
+
     FinalizeChecksumStarted {
         transfer_id: Uuid,
         file_id: FileId,
@@ -104,3 +180,28 @@ pub enum Event {
         progress: u64,
     },
 }
+
+/// The part of the download the checksum events belong to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChecksumPhase {
+    /// Checking the already downloaded part of the file when resuming, the
+    /// `VerifyChecksum*` events
+    VerifyExisting,
+    /// Hashing the whole file once downloaded, the `FinalizeChecksum*` events
+    Finalize,
+}
+
+impl Event {
+    /// The phase of the checksum event, `None` for the other events
+    pub fn checksum_phase(&self) -> Option<ChecksumPhase> {
+        match self {
+            Self::VerifyChecksumStarted { .. }
+            | Self::VerifyChecksumProgress { .. }
+            | Self::VerifyChecksumFinished { .. } => Some(ChecksumPhase::VerifyExisting),
+            Self::FinalizeChecksumStarted { .. }
+            | Self::FinalizeChecksumProgress { .. }
+            | Self::FinalizeChecksumFinished { .. } => Some(ChecksumPhase::Finalize),
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,55 @@
+use std::{sync::Arc, time::Duration};
+
+use slog::{debug, info, Logger};
+use tokio_util::sync::CancellationToken;
+
+use crate::{service::State, tasks::AliveGuard, IncomingTransfer, Transfer};
+
+/// Rejects the incoming transfer once `ttl` elapses without any of its files
+/// being downloaded or rejected
+pub(crate) fn spawn(
+    state: Arc<State>,
+    xfer: Arc<IncomingTransfer>,
+    ttl: Duration,
+    logger: Logger,
+    guard: AliveGuard,
+    stop: CancellationToken,
+) {
+    tokio::spawn(async move {
+        let _guard = guard;
+
+        let Some(acted_upon) = state
+            .transfer_manager
+            .incoming_offer_expiry(xfer.id())
+            .await
+        else {
+            return;
+        };
+
+        tokio::select! {
+            biased;
+
+            _ = stop.cancelled() => {
+                debug!(logger, "stop offer expiry job for: {}", xfer.id());
+            },
+            _ = acted_upon.cancelled() => {
+                debug!(logger, "Offer {} acted upon, expiry cancelled", xfer.id());
+            },
+            _ = state.clock.sleep(ttl) => expire(&state, &xfer, &logger).await,
+        }
+    });
+}
+
+async fn expire(state: &State, xfer: &IncomingTransfer, logger: &Logger) {
+    let Some(res) = state.transfer_manager.incoming_expire(xfer.id()).await else {
+        return;
+    };
+
+    info!(logger, "Incoming offer {} expired, rejecting", xfer.id());
+
+    for events in &res.file_events {
+        events.rejected(false).await;
+    }
+
+    res.xfer_events.expired(res.stats).await;
+}
@@ -1,10 +1,11 @@
-#[cfg(unix)]
-use std::os::unix::prelude::*;
 use std::{
     collections::HashSet,
-    fs,
+    fs, io,
     path::{Path, PathBuf},
+    sync::Arc,
 };
+#[cfg(unix)]
+use std::{mem::ManuallyDrop, os::unix::prelude::*};
 
 use drop_config::DropConfig;
 
@@ -24,6 +25,7 @@ pub struct GatherCtx<'a> {
     config: &'a DropConfig,
     #[cfg(unix)]
     fdresolv: Option<&'a super::FdResolver>,
+    mime_resolv: Option<Arc<super::MimeResolver>>,
     files: Vec<FileToSend>,
     used_names: HashSet<PathBuf>,
 }
@@ -34,6 +36,7 @@ impl<'a> GatherCtx<'a> {
             config,
             #[cfg(unix)]
             fdresolv: None,
+            mime_resolv: None,
             files: Vec::new(),
             used_names: HashSet::new(),
         }
@@ -45,9 +48,24 @@ impl<'a> GatherCtx<'a> {
         self
     }
 
+    /// Sets the callback consulted for the MIME type of the gathered files
+    /// before inferring it from their contents
+    pub fn with_mime_resolver(&mut self, mime_resolv: Arc<super::MimeResolver>) -> &mut Self {
+        self.mime_resolv = Some(mime_resolv);
+        self
+    }
+
     pub fn take(&mut self) -> Vec<FileToSend> {
         self.used_names.clear();
-        std::mem::take(&mut self.files)
+
+        let mut files = std::mem::take(&mut self.files);
+        if let Some(mime_resolv) = &self.mime_resolv {
+            for file in &mut files {
+                file.mime_resolver = Some(mime_resolv.clone());
+            }
+        }
+
+        files
     }
 
     fn fetch_free_dir_name(&mut self, path: &Path) -> crate::Result<PathBuf> {
@@ -95,21 +113,7 @@ impl<'a> GatherCtx<'a> {
         let fd = if let Some(fd) = fd {
             fd
         } else {
-            let fdresolv = if let Some(fdresolv) = self.fdresolv.as_ref() {
-                fdresolv
-            } else {
-                return Err(crate::Error::BadTransferState(
-                    "Content URI provided but RD resolver callback is not set up".into(),
-                ));
-            };
-
-            if let Some(fd) = fdresolv(uri.as_str()) {
-                fd
-            } else {
-                return Err(crate::Error::BadTransferState(format!(
-                    "Failed to fetch FD for file: {uri}"
-                )));
-            }
+            self.resolve_fd(&uri)?
         };
 
         // In case of FD, its allways a file
@@ -119,4 +123,71 @@ impl<'a> GatherCtx<'a> {
         self.files.push(file);
         Ok(self)
     }
+
+    /// Invokes the FD resolver for each of the content URIs up front, so that
+    /// the revoked or invalid ones are reported before the transfer starts
+    /// instead of failing it midway. The results follow the order of the
+    /// sources, the paths are always valid here
+    #[cfg(unix)]
+    pub fn validate_fds(&self, srcs: &[GatherSrc]) -> Vec<crate::Result<()>> {
+        srcs.iter()
+            .map(|src| match src {
+                GatherSrc::Path(_) => Ok(()),
+                GatherSrc::ContentUri { uri, fd, .. } => {
+                    let fd = match fd {
+                        Some(fd) => *fd,
+                        None => self.resolve_fd(uri)?,
+                    };
+
+                    // We do not own the FD so we cannot allow rust to close it
+                    let file = ManuallyDrop::new(unsafe { fs::File::from_raw_fd(fd) });
+                    if file.metadata()?.is_dir() {
+                        return Err(crate::Error::DirectoryNotExpected);
+                    }
+
+                    Ok(())
+                }
+            })
+            .collect()
+    }
+
+    #[cfg(unix)]
+    fn resolve_fd(&self, uri: &url::Url) -> crate::Result<RawFd> {
+        let fdresolv = self.fdresolv.as_ref().ok_or_else(|| {
+            crate::Error::BadTransferState(
+                "Content URI provided but RD resolver callback is not set up".into(),
+            )
+        })?;
+
+        fdresolv(uri.as_str()).ok_or_else(|| {
+            crate::Error::BadTransferState(format!("Failed to fetch FD for file: {uri}"))
+        })
+    }
+
+    pub fn gather_from_bytes(
+        &mut self,
+        path: impl AsRef<Path>,
+        bytes: Arc<Vec<u8>>,
+        mime: Option<String>,
+    ) -> crate::Result<&mut Self> {
+        let subpath = super::FileSubPath::from_file_name(path)?;
+        let file = FileToSend::from_bytes(subpath, bytes, mime);
+
+        self.files.push(file);
+        Ok(self)
+    }
+
+    /// Adds the file read from the stream of unknown length, e.g. a pipe
+    pub fn gather_from_reader(
+        &mut self,
+        path: impl AsRef<Path>,
+        reader: impl io::Read + Send + 'static,
+        mime: Option<String>,
+    ) -> crate::Result<&mut Self> {
+        let subpath = super::FileSubPath::from_file_name(path)?;
+        let file = FileToSend::from_reader(subpath, reader, mime);
+
+        self.files.push(file);
+        Ok(self)
+    }
 }
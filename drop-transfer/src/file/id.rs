@@ -128,6 +128,11 @@ impl FileSubPath {
     pub fn len(&self) -> usize {
         self.0.len()
     }
+
+    /// Checks whether the path lies under `prefix`, comparing whole components
+    pub fn starts_with(&self, prefix: &FileSubPath) -> bool {
+        self.0.starts_with(&prefix.0)
+    }
 }
 
 impl<T> From<T> for FileSubPath
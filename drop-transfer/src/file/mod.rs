@@ -2,14 +2,15 @@ mod gather;
 mod id;
 mod reader;
 
+#[cfg(unix)]
+use std::os::unix::prelude::*;
 use std::{
     fmt,
     future::Future,
     io::{self, BufRead, Read, Write},
     path::{Path, PathBuf},
+    sync::{Arc, Mutex},
 };
-#[cfg(unix)]
-use std::{os::unix::prelude::*, sync::Arc};
 
 use drop_analytics::TransferDirection;
 use drop_config::DropConfig;
@@ -30,10 +31,22 @@ pub struct FileInfo {
 #[cfg(unix)]
 pub type FdResolver = dyn Fn(&str) -> Option<RawFd> + Send + Sync;
 
+/// Provides a fresh content URI in place of the one that can no longer be
+/// opened when resuming an outgoing transfer. `None` fails the file
+#[cfg(unix)]
+pub type ContentUriRefresher = dyn Fn(&str) -> Option<String> + Send + Sync;
+
+/// Provides the MIME type of the file to send, consulted before inferring it
+/// from the file contents. `None` falls back to the inference
+pub type MimeResolver = dyn Fn(&FileToSend) -> Option<String> + Send + Sync;
+
 const HEADER_SIZE: usize = 1024;
 const UNKNOWN_STR: &str = "unknown";
 
-const CHECKSUM_CHUNK_SIZE: usize = 256 * 1024; // 256 KiB
+/// Scheme of the URIs stored in the database for the in-memory files
+pub(crate) const MEMORY_URI_SCHEME: &str = "memory";
+/// Scheme of the URIs stored in the database for the streamed files
+pub(crate) const STREAM_URI_SCHEME: &str = "stream";
 
 pub trait File {
     fn id(&self) -> &FileId;
@@ -51,13 +64,13 @@ pub trait File {
     }
 }
 
-#[derive(Debug)]
 pub struct FileToSend {
     file_id: FileId,
     subpath: FileSubPath,
     size: u64,
     pub(crate) source: FileSource,
     mime_type: OnceCell<Hidden<String>>,
+    mime_resolver: Option<Arc<MimeResolver>>,
 }
 
 #[derive(Debug, Clone)]
@@ -65,6 +78,8 @@ pub struct FileToRecv {
     file_id: FileId,
     subpath: FileSubPath,
     size: u64,
+    // The size is not known up front, the file ends with the end marker
+    streamed: bool,
 }
 
 pub enum FileSource {
@@ -75,6 +90,8 @@ pub enum FileSource {
         resolver: Option<Arc<FdResolver>>,
         content_uri: url::Url,
     },
+    Memory(Arc<Vec<u8>>),
+    Stream(Arc<Mutex<Option<Box<dyn io::Read + Send>>>>),
 }
 
 impl fmt::Debug for FileSource {
@@ -89,10 +106,27 @@ impl fmt::Debug for FileSource {
                 .field("uri", content_uri)
                 .field("fd", fd)
                 .finish_non_exhaustive(),
+            FileSource::Memory(bytes) => f
+                .debug_struct("FileSource::Memory")
+                .field("len", &bytes.len())
+                .finish_non_exhaustive(),
+            FileSource::Stream(_) => f.debug_struct("FileSource::Stream").finish_non_exhaustive(),
         }
     }
 }
 
+impl fmt::Debug for FileToSend {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("FileToSend")
+            .field("file_id", &self.file_id)
+            .field("subpath", &self.subpath)
+            .field("size", &self.size)
+            .field("source", &self.source)
+            .field("mime_type", &self.mime_type)
+            .finish_non_exhaustive()
+    }
+}
+
 impl File for FileToSend {
     fn id(&self) -> &FileId {
         &self.file_id
@@ -109,6 +143,17 @@ impl File for FileToSend {
     fn mime_type(&self) -> &str {
         self.mime_type
             .get_or_try_init(|| {
+                if let Some(mime) = self
+                    .mime_resolver
+                    .as_ref()
+                    .and_then(|resolve| resolve(self))
+                {
+                    return Ok(Hidden(mime));
+                }
+
+                // Inferring the type would consume the stream
+                self.ensure_not_streamed()?;
+
                 let reader = reader::open(&self.source)?;
                 let mime = infer_mime(reader)?;
                 crate::Result::Ok(Hidden(mime))
@@ -150,16 +195,63 @@ impl FileToRecv {
             file_id,
             subpath,
             size,
+            streamed: false,
         }
     }
+
+    /// Creates the file of unknown size, received until the sender marks its
+    /// end
+    pub fn new_streamed(file_id: FileId, subpath: FileSubPath) -> Self {
+        Self {
+            file_id,
+            subpath,
+            size: 0,
+            streamed: true,
+        }
+    }
+
+    pub fn is_streamed(&self) -> bool {
+        self.streamed
+    }
 }
 
 impl FileToSend {
+    /// The location of the file as stored in the database: `file://` for the
+    /// disk files, the content URI for the FD ones
+    pub fn uri(&self) -> Option<url::Url> {
+        match &self.source {
+            FileSource::Path(fullpath) => url::Url::from_file_path(&fullpath.0).ok(),
+            #[cfg(unix)]
+            FileSource::Fd { content_uri, .. } => Some(content_uri.clone()),
+            FileSource::Memory(_) => {
+                url::Url::parse(&format!("{MEMORY_URI_SCHEME}:{}", self.id())).ok()
+            }
+            FileSource::Stream(_) => {
+                url::Url::parse(&format!("{STREAM_URI_SCHEME}:{}", self.id())).ok()
+            }
+        }
+    }
+
+    /// Whether the file is read from the stream of unknown length
+    pub fn is_streamed(&self) -> bool {
+        matches!(self.source, FileSource::Stream(_))
+    }
+
+    fn ensure_not_streamed(&self) -> crate::Result<()> {
+        if self.is_streamed() {
+            return Err(Error::BadTransferState(
+                "The streamed file can be read only once".into(),
+            ));
+        }
+        Ok(())
+    }
+
     pub fn base_dir(&self) -> Option<&str> {
         let fullpath = match &self.source {
             FileSource::Path(fullpath) => fullpath,
             #[cfg(unix)]
             FileSource::Fd { .. } => return None,
+            FileSource::Memory(_) | FileSource::Stream(_) => return None,
         };
 
         let base_dir = fullpath.ancestors().nth(self.subpath.len())?;
@@ -188,6 +280,7 @@ impl FileToSend {
             size,
             source: FileSource::Path(Hidden(abspath)),
             mime_type: OnceCell::new(),
+            mime_resolver: None,
         }
     }
 
@@ -223,6 +316,7 @@ impl FileToSend {
                     content_uri,
                 },
                 mime_type: OnceCell::new(),
+                mime_resolver: None,
             })
         };
         let result = create_file();
@@ -251,6 +345,60 @@ impl FileToSend {
                 content_uri,
             },
             mime_type: OnceCell::new(),
+            mime_resolver: None,
+        }
+    }
+
+    /// Creates the file from the contents kept in memory. The MIME type is
+    /// inferred from the contents if not given. Such files are not persisted
+    /// and so the transfers containing them cannot be resumed after restart
+    pub fn from_bytes(subpath: FileSubPath, bytes: Arc<Vec<u8>>, mime: Option<String>) -> Self {
+        let mut hash = sha2::Sha256::new();
+        hash.update(subpath.to_string().as_bytes());
+        hash.update(bytes.as_slice());
+        let file_id = FileId::from(hash);
+
+        let mime_type = match mime {
+            Some(mime) => OnceCell::with_value(Hidden(mime)),
+            None => OnceCell::new(),
+        };
+
+        Self {
+            file_id,
+            subpath,
+            size: bytes.len() as _,
+            source: FileSource::Memory(bytes),
+            mime_type,
+            mime_resolver: None,
+        }
+    }
+
+    /// Creates the file read from the stream until its end, e.g. a pipe or
+    /// the standard input. The size is not known up front and so the file can
+    /// be sent only to the peers supporting protocol v9. The stream is read
+    /// once, the transfers containing such files cannot be resumed
+    pub fn from_reader(
+        subpath: FileSubPath,
+        reader: impl io::Read + Send + 'static,
+        mime: Option<String>,
+    ) -> Self {
+        let mut hash = sha2::Sha256::new();
+        hash.update(subpath.to_string().as_bytes());
+        hash.update(uuid::Uuid::new_v4().as_bytes());
+        let file_id = FileId::from(hash);
+
+        let mime_type = match mime {
+            Some(mime) => OnceCell::with_value(Hidden(mime)),
+            None => OnceCell::new(),
+        };
+
+        Self {
+            file_id,
+            subpath,
+            size: 0,
+            source: FileSource::Stream(Arc::new(Mutex::new(Some(Box::new(reader))))),
+            mime_type,
+            mime_resolver: None,
         }
     }
 
@@ -305,10 +453,26 @@ impl FileToSend {
         FileReader::new(reader, meta)
     }
 
+    // Same as `open()` but reads from a copy of the file taken beforehand so
+    // that the reads are not affected by the later modifications of the file
+    pub(crate) fn open_snapshot(&self, offset: u64) -> crate::Result<FileReader> {
+        if let FileSource::Memory(_) | FileSource::Stream(_) = self.source {
+            // The in-memory files cannot be modified and the streams are read once anyway
+            return self.open(offset);
+        }
+
+        let mut reader = reader::open_snapshot(&self.source)?;
+        let meta = reader.meta()?;
+
+        reader.seek(io::SeekFrom::Start(offset))?;
+        FileReader::new(reader, meta)
+    }
+
     /// Calculate sha2 of a file. This is a blocking operation
     pub(crate) async fn checksum<F, Fut>(
         &self,
         limit: u64,
+        chunk_size: usize,
         progress_cb: Option<F>,
         event_granularity: Option<u64>,
     ) -> crate::Result<[u8; 32]>
@@ -316,26 +480,188 @@ impl FileToSend {
         F: FnMut(u64) -> Fut + Send + Sync,
         Fut: Future<Output = ()>,
     {
+        self.ensure_not_streamed()?;
+
         let reader = reader::open(&self.source)?.take(limit);
-        let csum = checksum(reader, progress_cb, event_granularity).await?;
+        let csum = checksum(reader, chunk_size, progress_cb, event_granularity).await?;
         Ok(csum)
     }
+
+    /// Calculate sha2 of each `block_size` bytes long block of the file up to
+    /// the `limit`. This is a blocking operation
+    pub(crate) async fn block_checksums(
+        &self,
+        limit: u64,
+        block_size: u64,
+        chunk_size: usize,
+    ) -> crate::Result<Vec<[u8; 32]>> {
+        self.ensure_not_streamed()?;
+
+        let reader = reader::open(&self.source)?.take(limit);
+        let (_, blocks) = checksum_with_blocks(
+            reader,
+            chunk_size,
+            block_size,
+            None::<fn(u64) -> futures::future::Ready<()>>,
+            None,
+        )
+        .await?;
+        Ok(blocks)
+    }
+
+    /// Calculate sha2 of a file, reusing the value cached in storage when the
+    /// file size and modification time are unchanged since it was computed
+    pub(crate) async fn checksum_cached(
+        &self,
+        storage: &drop_storage::Storage,
+        limit: u64,
+        chunk_size: usize,
+    ) -> crate::Result<[u8; 32]> {
+        // The in-memory files are cheap to hash and have no stable cache key
+        let key = match self.cache_key() {
+            Some(key) => key,
+            None => {
+                return self
+                    .checksum(
+                        limit,
+                        chunk_size,
+                        None::<fn(u64) -> futures::future::Ready<()>>,
+                        None,
+                    )
+                    .await
+            }
+        };
+
+        let mut reader = reader::open(&self.source)?;
+        let meta = reader.meta()?;
+
+        let size = meta.len() as i64;
+        let mtime = mtime_millis(&meta);
+
+        if let Some(csum) = storage
+            .fetch_outgoing_checksum(&key, size, mtime, limit as _)
+            .await
+            .and_then(|csum| <[u8; 32]>::try_from(csum.as_slice()).ok())
+        {
+            return Ok(csum);
+        }
+
+        let csum = checksum(
+            (&mut reader).take(limit),
+            chunk_size,
+            None::<fn(u64) -> futures::future::Ready<()>>,
+            None,
+        )
+        .await?;
+
+        // Do not cache the result if the file changed while hashing
+        let meta = reader.meta()?;
+        if meta.len() as i64 == size && mtime_millis(&meta) == mtime {
+            storage
+                .save_outgoing_checksum(&key, size, mtime, limit as _, &csum)
+                .await;
+        }
+
+        Ok(csum)
+    }
+
+    /// The checksum cached in storage by `checksum_cached()`, provided the
+    /// file size and modification time are unchanged. The file contents are
+    /// never read
+    pub(crate) async fn cached_checksum(
+        &self,
+        storage: &drop_storage::Storage,
+        limit: u64,
+    ) -> Option<[u8; 32]> {
+        let key = self.cache_key()?;
+        let meta = reader::open(&self.source).ok()?.meta().ok()?;
+
+        storage
+            .fetch_outgoing_checksum(&key, meta.len() as _, mtime_millis(&meta), limit as _)
+            .await
+            .and_then(|csum| <[u8; 32]>::try_from(csum.as_slice()).ok())
+    }
+
+    // The URI the file is stored under, so that the cache entries are pruned
+    // along with the transfers sending the file
+    fn cache_key(&self) -> Option<String> {
+        match &self.source {
+            FileSource::Path(_) => self.uri().map(String::from),
+            #[cfg(unix)]
+            FileSource::Fd { content_uri, .. } => Some(content_uri.to_string()),
+            FileSource::Memory(_) | FileSource::Stream(_) => None,
+        }
+    }
+}
+
+fn mtime_millis(meta: &reader::Metadata) -> i64 {
+    let mtime = meta
+        .modified()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default();
+    mtime.as_millis() as _
 }
 
-/// This function performs buffering internally. No need to use buffered
-/// readers.
+/// This function performs buffering internally, reading `chunk_size` bytes at
+/// once. No need to use buffered readers.
 pub async fn checksum<F, Fut>(
     reader: impl io::Read,
-    mut progress_cb: Option<F>,
+    chunk_size: usize,
+    progress_cb: Option<F>,
     event_granularity: Option<u64>,
 ) -> io::Result<[u8; 32]>
 where
     F: FnMut(u64) -> Fut + Send + Sync,
     Fut: Future<Output = ()>,
 {
+    let (csum, _) = hash_stream(reader, chunk_size, None, progress_cb, event_granularity).await?;
+    Ok(csum)
+}
+
+/// Calculate the sha2 of the whole stream together with the sha2 of each
+/// consecutive `block_size` bytes long block. The last block can be shorter.
+/// This function performs buffering internally, reading `chunk_size` bytes at
+/// once. No need to use buffered readers.
+pub async fn checksum_with_blocks<F, Fut>(
+    reader: impl io::Read,
+    chunk_size: usize,
+    block_size: u64,
+    progress_cb: Option<F>,
+    event_granularity: Option<u64>,
+) -> io::Result<([u8; 32], Vec<[u8; 32]>)>
+where
+    F: FnMut(u64) -> Fut + Send + Sync,
+    Fut: Future<Output = ()>,
+{
+    hash_stream(
+        reader,
+        chunk_size,
+        Some(block_size),
+        progress_cb,
+        event_granularity,
+    )
+    .await
+}
+
+async fn hash_stream<F, Fut>(
+    reader: impl io::Read,
+    chunk_size: usize,
+    block_size: Option<u64>,
+    mut progress_cb: Option<F>,
+    event_granularity: Option<u64>,
+) -> io::Result<([u8; 32], Vec<[u8; 32]>)>
+where
+    F: FnMut(u64) -> Fut + Send + Sync,
+    Fut: Future<Output = ()>,
+{
+    assert_ne!(block_size, Some(0), "Block size must not be zero");
+
     let mut csum = sha2::Sha256::new();
+    let mut block_csum = sha2::Sha256::new();
+    let mut blocks = Vec::new();
+    let mut block_n: u64 = 0;
 
-    let mut reader = io::BufReader::with_capacity(CHECKSUM_CHUNK_SIZE, reader);
+    let mut reader = io::BufReader::with_capacity(chunk_size, reader);
 
     let mut total_n: u64 = 0;
     let mut announced_bytes: u64 = 0;
@@ -356,6 +682,21 @@ where
 
         csum.write_all(buf)?;
 
+        if let Some(block_size) = block_size {
+            let mut data = buf;
+            while !data.is_empty() {
+                let n = data.len().min((block_size - block_n) as usize);
+                block_csum.update(&data[..n]);
+                block_n += n as u64;
+                data = &data[n..];
+
+                if block_n == block_size {
+                    blocks.push(block_csum.finalize_reset().into());
+                    block_n = 0;
+                }
+            }
+        }
+
         let n = buf.len();
         reader.consume(n);
 
@@ -373,7 +714,11 @@ where
         tokio::task::yield_now().await;
     }
 
-    Ok(csum.finalize().into())
+    if block_n > 0 {
+        blocks.push(block_csum.finalize().into());
+    }
+
+    Ok((csum.finalize().into(), blocks))
 }
 
 fn file_id_from_path(path: impl AsRef<Path>) -> crate::Result<FileId> {
@@ -394,20 +739,48 @@ fn infer_mime(mut reader: impl io::Read) -> io::Result<String> {
 
 #[cfg(test)]
 mod tests {
+    use drop_config::CHECKSUM_CHUNK_SIZE;
 
     const TEST: &[u8] = b"abc";
     const EXPECTED: &[u8] = b"\xba\x78\x16\xbf\x8f\x01\xcf\xea\x41\x41\x40\xde\x5d\xae\x22\x23\xb0\x03\x61\xa3\x96\x17\x7a\x9c\xb4\x10\xff\x61\xf2\x00\x15\xad";
 
     #[tokio::test]
     async fn checksum() {
-        let csum = super::checksum(
-            &mut &TEST[..],
+        // The result does not depend on the chunk size
+        for chunk_size in [1, 2, CHECKSUM_CHUNK_SIZE] {
+            let csum = super::checksum(
+                &mut &TEST[..],
+                chunk_size,
+                None::<fn(u64) -> futures::future::Ready<()>>,
+                None,
+            )
+            .await
+            .unwrap();
+            assert_eq!(csum.as_slice(), EXPECTED);
+        }
+    }
+
+    #[tokio::test]
+    async fn block_checksums() {
+        use sha2::Digest;
+
+        let data: Vec<u8> = (0..=255).cycle().take(1000).collect();
+
+        let (csum, blocks) = super::checksum_with_blocks(
+            &mut &data[..],
+            CHECKSUM_CHUNK_SIZE,
+            300,
             None::<fn(u64) -> futures::future::Ready<()>>,
             None,
         )
         .await
         .unwrap();
-        assert_eq!(csum.as_slice(), EXPECTED);
+
+        assert_eq!(csum[..], sha2::Sha256::digest(&data)[..]);
+        assert_eq!(blocks.len(), 4);
+        for (block, chunk) in blocks.iter().zip(data.chunks(300)) {
+            assert_eq!(block[..], sha2::Sha256::digest(chunk)[..]);
+        }
     }
 
     #[tokio::test]
@@ -420,14 +793,299 @@ mod tests {
 
             let size = TEST.len() as _;
             let file = super::FileToSend::from_path(tmp.path(), size).unwrap();
-            file.checksum(size, None::<fn(u64) -> futures::future::Ready<()>>, None)
-                .await
-                .unwrap()
+            file.checksum(
+                size,
+                CHECKSUM_CHUNK_SIZE,
+                None::<fn(u64) -> futures::future::Ready<()>>,
+                None,
+            )
+            .await
+            .unwrap()
         };
 
         assert_eq!(csum.as_slice(), EXPECTED);
     }
 
+    #[tokio::test]
+    async fn cached_file_checksum() {
+        use std::io::Write;
+
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+        let storage = drop_storage::Storage::new(logger, ":memory:").unwrap();
+
+        let mut tmp = tempfile::NamedTempFile::new().expect("Failed to create tmp file");
+        tmp.write_all(TEST).unwrap();
+
+        let size = TEST.len() as _;
+        let file = super::FileToSend::from_path(tmp.path(), size).unwrap();
+
+        // Nothing is computed until the checksum is requested
+        assert!(file.cached_checksum(&storage, size).await.is_none());
+
+        let csum = file
+            .checksum_cached(&storage, size, CHECKSUM_CHUNK_SIZE)
+            .await
+            .unwrap();
+        assert_eq!(csum.as_slice(), EXPECTED);
+        assert_eq!(file.cached_checksum(&storage, size).await, Some(csum));
+    }
+
+    #[tokio::test]
+    async fn memory_file() {
+        use super::File;
+
+        let bytes = std::sync::Arc::new(TEST.to_vec());
+        let file = super::FileToSend::from_bytes(
+            super::FileSubPath::from("note.txt"),
+            bytes.clone(),
+            Some("text/plain".to_string()),
+        );
+
+        assert_eq!(file.size(), TEST.len() as u64);
+        assert_eq!(file.mime_type(), "text/plain");
+        assert!(file.base_dir().is_none());
+
+        let csum = file
+            .checksum(
+                file.size(),
+                CHECKSUM_CHUNK_SIZE,
+                None::<fn(u64) -> futures::future::Ready<()>>,
+                None,
+            )
+            .await
+            .unwrap();
+        assert_eq!(csum.as_slice(), EXPECTED);
+
+        let mut reader = file.open(1).unwrap();
+        assert_eq!(reader.read_chunk().unwrap(), Some(&TEST[1..]));
+        assert_eq!(reader.read_chunk().unwrap(), None);
+
+        // The MIME type is inferred from the contents when not given
+        let file = super::FileToSend::from_bytes(
+            super::FileSubPath::from("image"),
+            std::sync::Arc::new(b"\x89PNG\r\n\x1a\n".to_vec()),
+            None,
+        );
+        assert_eq!(file.mime_type(), "image/png");
+    }
+
+    #[tokio::test]
+    async fn streamed_file() {
+        use super::File;
+
+        let file = super::FileToSend::from_reader(
+            super::FileSubPath::from("stdin"),
+            std::io::Cursor::new(TEST.to_vec()),
+            None,
+        );
+
+        assert!(file.is_streamed());
+        assert_eq!(file.size(), 0);
+        assert_eq!(file.uri().unwrap().scheme(), super::STREAM_URI_SCHEME);
+        // Neither the type inference nor the checksum consume the stream
+        assert_eq!(file.mime_type(), super::UNKNOWN_STR);
+        assert!(file
+            .checksum(
+                0,
+                CHECKSUM_CHUNK_SIZE,
+                None::<fn(u64) -> futures::future::Ready<()>>,
+                None,
+            )
+            .await
+            .is_err());
+
+        let mut reader = file.open(0).unwrap();
+        assert_eq!(reader.read_chunk().unwrap(), Some(TEST));
+        assert_eq!(reader.read_chunk().unwrap(), None);
+
+        // The stream can be read once
+        assert!(matches!(file.open(0), Err(crate::Error::BadFile)));
+    }
+
+    #[tokio::test]
+    async fn empty_file() {
+        use sha2::Digest;
+
+        use super::File;
+
+        let tmp = tempfile::NamedTempFile::new().expect("Failed to create tmp file");
+        let file = super::FileToSend::from_path(tmp.path(), 0).unwrap();
+        assert_eq!(file.size(), 0);
+        assert_eq!(file.mime_type(), super::UNKNOWN_STR);
+
+        let mut reader = file.open(0).unwrap();
+        assert_eq!(reader.read_chunk().unwrap(), None);
+
+        let empty: [u8; 32] = sha2::Sha256::digest([]).into();
+        let csum = file
+            .checksum(
+                0,
+                CHECKSUM_CHUNK_SIZE,
+                None::<fn(u64) -> futures::future::Ready<()>>,
+                None,
+            )
+            .await
+            .unwrap();
+        assert_eq!(csum, empty);
+
+        assert!(file
+            .block_checksums(0, 300, CHECKSUM_CHUNK_SIZE)
+            .await
+            .unwrap()
+            .is_empty());
+
+        // With nothing to hash there is no progress to report
+        let mut reported = Vec::new();
+        let csum = super::checksum(
+            &[][..],
+            CHECKSUM_CHUNK_SIZE,
+            Some(|bytes| {
+                reported.push(bytes);
+                futures::future::ready(())
+            }),
+            Some(1024),
+        )
+        .await
+        .unwrap();
+        assert_eq!(csum, empty);
+        assert!(reported.is_empty());
+    }
+
+    #[test]
+    fn resolving_mime_type() {
+        use std::{io::Write, sync::Arc};
+
+        use super::File;
+
+        let mut tmp = tempfile::NamedTempFile::new().expect("Failed to create tmp file");
+        tmp.write_all(b"\x89PNG\r\n\x1a\n").unwrap();
+
+        let config = drop_config::DropConfig::default();
+        let expected_uri = url::Url::from_file_path(tmp.path()).unwrap();
+
+        let mut gather = super::GatherCtx::new(&config);
+        gather.with_mime_resolver(Arc::new(move |file: &super::FileToSend| {
+            (file.uri().as_ref() == Some(&expected_uri)).then(|| "image/x-custom".to_string())
+        }));
+        gather.gather_from_path(tmp.path()).unwrap();
+        let files = gather.take();
+        assert_eq!(files[0].mime_type(), "image/x-custom");
+
+        // Falls back to the inference when the resolver has no answer
+        let mut gather = super::GatherCtx::new(&config);
+        gather.with_mime_resolver(Arc::new(|_: &super::FileToSend| None));
+        gather.gather_from_path(tmp.path()).unwrap();
+        let files = gather.take();
+        assert_eq!(files[0].mime_type(), "image/png");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn source_modified_mid_stream() {
+        use std::{
+            io::{Seek, Write},
+            os::unix::prelude::*,
+            time::{Duration, SystemTime},
+        };
+
+        // Two chunks so that the modification happens after the first one is read
+        let data = vec![0xaa; 1024 * 1024 + 16];
+
+        let mut tmp = tempfile::NamedTempFile::new().expect("Failed to create tmp file");
+        tmp.write_all(&data).unwrap();
+
+        let file = super::FileToSend::from_path(tmp.path(), data.len() as _).unwrap();
+        let mut reader = file.open(0).unwrap();
+        let mut snapshot = file.open_snapshot(0).unwrap();
+
+        assert!(reader.read_chunk().unwrap().is_some());
+        let first = snapshot.read_chunk().unwrap().unwrap().len();
+
+        tmp.rewind().unwrap();
+        tmp.write_all(&vec![0xbb; data.len()]).unwrap();
+        // Moved ahead so that the change is noticed regardless of the timestamp
+        // granularity of the filesystem
+        let mtime = (SystemTime::now() + Duration::from_secs(60))
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap();
+        let times = [
+            libc::timespec {
+                tv_sec: 0,
+                tv_nsec: libc::UTIME_OMIT,
+            },
+            libc::timespec {
+                tv_sec: mtime.as_secs() as _,
+                tv_nsec: 0,
+            },
+        ];
+        assert_eq!(
+            unsafe { libc::futimens(tmp.as_file().as_raw_fd(), times.as_ptr()) },
+            0
+        );
+
+        assert!(matches!(
+            reader.read_chunk(),
+            Err(crate::Error::FileModified)
+        ));
+
+        // The snapshot keeps the contents from the moment it was taken
+        assert_eq!(snapshot.read_chunk().unwrap(), Some(&data[first..]));
+        assert_eq!(snapshot.read_chunk().unwrap(), None);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn validating_fds() {
+        use std::os::unix::prelude::*;
+
+        use drop_config::DropConfig;
+
+        use super::{GatherCtx, GatherSrc};
+
+        let tmp = tempfile::NamedTempFile::new().expect("Failed to create tmp file");
+        let dir = tempfile::tempdir().expect("Failed to create tmp dir");
+        let dir_fd = std::fs::File::open(dir.path()).unwrap();
+
+        let file_fd = tmp.as_file().as_raw_fd();
+        let dir_raw = dir_fd.as_raw_fd();
+        let resolver = move |uri: &str| match uri {
+            "content://file" => Some(file_fd),
+            "content://dir" => Some(dir_raw),
+            _ => None,
+        };
+
+        let content = |uri: &str| GatherSrc::ContentUri {
+            uri: uri.parse().unwrap(),
+            subpath: "file".into(),
+            fd: None,
+        };
+        let srcs = [
+            GatherSrc::Path(tmp.path().into()),
+            content("content://file"),
+            content("content://dir"),
+            content("content://revoked"),
+        ];
+
+        let config = DropConfig::default();
+
+        // Without the resolver none of the content URIs can be opened
+        let results = GatherCtx::new(&config).validate_fds(&srcs);
+        assert!(results[0].is_ok());
+        assert!(results[1..].iter().all(|res| res.is_err()));
+
+        let mut gather = GatherCtx::new(&config);
+        gather.with_fd_resover(&resolver);
+        let results = gather.validate_fds(&srcs);
+
+        assert!(results[0].is_ok());
+        assert!(results[1].is_ok());
+        assert!(matches!(
+            results[2],
+            Err(crate::Error::DirectoryNotExpected)
+        ));
+        assert!(matches!(results[3], Err(crate::Error::BadTransferState(_))));
+    }
+
     #[test]
     fn checksum_yielding() {
         use std::{
@@ -452,6 +1110,7 @@ mod tests {
         let mut cursor = io::Cursor::new(&buf);
         let mut future = super::checksum(
             &mut cursor,
+            CHECKSUM_CHUNK_SIZE,
             None::<fn(u64) -> futures::future::Ready<()>>,
             None,
         );
@@ -461,4 +1120,17 @@ mod tests {
         assert!(future.as_mut().poll(&mut cx).is_pending());
         assert!(matches!(future.as_mut().poll(&mut cx), Poll::Ready(Ok(_))));
     }
+
+    #[test]
+    fn subpath_prefix() {
+        use super::FileSubPath;
+
+        let path = FileSubPath::from("folder/photos/a.jpg");
+
+        assert!(path.starts_with(&FileSubPath::from("folder")));
+        assert!(path.starts_with(&FileSubPath::from("folder/photos")));
+        assert!(path.starts_with(&FileSubPath::from("folder/photos/a.jpg")));
+        assert!(!path.starts_with(&FileSubPath::from("folder/photo")));
+        assert!(!path.starts_with(&FileSubPath::from("photos")));
+    }
 }
@@ -49,8 +49,8 @@ impl super::Reader for FileReader {
         self.pos
     }
 
-    fn meta(&mut self) -> crate::Result<fs::Metadata> {
-        let meta = self.file.metadata()?;
+    fn meta(&mut self) -> crate::Result<super::Metadata> {
+        let meta = self.file.metadata()?.try_into()?;
         Ok(meta)
     }
 }
@@ -0,0 +1,48 @@
+use std::{io, sync::Arc, time::SystemTime};
+
+// Reads the file contents kept in memory
+pub struct FileReader {
+    bytes: Arc<Vec<u8>>,
+    pos: u64,
+}
+
+impl FileReader {
+    pub fn new(bytes: Arc<Vec<u8>>) -> Self {
+        Self { bytes, pos: 0 }
+    }
+}
+
+impl io::Read for FileReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let start = self.bytes.len().min(self.pos as usize);
+        let n = (&self.bytes[start..]).read(buf)?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl io::Seek for FileReader {
+    fn seek(&mut self, pos: io::SeekFrom) -> io::Result<u64> {
+        self.pos = match pos {
+            io::SeekFrom::Start(off) => off,
+            io::SeekFrom::End(off) => (self.bytes.len() as u64).wrapping_add(off as _),
+            io::SeekFrom::Current(off) => self.pos.wrapping_add(off as _),
+        };
+
+        Ok(self.pos)
+    }
+}
+
+impl super::Reader for FileReader {
+    fn bytes_read(&self) -> u64 {
+        self.pos
+    }
+
+    fn meta(&mut self) -> crate::Result<super::Metadata> {
+        // The buffer is immutable so the modification time never changes
+        Ok(super::Metadata {
+            len: self.bytes.len() as _,
+            modified: SystemTime::UNIX_EPOCH,
+        })
+    }
+}
@@ -1,9 +1,12 @@
 #[cfg(unix)]
 mod fd;
 
+mod memory;
 mod path;
+mod snapshot;
+mod stream;
 
-use std::{fs, io};
+use std::{fs, io, time::SystemTime};
 
 use crate::Error;
 
@@ -13,7 +16,35 @@ const CHUNK_SIZE: usize = 1024 * 1024;
 pub struct FileReader {
     inner: Box<dyn Reader>,
     buffer: Box<[u8]>,
-    meta: fs::Metadata,
+    meta: Metadata,
+}
+
+/// The part of the file metadata used for detecting modifications
+#[derive(Clone, Copy)]
+pub(crate) struct Metadata {
+    len: u64,
+    modified: SystemTime,
+}
+
+impl Metadata {
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn modified(&self) -> SystemTime {
+        self.modified
+    }
+}
+
+impl TryFrom<fs::Metadata> for Metadata {
+    type Error = io::Error;
+
+    fn try_from(meta: fs::Metadata) -> io::Result<Self> {
+        Ok(Self {
+            len: meta.len(),
+            modified: meta.modified()?,
+        })
+    }
 }
 
 pub(super) fn open(source: &super::FileSource) -> crate::Result<Box<dyn Reader>> {
@@ -35,13 +66,23 @@ pub(super) fn open(source: &super::FileSource) -> crate::Result<Box<dyn Reader>>
 
             Box::new(unsafe { fd::FileReader::new(fd) })
         }
+        super::FileSource::Memory(bytes) => Box::new(memory::FileReader::new(bytes.clone())),
+        super::FileSource::Stream(stream) => Box::new(stream::FileReader::new(stream)?),
     };
 
     Ok(reader)
 }
 
+/// Copies the contents of the source into a temporary file and opens it, so
+/// that the later modifications of the source are not visible to the reader
+pub(super) fn open_snapshot(source: &super::FileSource) -> crate::Result<Box<dyn Reader>> {
+    let mut source = open(source)?;
+    let reader = snapshot::FileReader::new(source.as_mut())?;
+    Ok(Box::new(reader))
+}
+
 impl FileReader {
-    pub(super) fn new(reader: Box<dyn Reader>, meta: fs::Metadata) -> crate::Result<Self> {
+    pub(super) fn new(reader: Box<dyn Reader>, meta: Metadata) -> crate::Result<Self> {
         Ok(Self {
             inner: reader,
             buffer: vec![0u8; CHUNK_SIZE].into_boxed_slice(),
@@ -57,18 +98,20 @@ impl FileReader {
         }
 
         let total_read = self.inner.bytes_read();
+        // The streamed files end wherever the stream does
+        let sized = self.inner.is_sized();
 
         if n == 0 {
             // File size might have been reduced while in the loop which
             // will result in an error
-            if total_read != self.meta.len() {
+            if sized && total_read != self.meta.len() {
                 return Err(Error::MismatchedSize);
             } else {
                 return Ok(None);
             }
         }
 
-        if total_read > self.meta.len() {
+        if sized && total_read > self.meta.len() {
             return Err(Error::MismatchedSize);
         }
 
@@ -77,8 +120,8 @@ impl FileReader {
     }
 
     fn is_mtime_ok(&mut self) -> crate::Result<bool> {
-        let mtime_orig = self.meta.modified()?;
-        let mtime_act = self.inner.meta()?.modified()?;
+        let mtime_orig = self.meta.modified();
+        let mtime_act = self.inner.meta()?.modified();
 
         Ok(mtime_orig == mtime_act)
     }
@@ -86,5 +129,10 @@ impl FileReader {
 
 pub(super) trait Reader: io::Read + io::Seek + Send + Sync {
     fn bytes_read(&self) -> u64;
-    fn meta(&mut self) -> crate::Result<fs::Metadata>;
+    fn meta(&mut self) -> crate::Result<Metadata>;
+
+    /// Whether the length reported in the metadata is known up front
+    fn is_sized(&self) -> bool {
+        true
+    }
 }
@@ -0,0 +1,78 @@
+use std::{
+    fs::{self, OpenOptions},
+    io::{self, Seek},
+    path::PathBuf,
+};
+
+// Reads a copy of the file taken upon creation. The copy is removed once the
+// reader is dropped
+pub struct FileReader {
+    file: Option<fs::File>,
+    path: PathBuf,
+    pos: u64,
+}
+
+impl FileReader {
+    pub fn new(source: &mut dyn super::Reader) -> io::Result<Self> {
+        let path = std::env::temp_dir().join(format!("drop-snapshot-{}", uuid::Uuid::new_v4()));
+
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(&path)?;
+
+        let mut reader = Self {
+            file: None,
+            path,
+            pos: 0,
+        };
+
+        source.seek(io::SeekFrom::Start(0))?;
+        io::copy(source, &mut file)?;
+        file.rewind()?;
+
+        reader.file = Some(file);
+        Ok(reader)
+    }
+
+    fn file(&mut self) -> io::Result<&mut fs::File> {
+        self.file
+            .as_mut()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::Other, "Snapshot file is closed"))
+    }
+}
+
+impl Drop for FileReader {
+    fn drop(&mut self) {
+        // The file needs to be closed first in order to be removed on Windows
+        drop(self.file.take());
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+impl io::Read for FileReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.file()?.read(buf)?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl io::Seek for FileReader {
+    fn seek(&mut self, pos: io::SeekFrom) -> io::Result<u64> {
+        self.pos = self.file()?.seek(pos)?;
+        Ok(self.pos)
+    }
+}
+
+impl super::Reader for FileReader {
+    fn bytes_read(&self) -> u64 {
+        self.pos
+    }
+
+    fn meta(&mut self) -> crate::Result<super::Metadata> {
+        let meta = self.file()?.metadata()?.try_into()?;
+        Ok(meta)
+    }
+}
@@ -0,0 +1,67 @@
+use std::{
+    io,
+    sync::{Arc, Mutex},
+    time::SystemTime,
+};
+
+// Reads the stream of unknown length, e.g. a pipe. The stream can be read only
+// once and from its beginning
+pub struct FileReader {
+    stream: Mutex<Box<dyn io::Read + Send>>,
+    pos: u64,
+}
+
+impl FileReader {
+    /// Takes the stream out of the source. Fails if it was taken already
+    pub fn new(source: &Arc<Mutex<Option<Box<dyn io::Read + Send>>>>) -> crate::Result<Self> {
+        let stream = source
+            .lock()
+            .expect("Poisoned lock")
+            .take()
+            .ok_or(crate::Error::BadFile)?;
+
+        Ok(Self {
+            stream: Mutex::new(stream),
+            pos: 0,
+        })
+    }
+}
+
+impl io::Read for FileReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.stream.get_mut().expect("Poisoned lock").read(buf)?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl io::Seek for FileReader {
+    fn seek(&mut self, pos: io::SeekFrom) -> io::Result<u64> {
+        match pos {
+            io::SeekFrom::Start(off) if off == self.pos => Ok(self.pos),
+            io::SeekFrom::Current(0) => Ok(self.pos),
+            _ => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "Cannot seek the streamed file",
+            )),
+        }
+    }
+}
+
+impl super::Reader for FileReader {
+    fn bytes_read(&self) -> u64 {
+        self.pos
+    }
+
+    fn meta(&mut self) -> crate::Result<super::Metadata> {
+        // The length is not known until the end of the stream
+        Ok(super::Metadata {
+            len: 0,
+            modified: SystemTime::UNIX_EPOCH,
+        })
+    }
+
+    fn is_sized(&self) -> bool {
+        false
+    }
+}
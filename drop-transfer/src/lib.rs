@@ -1,11 +1,15 @@
 pub mod auth;
 mod check;
+mod clock;
 mod error;
 pub mod event;
+mod expire;
 pub mod file;
 mod manager;
+mod metrics;
 mod protocol;
 mod quarantine;
+mod rate_limit;
 pub mod service;
 mod storage_dispatch;
 mod tasks;
@@ -14,13 +18,17 @@ pub mod utils;
 mod ws;
 
 #[cfg(unix)]
-pub use crate::file::FdResolver;
+pub use crate::file::{ContentUriRefresher, FdResolver};
 pub(crate) use crate::manager::TransferManager;
 pub use crate::{
-    error::Error,
-    event::Event,
+    clock::{Clock, SystemClock},
+    error::{Error, MismatchDetail},
+    event::{ChecksumPhase, Event},
     file::{File, FileId, FileToRecv, FileToSend},
-    service::Service,
+    service::{
+        ConnectionMetrics, DestinationResolver, PostAction, PostCompletionHook, Service,
+        ServiceMetrics, StallReason, TransferStatus, VerifyResult,
+    },
     storage_dispatch::StorageDispatch,
     transfer::{IncomingTransfer, OutgoingTransfer, Transfer, TransferData},
 };
@@ -3,27 +3,31 @@ use std::{
     io,
     path::{Path, PathBuf},
     sync::Arc,
+    time::{Duration, Instant, SystemTime},
 };
 
 use anyhow::Context;
 use drop_config::DropConfig;
 use drop_storage::{sync, types::OutgoingFileToRetry, Storage};
 use slog::{debug, error, info, trace, warn, Logger};
-use tokio::sync::{mpsc::UnboundedSender, Mutex};
+use tokio::sync::{mpsc::UnboundedSender, Mutex, Notify};
 use tokio_util::sync::CancellationToken;
 use uuid::Uuid;
 
 use crate::{
     check,
-    file::FileSubPath,
-    service::State,
+    error::MismatchDetail,
+    file::{FileSubPath, MEMORY_URI_SCHEME, STREAM_URI_SCHEME},
+    rate_limit::RateLimit,
+    service::{ConnectionMetrics, StallReason, State, TransferStatus},
     tasks::AliveGuard,
     transfer::{IncomingTransfer, OutgoingTransfer},
+    utils::{Hidden, Peer, RetryDeadline},
     ws::{
         self,
         client::ClientReq,
         server::{FileXferTask, ServerReq},
-        EventTxFactory, FileEventTx, IncomingFileEventTx, IncomingTransferEventTx,
+        EventTxFactory, FileActivity, FileEventTx, IncomingFileEventTx, IncomingTransferEventTx,
         OutgoingFileEventTx, OutgoingTransferEventTx, TransferEventTx,
     },
     File, FileId, FileToRecv, FileToSend, Transfer,
@@ -32,6 +36,45 @@ use crate::{
 pub struct CloseResult<T: Transfer> {
     pub file_events: Vec<Arc<FileEventTx<T>>>,
     pub xfer_events: Arc<TransferEventTx<T>>,
+    pub stats: TransferStats,
+}
+
+/// Outcome of the transfer files at the moment the transfer terminates
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct TransferStats {
+    pub succeeded: u32,
+    pub failed: u32,
+    pub rejected: u32,
+    /// Total size of the successfully transferred files
+    pub total_bytes: u64,
+}
+
+impl TransferStats {
+    fn new<'a, T: Transfer>(
+        xfer: &T,
+        terminal: impl IntoIterator<Item = (&'a FileId, FileTerminalState)>,
+    ) -> Self {
+        let mut stats = Self::default();
+
+        for (file_id, state) in terminal {
+            match state {
+                FileTerminalState::Completed => {
+                    stats.succeeded += 1;
+                    stats.total_bytes += xfer.files().get(file_id).map_or(0, |file| file.size());
+                }
+                FileTerminalState::Failed => stats.failed += 1,
+                FileTerminalState::Rejected => stats.rejected += 1,
+            }
+        }
+
+        stats
+    }
+}
+
+pub struct RejectManyResult<T: Transfer> {
+    pub file_events: Vec<Arc<FileEventTx<T>>>,
+    // Set when the rejection terminated the last files and closed the transfer
+    pub closed: Option<(Arc<TransferEventTx<T>>, TransferStats)>,
 }
 
 pub struct FinishResult<T: Transfer> {
@@ -48,7 +91,11 @@ pub enum FileTerminalState {
 
 enum IncomingLocalFileState {
     Idle,
-    InFlight { path: PathBuf },
+    InFlight {
+        path: PathBuf,
+        // The filename chosen by the user in place of the sender's one
+        rename_to: Option<String>,
+    },
     Terminal(FileTerminalState),
 }
 
@@ -65,6 +112,20 @@ pub struct IncomingState {
     file_sync: HashMap<FileId, IncomingLocalFileState>,
     file_events: HashMap<FileId, Arc<IncomingFileEventTx>>,
     pub xfer_events: Arc<IncomingTransferEventTx>,
+    metrics: ConnectionMetrics,
+    // A session was established since the transfer was loaded, the next one
+    // is a reconnect
+    was_connected: bool,
+    // Cancelled once any of the files is downloaded or rejected, stops the
+    // offer expiry
+    offer_expiry: CancellationToken,
+    // Wakes the connection loop to drop the current connection
+    conn_drop: Arc<Notify>,
+    rate_limit: Arc<RateLimit>,
+    // Bytes taken by the streamed files, shared by all of them
+    stream_budget: Arc<StreamBudget>,
+    // Restored transfers stay unresumed until their jobs get spawned
+    resumed: bool,
 }
 
 pub struct OutgoingState {
@@ -74,6 +135,22 @@ pub struct OutgoingState {
     file_sync: HashMap<FileId, OutgoingLocalFileState>,
     file_events: HashMap<FileId, Arc<OutgoingFileEventTx>>,
     pub xfer_events: Arc<OutgoingTransferEventTx>,
+    // Stops the client job (connection and retries) of this transfer only
+    client_stop: CancellationToken,
+    // Wakes the connection loop to drop the current connection
+    conn_drop: Arc<Notify>,
+    metrics: ConnectionMetrics,
+    // A session was established since the transfer was loaded, the next one
+    // is a reconnect
+    was_connected: bool,
+    // Number of the consecutive upload attempts of the file without a single
+    // chunk sent
+    file_attempts: HashMap<FileId, u32>,
+    rate_limit: Arc<RateLimit>,
+    // The end of the backoff before the next connection attempt
+    retry_deadline: Arc<RetryDeadline>,
+    // Restored transfers stay unresumed until their jobs get spawned
+    resumed: bool,
 }
 
 /// Transfer manager is responsible for keeping track of all ongoing or pending
@@ -91,6 +168,35 @@ pub struct DirMapping {
     mappings: HashMap<PathBuf, String>,
 }
 
+/// The part of the transfer size limit taken by the streamed files. Their size
+/// is unknown when the transfer is accepted so they all draw from whatever the
+/// limit leaves after the other files
+#[derive(Default)]
+pub(crate) struct StreamBudget {
+    received: std::sync::Mutex<HashMap<FileId, u64>>,
+}
+
+impl StreamBudget {
+    /// Records the streamed file has `received` bytes so far. Fails without
+    /// recording if all the streamed files together would exceed the `limit`
+    pub fn take(&self, file_id: &FileId, received: u64, limit: u64) -> bool {
+        let mut lock = self.received.lock().expect("Poisoned lock");
+
+        let others: u64 = lock
+            .iter()
+            .filter(|(id, _)| *id != file_id)
+            .map(|(_, bytes)| *bytes)
+            .sum();
+
+        if others.saturating_add(received) > limit {
+            return false;
+        }
+
+        lock.insert(file_id.clone(), received);
+        true
+    }
+}
+
 impl TransferManager {
     pub fn new(storage: Arc<Storage>, event_factory: EventTxFactory, logger: Logger) -> Self {
         Self {
@@ -102,19 +208,56 @@ impl TransferManager {
         }
     }
 
-    /// Returns `Some()` if the transfer is new one
+    /// Returns `Some()` if the transfer is new one. New transfers are refused
+    /// when the peer already has `max_per_peer` transfers, zero meaning no
+    /// limit
     pub async fn register_incoming(
         &self,
         xfer: Arc<IncomingTransfer>,
         conn: UnboundedSender<ServerReq>,
+        max_per_peer: usize,
     ) -> anyhow::Result<Option<Arc<IncomingTransferEventTx>>> {
         let mut lock = self.incoming.lock().await;
 
+        if max_per_peer > 0 && !lock.contains_key(&xfer.id()) {
+            let peer = xfer.peer();
+            let count = lock.values().filter(|s| s.xfer.peer() == peer).count();
+
+            if count >= max_per_peer {
+                warn!(
+                    self.logger,
+                    "Rejecting transfer {}: peer {} has {count} transfers already",
+                    xfer.id(),
+                    Peer(peer)
+                );
+                if let Err(e) = conn.send(ServerReq::Close) {
+                    warn!(self.logger, "Failed to send close request: {}", e);
+                }
+                return Ok(None);
+            }
+        }
+
         match lock.entry(xfer.id()) {
             Entry::Occupied(mut occ) => {
                 let state = occ.get_mut();
 
-                ensure_resume_matches_existing_transfer(&*xfer, &*state.xfer)?;
+                // Different peer using the same ID is either a collision or an
+                // attempt to take over someone else's transfer
+                if xfer.peer() != state.xfer.peer() {
+                    let err = crate::Error::TransferUuidConflict {
+                        existing: state.xfer.peer(),
+                        current: xfer.peer(),
+                    };
+                    warn!(self.logger, "Rejecting transfer {}: {err}", xfer.id());
+                    return Err(err.into());
+                }
+
+                let details = resume_mismatches(&*xfer, &*state.xfer);
+                if !details.is_empty() {
+                    let err = crate::Error::TransferMismatch { details };
+                    warn!(self.logger, "Rejecting resume of {}: {err}", xfer.id());
+                    return Err(err.into());
+                }
 
                 info!(
                     self.logger,
@@ -131,6 +274,10 @@ impl TransferManager {
 
                 info!(self.logger, "Issuing pending requests for: {}", xfer.id());
                 state.issue_pending_requests(&conn, &self.logger);
+                if state.was_connected {
+                    state.metrics.reconnects += 1;
+                }
+                state.was_connected = true;
 
                 match state.xfer_sync {
                     sync::TransferState::Canceled => {
@@ -183,9 +330,23 @@ impl TransferManager {
                             )
                         })
                         .collect(),
-                    xfer_events: Arc::new(self.event_factory.transfer(xfer, false)),
+                    xfer_events: Arc::new(self.event_factory.transfer(
+                        xfer,
+                        false,
+                        SystemTime::now(),
+                    )),
+                    metrics: ConnectionMetrics::default(),
+                    was_connected: true,
+                    offer_expiry: CancellationToken::new(),
+                    conn_drop: Arc::default(),
+                    rate_limit: Arc::default(),
+                    stream_budget: Arc::default(),
+                    resumed: true,
                 });
 
+                // Emit while holding the lock so that the event precedes any file events
+                state.xfer_events.created().await;
+
                 Ok(Some(state.xfer_events.clone()))
             }
         }
@@ -214,6 +375,11 @@ impl TransferManager {
             state.xfer_sync = sync::TransferState::Active;
         }
 
+        if state.was_connected {
+            state.metrics.reconnects += 1;
+        }
+        state.was_connected = true;
+
         match state.xfer_sync {
             sync::TransferState::Canceled => {
                 debug!(self.logger, "Outgoing transfer is locally cancelled");
@@ -251,6 +417,9 @@ impl TransferManager {
             }
             Entry::Vacant(entry) => {
                 self.storage.insert_transfer(&xfer.storage_info()).await;
+                if let Some(zone) = xfer.zone() {
+                    self.storage.set_transfer_zone(xfer.id(), zone).await;
+                }
 
                 entry.insert(OutgoingState {
                     xfer: xfer.clone(),
@@ -271,11 +440,26 @@ impl TransferManager {
                             )
                         })
                         .collect(),
-                    xfer_events: Arc::new(self.event_factory.transfer(xfer, false)),
+                    xfer_events: Arc::new(self.event_factory.transfer(
+                        xfer,
+                        false,
+                        SystemTime::now(),
+                    )),
+                    client_stop: CancellationToken::new(),
+                    conn_drop: Arc::default(),
+                    metrics: ConnectionMetrics::default(),
+                    was_connected: false,
+                    file_attempts: HashMap::new(),
+                    rate_limit: Arc::default(),
+                    retry_deadline: Arc::default(),
+                    resumed: true,
                 })
             }
         };
 
+        // Emit while holding the lock so that the event precedes any file events
+        state.xfer_events.created().await;
+
         Ok(state.xfer_events.clone())
     }
 
@@ -311,6 +495,45 @@ impl TransferManager {
         Ok(state.file_events(file_id)?.clone())
     }
 
+    /// Registers the upload attempt of the file. Fails with
+    /// `RetriesExceeded` if the previous attempts, more than `max_retries` of
+    /// them, did not send a single chunk
+    pub async fn outgoing_upload_attempt(
+        &self,
+        transfer_id: Uuid,
+        file_id: &FileId,
+        max_retries: Option<u32>,
+    ) -> crate::Result<()> {
+        let mut lock = self.outgoing.lock().await;
+
+        let state = lock
+            .get_mut(&transfer_id)
+            .ok_or(crate::Error::BadTransfer)?;
+
+        let attempts = state.file_attempts.entry(file_id.clone()).or_default();
+        if let Some(max) = max_retries {
+            if *attempts > max {
+                warn!(
+                    self.logger,
+                    "Abandoning file {file_id} of {transfer_id} after {attempts} failed attempts"
+                );
+                return Err(crate::Error::RetriesExceeded(*attempts));
+            }
+        }
+
+        *attempts += 1;
+        Ok(())
+    }
+
+    /// Resets the upload attempts counter once the file makes progress
+    pub async fn outgoing_upload_progress(&self, transfer_id: Uuid, file_id: &FileId) {
+        let mut lock = self.outgoing.lock().await;
+
+        if let Some(state) = lock.get_mut(&transfer_id) {
+            state.file_attempts.remove(file_id);
+        }
+    }
+
     pub async fn outgoing_rejection_post(
         &self,
         transfer_id: Uuid,
@@ -389,6 +612,51 @@ impl TransferManager {
         Ok(res)
     }
 
+    /// Terminates the files of the outgoing transfer the peer left out of its
+    /// selection as rejected, persisting them under a single storage
+    /// transaction. The files terminated already are skipped. Returns the
+    /// events of the rejected files
+    pub async fn outgoing_selection_recv(
+        &self,
+        transfer_id: Uuid,
+        selected: &[FileId],
+    ) -> crate::Result<Vec<Arc<OutgoingFileEventTx>>> {
+        let mut lock = self.outgoing.lock().await;
+
+        let state = lock
+            .get_mut(&transfer_id)
+            .ok_or(crate::Error::BadTransfer)?;
+
+        let mut rejected = Vec::new();
+        for (file_id, sync) in state.file_sync.iter_mut() {
+            if !selected.contains(file_id)
+                && sync.try_terminate(FileTerminalState::Rejected).is_ok()
+            {
+                rejected.push(file_id);
+            }
+        }
+
+        if rejected.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let ids: Vec<_> = rejected.iter().map(|file_id| file_id.as_ref()).collect();
+        self.storage
+            .update_outgoing_files_sync_states(transfer_id, &ids, sync::FileState::Terminal)
+            .await;
+
+        rejected
+            .into_iter()
+            .map(|file_id| {
+                state
+                    .file_events
+                    .get(file_id)
+                    .cloned()
+                    .ok_or(crate::Error::BadFileId)
+            })
+            .collect()
+    }
+
     pub async fn incoming_rejection_post(
         &self,
         transfer_id: Uuid,
@@ -404,6 +672,7 @@ impl TransferManager {
 
         let sync = state.file_sync_mut(file_id)?;
         sync.try_terminate_local(FileTerminalState::Rejected)?;
+        state.offer_expiry.cancel();
 
         self.storage
             .update_incoming_file_sync_states(
@@ -590,216 +859,1067 @@ impl TransferManager {
         let res = CloseResult {
             file_events: state.file_events.values().cloned().collect(),
             xfer_events: state.xfer_events.clone(),
+            stats: state.stats(),
         };
 
         Ok(res)
     }
 
-    pub async fn outgoing_issue_close(
+    /// Rejects all of the not yet terminated files of the incoming transfer and
+    /// closes it, passing the reason to the peer. Returns the events of the
+    /// files rejected by this call
+    pub async fn incoming_reject_all(
         &self,
         transfer_id: Uuid,
-    ) -> crate::Result<CloseResult<OutgoingTransfer>> {
-        let mut lock = self.outgoing.lock().await;
+        reason: &str,
+    ) -> crate::Result<CloseResult<IncomingTransfer>> {
+        let mut lock = self.incoming.lock().await;
 
         let state = lock
             .get_mut(&transfer_id)
             .ok_or(crate::Error::BadTransfer)?;
 
-        match state.xfer_sync {
-            // If it's new, then suppress transfer synchronization by deleting the state
-            sync::TransferState::New => {
-                self.storage.transfer_sync_clear(transfer_id).await;
+        state.ensure_not_cancelled()?;
 
-                let res = CloseResult {
-                    file_events: state.file_events.values().cloned().collect(),
-                    xfer_events: state.xfer_events.clone(),
-                };
+        Ok(self.reject_all_incoming_files(state, Some(reason)).await)
+    }
 
-                lock.remove(&transfer_id);
+    /// Rejects the listed files of the incoming transfer under a single lock
+    /// and storage transaction. Files terminated already are skipped. Once no
+    /// file of the transfer is left unterminated, the transfer is closed and
+    /// the result carries the transfer events and stats
+    pub async fn incoming_reject_many(
+        &self,
+        transfer_id: Uuid,
+        file_ids: &[FileId],
+    ) -> crate::Result<RejectManyResult<IncomingTransfer>> {
+        let mut lock = self.incoming.lock().await;
 
-                Ok(res)
-            }
-            sync::TransferState::Active => {
-                self.storage
-                    .update_transfer_sync_states(
-                        transfer_id,
-                        drop_storage::sync::TransferState::Canceled,
-                    )
-                    .await;
-                state.xfer_sync = sync::TransferState::Canceled;
+        let state = lock
+            .get_mut(&transfer_id)
+            .ok_or(crate::Error::BadTransfer)?;
 
-                if let Some(conn) = state.conn.take() {
-                    debug!(self.logger, "Pushing incoming  close request");
-                    if let Err(e) = conn.send(ClientReq::Close) {
-                        warn!(self.logger, "Failed to send close request: {}", e);
-                    }
-                }
+        state.ensure_not_cancelled()?;
 
-                Ok(CloseResult {
-                    file_events: state.file_events.values().cloned().collect(),
-                    xfer_events: state.xfer_events.clone(),
-                })
+        // Validate the whole batch first so that it is rejected all or nothing
+        for file_id in file_ids {
+            state.file_sync_mut(file_id)?;
+        }
+
+        let mut rejected = Vec::new();
+        for file_id in file_ids {
+            let sync = state.file_sync_mut(file_id)?;
+            if sync
+                .try_terminate_local(FileTerminalState::Rejected)
+                .is_ok()
+            {
+                rejected.push(file_id);
             }
-            sync::TransferState::Canceled => Err(crate::Error::BadTransfer),
         }
-    }
 
-    pub async fn outgoing_ensure_file_not_terminated(
-        &self,
-        transfer_id: Uuid,
-        file_id: &FileId,
-    ) -> crate::Result<()> {
-        let lock = self.outgoing.lock().await;
-        let state = lock.get(&transfer_id).ok_or(crate::Error::BadTransfer)?;
-        let state = state
-            .file_sync
-            .get(file_id)
-            .ok_or(crate::Error::BadFileId)?;
+        if rejected.is_empty() {
+            return Ok(RejectManyResult {
+                file_events: Vec::new(),
+                closed: None,
+            });
+        }
 
-        state.ensure_not_terminated()
-    }
+        state.offer_expiry.cancel();
 
-    pub async fn outgoing_remove(&self, transfer_id: Uuid) -> Option<OutgoingState> {
-        debug!(self.logger, "Removing outgoing transfer: {transfer_id}");
-        let mut lock = self.outgoing.lock().await;
+        let ids: Vec<_> = rejected.iter().map(|file_id| file_id.as_ref()).collect();
+        self.storage.stop_incoming_files(transfer_id, &ids).await;
 
-        let state = lock.remove(&transfer_id)?;
-        self.storage.transfer_sync_clear(transfer_id).await;
-        Some(state)
+        if let Some(conn) = &state.conn {
+            for file_id in &rejected {
+                debug!(
+                    self.logger,
+                    "Pushing incoming rejection request: file_id {file_id}"
+                );
+
+                if let Err(e) = conn.send(ServerReq::Reject {
+                    file: (*file_id).clone(),
+                }) {
+                    warn!(self.logger, "Failed to send reject request: {}", e);
+                };
+            }
+        }
+
+        self.finish_incoming_rejection(state, &rejected).await
     }
 
-    pub async fn outgoing_event_tx(
+    /// Rejects the files of the incoming transfer left out of the selection,
+    /// skipping the ones for which the download was issued already. The
+    /// rejected files are persisted under a single storage transaction and
+    /// the peers able to receive the selection get it in one message. Once no
+    /// file of the transfer is left unterminated, the transfer is closed and
+    /// the result carries the transfer events and stats
+    pub async fn incoming_reject_unselected(
         &self,
         transfer_id: Uuid,
-    ) -> Option<Arc<OutgoingTransferEventTx>> {
-        let lock = self.outgoing.lock().await;
-        lock.get(&transfer_id)
-            .map(|state| state.xfer_events.clone())
-    }
-
-    pub async fn incoming_disconnect(&self, transfer_id: Uuid) -> crate::Result<()> {
+        selected: &[FileId],
+    ) -> crate::Result<RejectManyResult<IncomingTransfer>> {
         let mut lock = self.incoming.lock().await;
-        let _ = lock
-            .get_mut(&transfer_id)
-            .ok_or(crate::Error::BadTransfer)?
-            .conn
-            .take();
-        Ok(())
-    }
 
-    pub async fn outgoing_disconnect(&self, transfer_id: Uuid) -> crate::Result<()> {
-        trace!(self.logger, "outgoing_disconnect: {}", transfer_id);
-        let mut lock = self.outgoing.lock().await;
-        let _ = lock
+        let state = lock
             .get_mut(&transfer_id)
-            .ok_or(crate::Error::BadTransfer)?
-            .conn
-            .take();
-        Ok(())
-    }
-}
+            .ok_or(crate::Error::BadTransfer)?;
 
-impl OutgoingState {
-    fn issue_pending_requests(&self, conn: &UnboundedSender<ClientReq>, logger: &Logger) {
-        let iter = self
-            .file_sync
-            .iter()
-            .filter_map(|(file_id, state)| match state {
-                OutgoingLocalFileState::Terminal(FileTerminalState::Rejected) => {
-                    info!(logger, "Rejecting file: {file_id}",);
+        state.ensure_not_cancelled()?;
 
-                    Some(ClientReq::Reject {
-                        file: file_id.clone(),
-                    })
-                }
-                OutgoingLocalFileState::Terminal(FileTerminalState::Failed) => {
-                    info!(logger, "Failing file: {file_id}",);
+        let mut rejected = Vec::new();
+        for (file_id, sync) in state.file_sync.iter_mut() {
+            if !selected.contains(file_id) && matches!(sync, IncomingLocalFileState::Idle) {
+                sync.try_terminate_local(FileTerminalState::Rejected)?;
+                rejected.push(file_id.clone());
+            }
+        }
 
-                    Some(ClientReq::Fail {
-                        file: file_id.clone(),
-                    })
-                }
-                _ => None,
+        if rejected.is_empty() {
+            return Ok(RejectManyResult {
+                file_events: Vec::new(),
+                closed: None,
             });
-
-        for req in iter {
-            if let Err(e) = conn.send(req) {
-                warn!(logger, "Failed to send request: {}", e);
-            }
         }
-    }
 
-    fn ensure_not_cancelled(&self) -> crate::Result<()> {
-        if let sync::TransferState::Canceled = self.xfer_sync {
-            return Err(crate::Error::BadTransfer);
+        state.offer_expiry.cancel();
+
+        let ids: Vec<_> = rejected.iter().map(|file_id| file_id.as_ref()).collect();
+        self.storage.stop_incoming_files(transfer_id, &ids).await;
+
+        if let Some(conn) = &state.conn {
+            debug!(
+                self.logger,
+                "Pushing incoming selection request, rejected files: {rejected:?}"
+            );
+
+            if let Err(e) = conn.send(ServerReq::Select {
+                rejected: rejected.clone(),
+            }) {
+                warn!(self.logger, "Failed to send select request: {}", e);
+            };
         }
-        Ok(())
-    }
 
-    fn file_events(&self, file_id: &FileId) -> crate::Result<&Arc<OutgoingFileEventTx>> {
-        self.file_events.get(file_id).ok_or(crate::Error::BadFileId)
+        let rejected: Vec<_> = rejected.iter().collect();
+        self.finish_incoming_rejection(state, &rejected).await
     }
 
-    fn file_sync_mut(&mut self, file_id: &FileId) -> crate::Result<&mut OutgoingLocalFileState> {
-        self.file_sync
-            .get_mut(file_id)
-            .ok_or(crate::Error::BadFileId)
-    }
-}
+    async fn finish_incoming_rejection(
+        &self,
+        state: &mut IncomingState,
+        rejected: &[&FileId],
+    ) -> crate::Result<RejectManyResult<IncomingTransfer>> {
+        let file_events = rejected
+            .iter()
+            .map(|file_id| state.file_events(file_id).cloned())
+            .collect::<crate::Result<_>>()?;
 
-impl IncomingState {
-    /// Returs `true` when the new download can be started and `false` in case
-    /// the downaload is already happening
-    pub fn validate_for_download(&self, file_id: &FileId) -> crate::Result<bool> {
-        self.ensure_not_cancelled()?;
+        let all_terminated = state
+            .file_sync
+            .values()
+            .all(|sync| matches!(sync, IncomingLocalFileState::Terminal(_)));
 
-        let state = self.file_sync.get(file_id).ok_or(crate::Error::BadFileId)?;
-        let start = match state {
-            IncomingLocalFileState::Idle => true,
-            IncomingLocalFileState::InFlight { .. } => false,
-            IncomingLocalFileState::Terminal(term) => {
-                return Err(crate::Error::FileStateMismatch(*term));
-            }
+        let closed = if all_terminated {
+            self.close_incoming(state, None).await;
+            Some((state.xfer_events.clone(), state.stats()))
+        } else {
+            None
         };
 
-        Ok(start)
+        Ok(RejectManyResult {
+            file_events,
+            closed,
+        })
+    }
+
+    /// Returns the token cancelled once any of the files of the incoming
+    /// transfer is downloaded or rejected
+    pub async fn incoming_offer_expiry(&self, transfer_id: Uuid) -> Option<CancellationToken> {
+        let lock = self.incoming.lock().await;
+        lock.get(&transfer_id)
+            .map(|state| state.offer_expiry.clone())
+    }
+
+    /// Rejects the whole incoming transfer and removes it if none of its files
+    /// were downloaded or rejected yet. Returns `None` if the transfer is gone
+    /// or some file was acted upon already
+    pub async fn incoming_expire(
+        &self,
+        transfer_id: Uuid,
+    ) -> Option<CloseResult<IncomingTransfer>> {
+        let mut lock = self.incoming.lock().await;
+
+        let state = lock.get_mut(&transfer_id)?;
+
+        if state.ensure_not_cancelled().is_err()
+            || state.offer_expiry.is_cancelled()
+            || !state
+                .file_sync
+                .values()
+                .all(|sync| matches!(sync, IncomingLocalFileState::Idle))
+        {
+            return None;
+        }
+
+        let res = self.reject_all_incoming_files(state, None).await;
+        lock.remove(&transfer_id);
+
+        Some(res)
+    }
+
+    async fn reject_all_incoming_files(
+        &self,
+        state: &mut IncomingState,
+        reason: Option<&str>,
+    ) -> CloseResult<IncomingTransfer> {
+        let transfer_id = state.xfer.id();
+
+        let mut file_events = Vec::new();
+        for (file_id, sync) in state.file_sync.iter_mut() {
+            if sync
+                .try_terminate_local(FileTerminalState::Rejected)
+                .is_err()
+            {
+                continue;
+            }
+
+            self.storage
+                .update_incoming_file_sync_states(
+                    transfer_id,
+                    file_id.as_ref(),
+                    sync::FileState::Terminal,
+                )
+                .await;
+
+            self.storage
+                .stop_incoming_file(transfer_id, file_id.as_ref())
+                .await;
+
+            if let Some(conn) = &state.conn {
+                debug!(
+                    self.logger,
+                    "Pushing incoming rejection request: file_id {file_id}"
+                );
+
+                if let Err(e) = conn.send(ServerReq::Reject {
+                    file: file_id.clone(),
+                }) {
+                    warn!(self.logger, "Failed to send reject request: {}", e);
+                };
+            }
+
+            if let Some(events) = state.file_events.get(file_id) {
+                file_events.push(events.clone());
+            }
+        }
+
+        // All of the files are terminated at this point, close the transfer
+        self.close_incoming(state, reason).await;
+
+        CloseResult {
+            file_events,
+            xfer_events: state.xfer_events.clone(),
+            stats: state.stats(),
+        }
+    }
+
+    async fn close_incoming(&self, state: &mut IncomingState, reason: Option<&str>) {
+        self.storage
+            .update_transfer_sync_states(
+                state.xfer.id(),
+                drop_storage::sync::TransferState::Canceled,
+            )
+            .await;
+        state.xfer_sync = sync::TransferState::Canceled;
+
+        if let Some(conn) = state.conn.take() {
+            debug!(self.logger, "Pushing outgoing close request");
+
+            let req = match reason {
+                Some(reason) => ServerReq::RejectTransfer {
+                    reason: reason.to_string(),
+                },
+                None => ServerReq::Close,
+            };
+            if let Err(e) = conn.send(req) {
+                warn!(self.logger, "Failed to send close request: {}", e);
+            }
+        }
+    }
+
+    /// Moves the downloads of the in-flight files into a new base directory.
+    /// The temporary files are moved along so that the downloads can resume
+    /// from where they stopped. The running downloads are redirected once
+    /// paused, see [`Self::incoming_redirected`]. Returns the redirected files
+    pub async fn incoming_redirect(
+        &self,
+        transfer_id: Uuid,
+        base_dir: &Path,
+    ) -> crate::Result<Vec<FileId>> {
+        let mut lock = self.incoming.lock().await;
+
+        let state = lock
+            .get_mut(&transfer_id)
+            .ok_or(crate::Error::BadTransfer)?;
+
+        state.ensure_not_cancelled()?;
+
+        let mut redirected = Vec::new();
+        for (file_id, sync) in state.file_sync.iter_mut() {
+            let (from, rename_to) = match sync {
+                IncomingLocalFileState::InFlight { path, rename_to } if path != base_dir => {
+                    (path, rename_to.clone())
+                }
+                _ => continue,
+            };
+
+            let file = &state.xfer.files()[file_id];
+            let task = FileXferTask::new(file.clone(), state.xfer.clone(), base_dir.into())
+                .with_rename(rename_to);
+
+            let mut sent = false;
+            if let Some(conn) = &state.conn {
+                debug!(
+                    self.logger,
+                    "Pushing download redirect request: file_id {file_id}"
+                );
+
+                sent = conn
+                    .send(ServerReq::Redirect {
+                        task: Box::new(task),
+                    })
+                    .is_ok();
+            }
+
+            // Without an active connection there is no download in progress so it's
+            // safe to move the file right away
+            if !sent {
+                ws::server::move_temp_file(&self.logger, transfer_id, file_id, from, base_dir);
+
+                *from = base_dir.to_path_buf();
+                self.storage
+                    .redirect_incoming_file(
+                        transfer_id,
+                        file_id.as_ref(),
+                        &base_dir.to_string_lossy(),
+                    )
+                    .await;
+            }
+
+            redirected.push(file_id.clone());
+        }
+
+        Ok(redirected)
+    }
+
+    /// Records the download of the in-flight file as moved into the new base
+    /// directory, called once the download is paused and its temporary file
+    /// moved
+    pub async fn incoming_redirected(
+        &self,
+        transfer_id: Uuid,
+        file_id: &FileId,
+        base_dir: &Path,
+    ) -> crate::Result<()> {
+        let mut lock = self.incoming.lock().await;
+
+        let state = lock
+            .get_mut(&transfer_id)
+            .ok_or(crate::Error::BadTransfer)?;
+
+        if let IncomingLocalFileState::InFlight { path, .. } = state.file_sync_mut(file_id)? {
+            *path = base_dir.to_path_buf();
+
+            self.storage
+                .redirect_incoming_file(transfer_id, file_id.as_ref(), &base_dir.to_string_lossy())
+                .await;
+        }
+
+        Ok(())
+    }
+
+    /// Requests the in-flight file again over the current connection, e.g.
+    /// once the sender stopped streaming it
+    pub async fn incoming_download_again(
+        &self,
+        transfer_id: Uuid,
+        file_id: &FileId,
+    ) -> crate::Result<()> {
+        let lock = self.incoming.lock().await;
+
+        let state = lock.get(&transfer_id).ok_or(crate::Error::BadTransfer)?;
+        state.ensure_not_cancelled()?;
+
+        let (path, rename_to) = match state.file_sync.get(file_id) {
+            Some(IncomingLocalFileState::InFlight { path, rename_to }) => (path, rename_to),
+            Some(_) => {
+                return Err(crate::Error::BadTransferState(
+                    "File is not being downloaded".into(),
+                ))
+            }
+            None => return Err(crate::Error::BadFileId),
+        };
+
+        let conn = state.conn.as_ref().ok_or(crate::Error::Canceled)?;
+        let task = FileXferTask::new(
+            state.xfer.files()[file_id].clone(),
+            state.xfer.clone(),
+            path.into(),
+        )
+        .with_rename(rename_to.clone());
+
+        conn.send(ServerReq::Download {
+            task: Box::new(task),
+        })
+        .map_err(|_| crate::Error::Canceled)
+    }
+
+    pub async fn outgoing_issue_close(
+        &self,
+        transfer_id: Uuid,
+    ) -> crate::Result<CloseResult<OutgoingTransfer>> {
+        let mut lock = self.outgoing.lock().await;
+
+        let state = lock
+            .get_mut(&transfer_id)
+            .ok_or(crate::Error::BadTransfer)?;
+
+        match state.xfer_sync {
+            // If it's new, then suppress transfer synchronization by deleting the state
+            sync::TransferState::New => {
+                self.storage.transfer_sync_clear(transfer_id).await;
+
+                let res = CloseResult {
+                    file_events: state.file_events.values().cloned().collect(),
+                    xfer_events: state.xfer_events.clone(),
+                    stats: state.stats(),
+                };
+
+                lock.remove(&transfer_id);
+
+                Ok(res)
+            }
+            sync::TransferState::Active => {
+                self.storage
+                    .update_transfer_sync_states(
+                        transfer_id,
+                        drop_storage::sync::TransferState::Canceled,
+                    )
+                    .await;
+                state.xfer_sync = sync::TransferState::Canceled;
+
+                if let Some(conn) = state.conn.take() {
+                    debug!(self.logger, "Pushing incoming  close request");
+                    if let Err(e) = conn.send(ClientReq::Close) {
+                        warn!(self.logger, "Failed to send close request: {}", e);
+                    }
+                }
+
+                Ok(CloseResult {
+                    file_events: state.file_events.values().cloned().collect(),
+                    xfer_events: state.xfer_events.clone(),
+                    stats: state.stats(),
+                })
+            }
+            sync::TransferState::Canceled => Err(crate::Error::BadTransfer),
+        }
+    }
+
+    pub async fn outgoing_ensure_file_not_terminated(
+        &self,
+        transfer_id: Uuid,
+        file_id: &FileId,
+    ) -> crate::Result<()> {
+        let lock = self.outgoing.lock().await;
+        let state = lock.get(&transfer_id).ok_or(crate::Error::BadTransfer)?;
+        let state = state
+            .file_sync
+            .get(file_id)
+            .ok_or(crate::Error::BadFileId)?;
+
+        state.ensure_not_terminated()
+    }
+
+    pub async fn outgoing_remove(&self, transfer_id: Uuid) -> Option<OutgoingState> {
+        debug!(self.logger, "Removing outgoing transfer: {transfer_id}");
+        let mut lock = self.outgoing.lock().await;
+
+        let state = lock.remove(&transfer_id)?;
+        self.storage.transfer_sync_clear(transfer_id).await;
+        Some(state)
+    }
+
+    pub async fn outgoing_client_stop(&self, transfer_id: Uuid) -> Option<CancellationToken> {
+        let lock = self.outgoing.lock().await;
+        lock.get(&transfer_id)
+            .map(|state| state.client_stop.clone())
+    }
+
+    pub async fn outgoing_conn_drop(&self, transfer_id: Uuid) -> Option<Arc<Notify>> {
+        let lock = self.outgoing.lock().await;
+        lock.get(&transfer_id).map(|state| state.conn_drop.clone())
+    }
+
+    pub async fn incoming_conn_drop(&self, transfer_id: Uuid) -> Option<Arc<Notify>> {
+        let lock = self.incoming.lock().await;
+        lock.get(&transfer_id).map(|state| state.conn_drop.clone())
+    }
+
+    pub async fn outgoing_retry_deadline(&self, transfer_id: Uuid) -> Option<Arc<RetryDeadline>> {
+        let lock = self.outgoing.lock().await;
+        lock.get(&transfer_id)
+            .map(|state| state.retry_deadline.clone())
+    }
+
+    pub async fn outgoing_rate_limit(&self, transfer_id: Uuid) -> Option<Arc<RateLimit>> {
+        let lock = self.outgoing.lock().await;
+        lock.get(&transfer_id).map(|state| state.rate_limit.clone())
+    }
+
+    pub async fn incoming_rate_limit(&self, transfer_id: Uuid) -> Option<Arc<RateLimit>> {
+        let lock = self.incoming.lock().await;
+        lock.get(&transfer_id).map(|state| state.rate_limit.clone())
+    }
+
+    pub async fn incoming_stream_budget(&self, transfer_id: Uuid) -> Option<Arc<StreamBudget>> {
+        let lock = self.incoming.lock().await;
+        lock.get(&transfer_id)
+            .map(|state| state.stream_budget.clone())
+    }
+
+    /// Caps the throughput of the transfer in bytes per second, `None`
+    /// removing the cap. The cap is persisted so that it applies once the
+    /// transfer is restored as well
+    pub async fn set_rate_limit(&self, transfer_id: Uuid, limit: Option<u64>) -> crate::Result<()> {
+        let rate_limit = match self.outgoing_rate_limit(transfer_id).await {
+            Some(rate_limit) => rate_limit,
+            None => self
+                .incoming_rate_limit(transfer_id)
+                .await
+                .ok_or(crate::Error::BadTransfer)?,
+        };
+
+        rate_limit.set(limit);
+        self.storage
+            .set_transfer_rate_limit(transfer_id, limit)
+            .await;
+
+        Ok(())
+    }
+
+    /// Flips the failed files of the transfer back to the resumable state: the
+    /// outgoing ones can be requested by the receiver again and the incoming
+    /// ones can be downloaded again. Returns the number of files reset
+    pub async fn retry_failed_files(&self, transfer_id: Uuid) -> crate::Result<usize> {
+        if let Some(state) = self.outgoing.lock().await.get_mut(&transfer_id) {
+            state.ensure_not_cancelled()?;
+            self.storage.reset_failed_files(transfer_id).await;
+
+            let mut count = 0;
+            for (file_id, sync) in state.file_sync.iter_mut() {
+                if let OutgoingLocalFileState::Terminal(FileTerminalState::Failed) = sync {
+                    *sync = OutgoingLocalFileState::Alive;
+                    count += 1;
+
+                    // The failed state might not be stored yet
+                    self.storage
+                        .update_outgoing_file_sync_states(
+                            transfer_id,
+                            file_id.as_ref(),
+                            sync::FileState::Alive,
+                        )
+                        .await;
+                    if let Some(events) = state.file_events.get(file_id) {
+                        events.reset().await;
+                    }
+                }
+            }
+
+            return Ok(count);
+        }
+
+        let mut lock = self.incoming.lock().await;
+        let state = lock
+            .get_mut(&transfer_id)
+            .ok_or(crate::Error::BadTransfer)?;
+
+        state.ensure_not_cancelled()?;
+        self.storage.reset_failed_files(transfer_id).await;
+
+        let mut count = 0;
+        for (file_id, sync) in state.file_sync.iter_mut() {
+            if let IncomingLocalFileState::Terminal(FileTerminalState::Failed) = sync {
+                *sync = IncomingLocalFileState::Idle;
+                count += 1;
+
+                // The failed state might not be stored yet
+                self.storage
+                    .update_incoming_file_sync_states(
+                        transfer_id,
+                        file_id.as_ref(),
+                        sync::FileState::Alive,
+                    )
+                    .await;
+                if let Some(events) = state.file_events.get(file_id) {
+                    events.reset().await;
+                }
+            }
+        }
+
+        Ok(count)
+    }
+
+    /// Makes the connection loop of the transfer drop its connection, keeping
+    /// the transfer state so that it resumes once connected again
+    pub async fn drop_connection(&self, transfer_id: Uuid) -> crate::Result<()> {
+        let conn_drop = match self.outgoing_conn_drop(transfer_id).await {
+            Some(conn_drop) => conn_drop,
+            None => self
+                .incoming_conn_drop(transfer_id)
+                .await
+                .ok_or(crate::Error::BadTransfer)?,
+        };
+
+        conn_drop.notify_waiters();
+        Ok(())
+    }
+
+    pub async fn outgoing_event_tx(
+        &self,
+        transfer_id: Uuid,
+    ) -> Option<Arc<OutgoingTransferEventTx>> {
+        let lock = self.outgoing.lock().await;
+        lock.get(&transfer_id)
+            .map(|state| state.xfer_events.clone())
+    }
+
+    pub async fn connection_metrics(&self, transfer_id: Uuid) -> Option<ConnectionMetrics> {
+        if let Some(state) = self.outgoing.lock().await.get(&transfer_id) {
+            return Some(state.metrics);
+        }
+
+        self.incoming
+            .lock()
+            .await
+            .get(&transfer_id)
+            .map(|state| state.metrics)
+    }
+
+    pub async fn outgoing_connect_failed(&self, transfer_id: Uuid) {
+        if let Some(state) = self.outgoing.lock().await.get_mut(&transfer_id) {
+            state.metrics.failed_attempts += 1;
+        }
+    }
+
+    pub async fn update_metrics(&self, transfer_id: Uuid, f: impl FnOnce(&mut ConnectionMetrics)) {
+        if let Some(state) = self.outgoing.lock().await.get_mut(&transfer_id) {
+            f(&mut state.metrics);
+            return;
+        }
+
+        if let Some(state) = self.incoming.lock().await.get_mut(&transfer_id) {
+            f(&mut state.metrics);
+        }
+    }
+
+    /// Returns the number of the (incoming, outgoing) transfers in memory
+    /// that are not canceled
+    pub async fn active_transfers(&self) -> (usize, usize) {
+        let incoming = self
+            .incoming
+            .lock()
+            .await
+            .values()
+            .filter(|state| !matches!(state.xfer_sync, sync::TransferState::Canceled))
+            .count();
+
+        let outgoing = self
+            .outgoing
+            .lock()
+            .await
+            .values()
+            .filter(|state| !matches!(state.xfer_sync, sync::TransferState::Canceled))
+            .count();
+
+        (incoming, outgoing)
+    }
+
+    /// Returns the files awaiting the user decision, that is neither
+    /// downloaded nor rejected, grouped by the transfer. Cancelled transfers
+    /// are skipped
+    pub async fn incoming_pending_files(&self) -> Vec<(Uuid, Vec<FileId>)> {
+        let lock = self.incoming.lock().await;
+
+        lock.values()
+            .filter(|state| state.ensure_not_cancelled().is_ok())
+            .filter_map(|state| {
+                let files = state.idle_files();
+                (!files.is_empty()).then(|| (state.xfer.id(), files))
+            })
+            .collect()
+    }
+
+    /// Stores the last reported progress of the downloads in flight as their
+    /// paused state, so that the resume offset does not depend on the
+    /// temporary file alone, which might be torn by the interruption
+    pub async fn incoming_persist_offsets(&self) {
+        let in_flight: Vec<_> = {
+            let lock = self.incoming.lock().await;
+
+            lock.values()
+                .flat_map(|state| {
+                    state
+                        .file_sync
+                        .iter()
+                        .filter(|(_, sync)| matches!(sync, IncomingLocalFileState::InFlight { .. }))
+                        .filter_map(|(file_id, _)| state.file_events.get(file_id))
+                        .map(|events| (state.xfer.id(), events.clone()))
+                })
+                .collect()
+        };
+
+        for (transfer_id, events) in in_flight {
+            let offset = events.transferred().await;
+            debug!(
+                self.logger,
+                "Persisting offset {offset} of {transfer_id}/{}",
+                events.file_id()
+            );
+
+            self.storage
+                .insert_incoming_path_paused_state(
+                    transfer_id,
+                    events.file_id().as_ref(),
+                    offset as _,
+                )
+                .await;
+        }
+    }
+
+    /// Asks the connections of all of the transfers to break and forgets
+    /// them. Returns the IDs of the transfers
+    pub async fn pause_all(&self) -> Vec<Uuid> {
+        let mut ids = Vec::new();
+
+        for (id, state) in self.incoming.lock().await.iter_mut() {
+            if let Some(conn) = state.conn.take() {
+                if let Err(e) = conn.send(ServerReq::Pause) {
+                    warn!(self.logger, "Failed to send pause request: {}", e);
+                }
+            }
+            ids.push(*id);
+        }
+
+        for (id, state) in self.outgoing.lock().await.iter_mut() {
+            if let Some(conn) = state.conn.take() {
+                if let Err(e) = conn.send(ClientReq::Pause) {
+                    warn!(self.logger, "Failed to send pause request: {}", e);
+                }
+            }
+            ids.push(*id);
+        }
+
+        ids
+    }
+
+    /// Makes the connection loops of all of the outgoing transfers drop their
+    /// connections, so that they reconnect
+    pub async fn drop_outgoing_connections(&self) {
+        for state in self.outgoing.lock().await.values() {
+            state.conn_drop.notify_waiters();
+        }
+    }
+
+    /// IDs of all of the incoming and outgoing transfers
+    pub async fn transfer_ids(&self) -> Vec<Uuid> {
+        let mut ids: Vec<_> = self.incoming.lock().await.keys().copied().collect();
+        ids.extend(self.outgoing.lock().await.keys());
+        ids
+    }
+
+    pub async fn incoming_disconnect(&self, transfer_id: Uuid) -> crate::Result<()> {
+        let mut lock = self.incoming.lock().await;
+        let _ = lock
+            .get_mut(&transfer_id)
+            .ok_or(crate::Error::BadTransfer)?
+            .conn
+            .take();
+        Ok(())
+    }
+
+    pub async fn outgoing_disconnect(&self, transfer_id: Uuid) -> crate::Result<()> {
+        trace!(self.logger, "outgoing_disconnect: {}", transfer_id);
+        let mut lock = self.outgoing.lock().await;
+        let _ = lock
+            .get_mut(&transfer_id)
+            .ok_or(crate::Error::BadTransfer)?
+            .conn
+            .take();
+        Ok(())
+    }
+}
+
+impl OutgoingState {
+    pub fn stats(&self) -> TransferStats {
+        TransferStats::new(
+            &*self.xfer,
+            self.file_sync
+                .iter()
+                .filter_map(|(file_id, state)| match state {
+                    OutgoingLocalFileState::Terminal(term) => Some((file_id, *term)),
+                    _ => None,
+                }),
+        )
+    }
+
+    fn issue_pending_requests(&self, conn: &UnboundedSender<ClientReq>, logger: &Logger) {
+        let iter = self
+            .file_sync
+            .iter()
+            .filter_map(|(file_id, state)| match state {
+                OutgoingLocalFileState::Terminal(FileTerminalState::Rejected) => {
+                    info!(logger, "Rejecting file: {file_id}",);
+
+                    Some(ClientReq::Reject {
+                        file: file_id.clone(),
+                    })
+                }
+                OutgoingLocalFileState::Terminal(FileTerminalState::Failed) => {
+                    info!(logger, "Failing file: {file_id}",);
+
+                    Some(ClientReq::Fail {
+                        file: file_id.clone(),
+                    })
+                }
+                _ => None,
+            });
+
+        for req in iter {
+            if let Err(e) = conn.send(req) {
+                warn!(logger, "Failed to send request: {}", e);
+            }
+        }
+    }
+
+    fn ensure_not_cancelled(&self) -> crate::Result<()> {
+        if let sync::TransferState::Canceled = self.xfer_sync {
+            return Err(crate::Error::BadTransfer);
+        }
+        Ok(())
+    }
+
+    fn file_events(&self, file_id: &FileId) -> crate::Result<&Arc<OutgoingFileEventTx>> {
+        self.file_events.get(file_id).ok_or(crate::Error::BadFileId)
+    }
+
+    pub fn status(&self) -> TransferStatus {
+        let finished = self
+            .file_sync
+            .values()
+            .all(|state| matches!(state, OutgoingLocalFileState::Terminal(_)));
+
+        transfer_status(&self.xfer_sync, finished, self.conn.as_ref(), self.resumed)
+    }
+
+    pub async fn stall_reason(&self, now: Instant) -> Option<StallReason> {
+        match self.status() {
+            TransferStatus::Active => {
+                let events = self
+                    .file_sync
+                    .iter()
+                    .filter(|(_, state)| !matches!(state, OutgoingLocalFileState::Terminal(_)))
+                    .filter_map(|(file_id, _)| self.file_events.get(file_id));
+
+                connected_stall_reason(events, &self.rate_limit, now).await
+            }
+            TransferStatus::Restored => Some(StallReason::AwaitingResume),
+            TransferStatus::Deferred => match self.retry_deadline.get() {
+                Some(deadline) if deadline > now => Some(StallReason::BackingOff {
+                    retry_in: deadline - now,
+                }),
+                _ => Some(StallReason::WaitingForPeerOnline),
+            },
+            _ => None,
+        }
+    }
+
+    fn file_sync_mut(&mut self, file_id: &FileId) -> crate::Result<&mut OutgoingLocalFileState> {
+        self.file_sync
+            .get_mut(file_id)
+            .ok_or(crate::Error::BadFileId)
+    }
+}
+
+impl IncomingState {
+    pub fn stats(&self) -> TransferStats {
+        TransferStats::new(
+            &*self.xfer,
+            self.file_sync
+                .iter()
+                .filter_map(|(file_id, state)| match state {
+                    IncomingLocalFileState::Terminal(term) => Some((file_id, *term)),
+                    _ => None,
+                }),
+        )
+    }
+
+    pub fn status(&self) -> TransferStatus {
+        let finished = self
+            .file_sync
+            .values()
+            .all(|state| matches!(state, IncomingLocalFileState::Terminal(_)));
+
+        transfer_status(&self.xfer_sync, finished, self.conn.as_ref(), self.resumed)
     }
 
-    pub async fn start_download(
+    pub async fn stall_reason(&self, now: Instant) -> Option<StallReason> {
+        match self.status() {
+            TransferStatus::Active => {
+                let events = self
+                    .file_sync
+                    .iter()
+                    .filter(|(_, state)| !matches!(state, IncomingLocalFileState::Terminal(_)))
+                    .filter_map(|(file_id, _)| self.file_events.get(file_id));
+
+                connected_stall_reason(events, &self.rate_limit, now).await
+            }
+            TransferStatus::Restored => Some(StallReason::AwaitingResume),
+            // The sender is the one reconnecting
+            TransferStatus::Deferred => Some(StallReason::WaitingForPeerOnline),
+            _ => None,
+        }
+    }
+
+    /// Emits the event if the completed file no longer exists at the location
+    /// it was downloaded to. With `redownload` set such file becomes idle
+    /// again, so that it can be downloaded once more. Returns `true` when the
+    /// file was reset
+    pub async fn check_missing_completed(
         &mut self,
         storage: &Storage,
         file_id: &FileId,
-        parent_dir: &Path,
-        logger: &Logger,
-    ) -> crate::Result<()> {
-        let state = self.file_sync_mut(file_id)?;
+        redownload: bool,
+    ) -> bool {
+        if !matches!(
+            self.file_sync.get(file_id),
+            Some(IncomingLocalFileState::Terminal(
+                FileTerminalState::Completed
+            ))
+        ) {
+            return false;
+        }
+
+        let Some(file) = storage
+            .completed_incoming_file(self.xfer.id(), file_id.as_ref())
+            .await
+        else {
+            return false;
+        };
+
+        // The file removed on purpose by the post completion hook is not missing
+        if file.deleted {
+            return false;
+        }
+
+        let final_path = PathBuf::from(file.final_path);
+        // The file is assumed to be there when its existence can't be checked
+        if !matches!(final_path.try_exists(), Ok(false)) {
+            return false;
+        }
 
-        state.ensure_not_terminated()?;
-        *state = IncomingLocalFileState::InFlight {
-            path: parent_dir.to_path_buf(),
+        let Some(events) = self.file_events.get(file_id) else {
+            return false;
         };
+        events.missing(final_path).await;
+
+        if !redownload {
+            return false;
+        }
 
+        events.reset().await;
+        self.file_sync
+            .insert(file_id.clone(), IncomingLocalFileState::Idle);
         storage
-            .start_incoming_file(
+            .update_incoming_file_sync_states(
                 self.xfer.id(),
                 file_id.as_ref(),
-                &parent_dir.to_string_lossy(),
+                sync::FileState::Alive,
             )
             .await;
 
-        let file = &self.xfer.files()[file_id];
+        true
+    }
 
-        if let Some(conn) = &self.conn {
-            let task = FileXferTask::new(file.clone(), self.xfer.clone(), parent_dir.into());
+    /// Returs `true` when the new download can be started and `false` in case
+    /// the downaload is already happening
+    pub fn validate_for_download(&self, file_id: &FileId) -> crate::Result<bool> {
+        self.ensure_not_cancelled()?;
 
-            debug!(logger, "Pushing download request: file_id {file_id}");
+        let state = self.file_sync.get(file_id).ok_or(crate::Error::BadFileId)?;
+        let start = match state {
+            IncomingLocalFileState::Idle => true,
+            IncomingLocalFileState::InFlight { .. } => false,
+            IncomingLocalFileState::Terminal(term) => {
+                return Err(crate::Error::FileStateMismatch(*term));
+            }
+        };
 
-            if let Err(e) = conn.send(ServerReq::Download {
-                task: Box::new(task),
-            }) {
-                warn!(logger, "Failed to send download request: {}", e);
+        Ok(start)
+    }
+
+    /// Returns the files for which the download was not yet issued
+    pub fn idle_files(&self) -> Vec<FileId> {
+        self.file_sync
+            .iter()
+            .filter(|(_, state)| matches!(state, IncomingLocalFileState::Idle))
+            .map(|(file_id, _)| file_id.clone())
+            .collect()
+    }
+
+    /// Starts the downloads of the files, `renames` holding the filenames
+    /// chosen by the user in place of the sender's ones
+    pub async fn start_downloads(
+        &mut self,
+        storage: &Storage,
+        file_ids: &[FileId],
+        parent_dir: &Path,
+        renames: &HashMap<FileId, String>,
+        logger: &Logger,
+    ) -> crate::Result<()> {
+        for file_id in file_ids {
+            self.file_sync_mut(file_id)?.ensure_not_terminated()?;
+        }
+
+        for file_id in file_ids {
+            *self.file_sync_mut(file_id)? = IncomingLocalFileState::InFlight {
+                path: parent_dir.to_path_buf(),
+                rename_to: renames.get(file_id).cloned(),
             };
         }
+        self.offer_expiry.cancel();
+
+        let files: Vec<(&str, Option<&str>)> = file_ids
+            .iter()
+            .map(|file_id| (file_id.as_ref(), renames.get(file_id).map(String::as_str)))
+            .collect();
+        storage
+            .start_incoming_files(self.xfer.id(), &files, &parent_dir.to_string_lossy())
+            .await;
+
+        if let Some(conn) = &self.conn {
+            for file_id in file_ids {
+                let file = &self.xfer.files()[file_id];
+                let task = FileXferTask::new(file.clone(), self.xfer.clone(), parent_dir.into())
+                    .with_rename(renames.get(file_id).cloned());
+
+                debug!(logger, "Pushing download request: file_id {file_id}");
+
+                if let Err(e) = conn.send(ServerReq::Download {
+                    task: Box::new(task),
+                }) {
+                    warn!(logger, "Failed to send download request: {}", e);
+                };
+            }
+        }
 
         Ok(())
     }
@@ -808,6 +1928,27 @@ impl IncomingState {
         self.file_events.get(file_id).ok_or(crate::Error::BadFileId)
     }
 
+    /// Composes the final path of the file and persists the directory mapping
+    /// so that it survives restarts
+    pub async fn compose_final_path(
+        &mut self,
+        storage: &Storage,
+        dest_dir: &Path,
+        file_subpath: &FileSubPath,
+    ) -> crate::Result<PathBuf> {
+        let (mapped, new_mapping) = self
+            .dir_mappings
+            .compose_final_path(dest_dir, file_subpath)?;
+
+        if let Some((path, mapped_name)) = new_mapping {
+            storage
+                .insert_incoming_dir_mapping(self.xfer.id(), &path.to_string_lossy(), &mapped_name)
+                .await;
+        }
+
+        Ok(mapped)
+    }
+
     fn ensure_not_cancelled(&self) -> crate::Result<()> {
         if let sync::TransferState::Canceled = self.xfer_sync {
             return Err(crate::Error::BadTransfer);
@@ -820,11 +1961,12 @@ impl IncomingState {
             .file_sync
             .iter()
             .filter_map(|(file_id, state)| match state {
-                IncomingLocalFileState::InFlight { path } => {
+                IncomingLocalFileState::InFlight { path, rename_to } => {
                     info!(logger, "Resuming file: {file_id}",);
 
                     let xfile = &self.xfer.files()[file_id];
-                    let task = FileXferTask::new(xfile.clone(), self.xfer.clone(), path.into());
+                    let task = FileXferTask::new(xfile.clone(), self.xfer.clone(), path.into())
+                        .with_rename(rename_to.clone());
                     Some(ServerReq::Download {
                         task: Box::new(task),
                     })
@@ -883,12 +2025,13 @@ impl DirMapping {
     /// * finally appends the rest of subpath components into the final path
     ///  `dest_dir/<mapped dir1>/dir2/../filename`
     ///
-    /// The results are cached in RAM to speed this up
+    /// The results are cached in RAM to speed this up. Besides the final path
+    /// the newly created directory mapping is returned, if any
     pub fn compose_final_path(
         &mut self,
         dest_dir: &Path,
         file_subpath: &FileSubPath,
-    ) -> crate::Result<PathBuf> {
+    ) -> crate::Result<(PathBuf, Option<(PathBuf, String)>)> {
         let mut iter = file_subpath.iter().map(crate::utils::normalize_filename);
 
         let probe = iter.next().ok_or_else(|| {
@@ -896,6 +2039,8 @@ impl DirMapping {
         })?;
         let next = iter.next();
 
+        let mut new_mapping = None;
+
         let mapped = match next {
             Some(next) => {
                 // Check if dir exists and is known to us
@@ -904,6 +2049,7 @@ impl DirMapping {
                     Entry::Occupied(occ) => occ.get().clone(),
                     // Dir in new, check if there is name conflict and add to known
                     Entry::Vacant(vacc) => {
+                        let key = vacc.key().clone();
                         let mapped = crate::utils::filepath_variants(vacc.key())?.find(|dst_location| {
                                 // Skip if there is already a file with the same name.
                                 // Additionaly there could be a dangling symlink with the same name,
@@ -916,11 +2062,11 @@ impl DirMapping {
                             mapped
                                 .file_name()
                                 .ok_or_else(|| crate::Error::BadPath("Missing file name".into()))?
-                                .to_str()
-                                .ok_or_else(|| crate::Error::BadPath("Invalid UTF8 path".into()))?
-                                .to_string(),
+                                .to_string_lossy()
+                                .into_owned(),
                         );
 
+                        new_mapping = Some((key, value.clone()));
                         value.clone()
                     }
                 };
@@ -933,7 +2079,11 @@ impl DirMapping {
             }
         };
 
-        Ok(mapped)
+        Ok((mapped, new_mapping))
+    }
+
+    fn register_mapping(&mut self, path: PathBuf, mapped_name: String) {
+        self.mappings.insert(path, mapped_name);
     }
 
     fn register_preexisting_final_path(
@@ -985,6 +2135,12 @@ impl OutgoingLocalFileState {
 }
 
 pub(crate) async fn restore_transfers_state(state: &Arc<State>, logger: &Logger) {
+    let (incoming, outgoing) = state.storage.count_transfers_to_resume().await;
+    info!(
+        logger,
+        "Restoring {incoming} incoming and {outgoing} outgoing transfers"
+    );
+
     let incoming = restore_incoming(
         &state.transfer_manager.event_factory,
         &state.storage,
@@ -998,17 +2154,30 @@ pub(crate) async fn restore_transfers_state(state: &Arc<State>, logger: &Logger)
     *state.transfer_manager.outgoing.lock().await = outgoing;
 }
 
+/// Spawns the jobs of the restored transfers which were not resumed yet,
+/// either all of them or only the given one. Returns the IDs of the resumed
+/// transfers
 pub(crate) async fn resume(
     refresh_trigger: &tokio::sync::watch::Receiver<()>,
     state: &Arc<State>,
     logger: &Logger,
     guard: &AliveGuard,
     stop: &CancellationToken,
-) {
+    only: Option<Uuid>,
+) -> Vec<Uuid> {
+    let selected = |id: &Uuid| only.map_or(true, |only| only == *id);
+    let mut resumed = Vec::new();
+
     {
-        let xfers = state.transfer_manager.outgoing.lock().await;
+        let mut xfers = state.transfer_manager.outgoing.lock().await;
+
+        for (id, xstate) in xfers.iter_mut() {
+            if xstate.resumed || !selected(id) {
+                continue;
+            }
+            xstate.resumed = true;
+            resumed.push(*id);
 
-        for xstate in xfers.values() {
             let trig = refresh_trigger.clone();
             ws::client::spawn(
                 trig,
@@ -1022,9 +2191,15 @@ pub(crate) async fn resume(
     }
 
     {
-        let xfers = state.transfer_manager.incoming.lock().await;
+        let mut xfers = state.transfer_manager.incoming.lock().await;
+
+        for (id, xstate) in xfers.iter_mut() {
+            if xstate.resumed || !selected(id) {
+                continue;
+            }
+            xstate.resumed = true;
+            resumed.push(*id);
 
-        for xstate in xfers.values() {
             let trig = refresh_trigger.clone();
             check::spawn(
                 trig,
@@ -1036,6 +2211,8 @@ pub(crate) async fn resume(
             );
         }
     }
+
+    resumed
 }
 
 async fn restore_incoming(
@@ -1048,6 +2225,7 @@ async fn restore_incoming(
 
     let mut xfers = HashMap::new();
     for transfer in transfers {
+        let created = stored_time(transfer.created_at.and_utc().timestamp_millis());
         let restore_transfer = async {
             let files = transfer
                 .files
@@ -1103,11 +2281,13 @@ async fn restore_incoming(
                     if state.ensure_not_terminated().is_ok() {
                         *state = IncomingLocalFileState::InFlight {
                             path: file.base_dir.into(),
+                            rename_to: file.rename_to,
                         };
                     }
                 }
             }
 
+            let xfer_id = xfer.id();
             let xfer = Arc::new(xfer);
             let mut xstate = IncomingState {
                 xfer: xfer.clone(),
@@ -1128,7 +2308,15 @@ async fn restore_incoming(
                 xfer_events: Arc::new(factory.transfer(
                     xfer,
                     matches!(sync.local_state, sync::TransferState::Canceled),
+                    created,
                 )),
+                metrics: ConnectionMetrics::default(),
+                was_connected: false,
+                offer_expiry: CancellationToken::new(),
+                conn_drop: Arc::default(),
+                rate_limit: Arc::new(RateLimit::new(storage.transfer_rate_limit(xfer_id).await)),
+                stream_budget: Arc::default(),
+                resumed: false,
             };
 
             debug!(
@@ -1146,6 +2334,27 @@ async fn restore_incoming(
                     .register_preexisting_final_path(&subpath, &path.final_path);
             }
 
+            // Restore also the mappings of the files that were not finished yet
+            let mappings = storage.incoming_dir_mappings(xstate.xfer.id()).await;
+            for mapping in mappings {
+                xstate
+                    .dir_mappings
+                    .register_mapping(mapping.path.into(), mapping.mapped_name);
+            }
+
+            let file_ids: Vec<_> = xstate.xfer.files().keys().cloned().collect();
+            for file_id in file_ids {
+                if xstate
+                    .check_missing_completed(storage, &file_id, config.redownload_missing)
+                    .await
+                {
+                    info!(
+                        logger,
+                        "Completed file {file_id} is missing, downloading again"
+                    );
+                }
+            }
+
             anyhow::Ok(xstate)
         };
 
@@ -1170,12 +2379,26 @@ async fn restore_outgoing(state: &Arc<State>, logger: &Logger) -> HashMap<Uuid,
 
     let mut xfers = HashMap::new();
     for transfer in transfers {
+        let created = stored_time(transfer.created_at.and_utc().timestamp_millis());
         let restore_transfer = || async move {
-            let files = transfer
-                .files
-                .into_iter()
-                .map(|dbfile| restore_outgoing_file(state, dbfile))
-                .collect::<Result<_, _>>()?;
+            let mut files = Vec::with_capacity(transfer.files.len());
+            #[cfg_attr(not(unix), allow(unused_mut))]
+            let mut unopenable = Vec::new();
+
+            for dbfile in transfer.files {
+                let file = restore_outgoing_file(state, dbfile)?;
+
+                #[cfg(unix)]
+                let file = match refresh_content_uri(state, transfer.uuid, file, logger).await {
+                    Ok(file) => file,
+                    Err((file, err)) => {
+                        unopenable.push((file.id().clone(), err));
+                        file
+                    }
+                };
+
+                files.push(file);
+            }
 
             let xfer = OutgoingTransfer::new_with_uuid(
                 transfer.peer.parse().context("Failed to parse peer IP")?,
@@ -1183,7 +2406,8 @@ async fn restore_outgoing(state: &Arc<State>, logger: &Logger) -> HashMap<Uuid,
                 transfer.uuid,
                 &state.config,
             )
-            .context("Failed to create transfer")?;
+            .context("Failed to create transfer")?
+            .with_zone(state.storage.transfer_zone(transfer.uuid).await);
 
             let sync = state
                 .storage
@@ -1217,6 +2441,23 @@ async fn restore_outgoing(state: &Arc<State>, logger: &Logger) -> HashMap<Uuid,
                 file_sync.insert(file_id.clone(), local);
             }
 
+            // Only the files that cannot be opened fail, the rest is resumed
+            for (file_id, _) in &unopenable {
+                file_sync.insert(
+                    file_id.clone(),
+                    OutgoingLocalFileState::Terminal(FileTerminalState::Failed),
+                );
+                state
+                    .storage
+                    .update_outgoing_file_sync_states(
+                        xfer.id(),
+                        file_id.as_ref(),
+                        sync::FileState::Terminal,
+                    )
+                    .await;
+            }
+
+            let xfer_id = xfer.id();
             let xfer = Arc::new(xfer);
             let xstate = OutgoingState {
                 xfer: xfer.clone(),
@@ -1241,8 +2482,24 @@ async fn restore_outgoing(state: &Arc<State>, logger: &Logger) -> HashMap<Uuid,
                 xfer_events: Arc::new(state.transfer_manager.event_factory.transfer(
                     xfer,
                     matches!(sync.local_state, sync::TransferState::Canceled),
+                    created,
                 )),
+                client_stop: CancellationToken::new(),
+                conn_drop: Arc::default(),
+                metrics: ConnectionMetrics::default(),
+                was_connected: false,
+                file_attempts: HashMap::new(),
+                rate_limit: Arc::new(RateLimit::new(
+                    state.storage.transfer_rate_limit(xfer_id).await,
+                )),
+                retry_deadline: Arc::default(),
+                resumed: false,
             };
+
+            for (file_id, err) in unopenable {
+                xstate.file_events[&file_id].failed(err).await;
+            }
+
             anyhow::Ok(xstate)
         };
 
@@ -1262,6 +2519,86 @@ async fn restore_outgoing(state: &Arc<State>, logger: &Logger) -> HashMap<Uuid,
     xfers
 }
 
+/// Checks whether the content URI file still to be uploaded can be opened and
+/// asks the host for a fresh URI otherwise. The refreshed URI is persisted.
+/// Returns the file along with the error if it cannot be opened anyway
+#[cfg(unix)]
+async fn refresh_content_uri(
+    state: &State,
+    transfer_id: Uuid,
+    file: FileToSend,
+    logger: &Logger,
+) -> Result<FileToSend, (FileToSend, crate::Error)> {
+    let content_uri = match &file.source {
+        crate::file::FileSource::Fd { content_uri, .. } => content_uri.clone(),
+        _ => return Ok(file),
+    };
+
+    let sync = state
+        .storage
+        .outgoing_file_sync_state(transfer_id, file.id().as_ref())
+        .await;
+    match sync {
+        Some(sync)
+            if matches!(sync.sync, sync::FileState::Alive)
+                && !(sync.is_success || sync.is_failed || sync.is_rejected) => {}
+        _ => return Ok(file),
+    }
+
+    let err = match file.open(0) {
+        Ok(_) => return Ok(file),
+        Err(err) => err,
+    };
+    warn!(
+        logger,
+        "Failed to open {:?} of transfer {transfer_id}: {err}",
+        Hidden(&content_uri)
+    );
+
+    let (Some(refresher), Some(resolver)) = (&state.uri_refresher, &state.fdresolv) else {
+        return Err((file, err));
+    };
+
+    let fresh_uri = match refresher(content_uri.as_str()).map(|uri| uri.parse::<url::Url>()) {
+        Some(Ok(uri)) => uri,
+        Some(Err(parse_err)) => {
+            warn!(logger, "Refreshed content URI is invalid: {parse_err}");
+            return Err((file, err));
+        }
+        None => return Err((file, err)),
+    };
+
+    let fresh = FileToSend::new_from_content_uri(
+        resolver.clone(),
+        file.subpath().clone(),
+        fresh_uri.clone(),
+        file.size(),
+        file.id().clone(),
+    );
+
+    match fresh.open(0) {
+        Ok(_) => {
+            info!(
+                logger,
+                "Content URI of file {} refreshed to {:?}",
+                file.id(),
+                Hidden(&fresh_uri)
+            );
+            state
+                .storage
+                .update_outgoing_path_uri(transfer_id, file.id().as_ref(), &fresh_uri)
+                .await;
+            Ok(fresh)
+        }
+        Err(err) => Err((file, err)),
+    }
+}
+
+/// Converts the UNIX timestamp in milliseconds read from the storage
+fn stored_time(millis: i64) -> SystemTime {
+    SystemTime::UNIX_EPOCH + Duration::from_millis(millis.max(0) as u64)
+}
+
 #[allow(unused_variables)]
 fn restore_outgoing_file(state: &State, dbfile: OutgoingFileToRetry) -> anyhow::Result<FileToSend> {
     let file_id: FileId = dbfile.file_id.into();
@@ -1287,6 +2624,12 @@ fn restore_outgoing_file(state: &State, dbfile: OutgoingFileToRetry) -> anyhow::
 
             FileToSend::new_from_content_uri(callback, subpath, uri, size, file_id)
         }
+        MEMORY_URI_SCHEME => {
+            anyhow::bail!("In-memory file {file_id} was not persisted and cannot be resumed")
+        }
+        STREAM_URI_SCHEME => {
+            anyhow::bail!("Streamed file {file_id} was read once and cannot be resumed")
+        }
         unknown => anyhow::bail!("Unknon URI schema: {unknown}"),
     };
 
@@ -1315,34 +2658,146 @@ fn extract_directory_mapping(
     }
 }
 
-fn ensure_resume_matches_existing_transfer<T: Transfer>(
-    current: &T,
-    existing: &T,
-) -> anyhow::Result<()> {
-    // Check if the transfer matches
-    anyhow::ensure!(current.peer() == existing.peer(), "Peers do not match",);
-    anyhow::ensure!(
-        current.files().len() == existing.files().len(),
-        "File count does not match"
-    );
+/// Lists the differences between the resumed transfer and the existing one.
+/// An empty list means the transfer can be resumed
+fn resume_mismatches<T: Transfer>(current: &T, existing: &T) -> Vec<MismatchDetail> {
+    let mut details = Vec::new();
+
+    if current.files().len() != existing.files().len() {
+        details.push(MismatchDetail::FileCount {
+            current: current.files().len(),
+            existing: existing.files().len(),
+        });
+    }
+
+    for (file_id, file) in current.files() {
+        let Some(known) = existing.files().get(file_id) else {
+            details.push(MismatchDetail::Unexpected(file_id.clone()));
+            continue;
+        };
+
+        if file.size() != known.size() {
+            details.push(MismatchDetail::Size {
+                file: file_id.clone(),
+                current: file.size(),
+                existing: known.size(),
+            });
+        }
+
+        if file.mime_type() != known.mime_type() {
+            details.push(MismatchDetail::MimeType {
+                file: file_id.clone(),
+                current: file.mime_type().to_string(),
+                existing: known.mime_type().to_string(),
+            });
+        }
+    }
 
-    anyhow::ensure!(
-        current
+    details.extend(
+        existing
             .files()
-            .iter()
-            .all(|(key, val)| existing.files().get(key).map_or(false, |v| {
-                val.id() == v.id() && val.size() == v.size() && val.mime_type() == v.mime_type()
-            })),
-        "Files do not match"
+            .keys()
+            .filter(|file_id| !current.files().contains_key(*file_id))
+            .map(|file_id| MismatchDetail::Missing(file_id.clone())),
     );
 
-    Ok(())
+    details
+}
+
+fn transfer_status<T>(
+    xfer_sync: &sync::TransferState,
+    finished: bool,
+    conn: Option<&UnboundedSender<T>>,
+    resumed: bool,
+) -> TransferStatus {
+    if let sync::TransferState::Canceled = xfer_sync {
+        TransferStatus::Canceled
+    } else if finished {
+        TransferStatus::Completed
+    } else if matches!(conn, Some(conn) if !conn.is_closed()) {
+        TransferStatus::Active
+    } else if !resumed {
+        TransferStatus::Restored
+    } else {
+        TransferStatus::Deferred
+    }
+}
+
+// Any file moving the bytes means progress, unless it's held back by the
+// rate limit. Otherwise the most advanced file explains the stall
+async fn connected_stall_reason<'a, T: Transfer + 'a>(
+    events: impl Iterator<Item = &'a Arc<FileEventTx<T>>>,
+    rate_limit: &RateLimit,
+    now: Instant,
+) -> Option<StallReason> {
+    let mut activities = Vec::new();
+    for events in events {
+        activities.push(events.activity().await);
+    }
+
+    if activities.contains(&FileActivity::Transferring) {
+        rate_limit
+            .is_pacing(now)
+            .then_some(StallReason::RateLimited)
+    } else if activities.contains(&FileActivity::Verifying) {
+        Some(StallReason::Verifying)
+    } else if activities.contains(&FileActivity::Throttled) {
+        Some(StallReason::ConcurrencyQueued)
+    } else {
+        Some(StallReason::AwaitingUserDecision)
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    // The manager with the given incoming transfers restored from a fresh
+    // storage. The files are named after their ids and are 1024 bytes long
+    async fn manager_with_incoming(
+        transfers: &[(Uuid, &[&str])],
+    ) -> (
+        TransferManager,
+        tokio::sync::mpsc::UnboundedReceiver<(crate::Event, std::time::SystemTime)>,
+    ) {
+        use drop_storage::types::{TransferFiles, TransferIncomingPath};
+
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+        let storage = Arc::new(Storage::new(logger.clone(), ":memory:").unwrap());
+        let config = DropConfig::default();
+        let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+        let factory = EventTxFactory::new(
+            tx,
+            drop_analytics::moose_mock(),
+            config.progress_events_granularity,
+        );
+
+        for &(id, files) in transfers {
+            storage
+                .insert_transfer(&drop_storage::TransferInfo {
+                    id,
+                    peer: "1.2.3.4".to_string(),
+                    files: TransferFiles::Incoming(
+                        files
+                            .iter()
+                            .map(|file_id| TransferIncomingPath {
+                                file_id: file_id.to_string(),
+                                relative_path: format!("{file_id}.txt"),
+                                size: 1024,
+                            })
+                            .collect(),
+                    ),
+                })
+                .await;
+        }
+
+        let xfers = restore_incoming(&factory, &storage, &config, &logger).await;
+        let manager = TransferManager::new(storage, factory, logger);
+        *manager.incoming.lock().await = xfers;
+
+        (manager, rx)
+    }
+
     #[test]
     fn extracting_dir_mapping() {
         let (path, name) = extract_directory_mapping(
@@ -1354,4 +2809,1131 @@ mod tests {
         assert_eq!(path, Path::new("/home/xyz/foo/bar/a"));
         assert_eq!(name, "a(2)");
     }
+
+    #[test]
+    fn resume_mismatch_details() {
+        let config = DropConfig::default();
+        let peer = "1.2.3.4".parse().unwrap();
+        let id = Uuid::new_v4();
+
+        let existing = IncomingTransfer::new_with_uuid(
+            peer,
+            vec![
+                FileToRecv::new("id1".into(), "a.txt".into(), 1024),
+                FileToRecv::new("id2".into(), "b.txt".into(), 1024),
+            ],
+            id,
+            &config,
+        )
+        .unwrap();
+        assert!(resume_mismatches(&existing, &existing).is_empty());
+
+        let current = IncomingTransfer::new_with_uuid(
+            peer,
+            vec![
+                FileToRecv::new("id1".into(), "a.txt".into(), 2048),
+                FileToRecv::new("id3".into(), "c.txt".into(), 1024),
+            ],
+            id,
+            &config,
+        )
+        .unwrap();
+
+        let details = resume_mismatches(&current, &existing);
+        assert_eq!(details.len(), 3);
+        assert!(details.contains(&MismatchDetail::Size {
+            file: "id1".into(),
+            current: 2048,
+            existing: 1024,
+        }));
+        assert!(details.contains(&MismatchDetail::Unexpected("id3".into())));
+        assert!(details.contains(&MismatchDetail::Missing("id2".into())));
+    }
+
+    #[test]
+    fn stream_budget_shared_by_files() {
+        let budget = StreamBudget::default();
+        let (a, b): (FileId, FileId) = ("id1".into(), "id2".into());
+
+        assert!(budget.take(&a, 3, 5));
+        // Restarting the file from scratch does not count its bytes twice
+        assert!(budget.take(&a, 4, 5));
+
+        // The other file gets only what the first one left
+        assert!(budget.take(&b, 1, 5));
+        assert!(!budget.take(&b, 2, 5));
+    }
+
+    #[tokio::test]
+    async fn restoring_dir_mapping_of_unfinished_files() {
+        use drop_storage::types::{TransferFiles, TransferIncomingPath};
+
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+        let storage = Storage::new(logger.clone(), ":memory:").unwrap();
+        let config = DropConfig::default();
+        let (tx, _rx) = tokio::sync::mpsc::unbounded_channel();
+        let factory = EventTxFactory::new(
+            tx,
+            drop_analytics::moose_mock(),
+            config.progress_events_granularity,
+        );
+
+        let dest = tempfile::tempdir().unwrap();
+        // Preexisting directory not created by us, forces the mapping to kick in
+        std::fs::create_dir(dest.path().join("dir")).unwrap();
+
+        let transfer_id: Uuid = "23e488a4-0521-11ee-be56-0242ac120002".parse().unwrap();
+        storage
+            .insert_transfer(&drop_storage::TransferInfo {
+                id: transfer_id,
+                peer: "1.2.3.4".to_string(),
+                files: TransferFiles::Incoming(vec![
+                    TransferIncomingPath {
+                        file_id: "id1".to_string(),
+                        relative_path: "dir/a.txt".to_string(),
+                        size: 1024,
+                    },
+                    TransferIncomingPath {
+                        file_id: "id2".to_string(),
+                        relative_path: "dir/b.txt".to_string(),
+                        size: 1024,
+                    },
+                ]),
+            })
+            .await;
+
+        let mut xfers = restore_incoming(&factory, &storage, &config, &logger).await;
+        let state = xfers.get_mut(&transfer_id).unwrap();
+
+        let path = state
+            .compose_final_path(
+                &storage,
+                dest.path(),
+                &FileSubPath::from_path("dir/a.txt").unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(path, Path::new("dir(1)/a.txt"));
+
+        // The directory gets created but the app is shut down before the file is
+        // marked as finished
+        std::fs::create_dir(dest.path().join("dir(1)")).unwrap();
+
+        let mut xfers = restore_incoming(&factory, &storage, &config, &logger).await;
+        let state = xfers.get_mut(&transfer_id).unwrap();
+
+        let path = state
+            .compose_final_path(
+                &storage,
+                dest.path(),
+                &FileSubPath::from_path("dir/b.txt").unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(path, Path::new("dir(1)/b.txt"));
+    }
+
+    #[tokio::test]
+    async fn listing_pending_incoming_files() {
+        let pending_id: Uuid = "23e488a4-0521-11ee-be56-0242ac120002".parse().unwrap();
+        let canceled_id: Uuid = "23e488a4-0521-11ee-be56-0242ac120003".parse().unwrap();
+
+        let (manager, _rx) = manager_with_incoming(&[
+            (pending_id, &["id1", "id2"]),
+            (canceled_id, &["id1", "id2"]),
+        ])
+        .await;
+
+        manager
+            .incoming_rejection_post(pending_id, &FileId::from("id2"))
+            .await
+            .unwrap();
+        manager.incoming_issue_close(canceled_id).await.unwrap();
+
+        assert_eq!(
+            manager.incoming_pending_files().await,
+            vec![(pending_id, vec![FileId::from("id1")])]
+        );
+    }
+
+    #[tokio::test]
+    async fn persisting_in_flight_offsets() {
+        use drop_storage::{
+            types::{DbTransferType, IncomingPathStateEventData},
+            TransferSort,
+        };
+
+        let transfer_id: Uuid = "23e488a4-0521-11ee-be56-0242ac120002".parse().unwrap();
+        let (manager, _rx) = manager_with_incoming(&[(transfer_id, &["id1", "id2"])]).await;
+        let storage = manager.storage.clone();
+
+        let mut lock = manager.incoming.lock().await;
+        let state = lock.get_mut(&transfer_id).unwrap();
+        state
+            .start_downloads(
+                &storage,
+                &[FileId::from("id1")],
+                Path::new("/recv"),
+                &HashMap::new(),
+                &manager.logger,
+            )
+            .await
+            .unwrap();
+
+        // The download is interrupted in the middle
+        let events = state.file_events(&FileId::from("id1")).unwrap().clone();
+        events.start("/recv", 0).await;
+        events.progress(512).await;
+        drop(lock);
+
+        manager.incoming_persist_offsets().await;
+
+        let transfers = storage.transfers_since(0, TransferSort::CreatedAsc).await;
+        let DbTransferType::Incoming(paths) = &transfers[0].transfer_type else {
+            panic!("Expected incoming transfer");
+        };
+
+        let paused: Vec<_> = paths
+            .iter()
+            .flat_map(|path| {
+                path.states.iter().filter_map(|state| match state.data {
+                    IncomingPathStateEventData::Paused { bytes_received } => {
+                        Some((path.file_id.as_str(), bytes_received))
+                    }
+                    _ => None,
+                })
+            })
+            .collect();
+        assert_eq!(paused, vec![("id1", 512)]);
+    }
+
+    #[tokio::test]
+    async fn rejecting_whole_incoming_transfer() {
+        let id: Uuid = "23e488a4-0521-11ee-be56-0242ac120002".parse().unwrap();
+        let (manager, _rx) = manager_with_incoming(&[(id, &["id1", "id2"])]).await;
+        let xfer = manager.incoming.lock().await[&id].xfer.clone();
+
+        let (conn, mut conn_rx) = tokio::sync::mpsc::unbounded_channel();
+        manager.register_incoming(xfer, conn, 0).await.unwrap();
+
+        manager
+            .incoming_rejection_post(id, &FileId::from("id2"))
+            .await
+            .unwrap();
+        assert!(matches!(
+            conn_rx.recv().await,
+            Some(ServerReq::Reject { file }) if file == FileId::from("id2")
+        ));
+
+        let res = manager
+            .incoming_reject_all(id, "Not expected")
+            .await
+            .unwrap();
+        let rejected: Vec<_> = res.file_events.iter().map(|ev| ev.file_id()).collect();
+        assert_eq!(rejected, vec![&FileId::from("id1")]);
+
+        // The reason is passed to the peer when closing the connection
+        assert!(matches!(
+            conn_rx.recv().await,
+            Some(ServerReq::Reject { file }) if file == FileId::from("id1")
+        ));
+        assert!(matches!(
+            conn_rx.recv().await,
+            Some(ServerReq::RejectTransfer { reason }) if reason == "Not expected"
+        ));
+
+        assert!(manager.incoming_pending_files().await.is_empty());
+        assert!(matches!(
+            manager.incoming_reject_all(id, "Not expected").await,
+            Err(crate::Error::BadTransfer)
+        ));
+    }
+
+    #[tokio::test]
+    async fn rate_limit_restored_after_restart() {
+        let id: Uuid = "23e488a4-0521-11ee-be56-0242ac120002".parse().unwrap();
+        let (manager, _rx) = manager_with_incoming(&[(id, &["id1"])]).await;
+        let storage = manager.storage.clone();
+        assert_eq!(manager.incoming.lock().await[&id].rate_limit.get(), None);
+
+        manager.set_rate_limit(id, Some(1000)).await.unwrap();
+        assert_eq!(storage.transfer_rate_limit(id).await, Some(1000));
+        assert!(matches!(
+            manager.set_rate_limit(Uuid::new_v4(), Some(1000)).await,
+            Err(crate::Error::BadTransfer)
+        ));
+
+        // Simulate the restart
+        let xfers = restore_incoming(
+            &manager.event_factory,
+            &storage,
+            &DropConfig::default(),
+            &manager.logger,
+        )
+        .await;
+        assert_eq!(xfers[&id].rate_limit.get(), Some(1000));
+    }
+
+    #[tokio::test]
+    async fn rejecting_many_incoming_files() {
+        let id: Uuid = "3b8b3e2c-0521-11ee-be56-0242ac120002".parse().unwrap();
+        let (manager, _rx) = manager_with_incoming(&[(id, &["id1", "id2", "id3"])]).await;
+
+        // An unknown file fails the whole batch
+        assert!(matches!(
+            manager
+                .incoming_reject_many(id, &["id1".into(), "unknown".into()])
+                .await,
+            Err(crate::Error::BadFileId)
+        ));
+
+        let res = manager
+            .incoming_reject_many(id, &["id1".into(), "id2".into()])
+            .await
+            .unwrap();
+        let rejected: Vec<_> = res.file_events.iter().map(|ev| ev.file_id()).collect();
+        assert_eq!(rejected, [&FileId::from("id1"), &FileId::from("id2")]);
+        assert!(res.closed.is_none());
+
+        // The already rejected file is skipped and the last one closes the
+        // transfer
+        let res = manager
+            .incoming_reject_many(id, &["id2".into(), "id3".into()])
+            .await
+            .unwrap();
+        let rejected: Vec<_> = res.file_events.iter().map(|ev| ev.file_id()).collect();
+        assert_eq!(rejected, [&FileId::from("id3")]);
+
+        let (_, stats) = res.closed.expect("The transfer should be closed");
+        assert_eq!(stats.rejected, 3);
+
+        assert!(manager.incoming_pending_files().await.is_empty());
+        assert!(matches!(
+            manager.incoming_reject_many(id, &["id1".into()]).await,
+            Err(crate::Error::BadTransfer)
+        ));
+    }
+
+    #[tokio::test]
+    async fn closed_transfer_stats() {
+        let id: Uuid = "23e488a4-0521-11ee-be56-0242ac120002".parse().unwrap();
+        let (manager, _rx) = manager_with_incoming(&[(id, &["id1", "id2"])]).await;
+
+        manager
+            .incoming_rejection_post(id, &FileId::from("id2"))
+            .await
+            .unwrap();
+
+        let res = manager.incoming_issue_close(id).await.unwrap();
+        assert_eq!(
+            res.stats,
+            TransferStats {
+                succeeded: 0,
+                failed: 0,
+                rejected: 1,
+                total_bytes: 0,
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn counting_incoming_reconnects() {
+        let transfer_id: Uuid = "23e488a4-0521-11ee-be56-0242ac120002".parse().unwrap();
+        let (manager, _rx) = manager_with_incoming(&[(transfer_id, &["id1"])]).await;
+        let xfer = manager.incoming.lock().await[&transfer_id].xfer.clone();
+
+        assert_eq!(
+            manager.connection_metrics(transfer_id).await,
+            Some(ConnectionMetrics::default())
+        );
+
+        // The first connection of the restored transfer is not a reconnect
+        for expected in [0, 1, 2] {
+            let (conn, _conn_rx) = tokio::sync::mpsc::unbounded_channel();
+            manager
+                .register_incoming(xfer.clone(), conn, 0)
+                .await
+                .unwrap();
+            manager.incoming_disconnect(transfer_id).await.unwrap();
+
+            let metrics = manager.connection_metrics(transfer_id).await.unwrap();
+            assert_eq!(metrics.reconnects, expected);
+        }
+
+        assert_eq!(manager.connection_metrics(Uuid::nil()).await, None);
+    }
+
+    #[tokio::test]
+    async fn counting_outgoing_reconnects() {
+        let config = DropConfig::default();
+        let (manager, _rx) = manager_with_incoming(&[]).await;
+
+        let file = FileToSend::from_bytes("a.txt".into(), Arc::new(b"abc".to_vec()), None);
+        let xfer = Arc::new(
+            OutgoingTransfer::new("1.2.3.4".parse().unwrap(), vec![file], &config).unwrap(),
+        );
+        let transfer_id = xfer.id();
+        manager.insert_outgoing(xfer).await.unwrap();
+
+        // The failed attempts are not reconnects
+        manager.outgoing_connect_failed(transfer_id).await;
+        manager.outgoing_connect_failed(transfer_id).await;
+
+        for expected in [0, 1] {
+            let (conn, _conn_rx) = tokio::sync::mpsc::unbounded_channel();
+            manager.outgoing_connected(transfer_id, conn).await.unwrap();
+            manager.outgoing_disconnect(transfer_id).await.unwrap();
+
+            let metrics = manager.connection_metrics(transfer_id).await.unwrap();
+            assert_eq!(metrics.reconnects, expected);
+            assert_eq!(metrics.failed_attempts, 2);
+        }
+
+        manager.outgoing_connect_failed(transfer_id).await;
+        let metrics = manager.connection_metrics(transfer_id).await.unwrap();
+        assert_eq!(metrics.failed_attempts, 3);
+    }
+
+    #[tokio::test]
+    async fn receiving_outgoing_file_selection() {
+        let config = DropConfig::default();
+        let (manager, _rx) = manager_with_incoming(&[]).await;
+
+        let files: Vec<_> = ["a.txt", "b.txt", "c.txt"]
+            .into_iter()
+            .map(|name| FileToSend::from_bytes(name.into(), Arc::new(b"abc".to_vec()), None))
+            .collect();
+        let ids: Vec<_> = files.iter().map(|file| file.id().clone()).collect();
+        let xfer =
+            Arc::new(OutgoingTransfer::new("1.2.3.4".parse().unwrap(), files, &config).unwrap());
+        let transfer_id = xfer.id();
+        manager.insert_outgoing(xfer).await.unwrap();
+
+        manager
+            .outgoing_terminal_recv(transfer_id, &ids[2], FileTerminalState::Completed)
+            .await
+            .unwrap();
+
+        // Only the unfinished files left out of the selection are rejected
+        let rejected = manager
+            .outgoing_selection_recv(transfer_id, &ids[..1])
+            .await
+            .unwrap();
+        let rejected: Vec<_> = rejected.iter().map(|ev| ev.file_id()).collect();
+        assert_eq!(rejected, vec![&ids[1]]);
+
+        assert!(matches!(
+            manager
+                .outgoing_ensure_file_not_terminated(transfer_id, &ids[1])
+                .await,
+            Err(crate::Error::FileStateMismatch(FileTerminalState::Rejected))
+        ));
+        assert!(manager
+            .outgoing_ensure_file_not_terminated(transfer_id, &ids[0])
+            .await
+            .is_ok());
+
+        assert!(manager
+            .outgoing_selection_recv(transfer_id, &ids[..1])
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn explaining_stalled_incoming_transfer() {
+        use crate::{clock::MockClock, Clock};
+
+        let clock = MockClock::new(SystemTime::UNIX_EPOCH);
+
+        let transfer_id: Uuid = "23e488a4-0521-11ee-be56-0242ac120002".parse().unwrap();
+        let (manager, _rx) = manager_with_incoming(&[(transfer_id, &["id1"])]).await;
+        let xfer = manager.incoming.lock().await[&transfer_id].xfer.clone();
+
+        let stall_reason = || async {
+            let lock = manager.incoming.lock().await;
+            lock[&transfer_id].stall_reason(clock.instant()).await
+        };
+
+        assert_eq!(stall_reason().await, Some(StallReason::AwaitingResume));
+
+        manager
+            .incoming
+            .lock()
+            .await
+            .get_mut(&transfer_id)
+            .unwrap()
+            .resumed = true;
+        assert_eq!(
+            stall_reason().await,
+            Some(StallReason::WaitingForPeerOnline)
+        );
+
+        let (conn, _conn_rx) = tokio::sync::mpsc::unbounded_channel();
+        manager
+            .register_incoming(xfer.clone(), conn, 0)
+            .await
+            .unwrap();
+        assert_eq!(
+            stall_reason().await,
+            Some(StallReason::AwaitingUserDecision)
+        );
+
+        let (events, rate_limit) = {
+            let lock = manager.incoming.lock().await;
+            let state = &lock[&transfer_id];
+            (
+                state.file_events[&FileId::from("id1")].clone(),
+                state.rate_limit.clone(),
+            )
+        };
+
+        events.preflight().await;
+        assert_eq!(stall_reason().await, Some(StallReason::Verifying));
+
+        events.start("/tmp", 0).await;
+        assert_eq!(stall_reason().await, None);
+
+        rate_limit.set(Some(100));
+        let pacing = rate_limit.consume(100, &clock);
+        tokio::pin!(pacing);
+        assert!(futures::poll!(&mut pacing).is_pending());
+        assert_eq!(stall_reason().await, Some(StallReason::RateLimited));
+
+        manager.incoming_disconnect(transfer_id).await.unwrap();
+        assert_eq!(
+            stall_reason().await,
+            Some(StallReason::WaitingForPeerOnline)
+        );
+    }
+
+    #[tokio::test]
+    async fn resuming_restored_transfers() {
+        use drop_storage::types::{TransferFiles, TransferIncomingPath};
+
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+        let (tx, _rx) = tokio::sync::mpsc::unbounded_channel();
+        let state = Arc::new(ws::server::tests::mock_state(
+            &logger,
+            Arc::new(DropConfig::default()),
+            tx,
+        ));
+
+        let ids: [Uuid; 2] = [
+            "23e488a4-0521-11ee-be56-0242ac120002".parse().unwrap(),
+            "23e488a4-0521-11ee-be56-0242ac120003".parse().unwrap(),
+        ];
+        for id in ids {
+            state
+                .storage
+                .insert_transfer(&drop_storage::TransferInfo {
+                    id,
+                    peer: "1.2.3.4".to_string(),
+                    files: TransferFiles::Incoming(vec![TransferIncomingPath {
+                        file_id: "id1".to_string(),
+                        relative_path: "a.txt".to_string(),
+                        size: 1024,
+                    }]),
+                })
+                .await;
+        }
+
+        restore_transfers_state(&state, &logger).await;
+
+        let status = |id| {
+            let state = state.clone();
+            async move { state.transfer_manager.incoming.lock().await[&id].status() }
+        };
+        for id in ids {
+            assert_eq!(status(id).await, TransferStatus::Restored);
+        }
+
+        // The jobs quit right away
+        let stop = CancellationToken::new();
+        stop.cancel();
+        let trigger = tokio::sync::watch::channel(()).1;
+        let guard = crate::tasks::AliveWaiter::new().guard();
+
+        let resumed = resume(&trigger, &state, &logger, &guard, &stop, Some(ids[0])).await;
+        assert_eq!(resumed, [ids[0]]);
+        assert_eq!(status(ids[0]).await, TransferStatus::Deferred);
+        assert_eq!(status(ids[1]).await, TransferStatus::Restored);
+
+        let resumed = resume(&trigger, &state, &logger, &guard, &stop, None).await;
+        assert_eq!(resumed, [ids[1]]);
+        assert_eq!(status(ids[1]).await, TransferStatus::Deferred);
+    }
+
+    #[tokio::test]
+    async fn retrying_failed_files() {
+        use drop_storage::types::{TransferFiles, TransferIncomingPath};
+
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+        let (tx, _rx) = tokio::sync::mpsc::unbounded_channel();
+        let state = Arc::new(ws::server::tests::mock_state(
+            &logger,
+            Arc::new(DropConfig::default()),
+            tx,
+        ));
+
+        let transfer_id: Uuid = "23e488a4-0521-11ee-be56-0242ac120002".parse().unwrap();
+        let path = |file_id: &str| TransferIncomingPath {
+            file_id: file_id.to_string(),
+            relative_path: format!("{file_id}.txt"),
+            size: 1024,
+        };
+        state
+            .storage
+            .insert_transfer(&drop_storage::TransferInfo {
+                id: transfer_id,
+                peer: "1.2.3.4".to_string(),
+                files: TransferFiles::Incoming(vec![path("id1"), path("id2")]),
+            })
+            .await;
+
+        state
+            .storage
+            .insert_incoming_path_failed_state(transfer_id, "id1", 1, 100)
+            .await;
+        state
+            .storage
+            .update_incoming_file_sync_states(transfer_id, "id1", sync::FileState::Terminal)
+            .await;
+
+        restore_transfers_state(&state, &logger).await;
+
+        let manager = &state.transfer_manager;
+        let failed = FileId::from("id1");
+        assert!(matches!(
+            manager.incoming.lock().await[&transfer_id].validate_for_download(&failed),
+            Err(crate::Error::FileStateMismatch(FileTerminalState::Failed))
+        ));
+
+        assert_eq!(manager.retry_failed_files(transfer_id).await.unwrap(), 1);
+        assert!(manager.incoming.lock().await[&transfer_id]
+            .validate_for_download(&failed)
+            .unwrap());
+        assert!(matches!(
+            state
+                .storage
+                .incoming_file_sync_state(transfer_id, "id1")
+                .await
+                .unwrap()
+                .sync,
+            sync::FileState::Alive
+        ));
+
+        assert_eq!(manager.retry_failed_files(transfer_id).await.unwrap(), 0);
+        assert!(matches!(
+            manager.retry_failed_files(Uuid::nil()).await,
+            Err(crate::Error::BadTransfer)
+        ));
+    }
+
+    #[tokio::test]
+    async fn pausing_all_transfers() {
+        let transfer_id: Uuid = "23e488a4-0521-11ee-be56-0242ac120002".parse().unwrap();
+        let (manager, _rx) = manager_with_incoming(&[(transfer_id, &["id1"])]).await;
+        let xfer = manager.incoming.lock().await[&transfer_id].xfer.clone();
+
+        let (conn, mut conn_rx) = tokio::sync::mpsc::unbounded_channel();
+        manager.register_incoming(xfer, conn, 0).await.unwrap();
+
+        assert_eq!(manager.pause_all().await, [transfer_id]);
+        assert!(matches!(conn_rx.recv().await, Some(ServerReq::Pause)));
+        // The connection is forgotten
+        assert!(conn_rx.recv().await.is_none());
+
+        // Pausing again does not touch the transfer connections anymore
+        assert_eq!(manager.pause_all().await, [transfer_id]);
+        assert_eq!(manager.transfer_ids().await, [transfer_id]);
+    }
+
+    #[tokio::test]
+    async fn limiting_transfers_per_peer() {
+        let config = DropConfig::default();
+        let (manager, _rx) = manager_with_incoming(&[]).await;
+
+        let new_xfer = |peer: &str| {
+            Arc::new(
+                IncomingTransfer::new(
+                    peer.parse().unwrap(),
+                    vec![FileToRecv::new("id1".into(), "a.txt".into(), 1024)],
+                    &config,
+                )
+                .unwrap(),
+            )
+        };
+
+        for _ in 0..2 {
+            let (conn, _conn_rx) = tokio::sync::mpsc::unbounded_channel();
+            let xfer = new_xfer("1.2.3.4");
+            let res = manager.register_incoming(xfer, conn, 2).await.unwrap();
+            assert!(res.is_some());
+        }
+
+        let (conn, mut conn_rx) = tokio::sync::mpsc::unbounded_channel();
+        let xfer = new_xfer("1.2.3.4");
+        let res = manager.register_incoming(xfer.clone(), conn, 2).await;
+        assert!(res.unwrap().is_none());
+        assert!(matches!(conn_rx.recv().await, Some(ServerReq::Close)));
+        assert!(manager.incoming.lock().await.get(&xfer.id()).is_none());
+
+        // Other peers are not affected
+        let (conn, _conn_rx) = tokio::sync::mpsc::unbounded_channel();
+        let res = manager
+            .register_incoming(new_xfer("5.6.7.8"), conn, 2)
+            .await;
+        assert!(res.unwrap().is_some());
+
+        // Zero means no limit
+        let (conn, _conn_rx) = tokio::sync::mpsc::unbounded_channel();
+        let res = manager
+            .register_incoming(new_xfer("1.2.3.4"), conn, 0)
+            .await;
+        assert!(res.unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn abandoning_file_uploads() {
+        let config = DropConfig::default();
+        let (manager, _rx) = manager_with_incoming(&[]).await;
+
+        let file = FileToSend::from_bytes("a.txt".into(), Arc::new(b"abc".to_vec()), None);
+        let file_id = file.id().clone();
+        let xfer = Arc::new(
+            OutgoingTransfer::new("1.2.3.4".parse().unwrap(), vec![file], &config).unwrap(),
+        );
+        let transfer_id = xfer.id();
+        manager.insert_outgoing(xfer).await.unwrap();
+
+        let attempt = |max| manager.outgoing_upload_attempt(transfer_id, &file_id, max);
+
+        // No limit
+        for _ in 0..5 {
+            attempt(None).await.unwrap();
+        }
+        assert!(matches!(
+            attempt(Some(2)).await,
+            Err(crate::Error::RetriesExceeded(5))
+        ));
+
+        // The progress resets the counter
+        manager
+            .outgoing_upload_progress(transfer_id, &file_id)
+            .await;
+        for _ in 0..3 {
+            attempt(Some(2)).await.unwrap();
+        }
+        assert!(matches!(
+            attempt(Some(2)).await,
+            Err(crate::Error::RetriesExceeded(3))
+        ));
+    }
+
+    #[tokio::test]
+    async fn restoring_outgoing_zone() {
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+        let config = Arc::new(DropConfig::default());
+        let (tx, _rx) = tokio::sync::mpsc::unbounded_channel();
+        let state = Arc::new(ws::server::tests::mock_state(&logger, config.clone(), tx));
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("a.txt");
+        std::fs::write(&path, b"abc").unwrap();
+
+        let file = FileToSend::new("a.txt".into(), path, 3, "id1".into());
+        let xfer = OutgoingTransfer::new("fe80::1".parse().unwrap(), vec![file], &config)
+            .unwrap()
+            .with_zone(Some("2".into()));
+        let transfer_id = xfer.id();
+        state
+            .transfer_manager
+            .insert_outgoing(Arc::new(xfer))
+            .await
+            .unwrap();
+
+        // The zone survives the restart as it was given
+        restore_transfers_state(&state, &logger).await;
+        let xfer = state.transfer_manager.outgoing.lock().await[&transfer_id]
+            .xfer
+            .clone();
+        assert_eq!(xfer.zone(), Some("2"));
+
+        let remotes: Vec<_> = xfer.peer_candidates(drop_config::PORT).collect();
+        assert!(matches!(remotes[..], [std::net::SocketAddr::V6(addr)] if addr.scope_id() == 2));
+    }
+
+    #[tokio::test]
+    async fn expiring_idle_incoming_offers() {
+        let config = DropConfig::default();
+        let (manager, _rx) = manager_with_incoming(&[]).await;
+
+        let new_xfer = || {
+            Arc::new(
+                IncomingTransfer::new(
+                    "1.2.3.4".parse().unwrap(),
+                    vec![
+                        FileToRecv::new("id1".into(), "a.txt".into(), 1024),
+                        FileToRecv::new("id2".into(), "b.txt".into(), 1024),
+                    ],
+                    &config,
+                )
+                .unwrap(),
+            )
+        };
+
+        // All of the files idle, the whole transfer is rejected and removed
+        let (conn, mut conn_rx) = tokio::sync::mpsc::unbounded_channel();
+        let xfer = new_xfer();
+        manager
+            .register_incoming(xfer.clone(), conn, 0)
+            .await
+            .unwrap();
+
+        let res = manager.incoming_expire(xfer.id()).await.unwrap();
+        assert_eq!(res.file_events.len(), 2);
+        assert_eq!(res.stats.rejected, 2);
+        assert!(!manager.is_incoming_alive(xfer.id()).await);
+
+        let mut rejected = 0;
+        while let Ok(req) = conn_rx.try_recv() {
+            match req {
+                ServerReq::Reject { .. } => rejected += 1,
+                ServerReq::Close => break,
+                _ => panic!("Unexpected request"),
+            }
+        }
+        assert_eq!(rejected, 2);
+
+        assert!(manager.incoming_expire(xfer.id()).await.is_none());
+
+        // A file was acted upon, the transfer stays
+        let (conn, _conn_rx) = tokio::sync::mpsc::unbounded_channel();
+        let xfer = new_xfer();
+        manager
+            .register_incoming(xfer.clone(), conn, 0)
+            .await
+            .unwrap();
+        manager
+            .incoming_rejection_post(xfer.id(), &FileId::from("id1"))
+            .await
+            .unwrap();
+
+        assert!(manager.incoming_expire(xfer.id()).await.is_none());
+        assert!(manager.is_incoming_alive(xfer.id()).await);
+    }
+
+    #[tokio::test]
+    async fn conflicting_transfer_uuid() {
+        let config = DropConfig::default();
+        let (manager, _rx) = manager_with_incoming(&[]).await;
+
+        let id = Uuid::new_v4();
+        let new_xfer = |peer: &str| {
+            Arc::new(
+                IncomingTransfer::new_with_uuid(
+                    peer.parse().unwrap(),
+                    vec![FileToRecv::new("id1".into(), "a.txt".into(), 1024)],
+                    id,
+                    &config,
+                )
+                .unwrap(),
+            )
+        };
+
+        let (conn, _conn_rx) = tokio::sync::mpsc::unbounded_channel();
+        assert!(manager
+            .register_incoming(new_xfer("1.2.3.4"), conn, 0)
+            .await
+            .unwrap()
+            .is_some());
+
+        let (conn, _conn_rx) = tokio::sync::mpsc::unbounded_channel();
+        let Err(err) = manager
+            .register_incoming(new_xfer("5.6.7.8"), conn, 0)
+            .await
+        else {
+            panic!("The conflicting transfer should be rejected");
+        };
+
+        assert!(matches!(
+            err.downcast_ref::<crate::Error>(),
+            Some(crate::Error::TransferUuidConflict { existing, current })
+                if *existing == "1.2.3.4".parse::<std::net::IpAddr>().unwrap()
+                    && *current == "5.6.7.8".parse::<std::net::IpAddr>().unwrap()
+        ));
+
+        // The original transfer is left intact
+        let lock = manager.incoming.lock().await;
+        assert_eq!(lock[&id].xfer.peer().to_string(), "1.2.3.4");
+    }
+
+    #[tokio::test]
+    async fn dropping_connection_on_request() {
+        let config = DropConfig::default();
+        let (manager, _rx) = manager_with_incoming(&[]).await;
+
+        let xfer = Arc::new(
+            IncomingTransfer::new(
+                "1.2.3.4".parse().unwrap(),
+                vec![FileToRecv::new("id1".into(), "a.txt".into(), 1024)],
+                &config,
+            )
+            .unwrap(),
+        );
+        let (conn, _conn_rx) = tokio::sync::mpsc::unbounded_channel();
+        manager
+            .register_incoming(xfer.clone(), conn, 0)
+            .await
+            .unwrap();
+
+        let conn_drop = manager.incoming_conn_drop(xfer.id()).await.unwrap();
+        let dropped = conn_drop.notified();
+        tokio::pin!(dropped);
+        dropped.as_mut().enable();
+
+        manager.drop_connection(xfer.id()).await.unwrap();
+        tokio::time::timeout(std::time::Duration::from_secs(1), dropped)
+            .await
+            .expect("The connection loop should be woken");
+
+        // The transfer is kept so that it resumes on reconnection
+        assert!(manager.is_incoming_alive(xfer.id()).await);
+
+        assert!(matches!(
+            manager.drop_connection(Uuid::new_v4()).await,
+            Err(crate::Error::BadTransfer)
+        ));
+    }
+
+    #[tokio::test]
+    async fn restoring_missing_completed_files() {
+        use drop_storage::types::{TransferFiles, TransferIncomingPath};
+
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+        let storage = Storage::new(logger.clone(), ":memory:").unwrap();
+        let mut config = DropConfig::default();
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        let factory = EventTxFactory::new(
+            tx,
+            drop_analytics::moose_mock(),
+            config.progress_events_granularity,
+        );
+
+        let id: Uuid = "23e488a4-0521-11ee-be56-0242ac120002".parse().unwrap();
+        storage
+            .insert_transfer(&drop_storage::TransferInfo {
+                id,
+                peer: "1.2.3.4".to_string(),
+                files: TransferFiles::Incoming(vec![
+                    TransferIncomingPath {
+                        file_id: "id1".to_string(),
+                        relative_path: "a.txt".to_string(),
+                        size: 1024,
+                    },
+                    TransferIncomingPath {
+                        file_id: "id2".to_string(),
+                        relative_path: "b.txt".to_string(),
+                        size: 1024,
+                    },
+                ]),
+            })
+            .await;
+
+        let dest = tempfile::tempdir().unwrap();
+        let final_path = dest.path().join("a.txt");
+        storage
+            .insert_incoming_path_completed_state(
+                id,
+                "id1",
+                &final_path.to_string_lossy(),
+                false,
+                false,
+            )
+            .await;
+        storage
+            .update_incoming_file_sync_states(id, "id1", sync::FileState::Terminal)
+            .await;
+        // Removed by the post completion hook
+        storage
+            .insert_incoming_path_completed_state(
+                id,
+                "id2",
+                &dest.path().join("b.txt").to_string_lossy(),
+                false,
+                true,
+            )
+            .await;
+        storage
+            .update_incoming_file_sync_states(id, "id2", sync::FileState::Terminal)
+            .await;
+
+        let mut missing = || {
+            let mut out = Vec::new();
+            while let Ok((event, _)) = rx.try_recv() {
+                if let crate::Event::FileDownloadMissing {
+                    file_id,
+                    final_path,
+                    ..
+                } = event
+                {
+                    out.push((file_id, final_path.0.into_path_buf()));
+                }
+            }
+            out
+        };
+        let file_id = FileId::from("id1");
+
+        // The file is there
+        std::fs::write(&final_path, b"data").unwrap();
+        let xfers = restore_incoming(&factory, &storage, &config, &logger).await;
+        assert!(xfers[&id].validate_for_download(&file_id).is_err());
+        assert!(missing().is_empty());
+
+        // Removed by the user, only reported by default
+        std::fs::remove_file(&final_path).unwrap();
+        let xfers = restore_incoming(&factory, &storage, &config, &logger).await;
+        assert!(matches!(
+            xfers[&id].validate_for_download(&file_id),
+            Err(crate::Error::FileStateMismatch(
+                FileTerminalState::Completed
+            ))
+        ));
+        assert_eq!(missing(), [(file_id.clone(), final_path.clone())]);
+
+        config.redownload_missing = true;
+        let xfers = restore_incoming(&factory, &storage, &config, &logger).await;
+        assert!(xfers[&id].validate_for_download(&file_id).unwrap());
+        assert_eq!(missing(), [(file_id.clone(), final_path)]);
+        assert!(xfers[&id]
+            .validate_for_download(&FileId::from("id2"))
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn redirecting_incoming_downloads() {
+        let id: Uuid = "23e488a4-0521-11ee-be56-0242ac120002".parse().unwrap();
+        let (manager, _rx) = manager_with_incoming(&[(id, &["id1", "id2"])]).await;
+        let storage = manager.storage.clone();
+
+        let old_dir = tempfile::tempdir().unwrap();
+        let new_dir = tempfile::tempdir().unwrap();
+        let new_base = new_dir.path().join("downloads");
+
+        let file_id = FileId::from("id1");
+        manager
+            .incoming
+            .lock()
+            .await
+            .get_mut(&id)
+            .unwrap()
+            .start_downloads(
+                &storage,
+                std::slice::from_ref(&file_id),
+                old_dir.path(),
+                &HashMap::new(),
+                &manager.logger,
+            )
+            .await
+            .unwrap();
+
+        let tmp_name = ws::server::temp_file_name(id, &file_id);
+        std::fs::write(old_dir.path().join(&tmp_name), b"partial").unwrap();
+
+        let redirected = manager.incoming_redirect(id, &new_base).await.unwrap();
+        assert_eq!(redirected, vec![file_id.clone()]);
+
+        assert!(!old_dir.path().join(&tmp_name).exists());
+        assert_eq!(std::fs::read(new_base.join(&tmp_name)).unwrap(), b"partial");
+
+        let resumed = storage.incoming_files_to_resume(id).await;
+        assert_eq!(resumed.len(), 1);
+        assert_eq!(resumed[0].file_id, "id1");
+        assert_eq!(resumed[0].base_dir, new_base.to_string_lossy());
+
+        // Redirecting into the same directory is a no-op
+        let redirected = manager.incoming_redirect(id, &new_base).await.unwrap();
+        assert!(redirected.is_empty());
+
+        // The running download keeps its directory until it's paused
+        let (conn, mut reqs) = tokio::sync::mpsc::unbounded_channel();
+        manager.incoming.lock().await.get_mut(&id).unwrap().conn = Some(conn);
+
+        let newer_base = new_dir.path().join("newer");
+        let redirected = manager.incoming_redirect(id, &newer_base).await.unwrap();
+        assert_eq!(redirected, vec![file_id.clone()]);
+        assert!(matches!(
+            reqs.try_recv().unwrap(),
+            ServerReq::Redirect { task } if *task.base_dir == newer_base
+        ));
+
+        assert!(new_base.join(&tmp_name).exists());
+        let resumed = storage.incoming_files_to_resume(id).await;
+        assert_eq!(resumed[0].base_dir, new_base.to_string_lossy());
+
+        manager
+            .incoming_redirected(id, &file_id, &newer_base)
+            .await
+            .unwrap();
+
+        let resumed = storage.incoming_files_to_resume(id).await;
+        assert_eq!(resumed.len(), 1);
+        assert_eq!(resumed[0].base_dir, newer_base.to_string_lossy());
+        assert!(matches!(
+            &manager.incoming.lock().await[&id].file_sync[&file_id],
+            IncomingLocalFileState::InFlight { path, .. } if *path == newer_base
+        ));
+    }
+
+    #[tokio::test]
+    async fn downloading_restarted_file_again() {
+        let id: Uuid = "23e488a4-0521-11ee-be56-0242ac120002".parse().unwrap();
+        let (manager, _rx) = manager_with_incoming(&[(id, &["id1", "id2"])]).await;
+        let storage = manager.storage.clone();
+        let dir = tempfile::tempdir().unwrap();
+
+        let (conn, mut reqs) = tokio::sync::mpsc::unbounded_channel();
+        manager.incoming.lock().await.get_mut(&id).unwrap().conn = Some(conn);
+
+        manager
+            .incoming
+            .lock()
+            .await
+            .get_mut(&id)
+            .unwrap()
+            .start_downloads(
+                &storage,
+                &["id1".into()],
+                dir.path(),
+                &HashMap::new(),
+                &manager.logger,
+            )
+            .await
+            .unwrap();
+        assert!(matches!(reqs.try_recv(), Ok(ServerReq::Download { .. })));
+
+        // Only the restarted file is requested, into its directory
+        manager
+            .incoming_download_again(id, &"id1".into())
+            .await
+            .unwrap();
+        assert!(matches!(
+            reqs.try_recv().unwrap(),
+            ServerReq::Download { task }
+                if task.file.id().as_ref() == "id1" && *task.base_dir == dir.path()
+        ));
+        assert!(reqs.try_recv().is_err());
+
+        // The files not being downloaded are not requested
+        assert!(manager
+            .incoming_download_again(id, &"id2".into())
+            .await
+            .is_err());
+        assert!(manager
+            .incoming_download_again(id, &"id3".into())
+            .await
+            .is_err());
+        assert!(reqs.try_recv().is_err());
+    }
 }
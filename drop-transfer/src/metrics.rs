@@ -0,0 +1,144 @@
+use std::{
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Instant,
+};
+
+use drop_analytics::TransferDirection;
+
+// Weight of the latest throughput sample in the per file moving average
+const RATE_SMOOTHING: f64 = 0.3;
+
+/// Service-wide counters, updated where the progress and connection events
+/// happen
+#[derive(Default)]
+pub(crate) struct Metrics {
+    bytes_transferred: AtomicU64,
+    upload_rate: AtomicU64,
+    download_rate: AtomicU64,
+    connections: AtomicUsize,
+    rate_limited: AtomicU64,
+}
+
+/// Keeps the connection counted for as long as it's alive
+pub(crate) struct ConnectionGuard(Arc<Metrics>);
+
+impl Metrics {
+    pub fn bytes_transferred(&self) -> u64 {
+        self.bytes_transferred.load(Ordering::Relaxed)
+    }
+
+    pub fn upload_rate(&self) -> u64 {
+        self.upload_rate.load(Ordering::Relaxed)
+    }
+
+    pub fn download_rate(&self) -> u64 {
+        self.download_rate.load(Ordering::Relaxed)
+    }
+
+    pub fn connections(&self) -> usize {
+        self.connections.load(Ordering::Relaxed)
+    }
+
+    pub fn rate_limited(&self) -> u64 {
+        self.rate_limited.load(Ordering::Relaxed)
+    }
+
+    pub fn add_bytes(&self, bytes: u64) {
+        self.bytes_transferred.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub fn add_rate_limited(&self) {
+        self.rate_limited.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn connected(self: &Arc<Self>) -> ConnectionGuard {
+        self.connections.fetch_add(1, Ordering::Relaxed);
+        ConnectionGuard(self.clone())
+    }
+
+    // Replaces the file's contribution to the total throughput of its direction
+    fn replace_rate(&self, direction: TransferDirection, old: u64, new: u64) {
+        let rate = match direction {
+            TransferDirection::Upload => &self.upload_rate,
+            TransferDirection::Download => &self.download_rate,
+        };
+
+        // Wrapping arithmetic makes the addition of a negative difference work
+        rate.fetch_add(new.wrapping_sub(old), Ordering::Relaxed);
+    }
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.0.connections.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Throughput of a single file, contributing to the service-wide counters
+pub(crate) struct FileRate {
+    metrics: Arc<Metrics>,
+    direction: TransferDirection,
+    rate: u64,
+    last_sample: Option<(Instant, u64)>,
+}
+
+impl FileRate {
+    pub fn new(metrics: Arc<Metrics>, direction: TransferDirection) -> Self {
+        Self {
+            metrics,
+            direction,
+            rate: 0,
+            last_sample: None,
+        }
+    }
+
+    /// Starts measuring from the given offset
+    pub fn start(&mut self, offset: u64) {
+        self.stop();
+        self.last_sample = Some((Instant::now(), offset));
+    }
+
+    /// Records the file progress, `transferred` being the total bytes of the
+    /// file received or sent so far
+    pub fn progress(&mut self, transferred: u64) {
+        let now = Instant::now();
+
+        let Some((at, bytes)) = self.last_sample.replace((now, transferred)) else {
+            return;
+        };
+
+        let delta = transferred.saturating_sub(bytes);
+        self.metrics.add_bytes(delta);
+
+        let elapsed = now.duration_since(at).as_secs_f64();
+        if elapsed <= 0.0 {
+            return;
+        }
+
+        let sample = delta as f64 / elapsed;
+        let rate = if self.rate == 0 {
+            sample
+        } else {
+            self.rate as f64 * (1.0 - RATE_SMOOTHING) + sample * RATE_SMOOTHING
+        } as u64;
+
+        self.metrics.replace_rate(self.direction, self.rate, rate);
+        self.rate = rate;
+    }
+
+    /// Withdraws the file from the throughput once it's no longer in flight
+    pub fn stop(&mut self) {
+        self.metrics.replace_rate(self.direction, self.rate, 0);
+        self.rate = 0;
+        self.last_sample = None;
+    }
+}
+
+impl Drop for FileRate {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
@@ -5,7 +5,7 @@ pub mod v2 {
 pub mod v4;
 pub mod v6;
 
-#[derive(Copy, Clone, strum::Display, strum::EnumString)]
+#[derive(Copy, Clone, PartialEq, Eq, strum::Display, strum::EnumString)]
 pub enum Version {
     #[strum(serialize = "v1")]
     V1,
@@ -19,6 +19,65 @@ pub enum Version {
     V5,
     #[strum(serialize = "v6")]
     V6,
+    #[strum(serialize = "v7")]
+    V7,
+    #[strum(serialize = "v8")]
+    V8,
+    #[strum(serialize = "v9")]
+    V9,
+    #[strum(serialize = "v10")]
+    V10,
+    #[strum(serialize = "v11")]
+    V11,
+    #[strum(serialize = "v12")]
+    V12,
+}
+
+impl Version {
+    pub const LATEST: Self = Self::V12;
+
+    /// The versions without the client authentication
+    pub fn is_legacy(self) -> bool {
+        matches!(self, Self::V1 | Self::V2)
+    }
+
+    /// The versions exchanging the display only information about the peers
+    pub fn has_peer_info(self) -> bool {
+        matches!(
+            self,
+            Self::V8 | Self::V9 | Self::V10 | Self::V11 | Self::V12
+        )
+    }
+
+    /// The versions receiving the reason of the whole transfer rejection
+    pub fn has_rejection_reason(self) -> bool {
+        matches!(
+            self,
+            Self::V6 | Self::V7 | Self::V8 | Self::V9 | Self::V10 | Self::V11 | Self::V12
+        )
+    }
+
+    /// The versions able to send the files of unknown size
+    pub fn has_streamed_files(self) -> bool {
+        matches!(self, Self::V9 | Self::V10 | Self::V11 | Self::V12)
+    }
+
+    /// The versions able to encrypt the file contents
+    pub fn has_encrypted_contents(self) -> bool {
+        matches!(self, Self::V10 | Self::V11 | Self::V12)
+    }
+
+    /// The versions in which the receiver requests again the file the sender
+    /// stopped streaming
+    pub fn has_file_restart(self) -> bool {
+        matches!(self, Self::V11 | Self::V12)
+    }
+
+    /// The versions in which the receiver lists the files it wants, the
+    /// sender rejecting the rest of them on its side
+    pub fn has_file_selection(self) -> bool {
+        matches!(self, Self::V12)
+    }
 }
 
 impl From<Version> for i32 {
@@ -29,6 +88,12 @@ impl From<Version> for i32 {
             Version::V4 => 4,
             Version::V5 => 5,
             Version::V6 => 6,
+            Version::V7 => 7,
+            Version::V8 => 8,
+            Version::V9 => 9,
+            Version::V10 => 10,
+            Version::V11 => 11,
+            Version::V12 => 12,
         }
     }
 }
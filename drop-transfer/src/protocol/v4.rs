@@ -82,15 +82,19 @@ mod tests {
                     File {
                         path: "dir/a.txt".into(),
                         id: "ID1".into(),
-                        size: 41,
+                        size: Some(41),
+                        checksum: None,
                     },
                     File {
                         path: "dir/b.txt".into(),
                         id: "ID2".into(),
-                        size: 4141,
+                        size: Some(4141),
+                        checksum: None,
                     },
                 ],
                 id: uuid::uuid!("1b0397eb-66e9-4252-b7cf-71782698ee3d"),
+                info: None,
+                encrypted: false,
             },
             r#"
             {
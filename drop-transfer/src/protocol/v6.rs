@@ -8,6 +8,12 @@
 //! * server (receiver) ->   client (sender): `ReqChsum (file)`
 //! * client (sender)   -> server (receiver): `ReportChsum (file)`
 //!
+//! Since v7, when resuming the partially downloaded file, the server can
+//! request the checksums of consecutive blocks of the file instead. That way
+//! only the part following the first mismatching block is downloaded again
+//! * server (receiver) ->   client (sender): `ReqBlockChsums (file)`
+//! * client (sender)   -> server (receiver): `ReportBlockChsums (file)`
+//!
 //! If the server needs to download something:
 //! * server (receiver) ->   client (sender): `Start (file)`
 //! * client (sender)   -> server (receiver): `Chunk (file)`
@@ -23,6 +29,33 @@
 //! * client (receiver) ->   server (sender): `Reject (file)`
 //! The operation cannot be undone and subsequest downloads of this file
 //! will result in error
+//!
+//! Since v8, the client attaches its `PeerInfo` to the `TransferRequest` and
+//! the server responds with its own right after receiving the request. The
+//! information is for display only and is not verified in any way
+//! * server (receiver) ->   client (sender): `PeerInfo`
+//!
+//! The client may attach the full checksum to any of the files in the
+//! `TransferRequest`. The server then uses it instead of requesting the
+//! checksum with `ReqChsum`. Servers unaware of the field ignore it
+//!
+//! Since v9, the size of the file can be `null` when it's not known up front,
+//! e.g. when the file is read from a pipe. Such a file is always sent from its
+//! beginning and the client marks its end with the full checksum right after
+//! the last chunk. Its checksum can't be requested with `ReqChsum`
+//! * client (sender)   -> server (receiver): `End (file)`
+//!
+//! Since v10, the client may set the `encrypted` flag of the
+//! `TransferRequest`. The data of every `Chunk` is then sealed with
+//! ChaCha20-Poly1305 using the key derived from both peers' keys and the
+//! transfer ID. The sealed data is the random nonce followed by the ciphertext
+//! and the tag. The file ID and the offset of the chunk within the file are
+//! the associated data, so a chunk cannot be moved to another place
+//!
+//! Since v12, the server can list the files it wants out of the transfer. The
+//! client rejects the rest of the files that are not finished yet, the same as
+//! if each of them was rejected with `Reject`, and streams only the listed ones
+//! * server (receiver) ->   client (sender): `Select (files)`
 
 use anyhow::Context;
 use serde::{Deserialize, Serialize};
@@ -37,13 +70,61 @@ use crate::{
 pub struct File {
     pub path: FileSubPath,
     pub id: FileId,
-    pub size: u64,
+    // `None` for the streamed files, since v9
+    pub size: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none", with = "hex_opt")]
+    pub checksum: Option<[u8; 32]>,
 }
 
 #[derive(Serialize, Deserialize, Eq, PartialEq)]
 pub struct TransferRequest {
     pub files: Vec<File>,
     pub id: uuid::Uuid,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub info: Option<PeerInfo>,
+    // The chunks are encrypted, since v10
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub encrypted: bool,
+}
+
+// Longer values are truncated, the info is meant for display only
+const MAX_PEER_INFO_CHARS: usize = 64;
+
+#[derive(Serialize, Deserialize, Eq, PartialEq, Clone, Debug, Default)]
+pub struct PeerInfo {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub device_name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub app_version: Option<String>,
+}
+
+impl PeerInfo {
+    pub fn from_config(config: &drop_config::DropConfig) -> Self {
+        Self {
+            device_name: config.device_name.clone(),
+            app_version: config.app_version.clone(),
+        }
+    }
+
+    /// Strips the control characters and limits the length of the values
+    /// received from the peer
+    pub fn sanitized(self) -> Self {
+        let sanitize = |value: Option<String>| {
+            let value: String = value?
+                .chars()
+                .filter(|c| !c.is_control())
+                .take(MAX_PEER_INFO_CHARS)
+                .collect();
+            let value = value.trim();
+
+            (!value.is_empty()).then(|| value.to_string())
+        };
+
+        Self {
+            device_name: sanitize(self.device_name),
+            app_version: sanitize(self.app_version),
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Eq, PartialEq)]
@@ -62,6 +143,27 @@ pub struct ReportChsum {
     pub checksum: [u8; 32],
 }
 
+// The bounds of the block checksums request, keeping the report small
+pub const MIN_CHECKSUM_BLOCK_SIZE: u64 = 1024 * 1024;
+pub const MAX_CHECKSUM_BLOCKS: u64 = 1 << 16;
+
+#[derive(Serialize, Deserialize, Eq, PartialEq)]
+pub struct ReqBlockChsums {
+    pub file: FileId,
+    // Up to which point calculate checksums
+    pub limit: u64,
+    pub block_size: u64,
+}
+
+#[derive(Serialize, Deserialize, Eq, PartialEq)]
+pub struct ReportBlockChsums {
+    pub file: FileId,
+    pub limit: u64,
+    pub block_size: u64,
+    #[serde(with = "hex_vec")]
+    pub checksums: Vec<[u8; 32]>,
+}
+
 #[derive(Serialize, Deserialize, Eq, PartialEq)]
 pub struct Progress<T = FileId> {
     pub file: T,
@@ -80,6 +182,14 @@ pub struct Error<T = FileId> {
     pub msg: String,
 }
 
+#[derive(Serialize, Deserialize, Eq, PartialEq)]
+pub struct End {
+    pub file: FileId,
+    #[serde(serialize_with = "hex::serialize")]
+    #[serde(deserialize_with = "hex::deserialize")]
+    pub checksum: [u8; 32],
+}
+
 #[derive(Serialize, Deserialize, Eq, PartialEq)]
 pub struct Start {
     pub file: FileId,
@@ -96,6 +206,11 @@ pub struct Reject {
     pub file: FileId,
 }
 
+#[derive(Serialize, Deserialize, Eq, PartialEq)]
+pub struct Select {
+    pub files: Vec<FileId>,
+}
+
 #[derive(Serialize, Deserialize, Eq, PartialEq)]
 #[serde(tag = "type")]
 pub enum ServerMsg {
@@ -103,18 +218,63 @@ pub enum ServerMsg {
     Done(Done),
     Error(Error<FileId>),
     ReqChsum(ReqChsum),
+    ReqBlockChsums(ReqBlockChsums),
     Start(Start),
     Cancel(Cancel),
     Reject(Reject),
+    PeerInfo(PeerInfo),
+    Select(Select),
 }
 
 #[derive(Serialize, Deserialize, Eq, PartialEq)]
 #[serde(tag = "type")]
 pub enum ClientMsg {
     ReportChsum(ReportChsum),
+    ReportBlockChsums(ReportBlockChsums),
     Error(Error<FileId>),
     Cancel(Cancel),
     Reject(Reject),
+    End(End),
+}
+
+mod hex_vec {
+    use serde::{de::Error as _, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(items: &[[u8; 32]], ser: S) -> Result<S::Ok, S::Error> {
+        ser.collect_seq(items.iter().map(hex::encode))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(de: D) -> Result<Vec<[u8; 32]>, D::Error> {
+        Vec::<String>::deserialize(de)?
+            .into_iter()
+            .map(|item| {
+                let mut out = [0; 32];
+                hex::decode_to_slice(item, &mut out).map_err(D::Error::custom)?;
+                Ok(out)
+            })
+            .collect()
+    }
+}
+
+mod hex_opt {
+    use serde::{de::Error as _, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(item: &Option<[u8; 32]>, ser: S) -> Result<S::Ok, S::Error> {
+        match item {
+            Some(item) => ser.serialize_some(&hex::encode(item)),
+            None => ser.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(de: D) -> Result<Option<[u8; 32]>, D::Error> {
+        Option::<String>::deserialize(de)?
+            .map(|item| {
+                let mut out = [0; 32];
+                hex::decode_to_slice(item, &mut out).map_err(D::Error::custom)?;
+                Ok(out)
+            })
+            .transpose()
+    }
 }
 
 pub struct Chunk<T = FileId> {
@@ -173,6 +333,16 @@ where
     }
 }
 
+/// The associated data binding the encrypted chunk to its place in the file
+pub fn chunk_aad(file: &FileId, offset: u64) -> Vec<u8> {
+    file.as_ref()
+        .as_bytes()
+        .iter()
+        .copied()
+        .chain(offset.to_le_bytes())
+        .collect()
+}
+
 impl<T> From<Chunk<T>> for tokio_tungstenite::tungstenite::Message
 where
     T: From<String> + ToString,
@@ -191,14 +361,23 @@ impl From<&OutgoingTransfer> for TransferRequest {
                 .map(|f| File {
                     path: f.subpath().clone(),
                     id: f.id().clone(),
-                    size: f.size(),
+                    size: (!f.is_streamed()).then(|| f.size()),
+                    checksum: None,
                 })
                 .collect(),
             id: value.id(),
+            info: None,
+            encrypted: false,
         }
     }
 }
 
+impl TransferRequest {
+    pub fn has_streamed_files(&self) -> bool {
+        self.files.iter().any(|file| file.size.is_none())
+    }
+}
+
 impl From<&TransferRequest> for tokio_tungstenite::tungstenite::Message {
     fn from(value: &TransferRequest) -> Self {
         let msg = serde_json::to_string(value).expect("Failed to serialize client message");
@@ -253,15 +432,19 @@ mod tests {
                     File {
                         path: "dir/a.txt".into(),
                         id: "ID1".into(),
-                        size: 41,
+                        size: Some(41),
+                        checksum: None,
                     },
                     File {
                         path: "dir/b.txt".into(),
                         id: "ID2".into(),
-                        size: 4141,
+                        size: Some(4141),
+                        checksum: Some([0xff; 32]),
                     },
                 ],
                 id: uuid::uuid!("1b0397eb-66e9-4252-b7cf-71782698ee3d"),
+                info: None,
+                encrypted: false,
             },
             r#"
             {
@@ -274,13 +457,34 @@ mod tests {
                 {
                   "path": "dir/b.txt",
                   "id": "ID2",
-                  "size": 4141
+                  "size": 4141,
+                  "checksum": "ffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff"
                 }
               ],
               "id": "1b0397eb-66e9-4252-b7cf-71782698ee3d"
             }"#,
         );
 
+        test_json(
+            TransferRequest {
+                files: vec![],
+                id: uuid::uuid!("1b0397eb-66e9-4252-b7cf-71782698ee3d"),
+                info: Some(PeerInfo {
+                    device_name: Some("Alice's Pixel".to_string()),
+                    app_version: None,
+                }),
+                encrypted: false,
+            },
+            r#"
+            {
+              "files": [],
+              "id": "1b0397eb-66e9-4252-b7cf-71782698ee3d",
+              "info": {
+                "device_name": "Alice's Pixel"
+              }
+            }"#,
+        );
+
         test_json(
             ClientMsg::ReportChsum(ReportChsum {
                 file: FileId::from("TESTID"),
@@ -300,6 +504,27 @@ mod tests {
             "#,
         );
 
+        test_json(
+            ClientMsg::ReportBlockChsums(ReportBlockChsums {
+                file: FileId::from("TESTID"),
+                limit: 41,
+                block_size: 32,
+                checksums: vec![[0; 32], [0xff; 32]],
+            }),
+            r#"
+            {
+              "type": "ReportBlockChsums",
+              "file": "TESTID",
+              "limit": 41,
+              "block_size": 32,
+              "checksums": [
+                "0000000000000000000000000000000000000000000000000000000000000000",
+                "ffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff"
+              ]
+            }
+            "#,
+        );
+
         test_json(
             ClientMsg::Error(Error {
                 file: Some(FileId::from("TESTID")),
@@ -351,6 +576,60 @@ mod tests {
             }
             "#,
         );
+
+        test_json(
+            TransferRequest {
+                files: vec![File {
+                    path: "stdin".into(),
+                    id: "ID1".into(),
+                    size: None,
+                    checksum: None,
+                }],
+                id: uuid::uuid!("1b0397eb-66e9-4252-b7cf-71782698ee3d"),
+                info: None,
+                encrypted: false,
+            },
+            r#"
+            {
+              "files": [
+                {
+                  "path": "stdin",
+                  "id": "ID1",
+                  "size": null
+                }
+              ],
+              "id": "1b0397eb-66e9-4252-b7cf-71782698ee3d"
+            }"#,
+        );
+
+        test_json(
+            TransferRequest {
+                files: vec![],
+                id: uuid::uuid!("1b0397eb-66e9-4252-b7cf-71782698ee3d"),
+                info: None,
+                encrypted: true,
+            },
+            r#"
+            {
+              "files": [],
+              "id": "1b0397eb-66e9-4252-b7cf-71782698ee3d",
+              "encrypted": true
+            }"#,
+        );
+
+        test_json(
+            ClientMsg::End(End {
+                file: FileId::from("TESTID"),
+                checksum: [0xff; 32],
+            }),
+            r#"
+            {
+              "type": "End",
+              "file": "TESTID",
+              "checksum": "ffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff"
+            }
+            "#,
+        );
     }
 
     #[test]
@@ -420,6 +699,21 @@ mod tests {
             }"#,
         );
 
+        test_json(
+            ServerMsg::ReqBlockChsums(ReqBlockChsums {
+                file: FileId::from("TESTID"),
+                limit: 41,
+                block_size: 32,
+            }),
+            r#"
+            {
+              "type": "ReqBlockChsums",
+              "file": "TESTID",
+              "limit": 41,
+              "block_size": 32
+            }"#,
+        );
+
         test_json(
             ServerMsg::Start(Start {
                 file: FileId::from("TESTID"),
@@ -455,5 +749,45 @@ mod tests {
             }
             "#,
         );
+
+        test_json(
+            ServerMsg::PeerInfo(PeerInfo {
+                device_name: Some("Bob's Mac".to_string()),
+                app_version: Some("NordDrop 2.3".to_string()),
+            }),
+            r#"
+            {
+              "type": "PeerInfo",
+              "device_name": "Bob's Mac",
+              "app_version": "NordDrop 2.3"
+            }
+            "#,
+        );
+
+        test_json(
+            ServerMsg::Select(Select {
+                files: vec![FileId::from("TESTID"), FileId::from("OTHERID")],
+            }),
+            r#"
+            {
+              "type": "Select",
+              "files": ["TESTID", "OTHERID"]
+            }
+            "#,
+        );
+    }
+
+    #[test]
+    fn peer_info_sanitization() {
+        let info = PeerInfo {
+            device_name: Some(format!(" Alice\n\u{7}{} ", "x".repeat(100))),
+            app_version: Some("\t ".to_string()),
+        }
+        .sanitized();
+
+        let name = info.device_name.unwrap();
+        assert!(name.starts_with("Alicexxx"));
+        assert_eq!(name.chars().count(), MAX_PEER_INFO_CHARS - 1);
+        assert_eq!(info.app_version, None);
     }
 }
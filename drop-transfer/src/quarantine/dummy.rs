@@ -1,7 +1,7 @@
 use std::{io::Result, path::Path};
 
 impl super::PathExt for Path {
-    fn quarantine(&self) -> Result<()> {
+    fn quarantine(&self, _origin: &str) -> Result<()> {
         Ok(())
     }
 }
@@ -69,7 +69,7 @@ fn bundle_fn_ptr(bundle_name: &str, fn_name: &str) -> Result<*const c_void> {
 }
 
 impl super::PathExt for Path {
-    fn quarantine(&self) -> Result<()> {
+    fn quarantine(&self, origin: &str) -> Result<()> {
         // The reason this is loaded dynamically is that `MDItemSetAttribute()`
         // is not documented and its existence cannot be guaranteed, even though
         // it is already used by some major browsers to perform the same task
@@ -95,7 +95,7 @@ impl super::PathExt for Path {
         // so we want to release it afterwards to avoid leaks.
         let item = unsafe { MDItem::wrap_under_create_rule(item) };
 
-        let location = CFString::new("meshnet");
+        let location = CFString::new(origin);
         let array = CFArray::from_CFTypes(&[location.as_CFType()]);
 
         unsafe {
@@ -144,7 +144,7 @@ mod tests {
         let file = NamedTempFile::new_in(current_dir()?)?;
         let path = file.path();
 
-        path.quarantine()?;
+        path.quarantine("meshnet")?;
 
         let array = unsafe {
             let item = MDItemCreate(
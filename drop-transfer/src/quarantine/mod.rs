@@ -4,5 +4,7 @@
 mod plat;
 
 pub(crate) trait PathExt {
-    fn quarantine(&self) -> std::io::Result<()>;
+    /// Marks the file as downloaded from the internet. The `origin` describes
+    /// where the file came from, platforms not recording it ignore it
+    fn quarantine(&self, origin: &str) -> std::io::Result<()>;
 }
@@ -7,7 +7,7 @@ use std::{
 use super::PathExt;
 
 impl PathExt for Path {
-    fn quarantine(&self) -> Result<()> {
+    fn quarantine(&self, origin: &str) -> Result<()> {
         if let Some(name) = self.file_name() {
             let mut name = name.to_os_string();
 
@@ -15,7 +15,7 @@ impl PathExt for Path {
 
             let mut f = File::create(self.with_file_name(name))?;
 
-            write!(f, "[ZoneTransfer]\nZoneId=3")?;
+            write!(f, "[ZoneTransfer]\nZoneId=3\nReferrerUrl={origin}")?;
         } else {
             return Err(Error::new(
                 ErrorKind::Unsupported,
@@ -41,13 +41,16 @@ mod tests {
 
         assert!(name.is_some());
 
-        path.quarantine()?;
+        path.quarantine("meshnet")?;
 
         let mut name = name.unwrap().to_os_string();
 
         name.push(":Zone.Identifier:$DATA");
 
-        assert!(fs::read_to_string(path.with_file_name(name))? == "[ZoneTransfer]\nZoneId=3");
+        assert!(
+            fs::read_to_string(path.with_file_name(name))?
+                == "[ZoneTransfer]\nZoneId=3\nReferrerUrl=meshnet"
+        );
 
         Ok(())
     }
@@ -0,0 +1,135 @@
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use crate::Clock;
+
+/// Throughput cap of a single transfer, shared by all of its files
+#[derive(Default)]
+pub(crate) struct RateLimit {
+    // Bytes per second, 0 meaning no cap
+    limit: AtomicU64,
+    // The moment the bytes passed so far fit into the cap
+    next: Mutex<Option<Instant>>,
+}
+
+impl RateLimit {
+    pub fn new(limit: Option<u64>) -> Self {
+        let this = Self::default();
+        this.set(limit);
+        this
+    }
+
+    pub fn get(&self) -> Option<u64> {
+        match self.limit.load(Ordering::Relaxed) {
+            0 => None,
+            limit => Some(limit),
+        }
+    }
+
+    pub fn set(&self, limit: Option<u64>) {
+        self.limit.store(limit.unwrap_or(0), Ordering::Relaxed);
+        // Start pacing anew with the new cap
+        *self.next.lock().expect("Poisoned lock") = None;
+    }
+
+    /// Whether the transfer is currently held back by the cap
+    pub fn is_pacing(&self, now: Instant) -> bool {
+        self.get().is_some()
+            && matches!(*self.next.lock().expect("Poisoned lock"), Some(next) if next > now)
+    }
+
+    /// Accounts the given number of bytes, returning how long it takes until
+    /// they fit into the cap
+    pub fn reserve(&self, bytes: u64, now: Instant) -> Duration {
+        let Some(limit) = self.get() else {
+            return Duration::ZERO;
+        };
+
+        let mut next = self.next.lock().expect("Poisoned lock");
+
+        let start = next.filter(|next| *next > now).unwrap_or(now);
+        let end = start + Duration::from_secs_f64(bytes as f64 / limit as f64);
+        *next = Some(end);
+
+        end - now
+    }
+
+    /// Waits until passing the given number of bytes fits into the cap
+    pub async fn consume(&self, bytes: u64, clock: &dyn Clock) {
+        let delay = self.reserve(bytes, clock.instant());
+        if !delay.is_zero() {
+            clock.sleep(delay).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::SystemTime;
+
+    use super::*;
+    use crate::clock::MockClock;
+
+    #[tokio::test]
+    async fn pacing_to_the_cap() {
+        let clock = MockClock::new(SystemTime::UNIX_EPOCH);
+        let limit = RateLimit::new(Some(1000));
+
+        // Two chunks of 500 bytes take a second in total
+        let first = limit.consume(500, &clock);
+        tokio::pin!(first);
+        assert!(futures::poll!(&mut first).is_pending());
+
+        clock.advance(Duration::from_millis(500));
+        assert!(futures::poll!(&mut first).is_ready());
+
+        let second = limit.consume(500, &clock);
+        tokio::pin!(second);
+        assert!(futures::poll!(&mut second).is_pending());
+
+        clock.advance(Duration::from_millis(499));
+        assert!(futures::poll!(&mut second).is_pending());
+        clock.advance(Duration::from_millis(1));
+        assert!(futures::poll!(&mut second).is_ready());
+
+        assert!(!limit.is_pacing(clock.instant()));
+        let third = limit.consume(500, &clock);
+        tokio::pin!(third);
+        assert!(futures::poll!(&mut third).is_pending());
+        assert!(limit.is_pacing(clock.instant()));
+        clock.advance(Duration::from_millis(500));
+        assert!(futures::poll!(&mut third).is_ready());
+
+        // No cap, no waiting
+        limit.set(None);
+        let unlimited = limit.consume(u64::MAX, &clock);
+        tokio::pin!(unlimited);
+        assert!(futures::poll!(&mut unlimited).is_ready());
+    }
+
+    #[test]
+    fn reserving_ahead() {
+        let now = Instant::now();
+        let limit = RateLimit::new(Some(1000));
+
+        // The delays add up until the time catches up with them
+        assert_eq!(limit.reserve(500, now), Duration::from_millis(500));
+        assert_eq!(limit.reserve(500, now), Duration::from_secs(1));
+        assert_eq!(
+            limit.reserve(500, now + Duration::from_millis(250)),
+            Duration::from_millis(1250)
+        );
+        assert_eq!(
+            limit.reserve(500, now + Duration::from_secs(10)),
+            Duration::from_millis(500)
+        );
+
+        limit.set(None);
+        assert_eq!(limit.reserve(u64::MAX, now), Duration::ZERO);
+    }
+}
@@ -1,30 +1,159 @@
 use std::{
+    collections::{HashMap, HashSet},
     fs,
+    future::Future,
+    io,
     net::IpAddr,
-    path::{Component, Path},
+    path::{Component, Path, PathBuf},
     sync::Arc,
-    time::{Instant, SystemTime},
+    time::{Duration, Instant, SystemTime},
 };
 
 use drop_analytics::{InitEventData, Moose, TransferStateEventData};
 use drop_config::DropConfig;
 use drop_core::Status;
-use drop_storage::Storage;
-use slog::{debug, trace, Logger};
-use tokio::sync::{mpsc, Semaphore};
+use drop_storage::{CancelCause, Storage, StoredTransferState};
+use slog::{debug, trace, warn, Logger};
+use tokio::{
+    sync::{mpsc, watch, Semaphore},
+    task::JoinHandle,
+};
 use tokio_util::sync::CancellationToken;
 use uuid::Uuid;
 
 use crate::{
     auth,
+    clock::Clock,
     error::ResultExt,
-    manager,
+    file::FileSubPath,
+    manager::{self, FileTerminalState},
+    metrics::Metrics,
     tasks::AliveWaiter,
     transfer::Transfer,
-    ws::{self, EventTxFactory},
-    Error, Event, FileId, TransferManager,
+    utils,
+    ws::{self, server::temp_file_name, EventTxFactory},
+    Error, Event, File, FileId, FileToRecv, IncomingTransfer, TransferManager,
 };
 
+/// Callback deciding where a downloaded file should be placed. It receives
+/// the transfer, the file and the default destination path computed by the
+/// library and returns the path to use instead. Relative paths are resolved
+/// against the download directory.
+pub type DestinationResolver =
+    dyn Fn(&IncomingTransfer, &FileToRecv, &Path) -> PathBuf + Send + Sync;
+
+/// What to do with the downloaded file once it's placed into the destination
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PostAction {
+    /// Leave the file where it is
+    Keep,
+    /// Remove the file. It is still reported as downloaded and stored as
+    /// deleted, so it's never considered missing nor downloaded again
+    Delete,
+    /// Move the file to the given path. Relative paths are resolved against
+    /// the download directory
+    Relocate(PathBuf),
+}
+
+/// Callback invoked right after a downloaded file is placed into its
+/// destination. It runs on the blocking thread pool while the download task of
+/// the file waits for it, so the file is reported as downloaded, and the final
+/// path is stored, only once it returns and the requested action is applied. A
+/// slow callback stalls the completion of that file only, a panicking one is
+/// treated as [`PostAction::Keep`].
+pub type PostCompletionHook =
+    dyn Fn(&IncomingTransfer, &FileToRecv, &Path) -> PostAction + Send + Sync;
+
+/// Outcome of the downloaded file verification
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VerifyResult {
+    /// The file content matches the checksum
+    Ok,
+    /// The file content differs from the one advertised by the sender
+    Mismatch,
+    /// The file no longer exists at the location it was downloaded to
+    FileMissing,
+}
+
+/// Status of a transfer as seen by this instance
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransferStatus {
+    /// The transfer is connected with the peer
+    Active,
+    /// The transfer is waiting for the connection with the peer to be
+    /// (re)established
+    Deferred,
+    /// The transfer was restored on startup but it's not resumed yet, see
+    /// `Service::resume_transfer()`
+    Restored,
+    /// The transfer was canceled by either side
+    Canceled,
+    /// The transfer failed
+    Failed,
+    /// All of the transfer files reached the terminal state
+    Completed,
+    /// The transfer is not known
+    Unknown,
+}
+
+/// The reason why a transfer is not progressing at the moment
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StallReason {
+    /// The peer is not reachable, the transfer waits for it to come online
+    WaitingForPeerOnline,
+    /// The connection attempt failed, the next one is made after the delay
+    BackingOff { retry_in: Duration },
+    /// The transfer is held back by its rate limit
+    RateLimited,
+    /// None of the files was accepted for download yet
+    AwaitingUserDecision,
+    /// The files wait for the other uploads to finish
+    ConcurrencyQueued,
+    /// The checksums of the files are being computed
+    Verifying,
+    /// The transfer was restored on startup but it's not resumed yet
+    AwaitingResume,
+}
+
+/// Connection diagnostics of a transfer, collected since it was loaded into
+/// memory
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ConnectionMetrics {
+    /// The number of times the connection was established again after a
+    /// session established since the transfer was loaded
+    pub reconnects: u32,
+    /// The number of the connection attempts to the peer that failed
+    pub failed_attempts: u32,
+    /// The round trip time measured with the last ping
+    pub last_rtt: Option<Duration>,
+    /// The bytes transferred once more because the resumed files started
+    /// before the point reached previously
+    pub bytes_resent: u64,
+}
+
+/// Service-wide diagnostics collected since the service was started
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ServiceMetrics {
+    /// The number of the incoming transfers loaded into memory and not
+    /// canceled
+    pub active_incoming: usize,
+    /// The number of the outgoing transfers loaded into memory and not
+    /// canceled
+    pub active_outgoing: usize,
+    /// The total bytes of the files sent and received
+    pub bytes_transferred: u64,
+    /// The current upload throughput in bytes per second, summed over the
+    /// files in flight
+    pub upload_rate: u64,
+    /// The current download throughput in bytes per second, summed over the
+    /// files in flight
+    pub download_rate: u64,
+    /// The number of the open peer connections, one per transfer
+    pub connected_peers: usize,
+    /// The number of the incoming requests rejected by the rate limiter
+    pub rate_limited_requests: u64,
+}
+
 pub(super) struct State {
     pub(super) event_tx: mpsc::UnboundedSender<(Event, SystemTime)>,
     pub(super) transfer_manager: TransferManager,
@@ -33,9 +162,18 @@ pub(super) struct State {
     pub(crate) config: Arc<DropConfig>,
     pub(crate) storage: Arc<Storage>,
     pub(crate) throttle: Arc<Semaphore>,
-    pub(crate) addr: IpAddr,
+    // Replaced on `Service::rebind()`
+    pub(crate) addr: watch::Sender<IpAddr>,
     #[cfg(unix)]
     pub fdresolv: Option<Arc<crate::file::FdResolver>>,
+    #[cfg(unix)]
+    pub uri_refresher: Option<Arc<crate::file::ContentUriRefresher>>,
+    pub dest_resolver: Option<Arc<DestinationResolver>>,
+    pub post_completion: Option<Arc<PostCompletionHook>>,
+    // Set while the transfers are suspended with `Service::pause_all()`
+    pub(crate) paused: watch::Sender<bool>,
+    pub(crate) metrics: Arc<Metrics>,
+    pub(crate) clock: Arc<dyn Clock>,
 }
 
 impl State {
@@ -44,12 +182,30 @@ impl State {
             .send((event, SystemTime::now()))
             .expect("Failed to emit Event");
     }
+
+    /// The address the server listens on and the connections are made from
+    pub(crate) fn addr(&self) -> IpAddr {
+        *self.addr.borrow()
+    }
+
+    pub(crate) fn is_paused(&self) -> bool {
+        *self.paused.borrow()
+    }
+
+    pub(crate) async fn wait_unpaused(&self) {
+        let mut paused = self.paused.subscribe();
+        // The sender lives as long as the state so the wait cannot fail
+        let _ = paused.wait_for(|paused| !paused).await;
+    }
 }
 
 pub struct Service {
     pub(super) state: Arc<State>,
     stop: CancellationToken,
     waiter: AliveWaiter,
+    // Stops just the WS server, the connections it accepted are not affected
+    server_stop: CancellationToken,
+    server: Option<JoinHandle<()>>,
     pub(super) logger: Logger,
 
     refresh_trigger: tokio::sync::watch::Sender<()>,
@@ -67,13 +223,30 @@ impl Service {
         auth: Arc<auth::Context>,
         init_time: Instant,
         #[cfg(unix)] fdresolv: Option<Arc<crate::FdResolver>>,
+        #[cfg(unix)] uri_refresher: Option<Arc<crate::ContentUriRefresher>>,
+        dest_resolver: Option<Arc<DestinationResolver>>,
+        post_completion: Option<Arc<PostCompletionHook>>,
+        clock: Arc<dyn Clock>,
+        auto_resume: bool,
     ) -> Result<Self, Error> {
+        let logger = Logger::root(
+            utils::RedactingDrain::new(logger, config.log_redaction),
+            slog::o!(),
+        );
+
         let task = async {
+            let metrics = Arc::new(Metrics::default());
             let state = Arc::new(State {
                 throttle: Arc::new(Semaphore::new(drop_config::MAX_UPLOADS_IN_FLIGHT)),
                 transfer_manager: TransferManager::new(
                     storage.clone(),
-                    EventTxFactory::new(event_tx.clone(), moose.clone()),
+                    EventTxFactory::new(
+                        event_tx.clone(),
+                        moose.clone(),
+                        config.progress_events_granularity,
+                    )
+                    .with_progress_interval(config.progress_events_interval)
+                    .with_metrics(metrics.clone()),
                     logger.clone(),
                 ),
                 event_tx,
@@ -81,9 +254,16 @@ impl Service {
                 config,
                 auth: auth.clone(),
                 storage,
-                addr,
+                addr: watch::channel(addr).0,
                 #[cfg(unix)]
                 fdresolv,
+                #[cfg(unix)]
+                uri_refresher,
+                dest_resolver,
+                post_completion,
+                paused: watch::channel(false).0,
+                metrics,
+                clock,
             });
 
             let waiter = AliveWaiter::new();
@@ -96,21 +276,37 @@ impl Service {
             manager::restore_transfers_state(&state, &logger).await;
 
             let refresh_trigger = tokio::sync::watch::channel(()).0;
-            ws::server::spawn(
+            let server_stop = stop.child_token();
+            let server = ws::server::spawn(
                 refresh_trigger.subscribe(),
                 state.clone(),
                 logger.clone(),
                 stop.clone(),
+                server_stop.clone(),
                 guard.clone(),
             )?;
 
-            manager::resume(&refresh_trigger.subscribe(), &state, &logger, &guard, &stop).await;
+            // Otherwise the restored transfers wait for `resume_transfer()` or
+            // `resume_restored()`
+            if auto_resume {
+                manager::resume(
+                    &refresh_trigger.subscribe(),
+                    &state,
+                    &logger,
+                    &guard,
+                    &stop,
+                    None,
+                )
+                .await;
+            }
 
             Ok(Self {
                 refresh_trigger,
                 state,
                 stop,
                 waiter,
+                server_stop,
+                server: Some(server),
                 logger: logger.clone(),
             })
         };
@@ -126,20 +322,239 @@ impl Service {
     }
 
     pub async fn stop(self) {
+        self.state.transfer_manager.incoming_persist_offsets().await;
+
         self.stop.cancel();
         self.waiter.wait_for_all().await;
     }
 
+    /// Suspends all of the transfers without stopping the service. The
+    /// connections are dropped, which pauses the files in flight, and no new
+    /// connections are made or accepted until `resume_all()` is called.
+    /// Calling it again while paused does nothing
+    pub async fn pause_all(&self) {
+        if self.state.paused.send_replace(true) {
+            debug!(self.logger, "Transfers are already paused");
+            return;
+        }
+
+        self.state.transfer_manager.incoming_persist_offsets().await;
+
+        for transfer_id in self.state.transfer_manager.pause_all().await {
+            self.state.emit_event(Event::TransferPaused { transfer_id });
+        }
+    }
+
+    /// Resumes the transfers suspended with `pause_all()`. The outgoing
+    /// transfers reconnect right away and continue from their offsets, the
+    /// incoming ones wait for the peers to reconnect. Calling it while not
+    /// paused does nothing
+    pub async fn resume_all(&self) {
+        if !self.state.paused.send_replace(false) {
+            debug!(self.logger, "Transfers are not paused");
+            return;
+        }
+
+        for transfer_id in self.state.transfer_manager.transfer_ids().await {
+            self.state
+                .emit_event(Event::TransferResumed { transfer_id });
+        }
+
+        if self.refresh_trigger.send(()).is_ok() {
+            trace!(self.logger, "Refresh trigger sent on resume");
+        }
+    }
+
+    /// Resumes the transfer restored on startup when the service was started
+    /// without `auto_resume`. The outgoing transfer starts connecting to the
+    /// peer and the incoming one starts checking the sender. Resuming the
+    /// transfer that is already running does nothing
+    pub async fn resume_transfer(&self, transfer_id: Uuid) -> crate::Result<()> {
+        if !self
+            .state
+            .transfer_manager
+            .transfer_ids()
+            .await
+            .contains(&transfer_id)
+        {
+            return Err(crate::Error::BadTransfer);
+        }
+
+        manager::resume(
+            &self.refresh_trigger.subscribe(),
+            &self.state,
+            &self.logger,
+            &self.waiter.guard(),
+            &self.stop,
+            Some(transfer_id),
+        )
+        .await;
+
+        Ok(())
+    }
+
+    /// Resumes all of the transfers restored on startup that were not resumed
+    /// yet. Unlike `resume_all()` it doesn't concern the paused transfers.
+    /// Returns the IDs of the resumed transfers
+    pub async fn resume_restored(&self) -> Vec<Uuid> {
+        manager::resume(
+            &self.refresh_trigger.subscribe(),
+            &self.state,
+            &self.logger,
+            &self.waiter.guard(),
+            &self.stop,
+            None,
+        )
+        .await
+    }
+
+    /// Moves the WS server to the new address, e.g. when the device switches
+    /// the network interface. The transfers are kept, the outgoing ones
+    /// reconnect from the new address right away. The server keeps listening
+    /// on the previous address if it cannot bind to the new one
+    pub async fn rebind(&mut self, addr: IpAddr) -> crate::Result<()> {
+        let prev = self.state.addr.send_replace(addr);
+        debug!(self.logger, "Rebinding WS server from {prev} to {addr}");
+
+        self.server_stop.cancel();
+        // The listener needs to be released in case the port is reused
+        if let Some(server) = self.server.take() {
+            if let Err(err) = server.await {
+                warn!(self.logger, "WS server task failed: {err}");
+            }
+        }
+
+        match self.spawn_server() {
+            Ok(()) => (),
+            Err(err) => {
+                warn!(self.logger, "Failed to bind WS server to {addr}: {err}");
+
+                self.state.addr.send_replace(prev);
+                self.spawn_server()?;
+                return Err(err);
+            }
+        }
+
+        self.state
+            .transfer_manager
+            .drop_outgoing_connections()
+            .await;
+        if self.refresh_trigger.send(()).is_ok() {
+            trace!(self.logger, "Refresh trigger sent on rebind");
+        }
+
+        self.state.emit_event(Event::ListenAddressChanged { addr });
+        Ok(())
+    }
+
+    fn spawn_server(&mut self) -> crate::Result<()> {
+        let server_stop = self.stop.child_token();
+
+        let server = ws::server::spawn(
+            self.refresh_trigger.subscribe(),
+            self.state.clone(),
+            self.logger.clone(),
+            self.stop.clone(),
+            server_stop.clone(),
+            self.waiter.guard(),
+        )?;
+        self.server = Some(server);
+        self.server_stop = server_stop;
+
+        Ok(())
+    }
+
     pub fn storage(&self) -> &Storage {
         &self.state.storage
     }
 
+    /// The configuration the service runs with
+    pub fn config(&self) -> &DropConfig {
+        &self.state.config
+    }
+
     pub fn network_refresh(&mut self) {
         if self.refresh_trigger.send(()).is_ok() {
             trace!(self.logger, "Refresh trigger sent");
         }
     }
 
+    /// Drops the current connection of the transfer, e.g. when the app finds
+    /// it stale before the pings do. The files in flight are paused and
+    /// resume from their offsets: the outgoing transfer reconnects right away,
+    /// the incoming one waits for the peer to reconnect
+    pub async fn reconnect_transfer(&self, transfer_id: Uuid) -> crate::Result<()> {
+        self.state
+            .transfer_manager
+            .drop_connection(transfer_id)
+            .await?;
+
+        if self.refresh_trigger.send(()).is_ok() {
+            trace!(self.logger, "Refresh trigger sent on reconnect");
+        }
+
+        Ok(())
+    }
+
+    /// Lets the failed files of the transfer be transferred again. The peer
+    /// has to retry the files on its side as well: the incoming files are
+    /// downloaded again with `download()` and the outgoing ones are sent once
+    /// the receiver requests them. The connection is re-established right
+    /// away so that the transfer is synchronized with the peer anew. Returns
+    /// the number of files reset
+    pub async fn retry_failed_files(&self, transfer_id: Uuid) -> crate::Result<usize> {
+        let count = self
+            .state
+            .transfer_manager
+            .retry_failed_files(transfer_id)
+            .await?;
+
+        if count > 0 {
+            self.reconnect_transfer(transfer_id).await?;
+        }
+
+        Ok(count)
+    }
+
+    /// Resolves the FDs of the content URI files up front so that the app can
+    /// fix the permissions before starting the transfer. Returns the result
+    /// per descriptor
+    #[cfg(unix)]
+    pub fn preflight_fds(&self, descriptors: &[crate::file::GatherSrc]) -> Vec<crate::Result<()>> {
+        let mut gather = crate::file::GatherCtx::new(&self.state.config);
+        if let Some(fdresolv) = self.state.fdresolv.as_ref() {
+            gather.with_fd_resover(fdresolv.as_ref());
+        }
+
+        gather.validate_fds(descriptors)
+    }
+
+    /// Wake all of the outgoing transfers waiting for the connection to be
+    /// re-established so that they retry right away instead of waiting for
+    /// the backoff to elapse. The trigger only interrupts the backoff, the
+    /// connected transfers are left intact. Returns the number of deferred
+    /// transfers
+    pub async fn retry_all_deferred(&mut self) -> usize {
+        let deferred = self
+            .state
+            .transfer_manager
+            .outgoing
+            .lock()
+            .await
+            .values()
+            .filter(|state| state.status() == TransferStatus::Deferred)
+            .count();
+
+        if deferred > 0 && self.refresh_trigger.send(()).is_ok() {
+            trace!(
+                self.logger,
+                "Refresh trigger sent for {deferred} deferred transfers"
+            );
+        }
+
+        deferred
+    }
+
     pub async fn send_request(&mut self, xfer: crate::OutgoingTransfer) {
         let xfer = Arc::new(xfer);
 
@@ -180,39 +595,653 @@ impl Service {
         );
     }
 
+    /// Download the file into `parent_dir`. With `rename_to` the file is saved
+    /// under the given name instead of the sender's one, the directories of
+    /// the file stay as they are
     pub async fn download(
         &mut self,
         uuid: Uuid,
         file_id: &FileId,
         parent_dir: &str,
+        rename_to: Option<&str>,
     ) -> crate::Result<()> {
         debug!(
             self.logger,
-            "Client::download() called with Uuid: {}, file: {:?}, parent_dir: {parent_dir}",
+            "Client::download() called with Uuid: {}, file: {:?}, parent_dir: {:?}, rename_to: \
+             {:?}",
             uuid,
             file_id,
+            utils::Hidden(parent_dir),
+            rename_to.map(utils::Hidden),
+        );
+
+        let mut renames = HashMap::new();
+        if let Some(name) = rename_to {
+            ws::server::validate_rename_for_download(name, &self.state.config)?;
+            renames.insert(file_id.clone(), name.to_string());
+        }
+
+        let mut lock = self.state.transfer_manager.incoming.lock().await;
+        let state = lock.get_mut(&uuid).ok_or(crate::Error::BadTransfer)?;
+
+        match self
+            .start_downloads(state, std::slice::from_ref(file_id), parent_dir, &renames)
+            .await?
+            .pop()
+        {
+            Some((_, err)) => Err(err),
+            None => Ok(()),
+        }
+    }
+
+    /// Same as `download()` but the returned future resolves with the final
+    /// path of the file once it's downloaded or with the reason it was not.
+    /// The future does not drive the download, which goes on even if it's
+    /// dropped. Calling it for the file being downloaded already, also with
+    /// `download()`, just awaits the ongoing download
+    pub async fn download_awaitable(
+        &mut self,
+        uuid: Uuid,
+        file_id: &FileId,
+        parent_dir: &str,
+    ) -> impl Future<Output = crate::Result<PathBuf>> + Send + 'static {
+        let outcome = {
+            let lock = self.state.transfer_manager.incoming.lock().await;
+            match lock.get(&uuid).map(|state| state.file_events(file_id)) {
+                Some(Ok(events)) => Ok(events.subscribe_outcome().await),
+                Some(Err(err)) => Err(err),
+                None => Err(crate::Error::BadTransfer),
+            }
+        };
+
+        // Subscribed before starting so that the outcome cannot be missed
+        let started = match outcome {
+            Ok(rx) => self
+                .download(uuid, file_id, parent_dir, None)
+                .await
+                .map(|_| rx),
+            Err(err) => Err(err),
+        };
+
+        async move {
+            match started?.await {
+                Ok(outcome) => outcome,
+                Err(_) => Err(crate::Error::Canceled),
+            }
+        }
+    }
+
+    /// Download multiple files of the transfer at once. Behaves as if
+    /// `download()` was called for each file separately. Returns the files
+    /// that could not be downloaded along with the reason
+    pub async fn download_files(
+        &mut self,
+        uuid: Uuid,
+        file_ids: &[FileId],
+        parent_dir: &str,
+    ) -> crate::Result<Vec<(FileId, crate::Error)>> {
+        debug!(
+            self.logger,
+            "Client::download_files() called with Uuid: {}, files: {:?}, parent_dir: {:?}",
+            uuid,
+            file_ids,
+            utils::Hidden(parent_dir),
+        );
+
+        let mut lock = self.state.transfer_manager.incoming.lock().await;
+        let state = lock.get_mut(&uuid).ok_or(crate::Error::BadTransfer)?;
+
+        self.start_downloads(state, file_ids, parent_dir, &HashMap::new())
+            .await
+    }
+
+    /// Download the selected files of the transfer and reject the rest of the
+    /// files for which the download was not issued yet, so that the peer
+    /// streams only the selected ones. Since protocol v12 the selection is sent
+    /// in one message, the older peers get a rejection of each file. Nothing
+    /// is rejected if the downloads could not be started at all. Returns the
+    /// files that could not be downloaded along with the reason
+    pub async fn download_only(
+        &mut self,
+        uuid: Uuid,
+        file_ids: &[FileId],
+        parent_dir: &str,
+    ) -> crate::Result<Vec<(FileId, crate::Error)>> {
+        debug!(
+            self.logger,
+            "Client::download_only() called with Uuid: {}, files: {:?}, parent_dir: {:?}",
+            uuid,
+            file_ids,
+            utils::Hidden(parent_dir),
+        );
+
+        let failed = {
+            let mut lock = self.state.transfer_manager.incoming.lock().await;
+            let state = lock.get_mut(&uuid).ok_or(crate::Error::BadTransfer)?;
+
+            self.start_downloads(state, file_ids, parent_dir, &HashMap::new())
+                .await?
+        };
+
+        let res = self
+            .state
+            .transfer_manager
+            .incoming_reject_unselected(uuid, file_ids)
+            .await?;
+
+        for events in &res.file_events {
+            let file_id = events.file_id();
+            let tmp_bases = self
+                .state
+                .storage
+                .fetch_base_dirs_for_file(uuid, file_id.as_ref())
+                .await;
+
+            super::ws::server::remove_temp_files(
+                &self.logger,
+                uuid,
+                tmp_bases.into_iter().map(|base| (base, file_id)),
+            );
+
+            events.rejected(false).await;
+        }
+
+        if let Some((xfer_events, stats)) = res.closed {
+            xfer_events.cancel(false, CancelCause::User, stats).await;
+        }
+
+        Ok(failed)
+    }
+
+    /// Download all of the files of the transfer for which the download was
+    /// not issued yet. Returns the files that could not be downloaded along
+    /// with the reason. The failure to start the downloads at all is reported
+    /// for each of the files, the same as when downloaded one by one
+    pub async fn download_all(
+        &mut self,
+        uuid: Uuid,
+        parent_dir: &str,
+    ) -> crate::Result<Vec<(FileId, crate::Error)>> {
+        debug!(
+            self.logger,
+            "Client::download_all() called with Uuid: {}, parent_dir: {:?}",
+            uuid,
+            utils::Hidden(parent_dir),
         );
 
         let mut lock = self.state.transfer_manager.incoming.lock().await;
+        let state = lock.get_mut(&uuid).ok_or(crate::Error::BadTransfer)?;
 
+        let file_ids = state.idle_files();
+        match self
+            .start_downloads(state, &file_ids, parent_dir, &HashMap::new())
+            .await
+        {
+            Ok(failed) => Ok(failed),
+            Err(err) => Ok(fail_each(state, &file_ids, err)),
+        }
+    }
+
+    /// Download all of the files of the transfer whose subpath lies under
+    /// `prefix`, e.g. a single subdirectory of the received folder. The rest of
+    /// the files is left untouched. Returns the files that could not be
+    /// downloaded along with the reason, the failure to start the downloads at
+    /// all being reported for each of the files
+    pub async fn download_subtree(
+        &mut self,
+        uuid: Uuid,
+        prefix: &str,
+        parent_dir: &str,
+    ) -> crate::Result<Vec<(FileId, crate::Error)>> {
+        debug!(
+            self.logger,
+            "Client::download_subtree() called with Uuid: {}, prefix: {:?}, parent_dir: {:?}",
+            uuid,
+            utils::Hidden(prefix),
+            utils::Hidden(parent_dir),
+        );
+
+        let prefix = FileSubPath::from(prefix.trim_matches('/'));
+
+        let mut lock = self.state.transfer_manager.incoming.lock().await;
         let state = lock.get_mut(&uuid).ok_or(crate::Error::BadTransfer)?;
-        let started = state.validate_for_download(file_id)?;
 
-        if started {
-            validate_dest_path(parent_dir.as_ref())?;
+        let file_ids: Vec<_> = state
+            .xfer
+            .files()
+            .values()
+            .filter(|file| file.subpath().starts_with(&prefix))
+            .map(|file| file.id().clone())
+            .collect();
+
+        if file_ids.is_empty() {
+            return Err(crate::Error::BadPath(
+                "No transfer files under the given prefix".into(),
+            ));
+        }
+
+        match self
+            .start_downloads(state, &file_ids, parent_dir, &HashMap::new())
+            .await
+        {
+            Ok(failed) => Ok(failed),
+            Err(err) => Ok(fail_each(state, &file_ids, err)),
+        }
+    }
+
+    async fn start_downloads(
+        &self,
+        state: &mut manager::IncomingState,
+        file_ids: &[FileId],
+        parent_dir: &str,
+        renames: &HashMap<FileId, String>,
+    ) -> crate::Result<Vec<(FileId, crate::Error)>> {
+        let mut failed = Vec::new();
+        let mut to_start = Vec::new();
+
+        // The repeated files are handled once
+        let mut seen = HashSet::new();
+        for file_id in file_ids.iter().filter(|file_id| seen.insert(*file_id)) {
+            match state.validate_for_download(file_id) {
+                Ok(true) => to_start.push(file_id.clone()),
+                Ok(false) => (),
+                Err(crate::Error::FileStateMismatch(FileTerminalState::Completed))
+                    if self.state.config.redownload_missing
+                        && state
+                            .check_missing_completed(&self.state.storage, file_id, true)
+                            .await =>
+                {
+                    to_start.push(file_id.clone())
+                }
+                Err(err) => failed.push((file_id.clone(), err)),
+            }
+        }
+
+        if to_start.is_empty() {
+            return Ok(failed);
+        }
+
+        validate_dest_path(parent_dir.as_ref())?;
+        if !self.has_free_space(state, &to_start, parent_dir) {
+            return Err(crate::Error::InsufficientStorage);
+        }
+
+        for file_id in &to_start {
             state.file_events(file_id)?.pending(parent_dir).await;
+        }
 
-            state
-                .start_download(
-                    &self.state.storage,
-                    file_id,
-                    parent_dir.as_ref(),
-                    &self.logger,
-                )
-                .await?;
+        state
+            .start_downloads(
+                &self.state.storage,
+                &to_start,
+                parent_dir.as_ref(),
+                renames,
+                &self.logger,
+            )
+            .await?;
+
+        Ok(failed)
+    }
+
+    /// Check the destination can hold the remaining bytes of all of the files.
+    /// The check is skipped when the free space can't be queried. The streamed
+    /// files count as empty here, their space is checked as the bytes arrive
+    fn has_free_space(
+        &self,
+        state: &manager::IncomingState,
+        file_ids: &[FileId],
+        parent_dir: &str,
+    ) -> bool {
+        let Some(available) = utils::available_space(parent_dir.as_ref()) else {
+            return true;
+        };
+
+        let required = file_ids
+            .iter()
+            .filter_map(|file_id| state.xfer.files().get(file_id))
+            .map(|file| {
+                let tmp_location =
+                    Path::new(parent_dir).join(temp_file_name(state.xfer.id(), file.id()));
+                let received = fs::metadata(tmp_location).map_or(0, |meta| meta.len());
+
+                file.size().saturating_sub(received)
+            })
+            .sum();
+
+        if available < required {
+            warn!(
+                self.logger,
+                "Not enough space in {:?} to download transfer {}, required: {required}, \
+                 available: {available}",
+                utils::Hidden(parent_dir),
+                state.xfer.id()
+            );
+
+            self.state
+                .emit_event(Event::IncomingTransferInsufficientStorage {
+                    transfer_id: state.xfer.id(),
+                    base_dir: parent_dir.to_string(),
+                    required,
+                    available,
+                });
+
+            return false;
         }
 
-        Ok(())
+        true
+    }
+
+    /// Returns the connection diagnostics of the transfer if it's loaded in
+    /// memory
+    pub async fn connection_metrics(&self, transfer_id: Uuid) -> Option<ConnectionMetrics> {
+        self.state
+            .transfer_manager
+            .connection_metrics(transfer_id)
+            .await
+    }
+
+    /// Returns the service-wide counters
+    pub async fn metrics(&self) -> ServiceMetrics {
+        let (active_incoming, active_outgoing) =
+            self.state.transfer_manager.active_transfers().await;
+        let metrics = &self.state.metrics;
+
+        ServiceMetrics {
+            active_incoming,
+            active_outgoing,
+            bytes_transferred: metrics.bytes_transferred(),
+            upload_rate: metrics.upload_rate(),
+            download_rate: metrics.download_rate(),
+            connected_peers: metrics.connections(),
+            rate_limited_requests: metrics.rate_limited(),
+        }
+    }
+
+    /// Returns the files of the incoming transfers awaiting the user decision,
+    /// neither downloaded nor rejected yet
+    pub async fn pending_incoming_files(&self) -> Vec<(Uuid, Vec<FileId>)> {
+        self.state.transfer_manager.incoming_pending_files().await
+    }
+
+    /// Recompute the checksum of an already downloaded file and compare it
+    /// with the one advertised by the sender. Emits the verify checksum events.
+    /// The returned future does not borrow the service so that the file can be
+    /// hashed without blocking the other calls
+    pub fn verify_file(
+        &self,
+        transfer_id: Uuid,
+        file_id: FileId,
+    ) -> impl Future<Output = crate::Result<VerifyResult>> + Send + 'static {
+        debug!(
+            self.logger,
+            "Client::verify_file() called with Uuid: {transfer_id}, file: {file_id:?}"
+        );
+
+        let state = self.state.clone();
+
+        async move {
+            let file = state
+                .storage
+                .completed_incoming_file(transfer_id, file_id.as_ref())
+                .await
+                .ok_or(crate::Error::BadFileId)?;
+
+            // Removed on purpose by the post completion hook
+            if file.deleted {
+                return Ok(VerifyResult::FileMissing);
+            }
+
+            let expected = file.checksum.ok_or_else(|| {
+                crate::Error::BadTransferState("The file checksum is not known".into())
+            })?;
+
+            let reader = match fs::File::open(&file.final_path) {
+                Ok(reader) => reader,
+                Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                    return Ok(VerifyResult::FileMissing)
+                }
+                Err(err) => return Err(err.into()),
+            };
+            let csum = checksum_file(&state, transfer_id, &file_id, reader).await?;
+
+            if csum[..] == expected[..] {
+                Ok(VerifyResult::Ok)
+            } else {
+                Ok(VerifyResult::Mismatch)
+            }
+        }
+    }
+
+    /// Returns the hex encoded checksum of the incoming file, `None` if it is
+    /// not known
+    pub async fn file_checksum(&self, transfer_id: Uuid, file_id: FileId) -> Option<String> {
+        self.state
+            .storage
+            .file_checksum(transfer_id, file_id.as_ref())
+            .await
+            .map(hex::encode)
+    }
+
+    /// Returns the downloaded files of the incoming transfer for which the
+    /// checksum is not known. The files removed by the post completion hook
+    /// are left out
+    pub async fn files_awaiting_checksum(&self, transfer_id: Uuid) -> Vec<FileId> {
+        let storage = &self.state.storage;
+
+        let mut files = Vec::new();
+        for file in storage.fetch_checksums(transfer_id).await {
+            if file.checksum.is_none()
+                && storage
+                    .completed_incoming_file(transfer_id, &file.file_id)
+                    .await
+                    .is_some_and(|completed| !completed.deleted)
+            {
+                files.push(FileId::from(file.file_id));
+            }
+        }
+
+        files
+    }
+
+    /// Compute the checksum of an already downloaded file regardless of the
+    /// `checksum_events_size_threshold` config. Emits the verify checksum
+    /// events. The checksum is stored unless already known, the stored one is
+    /// never overwritten
+    pub async fn request_checksum(
+        &self,
+        transfer_id: Uuid,
+        file_id: FileId,
+    ) -> crate::Result<[u8; 32]> {
+        debug!(
+            self.logger,
+            "Client::request_checksum() called with Uuid: {transfer_id}, file: {file_id:?}"
+        );
+
+        let file = self
+            .state
+            .storage
+            .completed_incoming_file(transfer_id, file_id.as_ref())
+            .await
+            .ok_or(crate::Error::BadFileId)?;
+
+        if file.deleted {
+            return Err(crate::Error::BadTransferState(
+                "The file was removed by the post completion hook".into(),
+            ));
+        }
+
+        let reader = fs::File::open(&file.final_path)?;
+        let csum = checksum_file(&self.state, transfer_id, &file_id, reader).await?;
+
+        let known = self
+            .state
+            .storage
+            .fetch_checksums(transfer_id)
+            .await
+            .into_iter()
+            .any(|known| known.file_id == file_id.as_ref() && known.checksum.is_some());
+
+        if !known {
+            self.state
+                .storage
+                .save_checksum(transfer_id, file_id.as_ref(), &csum)
+                .await;
+        }
+
+        Ok(csum)
+    }
+
+    /// Remove the temporary download files that belong to transfers no longer
+    /// live. Every directory the files were ever downloaded into is scanned.
+    /// Files of transfers that can still be resumed are kept. Returns the
+    /// number of removed files and the bytes reclaimed
+    pub async fn cleanup_orphan_temp_files(&self) -> (usize, u64) {
+        debug!(self.logger, "Client::cleanup_orphan_temp_files() called");
+
+        let mut live = HashMap::new();
+        let mut count = 0;
+        let mut bytes = 0;
+
+        for base_dir in self.state.storage.fetch_temp_base_dirs().await {
+            let entries = match fs::read_dir(&base_dir) {
+                Ok(entries) => entries,
+                Err(err) if err.kind() == io::ErrorKind::NotFound => continue,
+                Err(err) => {
+                    warn!(
+                        self.logger,
+                        "Failed to read directory {:?}: {err}",
+                        utils::Hidden(&base_dir)
+                    );
+                    continue;
+                }
+            };
+
+            for entry in entries.flatten() {
+                let Some(transfer_id) = entry
+                    .file_name()
+                    .to_str()
+                    .and_then(ws::server::temp_file_transfer_id)
+                else {
+                    continue;
+                };
+
+                let is_live = match live.get(&transfer_id) {
+                    Some(&is_live) => is_live,
+                    None => {
+                        let is_live = self
+                            .state
+                            .transfer_manager
+                            .incoming
+                            .lock()
+                            .await
+                            .contains_key(&transfer_id)
+                            || self
+                                .state
+                                .storage
+                                .transfer_sync_state(transfer_id)
+                                .await
+                                .is_some_and(|state| {
+                                    !matches!(
+                                        state.local_state,
+                                        drop_storage::sync::TransferState::Canceled
+                                    )
+                                });
+
+                        live.insert(transfer_id, is_live);
+                        is_live
+                    }
+                };
+
+                if is_live {
+                    continue;
+                }
+
+                let path = entry.path();
+                let size = entry.metadata().map(|meta| meta.len()).unwrap_or(0);
+
+                debug!(
+                    self.logger,
+                    "Removing orphaned temporary file: {:?}",
+                    utils::Hidden(&path)
+                );
+                match fs::remove_file(&path) {
+                    Ok(()) => {
+                        count += 1;
+                        bytes += size;
+                    }
+                    Err(err) => {
+                        warn!(
+                            self.logger,
+                            "Failed to remove orphaned temporary file {:?}: {err}",
+                            utils::Hidden(&path)
+                        );
+                    }
+                }
+            }
+        }
+
+        (count, bytes)
+    }
+
+    /// Check the status of the transfer. The transfers tracked in memory take
+    /// precedence, the outgoing ones being checked first. For those, a local
+    /// cancellation awaiting the peer reports `Canceled`, the transfer with
+    /// all of the files in terminal state reports `Completed` and otherwise
+    /// it's `Active` or `Deferred` depending on the connection. Only then the
+    /// persisted states are consulted, where the not finished transfers
+    /// report `Deferred` as they can be resumed
+    pub async fn transfer_status(&self, transfer_id: Uuid) -> TransferStatus {
+        if let Some(state) = self
+            .state
+            .transfer_manager
+            .outgoing
+            .lock()
+            .await
+            .get(&transfer_id)
+        {
+            return state.status();
+        }
+
+        if let Some(state) = self
+            .state
+            .transfer_manager
+            .incoming
+            .lock()
+            .await
+            .get(&transfer_id)
+        {
+            return state.status();
+        }
+
+        match self.state.storage.stored_transfer_state(transfer_id).await {
+            Some(StoredTransferState::InProgress) => TransferStatus::Deferred,
+            Some(StoredTransferState::Canceled) => TransferStatus::Canceled,
+            Some(StoredTransferState::Failed) => TransferStatus::Failed,
+            Some(StoredTransferState::Completed) => TransferStatus::Completed,
+            None => TransferStatus::Unknown,
+        }
+    }
+
+    /// Explain why the transfer is not progressing. `None` is returned for
+    /// the actively transferring ones as well as for the finished or not
+    /// loaded transfers, see `transfer_status()` for those. Only the in-memory
+    /// state is inspected, nothing is changed
+    pub async fn stall_reason(&self, transfer_id: Uuid) -> Option<StallReason> {
+        let now = self.state.clock.instant();
+
+        if let Some(state) = self
+            .state
+            .transfer_manager
+            .outgoing
+            .lock()
+            .await
+            .get(&transfer_id)
+        {
+            return state.stall_reason(now).await;
+        }
+
+        let incoming = self.state.transfer_manager.incoming.lock().await;
+        incoming.get(&transfer_id)?.stall_reason(now).await
     }
 
     /// Reject a single file in a transfer. After rejection the file can no
@@ -265,8 +1294,71 @@ impl Service {
         Err(crate::Error::BadTransfer)
     }
 
+    /// Cap the throughput of the transfer in bytes per second, leaving the
+    /// other transfers unaffected. `None` removes the cap. The cap is stored
+    /// and so it applies once the transfer is resumed after a restart too
+    pub async fn set_transfer_rate_limit(
+        &self,
+        transfer_id: Uuid,
+        limit: Option<u64>,
+    ) -> crate::Result<()> {
+        if limit == Some(0) {
+            return Err(crate::Error::InvalidArgument);
+        }
+
+        self.state
+            .transfer_manager
+            .set_rate_limit(transfer_id, limit)
+            .await
+    }
+
+    /// Reject multiple files of the incoming transfer at once. The files
+    /// rejected already are skipped. When no file is left to act upon, the
+    /// transfer is closed as canceled by the user
+    pub async fn reject_files(&self, transfer_id: Uuid, files: &[FileId]) -> crate::Result<()> {
+        let res = self
+            .state
+            .transfer_manager
+            .incoming_reject_many(transfer_id, files)
+            .await?;
+
+        for events in &res.file_events {
+            let file_id = events.file_id();
+            let tmp_bases = self
+                .state
+                .storage
+                .fetch_base_dirs_for_file(transfer_id, file_id.as_ref())
+                .await;
+
+            super::ws::server::remove_temp_files(
+                &self.logger,
+                transfer_id,
+                tmp_bases.into_iter().map(|base| (base, file_id)),
+            );
+
+            events.rejected(false).await;
+        }
+
+        if let Some((xfer_events, stats)) = res.closed {
+            xfer_events.cancel(false, CancelCause::User, stats).await;
+        }
+
+        Ok(())
+    }
+
     /// Cancel all of the files in a transfer
     pub async fn cancel_all(&mut self, transfer_id: Uuid) -> crate::Result<()> {
+        self.cancel_all_with_cause(transfer_id, CancelCause::User)
+            .await
+    }
+
+    /// Cancel all of the files in a transfer. The cause is persisted along
+    /// with the cancel state and carried by the cancel event
+    pub async fn cancel_all_with_cause(
+        &mut self,
+        transfer_id: Uuid,
+        cause: CancelCause,
+    ) -> crate::Result<()> {
         {
             match self
                 .state
@@ -282,7 +1374,7 @@ impl Service {
                     )
                     .await;
 
-                    res.xfer_events.cancel(false).await;
+                    res.xfer_events.cancel(false, cause, res.stats).await;
                     return Ok(());
                 }
                 Err(crate::Error::BadTransfer) => (),
@@ -304,7 +1396,7 @@ impl Service {
                     )
                     .await;
 
-                    res.xfer_events.cancel(false).await;
+                    res.xfer_events.cancel(false, cause, res.stats).await;
                     return Ok(());
                 }
                 Err(crate::Error::BadTransfer) => (),
@@ -314,6 +1406,164 @@ impl Service {
 
         Err(crate::Error::BadTransfer)
     }
+
+    /// Reject the whole incoming transfer. All of the not yet terminated files
+    /// are rejected and the transfer is closed. Unlike `cancel_all()` the
+    /// transfer ends up with the rejected event carrying the given reason
+    pub async fn reject_transfer(
+        &mut self,
+        transfer_id: Uuid,
+        reason: String,
+    ) -> crate::Result<()> {
+        let res = self
+            .state
+            .transfer_manager
+            .incoming_reject_all(transfer_id, &reason)
+            .await?;
+
+        debug!(
+            self.logger,
+            "Rejecting transfer {transfer_id}, reason: {reason}"
+        );
+
+        for events in &res.file_events {
+            let file_id = events.file_id();
+            let tmp_bases = self
+                .state
+                .storage
+                .fetch_base_dirs_for_file(transfer_id, file_id.as_ref())
+                .await;
+
+            super::ws::server::remove_temp_files(
+                &self.logger,
+                transfer_id,
+                tmp_bases.into_iter().map(|base| (base, file_id)),
+            );
+
+            events.rejected(false).await;
+        }
+
+        res.xfer_events.rejected(reason, res.stats).await;
+        Ok(())
+    }
+
+    /// Move the not yet completed downloads of the incoming transfer into a
+    /// new directory. The partially downloaded files are moved along and the
+    /// downloads continue where they stopped. Already completed files are
+    /// left in place. Returns the redirected files
+    pub async fn redirect_incoming(
+        &mut self,
+        transfer_id: Uuid,
+        base_dir: &str,
+    ) -> crate::Result<Vec<FileId>> {
+        debug!(
+            self.logger,
+            "Client::redirect_incoming() called with Uuid: {transfer_id}, base_dir: {:?}",
+            utils::Hidden(base_dir)
+        );
+
+        self.state
+            .transfer_manager
+            .incoming_redirect(transfer_id, Path::new(base_dir))
+            .await
+    }
+
+    /// Cancel all of the transfers with the given peer and stop any further
+    /// connection retries to it. Returns the IDs of the canceled transfers
+    pub async fn cancel_peer(&mut self, peer: IpAddr) -> Vec<Uuid> {
+        let outgoing: Vec<_> = {
+            let lock = self.state.transfer_manager.outgoing.lock().await;
+            lock.values()
+                .filter(|state| state.xfer.peer() == peer)
+                .map(|state| state.xfer.id())
+                .collect()
+        };
+
+        let incoming: Vec<_> = {
+            let lock = self.state.transfer_manager.incoming.lock().await;
+            lock.values()
+                .filter(|state| state.xfer.peer() == peer)
+                .map(|state| state.xfer.id())
+                .collect()
+        };
+
+        let mut canceled = Vec::new();
+
+        for transfer_id in outgoing.into_iter().chain(incoming) {
+            if let Some(client_stop) = self
+                .state
+                .transfer_manager
+                .outgoing_client_stop(transfer_id)
+                .await
+            {
+                client_stop.cancel();
+            }
+
+            match self.cancel_all(transfer_id).await {
+                Ok(()) => canceled.push(transfer_id),
+                Err(err) => {
+                    debug!(self.logger, "Transfer {transfer_id} not canceled: {err}");
+                }
+            }
+        }
+
+        canceled
+    }
+}
+
+// Hashes the downloaded file emitting the verify checksum events
+async fn checksum_file(
+    state: &State,
+    transfer_id: Uuid,
+    file_id: &FileId,
+    reader: fs::File,
+) -> io::Result<[u8; 32]> {
+    let size = reader.metadata()?.len();
+
+    state.emit_event(Event::VerifyChecksumStarted {
+        transfer_id,
+        file_id: file_id.clone(),
+        size,
+    });
+
+    let csum = crate::file::checksum(
+        reader,
+        state.config.checksum_chunk_size,
+        Some(|progress| {
+            state.emit_event(Event::VerifyChecksumProgress {
+                transfer_id,
+                file_id: file_id.clone(),
+                progress,
+            });
+            futures::future::ready(())
+        }),
+        Some(state.config.checksum_events_granularity),
+    )
+    .await?;
+
+    state.emit_event(Event::VerifyChecksumFinished {
+        transfer_id,
+        file_id: file_id.clone(),
+    });
+
+    Ok(csum)
+}
+
+// Spreads the failure to start the downloads over the files that were to be
+// started, the same as if they were downloaded one by one
+fn fail_each(
+    state: &manager::IncomingState,
+    file_ids: &[FileId],
+    err: crate::Error,
+) -> Vec<(FileId, crate::Error)> {
+    file_ids
+        .iter()
+        .filter_map(|file_id| match state.validate_for_download(file_id) {
+            Ok(true) => Some((file_id.clone(), err.duplicate())),
+            Ok(false) => None,
+            Err(err) => Some((file_id.clone(), err)),
+        })
+        .collect()
 }
 
 fn validate_dest_path(parent_dir: &Path) -> crate::Result<()> {
@@ -333,3 +1583,299 @@ fn validate_dest_path(parent_dir: &Path) -> crate::Result<()> {
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ws::server::ServerReq;
+
+    fn mock_service(logger: Logger) -> Service {
+        let (tx, _rx) = tokio::sync::mpsc::unbounded_channel();
+        let state = Arc::new(ws::server::tests::mock_state(
+            &logger,
+            Arc::new(DropConfig::default()),
+            tx,
+        ));
+
+        Service {
+            state,
+            stop: CancellationToken::new(),
+            waiter: AliveWaiter::new(),
+            server_stop: CancellationToken::new(),
+            server: None,
+            logger,
+            refresh_trigger: tokio::sync::watch::channel(()).0,
+        }
+    }
+
+    // Restores the incoming transfer with the given files and connects it,
+    // returning the requests sent over the connection
+    async fn connected_incoming(
+        service: &Service,
+        transfer_id: Uuid,
+        files: &[&str],
+    ) -> mpsc::UnboundedReceiver<ServerReq> {
+        use drop_storage::types::{TransferFiles, TransferIncomingPath};
+
+        service
+            .state
+            .storage
+            .insert_transfer(&drop_storage::TransferInfo {
+                id: transfer_id,
+                peer: "1.2.3.4".to_string(),
+                files: TransferFiles::Incoming(
+                    files
+                        .iter()
+                        .map(|file_id| TransferIncomingPath {
+                            file_id: file_id.to_string(),
+                            relative_path: format!("{file_id}.txt"),
+                            size: 1024,
+                        })
+                        .collect(),
+                ),
+            })
+            .await;
+        manager::restore_transfers_state(&service.state, &service.logger).await;
+
+        let xfer = service.state.transfer_manager.incoming.lock().await[&transfer_id]
+            .xfer
+            .clone();
+        let (conn, conn_rx) = mpsc::unbounded_channel();
+        service
+            .state
+            .transfer_manager
+            .register_incoming(xfer, conn, 0)
+            .await
+            .unwrap();
+
+        conn_rx
+    }
+
+    #[tokio::test]
+    async fn cleaning_orphan_temp_files() {
+        use drop_storage::types::{TransferFiles, TransferIncomingPath};
+
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+        let service = mock_service(logger);
+        let storage = service.state.storage.clone();
+
+        let live = Uuid::new_v4();
+        let canceled = Uuid::new_v4();
+        let removed = Uuid::new_v4();
+
+        let dir = tempfile::tempdir().unwrap();
+        let removed_dir = tempfile::tempdir().unwrap();
+        let base_dir = |id| {
+            if id == removed {
+                removed_dir.path()
+            } else {
+                dir.path()
+            }
+        };
+
+        for id in [live, canceled, removed] {
+            storage
+                .insert_transfer(&drop_storage::TransferInfo {
+                    id,
+                    peer: "1.2.3.4".to_string(),
+                    files: TransferFiles::Incoming(vec![TransferIncomingPath {
+                        file_id: "id1".to_string(),
+                        relative_path: "a.txt".to_string(),
+                        size: 1024,
+                    }]),
+                })
+                .await;
+            storage
+                .start_incoming_file(id, "id1", &base_dir(id).to_string_lossy())
+                .await;
+        }
+
+        storage
+            .update_transfer_sync_states(canceled, drop_storage::sync::TransferState::Canceled)
+            .await;
+
+        // Removed from the storage altogether, along with its base directory
+        storage
+            .insert_transfer_cancel_state(removed, false, CancelCause::User)
+            .await;
+        storage.transfer_sync_clear(removed).await.unwrap();
+        storage.purge_transfers(&[removed.to_string()]).await;
+        assert_eq!(storage.cleanup_garbage_transfers().await, 1);
+
+        let tmp = |id| ws::server::temp_file_name(id, &FileId::from("id1"));
+        for id in [live, canceled, removed] {
+            fs::write(base_dir(id).join(tmp(id)), b"abc").unwrap();
+        }
+        // Left behind in the directory of another transfer
+        fs::write(dir.path().join(tmp(removed)), b"abcd").unwrap();
+        fs::write(dir.path().join("a.txt"), b"data").unwrap();
+
+        assert_eq!(service.cleanup_orphan_temp_files().await, (3, 10));
+
+        assert!(dir.path().join(tmp(live)).exists());
+        assert!(!dir.path().join(tmp(canceled)).exists());
+        assert!(!dir.path().join(tmp(removed)).exists());
+        assert!(!removed_dir.path().join(tmp(removed)).exists());
+        assert!(dir.path().join("a.txt").exists());
+
+        assert_eq!(service.cleanup_orphan_temp_files().await, (0, 0));
+    }
+
+    #[tokio::test]
+    async fn downloading_only_selected_files() {
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+        let mut service = mock_service(logger);
+
+        let transfer_id: Uuid = "23e488a4-0521-11ee-be56-0242ac120002".parse().unwrap();
+        let mut conn_rx = connected_incoming(&service, transfer_id, &["id1", "id2", "id3"]).await;
+
+        let state = service.state.clone();
+        let pending = || async {
+            let mut files: Vec<_> = state
+                .transfer_manager
+                .incoming_pending_files()
+                .await
+                .into_iter()
+                .flat_map(|(_, files)| files)
+                .collect();
+            files.sort_by(|a, b| a.as_ref().cmp(b.as_ref()));
+            files
+        };
+        let all: Vec<FileId> = vec!["id1".into(), "id2".into(), "id3".into()];
+
+        // Nothing is rejected when the downloads cannot be started
+        let dir = tempfile::tempdir().unwrap();
+        let not_a_dir = dir.path().join("file");
+        fs::write(&not_a_dir, b"data").unwrap();
+        assert!(service
+            .download_only(transfer_id, &all[..1], &not_a_dir.to_string_lossy())
+            .await
+            .is_err());
+        assert_eq!(pending().await, all);
+        assert!(conn_rx.try_recv().is_err());
+
+        // The download issued earlier is kept even though it's not selected
+        assert!(service
+            .download_files(transfer_id, &all[1..2], &dir.path().to_string_lossy())
+            .await
+            .unwrap()
+            .is_empty());
+        assert!(matches!(conn_rx.try_recv(), Ok(ServerReq::Download { .. })));
+
+        let failed = service
+            .download_only(transfer_id, &all[..1], &dir.path().to_string_lossy())
+            .await
+            .unwrap();
+        assert!(failed.is_empty());
+        assert!(pending().await.is_empty());
+
+        // The rest of the files is rejected with a single selection
+        let reqs: Vec<_> = std::iter::from_fn(|| conn_rx.try_recv().ok()).collect();
+        assert_eq!(reqs.len(), 2);
+        assert!(matches!(&reqs[0], ServerReq::Download { task } if task.file.id() == &all[0]));
+        assert!(matches!(&reqs[1], ServerReq::Select { rejected } if rejected == &all[2..]));
+        assert!(matches!(
+            state
+                .storage
+                .incoming_file_sync_state(transfer_id, "id3")
+                .await
+                .unwrap()
+                .sync,
+            drop_storage::sync::FileState::Terminal
+        ));
+
+        // The selected downloads keep the transfer going
+        assert_eq!(
+            service.transfer_status(transfer_id).await,
+            TransferStatus::Active
+        );
+        let lock = state.transfer_manager.incoming.lock().await;
+        let state = &lock[&transfer_id];
+        assert!(matches!(state.validate_for_download(&all[0]), Ok(false)));
+        assert!(matches!(state.validate_for_download(&all[1]), Ok(false)));
+        assert!(matches!(
+            state.validate_for_download(&all[2]),
+            Err(crate::Error::FileStateMismatch(FileTerminalState::Rejected))
+        ));
+    }
+
+    #[tokio::test]
+    async fn downloading_repeated_files() {
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+        let mut service = mock_service(logger);
+
+        let transfer_id: Uuid = "23e488a4-0521-11ee-be56-0242ac120002".parse().unwrap();
+        let mut conn_rx = connected_incoming(&service, transfer_id, &["id1", "id2", "id3"]).await;
+
+        let dir = tempfile::tempdir().unwrap();
+        let dir = dir.path().to_string_lossy();
+        let id = FileId::from;
+
+        // Each file is downloaded once no matter how many times it's listed
+        let failed = service
+            .download_files(transfer_id, &[id("id1"), id("id2"), id("id1")], &dir)
+            .await
+            .unwrap();
+        assert!(failed.is_empty());
+
+        let downloads: Vec<_> = std::iter::from_fn(|| conn_rx.try_recv().ok())
+            .map(|req| match req {
+                ServerReq::Download { task } => task.file.id().clone(),
+                _ => panic!("Expected only the download requests"),
+            })
+            .collect();
+        assert_eq!(downloads, [id("id1"), id("id2")]);
+
+        // And the failure is reported once as well
+        service.reject(transfer_id, id("id3")).await.unwrap();
+        assert!(matches!(conn_rx.try_recv(), Ok(ServerReq::Reject { .. })));
+
+        let failed = service
+            .download_files(transfer_id, &[id("id3"), id("id3")], &dir)
+            .await
+            .unwrap();
+        assert_eq!(failed.len(), 1);
+        assert_eq!(failed[0].0, id("id3"));
+        assert!(matches!(
+            failed[0].1,
+            crate::Error::FileStateMismatch(FileTerminalState::Rejected)
+        ));
+        assert!(conn_rx.try_recv().is_err());
+
+        let failed = service.download_all(transfer_id, &dir).await.unwrap();
+        assert!(failed.is_empty());
+        assert!(conn_rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn downloading_all_to_bad_destination() {
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+        let mut service = mock_service(logger);
+
+        let transfer_id: Uuid = "23e488a4-0521-11ee-be56-0242ac120002".parse().unwrap();
+        let mut conn_rx = connected_incoming(&service, transfer_id, &["id1", "id2"]).await;
+
+        // Each of the files fails on its own and the transfer stays alive
+        let mut failed = service
+            .download_all(transfer_id, "/tmp/../etc")
+            .await
+            .unwrap();
+        failed.sort_by_key(|(file_id, _)| file_id.to_string());
+
+        assert_eq!(failed.len(), 2);
+        assert_eq!(failed[0].0, FileId::from("id1"));
+        assert_eq!(failed[1].0, FileId::from("id2"));
+        assert!(failed
+            .iter()
+            .all(|(_, err)| matches!(err, crate::Error::BadPath(_))));
+
+        assert!(conn_rx.try_recv().is_err());
+        assert!(
+            service
+                .state
+                .transfer_manager
+                .is_incoming_alive(transfer_id)
+                .await
+        );
+    }
+}
@@ -1,6 +1,6 @@
 use std::collections::HashMap;
 
-use drop_storage::Storage;
+use drop_storage::{CancelCause, Storage};
 use uuid::Uuid;
 
 use crate::{transfer::Transfer, FileId};
@@ -46,6 +46,8 @@ impl<'a> StorageDispatch<'a> {
                         transfer.id(),
                         download.id.as_ref(),
                         &download.final_path.to_string_lossy(),
+                        download.renamed,
+                        download.deleted,
                     )
                     .await
             }
@@ -54,15 +56,33 @@ impl<'a> StorageDispatch<'a> {
                     .insert_outgoing_path_completed_state(transfer.id(), file_id.as_ref())
                     .await
             }
-            crate::Event::IncomingTransferCanceled(transfer, by_peer) => {
+            crate::Event::IncomingTransferCanceled(transfer, by_peer, cause) => {
                 self.storage
-                    .insert_transfer_cancel_state(transfer.id(), *by_peer)
+                    .insert_transfer_cancel_state(transfer.id(), *by_peer, *cause)
                     .await;
                 self.clear_transfer(transfer.id());
             }
-            crate::Event::OutgoingTransferCanceled(transfer, by_peer) => {
+            crate::Event::IncomingTransferRejected { transfer, reason } => {
                 self.storage
-                    .insert_transfer_cancel_state(transfer.id(), *by_peer)
+                    .insert_transfer_reject_state(transfer.id(), false, reason)
+                    .await;
+                self.clear_transfer(transfer.id());
+            }
+            crate::Event::IncomingTransferExpired(transfer) => {
+                self.storage
+                    .insert_transfer_cancel_state(transfer.id(), false, CancelCause::Expired)
+                    .await;
+                self.clear_transfer(transfer.id());
+            }
+            crate::Event::OutgoingTransferCanceled(transfer, by_peer, cause) => {
+                self.storage
+                    .insert_transfer_cancel_state(transfer.id(), *by_peer, *cause)
+                    .await;
+                self.clear_transfer(transfer.id());
+            }
+            crate::Event::OutgoingTransferRejected { transfer, reason } => {
+                self.storage
+                    .insert_transfer_reject_state(transfer.id(), true, reason)
                     .await;
                 self.clear_transfer(transfer.id());
             }
@@ -154,9 +174,18 @@ impl<'a> StorageDispatch<'a> {
             // not stored in the database
             crate::Event::RequestReceived(_) => (),
             crate::Event::RequestQueued(_) => (),
+            crate::Event::IncomingTransferCreated(_) => (),
+            crate::Event::OutgoingTransferCreated(_) => (),
             crate::Event::FileUploadThrottled { .. } => (),
+            crate::Event::IncomingTransferInsufficientStorage { .. } => (),
 
             crate::Event::OutgoingTransferDeferred { .. } => (),
+            crate::Event::TransferSummary { .. } => (),
+            crate::Event::TransferPaused { .. } => (),
+            crate::Event::TransferResumed { .. } => (),
+            crate::Event::ProtocolDowngraded { .. } => (),
+            crate::Event::ListenAddressChanged { .. } => (),
+            crate::Event::PeerInfo { .. } => (),
 
             crate::Event::FinalizeChecksumStarted { .. } => (),
             crate::Event::FinalizeChecksumFinished { .. } => (),
@@ -167,6 +196,7 @@ impl<'a> StorageDispatch<'a> {
             crate::Event::VerifyChecksumProgress { .. } => (),
 
             crate::Event::FileDownloadPending { .. } => (),
+            crate::Event::FileDownloadMissing { .. } => (),
         }
     }
 
@@ -191,3 +221,143 @@ impl<'a> StorageDispatch<'a> {
         self.file_progress.remove(&transfer_id);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, time::SystemTime};
+
+    use drop_config::DropConfig;
+    use drop_storage::{
+        types::{
+            DbTransferType, IncomingPathStateEvent, IncomingPathStateEventData,
+            OutgoingPathStateEvent, OutgoingPathStateEventData, TransferStateEvent,
+            TransferStateEventData,
+        },
+        TransferSort,
+    };
+
+    use super::*;
+    use crate::{ws::EventTxFactory, FileToRecv, FileToSend, IncomingTransfer, OutgoingTransfer};
+
+    #[tokio::test]
+    async fn storing_cancel_and_reject_initiators() {
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+        let storage = Storage::new(logger, ":memory:").unwrap();
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        let factory = EventTxFactory::new(tx, drop_analytics::moose_mock(), 1);
+        let config = DropConfig::default();
+
+        let incoming = Arc::new(
+            IncomingTransfer::new(
+                "1.2.3.4".parse().unwrap(),
+                vec![FileToRecv::new("id1".into(), "a.txt".into(), 1024)],
+                &config,
+            )
+            .unwrap(),
+        );
+        let outgoing = Arc::new(
+            OutgoingTransfer::new(
+                "5.6.7.8".parse().unwrap(),
+                vec![FileToSend::from_bytes(
+                    "b.txt".into(),
+                    Arc::new(b"abc".to_vec()),
+                    None,
+                )],
+                &config,
+            )
+            .unwrap(),
+        );
+        let outgoing_file = outgoing.files().keys().next().unwrap().clone();
+
+        storage.insert_transfer(&incoming.storage_info()).await;
+        storage.insert_transfer(&outgoing.storage_info()).await;
+
+        // The receiver rejects its file and the sender cancels the transfer
+        factory
+            .file(incoming.clone(), "id1".into())
+            .rejected(false)
+            .await;
+        factory
+            .transfer(incoming.clone(), false, SystemTime::now())
+            .cancel(true, CancelCause::User, Default::default())
+            .await;
+        // The receiver rejects the sent file and we cancel the transfer
+        factory
+            .file(outgoing.clone(), outgoing_file)
+            .rejected(true)
+            .await;
+        factory
+            .transfer(outgoing.clone(), false, SystemTime::now())
+            .cancel(false, CancelCause::User, Default::default())
+            .await;
+
+        let mut dispatch = StorageDispatch::new(&storage);
+        let mut initiators = Vec::new();
+        while let Ok((event, _)) = rx.try_recv() {
+            match &event {
+                crate::Event::FileDownloadRejected { by_peer, .. } => {
+                    initiators.push(("download rejected", *by_peer))
+                }
+                crate::Event::IncomingTransferCanceled(_, by_peer, _) => {
+                    initiators.push(("incoming canceled", *by_peer))
+                }
+                crate::Event::FileUploadRejected { by_peer, .. } => {
+                    initiators.push(("upload rejected", *by_peer))
+                }
+                crate::Event::OutgoingTransferCanceled(_, by_peer, _) => {
+                    initiators.push(("outgoing canceled", *by_peer))
+                }
+                _ => (),
+            }
+            dispatch.handle_event(&event).await;
+        }
+
+        assert_eq!(
+            initiators,
+            [
+                ("download rejected", false),
+                ("incoming canceled", true),
+                ("upload rejected", true),
+                ("outgoing canceled", false),
+            ]
+        );
+
+        let transfers = storage.transfers_since(0, TransferSort::CreatedAsc).await;
+        assert_eq!(transfers.len(), 2);
+
+        for transfer in transfers {
+            let is_incoming = transfer.id == incoming.id();
+
+            assert!(matches!(
+                transfer.states[..],
+                [TransferStateEvent {
+                    data: TransferStateEventData::Cancel { by_peer, .. },
+                    ..
+                }] if by_peer == is_incoming
+            ));
+
+            match transfer.transfer_type {
+                DbTransferType::Incoming(paths) => {
+                    assert!(is_incoming);
+                    assert!(matches!(
+                        paths[0].states[..],
+                        [IncomingPathStateEvent {
+                            data: IncomingPathStateEventData::Rejected { by_peer: false, .. },
+                            ..
+                        }]
+                    ));
+                }
+                DbTransferType::Outgoing(paths) => {
+                    assert!(!is_incoming);
+                    assert!(matches!(
+                        paths[0].states[..],
+                        [OutgoingPathStateEvent {
+                            data: OutgoingPathStateEventData::Rejected { by_peer: true, .. },
+                            ..
+                        }]
+                    ));
+                }
+            }
+        }
+    }
+}
@@ -1,4 +1,8 @@
-use std::{collections::HashMap, net::IpAddr};
+use std::{
+    collections::HashMap,
+    iter,
+    net::{IpAddr, SocketAddr},
+};
 
 use drop_analytics::{TransferDirection, TransferIntentEventData};
 use drop_config::DropConfig;
@@ -6,7 +10,7 @@ use drop_storage::TransferInfo as StorageInfo;
 use uuid::Uuid;
 
 use crate::{
-    file::{File, FileId, FileSource, FileSubPath, FileToRecv, FileToSend},
+    file::{File, FileId, FileSubPath, FileToRecv, FileToSend},
     utils, Error,
 };
 
@@ -69,6 +73,10 @@ pub trait Transfer {
 #[derive(Debug)]
 pub struct TransferData<F: File> {
     peer: IpAddr,
+    // Other addresses of the peer raced with the main one when connecting
+    alt_peers: Vec<IpAddr>,
+    // Zone of the link-local IPv6 peer addresses, the interface name or index
+    zone: Option<String>,
     uuid: Uuid,
 
     // all the files
@@ -99,7 +107,13 @@ impl<F: File> TransferData<F> {
             .map(|file| (file.id().clone(), file))
             .collect();
 
-        Ok(Self { peer, uuid, files })
+        Ok(Self {
+            peer,
+            alt_peers: Vec::new(),
+            zone: None,
+            uuid,
+            files,
+        })
     }
 }
 
@@ -144,16 +158,50 @@ impl IncomingTransfer {
 }
 
 impl OutgoingTransfer {
+    /// Sets the other addresses of the peer to try in parallel with the main
+    /// one. They live only in memory and so are not used once the transfer is
+    /// restored from the database
+    pub fn with_alt_peers(mut self, alt_peers: Vec<IpAddr>) -> Self {
+        self.alt_peers = alt_peers;
+        self
+    }
+
+    /// Sets the zone used to reach the link-local IPv6 peer, the name or the
+    /// index of the interface the peer is connected to. Unlike the alternative
+    /// addresses it's stored along with the transfer and so it's used once the
+    /// transfer is restored from the database too
+    pub fn with_zone(mut self, zone: Option<String>) -> Self {
+        self.zone = zone;
+        self
+    }
+
+    pub fn zone(&self) -> Option<&str> {
+        self.zone.as_deref()
+    }
+
+    /// The socket addresses of the main peer address followed by the
+    /// alternative ones
+    pub(crate) fn peer_candidates(&self, port: u16) -> impl Iterator<Item = SocketAddr> + '_ {
+        // The interface indices are not stable across reboots, so the zone is
+        // resolved on each connection attempt. A zone that no longer resolves
+        // leaves the address unscoped
+        let scope_id = self
+            .zone
+            .as_deref()
+            .and_then(utils::resolve_zone)
+            .unwrap_or(0);
+
+        iter::once(self.peer)
+            .chain(self.alt_peers.iter().copied())
+            .map(move |ip| utils::peer_socket_addr(ip, scope_id, port))
+    }
+
     pub(crate) fn storage_info(&self) -> StorageInfo {
         let files = self
             .files
             .values()
             .filter_map(|f| {
-                let uri = match &f.source {
-                    FileSource::Path(fullpath) => url::Url::from_file_path(&fullpath.0).ok()?,
-                    #[cfg(unix)]
-                    FileSource::Fd { content_uri, .. } => content_uri.clone(),
-                };
+                let uri = f.uri()?;
 
                 Some(drop_storage::types::TransferOutgoingPath {
                     file_id: f.id().to_string(),
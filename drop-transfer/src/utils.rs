@@ -1,36 +1,128 @@
 use std::{
+    cell::Cell,
     fmt, io, iter,
-    net::SocketAddr,
+    net::{IpAddr, Ipv6Addr, SocketAddr, SocketAddrV6},
     ops,
     path::{Path, PathBuf},
-    time::Duration,
+    sync::{
+        atomic::{AtomicU8, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
 };
 
+use drop_config::{LogRedaction, PeerResolution};
+use once_cell::sync::Lazy;
 use serde::{Deserialize, Serialize};
+use sha2::Digest;
 use tokio::{
     net::{TcpSocket, TcpStream},
     sync::watch,
 };
 
+use crate::Clock;
+
+thread_local! {
+    // The level of the logger the record is being logged through, the sensitive
+    // values are formatted where no config is at hand, e.g. in the `Debug`
+    // implementations
+    static LOG_REDACTION: Cell<LogRedaction> = const { Cell::new(LogRedaction::None) };
+}
+
+// Salts the hashes of the peer addresses so that they cannot be reversed by
+// hashing all of the possible addresses
+static PEER_SALT: Lazy<[u8; 16]> = Lazy::new(|| *uuid::Uuid::new_v4().as_bytes());
+
+fn log_redaction() -> LogRedaction {
+    LOG_REDACTION.with(Cell::get)
+}
+
+/// Masks the sensitive data in the records logged through it. The level is
+/// kept per drain and can be changed at any time
+#[derive(Clone)]
+pub struct RedactingDrain {
+    logger: slog::Logger,
+    level: Arc<AtomicU8>,
+}
+
+impl RedactingDrain {
+    pub fn new(logger: slog::Logger, level: LogRedaction) -> Self {
+        Self {
+            logger,
+            level: Arc::new(AtomicU8::new(level as u8)),
+        }
+    }
+
+    /// Sets how much of the sensitive data is masked in the logs from now on
+    pub fn set_level(&self, level: LogRedaction) {
+        self.level.store(level as u8, Ordering::Relaxed);
+    }
+
+    fn level(&self) -> LogRedaction {
+        match self.level.load(Ordering::Relaxed) {
+            x if x == LogRedaction::PathsAndPeers as u8 => LogRedaction::PathsAndPeers,
+            x if x == LogRedaction::Paths as u8 => LogRedaction::Paths,
+            _ => LogRedaction::None,
+        }
+    }
+}
+
+impl slog::Drain for RedactingDrain {
+    type Ok = ();
+    type Err = slog::Never;
+
+    fn log(&self, record: &slog::Record, values: &slog::OwnedKVList) -> Result<(), slog::Never> {
+        // The record is formatted by the inner drain, on this thread
+        let prev = LOG_REDACTION.with(|level| level.replace(self.level()));
+        let res = slog::Drain::log(&self.logger, record, values);
+        LOG_REDACTION.with(|level| level.set(prev));
+
+        res
+    }
+
+    fn is_enabled(&self, level: slog::Level) -> bool {
+        slog::Drain::is_enabled(&self.logger, level)
+    }
+}
+
 #[derive(Deserialize, Serialize, Copy, Clone, Hash, PartialEq, Eq, PartialOrd, Ord)]
 #[serde(transparent)]
 pub struct Hidden<T>(pub T);
 
+/// Formats the peer address, replaced with its hash when the log redaction
+/// covers the peers. The same address gives the same hash within the process
+pub struct Peer<T>(pub T);
+
 pub struct RetryTrigger {
     chan: watch::Receiver<()>,
     retry: u32,
     retries: u32,
+    clock: Arc<dyn Clock>,
+    deadline: Option<Arc<RetryDeadline>>,
 }
 
+/// The moment the pending backoff ends, unset when there is no backoff or it
+/// waits for the trigger only
+#[derive(Default)]
+pub struct RetryDeadline(Mutex<Option<Instant>>);
+
 impl RetryTrigger {
-    pub fn new(chan: watch::Receiver<()>, retries: u32) -> Self {
+    pub fn new(chan: watch::Receiver<()>, retries: u32, clock: Arc<dyn Clock>) -> Self {
         Self {
             chan,
             retry: 0,
             retries,
+            clock,
+            deadline: None,
         }
     }
 
+    /// Publish the end of each backoff in the given deadline
+    pub fn with_deadline(mut self, deadline: Arc<RetryDeadline>) -> Self {
+        self.deadline = Some(deadline);
+        self
+    }
+
     pub async fn backoff(&mut self) {
         let delay = if self.retry + 1 < self.retries {
             drop_config::FIRST_RETRY_AFTER * (0x01 << self.retry)
@@ -38,10 +130,28 @@ impl RetryTrigger {
             Duration::MAX
         };
 
+        if let Some(deadline) = &self.deadline {
+            deadline.set(self.clock.instant().checked_add(delay));
+        }
+
         self.retry = tokio::select! {
             _ = self.chan.changed() => 0,
-            _ = tokio::time::sleep(delay) => self.retry + 1,
+            _ = self.clock.sleep(delay) => self.retry + 1,
         };
+
+        if let Some(deadline) = &self.deadline {
+            deadline.set(None);
+        }
+    }
+}
+
+impl RetryDeadline {
+    pub fn get(&self) -> Option<Instant> {
+        *self.0.lock().expect("Poisoned lock")
+    }
+
+    fn set(&self, deadline: Option<Instant>) {
+        *self.0.lock().expect("Poisoned lock") = deadline;
     }
 }
 
@@ -49,14 +159,39 @@ impl<T> fmt::Debug for Hidden<T>
 where
     T: fmt::Debug,
 {
-    #[cfg(debug_assertions)]
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{:?}", self.0)
+        if cfg!(debug_assertions) && log_redaction() < LogRedaction::Paths {
+            write!(f, "{:?}", self.0)
+        } else {
+            f.write_str("****")
+        }
+    }
+}
+
+impl<T> fmt::Display for Peer<T>
+where
+    T: fmt::Display,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if log_redaction() < LogRedaction::PathsAndPeers {
+            return self.0.fmt(f);
+        }
+
+        let mut hash = sha2::Sha256::new();
+        hash.update(*PEER_SALT);
+        hash.update(self.0.to_string().as_bytes());
+        let hash = hash.finalize();
+
+        write!(f, "peer-{}", hex::encode(&hash[..4]))
     }
+}
 
-    #[cfg(not(debug_assertions))]
+impl<T> fmt::Debug for Peer<T>
+where
+    T: fmt::Display,
+{
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        f.write_str("****")
+        fmt::Display::fmt(self, f)
     }
 }
 
@@ -100,15 +235,22 @@ pub fn filepath_variants(location: &'_ Path) -> crate::Result<impl Iterator<Item
     Ok(iter)
 }
 
+const FILENAME_REPLACEMENT_CHAR: &str = "_";
+
+// This is unified across all platforms, because we don't have a control
+// over the filesystem mounted on user's device
+const FILENAME_ILLEGAL_CHARS: &[char] = &['<', '>', ':', '"', '\\', '/', '|', '?', '*'];
+
+/// Check if the name contains characters replaced by `normalize_filename()`.
+/// The replacement character is what the peer's invalid UTF-8 ends up as
+pub fn has_illegal_filename_chars(filename: &str) -> bool {
+    filename.contains(FILENAME_ILLEGAL_CHARS)
+        || filename.contains(|c: char| c.is_control() || c == char::REPLACEMENT_CHARACTER)
+}
+
 /// Replace invalid characters or invalid file names
 /// Rules taken from: <https://stackoverflow.com/questions/1976007/what-characters-are-forbidden-in-windows-and-linux-directory-names>
 pub fn normalize_filename(filename: impl AsRef<str>) -> String {
-    const REPLACEMENT_CHAR: &str = "_";
-
-    // This is unified across all platforms, because we don't have a control
-    // over the filesystem mounted on user's device
-    const ILLEGAL_CHARS: &[char] = &['<', '>', ':', '"', '\\', '/', '|', '?', '*'];
-
     #[cfg(windows)]
     fn check_illegal_filename(mut name: String) -> String {
         const ILLEGAL: &[&str] = &[
@@ -119,14 +261,14 @@ pub fn normalize_filename(filename: impl AsRef<str>) -> String {
         // file name cannot end with .
         if name.ends_with('.') {
             // append the replacement char
-            name.push_str(REPLACEMENT_CHAR);
+            name.push_str(FILENAME_REPLACEMENT_CHAR);
         }
 
         // check illegal names
         if let Some(prefix) = name.split('.').next() {
             if ILLEGAL.contains(&prefix) {
                 // prepend the replacement char
-                name.insert_str(0, REPLACEMENT_CHAR);
+                name.insert_str(0, FILENAME_REPLACEMENT_CHAR);
             }
         }
 
@@ -140,12 +282,56 @@ pub fn normalize_filename(filename: impl AsRef<str>) -> String {
 
     let name = filename
         .as_ref()
-        .replace(ILLEGAL_CHARS, REPLACEMENT_CHAR)
-        .replace(|c: char| c.is_ascii_control(), REPLACEMENT_CHAR);
+        .replace(FILENAME_ILLEGAL_CHARS, FILENAME_REPLACEMENT_CHAR)
+        .replace(
+            |c: char| c.is_control() || c == char::REPLACEMENT_CHARACTER,
+            FILENAME_REPLACEMENT_CHAR,
+        );
 
     check_illegal_filename(name)
 }
 
+fn cut_at_char_boundary(s: &str, max_len: usize) -> &str {
+    let mut end = max_len.min(s.len());
+    while !s.is_char_boundary(end) {
+        end -= 1;
+    }
+    &s[..end]
+}
+
+/// Shortens the file name to at most `max_len` bytes. The extension is
+/// preserved whenever it fits, only the stem is cut
+pub fn truncate_filename(name: &str, max_len: usize) -> String {
+    if name.len() <= max_len {
+        return name.to_string();
+    }
+
+    match name.rsplit_once('.') {
+        Some((stem, ext)) if !stem.is_empty() && ext.len() + 1 < max_len => {
+            let stem = cut_at_char_boundary(stem, max_len - ext.len() - 1);
+            format!("{stem}.{ext}")
+        }
+        _ => cut_at_char_boundary(name, max_len).to_string(),
+    }
+}
+
+/// Shortens the directory name to at most `max_len` bytes. The cut name is
+/// suffixed with the hash of the whole one so that the directories differing
+/// only past the cut are not merged together
+pub fn truncate_dirname(name: &str, max_len: usize) -> String {
+    // `-` followed by 8 hex digits
+    const HASH_SUFFIX_LEN: usize = 9;
+
+    if name.len() <= max_len {
+        return name.to_string();
+    }
+
+    let hash = sha2::Sha256::digest(name.as_bytes());
+    let prefix = cut_at_char_boundary(name, max_len.saturating_sub(HASH_SUFFIX_LEN));
+
+    format!("{prefix}-{}", hex::encode(&hash[..4]))
+}
+
 pub fn make_path_absolute(path: impl AsRef<Path>) -> io::Result<PathBuf> {
     let path = path.as_ref();
 
@@ -171,6 +357,231 @@ pub async fn connect(local: SocketAddr, remote: SocketAddr) -> io::Result<TcpStr
     sock.connect(remote).await
 }
 
+/// Orders the resolved peer addresses by the connection preference. For Happy
+/// Eyeballs the address families are interleaved starting with IPv6, as
+/// described in RFC 8305
+pub fn order_peer_addrs(
+    addrs: impl IntoIterator<Item = IpAddr>,
+    policy: PeerResolution,
+) -> Vec<IpAddr> {
+    let (v6, v4): (Vec<_>, Vec<_>) = addrs.into_iter().partition(IpAddr::is_ipv6);
+
+    match policy {
+        PeerResolution::PreferIpv4 => v4.into_iter().chain(v6).collect(),
+        PeerResolution::PreferIpv6 => v6.into_iter().chain(v4).collect(),
+        PeerResolution::HappyEyeballs => {
+            let mut v6 = v6.into_iter();
+            let mut v4 = v4.into_iter();
+
+            let mut addrs = Vec::new();
+            loop {
+                match (v6.next(), v4.next()) {
+                    (None, None) => break addrs,
+                    (first, second) => addrs.extend(first.into_iter().chain(second)),
+                }
+            }
+        }
+    }
+}
+
+/// Parses the peer IP address with an optional zone, as in `fe80::1%eth0` or
+/// `fe80::1%2`. The zone is either the interface name or its index and is
+/// accepted only for the IPv6 addresses and only if it resolves. Returns the
+/// address together with the zone as given
+pub fn parse_scoped_ip(addr: &str) -> Option<(IpAddr, Option<String>)> {
+    let Some((ip, zone)) = addr.split_once('%') else {
+        return Some((addr.parse().ok()?, None));
+    };
+
+    let ip: Ipv6Addr = ip.parse().ok()?;
+    resolve_zone(zone)?;
+
+    Some((ip.into(), Some(zone.to_string())))
+}
+
+/// Resolves the zone, the interface name or its index, to the scope id
+pub fn resolve_zone(zone: &str) -> Option<u32> {
+    if zone.is_empty() {
+        return None;
+    }
+
+    match zone.parse() {
+        Ok(index) => Some(index),
+        Err(_) => interface_index(zone),
+    }
+}
+
+#[cfg(unix)]
+fn interface_index(name: &str) -> Option<u32> {
+    let name = std::ffi::CString::new(name).ok()?;
+
+    // SAFETY: the name is a valid C string
+    match unsafe { libc::if_nametoindex(name.as_ptr()) } {
+        0 => None,
+        index => Some(index),
+    }
+}
+
+#[cfg(not(unix))]
+fn interface_index(_: &str) -> Option<u32> {
+    None
+}
+
+/// Builds the socket address of the peer. The scope id applies only to the
+/// link-local IPv6 addresses as it's meaningless for the other ones
+pub fn peer_socket_addr(ip: IpAddr, scope_id: u32, port: u16) -> SocketAddr {
+    match ip {
+        IpAddr::V6(ip) if is_unicast_link_local(&ip) => {
+            SocketAddrV6::new(ip, port, 0, scope_id).into()
+        }
+        ip => SocketAddr::new(ip, port),
+    }
+}
+
+fn is_unicast_link_local(ip: &Ipv6Addr) -> bool {
+    ip.segments()[0] & 0xffc0 == 0xfe80
+}
+
+/// Returns the space available to the user on the filesystem containing the
+/// given path or `None` if it can't be queried on this platform
+#[cfg(unix)]
+pub fn available_space(path: &Path) -> Option<u64> {
+    use std::{ffi::CString, os::unix::ffi::OsStrExt};
+
+    let path = CString::new(path.as_os_str().as_bytes()).ok()?;
+
+    let mut stat = std::mem::MaybeUninit::<libc::statvfs>::uninit();
+    // SAFETY: the path is a valid C string and the stat buffer is large enough
+    if unsafe { libc::statvfs(path.as_ptr(), stat.as_mut_ptr()) } != 0 {
+        return None;
+    }
+    // SAFETY: statvfs succeeded so the structure is initialized
+    let stat = unsafe { stat.assume_init() };
+
+    // The field types differ between the platforms
+    #[allow(clippy::unnecessary_cast)]
+    Some((stat.f_bavail as u64).saturating_mul(stat.f_frsize as u64))
+}
+
+#[cfg(windows)]
+pub fn available_space(path: &Path) -> Option<u64> {
+    use std::os::windows::ffi::OsStrExt;
+
+    use winapi::um::{fileapi::GetDiskFreeSpaceExW, winnt::ULARGE_INTEGER};
+
+    let path: Vec<u16> = path
+        .as_os_str()
+        .encode_wide()
+        .chain(iter::once(0))
+        .collect();
+
+    // SAFETY: the integer is plain data, valid when zeroed
+    let mut available: ULARGE_INTEGER = unsafe { std::mem::zeroed() };
+    // SAFETY: the path is NUL terminated and the totals are optional
+    let res = unsafe {
+        GetDiskFreeSpaceExW(
+            path.as_ptr(),
+            &mut available,
+            std::ptr::null_mut(),
+            std::ptr::null_mut(),
+        )
+    };
+
+    // SAFETY: the union is read as the whole 64-bit value
+    (res != 0).then(|| unsafe { *available.QuadPart() })
+}
+
+#[cfg(not(any(unix, windows)))]
+pub fn available_space(_path: &Path) -> Option<u64> {
+    None
+}
+
+/// Whether the IO operation failed because the disk is full. Checks the OS
+/// error code since `io::ErrorKind::StorageFull` is not available on the
+/// supported toolchain
+#[cfg(unix)]
+pub fn is_storage_full(err: &io::Error) -> bool {
+    err.raw_os_error() == Some(libc::ENOSPC)
+}
+
+/// Whether the IO operation failed because the disk is full. Checks the OS
+/// error code since `io::ErrorKind::StorageFull` is not available on the
+/// supported toolchain
+#[cfg(windows)]
+pub fn is_storage_full(err: &io::Error) -> bool {
+    use winapi::shared::winerror::{ERROR_DISK_FULL, ERROR_HANDLE_DISK_FULL};
+
+    matches!(
+        err.raw_os_error().map(|code| code as u32),
+        Some(ERROR_HANDLE_DISK_FULL | ERROR_DISK_FULL)
+    )
+}
+
+/// Reserves the disk space for the file to grow up to `len` bytes without
+/// changing its reported size, so appending to it keeps working as usual
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn preallocate(file: &std::fs::File, len: u64) -> io::Result<()> {
+    use std::os::fd::AsRawFd;
+
+    if len == 0 {
+        return Ok(());
+    }
+
+    // SAFETY: the descriptor is valid for the lifetime of the file
+    let res = unsafe {
+        libc::fallocate(
+            file.as_raw_fd(),
+            libc::FALLOC_FL_KEEP_SIZE,
+            0,
+            len.try_into().map_err(|_| io::ErrorKind::InvalidInput)?,
+        )
+    };
+
+    if res != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Reserves the disk space for the file to grow up to `len` bytes without
+/// changing its reported size, so appending to it keeps working as usual
+#[cfg(any(target_os = "macos", target_os = "ios"))]
+pub fn preallocate(file: &std::fs::File, len: u64) -> io::Result<()> {
+    use std::os::fd::AsRawFd;
+
+    let missing = len.saturating_sub(file.metadata()?.len());
+    if missing == 0 {
+        return Ok(());
+    }
+
+    let mut store = libc::fstore_t {
+        fst_flags: libc::F_ALLOCATEALL,
+        fst_posmode: libc::F_PEOFPOSMODE,
+        fst_offset: 0,
+        fst_length: missing
+            .try_into()
+            .map_err(|_| io::ErrorKind::InvalidInput)?,
+        fst_bytesalloc: 0,
+    };
+
+    // SAFETY: the descriptor is valid for the lifetime of the file and the store
+    // structure outlives the call
+    if unsafe { libc::fcntl(file.as_raw_fd(), libc::F_PREALLOCATE, &mut store) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "macos",
+    target_os = "ios"
+)))]
+pub fn preallocate(_file: &std::fs::File, _len: u64) -> io::Result<()> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
 /// Returns the number of kilobytes rounded up, used for moose event size
 /// calculations
 pub fn to_kb(bytes: u64) -> i32 {
@@ -182,6 +593,118 @@ mod tests {
 
     use super::*;
 
+    #[test]
+    fn scoped_peer_address() {
+        let link_local: IpAddr = "fe80::1".parse().unwrap();
+
+        assert_eq!(
+            parse_scoped_ip("fe80::1%2"),
+            Some((link_local, Some("2".into())))
+        );
+        assert_eq!(parse_scoped_ip("fe80::1"), Some((link_local, None)));
+        assert_eq!(
+            parse_scoped_ip("10.0.0.1"),
+            Some(("10.0.0.1".parse().unwrap(), None))
+        );
+        assert_eq!(parse_scoped_ip("fe80::1%"), None);
+        assert_eq!(parse_scoped_ip("10.0.0.1%2"), None);
+        assert_eq!(parse_scoped_ip("fe80::1%no-such-interface0"), None);
+
+        // The interface name is kept as given and resolved separately
+        #[cfg(target_os = "linux")]
+        {
+            assert_eq!(
+                parse_scoped_ip("fe80::1%lo"),
+                Some((link_local, Some("lo".into())))
+            );
+            assert_eq!(resolve_zone("lo"), Some(1));
+        }
+        assert_eq!(resolve_zone("2"), Some(2));
+        assert_eq!(resolve_zone(""), None);
+
+        let SocketAddr::V6(addr) = peer_socket_addr(link_local, 2, 49111) else {
+            panic!("Expected an IPv6 socket address");
+        };
+        assert_eq!(*addr.ip(), "fe80::1".parse::<Ipv6Addr>().unwrap());
+        assert_eq!(addr.port(), 49111);
+        assert_eq!(addr.scope_id(), 2);
+
+        // The scope id is dropped for the addresses outside of link-local
+        let SocketAddr::V6(addr) = peer_socket_addr("2001:db8::1".parse().unwrap(), 2, 49111)
+        else {
+            panic!("Expected an IPv6 socket address");
+        };
+        assert_eq!(addr.scope_id(), 0);
+        assert_eq!(
+            peer_socket_addr("10.0.0.1".parse().unwrap(), 2, 49111),
+            "10.0.0.1:49111".parse().unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn retry_backoff_follows_clock() {
+        use std::time::SystemTime;
+
+        use crate::clock::MockClock;
+
+        let clock = Arc::new(MockClock::new(SystemTime::UNIX_EPOCH));
+        let (_tx, rx) = watch::channel(());
+        let mut trigger = RetryTrigger::new(rx, 3, clock.clone());
+
+        for delay in [
+            drop_config::FIRST_RETRY_AFTER,
+            drop_config::FIRST_RETRY_AFTER * 2,
+        ] {
+            let backoff = trigger.backoff();
+            tokio::pin!(backoff);
+            assert!(futures::poll!(&mut backoff).is_pending());
+
+            clock.advance(delay - Duration::from_millis(1));
+            assert!(futures::poll!(&mut backoff).is_pending());
+
+            clock.advance(Duration::from_millis(1));
+            assert!(futures::poll!(&mut backoff).is_ready());
+        }
+
+        // Out of retries, only the trigger can wake it up
+        let backoff = trigger.backoff();
+        tokio::pin!(backoff);
+        clock.advance(Duration::from_secs(3600));
+        assert!(futures::poll!(&mut backoff).is_pending());
+    }
+
+    #[tokio::test]
+    async fn retry_deadline_tracks_backoff() {
+        use std::time::SystemTime;
+
+        use crate::clock::MockClock;
+
+        let clock = Arc::new(MockClock::new(SystemTime::UNIX_EPOCH));
+        let deadline = Arc::new(RetryDeadline::default());
+        let (_tx, rx) = watch::channel(());
+        let mut trigger = RetryTrigger::new(rx, 2, clock.clone()).with_deadline(deadline.clone());
+
+        {
+            let backoff = trigger.backoff();
+            tokio::pin!(backoff);
+            assert!(futures::poll!(&mut backoff).is_pending());
+            assert_eq!(
+                deadline.get(),
+                Some(clock.instant() + drop_config::FIRST_RETRY_AFTER)
+            );
+
+            clock.advance(drop_config::FIRST_RETRY_AFTER);
+            assert!(futures::poll!(&mut backoff).is_ready());
+            assert_eq!(deadline.get(), None);
+        }
+
+        // Waiting for the trigger only has no deadline
+        let backoff = trigger.backoff();
+        tokio::pin!(backoff);
+        assert!(futures::poll!(&mut backoff).is_pending());
+        assert_eq!(deadline.get(), None);
+    }
+
     #[test]
     fn path_normalization() {
         let valid_path = "this...isavalidpath1234$$%^😀";
@@ -196,6 +719,20 @@ mod tests {
         let norm = normalize_filename(special_char);
         assert_eq!(norm, "a_b__asdf_as_d_f");
 
+        let unicode_control = "a\u{85}b\u{9c}c";
+        let norm = normalize_filename(unicode_control);
+        assert_eq!(norm, "a_b_c");
+
+        let invalid_utf8 = String::from_utf8_lossy(b"ab\xffcd.txt");
+        let norm = normalize_filename(&invalid_utf8);
+        assert_eq!(norm, "ab_cd.txt");
+
+        assert!(!has_illegal_filename_chars(valid_path));
+        assert!(has_illegal_filename_chars(ascii_control));
+        assert!(has_illegal_filename_chars(special_char));
+        assert!(has_illegal_filename_chars(unicode_control));
+        assert!(has_illegal_filename_chars(&invalid_utf8));
+
         #[cfg(windows)]
         {
             let dot_at_end = "asdf.";
@@ -217,4 +754,155 @@ mod tests {
         assert_eq!(iter.next(), Some(PathBuf::from("file(2).ext")));
         assert_eq!(iter.next(), Some(PathBuf::from("file(3).ext")));
     }
+
+    #[test]
+    fn filename_truncation() {
+        assert_eq!(truncate_filename("short.txt", 10), "short.txt");
+        assert_eq!(truncate_filename("longer_name.txt", 10), "longer.txt");
+        assert_eq!(truncate_filename("no_extension", 5), "no_ex");
+        assert_eq!(truncate_filename("a.very_long_extension", 8), "a.very_l");
+
+        // Never cut in the middle of a character
+        assert_eq!(truncate_filename("ąąą.txt", 8), "ąą.txt");
+    }
+
+    #[test]
+    fn truncating_dirnames() {
+        assert_eq!(truncate_dirname("short", 16), "short");
+
+        let first = truncate_dirname("shared_long_prefix_a", 16);
+        let second = truncate_dirname("shared_long_prefix_b", 16);
+        assert_eq!(first.len(), 16);
+        assert_eq!(second.len(), 16);
+        assert!(first.starts_with("shared_-"));
+        assert!(second.starts_with("shared_-"));
+        assert_ne!(first, second);
+
+        // Stable, so that the files of one directory land together
+        assert_eq!(truncate_dirname("shared_long_prefix_a", 16), first);
+
+        assert!(truncate_dirname("ąąąąąąąąą", 12).starts_with("ą-"));
+    }
+
+    #[cfg(any(unix, windows))]
+    #[test]
+    fn available_space_query() {
+        let dir = tempfile::tempdir().unwrap();
+
+        assert!(available_space(dir.path()).is_some());
+        assert!(available_space(&dir.path().join("missing")).is_none());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn file_preallocation() {
+        use std::{io::Write, os::unix::fs::MetadataExt};
+
+        let dir = tempfile::tempdir().unwrap();
+        let mut file = std::fs::File::create(dir.path().join("file")).unwrap();
+
+        preallocate(&file, 1024 * 1024).unwrap();
+
+        let meta = file.metadata().unwrap();
+        assert_eq!(meta.len(), 0);
+        assert!(meta.blocks() * 512 >= 1024 * 1024);
+
+        // The writes land at the beginning of the file
+        file.write_all(b"abc").unwrap();
+        assert_eq!(file.metadata().unwrap().len(), 3);
+    }
+
+    #[test]
+    fn ordering_peer_addrs() {
+        let addrs: Vec<IpAddr> = vec![
+            "1.1.1.1".parse().unwrap(),
+            "2.2.2.2".parse().unwrap(),
+            "::1".parse().unwrap(),
+        ];
+
+        assert_eq!(
+            order_peer_addrs(addrs.clone(), PeerResolution::PreferIpv4),
+            addrs
+        );
+        assert_eq!(
+            order_peer_addrs(addrs.clone(), PeerResolution::PreferIpv6),
+            [addrs[2], addrs[0], addrs[1]]
+        );
+        assert_eq!(
+            order_peer_addrs(addrs.clone(), PeerResolution::HappyEyeballs),
+            [addrs[2], addrs[0], addrs[1]]
+        );
+
+        let addrs: Vec<IpAddr> = vec![
+            "1.1.1.1".parse().unwrap(),
+            "::1".parse().unwrap(),
+            "::2".parse().unwrap(),
+            "2.2.2.2".parse().unwrap(),
+        ];
+        assert_eq!(
+            order_peer_addrs(addrs.clone(), PeerResolution::HappyEyeballs),
+            [addrs[1], addrs[0], addrs[2], addrs[3]]
+        );
+    }
+
+    #[test]
+    fn storage_full_errors() {
+        #[cfg(unix)]
+        assert!(is_storage_full(&io::Error::from_raw_os_error(libc::ENOSPC)));
+        #[cfg(windows)]
+        assert!(is_storage_full(&io::Error::from_raw_os_error(
+            winapi::shared::winerror::ERROR_DISK_FULL as i32
+        )));
+
+        assert!(!is_storage_full(&io::Error::from(
+            io::ErrorKind::PermissionDenied
+        )));
+    }
+
+    #[test]
+    fn log_redaction_levels() {
+        struct Capture(Arc<Mutex<Vec<String>>>);
+
+        impl slog::Drain for Capture {
+            type Ok = ();
+            type Err = slog::Never;
+
+            fn log(&self, record: &slog::Record, _: &slog::OwnedKVList) -> Result<(), slog::Never> {
+                self.0.lock().unwrap().push(format!("{}", record.msg()));
+                Ok(())
+            }
+        }
+
+        let ip: IpAddr = "192.168.0.1".parse().unwrap();
+        let path = Hidden(Path::new("/home/user/file.txt"));
+
+        let lines = Arc::new(Mutex::new(Vec::new()));
+        let redaction = RedactingDrain::new(
+            slog::Logger::root(Capture(lines.clone()), slog::o!()),
+            LogRedaction::None,
+        );
+        let logger = slog::Logger::root(redaction.clone(), slog::o!());
+        let log = || {
+            slog::info!(logger, "{} {path:?}", Peer(ip));
+            lines.lock().unwrap().pop().unwrap()
+        };
+
+        #[cfg(debug_assertions)]
+        assert_eq!(log(), "192.168.0.1 \"/home/user/file.txt\"");
+
+        redaction.set_level(LogRedaction::Paths);
+        assert_eq!(log(), "192.168.0.1 ****");
+
+        redaction.set_level(LogRedaction::PathsAndPeers);
+        let hashed = log();
+        assert!(hashed.starts_with("peer-"));
+        assert!(!hashed.contains("192.168"));
+        assert!(hashed.ends_with(" ****"));
+        // The same address can still be correlated across the log lines
+        assert_eq!(log(), hashed);
+
+        // The level applies only to the records of the given logger
+        assert_eq!(Peer(ip).to_string(), "192.168.0.1");
+        assert_ne!(Peer("192.168.0.2").to_string(), Peer(ip).to_string());
+    }
 }
@@ -21,8 +21,8 @@ pub trait HandlerInit {
 
     fn upgrade(self, msg_tx: Sender<MsgToSend>, xfer: Arc<OutgoingTransfer>) -> Self::Loop;
     fn pinger(&mut self) -> Self::Pinger;
-    fn recv_timeout(&mut self) -> Duration {
-        drop_config::TRANFER_IDLE_LIFETIME
+    fn recv_timeout(&mut self, timeout: Duration) -> Duration {
+        timeout
     }
 }
 
@@ -46,6 +46,20 @@ pub trait Uploader: Send + 'static {
     async fn chunk(&mut self, chunk: &[u8]) -> crate::Result<()>;
     async fn error(&mut self, msg: String);
 
+    /// Marks the end of the streamed file, following its last chunk
+    async fn end(&mut self, _checksum: [u8; 32]) -> crate::Result<()> {
+        Err(crate::Error::BadTransferState(
+            "Streamed files are not supported by the protocol version".into(),
+        ))
+    }
+
+    /// Stops streaming the file so that the receiver requests it again
+    async fn restart(&mut self) -> crate::Result<()> {
+        Err(crate::Error::BadTransferState(
+            "Restarting the files is not supported by the protocol version".into(),
+        ))
+    }
+
     // File stream offset
     fn offset(&self) -> u64;
 }
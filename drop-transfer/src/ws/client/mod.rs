@@ -7,13 +7,18 @@ mod v6;
 
 use std::{
     io,
-    net::{IpAddr, SocketAddr},
+    net::SocketAddr,
     ops::ControlFlow,
     sync::Arc,
+    time::{Duration, Instant},
 };
 
 use anyhow::Context;
+use drop_config::SourceModifiedPolicy;
+use drop_storage::CancelCause;
+use futures::{stream::FuturesUnordered, StreamExt};
 use hyper::{Request, Response, StatusCode};
+use sha2::Digest;
 use slog::{debug, error, info, warn, Logger};
 use tokio::{
     net::TcpStream,
@@ -39,14 +44,19 @@ use crate::{
     service::State,
     tasks::AliveGuard,
     transfer::Transfer,
-    utils,
-    ws::{client::handler::MsgToSend, Pinger},
+    utils::{self, Peer},
+    ws::{self, client::handler::MsgToSend, Pinger},
     OutgoingTransfer,
 };
 
+/// Delay between the consecutive connection attempts to the peer addresses, as
+/// recommended by RFC 8305
+const CONNECTION_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
 pub enum ClientReq {
     Reject { file: FileId },
     Fail { file: FileId },
+    Pause,
     Close,
 }
 
@@ -81,18 +91,51 @@ pub(crate) fn spawn(
     let id = xfer.id();
 
     tokio::spawn(async move {
-        let mut backoff =
-            utils::RetryTrigger::new(refresh_trigger, state.config.connection_retries);
+        let retry_deadline = state
+            .transfer_manager
+            .outgoing_retry_deadline(id)
+            .await
+            .unwrap_or_default();
+        let mut backoff = utils::RetryTrigger::new(
+            refresh_trigger,
+            state.config.connection_retries,
+            state.clock.clone(),
+        )
+        .with_deadline(retry_deadline);
+
+        let xfer_stop = state
+            .transfer_manager
+            .outgoing_client_stop(id)
+            .await
+            .unwrap_or_default();
 
         let task = async {
             loop {
+                if state.is_paused() {
+                    debug!(logger, "Transfers paused, waiting to connect: {}", id);
+
+                    tokio::select! {
+                        _ = xfer_stop.cancelled() => {
+                            debug!(logger, "connection retries stopped for: {}", id);
+                            break;
+                        },
+                        _ = state.wait_unpaused() => (),
+                    }
+                }
+
                 let cf = connect_to_peer(&state, &xfer, &logger, &guard).await;
                 if cf.is_break() {
                     debug!(logger, "connection status is irrecoverable");
                     break;
                 }
 
-                backoff.backoff().await;
+                tokio::select! {
+                    _ = xfer_stop.cancelled() => {
+                        debug!(logger, "connection retries stopped for: {}", id);
+                        break;
+                    },
+                    _ = backoff.backoff() => (),
+                }
             }
         };
 
@@ -115,11 +158,28 @@ async fn connect_to_peer(
 ) -> ControlFlow<()> {
     debug!(logger, "Outgoing transfer job started for {}", xfer.id(),);
 
-    let (socket, ver) = match establish_ws_conn(state, xfer, logger).await {
+    let remotes: Vec<_> = xfer.peer_candidates(drop_config::PORT).collect();
+    let local = SocketAddr::new(state.addr(), 0);
+
+    let conn = establish_ws_conn_within(
+        state.config.connect_timeout,
+        local,
+        &remotes,
+        &state.auth,
+        state.config.allow_legacy_protocols,
+        logger,
+    );
+
+    let (socket, ver) = match conn.await {
         WsConnection::Connected(sock, ver) => (sock, ver),
         WsConnection::Recoverable(error) => {
             info!(logger, "Transfer deferred {}: {error}", xfer.id());
 
+            state
+                .transfer_manager
+                .outgoing_connect_failed(xfer.id())
+                .await;
+
             if let Some(tx) = state.transfer_manager.outgoing_event_tx(xfer.id()).await {
                 tx.deferred(error).await;
             }
@@ -129,18 +189,69 @@ async fn connect_to_peer(
             error!(logger, "Could not connect to peer {}: {}", xfer.id(), err);
 
             if let Some(state) = state.transfer_manager.outgoing_remove(xfer.id()).await {
-                state.xfer_events.failed(err, false).await
+                state.xfer_events.failed(err, false, state.stats()).await
             }
 
             return ControlFlow::Break(());
         }
     };
 
+    // Transfers might have been paused while connecting
+    if state.is_paused() {
+        debug!(
+            logger,
+            "Transfers paused, dropping the connection: {}",
+            xfer.id()
+        );
+        return ControlFlow::Continue(());
+    }
+
     if let Some(tx) = state.transfer_manager.outgoing_event_tx(xfer.id()).await {
         tx.connected(ver.into()).await;
     }
     info!(logger, "Client connected, using version: {ver}");
 
+    if ver != protocol::Version::LATEST {
+        warn!(
+            logger,
+            "Peer does not support {}, downgraded to {ver}",
+            protocol::Version::LATEST
+        );
+
+        state.emit_event(crate::Event::ProtocolDowngraded {
+            transfer_id: xfer.id(),
+            from: protocol::Version::LATEST.into(),
+            to: ver.into(),
+        });
+    }
+
+    // The older protocols have no means of sending the files of unknown size
+    if !ver.has_streamed_files() && xfer.files().values().any(|file| file.is_streamed()) {
+        error!(
+            logger,
+            "Peer does not support the streamed files of {}",
+            xfer.id()
+        );
+
+        if let Some(state) = state.transfer_manager.outgoing_remove(xfer.id()).await {
+            let err = crate::Error::BadTransferState(format!(
+                "Peer does not support streamed files, connected with {ver}"
+            ));
+            state.xfer_events.failed(err, false, state.stats()).await
+        }
+
+        return ControlFlow::Break(());
+    }
+
+    // The peer info arrives with the first message of the newer protocols
+    if !ver.has_peer_info() {
+        state.emit_event(crate::Event::PeerInfo {
+            transfer_id: xfer.id(),
+            device_name: None,
+            app_version: None,
+        });
+    }
+
     let ctx = RunContext {
         logger,
         state,
@@ -161,12 +272,15 @@ async fn connect_to_peer(
             ctx.run(socket, v4::HandlerInit::new(state, logger, alive))
                 .await
         }
-        Version::V5 => {
-            ctx.run(socket, v6::HandlerInit::new(state, logger, alive))
-                .await
-        }
-        Version::V6 => {
-            ctx.run(socket, v6::HandlerInit::new(state, logger, alive))
+        Version::V5
+        | Version::V6
+        | Version::V7
+        | Version::V8
+        | Version::V9
+        | Version::V10
+        | Version::V11
+        | Version::V12 => {
+            ctx.run(socket, v6::HandlerInit::new(state, logger, alive, ver))
                 .await
         }
     };
@@ -176,30 +290,70 @@ async fn connect_to_peer(
     control
 }
 
-async fn establish_ws_conn(
-    state: &State,
-    xfer: &OutgoingTransfer,
+/// Bounds the whole connection establishment with the timeout. A peer which
+/// accepts the TCP connection but never completes the WS upgrade would
+/// otherwise hang the attempt forever
+async fn establish_ws_conn_within(
+    timeout: Duration,
+    local: SocketAddr,
+    remotes: &[SocketAddr],
+    auth: &auth::Context,
+    allow_legacy: bool,
     logger: &Logger,
 ) -> WsConnection {
-    let remote = SocketAddr::new(xfer.peer(), drop_config::PORT);
-    let local = SocketAddr::new(state.addr, 0);
+    let conn = establish_ws_conn(local, remotes, auth, allow_legacy, logger);
 
-    let mut socket = match utils::connect(local, remote).await {
-        Ok(sock) => sock,
+    match tokio::time::timeout(timeout, conn).await {
+        Ok(conn) => conn,
+        Err(_) => {
+            info!(
+                logger,
+                "Connection with {:?} was not established within {timeout:?}",
+                remotes.iter().map(Peer).collect::<Vec<_>>()
+            );
+
+            WsConnection::Recoverable(crate::Error::Io(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "Connection establishment timed out",
+            )))
+        }
+    }
+}
+
+/// Tries the known protocol versions from the newest one downwards. The V1 and
+/// V2 versions lack the client authentication and so are tried only if
+/// `allow_legacy` is set
+async fn establish_ws_conn(
+    local: SocketAddr,
+    remotes: &[SocketAddr],
+    auth: &auth::Context,
+    allow_legacy: bool,
+    logger: &Logger,
+) -> WsConnection {
+    let (mut socket, remote) = match connect_any(local, remotes, logger).await {
+        Ok(conn) => conn,
         Err(err) => {
             debug!(logger, "Failed to connect: {:?}", err,);
             return WsConnection::Recoverable(crate::Error::Io(err));
         }
     };
+    info!(logger, "Connected to {}", Peer(remote));
 
     let mut versions_to_try = [
+        protocol::Version::V12,
+        protocol::Version::V11,
+        protocol::Version::V10,
+        protocol::Version::V9,
+        protocol::Version::V8,
+        protocol::Version::V7,
         protocol::Version::V6,
         protocol::Version::V5,
         protocol::Version::V4,
         protocol::Version::V2,
         protocol::Version::V1,
     ]
-    .into_iter();
+    .into_iter()
+    .filter(|ver| allow_legacy || !ver.is_legacy());
 
     let ver = loop {
         let ver = if let Some(ver) = versions_to_try.next() {
@@ -211,7 +365,7 @@ async fn establish_ws_conn(
             )));
         };
 
-        match make_request(&mut socket, xfer.peer(), ver, state.auth.as_ref(), logger).await {
+        match make_request(&mut socket, remote, ver, auth, logger).await {
             Ok(_) => break ver,
             Err(RequestError::General(err)) => {
                 info!(logger, "Error while making the HTTP request: {err:?}");
@@ -240,18 +394,60 @@ async fn establish_ws_conn(
     WsConnection::Connected(client, ver)
 }
 
+/// Connects to the first of the addresses to respond. The next attempt starts
+/// once the previous one fails or after `CONNECTION_ATTEMPT_DELAY`, while the
+/// previous attempts are still in progress
+async fn connect_any(
+    local: SocketAddr,
+    remotes: &[SocketAddr],
+    logger: &Logger,
+) -> io::Result<(TcpStream, SocketAddr)> {
+    let mut remotes = remotes.iter().copied();
+    let mut attempts = FuturesUnordered::new();
+    let mut last_err = io::Error::new(io::ErrorKind::NotFound, "No address to connect to");
+
+    loop {
+        if let Some(remote) = remotes.next() {
+            debug!(logger, "Connecting to {}", Peer(remote));
+            attempts.push(async move { (utils::connect(local, remote).await, remote) });
+        }
+
+        let attempt = if remotes.len() > 0 {
+            tokio::select! {
+                attempt = attempts.next() => attempt,
+                _ = tokio::time::sleep(CONNECTION_ATTEMPT_DELAY) => continue,
+            }
+        } else {
+            attempts.next().await
+        };
+
+        match attempt {
+            Some((Ok(sock), remote)) => return Ok((sock, remote)),
+            Some((Err(err), remote)) => {
+                debug!(logger, "Failed to connect to {}: {err}", Peer(remote));
+                last_err = err;
+            }
+            None => return Err(last_err),
+        }
+    }
+}
+
 async fn make_request(
     socket: &mut TcpStream,
-    ip: IpAddr,
+    addr: SocketAddr,
     version: protocol::Version,
     auth: &auth::Context,
     logger: &slog::Logger,
 ) -> Result<(), RequestError> {
-    let addr = SocketAddr::new(ip, drop_config::PORT);
+    let ip = addr.ip();
 
     let url = format!("ws://{addr}/drop/{version}",);
 
-    debug!(logger, "Making HTTP request: {url}");
+    debug!(
+        logger,
+        "Making HTTP request: ws://{}/drop/{version}",
+        Peer(addr)
+    );
 
     let mut req = url.as_str().into_client_request().context("Invalid URL")?;
 
@@ -283,7 +479,7 @@ async fn make_request(
         status if status.is_success() || status.is_informational() => {
             authorize()?;
 
-            debug!(logger, "Connected to {url} without authorization");
+            debug!(logger, "Connected to {} without authorization", Peer(addr));
             Ok(())
         }
         StatusCode::UNAUTHORIZED => {
@@ -291,7 +487,7 @@ async fn make_request(
 
             debug!(logger, "Creating 'authorization' header");
 
-            debug!(logger, "Extracting peers ({ip}) public key");
+            debug!(logger, "Extracting peers ({}) public key", Peer(ip));
             let (key, value) =
                 auth.create_clients_auth_header(&resp, ip, server_auth_scheme.is_some())?;
 
@@ -346,8 +542,11 @@ impl RunContext<'_> {
     }
 
     async fn run(mut self, socket: WsStream, mut handler: impl HandlerInit) -> ControlFlow<()> {
-        let mut socket =
-            WebSocket::new(socket, handler.recv_timeout(), drop_config::WS_SEND_TIMEOUT);
+        let mut socket = WebSocket::new(
+            socket,
+            handler.recv_timeout(self.state.config.ws_recv_timeout),
+            self.state.config.ws_send_timeout,
+        );
 
         let mut api_req_rx = match self.start(&mut socket, &mut handler).await {
             Ok(Some(rx)) => rx,
@@ -383,6 +582,15 @@ impl RunContext<'_> {
         let mut ping = handler.pinger();
         let mut handler = handler.upgrade(upload_tx, self.xfer.clone());
         let mut jobs = JoinSet::new();
+        let mut ping_sent = None;
+
+        let conn_drop = self
+            .state
+            .transfer_manager
+            .outgoing_conn_drop(self.xfer.id())
+            .await
+            .unwrap_or_default();
+        let connected = self.state.metrics.connected();
 
         let task = async {
             loop {
@@ -399,6 +607,10 @@ impl RunContext<'_> {
                     recv = socket.recv() => {
                         let msg =  recv.context("Failed to receive WS message")?;
 
+                        if msg.is_pong() {
+                            ws::utils::report_rtt(self.state, self.xfer.id(), &mut ping_sent).await;
+                        }
+
                         if self.on_recv(&mut socket, &mut handler, msg, &mut jobs).await.context("Handler on recv")?.is_break() {
                             break;
                         }
@@ -410,6 +622,7 @@ impl RunContext<'_> {
                     },
                     _ = ping.tick() => {
                         socket.send(Message::Ping(Vec::new())).await.context("Failed to send PING")?;
+                        ping_sent = Some(Instant::now());
                     }
                 }
             }
@@ -417,7 +630,12 @@ impl RunContext<'_> {
             anyhow::Ok(())
         };
 
-        let result = task.await;
+        // Aborting the loop also covers the connection stuck on sending
+        let result = tokio::select! {
+            result = task => result,
+            _ = conn_drop.notified() => Err(anyhow::anyhow!("Connection dropped on request")),
+        };
+        drop(connected);
 
         let cf = if let Err(err) = result {
             info!(
@@ -461,17 +679,38 @@ impl RunContext<'_> {
                 debug!(self.logger, "Received:\n\t{text}");
                 handler.on_text_msg(socket, jobs, text).await?;
             }
-            Message::Close(_) => {
-                debug!(self.logger, "Got CLOSE frame");
+            Message::Close(frame) => {
+                debug!(self.logger, "Got CLOSE frame: {frame:?}");
                 handler.on_close().await;
 
+                let size_rejected = matches!(
+                    &frame,
+                    Some(frame) if u16::from(frame.code) == ws::SIZE_REJECTED_CLOSE_CODE
+                        && frame.reason == ws::SIZE_REJECTED_CLOSE_REASON
+                );
+                let rejection_reason = frame
+                    .filter(|frame| u16::from(frame.code) == ws::TRANSFER_REJECTED_CLOSE_CODE)
+                    .map(|frame| frame.reason.to_string());
+
                 if let Some(state) = self
                     .state
                     .transfer_manager
                     .outgoing_remove(self.xfer.id())
                     .await
                 {
-                    state.xfer_events.cancel(true).await
+                    if size_rejected {
+                        state
+                            .xfer_events
+                            .failed(crate::Error::PeerRejectedSize, true, state.stats())
+                            .await
+                    } else if let Some(reason) = rejection_reason {
+                        state.xfer_events.rejected(reason, state.stats()).await
+                    } else {
+                        state
+                            .xfer_events
+                            .cancel(true, CancelCause::User, state.stats())
+                            .await
+                    }
                 }
 
                 return Ok(ControlFlow::Break(()));
@@ -501,6 +740,10 @@ impl RunContext<'_> {
             ClientReq::Fail { file } => {
                 handler.issue_failure(socket, file.clone()).await?;
             }
+            ClientReq::Pause => {
+                // Breaking the connection pauses the uploads on both sides
+                anyhow::bail!("Transfers paused");
+            }
             ClientReq::Close => {
                 debug!(self.logger, "Stopping client connection gracefuly");
                 socket.close().await?;
@@ -534,11 +777,18 @@ async fn start_upload(
         .await?;
 
     let offset = uploader.offset();
+    ws::utils::report_resent_bytes(&state, xfer.id(), &events, offset).await;
 
     let permit = throttle::init(&logger, &state, &events, offset)
         .await
         .context("Failed to acquire upload permit")?;
+    let rate_limit = state
+        .transfer_manager
+        .outgoing_rate_limit(xfer.id())
+        .await
+        .unwrap_or_default();
 
+    let job_events = events.clone();
     let upload_job = async move {
         let _guard = guard;
         let xfile = &xfer.files()[&file_id];
@@ -546,7 +796,30 @@ async fn start_upload(
         let send_file = async {
             let _permit = permit.acquire().await.ok_or(crate::Error::Canceled)?;
 
-            let mut iofile = match xfile.open(offset) {
+            state
+                .transfer_manager
+                .outgoing_upload_attempt(xfer.id(), &file_id, state.config.max_file_retries)
+                .await?;
+
+            let policy = state.config.on_source_modified;
+            let opened = if policy == SourceModifiedPolicy::SnapshotAtStart {
+                info!(logger, "Sending a snapshot of the file {file_id}");
+
+                // Copying the whole file would hold up the runtime thread
+                let (xfer, file_id) = (xfer.clone(), file_id.clone());
+                match tokio::task::spawn_blocking(move || {
+                    xfer.files()[&file_id].open_snapshot(offset)
+                })
+                .await
+                {
+                    Ok(res) => res,
+                    Err(err) => Err(crate::Error::Io(io::Error::new(io::ErrorKind::Other, err))),
+                }
+            } else {
+                xfile.open(offset)
+            };
+
+            let mut iofile = match opened {
                 Ok(f) => f,
                 Err(err) => {
                     error!(
@@ -557,10 +830,65 @@ async fn start_upload(
                 }
             };
 
+            // The receiver learns the checksum of the streamed file at its end
+            let mut hasher = xfile.is_streamed().then(sha2::Sha256::new);
+            let mut streamed_bytes = 0;
+
+            let mut progressed = false;
             loop {
-                match iofile.read_chunk()? {
-                    Some(chunk) => uploader.chunk(chunk).await?,
-                    None => return Ok(()),
+                match iofile.read_chunk() {
+                    Ok(Some(chunk)) => {
+                        let len = chunk.len() as u64;
+                        if let Some(hasher) = &mut hasher {
+                            hasher.update(chunk);
+                            streamed_bytes += len;
+                        }
+                        uploader.chunk(chunk).await?;
+                        rate_limit.consume(len, &*state.clock).await;
+                    }
+                    Ok(None) => {
+                        if let Some(hasher) = hasher.take() {
+                            state
+                                .storage
+                                .update_path_size(xfer.id(), file_id.as_ref(), streamed_bytes as _)
+                                .await;
+                            uploader.end(hasher.finalize().into()).await?;
+                        }
+
+                        // The empty files have no chunks, reaching their end is the progress
+                        if !progressed {
+                            state
+                                .transfer_manager
+                                .outgoing_upload_progress(xfer.id(), &file_id)
+                                .await;
+                        }
+                        return Ok(());
+                    }
+                    Err(crate::Error::FileModified) if policy == SourceModifiedPolicy::Restart => {
+                        // The receiver verifies the already downloaded part and
+                        // requests the rest of the file again
+                        info!(
+                            logger,
+                            "File {file_id} modified while being sent, restarting"
+                        );
+                        job_events.pause().await;
+
+                        if let Err(err) = uploader.restart().await {
+                            // The older peers request the file again only after reconnecting
+                            debug!(logger, "Cannot restart the file alone: {err}");
+                            state.transfer_manager.drop_connection(xfer.id()).await?;
+                        }
+                        return Err(crate::Error::Canceled);
+                    }
+                    Err(err) => return Err(err),
+                }
+
+                if !progressed {
+                    progressed = true;
+                    state
+                        .transfer_manager
+                        .outgoing_upload_progress(xfer.id(), &file_id)
+                        .await;
                 }
             }
         };
@@ -634,3 +962,151 @@ async fn on_upload_failure(
         Ok(None) => (),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn connection_establishment_timeout() {
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+        let auth = auth::Context::new(drop_auth::SecretKey::from([0; 32]), |_| None);
+
+        // The server accepts the connection but never responds to the upgrade request
+        let listener = tokio::net::TcpListener::bind((Ipv4Addr::LOCALHOST, 0))
+            .await
+            .unwrap();
+        let remote = listener.local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            let (_sock, _) = listener.accept().await.unwrap();
+            std::future::pending::<()>().await
+        });
+
+        let local = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0);
+        let conn = establish_ws_conn_within(
+            Duration::from_millis(200),
+            local,
+            &[remote],
+            &auth,
+            false,
+            &logger,
+        )
+        .await;
+
+        assert!(matches!(
+            conn,
+            WsConnection::Recoverable(crate::Error::Io(err)) if err.kind() == io::ErrorKind::TimedOut
+        ));
+
+        server.abort();
+    }
+
+    #[tokio::test]
+    async fn legacy_protocols_require_opt_in() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+        let auth = auth::Context::new(drop_auth::SecretKey::from([0; 32]), |_| None);
+
+        let (auth, logger) = (&auth, &logger);
+
+        // The server does not support any version and reports the requested ones
+        let serve = |allow_legacy| async move {
+            let listener = tokio::net::TcpListener::bind((Ipv4Addr::LOCALHOST, 0))
+                .await
+                .unwrap();
+            let remote = listener.local_addr().unwrap();
+            let server = tokio::spawn(async move {
+                let (mut sock, _) = listener.accept().await.unwrap();
+                let mut paths = Vec::new();
+                let mut buf = Vec::new();
+
+                loop {
+                    let mut chunk = [0; 1024];
+                    let n = sock.read(&mut chunk).await.unwrap();
+                    if n == 0 {
+                        break paths;
+                    }
+                    buf.extend_from_slice(&chunk[..n]);
+
+                    while let Some(end) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+                        let req = String::from_utf8(buf.drain(..end + 4).collect()).unwrap();
+                        paths.push(req.split(' ').nth(1).unwrap().to_string());
+
+                        sock.write_all(b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\r\n")
+                            .await
+                            .unwrap();
+                    }
+                }
+            });
+
+            let local = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0);
+            let conn = establish_ws_conn(local, &[remote], auth, allow_legacy, logger).await;
+            assert!(matches!(
+                conn,
+                WsConnection::Unrecoverable(crate::Error::Io(err)) if err.kind() == io::ErrorKind::NotFound
+            ));
+
+            server.await.unwrap()
+        };
+
+        assert_eq!(
+            serve(false).await,
+            [
+                "/drop/v12",
+                "/drop/v11",
+                "/drop/v10",
+                "/drop/v9",
+                "/drop/v8",
+                "/drop/v7",
+                "/drop/v6",
+                "/drop/v5",
+                "/drop/v4"
+            ]
+        );
+        assert_eq!(
+            serve(true).await,
+            [
+                "/drop/v12",
+                "/drop/v11",
+                "/drop/v10",
+                "/drop/v9",
+                "/drop/v8",
+                "/drop/v7",
+                "/drop/v6",
+                "/drop/v5",
+                "/drop/v4",
+                "/drop/v2",
+                "/drop/v1"
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn connecting_to_any_of_addresses() {
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+
+        // Nobody listens on the port of the dropped listener
+        let refused = tokio::net::TcpListener::bind((Ipv4Addr::LOCALHOST, 0))
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap();
+
+        let listener = tokio::net::TcpListener::bind((Ipv4Addr::LOCALHOST, 0))
+            .await
+            .unwrap();
+        let remote = listener.local_addr().unwrap();
+
+        let local = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0);
+        let (_, connected) = connect_any(local, &[refused, remote], &logger)
+            .await
+            .unwrap();
+        assert_eq!(connected, remote);
+
+        let err = connect_any(local, &[refused], &logger).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionRefused);
+    }
+}
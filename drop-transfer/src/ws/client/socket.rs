@@ -58,3 +58,49 @@ impl WebSocket {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn slow_peer_within_timeout() {
+        const RECV_TIMEOUT: Duration = Duration::from_millis(500);
+        const MSG_DELAY: Duration = Duration::from_millis(200);
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let peer = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut ws = tokio_tungstenite::accept_async(stream).await.unwrap();
+
+            for i in 0..3 {
+                tokio::time::sleep(MSG_DELAY).await;
+                ws.send(Message::Text(i.to_string())).await.unwrap();
+            }
+
+            // Go silent for longer than the timeout
+            tokio::time::sleep(RECV_TIMEOUT * 2).await;
+        });
+
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let (stream, _) = tokio_tungstenite::client_async(format!("ws://{addr}"), stream)
+            .await
+            .unwrap();
+        let mut socket = WebSocket::new(stream, RECV_TIMEOUT, RECV_TIMEOUT);
+
+        // The messages come in slower than usual but each within the timeout
+        for i in 0..3 {
+            let msg = socket.recv().await.unwrap();
+            assert_eq!(msg, Message::Text(i.to_string()));
+        }
+
+        let err = socket.recv().await.unwrap_err();
+        assert!(matches!(err, crate::Error::Io(err) if err.kind() == io::ErrorKind::TimedOut));
+
+        peer.await.unwrap();
+    }
+}
@@ -100,12 +100,12 @@ impl<'a, const PING: bool> handler::HandlerInit for HandlerInit<'a, PING> {
     }
 
     fn pinger(&mut self) -> Self::Pinger {
-        ws::utils::Pinger::<PING>::new()
+        ws::utils::Pinger::<PING>::new(&self.state.config)
     }
 
-    fn recv_timeout(&mut self) -> Duration {
+    fn recv_timeout(&mut self, timeout: Duration) -> Duration {
         if PING {
-            drop_config::TRANFER_IDLE_LIFETIME
+            timeout
         } else {
             Duration::MAX
         }
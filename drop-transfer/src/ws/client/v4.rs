@@ -17,7 +17,11 @@ use super::{
     WebSocket,
 };
 use crate::{
-    protocol::v4, service::State, tasks::AliveGuard, transfer::Transfer, ws::events::FileEventTx,
+    protocol::v4,
+    service::State,
+    tasks::AliveGuard,
+    transfer::Transfer,
+    ws::{self, events::FileEventTx},
     FileId, OutgoingTransfer,
 };
 
@@ -64,7 +68,7 @@ impl<'a> HandlerInit<'a> {
 
 #[async_trait::async_trait]
 impl<'a> handler::HandlerInit for HandlerInit<'a> {
-    type Pinger = tokio::time::Interval;
+    type Pinger = ws::utils::Pinger;
     type Loop = HandlerLoop<'a>;
 
     async fn start(
@@ -95,7 +99,7 @@ impl<'a> handler::HandlerInit for HandlerInit<'a> {
     }
 
     fn pinger(&mut self) -> Self::Pinger {
-        tokio::time::interval(drop_config::PING_INTERVAL)
+        ws::utils::Pinger::new(&self.state.config)
     }
 }
 
@@ -139,6 +143,7 @@ impl HandlerLoop<'_> {
                 let checksum = xfer.files()[&file_id]
                     .checksum::<_, futures::future::Ready<()>>(
                         limit,
+                        state.config.checksum_chunk_size,
                         None::<fn(u64) -> futures::future::Ready<()>>,
                         None,
                     )
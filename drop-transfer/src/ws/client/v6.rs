@@ -1,9 +1,11 @@
 use std::{
     collections::{hash_map::Entry, HashMap},
+    future::Future,
     sync::Arc,
 };
 
 use anyhow::Context;
+use drop_auth::ContentCipher;
 use drop_core::Status;
 use slog::{debug, error, info, warn};
 use tokio::{
@@ -17,14 +19,23 @@ use super::{
     WebSocket,
 };
 use crate::{
-    manager::FileTerminalState, protocol::v6 as prot, service::State, tasks::AliveGuard,
-    transfer::Transfer, ws::events::FileEventTx, FileId, OutgoingTransfer,
+    manager::FileTerminalState,
+    protocol::{self, v6 as prot},
+    service::State,
+    tasks::AliveGuard,
+    transfer::Transfer,
+    ws::{self, events::FileEventTx},
+    FileId, OutgoingTransfer,
 };
 
 pub struct HandlerInit<'a> {
     state: &'a Arc<State>,
     logger: &'a slog::Logger,
     alive: &'a AliveGuard,
+    exchange_info: bool,
+    encrypt_contents: bool,
+    file_restart: bool,
+    cipher: Option<Arc<ContentCipher>>,
 }
 
 pub struct HandlerLoop<'a> {
@@ -34,6 +45,8 @@ pub struct HandlerLoop<'a> {
     upload_tx: Sender<MsgToSend>,
     tasks: HashMap<FileId, FileTask>,
     xfer: Arc<OutgoingTransfer>,
+    cipher: Option<Arc<ContentCipher>>,
+    file_restart: bool,
 }
 
 struct FileTask {
@@ -46,6 +59,10 @@ struct Uploader {
     file_id: FileId,
     offset: u64,
     logger: slog::Logger,
+    cipher: Option<Arc<ContentCipher>>,
+    // Where the next chunk starts within the file
+    position: u64,
+    file_restart: bool,
 }
 
 impl<'a> HandlerInit<'a> {
@@ -53,18 +70,23 @@ impl<'a> HandlerInit<'a> {
         state: &'a Arc<State>,
         logger: &'a slog::Logger,
         alive: &'a AliveGuard,
+        version: protocol::Version,
     ) -> Self {
         Self {
             state,
             logger,
             alive,
+            exchange_info: version.has_peer_info(),
+            encrypt_contents: version.has_encrypted_contents() && state.config.encrypt_contents,
+            file_restart: version.has_file_restart(),
+            cipher: None,
         }
     }
 }
 
 #[async_trait::async_trait]
 impl<'a> handler::HandlerInit for HandlerInit<'a> {
-    type Pinger = tokio::time::Interval;
+    type Pinger = ws::utils::Pinger;
     type Loop = HandlerLoop<'a>;
 
     async fn start(
@@ -72,7 +94,35 @@ impl<'a> handler::HandlerInit for HandlerInit<'a> {
         socket: &mut WebSocket,
         xfer: &OutgoingTransfer,
     ) -> crate::Result<()> {
-        let req = prot::TransferRequest::from(xfer);
+        let mut req = prot::TransferRequest::from(xfer);
+        if self.state.config.always_send_checksums {
+            // Hashing the files here would delay the request past the receiver's timeout.
+            // Only the cached checksums are attached, the receiver requests the rest and
+            // these are computed in the background
+            for file in &mut req.files {
+                // The checksum of the streamed file is known only once it ends
+                let Some(size) = file.size else {
+                    continue;
+                };
+
+                file.checksum = xfer.files()[&file.id]
+                    .cached_checksum(&self.state.storage, size)
+                    .await;
+            }
+        }
+        if self.exchange_info {
+            req.info = Some(prot::PeerInfo::from_config(&self.state.config));
+        }
+        if self.encrypt_contents {
+            let cipher = self
+                .state
+                .auth
+                .content_cipher(xfer.peer(), xfer.id())
+                .ok_or(crate::Error::AuthenticationFailed)?;
+
+            self.cipher = Some(Arc::new(cipher));
+            req.encrypted = true;
+        }
         socket.send(Message::from(&req)).await?;
         Ok(())
     }
@@ -82,6 +132,10 @@ impl<'a> handler::HandlerInit for HandlerInit<'a> {
             state,
             logger,
             alive,
+            exchange_info: _,
+            encrypt_contents: _,
+            file_restart,
+            cipher,
         } = self;
 
         HandlerLoop {
@@ -91,11 +145,13 @@ impl<'a> handler::HandlerInit for HandlerInit<'a> {
             upload_tx,
             xfer,
             tasks: HashMap::new(),
+            cipher,
+            file_restart,
         }
     }
 
     fn pinger(&mut self) -> Self::Pinger {
-        tokio::time::interval(drop_config::PING_INTERVAL)
+        ws::utils::Pinger::new(&self.state.config)
     }
 }
 
@@ -126,6 +182,25 @@ impl HandlerLoop<'_> {
         self.stop_task(&file_id, Status::FileRejected).await;
     }
 
+    async fn on_select(&mut self, files: Vec<FileId>) {
+        info!(self.logger, "on select files {files:?}");
+
+        match self
+            .state
+            .transfer_manager
+            .outgoing_selection_recv(self.xfer.id(), &files)
+            .await
+        {
+            Err(err) => error!(self.logger, "Failed to handle file selection: {err}"),
+            Ok(rejected) => {
+                for events in rejected {
+                    events.rejected(true).await;
+                    self.stop_task(events.file_id(), Status::FileRejected).await;
+                }
+            }
+        }
+    }
+
     async fn stop_task(&mut self, file_id: &FileId, status: Status) {
         if let Some(task) = self.tasks.remove(file_id) {
             if !task.job.is_finished() {
@@ -147,12 +222,65 @@ impl HandlerLoop<'_> {
         }
     }
 
+    fn on_peer_info(&self, info: prot::PeerInfo) {
+        let info = info.sanitized();
+
+        self.state.emit_event(crate::Event::PeerInfo {
+            transfer_id: self.xfer.id(),
+            device_name: info.device_name,
+            app_version: info.app_version,
+        });
+    }
+
     async fn on_done(&mut self, file_id: FileId) {
         super::on_upload_finished(self.state, &self.xfer, &file_id, self.logger).await;
         self.stop_task(&file_id, Status::FileFinished).await;
     }
 
     fn on_checksum(&self, jobs: &mut JoinSet<()>, file_id: FileId, limit: u64) {
+        self.spawn_report(jobs, file_id, move |state, xfer, file_id| async move {
+            let checksum = xfer.files()[&file_id]
+                .checksum_cached(&state.storage, limit, state.config.checksum_chunk_size)
+                .await?;
+
+            Ok(prot::ClientMsg::ReportChsum(prot::ReportChsum {
+                file: file_id,
+                limit,
+                checksum,
+            }))
+        });
+    }
+
+    fn on_block_checksums(
+        &self,
+        jobs: &mut JoinSet<()>,
+        file_id: FileId,
+        limit: u64,
+        block_size: u64,
+    ) {
+        self.spawn_report(jobs, file_id, move |state, xfer, file_id| async move {
+            validate_block_request(limit, block_size)?;
+
+            let checksums = xfer.files()[&file_id]
+                .block_checksums(limit, block_size, state.config.checksum_chunk_size)
+                .await?;
+
+            Ok(prot::ClientMsg::ReportBlockChsums(
+                prot::ReportBlockChsums {
+                    file: file_id,
+                    limit,
+                    block_size,
+                    checksums,
+                },
+            ))
+        });
+    }
+
+    fn spawn_report<F, Fut>(&self, jobs: &mut JoinSet<()>, file_id: FileId, make_report: F)
+    where
+        F: FnOnce(Arc<State>, Arc<OutgoingTransfer>, FileId) -> Fut + Send + 'static,
+        Fut: Future<Output = crate::Result<prot::ClientMsg>> + Send,
+    {
         let state = self.state.clone();
         let msg_tx = self.upload_tx.clone();
         let xfer = self.xfer.clone();
@@ -162,33 +290,18 @@ impl HandlerLoop<'_> {
         let task = async move {
             let _guard = alive;
 
-            let make_report = async {
+            let report = async {
                 state
                     .transfer_manager
                     .outgoing_ensure_file_not_terminated(xfer.id(), &file_id)
                     .await?;
 
-                let checksum = xfer.files()[&file_id]
-                    .checksum::<_, futures::future::Ready<()>>(
-                        limit,
-                        None::<fn(u64) -> futures::future::Ready<()>>,
-                        None,
-                    )
-                    .await?;
-
-                crate::Result::Ok(prot::ReportChsum {
-                    file: file_id.clone(),
-                    limit,
-                    checksum,
-                })
+                make_report(state.clone(), xfer.clone(), file_id.clone()).await
             };
 
-            match make_report.await {
+            match report.await {
                 Ok(report) => {
-                    if let Err(e) = msg_tx
-                        .send(MsgToSend::from(&prot::ClientMsg::ReportChsum(report)))
-                        .await
-                    {
+                    if let Err(e) = msg_tx.send(MsgToSend::from(&report)).await {
                         warn!(logger, "Failed to send checksum report: {:?}", e);
                     };
                 }
@@ -246,6 +359,9 @@ impl HandlerLoop<'_> {
                     file_id: file_id.clone(),
                     offset,
                     logger: self.logger.clone(),
+                    cipher: self.cipher.clone(),
+                    position: offset,
+                    file_restart: self.file_restart,
                 };
                 let state = self.state.clone();
                 let alive = self.alive.clone();
@@ -368,11 +484,18 @@ impl handler::HandlerLoop for HandlerLoop<'_> {
             prot::ServerMsg::ReqChsum(prot::ReqChsum { file, limit }) => {
                 self.on_checksum(jobs, file, limit)
             }
+            prot::ServerMsg::ReqBlockChsums(prot::ReqBlockChsums {
+                file,
+                limit,
+                block_size,
+            }) => self.on_block_checksums(jobs, file, limit, block_size),
             prot::ServerMsg::Start(prot::Start { file, offset }) => {
                 self.on_start(socket, jobs, file, offset).await?
             }
             prot::ServerMsg::Cancel(prot::Cancel { file }) => self.on_cancel(file).await,
             prot::ServerMsg::Reject(prot::Reject { file }) => self.on_reject(file).await,
+            prot::ServerMsg::PeerInfo(info) => self.on_peer_info(info),
+            prot::ServerMsg::Select(prot::Select { files }) => self.on_select(files).await,
         }
         Ok(())
     }
@@ -405,9 +528,15 @@ impl Drop for HandlerLoop<'_> {
 #[async_trait::async_trait]
 impl handler::Uploader for Uploader {
     async fn chunk(&mut self, chunk: &[u8]) -> Result<(), crate::Error> {
+        let data = match &self.cipher {
+            Some(cipher) => cipher.seal(&prot::chunk_aad(&self.file_id, self.position), chunk),
+            None => chunk.to_vec(),
+        };
+        self.position += chunk.len() as u64;
+
         let msg = prot::Chunk {
             file: self.file_id.clone(),
-            data: chunk.to_vec(),
+            data,
         };
 
         self.sink
@@ -420,6 +549,43 @@ impl handler::Uploader for Uploader {
         Ok(())
     }
 
+    async fn end(&mut self, checksum: [u8; 32]) -> crate::Result<()> {
+        let msg = prot::ClientMsg::End(prot::End {
+            file: self.file_id.clone(),
+            checksum,
+        });
+
+        self.sink
+            .send(MsgToSend {
+                msg: Message::from(&msg),
+            })
+            .await
+            .map_err(|_| crate::Error::Canceled)?;
+
+        Ok(())
+    }
+
+    async fn restart(&mut self) -> crate::Result<()> {
+        if !self.file_restart {
+            return Err(crate::Error::BadTransferState(
+                "Restarting the files is not supported by the protocol version".into(),
+            ));
+        }
+
+        let msg = prot::ClientMsg::Cancel(prot::Cancel {
+            file: self.file_id.clone(),
+        });
+
+        self.sink
+            .send(MsgToSend {
+                msg: Message::from(&msg),
+            })
+            .await
+            .map_err(|_| crate::Error::Canceled)?;
+
+        Ok(())
+    }
+
     async fn error(&mut self, msg: String) {
         let msg = prot::ClientMsg::Error(prot::Error {
             file: Some(self.file_id.clone()),
@@ -441,3 +607,44 @@ impl handler::Uploader for Uploader {
         self.offset
     }
 }
+
+fn validate_block_request(limit: u64, block_size: u64) -> crate::Result<()> {
+    if block_size < prot::MIN_CHECKSUM_BLOCK_SIZE {
+        return Err(crate::Error::BadTransferState(format!(
+            "Checksum block size {block_size} is below {}",
+            prot::MIN_CHECKSUM_BLOCK_SIZE
+        )));
+    }
+
+    let blocks = limit / block_size + u64::from(limit % block_size != 0);
+    if blocks > prot::MAX_CHECKSUM_BLOCKS {
+        return Err(crate::Error::BadTransferState(format!(
+            "Too many checksum blocks requested, {limit} bytes in blocks of {block_size}"
+        )));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn block_request_bounds() {
+        let min = prot::MIN_CHECKSUM_BLOCK_SIZE;
+
+        assert!(validate_block_request(0, min).is_ok());
+        assert!(validate_block_request(10 * min, min).is_ok());
+        assert!(validate_block_request(u64::MAX, u64::MAX).is_ok());
+
+        // Small blocks would make the report huge
+        assert!(validate_block_request(10, 0).is_err());
+        assert!(validate_block_request(10, 1).is_err());
+        assert!(validate_block_request(10, min - 1).is_err());
+
+        // So would too many of them
+        assert!(validate_block_request(prot::MAX_CHECKSUM_BLOCKS * min, min).is_ok());
+        assert!(validate_block_request(prot::MAX_CHECKSUM_BLOCKS * min + 1, min).is_err());
+    }
+}
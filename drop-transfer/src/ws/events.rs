@@ -6,10 +6,14 @@ use std::{
 
 use drop_analytics::{Moose, TransferFileEventData, TransferStateEventData, MOOSE_STATUS_SUCCESS};
 use drop_core::Status;
-use tokio::sync::{mpsc::UnboundedSender, Mutex};
+use drop_storage::CancelCause;
+use tokio::sync::{mpsc::UnboundedSender, oneshot, Mutex};
 
 use crate::{
-    file::FileInfo, utils, Event, File, FileId, IncomingTransfer, OutgoingTransfer, Transfer,
+    file::FileInfo,
+    manager::{FileTerminalState, TransferStats},
+    metrics::{FileRate, Metrics},
+    utils, Event, File, FileId, IncomingTransfer, OutgoingTransfer, Transfer,
 };
 
 struct FileEventTxInner {
@@ -17,6 +21,25 @@ struct FileEventTxInner {
     moose: Arc<dyn Moose>,
     state: FileState,
     transferred: u64,
+    progress_granularity: u64,
+    last_progress: Option<u64>,
+    progress_interval: Duration,
+    last_progress_at: Option<Instant>,
+    // The latest progress event suppressed by the interval
+    pending_progress: Option<Event>,
+    rate: FileRate,
+    // Awaiting the terminal state of the downloaded file
+    waiters: Vec<oneshot::Sender<crate::Result<PathBuf>>>,
+}
+
+/// What the file is busy with, as seen by its events
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileActivity {
+    Idle,
+    Throttled,
+    Verifying,
+    Transferring,
+    Terminal,
 }
 
 enum FileState {
@@ -39,6 +62,9 @@ pub struct FileEventTx<T: Transfer> {
 pub struct EventTxFactory {
     events: UnboundedSender<(Event, SystemTime)>,
     moose: Arc<dyn Moose>,
+    progress_granularity: u64,
+    progress_interval: Duration,
+    metrics: Arc<Metrics>,
 }
 
 pub struct TransferEventTx<T: Transfer> {
@@ -58,6 +84,8 @@ struct TransferEventTxInner {
     tx: UnboundedSender<(Event, SystemTime)>,
     moose: Arc<dyn Moose>,
     state: TransferState,
+    // The time the transfer was created at, the summary duration is measured from
+    created: SystemTime,
 }
 
 trait EventTx {
@@ -73,8 +101,32 @@ impl EventTx for UnboundedSender<(Event, SystemTime)> {
 }
 
 impl EventTxFactory {
-    pub fn new(events: UnboundedSender<(Event, SystemTime)>, moose: Arc<dyn Moose>) -> Self {
-        Self { events, moose }
+    pub fn new(
+        events: UnboundedSender<(Event, SystemTime)>,
+        moose: Arc<dyn Moose>,
+        progress_granularity: u64,
+    ) -> Self {
+        Self {
+            events,
+            moose,
+            progress_granularity,
+            progress_interval: Duration::ZERO,
+            metrics: Arc::default(),
+        }
+    }
+
+    /// Emit at most one progress event per file within the interval. The
+    /// suppressed event is replaced by the next one or emitted right before
+    /// the file stops
+    pub fn with_progress_interval(mut self, interval: Duration) -> Self {
+        self.progress_interval = interval;
+        self
+    }
+
+    /// Account the progress of the files in the given service-wide counters
+    pub(crate) fn with_metrics(mut self, metrics: Arc<Metrics>) -> Self {
+        self.metrics = metrics;
+        self
     }
 
     pub fn file<T: Transfer>(&self, xfer: Arc<T>, file_id: FileId) -> FileEventTx<T> {
@@ -84,13 +136,25 @@ impl EventTxFactory {
                 moose: self.moose.clone(),
                 state: FileState::Idle,
                 transferred: 0,
+                progress_granularity: self.progress_granularity,
+                last_progress: None,
+                progress_interval: self.progress_interval,
+                last_progress_at: None,
+                pending_progress: None,
+                rate: FileRate::new(self.metrics.clone(), T::direction()),
+                waiters: Vec::new(),
             }),
             xfer,
             file_id,
         }
     }
 
-    pub fn transfer<T: Transfer>(&self, xfer: Arc<T>, blocked: bool) -> TransferEventTx<T> {
+    pub fn transfer<T: Transfer>(
+        &self,
+        xfer: Arc<T>,
+        blocked: bool,
+        created: SystemTime,
+    ) -> TransferEventTx<T> {
         TransferEventTx {
             inner: Mutex::new(TransferEventTxInner {
                 tx: self.events.clone(),
@@ -100,6 +164,7 @@ impl EventTxFactory {
                 } else {
                     TransferState::Ongoing
                 },
+                created,
             }),
             xfer,
         }
@@ -107,10 +172,44 @@ impl EventTxFactory {
 }
 
 impl<T: Transfer> FileEventTx<T> {
+    /// Lets the terminated file emit the events again once it's transferred
+    /// anew
+    pub async fn reset(&self) {
+        let mut lock = self.inner.lock().await;
+
+        if matches!(lock.state, FileState::Terminal) {
+            lock.state = FileState::Idle;
+        }
+    }
+
     fn file_info(&self) -> FileInfo {
         self.xfer.files()[&self.file_id].info()
     }
 
+    /// The last reported progress of the file
+    pub async fn transferred(&self) -> u64 {
+        self.inner.lock().await.transferred
+    }
+
+    pub async fn activity(&self) -> FileActivity {
+        let lock = self.inner.lock().await;
+
+        match lock.state {
+            FileState::Idle => FileActivity::Idle,
+            FileState::Throttled => FileActivity::Throttled,
+            // The checksum of the already downloaded part is being computed
+            FileState::Preflight => FileActivity::Verifying,
+            // All of the bytes are in, only the final checksum is left
+            FileState::InFlight { .. }
+                if lock.transferred >= self.xfer.files()[&self.file_id].size() =>
+            {
+                FileActivity::Verifying
+            }
+            FileState::InFlight { .. } => FileActivity::Transferring,
+            FileState::Terminal => FileActivity::Terminal,
+        }
+    }
+
     async fn emit_in_flight(&self, event: Event) {
         let mut lock = self.inner.lock().await;
 
@@ -124,6 +223,21 @@ impl<T: Transfer> FileEventTx<T> {
             Event::FileUploadProgress(_, _, progress)
             | Event::FileDownloadProgress(_, _, progress) => {
                 lock.transferred = progress;
+                lock.rate.progress(progress);
+
+                let now = Instant::now();
+                let complete = progress >= self.xfer.files()[&self.file_id].size();
+                let coalesce = lock
+                    .last_progress_at
+                    .is_some_and(|at| now.duration_since(at) < lock.progress_interval);
+
+                if coalesce && !complete {
+                    lock.pending_progress = Some(event);
+                    return;
+                }
+
+                lock.last_progress_at = Some(now);
+                lock.pending_progress = None;
             }
             _ => {}
         }
@@ -141,8 +255,17 @@ impl<T: Transfer> FileEventTx<T> {
         lock.state = FileState::InFlight {
             started: Instant::now(),
         };
+        lock.last_progress = None;
+        lock.last_progress_at = None;
+        lock.pending_progress = None;
 
         for event in events.into_iter() {
+            if let Event::FileUploadStarted(_, _, offset)
+            | Event::FileDownloadStarted(_, _, _, offset) = event
+            {
+                lock.rate.start(offset);
+            }
+
             lock.tx.emit(event);
         }
     }
@@ -157,6 +280,7 @@ impl<T: Transfer> FileEventTx<T> {
             FileState::Preflight => Duration::ZERO,
             FileState::Terminal => return,
         };
+        lock.rate.stop();
 
         let phase = match event {
             Event::FileUploadPaused { .. } | Event::FileDownloadPaused { .. } => {
@@ -182,6 +306,9 @@ impl<T: Transfer> FileEventTx<T> {
             result,
         });
 
+        if let Some(progress) = lock.pending_progress.take() {
+            lock.tx.emit(progress);
+        }
         lock.tx.emit(event);
     }
 
@@ -195,6 +322,7 @@ impl<T: Transfer> FileEventTx<T> {
             FileState::Preflight => Duration::ZERO,
             FileState::Terminal => return,
         };
+        lock.rate.stop();
 
         let phase = match event {
             Event::FileUploadPaused { .. } | Event::FileDownloadPaused { .. } => {
@@ -220,11 +348,28 @@ impl<T: Transfer> FileEventTx<T> {
             result,
         });
 
+        for waiter in std::mem::take(&mut lock.waiters) {
+            let outcome = match &event {
+                Event::FileDownloadSuccess(_, success) => Ok(success.final_path.to_path_buf()),
+                Event::FileDownloadFailed(_, _, err) => Err(err.duplicate()),
+                Event::FileDownloadRejected { .. } => {
+                    Err(crate::Error::FileStateMismatch(FileTerminalState::Rejected))
+                }
+                _ => Err(crate::Error::Canceled),
+            };
+            let _ = waiter.send(outcome);
+        }
+
+        if let Some(progress) = lock.pending_progress.take() {
+            lock.tx.emit(progress);
+        }
         lock.tx.emit(event);
     }
 
     pub async fn stop_silent(&self, status: Status) {
         let mut lock = self.inner.lock().await;
+        lock.pending_progress = None;
+        lock.rate.stop();
 
         let elapsed = match std::mem::replace(&mut lock.state, FileState::Idle) {
             FileState::Idle => None,
@@ -269,6 +414,29 @@ impl FileEventTx<IncomingTransfer> {
         });
     }
 
+    /// Resolves with the final path once the file is downloaded or with the
+    /// reason it failed or got rejected. The sender is dropped without the
+    /// outcome when the transfer goes away, e.g. it's canceled
+    pub async fn subscribe_outcome(&self) -> oneshot::Receiver<crate::Result<PathBuf>> {
+        let (tx, rx) = oneshot::channel();
+
+        let mut lock = self.inner.lock().await;
+        lock.waiters.retain(|waiter| !waiter.is_closed());
+        lock.waiters.push(tx);
+
+        rx
+    }
+
+    pub async fn missing(&self, final_path: impl Into<PathBuf>) {
+        let lock = self.inner.lock().await;
+
+        lock.tx.emit(crate::Event::FileDownloadMissing {
+            transfer_id: self.xfer.id(),
+            file_id: self.file_id.clone(),
+            final_path: crate::utils::Hidden(final_path.into().into_boxed_path()),
+        });
+    }
+
     pub async fn finalize_checksum_start(&self, size: u64) {
         self.emit_in_flight(crate::Event::FinalizeChecksumStarted {
             transfer_id: self.xfer.id(),
@@ -366,13 +534,15 @@ impl FileEventTx<IncomingTransfer> {
         .await
     }
 
-    pub async fn success(&self, final_path: impl Into<PathBuf>) {
+    pub async fn success(&self, final_path: impl Into<PathBuf>, renamed: bool, deleted: bool) {
         self.terminate(
             crate::Event::FileDownloadSuccess(
                 self.xfer.clone(),
                 crate::event::DownloadSuccess {
                     id: self.file_id.clone(),
                     final_path: crate::utils::Hidden(final_path.into().into_boxed_path()),
+                    renamed,
+                    deleted,
                 },
             ),
             Ok(()),
@@ -413,6 +583,26 @@ impl FileEventTx<OutgoingTransfer> {
     }
 
     pub async fn progress(&self, transfered: u64) {
+        // The receiver reports progress at its own pace, so the upload events are
+        // limited here to the locally configured granularity
+        {
+            let mut lock = self.inner.lock().await;
+
+            let due = match lock.last_progress {
+                Some(last) => {
+                    last + lock.progress_granularity <= transfered
+                        || transfered >= self.xfer.files()[&self.file_id].size()
+                }
+                None => true,
+            };
+
+            if !due {
+                return;
+            }
+
+            lock.last_progress = Some(transfered);
+        }
+
         self.emit_in_flight(crate::Event::FileUploadProgress(
             self.xfer.clone(),
             self.file_id.clone(),
@@ -493,7 +683,7 @@ impl<T: Transfer> TransferEventTx<T> {
         lock.tx.emit(event);
     }
 
-    async fn stop(&self, event: Event) {
+    async fn stop(&self, event: Event, stats: TransferStats) {
         let mut lock = self.inner.lock().await;
 
         if let TransferState::Terminated =
@@ -503,16 +693,38 @@ impl<T: Transfer> TransferEventTx<T> {
         }
 
         lock.tx.emit(event);
+        lock.emit_summary(self.xfer.id(), stats);
+    }
+}
+
+impl TransferEventTxInner {
+    fn emit_summary(&self, transfer_id: uuid::Uuid, stats: TransferStats) {
+        self.tx.emit(Event::TransferSummary {
+            transfer_id,
+            succeeded: stats.succeeded,
+            failed: stats.failed,
+            rejected: stats.rejected,
+            total_bytes: stats.total_bytes,
+            duration_secs: SystemTime::now()
+                .duration_since(self.created)
+                .unwrap_or_default()
+                .as_secs(),
+        });
     }
 }
 
 impl TransferEventTx<OutgoingTransfer> {
+    pub async fn created(&self) {
+        self.emit_ongoing(Event::OutgoingTransferCreated(self.xfer.clone()))
+            .await;
+    }
+
     pub async fn queued(&self) {
         self.emit_ongoing(Event::RequestQueued(self.xfer.clone()))
             .await;
     }
 
-    pub async fn failed(&self, err: crate::Error, by_peer: bool) {
+    pub async fn failed(&self, err: crate::Error, by_peer: bool, stats: TransferStats) {
         let mut lock = self.inner.lock().await;
 
         if let TransferState::Terminated =
@@ -532,6 +744,7 @@ impl TransferEventTx<OutgoingTransfer> {
             err,
             by_peer,
         ));
+        lock.emit_summary(self.xfer.id(), stats);
     }
 
     pub async fn deferred(&self, err: crate::Error) {
@@ -556,20 +769,58 @@ impl TransferEventTx<OutgoingTransfer> {
         });
     }
 
-    pub async fn cancel(&self, by_peer: bool) {
-        self.stop(Event::OutgoingTransferCanceled(self.xfer.clone(), by_peer))
-            .await;
+    pub async fn cancel(&self, by_peer: bool, cause: CancelCause, stats: TransferStats) {
+        self.stop(
+            Event::OutgoingTransferCanceled(self.xfer.clone(), by_peer, cause),
+            stats,
+        )
+        .await;
+    }
+
+    pub async fn rejected(&self, reason: String, stats: TransferStats) {
+        self.stop(
+            Event::OutgoingTransferRejected {
+                transfer: self.xfer.clone(),
+                reason,
+            },
+            stats,
+        )
+        .await;
     }
 }
 
 impl TransferEventTx<IncomingTransfer> {
+    pub async fn created(&self) {
+        self.emit_ongoing(Event::IncomingTransferCreated(self.xfer.clone()))
+            .await;
+    }
+
     pub async fn received(&self) {
         self.emit_ongoing(Event::RequestReceived(self.xfer.clone()))
             .await;
     }
 
-    pub async fn cancel(&self, by_peer: bool) {
-        self.stop(Event::IncomingTransferCanceled(self.xfer.clone(), by_peer))
+    pub async fn cancel(&self, by_peer: bool, cause: CancelCause, stats: TransferStats) {
+        self.stop(
+            Event::IncomingTransferCanceled(self.xfer.clone(), by_peer, cause),
+            stats,
+        )
+        .await;
+    }
+
+    pub async fn rejected(&self, reason: String, stats: TransferStats) {
+        self.stop(
+            Event::IncomingTransferRejected {
+                transfer: self.xfer.clone(),
+                reason,
+            },
+            stats,
+        )
+        .await;
+    }
+
+    pub async fn expired(&self, stats: TransferStats) {
+        self.stop(Event::IncomingTransferExpired(self.xfer.clone()), stats)
             .await;
     }
 }
@@ -604,3 +855,200 @@ impl<T: Transfer> Drop for FileEventTx<T> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use drop_config::DropConfig;
+
+    use super::*;
+    use crate::FileToRecv;
+
+    #[tokio::test]
+    async fn coalescing_progress_events() {
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        let factory = EventTxFactory::new(tx, drop_analytics::moose_mock(), 1)
+            .with_progress_interval(Duration::from_secs(60));
+
+        let xfer = Arc::new(
+            IncomingTransfer::new(
+                "1.2.3.4".parse().unwrap(),
+                vec![FileToRecv::new("id1".into(), "a.txt".into(), 1024)],
+                &DropConfig::default(),
+            )
+            .unwrap(),
+        );
+        let events = factory.file(xfer, "id1".into());
+
+        let mut progress = || {
+            let mut out = Vec::new();
+            while let Ok((event, _)) = rx.try_recv() {
+                match event {
+                    Event::FileDownloadProgress(_, _, bytes) => out.push(Some(bytes)),
+                    _ => out.push(None),
+                }
+            }
+            out
+        };
+
+        events.start("/dir", 0).await;
+        events.progress(0).await;
+        events.progress(100).await;
+        events.progress(200).await;
+        // The first update goes through and the next ones within the interval are held
+        assert_eq!(progress(), [None, Some(0)]);
+
+        // The latest held update precedes the pause
+        events.pause().await;
+        assert_eq!(progress(), [Some(200), None]);
+
+        events.start("/dir", 200).await;
+        events.progress(200).await;
+        events.progress(1024).await;
+        // Completion is never held back
+        assert_eq!(progress(), [None, Some(200), Some(1024)]);
+    }
+
+    #[tokio::test]
+    async fn awaiting_file_outcome() {
+        let factory = EventTxFactory::new(
+            tokio::sync::mpsc::unbounded_channel().0,
+            drop_analytics::moose_mock(),
+            1,
+        );
+
+        let xfer = Arc::new(
+            IncomingTransfer::new(
+                "1.2.3.4".parse().unwrap(),
+                vec![
+                    FileToRecv::new("id1".into(), "a.txt".into(), 1024),
+                    FileToRecv::new("id2".into(), "b.txt".into(), 1024),
+                    FileToRecv::new("id3".into(), "c.txt".into(), 1024),
+                ],
+                &DropConfig::default(),
+            )
+            .unwrap(),
+        );
+
+        // Any number of callers can await the same file
+        let events = factory.file(xfer.clone(), "id1".into());
+        let first = events.subscribe_outcome().await;
+        let second = events.subscribe_outcome().await;
+        events.start("/dir", 0).await;
+        events.progress(1024).await;
+        events.success("/dir/a.txt", false, false).await;
+
+        assert_eq!(first.await.unwrap().unwrap(), PathBuf::from("/dir/a.txt"));
+        assert_eq!(second.await.unwrap().unwrap(), PathBuf::from("/dir/a.txt"));
+
+        let events = factory.file(xfer.clone(), "id2".into());
+        let outcome = events.subscribe_outcome().await;
+        events.start("/dir", 0).await;
+        events
+            .failed(crate::Error::Io(std::io::Error::from_raw_os_error(28)))
+            .await;
+
+        let err = outcome.await.unwrap().unwrap_err();
+        assert!(matches!(err, crate::Error::Io(_)));
+        assert_eq!(err.os_err_code(), Some(28));
+
+        // The outcome never comes once the transfer goes away
+        let events = factory.file(xfer, "id3".into());
+        let outcome = events.subscribe_outcome().await;
+        drop(events);
+        assert!(outcome.await.is_err());
+    }
+
+    #[tokio::test]
+    async fn counting_metrics() {
+        let metrics = Arc::new(Metrics::default());
+        let factory = EventTxFactory::new(
+            tokio::sync::mpsc::unbounded_channel().0,
+            drop_analytics::moose_mock(),
+            1,
+        )
+        .with_metrics(metrics.clone());
+
+        let xfer = Arc::new(
+            IncomingTransfer::new(
+                "1.2.3.4".parse().unwrap(),
+                vec![FileToRecv::new("id1".into(), "a.txt".into(), 4096)],
+                &DropConfig::default(),
+            )
+            .unwrap(),
+        );
+        let events = factory.file(xfer, "id1".into());
+
+        // The bytes before the resume offset are not counted
+        events.start("/dir", 1024).await;
+        std::thread::sleep(Duration::from_millis(10));
+        events.progress(2048).await;
+
+        assert_eq!(metrics.bytes_transferred(), 1024);
+        assert!(metrics.download_rate() > 0);
+        assert_eq!(metrics.upload_rate(), 0);
+
+        // The file no longer contributes to the throughput once paused
+        events.pause().await;
+        assert_eq!(metrics.download_rate(), 0);
+
+        events.start("/dir", 2048).await;
+        std::thread::sleep(Duration::from_millis(10));
+        events.progress(4096).await;
+        assert_eq!(metrics.bytes_transferred(), 3072);
+        assert!(metrics.download_rate() > 0);
+
+        events.success("/dir/a.txt", false, false).await;
+        assert_eq!(metrics.download_rate(), 0);
+
+        let connected = metrics.connected();
+        assert_eq!(metrics.connections(), 1);
+        drop(connected);
+        assert_eq!(metrics.connections(), 0);
+    }
+
+    #[tokio::test]
+    async fn emitting_single_summary() {
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        let factory = EventTxFactory::new(tx, drop_analytics::moose_mock(), 1);
+
+        let xfer = Arc::new(
+            IncomingTransfer::new(
+                "1.2.3.4".parse().unwrap(),
+                vec![FileToRecv::new("id1".into(), "a.txt".into(), 1024)],
+                &DropConfig::default(),
+            )
+            .unwrap(),
+        );
+        // The transfer restored from the storage was created long before
+        let created = SystemTime::now() - Duration::from_secs(90);
+        let events = factory.transfer(xfer, false, created);
+
+        let stats = TransferStats {
+            succeeded: 1,
+            total_bytes: 1024,
+            ..Default::default()
+        };
+        events.cancel(false, CancelCause::User, stats).await;
+        events.cancel(true, CancelCause::User, stats).await;
+        events.rejected("late".into(), stats).await;
+        events.expired(stats).await;
+
+        let summaries: Vec<_> = std::iter::from_fn(|| rx.try_recv().ok())
+            .filter_map(|(event, _)| match event {
+                Event::TransferSummary {
+                    succeeded,
+                    total_bytes,
+                    duration_secs,
+                    ..
+                } => Some((succeeded, total_bytes, duration_secs)),
+                _ => None,
+            })
+            .collect();
+
+        assert_eq!(summaries.len(), 1);
+        let (succeeded, total_bytes, duration_secs) = summaries[0];
+        assert_eq!(succeeded, 1);
+        assert_eq!(total_bytes, 1024);
+        assert!(duration_secs >= 90);
+    }
+}
@@ -5,6 +5,30 @@ mod utils;
 
 pub use events::*;
 
+/// The close frame sent by the server when the transfer exceeds the configured
+/// `max_accept_bytes` limit. The code is the standard "message too big" one
+const SIZE_REJECTED_CLOSE_CODE: u16 = 1009;
+const SIZE_REJECTED_CLOSE_REASON: &str = "Transfer size exceeds the limit";
+
+/// The close frame sent by the server when the user rejects the whole transfer.
+/// The code is from the private use range, the frame reason carries the
+/// rejection reason
+const TRANSFER_REJECTED_CLOSE_CODE: u16 = 4000;
+// The close frame payload is limited to 125 bytes, 2 of which are the code
+const MAX_CLOSE_REASON_LEN: usize = 123;
+
+fn close_reason(mut reason: String) -> String {
+    if reason.len() > MAX_CLOSE_REASON_LEN {
+        let mut end = MAX_CLOSE_REASON_LEN;
+        while !reason.is_char_boundary(end) {
+            end -= 1;
+        }
+        reason.truncate(end);
+    }
+
+    reason
+}
+
 #[async_trait::async_trait]
 pub trait Pinger {
     async fn tick(&mut self);
@@ -5,6 +5,7 @@ use warp::ws::Message;
 
 use super::{socket::WebSocket, TmpFileState};
 use crate::{
+    protocol::v6::PeerInfo,
     transfer::IncomingTransfer,
     utils::Hidden,
     ws::{self},
@@ -32,8 +33,12 @@ pub trait HandlerInit {
     ) -> Option<Self::Loop>;
 
     fn pinger(&mut self) -> Self::Pinger;
-    fn recv_timeout(&mut self) -> Duration {
-        drop_config::TRANFER_IDLE_LIFETIME
+    fn recv_timeout(&mut self, timeout: Duration) -> Duration {
+        timeout
+    }
+    // The older protocols carry no information about the peer
+    fn peer_info(&self) -> PeerInfo {
+        PeerInfo::default()
     }
 }
 
@@ -47,6 +52,22 @@ pub trait HandlerLoop {
         offset: u64,
     ) -> anyhow::Result<()>;
     async fn issue_reject(&mut self, ws: &mut WebSocket, file: FileId) -> anyhow::Result<()>;
+    /// Tells the sender which files are wanted, all but the `rejected` ones.
+    /// The protocols without the selection reject the files one by one instead
+    async fn issue_select(
+        &mut self,
+        ws: &mut WebSocket,
+        rejected: Vec<FileId>,
+    ) -> anyhow::Result<()> {
+        for file in rejected {
+            self.issue_reject(ws, file).await?;
+        }
+        Ok(())
+    }
+    /// Pauses the running download of the file so that its temporary file can
+    /// be moved and the download started again. Returns `false` when the
+    /// download is running and the protocol is not able to pause it
+    async fn pause_download(&mut self, ws: &mut WebSocket, file: &FileId) -> anyhow::Result<bool>;
     async fn issue_failure(
         &mut self,
         ws: &mut WebSocket,
@@ -72,16 +93,31 @@ pub enum DownloadInit {
 }
 #[async_trait::async_trait]
 pub trait Downloader {
+    /// The size of the temporary file blocks to calculate the checksums of,
+    /// for protocols able to resume from the last matching block
+    fn tmp_block_size(&self) -> Option<u64> {
+        None
+    }
+
+    /// Turns the received chunk starting at `offset` into the file data, for
+    /// protocols able to encrypt the file contents
+    fn decrypt(&self, _offset: u64, chunk: Vec<u8>) -> crate::Result<Vec<u8>> {
+        Ok(chunk)
+    }
+
     async fn init(
         &mut self,
         task: &super::FileXferTask,
         tmp_file: Option<TmpFileState>,
     ) -> crate::Result<DownloadInit>;
     async fn open(&mut self, tmp_location: &Hidden<PathBuf>) -> crate::Result<fs::File>;
+    /// Called with the data written to the temporary file at `offset`
+    async fn written(&mut self, _offset: u64, _data: &[u8]) {}
     async fn progress(&mut self, bytes: u64) -> crate::Result<()>;
     async fn validate<F, Fut>(
         &mut self,
         location: &Hidden<PathBuf>,
+        chunk_size: usize,
         progress_cb: Option<F>,
         event_granularity: Option<u64>,
     ) -> crate::Result<()>
@@ -7,18 +7,22 @@ mod v6;
 
 use std::{
     borrow::Borrow,
-    collections::HashMap,
+    collections::{HashMap, VecDeque},
     fs,
     future::Future,
-    io::{self, Write},
-    net::SocketAddr,
+    io::{self, Seek, Write},
+    net::{IpAddr, SocketAddr},
     ops::ControlFlow,
     path::{Path, PathBuf},
     sync::Arc,
+    time::Instant,
 };
 
 use anyhow::Context;
 use drop_auth::Nonce;
+use drop_config::{DropConfig, ExistingFilePolicy, FilenameSanitization};
+use drop_storage::CancelCause;
+use futures::future::BoxFuture;
 use handler::{Downloader, HandlerInit, HandlerLoop};
 use hyper::StatusCode;
 use slog::{debug, error, info, warn, Logger};
@@ -27,7 +31,7 @@ use tokio::{
         mpsc::{self, UnboundedReceiver},
         Mutex,
     },
-    task::{AbortHandle, JoinSet},
+    task::{AbortHandle, JoinHandle, JoinSet},
 };
 use tokio_util::sync::CancellationToken;
 use warp::{ws::Message, Filter};
@@ -35,15 +39,16 @@ use warp::{ws::Message, Filter};
 use self::socket::{WebSocket, WsStream};
 use super::{events::FileEventTx, IncomingFileEventTx};
 use crate::{
-    check,
+    check, expire,
     file::{self, FileSubPath, FileToRecv},
     protocol,
     quarantine::PathExt,
-    service::State,
+    service::{PostAction, State},
     tasks::AliveGuard,
     transfer::{IncomingTransfer, Transfer},
-    utils::Hidden,
+    utils::{Hidden, Peer},
     ws::{
+        self,
         server::handler::{MsgToSend, Request},
         Pinger,
     },
@@ -53,21 +58,34 @@ use crate::{
 const MAX_FILENAME_LENGTH: usize = 255;
 const MAX_FILE_SUFFIX_LEN: usize = 5; // Assume that the suffix will fit into 5 characters e.g.
                                       // `<filename>(999).<ext>`
-const REPORT_PROGRESS_THRESHOLD: u64 = 1024 * 64;
+
+// How many bytes of the chunks received while the transfer is paced may wait
+// in memory before the connection stops being read
+const MAX_HELD_CHUNK_BYTES: u64 = 8 * 1024 * 1024;
+// How often the free space is checked while receiving the streamed file
+const STREAM_SPACE_CHECK_INTERVAL: u64 = 4 * 1024 * 1024;
 
 pub enum ServerReq {
     Download { task: Box<FileXferTask> },
+    Redirect { task: Box<FileXferTask> },
     Start { file: FileId, offset: u64 },
     Reject { file: FileId },
+    // Selects the rest of the files of the transfer, rejecting the listed ones at once
+    Select { rejected: Vec<FileId> },
     Done { file: FileId },
     Fail { file: FileId, msg: String },
+    Pause,
     Close,
+    // Closes the connection, passing the reason to the peers able to receive it
+    RejectTransfer { reason: String },
 }
 
 pub struct FileXferTask {
     pub file: FileToRecv,
     pub xfer: Arc<IncomingTransfer>,
     pub base_dir: Hidden<PathBuf>,
+    // Replaces the sender's filename, keeping the directories
+    pub rename_to: Option<Hidden<String>>,
 }
 
 pub struct FileStreamCtx<'a> {
@@ -81,7 +99,20 @@ pub struct FileStreamCtx<'a> {
 
 pub struct TmpFileState {
     meta: fs::Metadata,
-    csum: [u8; 32],
+    // Not known when the leading blocks were not read again
+    csum: Option<[u8; 32]>,
+    // Checksums of the consecutive blocks, filled in only when requested
+    blocks: Vec<[u8; 32]>,
+}
+
+// The downloaded file once placed into the destination
+#[derive(Debug)]
+struct PlacedFile {
+    path: PathBuf,
+    // The name collision made the file land under a different name
+    renamed: bool,
+    // The post completion hook removed the file
+    deleted: bool,
 }
 
 struct StreamCtx<'a> {
@@ -116,9 +147,10 @@ pub(crate) fn spawn(
     state: Arc<State>,
     logger: Logger,
     stop: CancellationToken,
+    shutdown: CancellationToken,
     alive: AliveGuard,
-) -> crate::Result<()> {
-    let addr = SocketAddr::new(state.addr, drop_config::PORT);
+) -> crate::Result<JoinHandle<()>> {
+    let addr = SocketAddr::new(state.addr(), drop_config::PORT);
 
     let nonce_store = Arc::new(Mutex::new(HashMap::new()));
 
@@ -132,9 +164,14 @@ pub(crate) fn spawn(
         let remote = warp::filters::addr::remote()
             .map(move |peer: Option<SocketAddr>| peer.expect("Transport should use IP addresses"));
 
+        let metrics = state.metrics.clone();
         let ddos = remote
             .and_then(move |peer: SocketAddr| {
                 let check = rate_limiter.check_key(&peer.ip());
+                if check.is_err() {
+                    metrics.add_rate_limited();
+                }
+
                 async move {
                     match check {
                         Ok(_) => Ok(()),
@@ -193,6 +230,16 @@ pub(crate) fn spawn(
                         )
                         .await?;
 
+                        // The peers handle this response by retrying later
+                        if state.is_paused() {
+                            debug!(
+                                logger,
+                                "Refusing connection from {}, transfers are paused",
+                                Peer(peer)
+                            );
+                            return Err(warp::reject::custom(ToManyReqs));
+                        }
+
                         let reply = ws.on_upgrade(move |socket| async move {
                             info!(logger, "Client requested protocol version: {}", version);
                             websocket_start(
@@ -255,40 +302,41 @@ pub(crate) fn spawn(
         })
     };
 
-    let future =
-        match warp::serve(service).try_bind_with_graceful_shutdown(addr, stop.cancelled_owned()) {
-            Ok((socket, future)) => {
-                debug!(logger, "WS server is bound to: {socket}");
-                future
-            }
-            Err(err) => {
-                // Check if this is IO error about address already in use
-                if let Some(ioerr) = std::error::Error::source(&err)
-                    .and_then(|src| src.downcast_ref::<hyper::Error>())
-                    .and_then(std::error::Error::source)
-                    .and_then(|src| src.downcast_ref::<io::Error>())
-                {
-                    if ioerr.kind() == io::ErrorKind::AddrInUse {
-                        error!(
-                            logger,
-                            "Found that the address {addr} is already used, while trying to bind \
+    let future = match warp::serve(service)
+        .try_bind_with_graceful_shutdown(addr, shutdown.cancelled_owned())
+    {
+        Ok((socket, future)) => {
+            debug!(logger, "WS server is bound to: {socket}");
+            future
+        }
+        Err(err) => {
+            // Check if this is IO error about address already in use
+            if let Some(ioerr) = std::error::Error::source(&err)
+                .and_then(|src| src.downcast_ref::<hyper::Error>())
+                .and_then(std::error::Error::source)
+                .and_then(|src| src.downcast_ref::<io::Error>())
+            {
+                if ioerr.kind() == io::ErrorKind::AddrInUse {
+                    error!(
+                        logger,
+                        "Found that the address {addr} is already used, while trying to bind \
                              the WS server: {ioerr}",
-                        );
-                        return Err(Error::AddrInUse);
-                    }
+                    );
+                    return Err(Error::AddrInUse);
                 }
-
-                return Err(err.into());
             }
-        };
 
-    tokio::spawn(async move {
+            return Err(err.into());
+        }
+    };
+
+    let server = tokio::spawn(async move {
         let _guard = alive;
         future.await;
         debug!(logger, "WS server stopped");
     });
 
-    Ok(())
+    Ok(server)
 }
 
 #[allow(clippy::too_many_arguments)]
@@ -308,6 +356,7 @@ async fn websocket_start(
         stop: &stop,
         alive: &alive,
         refresh_trigger: &refresh_trigger,
+        version,
     };
 
     match version {
@@ -332,17 +381,17 @@ async fn websocket_start(
             )
             .await
         }
-        protocol::Version::V5 => {
+        protocol::Version::V5
+        | protocol::Version::V6
+        | protocol::Version::V7
+        | protocol::Version::V8
+        | protocol::Version::V9
+        | protocol::Version::V10
+        | protocol::Version::V11
+        | protocol::Version::V12 => {
             ctx.run(
                 socket,
-                v6::HandlerInit::new(peer.ip(), state, &logger, &alive),
-            )
-            .await
-        }
-        protocol::Version::V6 => {
-            ctx.run(
-                socket,
-                v6::HandlerInit::new(peer.ip(), state, &logger, &alive),
+                v6::HandlerInit::new(peer.ip(), state, &logger, &alive, version),
             )
             .await
         }
@@ -420,12 +469,16 @@ struct RunContext<'a> {
     refresh_trigger: &'a tokio::sync::watch::Receiver<()>,
     stop: &'a CancellationToken,
     alive: &'a AliveGuard,
+    version: protocol::Version,
 }
 
 impl RunContext<'_> {
     async fn run(self, socket: WsStream, mut handler: impl HandlerInit) {
-        let mut socket =
-            WebSocket::new(socket, handler.recv_timeout(), drop_config::WS_SEND_TIMEOUT);
+        let mut socket = WebSocket::new(
+            socket,
+            handler.recv_timeout(self.state.config.ws_recv_timeout),
+            self.state.config.ws_send_timeout,
+        );
 
         let recv_task = handler.recv_req(&mut socket);
 
@@ -464,6 +517,28 @@ impl RunContext<'_> {
             }
         };
 
+        if let Some(limit) = self.state.config.max_accept_bytes {
+            let size: u64 = xfer.files().values().map(|file| file.size()).sum();
+
+            if size > limit {
+                warn!(
+                    self.logger,
+                    "Rejecting transfer {} of {size} bytes, the limit is {limit} bytes",
+                    xfer.id()
+                );
+
+                let msg = Message::close_with(
+                    ws::SIZE_REJECTED_CLOSE_CODE,
+                    ws::SIZE_REJECTED_CLOSE_REASON,
+                );
+                if let Err(err) = socket.send(msg).await {
+                    warn!(self.logger, "Failed to send size rejection: {err}");
+                }
+
+                return;
+            }
+        }
+
         let xfer = Arc::new(xfer);
         let xfer_id = xfer.id();
 
@@ -507,6 +582,13 @@ impl RunContext<'_> {
             return;
         }
 
+        let info = handler.peer_info();
+        self.state.emit_event(crate::Event::PeerInfo {
+            transfer_id: xfer.id(),
+            device_name: info.device_name,
+            app_version: info.app_version,
+        });
+
         let mut ping = handler.pinger();
 
         let (send_tx, mut send_rx) = mpsc::channel(2);
@@ -521,8 +603,29 @@ impl RunContext<'_> {
             return;
         };
 
+        let mut ping_sent = None;
+
+        let conn_drop = self
+            .state
+            .transfer_manager
+            .incoming_conn_drop(xfer.id())
+            .await
+            .unwrap_or_default();
+        let rate_limit = self
+            .state
+            .transfer_manager
+            .incoming_rate_limit(xfer.id())
+            .await
+            .unwrap_or_default();
+        let connected = self.state.metrics.connected();
+        // Completes once the bytes received so far fit into the rate limit
+        let mut paced: Option<BoxFuture<'static, ()>> = None;
+        // The chunks received while paced, passed on once the pacing ends
+        let mut held = VecDeque::new();
+        let mut held_bytes = 0;
+
         let task = async {
-            loop {
+            'conn: loop {
                 tokio::select! {
                     biased;
 
@@ -532,13 +635,52 @@ impl RunContext<'_> {
                             break;
                         }
                     },
-                    // Message received
-                    recv = socket.recv() => {
+                    _ = async { paced.as_mut().expect("Checked by the condition").await }, if paced.is_some() => {
+                        paced = None;
+
+                        while paced.is_none() {
+                            let Some(msg) = held.pop_front() else {
+                                break;
+                            };
+
+                            let received = msg.as_bytes().len() as u64;
+                            held_bytes -= received;
+
+                            if self.on_recv(&mut socket, &mut handler, &xfer, msg).await?.is_break() {
+                                break 'conn;
+                            }
+
+                            let delay = rate_limit.reserve(received, self.state.clock.instant());
+                            if !delay.is_zero() {
+                                paced = Some(self.state.clock.sleep(delay));
+                            }
+                        }
+                    },
+                    // Message received. The other messages are handled right away
+                    // while the chunks wait for the pacing to end. Once too many
+                    // of them wait, holding off the next read slows down the sender
+                    recv = socket.recv(), if held_bytes < MAX_HELD_CHUNK_BYTES => {
                         let msg =  recv.context("Failed to receive WS message")?;
 
+                        if msg.is_pong() {
+                            ws::utils::report_rtt(&self.state, xfer.id(), &mut ping_sent).await;
+                        }
+
+                        if msg.is_binary() && paced.is_some() {
+                            held_bytes += msg.as_bytes().len() as u64;
+                            held.push_back(msg);
+                            continue;
+                        }
+
+                        let received = if msg.is_binary() { msg.as_bytes().len() as u64 } else { 0 };
                         if self.on_recv(&mut socket, &mut handler, &xfer, msg).await?.is_break() {
                             break;
                         }
+
+                        let delay = rate_limit.reserve(received, self.state.clock.instant());
+                        if !delay.is_zero() {
+                            paced = Some(self.state.clock.sleep(delay));
+                        }
                     },
                     // Message to send down the wire
                     msg = send_rx.recv() => {
@@ -547,13 +689,19 @@ impl RunContext<'_> {
                     },
                     _ = ping.tick() => {
                         socket.send(Message::ping(Vec::new())).await.context("Failed to send PING message")?;
+                        ping_sent = Some(Instant::now());
                     }
                 };
             }
             anyhow::Ok(())
         };
 
-        let result = task.await;
+        // Aborting the loop also covers the connection stuck on sending
+        let result = tokio::select! {
+            result = task => result,
+            _ = conn_drop.notified() => Err(anyhow::anyhow!("Connection dropped on request")),
+        };
+        drop(connected);
         info!(self.logger, "Connection loop finished");
 
         jobs.shutdown().await;
@@ -579,7 +727,11 @@ impl RunContext<'_> {
         let is_new = self
             .state
             .transfer_manager
-            .register_incoming(xfer.clone(), req_send)
+            .register_incoming(
+                xfer.clone(),
+                req_send,
+                self.state.config.max_transfers_per_peer,
+            )
             .await?;
 
         if let Some(xfer_tx) = is_new {
@@ -593,6 +745,17 @@ impl RunContext<'_> {
                 self.alive.clone(),
                 self.stop.clone(),
             );
+
+            if let Some(ttl) = self.state.config.incoming_offer_ttl {
+                expire::spawn(
+                    self.state.clone(),
+                    xfer.clone(),
+                    ttl,
+                    self.logger.clone(),
+                    self.alive.clone(),
+                    self.stop.clone(),
+                );
+            }
         }
 
         Ok(())
@@ -616,7 +779,10 @@ impl RunContext<'_> {
             handler.on_close().await;
 
             if let Some(state) = self.state.transfer_manager.incoming_remove(xfer.id()).await {
-                state.xfer_events.cancel(true).await
+                state
+                    .xfer_events
+                    .cancel(true, CancelCause::User, state.stats())
+                    .await
             }
 
             return Ok(ControlFlow::Break(()));
@@ -653,14 +819,81 @@ impl RunContext<'_> {
 
                 handler.start_download(ctx).await?
             }
+            ServerReq::Redirect { task } => {
+                // The storage is updated only once the bytes are written into the new
+                // directory
+                if handler.pause_download(socket, task.file.id()).await? {
+                    let tmp_bases = self
+                        .state
+                        .storage
+                        .fetch_base_dirs_for_file(xfer.id(), task.file.id().as_ref())
+                        .await;
+
+                    for base in tmp_bases {
+                        let base = PathBuf::from(base);
+                        if base != *task.base_dir {
+                            move_temp_file(
+                                self.logger,
+                                xfer.id(),
+                                task.file.id(),
+                                &base,
+                                &task.base_dir,
+                            );
+                        }
+                    }
+
+                    if let Err(err) = self
+                        .state
+                        .transfer_manager
+                        .incoming_redirected(xfer.id(), task.file.id(), &task.base_dir)
+                        .await
+                    {
+                        warn!(self.logger, "Failed to redirect the download: {err}");
+                    }
+                } else {
+                    warn!(
+                        self.logger,
+                        "Cannot pause the download of {}, it continues in the previous directory",
+                        task.file.id()
+                    );
+                    return Ok(ControlFlow::Continue(()));
+                }
+
+                let ctx = FileStreamCtx {
+                    jobs,
+                    guard: self.alive.clone(),
+                    state: self.state.clone(),
+                    logger: self.logger.clone(),
+                    req_send: req_send.clone(),
+                    task: *task,
+                };
+
+                handler.start_download(ctx).await?
+            }
             ServerReq::Start { file, offset } => handler.issue_start(socket, file, offset).await?,
             ServerReq::Reject { file } => handler.issue_reject(socket, file).await?,
+            ServerReq::Select { rejected } => handler.issue_select(socket, rejected).await?,
             ServerReq::Done { file } => handler.issue_done(socket, file).await?,
             ServerReq::Fail { file, msg } => handler.issue_failure(socket, file, msg).await?,
 
-            ServerReq::Close => {
+            ServerReq::Pause => {
+                // Breaking the connection pauses the downloads on both sides
+                anyhow::bail!("Transfers paused");
+            }
+            req @ (ServerReq::Close | ServerReq::RejectTransfer { .. }) => {
                 debug!(self.logger, "Stoppping server connection gracefuly");
-                socket.send(Message::close()).await?;
+
+                let msg = match req {
+                    ServerReq::RejectTransfer { reason } if self.version.has_rejection_reason() => {
+                        Message::close_with(
+                            ws::TRANSFER_REJECTED_CLOSE_CODE,
+                            ws::close_reason(reason),
+                        )
+                    }
+                    _ => Message::close(),
+                };
+
+                socket.send(msg).await?;
                 handler.on_close().await;
                 socket.drain().await.context("Failed to drain the socket")?;
 
@@ -679,9 +912,15 @@ impl FileXferTask {
             file,
             xfer,
             base_dir: Hidden(base_dir),
+            rename_to: None,
         }
     }
 
+    pub fn with_rename(mut self, rename_to: Option<String>) -> Self {
+        self.rename_to = rename_to.map(Hidden);
+        self
+    }
+
     async fn stream_file(
         &mut self,
         StreamCtx {
@@ -691,11 +930,11 @@ impl FileXferTask {
             stream,
             events,
         }: StreamCtx<'_>,
-        downloader: &mut impl Downloader,
+        downloader: &mut (impl Downloader + Send),
         offset: u64,
         emit_checksum_events: bool,
         checksum_events_granularity: u64,
-    ) -> crate::Result<PathBuf> {
+    ) -> crate::Result<PlacedFile> {
         let mut out_file = match downloader.open(tmp_loc).await {
             Ok(out_file) => out_file,
             Err(err) => {
@@ -708,27 +947,113 @@ impl FileXferTask {
             }
         };
 
+        if state.config.preallocate_downloads {
+            match crate::utils::preallocate(&out_file, self.file.size()) {
+                Ok(()) => (),
+                Err(err) if crate::utils::is_storage_full(&err) => {
+                    error!(logger, "Not enough space to preallocate {tmp_loc:?}");
+                    return Err(crate::Error::InsufficientStorage);
+                }
+                Err(err) => {
+                    warn!(
+                        logger,
+                        "Failed to preallocate {tmp_loc:?}, writing incrementally: {err}"
+                    );
+                }
+            }
+        }
+
+        let mut short_of_data = false;
+        let streamed = self.file.is_streamed();
+        let has_blocks = downloader.tmp_block_size().is_some();
+
+        // The size of the streamed files is not accounted for when accepting the
+        // transfer. Together they may take up what the limit leaves after the
+        // other files
+        let stream_limit = match state.config.max_accept_bytes.filter(|_| streamed) {
+            Some(limit) => {
+                let known: u64 = self.xfer.files().values().map(|file| file.size()).sum();
+                let budget = state
+                    .transfer_manager
+                    .incoming_stream_budget(self.xfer.id())
+                    .await
+                    .ok_or(crate::Error::Canceled)?;
+
+                Some((limit.saturating_sub(known), budget))
+            }
+            None => None,
+        };
+        let stream_dir = tmp_loc.parent().unwrap_or(&self.base_dir).to_path_buf();
+
         let consume_file_chunks = async {
             let mut bytes_received = offset;
             let mut last_progress = bytes_received;
+            let mut last_space_check = None;
+            let progress_granularity = state.config.progress_events_granularity;
 
             // Announce initial state of the transfer
             downloader.progress(bytes_received).await?;
             events.progress(bytes_received).await;
 
-            while bytes_received < self.file.size() {
+            while streamed || bytes_received < self.file.size() {
                 let chunk = stream.recv().await.ok_or(crate::Error::Canceled)?;
 
+                // The empty chunk marks the end of the streamed file
+                if streamed && chunk.is_empty() {
+                    break;
+                }
+
+                let chunk = downloader.decrypt(bytes_received, chunk)?;
                 let chunk_size = chunk.len();
-                if chunk_size as u64 + bytes_received > self.file.size() {
+                if streamed {
+                    let total = bytes_received + chunk_size as u64;
+
+                    if let Some((limit, budget)) = &stream_limit {
+                        if !budget.take(self.file.id(), total, *limit) {
+                            warn!(
+                                logger,
+                                "Streamed file {} exceeds the transfer size limit",
+                                self.file.id()
+                            );
+                            return Err(crate::Error::TransferLimitsExceeded);
+                        }
+                    }
+
+                    // Checked as the bytes arrive since the size is not known up
+                    // front. The write itself reports the full storage in between
+                    let space_checked = matches!(
+                        last_space_check,
+                        Some(last) if total < last + STREAM_SPACE_CHECK_INTERVAL
+                    );
+                    if !space_checked {
+                        let available = crate::utils::available_space(&stream_dir);
+                        if available.is_some_and(|available| available < chunk_size as u64) {
+                            error!(
+                                logger,
+                                "Not enough space to keep receiving the streamed file {}",
+                                self.file.id()
+                            );
+                            return Err(crate::Error::InsufficientStorage);
+                        }
+
+                        last_space_check = Some(total);
+                    }
+                } else if chunk_size as u64 + bytes_received > self.file.size() {
                     return Err(crate::Error::MismatchedSize);
                 }
 
-                out_file.write_all(&chunk)?;
+                out_file.write_all(&chunk).map_err(|err| {
+                    if crate::utils::is_storage_full(&err) {
+                        crate::Error::InsufficientStorage
+                    } else {
+                        crate::Error::Io(err)
+                    }
+                })?;
+                downloader.written(bytes_received, &chunk).await;
 
                 bytes_received += chunk_size as u64;
 
-                if last_progress + REPORT_PROGRESS_THRESHOLD <= bytes_received {
+                if last_progress + progress_granularity <= bytes_received {
                     // send progress to the caller
                     downloader.progress(bytes_received).await?;
                     events.progress(bytes_received).await;
@@ -740,12 +1065,27 @@ impl FileXferTask {
             // Close the file handle
             drop(out_file);
 
-            if bytes_received > self.file.size() {
+            // The size of the streamed file is known only once it ends
+            let size = if streamed {
+                bytes_received
+            } else {
+                self.file.size()
+            };
+
+            if bytes_received > size {
                 return Err(crate::Error::UnexpectedData);
             }
 
+            // Never place a short file into the destination
+            if bytes_received < size {
+                warn!(logger, "Received {bytes_received} out of {size} bytes");
+
+                short_of_data = true;
+                return Err(crate::Error::MismatchedSize);
+            }
+
             if emit_checksum_events {
-                events.finalize_checksum_start(self.file.size()).await;
+                events.finalize_checksum_start(size).await;
                 let progress_cb = {
                     move |progress_bytes: u64| async move {
                         events.finalize_checksum_progress(progress_bytes).await;
@@ -755,6 +1095,7 @@ impl FileXferTask {
                 downloader
                     .validate(
                         tmp_loc,
+                        state.config.checksum_chunk_size,
                         Some(progress_cb),
                         Some(checksum_events_granularity),
                     )
@@ -765,19 +1106,38 @@ impl FileXferTask {
                 downloader
                     .validate::<_, futures::future::Ready<()>>(
                         tmp_loc,
+                        state.config.checksum_chunk_size,
                         None::<fn(u64) -> futures::future::Ready<()>>,
                         None,
                     )
                     .await?;
             }
 
+            if streamed {
+                state
+                    .storage
+                    .update_path_size(self.xfer.id(), self.file.id().as_ref(), size as _)
+                    .await;
+            }
+
             Ok(())
         };
 
         match consume_file_chunks.await {
-            Err(err @ crate::Error::Canceled) => return Err(err), // Do not remove temp file
-            // when cancelled. We might
-            // resume
+            // Do not remove temp file when cancelled or out of space. We might resume
+            Err(err @ (crate::Error::Canceled | crate::Error::InsufficientStorage)) => {
+                if has_blocks {
+                    self.record_tmp_file(state, tmp_loc).await;
+                }
+                return Err(err);
+            }
+            // The received part is still valid, keep it for the download to be resumed
+            Err(err) if short_of_data => {
+                if has_blocks {
+                    self.record_tmp_file(state, tmp_loc).await;
+                }
+                return Err(err);
+            }
             Err(err) => {
                 if let Err(ioerr) = fs::remove_file(&tmp_loc.0) {
                     error!(
@@ -793,15 +1153,18 @@ impl FileXferTask {
         };
 
         let dst = match self.place_file_into_dest(state, logger, tmp_loc).await {
-            Ok(dst) => {
+            Ok(placed) => {
                 info!(
                     logger,
-                    "Sucesfully placed file for id {} into destination: {tmp_loc:?} -> {:?}",
+                    "Sucesfully placed file for id {} into destination: {tmp_loc:?} -> {:?}, \
+                     renamed: {}, deleted: {}",
                     self.file.id(),
-                    Hidden(&dst)
+                    Hidden(&placed.path),
+                    placed.renamed,
+                    placed.deleted,
                 );
 
-                dst
+                placed
             }
             Err(err) => {
                 error!(
@@ -816,20 +1179,54 @@ impl FileXferTask {
         Ok(dst)
     }
 
-    async fn prepare_abs_path(&self, state: &State) -> crate::Result<PathBuf> {
+    /// Returns the destination path of the file along with the information
+    /// whether its top directory got renamed because of a name collision
+    async fn prepare_abs_path(&self, state: &State) -> crate::Result<(PathBuf, bool)> {
+        let storage = &state.storage;
+        let config = &state.config;
         let mut lock = state.transfer_manager.incoming.lock().await;
 
         let state = lock
             .get_mut(&self.xfer.id())
             .ok_or(crate::Error::Canceled)?;
 
+        let mut subpath = self.file.subpath().clone();
+        if config.flatten_incoming {
+            // Drop the sender's directory structure, the name collisions are resolved when
+            // moving the file into place
+            subpath = FileSubPath::from(subpath.name());
+        }
+        if let Some(rename_to) = &self.rename_to {
+            if let Some(name) = subpath.iter_mut().last() {
+                name.clone_from(rename_to);
+            }
+        }
+        if config.truncate_long_filenames {
+            let count = subpath.iter().count();
+            for (i, name) in subpath.iter_mut().enumerate() {
+                let max_len = MAX_FILENAME_LENGTH - MAX_FILE_SUFFIX_LEN;
+
+                *name = if i + 1 < count {
+                    crate::utils::truncate_dirname(name, max_len)
+                } else {
+                    crate::utils::truncate_filename(name, max_len)
+                };
+            }
+        }
+
         let mapping = state
-            .dir_mappings
-            .compose_final_path(&self.base_dir, self.file.subpath())?;
+            .compose_final_path(storage, &self.base_dir, &subpath)
+            .await?;
 
         drop(lock);
 
-        Ok(self.base_dir.join(mapping))
+        let requested: PathBuf = subpath
+            .iter()
+            .map(crate::utils::normalize_filename)
+            .collect();
+        let renamed = mapping != requested;
+
+        Ok((self.base_dir.join(mapping), renamed))
     }
 
     async fn place_file_into_dest(
@@ -837,17 +1234,169 @@ impl FileXferTask {
         state: &State,
         logger: &Logger,
         tmp_location: &Hidden<PathBuf>,
-    ) -> crate::Result<PathBuf> {
-        let abs_path = self.prepare_abs_path(state).await?;
+    ) -> crate::Result<PlacedFile> {
+        let (mut abs_path, mut renamed) = self.prepare_abs_path(state).await?;
+        if let Some(resolver) = &state.dest_resolver {
+            abs_path = self
+                .base_dir
+                .join(resolver(&self.xfer, &self.file, &abs_path));
+            // The path chosen by the resolver is what was requested
+            renamed = false;
+            debug!(
+                logger,
+                "Destination path resolved to: {:?}",
+                Hidden(&abs_path)
+            );
+        }
+
         if let Some(parent) = abs_path.parent() {
             std::fs::create_dir_all(parent)?;
         }
 
-        let dst = move_tmp_to_dst(tmp_location, Hidden(&abs_path), logger)?;
+        let origin = quarantine_origin(&state.config, self.xfer.peer());
+        let dst = move_tmp_to_dst(
+            tmp_location,
+            Hidden(&abs_path),
+            state.config.existing_file_policy,
+            &origin,
+            logger,
+        )?;
+        renamed |= dst != abs_path;
+
+        let (dst, deleted) = match state.post_completion.clone() {
+            Some(hook) => {
+                let xfer = self.xfer.clone();
+                let file = self.file.clone();
+                let path = dst.clone();
+
+                // The callback is user code, keep it off the runtime workers
+                let action =
+                    match tokio::task::spawn_blocking(move || hook(&xfer, &file, &path)).await {
+                        Ok(action) => action,
+                        Err(err) => {
+                            warn!(logger, "Post completion hook failed: {err}");
+                            PostAction::Keep
+                        }
+                    };
+                self.apply_post_action(action, dst, logger)
+            }
+            None => (dst, false),
+        };
+
+        Ok(PlacedFile {
+            path: dst,
+            renamed,
+            deleted,
+        })
+    }
 
-        Ok(dst)
+    /// Applies the action requested by the post completion hook and returns
+    /// the path of the file afterwards along with whether the file got
+    /// removed. Failures are logged and leave the file where it was
+    fn apply_post_action(
+        &self,
+        action: PostAction,
+        dst: PathBuf,
+        logger: &Logger,
+    ) -> (PathBuf, bool) {
+        match action {
+            PostAction::Keep => (dst, false),
+            PostAction::Delete => {
+                debug!(logger, "Removing downloaded file {}", self.file.id());
+
+                match fs::remove_file(&dst) {
+                    Ok(()) => (dst, true),
+                    Err(err) => {
+                        warn!(logger, "Failed to remove downloaded file: {err}");
+                        (dst, false)
+                    }
+                }
+            }
+            PostAction::Relocate(path) => {
+                let path = self.base_dir.join(path);
+                debug!(
+                    logger,
+                    "Relocating downloaded file {} to {:?}",
+                    self.file.id(),
+                    Hidden(&path)
+                );
+
+                let res = if path.exists() {
+                    Err(io::Error::from(io::ErrorKind::AlreadyExists))
+                } else {
+                    path.parent()
+                        .map_or(Ok(()), fs::create_dir_all)
+                        .and_then(|_| drop_core::fs::move_file(&dst, &path))
+                };
+
+                match res {
+                    Ok(()) => (path, false),
+                    Err(err) => {
+                        warn!(logger, "Failed to relocate downloaded file: {err}");
+                        (dst, false)
+                    }
+                }
+            }
+        }
     }
 
+    // The block checksums recorded for the temporary file as its download
+    // left it. Once the file changes they are dropped and the whole file is
+    // hashed again
+    async fn known_blocks(
+        &self,
+        state: &State,
+        tmp_location: &Hidden<PathBuf>,
+        block_size: u64,
+    ) -> Vec<[u8; 32]> {
+        let (xfer_id, file_id) = (self.xfer.id(), self.file.id().as_ref());
+
+        let blocks = match fs::metadata(&tmp_location.0) {
+            Ok(meta) => {
+                state
+                    .storage
+                    .fetch_block_checksums(
+                        xfer_id,
+                        file_id,
+                        block_size,
+                        meta.len(),
+                        mtime_millis(&meta),
+                    )
+                    .await
+            }
+            Err(_) => Vec::new(),
+        };
+
+        if blocks.is_empty() {
+            state
+                .storage
+                .remove_block_checksums(xfer_id, file_id, 0)
+                .await;
+        }
+
+        blocks
+            .into_iter()
+            .map_while(|csum| csum.try_into().ok())
+            .collect()
+    }
+
+    // Records the temporary file as it's left for the resumed download, which
+    // trusts the block checksums only as long as the file stays the same
+    async fn record_tmp_file(&self, state: &State, tmp_loc: &Hidden<PathBuf>) {
+        if let Ok(meta) = fs::metadata(&tmp_loc.0) {
+            state
+                .storage
+                .set_block_checksums_file_state(
+                    self.xfer.id(),
+                    self.file.id().as_ref(),
+                    meta.len(),
+                    mtime_millis(&meta),
+                )
+                .await;
+        }
+    }
+
+    #[allow(clippy::too_many_arguments)]
     async fn handle_tmp_file(
         &mut self,
         logger: &Logger,
@@ -855,6 +1404,9 @@ impl FileXferTask {
         tmp_location: &Hidden<PathBuf>,
         emit_checksum_events: bool,
         checksum_events_granularity: u64,
+        checksum_chunk_size: usize,
+        block_size: Option<u64>,
+        known_blocks: Vec<[u8; 32]>,
     ) -> Option<TmpFileState> {
         // TODO: we load the file's metadata to check if we should emit checksum events
         // based on size threshold. However TmpFileState::load also does the
@@ -878,6 +1430,9 @@ impl FileXferTask {
         // Check if we can resume the temporary file
         let tmp_file_state = match TmpFileState::load(
             &tmp_location.0,
+            checksum_chunk_size,
+            block_size,
+            known_blocks,
             cb,
             Some(checksum_events_granularity),
         )
@@ -887,7 +1442,7 @@ impl FileXferTask {
                 debug!(
                     logger,
                     "Found temporary file: {:?}, of size: {}",
-                    tmp_location.0,
+                    tmp_location,
                     tmp_file_state.meta.len()
                 );
                 Some(tmp_file_state)
@@ -910,14 +1465,14 @@ impl FileXferTask {
         mut self,
         state: Arc<State>,
         events: Arc<FileEventTx<IncomingTransfer>>,
-        mut downloader: impl Downloader,
+        mut downloader: impl Downloader + Send,
         mut stream: UnboundedReceiver<Vec<u8>>,
         req_send: mpsc::UnboundedSender<ServerReq>,
         logger: Logger,
         guard: AliveGuard,
     ) {
         let task = async {
-            validate_subpath_for_download(self.file.subpath())?;
+            validate_subpath_for_download(self.file.subpath(), &state.config)?;
 
             let emit_checksum_events = {
                 if let Some(threshold) = state.config.checksum_events_size_threshold {
@@ -935,6 +1490,12 @@ impl FileXferTask {
                     .join(temp_file_name(self.xfer.id(), self.file.id())),
             );
 
+            let block_size = downloader.tmp_block_size();
+            let known_blocks = match block_size {
+                Some(block_size) => self.known_blocks(&state, &tmp_location, block_size).await,
+                None => Vec::new(),
+            };
+
             let tmp_file_state = self
                 .handle_tmp_file(
                     &logger,
@@ -942,6 +1503,9 @@ impl FileXferTask {
                     &tmp_location,
                     emit_checksum_events,
                     checksum_events_granularity,
+                    state.config.checksum_chunk_size,
+                    block_size,
+                    known_blocks,
                 )
                 .await;
 
@@ -960,6 +1524,7 @@ impl FileXferTask {
                         return Err(Error::Canceled);
                     }
 
+                    ws::utils::report_resent_bytes(&state, self.xfer.id(), &events, offset).await;
                     events.start(self.base_dir.to_string_lossy(), offset).await;
 
                     self.stream_file(
@@ -993,7 +1558,7 @@ impl FileXferTask {
                 Err(crate::Error::Canceled) => {
                     info!(logger, "File {} stopped", self.file.id())
                 }
-                Ok(dst_location) => {
+                Ok(placed) => {
                     info!(logger, "File {} downloaded succesfully", self.file.id());
 
                     if let Err(err) = state
@@ -1010,7 +1575,9 @@ impl FileXferTask {
                         warn!(logger, "Failed to send DONE message: {}", e);
                     };
 
-                    events.success(dst_location).await;
+                    events
+                        .success(placed.path, placed.renamed, placed.deleted)
+                        .await;
                 }
                 Err(err) => {
                     info!(
@@ -1046,9 +1613,14 @@ impl FileXferTask {
 }
 
 impl TmpFileState {
-    // Blocking operation
+    // Blocking operation. The checksums of the blocks written in full during
+    // the previous attempts are taken from `known_blocks`, only the rest of
+    // the file is read
     async fn load<F, Fut>(
         path: &Path,
+        chunk_size: usize,
+        block_size: Option<u64>,
+        mut known_blocks: Vec<[u8; 32]>,
         progress_cb: Option<F>,
         event_granularity: Option<u64>,
     ) -> io::Result<Self>
@@ -1056,15 +1628,49 @@ impl TmpFileState {
         F: Fn(u64) -> Fut + Sync + Send,
         Fut: Future<Output = ()>,
     {
-        let file = fs::File::open(path)?;
+        let mut file = fs::File::open(path)?;
 
         let meta = file.metadata()?;
 
-        let csum = file::checksum(file, progress_cb, event_granularity).await?;
-        Ok(TmpFileState { meta, csum })
+        let (csum, blocks) = match block_size {
+            Some(block_size) => {
+                known_blocks.truncate((meta.len() / block_size) as usize);
+
+                let skipped = known_blocks.len() as u64 * block_size;
+                file.seek(io::SeekFrom::Start(skipped))?;
+
+                let (csum, rest) = file::checksum_with_blocks(
+                    file,
+                    chunk_size,
+                    block_size,
+                    progress_cb.map(|cb| move |bytes| cb(skipped + bytes)),
+                    event_granularity,
+                )
+                .await?;
+
+                known_blocks.extend(rest);
+                ((skipped == 0).then_some(csum), known_blocks)
+            }
+            None => (
+                Some(file::checksum(file, chunk_size, progress_cb, event_granularity).await?),
+                Vec::new(),
+            ),
+        };
+
+        Ok(TmpFileState { meta, csum, blocks })
     }
 }
 
+// The modification time as stored along with the block checksums
+fn mtime_millis(meta: &fs::Metadata) -> i64 {
+    let mtime = meta
+        .modified()
+        .ok()
+        .and_then(|mtime| mtime.duration_since(std::time::UNIX_EPOCH).ok())
+        .unwrap_or_default();
+    mtime.as_millis() as _
+}
+
 fn validate_tmp_location_path(tmp_location: &Hidden<PathBuf>) -> crate::Result<()> {
     let char_count = tmp_location
         .file_name()
@@ -1078,11 +1684,42 @@ fn validate_tmp_location_path(tmp_location: &Hidden<PathBuf>) -> crate::Result<(
     Ok(())
 }
 
+// The source of the received file recorded in the quarantine metadata
+fn quarantine_origin(config: &DropConfig, peer: IpAddr) -> String {
+    match &config.quarantine_origin {
+        Some(origin) => origin.replace("{peer}", &peer.to_string()),
+        None => peer.to_string(),
+    }
+}
+
 fn move_tmp_to_dst(
     tmp_location: &Hidden<PathBuf>,
     absolute_path: Hidden<&Path>,
+    policy: ExistingFilePolicy,
+    origin: &str,
     logger: &Logger,
 ) -> crate::Result<PathBuf> {
+    if policy == ExistingFilePolicy::Skip && absolute_path.0.is_file() {
+        debug!(
+            logger,
+            "Destination file already exists, skipping: {:?}", absolute_path
+        );
+
+        fs::remove_file(&tmp_location.0)?;
+        return Ok(absolute_path.0.to_path_buf());
+    }
+
+    if policy == ExistingFilePolicy::Overwrite && !absolute_path.0.is_dir() {
+        // The rename replaces the existing file in one step so that it stays
+        // intact when the move fails
+        drop_core::fs::replace_file(&tmp_location.0, absolute_path.0)?;
+
+        if let Err(err) = absolute_path.0.quarantine(origin) {
+            error!(logger, "Failed to quarantine downloaded file: {err}");
+        }
+        return Ok(absolute_path.0.to_path_buf());
+    }
+
     let mut opts = fs::OpenOptions::new();
     opts.write(true).create_new(true);
 
@@ -1112,7 +1749,7 @@ fn move_tmp_to_dst(
         }
     };
 
-    if let Err(err) = fs::rename(&tmp_location.0, &dst_location) {
+    if let Err(err) = drop_core::fs::move_file(&tmp_location.0, &dst_location) {
         if let Err(err) = fs::remove_file(&dst_location) {
             warn!(
                 logger,
@@ -1122,7 +1759,7 @@ fn move_tmp_to_dst(
         return Err(err.into());
     }
 
-    if let Err(err) = dst_location.quarantine() {
+    if let Err(err) = dst_location.quarantine(origin) {
         error!(logger, "Failed to quarantine downloaded file: {err}");
     }
 
@@ -1165,6 +1802,42 @@ impl<'a> FileStreamCtx<'a> {
     }
 }
 
+/// Aborts the download task and waits until it's dropped, closing the
+/// temporary file
+async fn abort_and_wait(job: &AbortHandle) {
+    job.abort();
+
+    while !job.is_finished() {
+        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+    }
+}
+
+/// Moves the temporary file of the file being downloaded into another base
+/// directory
+pub fn move_temp_file(
+    logger: &Logger,
+    transfer_id: uuid::Uuid,
+    file_id: &FileId,
+    from: &Path,
+    to: &Path,
+) {
+    let name = temp_file_name(transfer_id, file_id);
+    let src = Hidden(from.join(&name));
+    let dst = Hidden(to.join(&name));
+
+    if !src.exists() {
+        return;
+    }
+
+    debug!(logger, "Moving temporary file: {src:?} -> {dst:?}");
+    if let Err(err) = fs::create_dir_all(to).and_then(|_| drop_core::fs::move_file(&src, &dst)) {
+        error!(
+            logger,
+            "Failed to move temporary file, id: {file_id}, path {src:?}, {err:?}",
+        );
+    }
+}
+
 pub fn remove_temp_files<P, I>(
     logger: &Logger,
     transfer_id: uuid::Uuid,
@@ -1192,16 +1865,31 @@ pub fn remove_temp_files<P, I>(
     }
 }
 
-fn temp_file_name(transfer_id: uuid::Uuid, file_id: &FileId) -> String {
+pub(crate) fn temp_file_name(transfer_id: uuid::Uuid, file_id: &FileId) -> String {
     format!("{}-{file_id}.dropdl-part", transfer_id.as_simple(),)
 }
 
+/// Extract the transfer ID out of the temporary file name. Returns `None` for
+/// files not created by the downloader
+pub(crate) fn temp_file_transfer_id(name: &str) -> Option<uuid::Uuid> {
+    let (transfer_id, _) = name.strip_suffix(".dropdl-part")?.split_once('-')?;
+    transfer_id.parse().ok()
+}
+
 /// Check file and dir names are shorter then MAX and contain illegal values
-fn validate_subpath_for_download(subpath: &FileSubPath) -> crate::Result<()> {
-    const DISALLOWED: &[&str] = &[".."];
+fn validate_subpath_for_download(subpath: &FileSubPath, config: &DropConfig) -> crate::Result<()> {
+    const DISALLOWED: &[&str] = &["", ".", ".."];
+
+    // Every component is a directory to create and a level of the path mapping
+    if subpath.iter().count() > config.max_path_components {
+        return Err(Error::BadPath(
+            "File subpath contains too many components".into(),
+        ));
+    }
 
     for name in subpath.iter() {
-        if name.len() + MAX_FILE_SUFFIX_LEN > MAX_FILENAME_LENGTH {
+        if !config.truncate_long_filenames && name.len() + MAX_FILE_SUFFIX_LEN > MAX_FILENAME_LENGTH
+        {
             return Err(Error::FilenameTooLong);
         }
 
@@ -1210,23 +1898,62 @@ fn validate_subpath_for_download(subpath: &FileSubPath) -> crate::Result<()> {
                 "File subpath contains disallowed element".into(),
             ));
         }
+
+        if is_rooted_component(name) {
+            return Err(Error::BadPath(
+                "File subpath contains absolute element".into(),
+            ));
+        }
+
+        if config.filename_sanitization == FilenameSanitization::Reject
+            && crate::utils::has_illegal_filename_chars(name)
+        {
+            return Err(Error::BadPath(
+                "File subpath contains illegal characters".into(),
+            ));
+        }
     }
 
     Ok(())
 }
 
+/// Checks the filename chosen by the user in place of the sender's one the
+/// same way as the subpaths are checked
+pub(crate) fn validate_rename_for_download(name: &str, config: &DropConfig) -> crate::Result<()> {
+    let subpath = FileSubPath::from(name);
+    if subpath.len() != 1 {
+        return Err(Error::BadPath(
+            "Filename must not contain directories".into(),
+        ));
+    }
+
+    validate_subpath_for_download(&subpath, config)
+}
+
+// Checks if joining the component would discard the base directory on any of
+// the platforms, i.e. it starts with a root, a drive letter or an UNC prefix
+fn is_rooted_component(name: &str) -> bool {
+    let is_drive = matches!(name.as_bytes(), [letter, b':', ..] if letter.is_ascii_alphabetic());
+
+    is_drive || name.starts_with(['/', '\\']) || Path::new(name).has_root()
+}
+
 #[cfg(test)]
-mod tests {
-    use crate::file::FileSubPath;
+pub(crate) mod tests {
+    use drop_config::{DropConfig, ExistingFilePolicy, FilenameSanitization};
+
+    use crate::{file::FileSubPath, utils::Hidden};
 
     #[test]
     fn validate_subpath() {
+        let config = DropConfig::default();
+
         let sp = FileSubPath::from_path("abc/dfg/hjk.txt").unwrap();
-        assert!(super::validate_subpath_for_download(&sp).is_ok());
+        assert!(super::validate_subpath_for_download(&sp, &config).is_ok());
 
         let sp = FileSubPath::from_path("abc/../hjk.txt").unwrap();
         assert!(matches!(
-            super::validate_subpath_for_download(&sp),
+            super::validate_subpath_for_download(&sp, &config),
             Err(crate::Error::BadPath(..))
         ));
 
@@ -1235,8 +1962,771 @@ mod tests {
         path.push_str("/hjk.txt");
         let sp = FileSubPath::from_path(&path).unwrap();
         assert!(matches!(
-            super::validate_subpath_for_download(&sp),
+            super::validate_subpath_for_download(&sp, &config),
             Err(crate::Error::FilenameTooLong)
         ));
+        let truncating = DropConfig {
+            truncate_long_filenames: true,
+            ..DropConfig::default()
+        };
+        assert!(super::validate_subpath_for_download(&sp, &truncating).is_ok());
+
+        let sp = FileSubPath::from("abc/d\x07f:g/hjk\u{fffd}.txt");
+        assert!(super::validate_subpath_for_download(&sp, &config).is_ok());
+        let rejecting = DropConfig {
+            filename_sanitization: FilenameSanitization::Reject,
+            ..DropConfig::default()
+        };
+        assert!(matches!(
+            super::validate_subpath_for_download(&sp, &rejecting),
+            Err(crate::Error::BadPath(..))
+        ));
+    }
+
+    #[test]
+    fn validate_absolute_subpath() {
+        let config = DropConfig::default();
+
+        for path in [
+            "/etc/passwd",
+            "abc//passwd",
+            "./abc",
+            "C:\\Windows\\System32",
+            "abc/C:\\Windows",
+            "c:abc",
+            "\\\\server\\share\\file",
+            "abc/\\file",
+        ] {
+            let sp = FileSubPath::from(path);
+            assert!(
+                matches!(
+                    super::validate_subpath_for_download(&sp, &config),
+                    Err(crate::Error::BadPath(..))
+                ),
+                "{path:?} should be rejected"
+            );
+        }
+
+        // Only the drive letter prefix is special, the other colons and backslashes are sanitized
+        let sp = FileSubPath::from("abc/file:1.txt/a\\b");
+        assert!(super::validate_subpath_for_download(&sp, &config).is_ok());
+    }
+
+    #[test]
+    fn validate_deep_subpath() {
+        let config = DropConfig {
+            max_path_components: 64,
+            ..DropConfig::default()
+        };
+
+        let sp = FileSubPath::from_path(vec!["a"; 64].join("/")).unwrap();
+        assert!(super::validate_subpath_for_download(&sp, &config).is_ok());
+
+        let sp = FileSubPath::from_path(vec!["a"; 10_000].join("/")).unwrap();
+        assert!(matches!(
+            super::validate_subpath_for_download(&sp, &config),
+            Err(crate::Error::BadPath(..))
+        ));
+    }
+
+    #[test]
+    fn temp_file_transfer_id() {
+        let transfer_id: uuid::Uuid = "23e488a4-0521-11ee-be56-0242ac120002".parse().unwrap();
+        let file_id = "Mc_uMiGAVxk8dWbaodvf5aNGdC8RxKDDjvlhe7oUlMs".into();
+
+        let name = super::temp_file_name(transfer_id, &file_id);
+        assert_eq!(super::temp_file_transfer_id(&name), Some(transfer_id));
+
+        assert_eq!(super::temp_file_transfer_id("file.txt"), None);
+        assert_eq!(super::temp_file_transfer_id("abc-def.dropdl-part"), None);
+    }
+
+    #[test]
+    fn move_tmp_to_colliding_dst() {
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+        let dir = tempfile::tempdir().unwrap();
+
+        let existing = dir.path().join("file.txt");
+        std::fs::write(&existing, "existing").unwrap();
+
+        let tmp = Hidden(dir.path().join("file.dropdl-part"));
+        std::fs::write(&tmp.0, "downloaded").unwrap();
+
+        let dst = super::move_tmp_to_dst(
+            &tmp,
+            Hidden(&existing),
+            ExistingFilePolicy::Rename,
+            "1.2.3.4",
+            &logger,
+        )
+        .unwrap();
+
+        assert_eq!(dst, dir.path().join("file(1).txt"));
+        assert_eq!(std::fs::read_to_string(&dst).unwrap(), "downloaded");
+        assert_eq!(std::fs::read_to_string(&existing).unwrap(), "existing");
+        assert!(!tmp.0.exists());
+    }
+
+    #[test]
+    fn move_tmp_to_existing_dst_with_policy() {
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+        let dir = tempfile::tempdir().unwrap();
+
+        let existing = dir.path().join("file.txt");
+        let tmp = Hidden(dir.path().join("file.dropdl-part"));
+
+        std::fs::write(&existing, "existing").unwrap();
+        std::fs::write(&tmp.0, "downloaded").unwrap();
+
+        let dst = super::move_tmp_to_dst(
+            &tmp,
+            Hidden(&existing),
+            ExistingFilePolicy::Skip,
+            "1.2.3.4",
+            &logger,
+        )
+        .unwrap();
+
+        assert_eq!(dst, existing);
+        assert_eq!(std::fs::read_to_string(&existing).unwrap(), "existing");
+        assert!(!tmp.0.exists());
+
+        std::fs::write(&tmp.0, "downloaded").unwrap();
+
+        let dst = super::move_tmp_to_dst(
+            &tmp,
+            Hidden(&existing),
+            ExistingFilePolicy::Overwrite,
+            "1.2.3.4",
+            &logger,
+        )
+        .unwrap();
+
+        assert_eq!(dst, existing);
+        assert_eq!(std::fs::read_to_string(&existing).unwrap(), "downloaded");
+        assert!(!tmp.0.exists());
+        assert!(!dir.path().join("file(1).txt").exists());
+
+        // A failed move leaves the existing file intact
+        super::move_tmp_to_dst(
+            &tmp,
+            Hidden(&existing),
+            ExistingFilePolicy::Overwrite,
+            "1.2.3.4",
+            &logger,
+        )
+        .unwrap_err();
+        assert_eq!(std::fs::read_to_string(&existing).unwrap(), "downloaded");
+    }
+
+    #[test]
+    fn quarantine_origin() {
+        let peer = "1.2.3.4".parse().unwrap();
+        let mut config = drop_config::DropConfig::default();
+        assert_eq!(super::quarantine_origin(&config, peer), "1.2.3.4");
+
+        config.quarantine_origin = Some("NordDrop from {peer}".into());
+        assert_eq!(
+            super::quarantine_origin(&config, peer),
+            "NordDrop from 1.2.3.4"
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn aborting_download_waits_for_task() {
+        use std::sync::Arc;
+
+        // Stands for the temporary file held open by the download
+        let file = Arc::new(());
+
+        let mut jobs = tokio::task::JoinSet::new();
+        let job = {
+            let file = file.clone();
+            jobs.spawn(async move {
+                let _file = file;
+                std::future::pending::<()>().await;
+            })
+        };
+
+        super::abort_and_wait(&job).await;
+        assert_eq!(Arc::strong_count(&file), 1);
+    }
+
+    #[tokio::test]
+    async fn checksum_phases_on_resume() {
+        use std::sync::Arc;
+
+        use drop_config::CHECKSUM_CHUNK_SIZE;
+
+        use crate::{ws::EventTxFactory, ChecksumPhase, Event, File, FileToRecv, IncomingTransfer};
+
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+        let dir = tempfile::tempdir().unwrap();
+        let config = DropConfig::default();
+
+        let file = FileToRecv::new("id1".into(), "file.txt".into(), 1024);
+        let xfer = Arc::new(
+            IncomingTransfer::new("1.2.3.4".parse().unwrap(), vec![file.clone()], &config).unwrap(),
+        );
+
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        let factory = EventTxFactory::new(tx, drop_analytics::moose_mock(), 1);
+        let events = factory.file(xfer.clone(), file.id().clone());
+        events.start(dir.path().to_string_lossy(), 0).await;
+
+        // The part downloaded before the transfer was paused
+        let tmp = Hidden(dir.path().join("file.dropdl-part"));
+        std::fs::write(&tmp.0, [0u8; 512]).unwrap();
+
+        let mut task = super::FileXferTask::new(file.clone(), xfer, dir.path().to_path_buf());
+        let state = task
+            .handle_tmp_file(
+                &logger,
+                &events,
+                &tmp,
+                true,
+                256,
+                CHECKSUM_CHUNK_SIZE,
+                None,
+                Vec::new(),
+            )
+            .await;
+        assert_eq!(state.unwrap().meta.len(), 512);
+
+        events.finalize_checksum_start(1024).await;
+        events.finalize_checksum_progress(1024).await;
+        events.finalize_checksum_finish().await;
+
+        let mut phases = Vec::new();
+        while let Ok((event, _)) = rx.try_recv() {
+            let Some(phase) = event.checksum_phase() else {
+                continue;
+            };
+
+            let file_id = match &event {
+                Event::VerifyChecksumStarted { file_id, .. }
+                | Event::VerifyChecksumProgress { file_id, .. }
+                | Event::VerifyChecksumFinished { file_id, .. }
+                | Event::FinalizeChecksumStarted { file_id, .. }
+                | Event::FinalizeChecksumProgress { file_id, .. }
+                | Event::FinalizeChecksumFinished { file_id, .. } => file_id,
+                _ => unreachable!(),
+            };
+            assert_eq!(file_id, file.id());
+
+            phases.push(phase);
+        }
+
+        // The verification of the existing part always comes first
+        let split = phases
+            .iter()
+            .position(|phase| *phase == ChecksumPhase::Finalize)
+            .unwrap();
+        assert!(split >= 2);
+        assert!(phases[..split]
+            .iter()
+            .all(|phase| *phase == ChecksumPhase::VerifyExisting));
+        assert_eq!(phases[split..].len(), 3);
+        assert!(phases[split..]
+            .iter()
+            .all(|phase| *phase == ChecksumPhase::Finalize));
+    }
+
+    struct MockDownloader;
+
+    #[async_trait::async_trait]
+    impl super::Downloader for MockDownloader {
+        async fn init(
+            &mut self,
+            _: &super::FileXferTask,
+            tmp: Option<super::TmpFileState>,
+        ) -> crate::Result<super::handler::DownloadInit> {
+            // Resume from whatever is already downloaded
+            Ok(super::handler::DownloadInit::Stream {
+                offset: tmp.map_or(0, |tmp| tmp.meta.len()),
+            })
+        }
+
+        async fn open(
+            &mut self,
+            tmp_location: &Hidden<std::path::PathBuf>,
+        ) -> crate::Result<std::fs::File> {
+            Ok(std::fs::File::create(&tmp_location.0)?)
+        }
+
+        async fn progress(&mut self, _: u64) -> crate::Result<()> {
+            Ok(())
+        }
+
+        async fn validate<F, Fut>(
+            &mut self,
+            _: &Hidden<std::path::PathBuf>,
+            _: usize,
+            _: Option<F>,
+            _: Option<u64>,
+        ) -> crate::Result<()>
+        where
+            F: FnMut(u64) -> Fut + Send + Sync,
+            Fut: std::future::Future<Output = ()> + Send + Sync,
+        {
+            Ok(())
+        }
+    }
+
+    pub(crate) fn mock_state(
+        logger: &slog::Logger,
+        config: std::sync::Arc<DropConfig>,
+        event_tx: tokio::sync::mpsc::UnboundedSender<(crate::Event, std::time::SystemTime)>,
+    ) -> crate::service::State {
+        use std::sync::Arc;
+
+        use tokio::sync::{mpsc, watch, Semaphore};
+
+        use crate::{auth, ws::EventTxFactory, TransferManager};
+
+        let moose = drop_analytics::moose_mock();
+        let storage = Arc::new(drop_storage::Storage::new(logger.clone(), ":memory:").unwrap());
+
+        crate::service::State {
+            event_tx,
+            transfer_manager: TransferManager::new(
+                storage.clone(),
+                EventTxFactory::new(mpsc::unbounded_channel().0, moose.clone(), 1),
+                logger.clone(),
+            ),
+            moose,
+            auth: Arc::new(auth::Context::new(
+                drop_auth::SecretKey::from([0; 32]),
+                |_| None,
+            )),
+            config,
+            storage,
+            throttle: Arc::new(Semaphore::new(1)),
+            addr: watch::channel("1.2.3.4".parse().unwrap()).0,
+            #[cfg(unix)]
+            fdresolv: None,
+            #[cfg(unix)]
+            uri_refresher: None,
+            dest_resolver: None,
+            post_completion: None,
+            paused: watch::channel(false).0,
+            metrics: Arc::default(),
+            clock: Arc::new(crate::SystemClock),
+        }
+    }
+
+    // The incoming transfer of the files registered with the mocked state. The
+    // files are streamed into a temporary download directory
+    struct StreamFixture {
+        logger: slog::Logger,
+        dir: tempfile::TempDir,
+        state: crate::service::State,
+        factory: crate::ws::EventTxFactory,
+        xfer: std::sync::Arc<crate::IncomingTransfer>,
+        rx: tokio::sync::mpsc::UnboundedReceiver<(crate::Event, std::time::SystemTime)>,
+    }
+
+    impl StreamFixture {
+        async fn new(config: DropConfig, files: Vec<crate::FileToRecv>) -> Self {
+            use std::sync::Arc;
+
+            use tokio::sync::mpsc;
+
+            use crate::{ws::EventTxFactory, IncomingTransfer};
+
+            let logger = slog::Logger::root(slog::Discard, slog::o!());
+            let config = Arc::new(config);
+
+            let (tx, rx) = mpsc::unbounded_channel();
+            let factory = EventTxFactory::new(tx.clone(), drop_analytics::moose_mock(), 1);
+            let state = mock_state(&logger, config.clone(), tx);
+
+            let xfer = Arc::new(
+                IncomingTransfer::new("1.2.3.4".parse().unwrap(), files, &config).unwrap(),
+            );
+            state
+                .transfer_manager
+                .register_incoming(xfer.clone(), mpsc::unbounded_channel().0, 0)
+                .await
+                .unwrap();
+
+            Self {
+                logger,
+                dir: tempfile::tempdir().unwrap(),
+                state,
+                factory,
+                xfer,
+                rx,
+            }
+        }
+
+        fn task(&self, file: crate::FileToRecv) -> super::FileXferTask {
+            super::FileXferTask::new(file, self.xfer.clone(), self.dir.path().to_path_buf())
+        }
+
+        fn tmp(&self, task: &super::FileXferTask) -> Hidden<std::path::PathBuf> {
+            use crate::File;
+
+            Hidden(
+                self.dir
+                    .path()
+                    .join(format!("{}.dropdl-part", task.file.id())),
+            )
+        }
+
+        // Starts the file and streams the chunks into it from the beginning
+        async fn stream(
+            &self,
+            task: &mut super::FileXferTask,
+            chunks: &mut tokio::sync::mpsc::UnboundedReceiver<Vec<u8>>,
+            emit_checksum_events: bool,
+        ) -> crate::Result<super::PlacedFile> {
+            use crate::File;
+
+            let events = self.factory.file(self.xfer.clone(), task.file.id().clone());
+            events.start(self.dir.path().to_string_lossy(), 0).await;
+
+            let tmp = self.tmp(task);
+            task.stream_file(
+                super::StreamCtx {
+                    logger: &self.logger,
+                    state: &self.state,
+                    tmp_loc: &tmp,
+                    stream: chunks,
+                    events: &events,
+                },
+                &mut MockDownloader,
+                0,
+                emit_checksum_events,
+                1,
+            )
+            .await
+        }
+    }
+
+    #[tokio::test]
+    async fn short_stream_keeps_tmp_file() {
+        use crate::FileToRecv;
+
+        let file = FileToRecv::new("id1".into(), "file.txt".into(), 1024);
+        let fx = StreamFixture::new(DropConfig::default(), vec![file.clone()]).await;
+
+        // The peer sends half of the file and closes the stream
+        let (chunks_tx, mut chunks_rx) = tokio::sync::mpsc::unbounded_channel();
+        chunks_tx.send(vec![0; 512]).unwrap();
+        drop(chunks_tx);
+
+        let mut task = fx.task(file);
+        let result = fx.stream(&mut task, &mut chunks_rx, false).await;
+
+        assert!(result.is_err());
+        assert_eq!(std::fs::metadata(&fx.tmp(&task).0).unwrap().len(), 512);
+        assert!(!fx.dir.path().join("file.txt").exists());
+    }
+
+    #[tokio::test]
+    async fn empty_file_stream() {
+        use crate::{Event, FileToRecv};
+
+        let file = FileToRecv::new("id1".into(), "empty.txt".into(), 0);
+        let mut fx = StreamFixture::new(DropConfig::default(), vec![file.clone()]).await;
+
+        // The peer has no chunks to send
+        let (_chunks_tx, mut chunks_rx) = tokio::sync::mpsc::unbounded_channel();
+
+        let mut task = fx.task(file);
+        let super::PlacedFile {
+            path: dst, renamed, ..
+        } = fx.stream(&mut task, &mut chunks_rx, true).await.unwrap();
+
+        assert_eq!(dst, fx.dir.path().join("empty.txt"));
+        assert!(!renamed);
+        assert_eq!(std::fs::metadata(&dst).unwrap().len(), 0);
+        assert!(!fx.tmp(&task).0.exists());
+
+        let mut progress = Vec::new();
+        while let Ok((event, _)) = fx.rx.try_recv() {
+            if let Event::FileDownloadProgress(_, _, bytes) = event {
+                progress.push(bytes);
+            }
+        }
+        assert_eq!(progress, [0]);
+    }
+
+    #[tokio::test]
+    async fn streamed_file_ends_with_marker() {
+        use crate::FileToRecv;
+
+        let file = FileToRecv::new_streamed("id1".into(), "stdin".into());
+        let fx = StreamFixture::new(DropConfig::default(), vec![file.clone()]).await;
+
+        // The stream goes on until the end marker, the channel is kept open
+        let (chunks_tx, mut chunks_rx) = tokio::sync::mpsc::unbounded_channel();
+        chunks_tx.send(b"abc".to_vec()).unwrap();
+        chunks_tx.send(b"def".to_vec()).unwrap();
+        chunks_tx.send(Vec::new()).unwrap();
+
+        let mut task = fx.task(file);
+        let super::PlacedFile { path: dst, .. } =
+            fx.stream(&mut task, &mut chunks_rx, false).await.unwrap();
+
+        assert_eq!(dst, fx.dir.path().join("stdin"));
+        assert_eq!(std::fs::read(&dst).unwrap(), b"abcdef");
+        assert!(!fx.tmp(&task).0.exists());
+
+        // The size known at the end is stored in place of the unknown one
+        let transfers = fx
+            .state
+            .storage
+            .transfers_since(0, drop_storage::types::TransferSort::CreatedAsc)
+            .await;
+        let drop_storage::types::DbTransferType::Incoming(paths) = &transfers[0].transfer_type
+        else {
+            panic!("Expected incoming transfer");
+        };
+        assert_eq!(paths[0].bytes, 6);
+    }
+
+    #[tokio::test]
+    async fn streamed_file_exceeding_limit() {
+        use crate::FileToRecv;
+
+        // The file of known size leaves 3 bytes of the limit for the stream
+        let file = FileToRecv::new_streamed("id1".into(), "stdin".into());
+        let fx = StreamFixture::new(
+            DropConfig {
+                max_accept_bytes: Some(5),
+                ..Default::default()
+            },
+            vec![
+                file.clone(),
+                FileToRecv::new("id2".into(), "b.txt".into(), 2),
+            ],
+        )
+        .await;
+
+        let (chunks_tx, mut chunks_rx) = tokio::sync::mpsc::unbounded_channel();
+        chunks_tx.send(b"abc".to_vec()).unwrap();
+        chunks_tx.send(b"d".to_vec()).unwrap();
+        chunks_tx.send(Vec::new()).unwrap();
+
+        let mut task = fx.task(file);
+        let res = fx.stream(&mut task, &mut chunks_rx, false).await;
+
+        // The file is aborted with nothing left behind
+        assert!(matches!(res, Err(crate::Error::TransferLimitsExceeded)));
+        assert!(!fx.tmp(&task).0.exists());
+        assert!(!fx.dir.path().join("stdin").exists());
+    }
+
+    #[tokio::test]
+    async fn post_completion_hook_relocates_file() {
+        use std::{path::PathBuf, sync::Arc};
+
+        use crate::{File, FileToRecv, PostAction};
+
+        let file = FileToRecv::new("id1".into(), "a.txt".into(), 0);
+        let mut fx = StreamFixture::new(DropConfig::default(), vec![file.clone()]).await;
+        fx.state.post_completion = Some(Arc::new(|_, file: &FileToRecv, path| {
+            assert_eq!(path.file_name().unwrap(), "a.txt");
+            PostAction::Relocate(PathBuf::from("library").join(file.subpath().name()))
+        }));
+
+        let (_chunks_tx, mut chunks_rx) = tokio::sync::mpsc::unbounded_channel();
+
+        let super::PlacedFile {
+            path: dst, renamed, ..
+        } = fx
+            .stream(&mut fx.task(file), &mut chunks_rx, false)
+            .await
+            .unwrap();
+
+        assert_eq!(dst, fx.dir.path().join("library").join("a.txt"));
+        assert!(!renamed);
+        assert!(dst.is_file());
+        assert!(!fx.dir.path().join("a.txt").exists());
+    }
+
+    #[tokio::test]
+    async fn post_completion_hook_deletes_file() {
+        use std::sync::Arc;
+
+        use crate::{FileToRecv, IncomingTransfer, PostAction};
+
+        for (hook, deleted) in [
+            (
+                Arc::new(
+                    |_: &IncomingTransfer, _: &FileToRecv, _: &std::path::Path| PostAction::Delete,
+                ) as Arc<crate::PostCompletionHook>,
+                true,
+            ),
+            (
+                Arc::new(
+                    |_: &IncomingTransfer, _: &FileToRecv, _: &std::path::Path| {
+                        panic!("Hook failure")
+                    },
+                ),
+                false,
+            ),
+        ] {
+            let file = FileToRecv::new("id1".into(), "a.txt".into(), 0);
+            let mut fx = StreamFixture::new(DropConfig::default(), vec![file.clone()]).await;
+            fx.state.post_completion = Some(hook);
+
+            let (_chunks_tx, mut chunks_rx) = tokio::sync::mpsc::unbounded_channel();
+
+            let placed = fx
+                .stream(&mut fx.task(file), &mut chunks_rx, false)
+                .await
+                .unwrap();
+
+            assert_eq!(placed.path, fx.dir.path().join("a.txt"));
+            assert_eq!(placed.deleted, deleted);
+            // A panicking hook leaves the file in place
+            assert_eq!(placed.path.exists(), !deleted);
+        }
+    }
+
+    #[tokio::test]
+    async fn destination_resolver_places_file() {
+        use std::{path::PathBuf, sync::Arc};
+
+        use crate::{File, FileToRecv};
+
+        let file = FileToRecv::new("id1".into(), "a.txt".into(), 3);
+        let mut fx = StreamFixture::new(DropConfig::default(), vec![file.clone()]).await;
+        fx.state.dest_resolver = Some(Arc::new(|_, file: &FileToRecv, path| {
+            assert_eq!(path.file_name().unwrap(), "a.txt");
+            PathBuf::from("sorted").join(format!("{}.bak", file.subpath().name()))
+        }));
+
+        let (chunks_tx, mut chunks_rx) = tokio::sync::mpsc::unbounded_channel();
+        chunks_tx.send(b"abc".to_vec()).unwrap();
+
+        let super::PlacedFile {
+            path: dst, renamed, ..
+        } = fx
+            .stream(&mut fx.task(file), &mut chunks_rx, false)
+            .await
+            .unwrap();
+
+        // The relative path is resolved against the download directory
+        assert_eq!(dst, fx.dir.path().join("sorted").join("a.txt.bak"));
+        assert!(!renamed);
+        assert_eq!(std::fs::read(&dst).unwrap(), b"abc");
+        assert!(!fx.dir.path().join("a.txt").exists());
+    }
+
+    #[tokio::test]
+    async fn downloading_with_rename() {
+        use crate::FileToRecv;
+
+        let config = DropConfig::default();
+        assert!(super::validate_rename_for_download("b.txt", &config).is_ok());
+        for name in ["", "..", "x/b.txt", "/b.txt"] {
+            assert!(matches!(
+                super::validate_rename_for_download(name, &config),
+                Err(crate::Error::BadPath(_))
+            ));
+        }
+
+        let file = FileToRecv::new("id1".into(), "dir/a.txt".into(), 0);
+        let fx = StreamFixture::new(config, vec![file.clone()]).await;
+
+        let (_chunks_tx, mut chunks_rx) = tokio::sync::mpsc::unbounded_channel();
+
+        let mut task = fx.task(file).with_rename(Some("b.txt".into()));
+        let super::PlacedFile {
+            path: dst, renamed, ..
+        } = fx.stream(&mut task, &mut chunks_rx, false).await.unwrap();
+
+        // Only the name changes, the directory is kept
+        assert_eq!(dst, fx.dir.path().join("dir").join("b.txt"));
+        assert!(!renamed);
+        assert!(dst.is_file());
+    }
+
+    #[tokio::test]
+    async fn flattening_incoming_paths() {
+        use crate::FileToRecv;
+
+        // Same named files in distinct subdirectories
+        let files = vec![
+            FileToRecv::new("id1".into(), "dir/a/x.txt".into(), 0),
+            FileToRecv::new("id2".into(), "dir/b/x.txt".into(), 0),
+        ];
+        let fx = StreamFixture::new(
+            DropConfig {
+                flatten_incoming: true,
+                ..DropConfig::default()
+            },
+            files.clone(),
+        )
+        .await;
+
+        let mut placed = Vec::new();
+        for file in files {
+            let (_chunks_tx, mut chunks_rx) = tokio::sync::mpsc::unbounded_channel();
+
+            placed.push(
+                fx.stream(&mut fx.task(file), &mut chunks_rx, false)
+                    .await
+                    .map(|placed| (placed.path, placed.renamed))
+                    .unwrap(),
+            );
+        }
+
+        // The directories are dropped and the second file gets the collision suffix
+        assert_eq!(
+            placed,
+            vec![
+                (fx.dir.path().join("x.txt"), false),
+                (fx.dir.path().join("x(1).txt"), true),
+            ]
+        );
+        assert!(!fx.dir.path().join("dir").exists());
+    }
+
+    #[tokio::test]
+    async fn truncating_long_directories() {
+        use crate::FileToRecv;
+
+        // The directories differ only past the length limit
+        let prefix = "d".repeat(300);
+        let files = vec![
+            FileToRecv::new("id1".into(), format!("top/{prefix}a/x.txt").into(), 0),
+            FileToRecv::new("id2".into(), format!("top/{prefix}b/x.txt").into(), 0),
+        ];
+        let fx = StreamFixture::new(
+            DropConfig {
+                truncate_long_filenames: true,
+                ..DropConfig::default()
+            },
+            files.clone(),
+        )
+        .await;
+
+        let mut placed = Vec::new();
+        for file in files {
+            let (_chunks_tx, mut chunks_rx) = tokio::sync::mpsc::unbounded_channel();
+
+            let super::PlacedFile { path, renamed, .. } = fx
+                .stream(&mut fx.task(file), &mut chunks_rx, false)
+                .await
+                .unwrap();
+
+            assert!(!renamed);
+            placed.push(path);
+        }
+
+        // Each file keeps its own directory
+        let parents: Vec<_> = placed.iter().map(|path| path.parent().unwrap()).collect();
+        assert_ne!(parents[0], parents[1]);
+        for (path, parent) in placed.iter().zip(&parents) {
+            assert_eq!(path.file_name().unwrap(), "x.txt");
+            assert_eq!(parent.parent().unwrap(), fx.dir.path().join("top"));
+            assert!(parent.file_name().unwrap().len() <= super::MAX_FILENAME_LENGTH);
+            assert!(path.exists());
+        }
     }
 }
@@ -128,12 +128,12 @@ impl<'a, const PING: bool> handler::HandlerInit for HandlerInit<'a, PING> {
     }
 
     fn pinger(&mut self) -> Self::Pinger {
-        ws::utils::Pinger::<PING>::new()
+        ws::utils::Pinger::<PING>::new(&self.state.config)
     }
 
-    fn recv_timeout(&mut self) -> Duration {
+    fn recv_timeout(&mut self, timeout: Duration) -> Duration {
         if PING {
-            drop_config::TRANFER_IDLE_LIFETIME
+            timeout
         } else {
             Duration::MAX
         }
@@ -276,6 +276,18 @@ impl<const PING: bool> handler::HandlerLoop for HandlerLoop<'_, PING> {
         Ok(())
     }
 
+    async fn pause_download(
+        &mut self,
+        _: &mut WebSocket,
+        file_id: &FileId,
+    ) -> anyhow::Result<bool> {
+        // The protocol has no means of pausing the upload on the sender side
+        Ok(self
+            .jobs
+            .get(file_id)
+            .map_or(true, |task| task.job.is_finished()))
+    }
+
     async fn issue_reject(
         &mut self,
         socket: &mut WebSocket,
@@ -428,7 +440,7 @@ impl handler::Downloader for Downloader {
                 output
             });
 
-        let abs_path = task.prepare_abs_path(&self.state).await?;
+        let (abs_path, _) = task.prepare_abs_path(&self.state).await?;
 
         let tmp_location: Hidden<PathBuf> = Hidden(
             format!(
@@ -468,6 +480,7 @@ impl handler::Downloader for Downloader {
     async fn validate<F, Fut>(
         &mut self,
         _path: &Hidden<PathBuf>,
+        _: usize,
         _: Option<F>,
         _: Option<u64>,
     ) -> crate::Result<()>
@@ -26,7 +26,7 @@ use crate::{
     tasks::AliveGuard,
     transfer::{IncomingTransfer, Transfer},
     utils::Hidden,
-    ws::events::FileEventTx,
+    ws::{self, events::FileEventTx},
     File, FileId,
 };
 
@@ -82,7 +82,7 @@ impl<'a> HandlerInit<'a> {
 impl<'a> handler::HandlerInit for HandlerInit<'a> {
     type Request = (v4::TransferRequest, IpAddr, Arc<DropConfig>);
     type Loop = HandlerLoop<'a>;
-    type Pinger = tokio::time::Interval;
+    type Pinger = ws::utils::Pinger;
 
     async fn recv_req(&mut self, ws: &mut WebSocket) -> anyhow::Result<Self::Request> {
         let msg = ws
@@ -91,9 +91,15 @@ impl<'a> handler::HandlerInit for HandlerInit<'a> {
             .context("Failed to receive transfer request")?;
 
         let msg = msg.to_str().ok().context("Expected JSON message")?;
-        debug!(self.logger, "Request received:\n\t{msg}");
+        debug!(self.logger, "Request received:\n\t{:?}", Hidden(msg));
 
-        let req = serde_json::from_str(msg).context("Failed to deserialize transfer request")?;
+        let req: v4::TransferRequest =
+            serde_json::from_str(msg).context("Failed to deserialize transfer request")?;
+
+        anyhow::ensure!(
+            !req.has_streamed_files(),
+            "Streamed files are not supported by the protocol version"
+        );
 
         Ok((req, self.peer, self.state.config.clone()))
     }
@@ -200,7 +206,7 @@ impl<'a> handler::HandlerInit for HandlerInit<'a> {
     }
 
     fn pinger(&mut self) -> Self::Pinger {
-        tokio::time::interval(drop_config::PING_INTERVAL)
+        ws::utils::Pinger::new(&self.state.config)
     }
 }
 
@@ -385,6 +391,18 @@ impl handler::HandlerLoop for HandlerLoop<'_> {
         Ok(())
     }
 
+    async fn pause_download(
+        &mut self,
+        _: &mut WebSocket,
+        file_id: &FileId,
+    ) -> anyhow::Result<bool> {
+        // The protocol has no means of pausing the upload on the sender side
+        Ok(self
+            .jobs
+            .get(file_id)
+            .map_or(true, |task| task.job.is_finished()))
+    }
+
     async fn issue_reject(
         &mut self,
         socket: &mut WebSocket,
@@ -532,12 +550,12 @@ impl handler::Downloader for Downloader {
         tmpstate: Option<TmpFileState>,
     ) -> crate::Result<handler::DownloadInit> {
         match tmpstate {
-            Some(TmpFileState { meta, csum }) => {
+            Some(TmpFileState { meta, csum, .. }) => {
                 self.offset = match meta.len().cmp(&task.file.size()) {
                     Ordering::Less => {
                         let report = self.request_csum(meta.len()).await?;
 
-                        if report.limit == meta.len() && report.checksum == csum {
+                        if report.limit == meta.len() && Some(report.checksum) == csum {
                             // All matches, we can continue with temp file
                             meta.len()
                         } else {
@@ -550,7 +568,7 @@ impl handler::Downloader for Downloader {
                         }
                     }
                     Ordering::Equal => {
-                        if self.full_csum.get().await == csum {
+                        if Some(self.full_csum.get().await) == csum {
                             // All matches the temp file is actually the full file
                             meta.len()
                         } else {
@@ -603,6 +621,7 @@ impl handler::Downloader for Downloader {
     async fn validate<F, Fut>(
         &mut self,
         path: &Hidden<PathBuf>,
+        chunk_size: usize,
         progress_cb: Option<F>,
         event_granularity: Option<u64>,
     ) -> crate::Result<()>
@@ -611,7 +630,7 @@ impl handler::Downloader for Downloader {
         Fut: Future<Output = ()> + Send + Sync,
     {
         let file = std::fs::File::open(&path.0)?;
-        let csum = file::checksum(file, progress_cb, event_granularity).await?;
+        let csum = file::checksum(file, chunk_size, progress_cb, event_granularity).await?;
 
         if self.full_csum.get().await != csum {
             return Err(crate::Error::ChecksumMismatch);
@@ -10,8 +10,10 @@ use std::{
 
 use anyhow::Context;
 use async_cell::sync::AsyncCell;
+use drop_auth::ContentCipher;
 use drop_config::DropConfig;
 use drop_core::Status;
+use sha2::Digest;
 use slog::{debug, error, info, warn};
 use tokio::{
     sync::mpsc::{self, Sender, UnboundedSender},
@@ -27,20 +29,33 @@ use super::{
 use crate::{
     file::{self, FileToRecv},
     manager::FileTerminalState,
-    protocol::v6 as prot,
+    protocol::{self, v6 as prot},
     service::State,
     tasks::AliveGuard,
     transfer::{IncomingTransfer, Transfer},
     utils::{self, Hidden},
-    ws::events::FileEventTx,
+    ws::{self, events::FileEventTx},
     File, FileId,
 };
 
+// The size of the blocks compared when resuming the partially downloaded file
+const CHECKSUM_BLOCK_SIZE: u64 = 64 * 1024 * 1024;
+
 pub struct HandlerInit<'a> {
     peer: IpAddr,
     state: Arc<State>,
     logger: &'a slog::Logger,
     alive: &'a AliveGuard,
+    block_checksums: bool,
+    exchange_info: bool,
+    streamed_files: bool,
+    encrypted_contents: bool,
+    file_restart: bool,
+    file_selection: bool,
+    cipher: Option<Arc<ContentCipher>>,
+    peer_info: prot::PeerInfo,
+    // The checksums attached by the client to the transfer request
+    advertised_checksums: HashMap<FileId, [u8; 32]>,
 }
 
 pub struct HandlerLoop<'a> {
@@ -50,6 +65,10 @@ pub struct HandlerLoop<'a> {
     xfer: Arc<IncomingTransfer>,
     jobs: HashMap<FileId, FileTask>,
     checksums: HashMap<FileId, Arc<AsyncCell<[u8; 32]>>>,
+    block_checksums: bool,
+    cipher: Option<Arc<ContentCipher>>,
+    file_restart: bool,
+    file_selection: bool,
 }
 
 struct Downloader {
@@ -57,8 +76,15 @@ struct Downloader {
     file_id: FileId,
     msg_tx: Sender<MsgToSend>,
     csum_rx: mpsc::Receiver<prot::ReportChsum>,
+    block_csum_rx: mpsc::Receiver<prot::ReportBlockChsums>,
     full_csum: Arc<AsyncCell<[u8; 32]>>,
+    block_size: Option<u64>,
     offset: u64,
+    cipher: Option<Arc<ContentCipher>>,
+    storage: Arc<drop_storage::Storage>,
+    xfer_id: uuid::Uuid,
+    // Hashes the block being written, not set when resumed in its middle
+    block_hasher: Option<sha2::Sha256>,
 }
 
 struct FileTask {
@@ -66,6 +92,7 @@ struct FileTask {
     chunks_tx: UnboundedSender<Vec<u8>>,
     events: Arc<FileEventTx<IncomingTransfer>>,
     csum_tx: mpsc::Sender<prot::ReportChsum>,
+    block_csum_tx: mpsc::Sender<prot::ReportBlockChsums>,
 }
 
 impl<'a> HandlerInit<'a> {
@@ -74,12 +101,30 @@ impl<'a> HandlerInit<'a> {
         state: Arc<State>,
         logger: &'a slog::Logger,
         alive: &'a AliveGuard,
+        version: protocol::Version,
     ) -> Self {
         Self {
             peer,
             state,
             logger,
             alive,
+            block_checksums: matches!(
+                version,
+                protocol::Version::V7
+                    | protocol::Version::V8
+                    | protocol::Version::V9
+                    | protocol::Version::V10
+                    | protocol::Version::V11
+                    | protocol::Version::V12
+            ),
+            exchange_info: version.has_peer_info(),
+            streamed_files: version.has_streamed_files(),
+            encrypted_contents: version.has_encrypted_contents(),
+            file_restart: version.has_file_restart(),
+            file_selection: version.has_file_selection(),
+            cipher: None,
+            peer_info: prot::PeerInfo::default(),
+            advertised_checksums: HashMap::new(),
         }
     }
 }
@@ -88,7 +133,7 @@ impl<'a> HandlerInit<'a> {
 impl<'a> handler::HandlerInit for HandlerInit<'a> {
     type Request = (prot::TransferRequest, IpAddr, Arc<DropConfig>);
     type Loop = HandlerLoop<'a>;
-    type Pinger = tokio::time::Interval;
+    type Pinger = ws::utils::Pinger;
 
     async fn recv_req(&mut self, ws: &mut WebSocket) -> anyhow::Result<Self::Request> {
         let msg = ws
@@ -96,13 +141,40 @@ impl<'a> handler::HandlerInit for HandlerInit<'a> {
             .await
             .context("Failed to receive transfer request")?;
 
-        // print msg as ascii
-        debug!(self.logger, "************** msg:\n\t{msg:?}");
-
         let msg = msg.to_str().ok().context("Expected JSON message")?;
-        debug!(self.logger, "Request received:\n\t{msg}");
+        debug!(self.logger, "Request received:\n\t{:?}", Hidden(msg));
+
+        let mut req: prot::TransferRequest =
+            serde_json::from_str(msg).context("Failed to deserialize transfer request")?;
+
+        anyhow::ensure!(
+            self.streamed_files || !req.has_streamed_files(),
+            "Streamed files are not supported by the protocol version"
+        );
+
+        if let Some(info) = req.info.take() {
+            self.peer_info = info.sanitized();
+        }
+
+        if req.encrypted {
+            anyhow::ensure!(
+                self.encrypted_contents,
+                "Encrypted contents are not supported by the protocol version"
+            );
+
+            let cipher = self
+                .state
+                .auth
+                .content_cipher(self.peer, req.id)
+                .context("Failed to derive the content key")?;
+            self.cipher = Some(Arc::new(cipher));
+        }
 
-        let req = serde_json::from_str(msg).context("Failed to deserialize transfer request")?;
+        self.advertised_checksums = req
+            .files
+            .iter()
+            .filter_map(|file| Some((file.id.clone(), file.checksum?)))
+            .collect();
 
         Ok((req, self.peer, self.state.config.clone()))
     }
@@ -126,6 +198,8 @@ impl<'a> handler::HandlerInit for HandlerInit<'a> {
         msg_tx: Sender<MsgToSend>,
         xfer: Arc<IncomingTransfer>,
     ) -> Option<Self::Loop> {
+        let mut advertised = std::mem::take(&mut self.advertised_checksums);
+
         let task = async {
             let checksums = self.state.storage.fetch_checksums(xfer.id()).await;
 
@@ -147,7 +221,19 @@ impl<'a> handler::HandlerInit for HandlerInit<'a> {
                             .ok()
                             .context("Invalid length checksum stored in the DB")?,
                     ),
-                    None => to_fetch.push(xfile.id().clone()),
+                    // The checksum of the streamed file arrives with its end
+                    None if xfile.is_streamed() => (),
+                    None => match advertised.remove(xfile.id()) {
+                        // No need to ask for the checksum the client sent already
+                        Some(csum) => {
+                            self.state
+                                .storage
+                                .save_checksum(xfer.id(), xfile.id().as_ref(), &csum)
+                                .await;
+                            acell.set(csum);
+                        }
+                        None => to_fetch.push(xfile.id().clone()),
+                    },
                 }
             }
 
@@ -166,11 +252,28 @@ impl<'a> handler::HandlerInit for HandlerInit<'a> {
             }
         };
 
+        if self.exchange_info {
+            let msg = prot::ServerMsg::PeerInfo(prot::PeerInfo::from_config(&self.state.config));
+
+            if let Err(err) = ws.send(Message::from(&msg)).await {
+                warn!(self.logger, "Failed to send peer info: {err}");
+            }
+        }
+
         let Self {
             peer: _,
             state,
             logger,
             alive,
+            block_checksums,
+            exchange_info: _,
+            streamed_files: _,
+            encrypted_contents: _,
+            file_restart,
+            file_selection,
+            cipher,
+            peer_info: _,
+            advertised_checksums: _,
         } = self;
 
         // task responsible for requesting the checksum
@@ -205,11 +308,19 @@ impl<'a> handler::HandlerInit for HandlerInit<'a> {
             jobs: HashMap::new(),
             logger,
             checksums,
+            block_checksums,
+            cipher,
+            file_restart,
+            file_selection,
         })
     }
 
     fn pinger(&mut self) -> Self::Pinger {
-        tokio::time::interval(drop_config::PING_INTERVAL)
+        ws::utils::Pinger::new(&self.state.config)
+    }
+
+    fn peer_info(&self) -> prot::PeerInfo {
+        self.peer_info.clone()
     }
 }
 
@@ -242,11 +353,27 @@ impl HandlerLoop<'_> {
             events,
             chunks_tx: _,
             csum_tx: _,
+            block_csum_tx: _,
         }) = self.jobs.remove(&file_id)
         {
             if !task.is_finished() {
-                task.abort();
+                super::abort_and_wait(&task).await;
                 events.pause().await;
+
+                // The sender stops streaming the file once its source changes.
+                // The download starts over by verifying the part received so far
+                if self.file_restart {
+                    info!(self.logger, "Sender restarts the file {file_id}");
+
+                    if let Err(err) = self
+                        .state
+                        .transfer_manager
+                        .incoming_download_again(self.xfer.id(), &file_id)
+                        .await
+                    {
+                        warn!(self.logger, "Failed to request the file again: {err}");
+                    }
+                }
             }
         }
     }
@@ -287,12 +414,30 @@ impl HandlerLoop<'_> {
         }
     }
 
+    async fn on_rejected(&mut self, file_id: &FileId) {
+        self.stop_task(file_id, Status::FileRejected).await;
+
+        // Try to delete temporary file
+        let tmp_bases = self
+            .state
+            .storage
+            .fetch_base_dirs_for_file(self.xfer.id(), file_id.as_ref())
+            .await;
+
+        super::remove_temp_files(
+            self.logger,
+            self.xfer.id(),
+            tmp_bases.into_iter().map(|base| (base, file_id)),
+        );
+    }
+
     async fn stop_task(&mut self, file_id: &FileId, status: Status) {
         if let Some(FileTask {
             job: task,
             events,
             chunks_tx: _,
             csum_tx: _,
+            block_csum_tx: _,
         }) = self.jobs.remove(file_id)
         {
             if !task.is_finished() {
@@ -371,6 +516,45 @@ impl HandlerLoop<'_> {
         }
     }
 
+    async fn on_end(&mut self, end: prot::End) {
+        let Some(xfile) = self.xfer.files().get(&end.file) else {
+            return;
+        };
+
+        if !xfile.is_streamed() {
+            warn!(
+                self.logger,
+                "Received the end of the file of known size: {}", end.file
+            );
+            return;
+        }
+
+        if let Some(csum) = self.checksums.get(&end.file) {
+            csum.or_set(end.checksum);
+        }
+
+        // The empty chunk tells the download task the stream is over
+        if let Some(task) = self.jobs.get(&end.file) {
+            if task.chunks_tx.send(Vec::new()).is_err() {
+                warn!(
+                    self.logger,
+                    "Failed to pass the end of the stream to receiver task"
+                );
+            }
+        }
+    }
+
+    async fn on_block_checksums(&mut self, report: prot::ReportBlockChsums) {
+        if let Some(job) = self.jobs.get_mut(&report.file) {
+            if job.block_csum_tx.send(report).await.is_err() {
+                warn!(
+                    self.logger,
+                    "Failed to pass block checksums report to receiver task"
+                );
+            }
+        }
+    }
+
     fn take_pause_futures(&mut self) -> impl Future<Output = ()> {
         let jobs = std::mem::take(&mut self.jobs);
 
@@ -404,14 +588,21 @@ impl handler::HandlerLoop for HandlerLoop<'_> {
 
         let (chunks_tx, chunks_rx) = mpsc::unbounded_channel();
         let (csum_tx, csum_rx) = mpsc::channel(4);
+        let (block_csum_tx, block_csum_rx) = mpsc::channel(4);
 
         let downloader = Downloader {
             file_id: ctx.task.file.id().clone(),
             msg_tx: self.msg_tx.clone(),
             logger: self.logger.clone(),
             csum_rx,
+            block_csum_rx,
             full_csum: full_csum_cell,
+            block_size: self.block_checksums.then_some(CHECKSUM_BLOCK_SIZE),
             offset: 0,
+            cipher: self.cipher.clone(),
+            storage: self.state.storage.clone(),
+            xfer_id: self.xfer.id(),
+            block_hasher: None,
         };
 
         let file_id = ctx.task.file.id().clone();
@@ -424,12 +615,39 @@ impl handler::HandlerLoop for HandlerLoop<'_> {
                 chunks_tx,
                 events,
                 csum_tx,
+                block_csum_tx,
             },
         );
 
         Ok(())
     }
 
+    async fn pause_download(
+        &mut self,
+        socket: &mut WebSocket,
+        file_id: &FileId,
+    ) -> anyhow::Result<bool> {
+        if let Some(task) = self.jobs.remove(file_id) {
+            if !task.job.is_finished() {
+                debug!(
+                    self.logger,
+                    "Pausing download job: {}:{file_id}",
+                    self.xfer.id()
+                );
+
+                super::abort_and_wait(&task.job).await;
+                task.events.pause().await;
+
+                let msg = prot::ServerMsg::Cancel(prot::Cancel {
+                    file: file_id.clone(),
+                });
+                socket.send(Message::from(&msg)).await?;
+            }
+        }
+
+        Ok(true)
+    }
+
     async fn issue_reject(
         &mut self,
         socket: &mut WebSocket,
@@ -440,20 +658,37 @@ impl handler::HandlerLoop for HandlerLoop<'_> {
         });
         socket.send(Message::from(&msg)).await?;
 
-        self.stop_task(&file_id, Status::FileRejected).await;
+        self.on_rejected(&file_id).await;
 
-        // Try to delete temporary file
-        let tmp_bases = self
-            .state
-            .storage
-            .fetch_base_dirs_for_file(self.xfer.id(), file_id.as_ref())
-            .await;
+        Ok(())
+    }
 
-        super::remove_temp_files(
-            self.logger,
-            self.xfer.id(),
-            tmp_bases.into_iter().map(|base| (base, &file_id)),
-        );
+    async fn issue_select(
+        &mut self,
+        socket: &mut WebSocket,
+        rejected: Vec<FileId>,
+    ) -> anyhow::Result<()> {
+        if !self.file_selection {
+            for file_id in rejected {
+                self.issue_reject(socket, file_id).await?;
+            }
+            return Ok(());
+        }
+
+        let files = self
+            .xfer
+            .files()
+            .keys()
+            .filter(|file_id| !rejected.contains(file_id))
+            .cloned()
+            .collect();
+
+        let msg = prot::ServerMsg::Select(prot::Select { files });
+        socket.send(Message::from(&msg)).await?;
+
+        for file_id in &rejected {
+            self.on_rejected(file_id).await;
+        }
 
         Ok(())
     }
@@ -516,7 +751,9 @@ impl handler::HandlerLoop for HandlerLoop<'_> {
             prot::ClientMsg::Error(prot::Error { file, msg }) => self.on_error(file, msg).await,
             prot::ClientMsg::Cancel(prot::Cancel { file }) => self.on_cancel(file).await,
             prot::ClientMsg::ReportChsum(report) => self.on_checksum(report).await,
+            prot::ClientMsg::ReportBlockChsums(report) => self.on_block_checksums(report).await,
             prot::ClientMsg::Reject(prot::Reject { file }) => self.on_reject(file).await,
+            prot::ClientMsg::End(end) => self.on_end(end).await,
         }
         Ok(())
     }
@@ -581,6 +818,45 @@ impl Downloader {
 
         Ok(report)
     }
+
+    // Returns the length of the temporary file prefix made of the blocks
+    // matching the ones of the sender
+    async fn matching_prefix(
+        &mut self,
+        len: u64,
+        block_size: u64,
+        blocks: &[[u8; 32]],
+    ) -> crate::Result<u64> {
+        let msg = prot::ServerMsg::ReqBlockChsums(prot::ReqBlockChsums {
+            file: self.file_id.clone(),
+            limit: len,
+            block_size,
+        });
+        self.send(Message::from(&msg)).await?;
+
+        let report = self
+            .block_csum_rx
+            .recv()
+            .await
+            .ok_or(crate::Error::Canceled)?;
+
+        if report.limit != len || report.block_size != block_size {
+            warn!(
+                self.logger,
+                "Received block checksums do not match the request"
+            );
+            return Ok(0);
+        }
+
+        let matching = report
+            .checksums
+            .iter()
+            .zip(blocks)
+            .take_while(|(theirs, ours)| theirs == ours)
+            .count() as u64;
+
+        Ok(len.min(matching * block_size))
+    }
 }
 
 #[async_trait::async_trait]
@@ -590,13 +866,34 @@ impl handler::Downloader for Downloader {
         task: &super::FileXferTask,
         tmpstate: Option<TmpFileState>,
     ) -> crate::Result<handler::DownloadInit> {
+        // The stream is sent from its beginning only
+        if task.file.is_streamed() {
+            self.block_size = None;
+            return Ok(handler::DownloadInit::Stream { offset: 0 });
+        }
+
         match tmpstate {
-            Some(TmpFileState { meta, csum }) => {
-                self.offset = match meta.len().cmp(&task.file.size()) {
-                    Ordering::Less => {
+            Some(TmpFileState { meta, csum, blocks }) => {
+                self.offset = match (meta.len().cmp(&task.file.size()), self.block_size) {
+                    (Ordering::Less, Some(block_size)) => {
+                        let offset = self
+                            .matching_prefix(meta.len(), block_size, &blocks)
+                            .await?;
+
+                        if offset < meta.len() {
+                            info!(
+                                self.logger,
+                                "Found missmatch in partially downloaded file, resuming from \
+                                 {offset}"
+                            );
+                        }
+
+                        offset
+                    }
+                    (Ordering::Less, None) => {
                         let report = self.request_csum(meta.len()).await?;
 
-                        if report.limit == meta.len() && report.checksum == csum {
+                        if report.limit == meta.len() && Some(report.checksum) == csum {
                             // All matches, we can continue with temp file
                             meta.len()
                         } else {
@@ -608,10 +905,23 @@ impl handler::Downloader for Downloader {
                             0
                         }
                     }
-                    Ordering::Equal => {
-                        if self.full_csum.get().await == csum {
+                    (Ordering::Equal, block_size) => {
+                        // Without the checksum of the whole temp file the blocks are compared
+                        if Some(self.full_csum.get().await) == csum {
                             // All matches the temp file is actually the full file
                             meta.len()
+                        } else if let Some(block_size) = block_size {
+                            let offset = self
+                                .matching_prefix(meta.len(), block_size, &blocks)
+                                .await?;
+
+                            info!(
+                                self.logger,
+                                "The partially downloaded file has the same size as the target \
+                                 file but the checksum does not match, resuming from {offset}"
+                            );
+
+                            offset
                         } else {
                             info!(
                                 self.logger,
@@ -622,7 +932,7 @@ impl handler::Downloader for Downloader {
                             0
                         }
                     }
-                    Ordering::Greater => {
+                    (Ordering::Greater, _) => {
                         info!(
                             self.logger,
                             "The partially downloaded file is bigger then the target file, \
@@ -645,12 +955,75 @@ impl handler::Downloader for Downloader {
         let file = if self.offset == 0 {
             fs::File::create(&path.0)?
         } else {
-            fs::File::options().append(true).open(&path.0)?
+            let file = fs::File::options().append(true).open(&path.0)?;
+            // Drop the part following the last matching block
+            file.set_len(self.offset)?;
+            file
         };
 
+        if let Some(block_size) = self.block_size {
+            // The blocks following the offset are written again
+            self.storage
+                .remove_block_checksums(
+                    self.xfer_id,
+                    self.file_id.as_ref(),
+                    self.offset / block_size,
+                )
+                .await;
+        }
+
         Ok(file)
     }
 
+    async fn written(&mut self, mut offset: u64, mut data: &[u8]) {
+        let Some(block_size) = self.block_size else {
+            return;
+        };
+
+        while !data.is_empty() {
+            let in_block = offset % block_size;
+            if in_block == 0 {
+                self.block_hasher = Some(sha2::Sha256::new());
+            }
+
+            let len = data.len().min((block_size - in_block) as usize);
+            let (head, tail) = data.split_at(len);
+            if let Some(hasher) = &mut self.block_hasher {
+                hasher.update(head);
+            }
+
+            offset += len as u64;
+            data = tail;
+
+            if offset % block_size == 0 {
+                if let Some(hasher) = self.block_hasher.take() {
+                    self.storage
+                        .save_block_checksum(
+                            self.xfer_id,
+                            self.file_id.as_ref(),
+                            block_size,
+                            offset / block_size - 1,
+                            &hasher.finalize(),
+                        )
+                        .await;
+                }
+            }
+        }
+    }
+
+    fn decrypt(&self, offset: u64, chunk: Vec<u8>) -> crate::Result<Vec<u8>> {
+        match &self.cipher {
+            Some(cipher) => cipher
+                .open(&prot::chunk_aad(&self.file_id, offset), &chunk)
+                .ok_or(crate::Error::AuthenticationFailed),
+            None => Ok(chunk),
+        }
+    }
+
+    fn tmp_block_size(&self) -> Option<u64> {
+        self.block_size
+    }
+
     async fn progress(&mut self, bytes: u64) -> crate::Result<()> {
         self.send(&prot::ServerMsg::Progress(prot::Progress {
             file: self.file_id.clone(),
@@ -662,6 +1035,7 @@ impl handler::Downloader for Downloader {
     async fn validate<F, Fut>(
         &mut self,
         path: &Hidden<PathBuf>,
+        chunk_size: usize,
         progress_cb: Option<F>,
         event_granularity: Option<u64>,
     ) -> crate::Result<()>
@@ -670,19 +1044,25 @@ impl handler::Downloader for Downloader {
         Fut: Future<Output = ()> + Send,
     {
         let file = std::fs::File::open(&path.0)?;
-        let csum = file::checksum(file, progress_cb, event_granularity).await?;
+        let csum = file::checksum(file, chunk_size, progress_cb, event_granularity).await?;
 
         if self.full_csum.get().await != csum {
             return Err(crate::Error::ChecksumMismatch);
         }
 
+        if self.block_size.is_some() {
+            self.storage
+                .remove_block_checksums(self.xfer_id, self.file_id.as_ref(), 0)
+                .await;
+        }
+
         Ok(())
     }
 }
 
 impl handler::Request for (prot::TransferRequest, IpAddr, Arc<DropConfig>) {
     fn parse(self) -> anyhow::Result<IncomingTransfer> {
-        let (prot::TransferRequest { files, id }, peer, config) = self;
+        let (prot::TransferRequest { files, id, .. }, peer, config) = self;
 
         IncomingTransfer::new_with_uuid(peer, map_files(files)?, id, &config)
             .context("Failed to crate transfer")
@@ -694,7 +1074,10 @@ fn map_files(files: Vec<prot::File>) -> anyhow::Result<Vec<FileToRecv>> {
 
     let mut used_mappings = HashMap::new();
 
-    for prot::File { mut path, id, size } in files {
+    for prot::File {
+        mut path, id, size, ..
+    } in files
+    {
         let uroot = path.root();
         let nroot = utils::normalize_filename(uroot);
 
@@ -723,7 +1106,10 @@ fn map_files(files: Vec<prot::File>) -> anyhow::Result<Vec<FileToRecv>> {
             *piter.next().context("Subpath should always contain root")? = nroot;
             piter.for_each(|s| *s = utils::normalize_filename(&*s));
 
-            out.push(FileToRecv::new(id, path, size));
+            out.push(match size {
+                Some(size) => FileToRecv::new(id, path, size),
+                None => FileToRecv::new_streamed(id, path),
+            });
             break;
         }
     }
@@ -743,17 +1129,20 @@ mod tests {
             prot::File {
                 path: FileSubPath::from("a/b"),
                 id: FileId::from("id1"),
-                size: 0,
+                size: Some(0),
+                checksum: None,
             },
             prot::File {
                 path: FileSubPath::from("b"),
                 id: FileId::from("id2"),
-                size: 0,
+                size: Some(0),
+                checksum: None,
             },
             prot::File {
                 path: FileSubPath::from("c"),
                 id: FileId::from("id3"),
-                size: 0,
+                size: Some(0),
+                checksum: None,
             },
         ];
         let output = map_files(input).unwrap();
@@ -767,12 +1156,14 @@ mod tests {
             prot::File {
                 path: FileSubPath::from("a/b"),
                 id: FileId::from("id1"),
-                size: 0,
+                size: Some(0),
+                checksum: None,
             },
             prot::File {
                 path: FileSubPath::from("a/c"),
                 id: FileId::from("id2"),
-                size: 0,
+                size: Some(0),
+                checksum: None,
             },
         ];
         let output = map_files(input).unwrap();
@@ -785,22 +1176,26 @@ mod tests {
             prot::File {
                 path: FileSubPath::from("</a"),
                 id: FileId::from("id1"),
-                size: 0,
+                size: Some(0),
+                checksum: None,
             },
             prot::File {
                 path: FileSubPath::from("</b"),
                 id: FileId::from("id2"),
-                size: 0,
+                size: Some(0),
+                checksum: None,
             },
             prot::File {
                 path: FileSubPath::from(">/c"),
                 id: FileId::from("id3"),
-                size: 0,
+                size: Some(0),
+                checksum: None,
             },
             prot::File {
                 path: FileSubPath::from(">/d"),
                 id: FileId::from("id4"),
-                size: 0,
+                size: Some(0),
+                checksum: None,
             },
         ];
         let output = map_files(input).unwrap();
@@ -810,4 +1205,207 @@ mod tests {
         assert_eq!(*output[2].subpath(), FileSubPath::from("_(1)/c"));
         assert_eq!(*output[3].subpath(), FileSubPath::from("_(1)/d"));
     }
+
+    fn downloader(
+        xfer_id: uuid::Uuid,
+        block_size: Option<u64>,
+    ) -> (
+        Downloader,
+        mpsc::Receiver<MsgToSend>,
+        mpsc::Sender<prot::ReportBlockChsums>,
+    ) {
+        let logger = slog::Logger::root(slog::Discard, slog::o!());
+
+        let (msg_tx, msg_rx) = mpsc::channel(4);
+        let (block_csum_tx, block_csum_rx) = mpsc::channel(4);
+        let downloader = Downloader {
+            logger: logger.clone(),
+            file_id: FileId::from("id1"),
+            msg_tx,
+            csum_rx: mpsc::channel(1).1,
+            block_csum_rx,
+            full_csum: AsyncCell::shared(),
+            block_size,
+            offset: 0,
+            cipher: None,
+            storage: Arc::new(drop_storage::Storage::new(logger, ":memory:").unwrap()),
+            xfer_id,
+            block_hasher: None,
+        };
+
+        (downloader, msg_rx, block_csum_tx)
+    }
+
+    #[tokio::test]
+    async fn matching_prefix() {
+        let block = |b: &[u8]| -> [u8; 32] { sha2::Sha256::digest(b).into() };
+        let ours = [block(b"aaaa"), block(b"bbbb"), block(b"cc")];
+
+        let (mut downloader, _msg_rx, block_csum_tx) = downloader(uuid::Uuid::new_v4(), Some(4));
+
+        let report = |limit, checksums| prot::ReportBlockChsums {
+            file: FileId::from("id1"),
+            limit,
+            block_size: 4,
+            checksums,
+        };
+
+        // Resumed from the first mismatching block
+        block_csum_tx
+            .send(report(10, vec![ours[0], block(b"xxxx"), ours[2]]))
+            .await
+            .unwrap();
+        assert_eq!(downloader.matching_prefix(10, 4, &ours).await.unwrap(), 4);
+
+        // All blocks match, the last one is shorter
+        block_csum_tx.send(report(10, ours.to_vec())).await.unwrap();
+        assert_eq!(downloader.matching_prefix(10, 4, &ours).await.unwrap(), 10);
+
+        // The report for a different request is not trusted
+        block_csum_tx.send(report(8, ours.to_vec())).await.unwrap();
+        assert_eq!(downloader.matching_prefix(10, 4, &ours).await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn resuming_from_mismatching_block() {
+        use handler::Downloader as _;
+
+        let block = |b: &[u8]| -> [u8; 32] { sha2::Sha256::digest(b).into() };
+
+        let dir = tempfile::tempdir().unwrap();
+        let config = DropConfig::default();
+        let file = FileToRecv::new("id1".into(), "file.txt".into(), 16);
+        let xfer = Arc::new(
+            IncomingTransfer::new("1.2.3.4".parse().unwrap(), vec![file.clone()], &config).unwrap(),
+        );
+
+        let (mut downloader, _msg_rx, block_csum_tx) = downloader(xfer.id(), Some(4));
+        downloader
+            .storage
+            .insert_transfer(&xfer.storage_info())
+            .await
+            .unwrap();
+
+        // The full blocks written in the previous attempt are remembered along
+        // with the temporary file they were written to
+        let tmp = Hidden(dir.path().join("file.dropdl-part"));
+        std::fs::write(&tmp.0, b"aaaabbbbcc").unwrap();
+        downloader.written(0, b"aaaabb").await;
+        downloader.written(6, b"bbcc").await;
+
+        let meta = std::fs::metadata(&tmp.0).unwrap();
+        let mtime = super::super::mtime_millis(&meta);
+        downloader
+            .storage
+            .set_block_checksums_file_state(xfer.id(), "id1", meta.len(), mtime)
+            .await;
+        assert_eq!(
+            downloader
+                .storage
+                .fetch_block_checksums(xfer.id(), "id1", 4, meta.len(), mtime)
+                .await,
+            vec![block(b"aaaa").to_vec(), block(b"bbbb").to_vec()]
+        );
+        // Modified while the download was stopped
+        assert!(downloader
+            .storage
+            .fetch_block_checksums(xfer.id(), "id1", 4, meta.len() + 1, mtime)
+            .await
+            .is_empty());
+
+        // Only the trailing part of the temporary file is read again
+        let known = downloader
+            .storage
+            .fetch_block_checksums(xfer.id(), "id1", 4, meta.len(), mtime)
+            .await
+            .into_iter()
+            .map(|csum| csum.try_into().unwrap())
+            .collect();
+        let tmpstate = TmpFileState::load(
+            &tmp.0,
+            4,
+            Some(4),
+            known,
+            None::<fn(u64) -> std::future::Ready<()>>,
+            None,
+        )
+        .await
+        .unwrap();
+        assert!(tmpstate.csum.is_none());
+        assert_eq!(
+            tmpstate.blocks,
+            [block(b"aaaa"), block(b"bbbb"), block(b"cc")]
+        );
+
+        block_csum_tx
+            .send(prot::ReportBlockChsums {
+                file: FileId::from("id1"),
+                limit: 10,
+                block_size: 4,
+                checksums: vec![block(b"aaaa"), block(b"xxxx"), block(b"cc")],
+            })
+            .await
+            .unwrap();
+
+        let task = super::super::FileXferTask::new(file, xfer.clone(), dir.path().to_path_buf());
+        assert!(matches!(
+            downloader.init(&task, Some(tmpstate)).await.unwrap(),
+            handler::DownloadInit::Stream { offset: 4 }
+        ));
+
+        // The mismatching part is dropped along with its block checksums
+        let out = downloader.open(&tmp).await.unwrap();
+        assert_eq!(out.metadata().unwrap().len(), 4);
+        assert_eq!(
+            downloader
+                .storage
+                .fetch_block_checksums(xfer.id(), "id1", 4, meta.len(), mtime)
+                .await,
+            vec![block(b"aaaa").to_vec()]
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn decrypting_chunks() {
+        use handler::Downloader as _;
+
+        let alice = drop_auth::SecretKey::from([1; 32]);
+        let bob = drop_auth::SecretKey::from([2; 32]);
+        let alice_pub = drop_auth::PublicKey::from(&alice);
+        let bob_pub = drop_auth::PublicKey::from(&bob);
+
+        let xfer_id = uuid::Uuid::new_v4();
+        let peer = "1.2.3.4".parse().unwrap();
+        let sender = crate::auth::Context::new(alice, move |_| Some(bob_pub))
+            .content_cipher(peer, xfer_id)
+            .unwrap();
+        let receiver = crate::auth::Context::new(bob, move |_| Some(alice_pub))
+            .content_cipher(peer, xfer_id)
+            .unwrap();
+
+        let (mut downloader, _msg_rx, _block_csum_tx) = downloader(xfer_id, None);
+        downloader.cipher = Some(Arc::new(receiver));
+
+        let sealed = sender.seal(&prot::chunk_aad(&FileId::from("id1"), 10), b"abc");
+        assert_eq!(downloader.decrypt(10, sealed.clone()).unwrap(), b"abc");
+
+        // Tampered ciphertext is rejected
+        let mut tampered = sealed.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        assert!(matches!(
+            downloader.decrypt(10, tampered),
+            Err(crate::Error::AuthenticationFailed)
+        ));
+
+        // So is the chunk replayed at another offset or sealed for another file
+        assert!(matches!(
+            downloader.decrypt(13, sealed),
+            Err(crate::Error::AuthenticationFailed)
+        ));
+        let sealed = sender.seal(&prot::chunk_aad(&FileId::from("id2"), 10), b"abc");
+        assert!(matches!(
+            downloader.decrypt(10, sealed),
+            Err(crate::Error::AuthenticationFailed)
+        ));
+    }
 }
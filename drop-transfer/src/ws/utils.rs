@@ -1,11 +1,26 @@
+use std::time::{Duration, Instant};
+
+use drop_config::DropConfig;
+use rand::Rng;
+
+use super::FileEventTx;
+use crate::{service::State, Transfer};
+
+/// Pinger firing at the configured interval with a random jitter applied to
+/// every cycle. The first tick completes immediately
 pub struct Pinger<const PING: bool = true> {
-    interval: tokio::time::Interval,
+    interval: Duration,
+    jitter: f64,
+    next: tokio::time::Instant,
 }
 
 impl<const PING: bool> Pinger<PING> {
-    pub(crate) fn new() -> Self {
-        let interval = tokio::time::interval(drop_config::PING_INTERVAL);
-        Self { interval }
+    pub(crate) fn new(config: &DropConfig) -> Self {
+        Self {
+            interval: config.ping_interval,
+            jitter: config.ping_jitter.clamp(0.0, 1.0),
+            next: tokio::time::Instant::now(),
+        }
     }
 }
 
@@ -13,16 +28,74 @@ impl<const PING: bool> Pinger<PING> {
 impl<const PING: bool> super::Pinger for Pinger<PING> {
     async fn tick(&mut self) {
         if PING {
-            self.interval.tick().await;
+            tokio::time::sleep_until(self.next).await;
+            self.next = tokio::time::Instant::now()
+                + jittered(self.interval, self.jitter, &mut rand::thread_rng());
         } else {
             std::future::pending::<()>().await;
         }
     }
 }
 
-#[async_trait::async_trait]
-impl super::Pinger for tokio::time::Interval {
-    async fn tick(&mut self) {
-        self.tick().await;
+fn jittered(interval: Duration, jitter: f64, rng: &mut impl Rng) -> Duration {
+    interval.mul_f64(1.0 + rng.gen_range(-jitter..=jitter))
+}
+
+/// Update the round trip time with the time elapsed since the last ping
+pub(crate) async fn report_rtt(
+    state: &State,
+    transfer_id: uuid::Uuid,
+    ping_sent: &mut Option<Instant>,
+) {
+    if let Some(sent) = ping_sent.take() {
+        let rtt = sent.elapsed();
+
+        state
+            .transfer_manager
+            .update_metrics(transfer_id, |metrics| metrics.last_rtt = Some(rtt))
+            .await;
+    }
+}
+
+/// Account the bytes that are going to be transferred again because the file
+/// is resumed from an earlier point than the last reported progress
+pub(crate) async fn report_resent_bytes<T: Transfer>(
+    state: &State,
+    transfer_id: uuid::Uuid,
+    events: &FileEventTx<T>,
+    offset: u64,
+) {
+    let resent = events.transferred().await.saturating_sub(offset);
+
+    if resent > 0 {
+        state
+            .transfer_manager
+            .update_metrics(transfer_id, |metrics| metrics.bytes_resent += resent)
+            .await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    #[test]
+    fn jittered_ping_interval() {
+        const INTERVAL: Duration = Duration::from_secs(30);
+        const JITTER: f64 = 0.1;
+        const SAMPLES: u32 = 10_000;
+
+        let mut rng = rand::thread_rng();
+        let mut total = Duration::ZERO;
+
+        for _ in 0..SAMPLES {
+            let next = super::jittered(INTERVAL, JITTER, &mut rng);
+            assert!(next >= INTERVAL.mul_f64(1.0 - JITTER));
+            assert!(next <= INTERVAL.mul_f64(1.0 + JITTER));
+            total += next;
+        }
+
+        let mean = (total / SAMPLES).as_secs_f64();
+        assert!((mean - INTERVAL.as_secs_f64()).abs() < INTERVAL.as_secs_f64() * 0.01);
     }
 }
@@ -1,3 +1,5 @@
+use std::time::Duration;
+
 #[derive(Debug)]
 pub struct Config {
     pub dir_depth_limit: u64,
@@ -7,7 +9,36 @@ pub struct Config {
     pub storage_path: String,
     pub checksum_events_size_threshold: Option<u64>,
     pub checksum_events_granularity: Option<u64>,
+    pub checksum_chunk_size: Option<u32>,
+    pub progress_events_granularity: Option<u64>,
+    pub progress_events_interval_ms: Option<u64>,
     pub connection_retries: Option<u32>,
+    pub storage_busy_timeout_ms: Option<u64>,
+    pub connect_timeout_ms: Option<u64>,
+    pub ws_send_timeout_ms: Option<u64>,
+    pub ws_recv_timeout_ms: Option<u64>,
+    pub ping_interval_ms: Option<u64>,
+    pub ping_jitter: Option<f64>,
+    pub truncate_long_filenames: Option<bool>,
+    pub filename_sanitization: Option<drop_config::FilenameSanitization>,
+    pub flatten_incoming: Option<bool>,
+    pub max_accept_bytes: Option<u64>,
+    pub peer_resolution: Option<drop_config::PeerResolution>,
+    pub max_transfers_per_peer: Option<u32>,
+    pub allow_legacy_protocols: Option<bool>,
+    pub max_file_retries: Option<u32>,
+    pub quarantine_origin: Option<String>,
+    pub incoming_offer_ttl_ms: Option<u64>,
+    pub device_name: Option<String>,
+    pub app_version: Option<String>,
+    pub preallocate_downloads: Option<bool>,
+    pub existing_file_policy: Option<drop_config::ExistingFilePolicy>,
+    pub max_path_components: Option<u32>,
+    pub on_source_modified: Option<drop_config::SourceModifiedPolicy>,
+    pub redownload_missing: Option<bool>,
+    pub always_send_checksums: Option<bool>,
+    pub log_redaction: Option<drop_config::LogRedaction>,
+    pub encrypt_contents: Option<bool>,
 }
 
 impl Config {
@@ -18,6 +49,10 @@ impl Config {
     const fn default_checksum_granularity() -> u32 {
         256 * 1024
     }
+
+    const fn default_progress_granularity() -> u64 {
+        64 * 1024
+    }
 }
 
 impl From<Config> for drop_config::Config {
@@ -30,7 +65,36 @@ impl From<Config> for drop_config::Config {
             storage_path,
             checksum_events_size_threshold,
             checksum_events_granularity,
+            checksum_chunk_size,
+            progress_events_granularity,
+            progress_events_interval_ms,
             connection_retries,
+            storage_busy_timeout_ms,
+            connect_timeout_ms,
+            ws_send_timeout_ms,
+            ws_recv_timeout_ms,
+            ping_interval_ms,
+            ping_jitter,
+            truncate_long_filenames,
+            filename_sanitization,
+            flatten_incoming,
+            max_accept_bytes,
+            peer_resolution,
+            max_transfers_per_peer,
+            allow_legacy_protocols,
+            max_file_retries,
+            quarantine_origin,
+            incoming_offer_ttl_ms,
+            device_name,
+            app_version,
+            preallocate_downloads,
+            existing_file_policy,
+            max_path_components,
+            on_source_modified,
+            redownload_missing,
+            always_send_checksums,
+            log_redaction,
+            encrypt_contents,
         } = val;
 
         drop_config::Config {
@@ -41,8 +105,46 @@ impl From<Config> for drop_config::Config {
                 checksum_events_size_threshold: checksum_events_size_threshold.map(|x| x as _),
                 checksum_events_granularity: checksum_events_granularity
                     .unwrap_or(Config::default_checksum_granularity() as _),
+                checksum_chunk_size: checksum_chunk_size
+                    .map_or(drop_config::CHECKSUM_CHUNK_SIZE, |x| x as _),
+                progress_events_granularity: progress_events_granularity
+                    .unwrap_or(Config::default_progress_granularity()),
+                progress_events_interval: progress_events_interval_ms
+                    .map_or(Duration::ZERO, Duration::from_millis),
                 connection_retries: connection_retries
                     .unwrap_or(Config::default_connection_retries()),
+                storage_busy_timeout: storage_busy_timeout_ms
+                    .map_or(drop_config::STORAGE_BUSY_TIMEOUT, Duration::from_millis),
+                connect_timeout: connect_timeout_ms
+                    .map_or(drop_config::CONNECT_TIMEOUT, Duration::from_millis),
+                ws_send_timeout: ws_send_timeout_ms
+                    .map_or(drop_config::WS_SEND_TIMEOUT, Duration::from_millis),
+                ws_recv_timeout: ws_recv_timeout_ms
+                    .map_or(drop_config::TRANFER_IDLE_LIFETIME, Duration::from_millis),
+                truncate_long_filenames: truncate_long_filenames.unwrap_or(false),
+                ping_interval: ping_interval_ms
+                    .map_or(drop_config::PING_INTERVAL, Duration::from_millis),
+                ping_jitter: ping_jitter.unwrap_or(drop_config::PING_JITTER),
+                filename_sanitization: filename_sanitization.unwrap_or_default(),
+                flatten_incoming: flatten_incoming.unwrap_or(false),
+                max_accept_bytes,
+                peer_resolution: peer_resolution.unwrap_or_default(),
+                max_transfers_per_peer: max_transfers_per_peer.unwrap_or(0) as _,
+                allow_legacy_protocols: allow_legacy_protocols.unwrap_or(false),
+                max_file_retries,
+                quarantine_origin,
+                incoming_offer_ttl: incoming_offer_ttl_ms.map(Duration::from_millis),
+                device_name,
+                app_version,
+                preallocate_downloads: preallocate_downloads.unwrap_or(false),
+                existing_file_policy: existing_file_policy.unwrap_or_default(),
+                max_path_components: max_path_components
+                    .map_or(drop_config::MAX_PATH_COMPONENTS, |x| x as _),
+                on_source_modified: on_source_modified.unwrap_or_default(),
+                redownload_missing: redownload_missing.unwrap_or(false),
+                always_send_checksums: always_send_checksums.unwrap_or(false),
+                log_redaction: log_redaction.unwrap_or_default(),
+                encrypt_contents: encrypt_contents.unwrap_or(false),
             },
             moose: drop_config::MooseConfig {
                 event_path: moose_event_path,
@@ -51,3 +153,89 @@ impl From<Config> for drop_config::Config {
         }
     }
 }
+
+impl From<drop_config::Config> for Config {
+    fn from(val: drop_config::Config) -> Self {
+        let drop_config::Config {
+            drop:
+                drop_config::DropConfig {
+                    dir_depth_limit,
+                    transfer_file_limit,
+                    storage_path,
+                    checksum_events_size_threshold,
+                    checksum_events_granularity,
+                    checksum_chunk_size,
+                    progress_events_granularity,
+                    progress_events_interval,
+                    connection_retries,
+                    storage_busy_timeout,
+                    connect_timeout,
+                    ws_send_timeout,
+                    ws_recv_timeout,
+                    truncate_long_filenames,
+                    ping_interval,
+                    ping_jitter,
+                    filename_sanitization,
+                    flatten_incoming,
+                    max_accept_bytes,
+                    peer_resolution,
+                    max_transfers_per_peer,
+                    allow_legacy_protocols,
+                    max_file_retries,
+                    quarantine_origin,
+                    incoming_offer_ttl,
+                    device_name,
+                    app_version,
+                    preallocate_downloads,
+                    existing_file_policy,
+                    max_path_components,
+                    on_source_modified,
+                    redownload_missing,
+                    always_send_checksums,
+                    log_redaction,
+                    encrypt_contents,
+                },
+            moose: drop_config::MooseConfig { event_path, prod },
+        } = val;
+
+        Config {
+            dir_depth_limit: dir_depth_limit as _,
+            transfer_file_limit: transfer_file_limit as _,
+            moose_event_path: event_path,
+            moose_prod: prod,
+            storage_path,
+            checksum_events_size_threshold: checksum_events_size_threshold.map(|x| x as _),
+            checksum_events_granularity: Some(checksum_events_granularity),
+            checksum_chunk_size: Some(checksum_chunk_size as _),
+            progress_events_granularity: Some(progress_events_granularity),
+            progress_events_interval_ms: Some(progress_events_interval.as_millis() as _),
+            connection_retries: Some(connection_retries),
+            storage_busy_timeout_ms: Some(storage_busy_timeout.as_millis() as _),
+            connect_timeout_ms: Some(connect_timeout.as_millis() as _),
+            ws_send_timeout_ms: Some(ws_send_timeout.as_millis() as _),
+            ws_recv_timeout_ms: Some(ws_recv_timeout.as_millis() as _),
+            ping_interval_ms: Some(ping_interval.as_millis() as _),
+            ping_jitter: Some(ping_jitter),
+            truncate_long_filenames: Some(truncate_long_filenames),
+            filename_sanitization: Some(filename_sanitization),
+            flatten_incoming: Some(flatten_incoming),
+            max_accept_bytes,
+            peer_resolution: Some(peer_resolution),
+            max_transfers_per_peer: Some(max_transfers_per_peer as _),
+            allow_legacy_protocols: Some(allow_legacy_protocols),
+            max_file_retries,
+            quarantine_origin,
+            incoming_offer_ttl_ms: incoming_offer_ttl.map(|ttl| ttl.as_millis() as _),
+            device_name,
+            app_version,
+            preallocate_downloads: Some(preallocate_downloads),
+            existing_file_policy: Some(existing_file_policy),
+            max_path_components: Some(max_path_components as _),
+            on_source_modified: Some(on_source_modified),
+            redownload_missing: Some(redownload_missing),
+            always_send_checksums: Some(always_send_checksums),
+            log_redaction: Some(log_redaction),
+            encrypt_contents: Some(encrypt_contents),
+        }
+    }
+}
@@ -1,4 +1,5 @@
 use std::{
+    collections::VecDeque,
     net::{IpAddr, ToSocketAddrs},
     sync::Arc,
     time::SystemTime,
@@ -6,9 +7,13 @@ use std::{
 
 use drop_analytics::DeveloperExceptionEventData;
 use drop_auth::{PublicKey, SecretKey};
-use drop_config::{Config, DropConfig, MooseConfig};
+use drop_config::{Config, MooseConfig};
 use drop_storage::types::Transfer as TransferInfo;
-use drop_transfer::{auth, utils::Hidden, Event, FileToSend, OutgoingTransfer, Service, Transfer};
+use drop_transfer::{
+    auth,
+    utils::{Hidden, Peer, RedactingDrain},
+    Event, FileToSend, OutgoingTransfer, Service, Transfer,
+};
 use slog::{debug, error, trace, warn, Logger};
 use tokio::{
     sync::{mpsc, Mutex},
@@ -22,15 +27,23 @@ pub type Result<T = ()> = std::result::Result<T, crate::LibdropError>;
 const SQLITE_TIMESTAMP_MIN: i64 = -210866760000;
 const SQLITE_TIMESTAMP_MAX: i64 = 253402300799;
 
+// Maximum number of events kept for polling when no event callback is registered
+const EVENT_QUEUE_CAPACITY: usize = 4096;
+
 pub(super) struct NordDropFFI {
     rt: tokio::runtime::Runtime,
     pub logger: Logger,
+    // Applies the configured log redaction to the records of `logger`
+    log_redaction: RedactingDrain,
     instance: Arc<Mutex<Option<ServiceData>>>,
     event_dispatcher: EventDispatcher,
     keys: Arc<auth::Context>,
-    config: DropConfig,
+    config: Config,
     #[cfg(unix)]
     fdresolv: Option<Arc<drop_transfer::file::FdResolver>>,
+    #[cfg(unix)]
+    uri_refresher: Option<Arc<drop_transfer::file::ContentUriRefresher>>,
+    mime_resolv: Option<Arc<drop_transfer::file::MimeResolver>>,
 }
 
 struct ServiceData {
@@ -38,24 +51,94 @@ struct ServiceData {
     event_task: JoinHandle<()>,
 }
 
+#[derive(Clone)]
+enum EventSink {
+    Callback(Arc<dyn Fn(crate::Event) + Send + Sync>),
+    Queue(Arc<std::sync::Mutex<EventQueue<crate::Event>>>),
+}
+
+// Bounded queue of the events awaiting the poll, dropping the oldest events
+// once full
+struct EventQueue<T> {
+    events: VecDeque<T>,
+    capacity: usize,
+    dropped: usize,
+}
+
+impl<T> EventQueue<T> {
+    fn new(capacity: usize) -> Self {
+        Self {
+            events: VecDeque::new(),
+            capacity,
+            dropped: 0,
+        }
+    }
+
+    /// Returns `true` when the push starts dropping the events, subsequent
+    /// drops until the next drain return `false`
+    fn push(&mut self, event: T) -> bool {
+        let overflow = self.events.len() >= self.capacity;
+        if overflow {
+            self.events.pop_front();
+            self.dropped += 1;
+        }
+
+        self.events.push_back(event);
+        overflow && self.dropped == 1
+    }
+
+    /// Takes the queued events along with the number of the events dropped
+    /// since the last drain
+    fn drain(&mut self) -> (Vec<T>, usize) {
+        (
+            self.events.drain(..).collect(),
+            std::mem::take(&mut self.dropped),
+        )
+    }
+}
+
+impl<T> Default for EventQueue<T> {
+    fn default() -> Self {
+        Self::new(EVENT_QUEUE_CAPACITY)
+    }
+}
+
 #[derive(Clone)]
 struct EventDispatcher {
-    cb: Arc<dyn Fn(crate::Event) + Send + Sync>,
+    sink: EventSink,
+    logger: Logger,
 }
 
 impl EventDispatcher {
     fn dispatch(&self, e: impl Into<crate::Event>) {
-        (self.cb)(e.into());
+        match &self.sink {
+            EventSink::Callback(cb) => cb(e.into()),
+            EventSink::Queue(queue) => {
+                let event = e.into();
+                let overflow = queue.lock().expect("Poisoned lock").push(event);
+
+                if overflow {
+                    warn!(
+                        self.logger,
+                        "Event queue is full, dropping the oldest events. The events are not \
+                         polled fast enough"
+                    );
+                }
+            }
+        }
     }
 }
 
 impl NordDropFFI {
     pub(super) fn new(
-        event_cb: impl Fn(crate::Event) + Send + Sync + 'static,
+        event_cb: Option<Arc<dyn Fn(crate::Event) + Send + Sync>>,
         pubkey_cb: impl Fn(IpAddr) -> Option<PublicKey> + Send + 'static,
         privkey: SecretKey,
         logger: Logger,
     ) -> Result<Self> {
+        let log_redaction = RedactingDrain::new(logger, drop_config::LogRedaction::None);
+        let logger = Logger::root(log_redaction.clone(), slog::o!());
+
         trace!(logger, "norddrop_new()");
 
         // It's a debug print. Not visible in the production build
@@ -64,14 +147,22 @@ impl NordDropFFI {
         Ok(NordDropFFI {
             instance: Arc::default(),
             logger: logger.clone(),
+            log_redaction,
             rt: tokio::runtime::Runtime::new().map_err(|_| crate::LibdropError::Unknown)?,
             event_dispatcher: EventDispatcher {
-                cb: Arc::new(event_cb) as _,
+                sink: match event_cb {
+                    Some(cb) => EventSink::Callback(cb),
+                    None => EventSink::Queue(Arc::default()),
+                },
+                logger: logger.clone(),
             },
-            config: DropConfig::default(),
+            config: Config::default(),
             keys: Arc::new(crate_key_context(logger, privkey, pubkey_cb)),
             #[cfg(unix)]
             fdresolv: None,
+            #[cfg(unix)]
+            uri_refresher: None,
+            mime_resolv: None,
         })
     }
 
@@ -84,7 +175,8 @@ impl NordDropFFI {
         );
 
         // Check preconditions first
-        validate_config(&self.logger, &config)?;
+        let config = validate_config(&self.logger, config)?;
+        self.log_redaction.set_level(config.drop.log_redaction);
         let addr: IpAddr = match listen_addr.parse() {
             Ok(addr) => addr,
             Err(err) => {
@@ -102,12 +194,29 @@ impl NordDropFFI {
 
         let moose = initialize_moose(&self.logger, config.moose)?;
 
-        let storage = Arc::new(open_database(
+        let storage = open_database(
             &config.drop.storage_path,
             &self.event_dispatcher,
             &self.logger,
             &moose,
-        )?);
+        )?;
+        let storage = storage
+            .with_busy_timeout(config.drop.storage_busy_timeout)
+            .map_err(|err| {
+                error!(self.logger, "Failed to set DB busy timeout: {err}");
+                crate::LibdropError::DbError
+            })?;
+        let storage = Arc::new(storage.with_error_callback({
+            let moose = moose.clone();
+            move |op, err| {
+                moose.developer_exception(DeveloperExceptionEventData {
+                    code: crate::LibdropError::DbError as i32,
+                    note: op.to_string(),
+                    message: err.to_string(),
+                    name: "DB Error".to_string(),
+                })
+            }
+        }));
 
         // Spawn a task grabbing events from the inner service and dispatch them
         // to the host app
@@ -142,6 +251,12 @@ impl NordDropFFI {
             init_time,
             #[cfg(unix)]
             self.fdresolv.clone(),
+            #[cfg(unix)]
+            self.uri_refresher.clone(),
+            None,
+            None,
+            Arc::new(drop_transfer::SystemClock),
+            true,
         )) {
             Ok(service) => instance.replace(ServiceData {
                 service,
@@ -159,7 +274,7 @@ impl NordDropFFI {
             }
         };
 
-        self.config = config.drop;
+        self.config = config;
 
         Ok(())
     }
@@ -181,6 +296,32 @@ impl NordDropFFI {
         Ok(())
     }
 
+    pub(super) fn poll_events(&self) -> Result<Vec<crate::Event>> {
+        trace!(self.logger, "norddrop_poll_events()");
+
+        match &self.event_dispatcher.sink {
+            EventSink::Queue(queue) => {
+                let (events, dropped) = queue.lock().expect("Poisoned lock").drain();
+
+                if dropped > 0 {
+                    warn!(
+                        self.logger,
+                        "Dropped {dropped} events while the event queue was full"
+                    );
+                }
+
+                Ok(events)
+            }
+            EventSink::Callback(_) => {
+                error!(
+                    self.logger,
+                    "Failed to poll events. The event callback is registered"
+                );
+                Err(crate::LibdropError::BadInput)
+            }
+        }
+    }
+
     pub(super) fn purge_transfers(&mut self, transfer_ids: &[String]) -> Result<()> {
         trace!(
             self.logger,
@@ -199,6 +340,32 @@ impl NordDropFFI {
         Ok(())
     }
 
+    pub(super) fn migrate_storage(&self, from: &str, to: &str) -> Result<()> {
+        trace!(
+            self.logger,
+            "norddrop_migrate_storage() from: {:?}, to: {:?}",
+            Hidden(from),
+            Hidden(to)
+        );
+
+        if self.instance.blocking_lock().is_some() {
+            error!(
+                self.logger,
+                "Failed to migrate storage. Instance is already started"
+            );
+            return Err(crate::LibdropError::Unknown);
+        }
+
+        drop_storage::migrate_storage(&self.logger, from, to).map_err(|err| {
+            error!(self.logger, "Failed to migrate storage: {err}");
+
+            match err {
+                drop_storage::error::Error::DestinationExists => crate::LibdropError::BadInput,
+                _ => crate::LibdropError::DbError,
+            }
+        })
+    }
+
     pub(super) fn purge_transfers_until(&mut self, until_timestamp_s: i64) -> Result<()> {
         trace!(
             self.logger,
@@ -228,11 +395,16 @@ impl NordDropFFI {
         Ok(())
     }
 
-    pub(super) fn transfers_since(&mut self, since_timestamp_s: i64) -> Result<Vec<TransferInfo>> {
+    pub(super) fn transfers_since(
+        &mut self,
+        since_timestamp_s: i64,
+        sort: drop_storage::TransferSort,
+    ) -> Result<Vec<TransferInfo>> {
         trace!(
             self.logger,
-            "norddrop_get_transfers_since() since_timestamp: {:?}",
-            since_timestamp_s
+            "norddrop_get_transfers_since() since_timestamp: {:?}, sort: {:?}",
+            since_timestamp_s,
+            sort
         );
 
         if !(SQLITE_TIMESTAMP_MIN..=SQLITE_TIMESTAMP_MAX).contains(&since_timestamp_s) {
@@ -251,10 +423,33 @@ impl NordDropFFI {
             .service
             .storage();
 
-        let result = self.rt.block_on(storage.transfers_since(since_timestamp_s));
+        let result = self
+            .rt
+            .block_on(storage.transfers_since(since_timestamp_s, sort));
         Ok(result)
     }
 
+    pub(super) fn import_completed_incoming(
+        &self,
+        records: &[drop_storage::ImportedIncoming],
+    ) -> Result<u64> {
+        trace!(
+            self.logger,
+            "import_completed_incoming() records: {}",
+            records.len()
+        );
+
+        let instance = self.instance.blocking_lock();
+        let storage = instance
+            .as_ref()
+            .ok_or(crate::LibdropError::NotStarted)?
+            .service
+            .storage();
+
+        let count = self.rt.block_on(storage.import_completed_incoming(records));
+        Ok(count as _)
+    }
+
     pub(super) fn remove_transfer_file(
         &self,
         transfer_id: uuid::Uuid,
@@ -279,30 +474,305 @@ impl NordDropFFI {
         res.ok_or(crate::LibdropError::BadInput)
     }
 
+    pub(super) fn verify_file(&self, transfer_id: uuid::Uuid, file_id: String) -> Result<()> {
+        let logger = self.logger.clone();
+        let ed = self.event_dispatcher.clone();
+
+        trace!(
+            logger,
+            "verify_file() transfer_id: {transfer_id}, file_id: {file_id}",
+        );
+
+        // The file is hashed without holding the instance lock
+        let verify = {
+            let instance = self.instance.blocking_lock();
+            instance
+                .as_ref()
+                .ok_or(crate::LibdropError::NotStarted)?
+                .service
+                .verify_file(transfer_id, file_id.clone().into())
+        };
+
+        self.rt.spawn(async move {
+            match verify.await {
+                Ok(result) => ed.dispatch(event::EventKind::FileVerified {
+                    transfer_id: transfer_id.to_string(),
+                    file_id,
+                    result,
+                }),
+                Err(e) => {
+                    error!(
+                        logger,
+                        "Failed to verify file with xfid: {}, file: {:?}, error: {:?}",
+                        transfer_id,
+                        Hidden(&file_id),
+                        e
+                    );
+
+                    ed.dispatch(event::EventKind::FileVerifyFailed {
+                        transfer_id: transfer_id.to_string(),
+                        file_id,
+                        status: From::from(&e),
+                    });
+                }
+            }
+        });
+
+        Ok(())
+    }
+
+    pub(super) fn files_awaiting_checksum(&self, transfer_id: uuid::Uuid) -> Result<Vec<String>> {
+        trace!(
+            self.logger,
+            "files_awaiting_checksum() transfer_id: {transfer_id}"
+        );
+
+        let instance = self.instance.blocking_lock();
+        let service = &instance
+            .as_ref()
+            .ok_or(crate::LibdropError::NotStarted)?
+            .service;
+
+        let file_ids = self
+            .rt
+            .block_on(service.files_awaiting_checksum(transfer_id));
+
+        Ok(file_ids.into_iter().map(|id| id.to_string()).collect())
+    }
+
+    pub(super) fn file_checksum(
+        &self,
+        transfer_id: uuid::Uuid,
+        file_id: String,
+    ) -> Result<Option<String>> {
+        trace!(
+            self.logger,
+            "file_checksum() transfer_id: {transfer_id}, file_id: {file_id}",
+        );
+
+        let instance = self.instance.blocking_lock();
+        let service = &instance
+            .as_ref()
+            .ok_or(crate::LibdropError::NotStarted)?
+            .service;
+
+        Ok(self
+            .rt
+            .block_on(service.file_checksum(transfer_id, file_id.into())))
+    }
+
+    pub(super) fn request_checksum(
+        &self,
+        transfer_id: uuid::Uuid,
+        file_id: String,
+    ) -> Result<Vec<u8>> {
+        trace!(
+            self.logger,
+            "request_checksum() transfer_id: {transfer_id}, file_id: {file_id}",
+        );
+
+        let instance = self.instance.blocking_lock();
+        let service = &instance
+            .as_ref()
+            .ok_or(crate::LibdropError::NotStarted)?
+            .service;
+
+        self.rt
+            .block_on(service.request_checksum(transfer_id, file_id.into()))
+            .map(Vec::from)
+            .map_err(|err| {
+                error!(self.logger, "Failed to compute file checksum: {err:?}");
+
+                match err {
+                    drop_transfer::Error::BadFileId => crate::LibdropError::BadInput,
+                    _ => crate::LibdropError::Unknown,
+                }
+            })
+    }
+
+    pub(super) fn transfer_status(
+        &self,
+        transfer_id: uuid::Uuid,
+    ) -> Result<drop_transfer::TransferStatus> {
+        trace!(self.logger, "transfer_status() transfer_id: {transfer_id}");
+
+        let instance = self.instance.blocking_lock();
+        let service = &instance
+            .as_ref()
+            .ok_or(crate::LibdropError::NotStarted)?
+            .service;
+
+        Ok(self.rt.block_on(service.transfer_status(transfer_id)))
+    }
+
+    pub(super) fn pending_incoming_files(&self) -> Result<Vec<crate::PendingTransfer>> {
+        trace!(self.logger, "pending_incoming_files()");
+
+        let instance = self.instance.blocking_lock();
+        let service = &instance
+            .as_ref()
+            .ok_or(crate::LibdropError::NotStarted)?
+            .service;
+
+        let pending = self.rt.block_on(service.pending_incoming_files());
+
+        Ok(pending
+            .into_iter()
+            .map(|(transfer_id, file_ids)| crate::PendingTransfer {
+                transfer_id: transfer_id.to_string(),
+                file_ids: file_ids.into_iter().map(|id| id.to_string()).collect(),
+            })
+            .collect())
+    }
+
+    pub(super) fn metrics(&self) -> Result<crate::ServiceMetrics> {
+        trace!(self.logger, "metrics()");
+
+        let instance = self.instance.blocking_lock();
+        let service = &instance
+            .as_ref()
+            .ok_or(crate::LibdropError::NotStarted)?
+            .service;
+
+        let metrics = self.rt.block_on(service.metrics());
+
+        Ok(crate::ServiceMetrics {
+            active_incoming: metrics.active_incoming as _,
+            active_outgoing: metrics.active_outgoing as _,
+            bytes_transferred: metrics.bytes_transferred,
+            upload_rate: metrics.upload_rate,
+            download_rate: metrics.download_rate,
+            connected_peers: metrics.connected_peers as _,
+            rate_limited_requests: metrics.rate_limited_requests,
+        })
+    }
+
+    pub(super) fn cancel_peer(&self, peer: &str) -> Result<Vec<uuid::Uuid>> {
+        trace!(self.logger, "cancel_peer() peer: {}", Peer(peer));
+
+        let (peer, _) = drop_transfer::utils::parse_scoped_ip(peer).ok_or_else(|| {
+            error!(self.logger, "Failed to parse peer address: {}", Peer(peer));
+            crate::LibdropError::BadInput
+        })?;
+
+        let mut instance = self.instance.blocking_lock();
+        let service = &mut instance
+            .as_mut()
+            .ok_or(crate::LibdropError::NotStarted)?
+            .service;
+
+        Ok(self.rt.block_on(service.cancel_peer(peer)))
+    }
+
+    pub(super) fn redirect_incoming(
+        &self,
+        transfer_id: uuid::Uuid,
+        base_dir: &str,
+    ) -> Result<Vec<String>> {
+        trace!(
+            self.logger,
+            "redirect_incoming() transfer_id: {transfer_id}, base_dir: {:?}",
+            Hidden(base_dir)
+        );
+
+        let mut instance = self.instance.blocking_lock();
+        let service = &mut instance
+            .as_mut()
+            .ok_or(crate::LibdropError::NotStarted)?
+            .service;
+
+        let file_ids = self
+            .rt
+            .block_on(service.redirect_incoming(transfer_id, base_dir))
+            .map_err(|err| {
+                error!(self.logger, "Failed to redirect incoming transfer: {err:?}");
+
+                match err {
+                    drop_transfer::Error::BadTransfer => crate::LibdropError::BadInput,
+                    _ => crate::LibdropError::Unknown,
+                }
+            })?;
+
+        Ok(file_ids.into_iter().map(|id| id.to_string()).collect())
+    }
+
+    pub(super) fn reconnect_transfer(&self, transfer_id: uuid::Uuid) -> Result<()> {
+        trace!(
+            self.logger,
+            "norddrop_reconnect_transfer() transfer_id: {transfer_id}"
+        );
+
+        let instance = self.instance.blocking_lock();
+        let service = &instance
+            .as_ref()
+            .ok_or(crate::LibdropError::NotStarted)?
+            .service;
+
+        self.rt
+            .block_on(service.reconnect_transfer(transfer_id))
+            .map_err(|err| {
+                error!(self.logger, "Failed to reconnect transfer: {err:?}");
+
+                match err {
+                    drop_transfer::Error::BadTransfer => crate::LibdropError::BadInput,
+                    _ => crate::LibdropError::Unknown,
+                }
+            })
+    }
+
     pub(super) fn new_transfer(
         &mut self,
         peer: &str,
         descriptors: &[TransferDescriptor],
     ) -> Result<uuid::Uuid> {
-        trace!(self.logger, "norddrop_new_transfer() to peer {peer:?}",);
+        trace!(
+            self.logger,
+            "norddrop_new_transfer() to peer {}",
+            Peer(peer)
+        );
 
-        let peer = (peer, drop_config::PORT)
-            .to_socket_addrs()
-            .map_err(|err| {
-                error!(self.logger, "Failed to perform lookup of address: {err}");
-                crate::LibdropError::BadInput
-            })?
-            .next()
-            .ok_or(crate::LibdropError::BadInput)?;
+        // The link-local addresses carry the zone index which the lookup loses
+        let (peer_ip, alt_peers, zone) = match drop_transfer::utils::parse_scoped_ip(peer) {
+            Some((ip, zone)) => (ip, Vec::new(), zone),
+            None => {
+                let addrs = (peer, drop_config::PORT).to_socket_addrs().map_err(|err| {
+                    error!(self.logger, "Failed to perform lookup of address: {err}");
+                    crate::LibdropError::BadInput
+                })?;
+
+                let mut addrs = drop_transfer::utils::order_peer_addrs(
+                    addrs.map(|addr| addr.ip()),
+                    self.config.drop.peer_resolution,
+                )
+                .into_iter();
+                let peer_ip = addrs.next().ok_or(crate::LibdropError::BadInput)?;
+
+                (peer_ip, addrs.collect(), None)
+            }
+        };
+
+        debug!(
+            self.logger,
+            "Resolved peer {} to {} (zone {zone:?}), other addresses: {:?}",
+            Peer(peer),
+            Peer(peer_ip),
+            alt_peers.iter().map(Peer).collect::<Vec<_>>()
+        );
 
         let xfer = {
             let files = self.prepare_transfer_files(descriptors)?;
-            OutgoingTransfer::new(peer.ip(), files, &self.config).map_err(|e| {
+            OutgoingTransfer::new(peer_ip, files, &self.config.drop).map_err(|e| {
                 error!(self.logger, "Could not create transfer: {e}");
                 crate::LibdropError::TransferCreate
             })?
         };
 
+        let xfer = match self.config.drop.peer_resolution {
+            drop_config::PeerResolution::HappyEyeballs => xfer.with_alt_peers(alt_peers),
+            _ => xfer,
+        };
+        let xfer = xfer.with_zone(zone);
+
         debug!(
             self.logger,
             "Created transfer with files:\n{:#?}",
@@ -330,6 +800,83 @@ impl NordDropFFI {
         Ok(())
     }
 
+    pub(super) fn rebind(&mut self, listen_addr: &str) -> Result<()> {
+        trace!(
+            self.logger,
+            "norddrop_rebind() listen address: {:?}",
+            listen_addr,
+        );
+
+        let addr: IpAddr = match listen_addr.parse() {
+            Ok(addr) => addr,
+            Err(err) => {
+                error!(self.logger, "Failed to parse IP address: {err}");
+                return Err(crate::LibdropError::BadInput);
+            }
+        };
+
+        let mut instance = self.instance.blocking_lock();
+        let instance = instance.as_mut().ok_or(crate::LibdropError::NotStarted)?;
+
+        if let Err(err) = self.rt.block_on(instance.service.rebind(addr)) {
+            error!(self.logger, "Failed to rebind the service: {}", err);
+
+            let err = match err {
+                drop_transfer::Error::AddrInUse => crate::LibdropError::AddrInUse,
+                _ => crate::LibdropError::Unknown,
+            };
+
+            return Err(err);
+        }
+
+        Ok(())
+    }
+
+    pub(super) fn effective_config(&self) -> Result<Config> {
+        trace!(self.logger, "norddrop_effective_config()");
+
+        let instance = self.instance.blocking_lock();
+        let instance = instance.as_ref().ok_or(crate::LibdropError::NotStarted)?;
+
+        Ok(Config {
+            drop: instance.service.config().clone(),
+            moose: self.config.moose.clone(),
+        })
+    }
+
+    pub(super) fn retry_all_deferred(&mut self) -> Result<u32> {
+        trace!(self.logger, "norddrop_retry_all_deferred()");
+
+        let mut instance = self.instance.blocking_lock();
+        let instance = instance.as_mut().ok_or(crate::LibdropError::NotStarted)?;
+
+        let count = self.rt.block_on(instance.service.retry_all_deferred());
+
+        Ok(count as u32)
+    }
+
+    pub(super) fn pause_all(&self) -> Result<()> {
+        trace!(self.logger, "norddrop_pause_all()");
+
+        let instance = self.instance.blocking_lock();
+        let instance = instance.as_ref().ok_or(crate::LibdropError::NotStarted)?;
+
+        self.rt.block_on(instance.service.pause_all());
+
+        Ok(())
+    }
+
+    pub(super) fn resume_all(&self) -> Result<()> {
+        trace!(self.logger, "norddrop_resume_all()");
+
+        let instance = self.instance.blocking_lock();
+        let instance = instance.as_ref().ok_or(crate::LibdropError::NotStarted)?;
+
+        self.rt.block_on(instance.service.resume_all());
+
+        Ok(())
+    }
+
     pub(super) fn download(
         &mut self,
         xfid: uuid::Uuid,
@@ -344,7 +891,7 @@ impl NordDropFFI {
             "norddrop_download() for transfer {:?}, file {:?}, to {:?}",
             xfid,
             file_id,
-            dst
+            Hidden(&dst)
         );
 
         let mut inst = self.instance.clone().blocking_lock_owned();
@@ -357,7 +904,7 @@ impl NordDropFFI {
 
             if let Err(e) = inst
                 .service
-                .download(xfid, &file_id.clone().into(), &dst)
+                .download(xfid, &file_id.clone().into(), &dst, None)
                 .await
             {
                 error!(
@@ -380,13 +927,260 @@ impl NordDropFFI {
         Ok(())
     }
 
-    pub(super) fn cancel_transfer(&mut self, xfid: uuid::Uuid) -> Result<()> {
+    pub(super) fn download_files(
+        &mut self,
+        xfid: uuid::Uuid,
+        file_ids: Vec<String>,
+        dst: String,
+    ) -> Result<()> {
         let logger = self.logger.clone();
         let ed = self.event_dispatcher.clone();
 
-        trace!(logger, "norddrop_cancel_transfer() for {:?}", xfid);
-
-        let mut inst = self.instance.clone().blocking_lock_owned();
+        trace!(
+            logger,
+            "norddrop_download_files() for transfer {:?}, files {:?}, to {:?}",
+            xfid,
+            file_ids,
+            Hidden(&dst)
+        );
+
+        let mut inst = self.instance.clone().blocking_lock_owned();
+        if inst.is_none() {
+            return Err(crate::LibdropError::NotStarted);
+        }
+
+        self.rt.spawn(async move {
+            let inst = inst.as_mut().expect("Instance not initialized");
+
+            let report = |file_id: String, e: &drop_transfer::Error| {
+                error!(
+                    logger,
+                    "Failed to download a file with xfid: {}, file: {:?}, dst: {:?}, error: {:?}",
+                    xfid,
+                    Hidden(&file_id),
+                    Hidden(&dst),
+                    e
+                );
+
+                ed.dispatch(event::EventKind::FileFailed {
+                    transfer_id: xfid.to_string(),
+                    file_id,
+                    status: From::from(e),
+                });
+            };
+
+            let files: Vec<_> = file_ids.iter().cloned().map(Into::into).collect();
+
+            match inst.service.download_files(xfid, &files, &dst).await {
+                Ok(failed) => {
+                    for (file_id, e) in failed {
+                        report(file_id.to_string(), &e);
+                    }
+                }
+                Err(e) => {
+                    for file_id in file_ids {
+                        report(file_id, &e);
+                    }
+                }
+            }
+        });
+
+        Ok(())
+    }
+
+    pub(super) fn download_only(
+        &mut self,
+        xfid: uuid::Uuid,
+        file_ids: Vec<String>,
+        dst: String,
+    ) -> Result<()> {
+        let logger = self.logger.clone();
+        let ed = self.event_dispatcher.clone();
+
+        trace!(
+            logger,
+            "norddrop_download_only() for transfer {:?}, files {:?}, to {:?}",
+            xfid,
+            file_ids,
+            Hidden(&dst)
+        );
+
+        let mut inst = self.instance.clone().blocking_lock_owned();
+        if inst.is_none() {
+            return Err(crate::LibdropError::NotStarted);
+        }
+
+        self.rt.spawn(async move {
+            let inst = inst.as_mut().expect("Instance not initialized");
+
+            let report = |file_id: String, e: &drop_transfer::Error| {
+                error!(
+                    logger,
+                    "Failed to download a file with xfid: {}, file: {:?}, dst: {:?}, error: {:?}",
+                    xfid,
+                    Hidden(&file_id),
+                    Hidden(&dst),
+                    e
+                );
+
+                ed.dispatch(event::EventKind::FileFailed {
+                    transfer_id: xfid.to_string(),
+                    file_id,
+                    status: From::from(e),
+                });
+            };
+
+            let files: Vec<_> = file_ids.iter().cloned().map(Into::into).collect();
+
+            match inst.service.download_only(xfid, &files, &dst).await {
+                Ok(failed) => {
+                    for (file_id, e) in failed {
+                        report(file_id.to_string(), &e);
+                    }
+                }
+                Err(e) => {
+                    for file_id in file_ids {
+                        report(file_id, &e);
+                    }
+                }
+            }
+        });
+
+        Ok(())
+    }
+
+    pub(super) fn download_all(&mut self, xfid: uuid::Uuid, dst: String) -> Result<()> {
+        let logger = self.logger.clone();
+        let ed = self.event_dispatcher.clone();
+
+        trace!(
+            logger,
+            "norddrop_download_all() for transfer {:?}, to {:?}",
+            xfid,
+            Hidden(&dst)
+        );
+
+        let mut inst = self.instance.clone().blocking_lock_owned();
+        if inst.is_none() {
+            return Err(crate::LibdropError::NotStarted);
+        }
+
+        self.rt.spawn(async move {
+            let inst = inst.as_mut().expect("Instance not initialized");
+
+            match inst.service.download_all(xfid, &dst).await {
+                Ok(failed) => {
+                    for (file_id, e) in failed {
+                        error!(
+                            logger,
+                            "Failed to download a file with xfid: {}, file: {:?}, dst: {:?}, \
+                             error: {:?}",
+                            xfid,
+                            Hidden(file_id.as_ref()),
+                            Hidden(&dst),
+                            e
+                        );
+
+                        ed.dispatch(event::EventKind::FileFailed {
+                            transfer_id: xfid.to_string(),
+                            file_id: file_id.to_string(),
+                            status: From::from(&e),
+                        });
+                    }
+                }
+                // No file is affected, e.g. the transfer does not exist
+                Err(e) => {
+                    error!(
+                        logger,
+                        "Failed to download transfer files with xfid: {}, dst: {:?}, error: {:?}",
+                        xfid,
+                        Hidden(&dst),
+                        e
+                    );
+                }
+            }
+        });
+
+        Ok(())
+    }
+
+    pub(super) fn download_subtree(
+        &mut self,
+        xfid: uuid::Uuid,
+        prefix: String,
+        dst: String,
+    ) -> Result<()> {
+        let logger = self.logger.clone();
+        let ed = self.event_dispatcher.clone();
+
+        trace!(
+            logger,
+            "norddrop_download_subtree() for transfer {:?}, prefix {:?}, to {:?}",
+            xfid,
+            Hidden(&prefix),
+            Hidden(&dst)
+        );
+
+        let mut inst = self.instance.clone().blocking_lock_owned();
+        if inst.is_none() {
+            return Err(crate::LibdropError::NotStarted);
+        }
+
+        self.rt.spawn(async move {
+            let inst = inst.as_mut().expect("Instance not initialized");
+
+            match inst.service.download_subtree(xfid, &prefix, &dst).await {
+                Ok(failed) => {
+                    for (file_id, e) in failed {
+                        error!(
+                            logger,
+                            "Failed to download a file with xfid: {}, file: {:?}, dst: {:?}, \
+                             error: {:?}",
+                            xfid,
+                            Hidden(file_id.as_ref()),
+                            Hidden(&dst),
+                            e
+                        );
+
+                        ed.dispatch(event::EventKind::FileFailed {
+                            transfer_id: xfid.to_string(),
+                            file_id: file_id.to_string(),
+                            status: From::from(&e),
+                        });
+                    }
+                }
+                // No file is affected, e.g. the transfer does not exist
+                Err(e) => {
+                    error!(
+                        logger,
+                        "Failed to download transfer files with xfid: {}, dst: {:?}, error: {:?}",
+                        xfid,
+                        Hidden(&dst),
+                        e
+                    );
+                }
+            }
+        });
+
+        Ok(())
+    }
+
+    pub(super) fn cancel_transfer(
+        &mut self,
+        xfid: uuid::Uuid,
+        cause: crate::CancelCause,
+    ) -> Result<()> {
+        let logger = self.logger.clone();
+        let ed = self.event_dispatcher.clone();
+
+        trace!(
+            logger,
+            "norddrop_cancel_transfer() for {:?}, cause: {:?}",
+            xfid,
+            cause
+        );
+
+        let mut inst = self.instance.clone().blocking_lock_owned();
         if inst.is_none() {
             return Err(crate::LibdropError::NotStarted);
         }
@@ -394,7 +1188,7 @@ impl NordDropFFI {
         self.rt.spawn(async move {
             let inst = inst.as_mut().expect("Instance not initialized");
 
-            if let Err(e) = inst.service.cancel_all(xfid).await {
+            if let Err(e) = inst.service.cancel_all_with_cause(xfid, cause).await {
                 error!(
                     logger,
                     "Failed to cancel a transfer with xfid: {:?}, error: {:?}", xfid, e
@@ -444,6 +1238,39 @@ impl NordDropFFI {
         Ok(())
     }
 
+    pub(super) fn reject_transfer(&mut self, xfid: uuid::Uuid, reason: String) -> Result<()> {
+        let logger = self.logger.clone();
+        let ed = self.event_dispatcher.clone();
+
+        trace!(
+            logger,
+            "norddrop_reject_transfer() for {xfid}, reason: {reason}"
+        );
+
+        let mut inst = self.instance.clone().blocking_lock_owned();
+        if inst.is_none() {
+            return Err(crate::LibdropError::NotStarted);
+        }
+
+        self.rt.spawn(async move {
+            let inst = inst.as_mut().expect("Instance not initialized");
+
+            if let Err(e) = inst.service.reject_transfer(xfid, reason).await {
+                error!(
+                    logger,
+                    "Failed to reject a transfer with xfid: {xfid}, error: {e:?}"
+                );
+
+                ed.dispatch(crate::EventKind::TransferFailed {
+                    transfer_id: xfid.to_string(),
+                    status: From::from(&e),
+                });
+            }
+        });
+
+        Ok(())
+    }
+
     #[cfg(unix)]
     pub(super) fn set_fd_resolver_callback(
         &mut self,
@@ -465,17 +1292,83 @@ impl NordDropFFI {
         Ok(())
     }
 
+    #[cfg(unix)]
+    pub(super) fn set_content_uri_refresher_callback(
+        &mut self,
+        callback: impl Fn(&str) -> Option<String> + Send + 'static,
+    ) -> Result<()> {
+        trace!(self.logger, "norddrop_set_content_uri_refresher_callback()",);
+
+        let inst = self.instance.blocking_lock();
+        if inst.is_some() {
+            error!(
+                self.logger,
+                "Failed to set content URI refresher callback. Instance is already started"
+            );
+            return Err(crate::LibdropError::Unknown);
+        }
+        drop(inst);
+
+        let callback = std::sync::Mutex::new(callback);
+        let func = move |uri: &str| {
+            let guard = callback.lock().expect("Failed to lock refresher callback");
+            guard(uri)
+        };
+
+        // The callback may block the executor
+        self.uri_refresher = Some(Arc::new(move |uri: &str| {
+            tokio::task::block_in_place(|| func(uri))
+        }));
+        Ok(())
+    }
+
+    pub(super) fn set_mime_resolver_callback(
+        &mut self,
+        callback: impl Fn(&str) -> Option<String> + Send + 'static,
+    ) -> Result<()> {
+        trace!(self.logger, "norddrop_set_mime_resolver_callback()",);
+
+        let inst = self.instance.blocking_lock();
+        if inst.is_some() {
+            error!(
+                self.logger,
+                "Failed to set MIME resolver callback. Instance is already started"
+            );
+            return Err(crate::LibdropError::Unknown);
+        }
+        drop(inst);
+
+        let callback = std::sync::Mutex::new(callback);
+        let func = move |file: &FileToSend| {
+            let uri = file.uri()?;
+            let guard = callback
+                .lock()
+                .expect("Failed to lock MIME resolver callback");
+            guard(uri.as_str())
+        };
+
+        // The callback may block the executor
+        self.mime_resolv = Some(Arc::new(move |file: &FileToSend| {
+            tokio::task::block_in_place(|| func(file))
+        }));
+        Ok(())
+    }
+
     fn prepare_transfer_files(
         &self,
         descriptors: &[TransferDescriptor],
     ) -> Result<Vec<FileToSend>> {
-        let mut gather = drop_transfer::file::GatherCtx::new(&self.config);
+        let mut gather = drop_transfer::file::GatherCtx::new(&self.config.drop);
 
         #[cfg(unix)]
         if let Some(fdresolv) = self.fdresolv.as_ref() {
             gather.with_fd_resover(fdresolv.as_ref());
         }
 
+        if let Some(mime_resolv) = self.mime_resolv.as_ref() {
+            gather.with_mime_resolver(mime_resolv.clone());
+        }
+
         for desc in descriptors {
             match desc {
                 #[cfg(windows)]
@@ -505,6 +1398,22 @@ impl NordDropFFI {
                             crate::LibdropError::TransferCreate
                         })?;
                 }
+                TransferDescriptor::Memory {
+                    filename,
+                    content,
+                    mime_type,
+                } => {
+                    gather
+                        .gather_from_bytes(filename, Arc::new(content.clone()), mime_type.clone())
+                        .map_err(|e| {
+                            error!(
+                                self.logger,
+                                "Could not add in-memory file {:?} for transfer: {e}",
+                                Hidden(filename)
+                            );
+                            crate::LibdropError::TransferCreate
+                        })?;
+                }
                 TransferDescriptor::Path { path } => {
                     gather.gather_from_path(path).map_err(|e| {
                         error!(
@@ -533,7 +1442,7 @@ fn crate_key_context(
         let key = guard(ip)?;
         drop(guard);
 
-        debug!(logger, "Public key for {ip:?}: {key:?}");
+        debug!(logger, "Public key for {}: {key:?}", Peer(ip));
         Some(key)
     };
 
@@ -548,6 +1457,33 @@ fn open_database(
 ) -> Result<drop_storage::Storage> {
     match drop_storage::Storage::new(logger.clone(), dbpath) {
         Ok(storage) => Ok(storage),
+        Err(err @ drop_storage::error::Error::SchemaTooNew(..)) => {
+            // The DB was created by a newer libdrop version. Do not wipe it so that the data is
+            // not lost once the app is upgraded again
+            error!(logger, "Failed to open DB at \"{dbpath}\": {err}",);
+
+            moose.developer_exception(DeveloperExceptionEventData {
+                code: crate::LibdropError::DbError as i32,
+                note: err.to_string(),
+                message: "DB schema is too new".to_string(),
+                name: "DB Error".to_string(),
+            });
+
+            open_in_memory_fallback(events, logger, moose)
+        }
+        Err(err @ drop_storage::error::Error::StorageNotWritable(..)) => {
+            // Recreating the file would not help with a read-only filesystem or a full disk
+            error!(logger, "Failed to open DB at \"{dbpath}\": {err}",);
+
+            moose.developer_exception(DeveloperExceptionEventData {
+                code: crate::LibdropError::DbError as i32,
+                note: err.to_string(),
+                message: "DB is not writable".to_string(),
+                name: "DB Error".to_string(),
+            });
+
+            open_in_memory_fallback(events, logger, moose)
+        }
         Err(err) => {
             error!(logger, "Failed to open DB at \"{dbpath}\": {err}",);
 
@@ -584,7 +1520,7 @@ fn open_database(
                         "Failed to open DB and failed to remove it's file: {err}"
                     );
                     // Try to at least open db in memory if the path doesn't work
-                    return open_database(":memory:", events, logger, moose);
+                    return open_in_memory_fallback(events, logger, moose);
                 } else {
                     // Inform app that we wiped the old DB file
                     events.dispatch(crate::EventKind::RuntimeError {
@@ -594,7 +1530,10 @@ fn open_database(
 
                 // Final try after cleaning up old DB file
                 match drop_storage::Storage::new(logger.clone(), dbpath) {
-                    Ok(storage) => Ok(storage),
+                    Ok(storage) => {
+                        events.dispatch(crate::EventKind::StorageRecreated);
+                        Ok(storage)
+                    }
                     Err(err) => {
                         let error = crate::LibdropError::DbError;
                         moose.developer_exception(DeveloperExceptionEventData {
@@ -615,6 +1554,20 @@ fn open_database(
     }
 }
 
+/// Opens the in-memory DB in place of the one that could not be used, the
+/// data won't persist past this session
+fn open_in_memory_fallback(
+    events: &EventDispatcher,
+    logger: &slog::Logger,
+    moose: &Arc<dyn drop_analytics::Moose>,
+) -> Result<drop_storage::Storage> {
+    let storage = open_database(":memory:", events, logger, moose)?;
+    warn!(logger, "Falling back to the in-memory DB");
+
+    events.dispatch(crate::EventKind::StorageInMemoryFallback);
+    Ok(storage)
+}
+
 #[cfg(unix)]
 fn crate_fd_callback(
     logger: slog::Logger,
@@ -628,7 +1581,7 @@ fn crate_fd_callback(
         drop(guard);
 
         if res.is_none() {
-            warn!(logger, "FD callback failed for {uri:?}");
+            warn!(logger, "FD callback failed for {:?}", Hidden(uri));
         }
         res
     };
@@ -639,13 +1592,25 @@ fn crate_fd_callback(
     Arc::new(func)
 }
 
-fn validate_config(logger: &slog::Logger, config: &Config) -> Result<()> {
+fn validate_config(logger: &slog::Logger, mut config: Config) -> Result<Config> {
     if config.moose.event_path.is_empty() {
         error!(logger, "Moose path cannot be empty");
         return Err(crate::LibdropError::BadInput);
     }
 
-    Ok(())
+    match config.drop.validate() {
+        Ok(clamped) => {
+            for msg in clamped {
+                warn!(logger, "{msg}");
+            }
+        }
+        Err(err) => {
+            error!(logger, "Invalid config: {err}");
+            return Err(crate::LibdropError::BadInput);
+        }
+    }
+
+    Ok(config)
 }
 
 fn initialize_moose(
@@ -678,3 +1643,27 @@ fn initialize_moose(
 
     Ok(moose)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::EventQueue;
+
+    #[test]
+    fn event_queue_drops_oldest() {
+        let mut queue = EventQueue::new(2);
+        assert!(!queue.push(1));
+        assert!(!queue.push(2));
+
+        // Only the first drop of the overflow is reported
+        assert!(queue.push(3));
+        assert!(!queue.push(4));
+        assert_eq!(queue.drain(), (vec![3, 4], 2));
+        assert_eq!(queue.drain(), (vec![], 0));
+
+        // The next overflow is reported again
+        assert!(!queue.push(5));
+        assert!(!queue.push(6));
+        assert!(queue.push(7));
+        assert_eq!(queue.drain(), (vec![6, 7], 1));
+    }
+}
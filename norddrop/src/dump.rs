@@ -1,8 +1,17 @@
 use drop_storage::types as db;
 
 pub enum TransferStateKind {
-    Cancel { by_peer: bool },
-    Failed { status: crate::StatusCode },
+    Cancel {
+        by_peer: bool,
+        cause: crate::CancelCause,
+    },
+    Failed {
+        status: crate::StatusCode,
+    },
+    Rejected {
+        by_peer: bool,
+        reason: String,
+    },
 }
 
 pub struct TransferState {
@@ -23,6 +32,8 @@ pub enum IncomingPathStateKind {
     },
     Completed {
         final_path: String,
+        was_renamed: bool,
+        deleted: bool,
     },
     Rejected {
         by_peer: bool,
@@ -99,10 +110,15 @@ pub struct TransferInfo {
 impl From<db::TransferStateEventData> for TransferStateKind {
     fn from(value: db::TransferStateEventData) -> Self {
         match value {
-            db::TransferStateEventData::Cancel { by_peer } => Self::Cancel { by_peer },
+            db::TransferStateEventData::Cancel { by_peer, cause } => {
+                Self::Cancel { by_peer, cause }
+            }
             db::TransferStateEventData::Failed { status_code } => Self::Failed {
                 status: crate::StatusCode::from(status_code as u32),
             },
+            db::TransferStateEventData::Rejected { by_peer, reason } => {
+                Self::Rejected { by_peer, reason }
+            }
         }
     }
 }
@@ -146,9 +162,15 @@ impl From<db::IncomingPathStateEventData> for IncomingPathStateKind {
                 status: crate::StatusCode::from(status_code as u32),
                 bytes_received: bytes_received as _,
             },
-            db::IncomingPathStateEventData::Completed { final_path } => {
-                IncomingPathStateKind::Completed { final_path }
-            }
+            db::IncomingPathStateEventData::Completed {
+                final_path,
+                was_renamed,
+                deleted,
+            } => IncomingPathStateKind::Completed {
+                final_path,
+                was_renamed,
+                deleted,
+            },
             db::IncomingPathStateEventData::Rejected {
                 by_peer,
                 bytes_received,
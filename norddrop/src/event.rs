@@ -15,6 +15,13 @@ pub struct QueuedFile {
     pub base_dir: Option<String>,
 }
 
+pub struct CreatedFile {
+    pub id: String,
+    pub path: String,
+    pub size: u64,
+    pub mime_type: String,
+}
+
 pub struct Status {
     pub status: crate::StatusCode,
     pub os_error_code: Option<i32>,
@@ -36,6 +43,11 @@ pub enum EventKind {
         transfer_id: String,
         files: Vec<QueuedFile>,
     },
+    TransferCreated {
+        peer: String,
+        transfer_id: String,
+        files: Vec<CreatedFile>,
+    },
 
     FileStarted {
         transfer_id: String,
@@ -79,54 +91,119 @@ pub enum EventKind {
         transfer_id: String,
         file_id: String,
     },
+    FileMissing {
+        transfer_id: String,
+        file_id: String,
+        final_path: String,
+    },
+    InsufficientStorage {
+        transfer_id: String,
+        base_dir: String,
+        required: u64,
+        available: u64,
+    },
 
     TransferFinalized {
         transfer_id: String,
         by_peer: bool,
+        cause: crate::CancelCause,
     },
     TransferFailed {
         transfer_id: String,
         status: Status,
     },
+    TransferRejected {
+        transfer_id: String,
+        by_peer: bool,
+        reason: String,
+    },
+    TransferExpired {
+        transfer_id: String,
+    },
     TransferDeferred {
         transfer_id: String,
         peer: String,
         status: Status,
     },
+    TransferPaused {
+        transfer_id: String,
+    },
+    TransferResumed {
+        transfer_id: String,
+    },
+    ProtocolDowngraded {
+        transfer_id: String,
+        from: i32,
+        to: i32,
+    },
+    PeerInfo {
+        transfer_id: String,
+        device_name: Option<String>,
+        app_version: Option<String>,
+    },
+    TransferSummary {
+        transfer_id: String,
+        succeeded: u32,
+        failed: u32,
+        rejected: u32,
+        total_bytes: u64,
+        duration_secs: u64,
+    },
 
     FinalizeChecksumStarted {
         transfer_id: String,
         file_id: String,
         size: u64,
+        phase: crate::ChecksumPhase,
     },
     FinalizeChecksumFinished {
         transfer_id: String,
         file_id: String,
+        phase: crate::ChecksumPhase,
     },
     FinalizeChecksumProgress {
         transfer_id: String,
         file_id: String,
         bytes_checksummed: u64,
+        phase: crate::ChecksumPhase,
     },
 
     VerifyChecksumStarted {
         transfer_id: String,
         file_id: String,
         size: u64,
+        phase: crate::ChecksumPhase,
     },
     VerifyChecksumFinished {
         transfer_id: String,
         file_id: String,
+        phase: crate::ChecksumPhase,
     },
     VerifyChecksumProgress {
         transfer_id: String,
         file_id: String,
         bytes_checksummed: u64,
+        phase: crate::ChecksumPhase,
+    },
+    FileVerified {
+        transfer_id: String,
+        file_id: String,
+        result: crate::VerifyResult,
+    },
+    FileVerifyFailed {
+        transfer_id: String,
+        file_id: String,
+        status: Status,
     },
 
     RuntimeError {
         status: crate::StatusCode,
     },
+    ListenAddressChanged {
+        addr: String,
+    },
+    StorageRecreated,
+    StorageInMemoryFallback,
 }
 
 impl From<&drop_transfer::Error> for Status {
@@ -177,6 +254,16 @@ impl From<drop_transfer::Event> for EventKind {
                 transfer_id: tx.id().to_string(),
                 files: tx.files().values().map(From::from).collect(),
             },
+            IncomingTransferCreated(tx) => Self::TransferCreated {
+                peer: tx.peer().to_string(),
+                transfer_id: tx.id().to_string(),
+                files: tx.files().values().map(CreatedFile::from_file).collect(),
+            },
+            OutgoingTransferCreated(tx) => Self::TransferCreated {
+                peer: tx.peer().to_string(),
+                transfer_id: tx.id().to_string(),
+                files: tx.files().values().map(CreatedFile::from_file).collect(),
+            },
             FileUploadStarted(tx, fid, transferred) => Self::FileStarted {
                 transfer_id: tx.id().to_string(),
                 file_id: fid.to_string(),
@@ -216,13 +303,28 @@ impl From<drop_transfer::Event> for EventKind {
                 file_id: fid.to_string(),
                 status: From::from(&status),
             },
-            IncomingTransferCanceled(tx, by_peer) => Self::TransferFinalized {
+            IncomingTransferCanceled(tx, by_peer, cause) => Self::TransferFinalized {
                 transfer_id: tx.id().to_string(),
                 by_peer,
+                cause,
             },
-            OutgoingTransferCanceled(tx, by_peer) => Self::TransferFinalized {
+            OutgoingTransferCanceled(tx, by_peer, cause) => Self::TransferFinalized {
                 transfer_id: tx.id().to_string(),
                 by_peer,
+                cause,
+            },
+            IncomingTransferRejected { transfer, reason } => Self::TransferRejected {
+                transfer_id: transfer.id().to_string(),
+                by_peer: false,
+                reason,
+            },
+            OutgoingTransferRejected { transfer, reason } => Self::TransferRejected {
+                transfer_id: transfer.id().to_string(),
+                by_peer: true,
+                reason,
+            },
+            IncomingTransferExpired(transfer) => Self::TransferExpired {
+                transfer_id: transfer.id().to_string(),
             },
             OutgoingTransferFailed(tx, status, _) => Self::TransferFailed {
                 transfer_id: tx.id().to_string(),
@@ -271,6 +373,18 @@ impl From<drop_transfer::Event> for EventKind {
                 transferred,
             },
 
+            IncomingTransferInsufficientStorage {
+                transfer_id,
+                base_dir,
+                required,
+                available,
+            } => Self::InsufficientStorage {
+                transfer_id: transfer_id.to_string(),
+                base_dir,
+                required,
+                available,
+            },
+
             FinalizeChecksumStarted {
                 transfer_id,
                 file_id,
@@ -279,6 +393,7 @@ impl From<drop_transfer::Event> for EventKind {
                 transfer_id: transfer_id.to_string(),
                 file_id: file_id.to_string(),
                 size,
+                phase: crate::ChecksumPhase::Finalize,
             },
             FinalizeChecksumFinished {
                 transfer_id,
@@ -286,6 +401,7 @@ impl From<drop_transfer::Event> for EventKind {
             } => Self::FinalizeChecksumFinished {
                 transfer_id: transfer_id.to_string(),
                 file_id: file_id.to_string(),
+                phase: crate::ChecksumPhase::Finalize,
             },
             FinalizeChecksumProgress {
                 transfer_id,
@@ -295,6 +411,7 @@ impl From<drop_transfer::Event> for EventKind {
                 transfer_id: transfer_id.to_string(),
                 file_id: file_id.to_string(),
                 bytes_checksummed: progress,
+                phase: crate::ChecksumPhase::Finalize,
             },
 
             VerifyChecksumStarted {
@@ -305,6 +422,7 @@ impl From<drop_transfer::Event> for EventKind {
                 transfer_id: transfer_id.to_string(),
                 file_id: file_id.to_string(),
                 size,
+                phase: crate::ChecksumPhase::VerifyExisting,
             },
             VerifyChecksumFinished {
                 transfer_id,
@@ -312,6 +430,7 @@ impl From<drop_transfer::Event> for EventKind {
             } => Self::VerifyChecksumFinished {
                 transfer_id: transfer_id.to_string(),
                 file_id: file_id.to_string(),
+                phase: crate::ChecksumPhase::VerifyExisting,
             },
             VerifyChecksumProgress {
                 transfer_id,
@@ -321,6 +440,7 @@ impl From<drop_transfer::Event> for EventKind {
                 transfer_id: transfer_id.to_string(),
                 file_id: file_id.to_string(),
                 bytes_checksummed: progress,
+                phase: crate::ChecksumPhase::VerifyExisting,
             },
 
             OutgoingTransferDeferred { transfer, error } => Self::TransferDeferred {
@@ -328,6 +448,48 @@ impl From<drop_transfer::Event> for EventKind {
                 peer: transfer.peer().to_string(),
                 status: Status::from(&error),
             },
+            TransferPaused { transfer_id } => Self::TransferPaused {
+                transfer_id: transfer_id.to_string(),
+            },
+            TransferResumed { transfer_id } => Self::TransferResumed {
+                transfer_id: transfer_id.to_string(),
+            },
+            ListenAddressChanged { addr } => Self::ListenAddressChanged {
+                addr: addr.to_string(),
+            },
+            ProtocolDowngraded {
+                transfer_id,
+                from,
+                to,
+            } => Self::ProtocolDowngraded {
+                transfer_id: transfer_id.to_string(),
+                from,
+                to,
+            },
+            PeerInfo {
+                transfer_id,
+                device_name,
+                app_version,
+            } => Self::PeerInfo {
+                transfer_id: transfer_id.to_string(),
+                device_name,
+                app_version,
+            },
+            TransferSummary {
+                transfer_id,
+                succeeded,
+                failed,
+                rejected,
+                total_bytes,
+                duration_secs,
+            } => Self::TransferSummary {
+                transfer_id: transfer_id.to_string(),
+                succeeded,
+                failed,
+                rejected,
+                total_bytes,
+                duration_secs,
+            },
             FileDownloadPending {
                 transfer_id,
                 file_id,
@@ -336,6 +498,15 @@ impl From<drop_transfer::Event> for EventKind {
                 transfer_id: transfer_id.to_string(),
                 file_id: file_id.to_string(),
             },
+            FileDownloadMissing {
+                transfer_id,
+                file_id,
+                final_path,
+            } => Self::FileMissing {
+                transfer_id: transfer_id.to_string(),
+                file_id: file_id.to_string(),
+                final_path: final_path.0.to_string_lossy().to_string(),
+            },
         }
     }
 }
@@ -367,3 +538,14 @@ impl From<&drop_transfer::FileToRecv> for ReceivedFile {
         }
     }
 }
+
+impl CreatedFile {
+    fn from_file(value: &impl File) -> Self {
+        Self {
+            id: value.id().to_string(),
+            path: value.subpath().to_string(),
+            size: value.size(),
+            mime_type: value.mime_type().to_string(),
+        }
+    }
+}
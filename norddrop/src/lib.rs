@@ -11,7 +11,12 @@ mod uni;
 uniffi::include_scaffolding!("norddrop");
 
 pub use config::*;
+pub use drop_config::{
+    ExistingFilePolicy, FilenameSanitization, LogRedaction, PeerResolution, SourceModifiedPolicy,
+};
 pub use drop_core::Status as StatusCode;
+pub use drop_storage::{CancelCause, TransferSort};
+pub use drop_transfer::{ChecksumPhase, TransferStatus, VerifyResult};
 pub use dump::*;
 pub use event::*;
 pub use types::*;
@@ -11,6 +11,36 @@ pub enum TransferDescriptor {
         content_uri: String,
         fd: Option<i32>,
     },
+    Memory {
+        filename: String,
+        content: Vec<u8>,
+        mime_type: Option<String>,
+    },
+}
+
+pub struct PendingTransfer {
+    pub transfer_id: String,
+    pub file_ids: Vec<String>,
+}
+
+pub struct ServiceMetrics {
+    pub active_incoming: u32,
+    pub active_outgoing: u32,
+    pub bytes_transferred: u64,
+    pub upload_rate: u64,
+    pub download_rate: u64,
+    pub connected_peers: u32,
+    pub rate_limited_requests: u64,
+}
+
+pub struct ImportedIncoming {
+    pub transfer_id: String,
+    pub peer: String,
+    pub file_id: String,
+    pub relative_path: String,
+    pub size: u64,
+    pub final_path: String,
+    pub checksum: Option<Vec<u8>>,
 }
 
 #[derive(Clone, Copy, Debug)]
@@ -1,4 +1,4 @@
-use std::sync::Mutex;
+use std::sync::{Arc, Mutex};
 
 use drop_auth::{PublicKey, SecretKey, PUBLIC_KEY_LENGTH};
 
@@ -24,13 +24,21 @@ pub trait FdResolver: Send + Sync {
     fn on_fd(&self, content_uri: String) -> Option<i32>;
 }
 
+pub trait ContentUriRefresher: Send + Sync {
+    fn on_stale_uri(&self, content_uri: String) -> Option<String>;
+}
+
+pub trait MimeResolver: Send + Sync {
+    fn on_mime(&self, uri: String) -> Option<String>;
+}
+
 pub struct NordDrop {
     dev: Mutex<NordDropFFI>,
 }
 
 impl NordDrop {
     pub fn new(
-        event_callback: Box<dyn EventCallback>,
+        event_callback: Option<Box<dyn EventCallback>>,
         key_store: Box<dyn KeyStore>,
         logger: Box<dyn Logger>,
     ) -> Result<Self> {
@@ -43,7 +51,8 @@ impl NordDrop {
         let privkey = SecretKey::from(privkey);
 
         let dev = NordDropFFI::new(
-            move |ev| event_callback.on_event(ev),
+            event_callback
+                .map(|cb| Arc::new(move |ev| cb.on_event(ev)) as Arc<dyn Fn(Event) + Send + Sync>),
             move |peer_ip| {
                 let pubkey = key_store.on_pubkey(peer_ip.to_string())?;
                 let pubkey: [u8; PUBLIC_KEY_LENGTH] = pubkey.try_into().ok()?;
@@ -73,6 +82,32 @@ impl NordDrop {
         Ok(())
     }
 
+    #[cfg(not(unix))]
+    pub fn set_content_uri_refresher(&self, refresher: Box<dyn ContentUriRefresher>) -> Result<()> {
+        Err(crate::LibdropError::Unknown)
+    }
+
+    #[cfg(unix)]
+    pub fn set_content_uri_refresher(&self, refresher: Box<dyn ContentUriRefresher>) -> Result<()> {
+        self.dev
+            .lock()
+            .expect("Poisoned lock")
+            .set_content_uri_refresher_callback(move |uri| {
+                refresher.on_stale_uri(uri.to_string())
+            })?;
+
+        Ok(())
+    }
+
+    pub fn set_mime_resolver(&self, resolver: Box<dyn MimeResolver>) -> Result<()> {
+        self.dev
+            .lock()
+            .expect("Poisoned lock")
+            .set_mime_resolver_callback(move |uri| resolver.on_mime(uri.to_string()))?;
+
+        Ok(())
+    }
+
     pub fn start(&self, addr: &str, config: crate::Config) -> Result<()> {
         self.dev
             .lock()
@@ -84,6 +119,10 @@ impl NordDrop {
         self.dev.lock().expect("Poisoned lock").stop()
     }
 
+    pub fn poll_events(&self) -> Result<Vec<Event>> {
+        self.dev.lock().expect("Poisoned lock").poll_events()
+    }
+
     pub fn purge_transfers(&self, transfer_ids: &[String]) -> Result<()> {
         self.dev
             .lock()
@@ -91,6 +130,13 @@ impl NordDrop {
             .purge_transfers(transfer_ids)
     }
 
+    pub fn migrate_storage(&self, old_path: &str, new_path: &str) -> Result<()> {
+        self.dev
+            .lock()
+            .expect("Poisoned lock")
+            .migrate_storage(old_path, new_path)
+    }
+
     pub fn purge_transfers_until(&self, until: i64) -> Result<()> {
         // The `device` function takes in seconds as an argument and this function takes
         // in ms
@@ -100,19 +146,48 @@ impl NordDrop {
             .purge_transfers_until(until / 100)
     }
 
-    pub fn transfers_since(&self, since: i64) -> Result<Vec<TransferInfo>> {
+    pub fn transfers_since(
+        &self,
+        since: i64,
+        sort: Option<crate::TransferSort>,
+    ) -> Result<Vec<TransferInfo>> {
         // The `device` function takes in seconds as an argument and this function takes
         // in ms
         let infos = self
             .dev
             .lock()
             .expect("Poisoned lock")
-            .transfers_since(since / 100)?;
+            .transfers_since(since / 100, sort.unwrap_or_default())?;
 
         let xfers = infos.into_iter().map(TransferInfo::from).collect();
         Ok(xfers)
     }
 
+    pub fn import_completed_incoming(&self, records: Vec<crate::ImportedIncoming>) -> Result<u64> {
+        let records = records
+            .into_iter()
+            .map(|record| {
+                Ok(drop_storage::ImportedIncoming {
+                    transfer_id: record
+                        .transfer_id
+                        .parse()
+                        .map_err(|_| crate::LibdropError::InvalidString)?,
+                    peer: record.peer,
+                    file_id: record.file_id,
+                    relative_path: record.relative_path,
+                    size: record.size as _,
+                    final_path: record.final_path,
+                    checksum: record.checksum,
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        self.dev
+            .lock()
+            .expect("Poisoned lock")
+            .import_completed_incoming(&records)
+    }
+
     pub fn new_transfer(&self, peer: &str, descriptors: &[TransferDescriptor]) -> Result<String> {
         let transfer_id = self
             .dev
@@ -124,10 +199,53 @@ impl NordDrop {
     }
 
     pub fn finalize_transfer(&self, transfer_id: &str) -> Result<()> {
+        self.finalize_transfer_with_cause(transfer_id, crate::CancelCause::User)
+    }
+
+    pub fn finalize_transfer_with_cause(
+        &self,
+        transfer_id: &str,
+        cause: crate::CancelCause,
+    ) -> Result<()> {
         self.dev.lock().expect("Poisoned lock").cancel_transfer(
             transfer_id
                 .parse()
                 .map_err(|_| crate::LibdropError::InvalidString)?,
+            cause,
+        )
+    }
+
+    pub fn pending_incoming_files(&self) -> Result<Vec<crate::PendingTransfer>> {
+        self.dev
+            .lock()
+            .expect("Poisoned lock")
+            .pending_incoming_files()
+    }
+
+    pub fn metrics(&self) -> Result<crate::ServiceMetrics> {
+        self.dev.lock().expect("Poisoned lock").metrics()
+    }
+
+    pub fn cancel_peer(&self, peer: &str) -> Result<Vec<String>> {
+        let transfers = self.dev.lock().expect("Poisoned lock").cancel_peer(peer)?;
+
+        Ok(transfers.into_iter().map(|id| id.to_string()).collect())
+    }
+
+    pub fn redirect_incoming(&self, transfer_id: &str, base_dir: &str) -> Result<Vec<String>> {
+        self.dev.lock().expect("Poisoned lock").redirect_incoming(
+            transfer_id
+                .parse()
+                .map_err(|_| crate::LibdropError::InvalidString)?,
+            base_dir,
+        )
+    }
+
+    pub fn reconnect_transfer(&self, transfer_id: &str) -> Result<()> {
+        self.dev.lock().expect("Poisoned lock").reconnect_transfer(
+            transfer_id
+                .parse()
+                .map_err(|_| crate::LibdropError::InvalidString)?,
         )
     }
 
@@ -153,6 +271,106 @@ impl NordDrop {
         )
     }
 
+    pub fn download_files(
+        &self,
+        transfer_id: &str,
+        file_ids: &[String],
+        destination: &str,
+    ) -> Result<()> {
+        self.dev.lock().expect("Poisoned lock").download_files(
+            transfer_id
+                .parse()
+                .map_err(|_| crate::LibdropError::InvalidString)?,
+            file_ids.to_vec(),
+            destination.to_string(),
+        )
+    }
+
+    pub fn download_only(
+        &self,
+        transfer_id: &str,
+        file_ids: &[String],
+        destination: &str,
+    ) -> Result<()> {
+        self.dev.lock().expect("Poisoned lock").download_only(
+            transfer_id
+                .parse()
+                .map_err(|_| crate::LibdropError::InvalidString)?,
+            file_ids.to_vec(),
+            destination.to_string(),
+        )
+    }
+
+    pub fn download_all(&self, transfer_id: &str, destination: &str) -> Result<()> {
+        self.dev.lock().expect("Poisoned lock").download_all(
+            transfer_id
+                .parse()
+                .map_err(|_| crate::LibdropError::InvalidString)?,
+            destination.to_string(),
+        )
+    }
+
+    pub fn download_subtree(
+        &self,
+        transfer_id: &str,
+        prefix: &str,
+        destination: &str,
+    ) -> Result<()> {
+        self.dev.lock().expect("Poisoned lock").download_subtree(
+            transfer_id
+                .parse()
+                .map_err(|_| crate::LibdropError::InvalidString)?,
+            prefix.to_string(),
+            destination.to_string(),
+        )
+    }
+
+    pub fn verify_file(&self, transfer_id: &str, file_id: &str) -> Result<()> {
+        self.dev.lock().expect("Poisoned lock").verify_file(
+            transfer_id
+                .parse()
+                .map_err(|_| crate::LibdropError::InvalidString)?,
+            file_id.to_string(),
+        )
+    }
+
+    pub fn files_awaiting_checksum(&self, transfer_id: &str) -> Result<Vec<String>> {
+        self.dev
+            .lock()
+            .expect("Poisoned lock")
+            .files_awaiting_checksum(
+                transfer_id
+                    .parse()
+                    .map_err(|_| crate::LibdropError::InvalidString)?,
+            )
+    }
+
+    pub fn file_checksum(&self, transfer_id: &str, file_id: &str) -> Result<Option<String>> {
+        self.dev.lock().expect("Poisoned lock").file_checksum(
+            transfer_id
+                .parse()
+                .map_err(|_| crate::LibdropError::InvalidString)?,
+            file_id.to_string(),
+        )
+    }
+
+    pub fn request_checksum(&self, transfer_id: &str, file_id: &str) -> Result<Vec<u8>> {
+        self.dev.lock().expect("Poisoned lock").request_checksum(
+            transfer_id
+                .parse()
+                .map_err(|_| crate::LibdropError::InvalidString)?,
+            file_id.to_string(),
+        )
+    }
+
+    pub fn transfer_status(&self, transfer_id: &str) -> Result<crate::TransferStatus> {
+        self.dev.lock().expect("Poisoned lock").transfer_status(
+            transfer_id
+                .parse()
+                .map_err(|_| crate::LibdropError::InvalidString)?,
+        )
+    }
+
     pub fn reject_file(&self, transfer_id: &str, file_id: &str) -> Result<()> {
         self.dev.lock().expect("Poisoned lock").reject_file(
             transfer_id
@@ -162,9 +380,42 @@ impl NordDrop {
         )
     }
 
+    pub fn reject_transfer(&self, transfer_id: &str, reason: &str) -> Result<()> {
+        self.dev.lock().expect("Poisoned lock").reject_transfer(
+            transfer_id
+                .parse()
+                .map_err(|_| crate::LibdropError::InvalidString)?,
+            reason.to_string(),
+        )
+    }
+
     pub fn network_refresh(&self) -> Result<()> {
         self.dev.lock().expect("Poisoned lock").network_refresh()
     }
+
+    pub fn rebind(&self, addr: &str) -> Result<()> {
+        self.dev.lock().expect("Poisoned lock").rebind(addr)
+    }
+
+    pub fn effective_config(&self) -> Result<crate::Config> {
+        self.dev
+            .lock()
+            .expect("Poisoned lock")
+            .effective_config()
+            .map(Into::into)
+    }
+
+    pub fn retry_all_deferred(&self) -> Result<u32> {
+        self.dev.lock().expect("Poisoned lock").retry_all_deferred()
+    }
+
+    pub fn pause_all(&self) -> Result<()> {
+        self.dev.lock().expect("Poisoned lock").pause_all()
+    }
+
+    pub fn resume_all(&self) -> Result<()> {
+        self.dev.lock().expect("Poisoned lock").resume_all()
+    }
 }
 
 #[cfg(any(target_os = "android", target_os = "linux"))]
@@ -186,6 +186,39 @@ class Throttled(Event):
         return f"Throttled(transfer={print_uuid(self._uuid_slot)}, file={self._file}, transfered={self._transferred})"
 
 
+class InsufficientStorage(Event):
+    def __init__(
+        self,
+        uuid_slot: int,
+        base_dir: str,
+        required: int,
+        available: typing.Optional[int] = None,
+    ):
+        self._uuid_slot = uuid_slot
+        self._base_dir = base_dir
+        self._required = required
+        self._available = available
+
+    def __eq__(self, rhs):
+        if not isinstance(rhs, InsufficientStorage):
+            return False
+        if self._uuid_slot != rhs._uuid_slot:
+            return False
+        if self._base_dir != rhs._base_dir:
+            return False
+        if self._required != rhs._required:
+            return False
+
+        if self._available is not None and rhs._available is not None:
+            if self._available != rhs._available:
+                return False
+
+        return True
+
+    def __str__(self):
+        return f"InsufficientStorage(transfer={print_uuid(self._uuid_slot)}, base_dir={self._base_dir}, required={self._required}, available={self._available})"
+
+
 class FinishTransferCanceled(Event):
     def __init__(self, uuid_slot: int, by_peer: bool):
         self._uuid_slot = uuid_slot
@@ -205,6 +238,98 @@ class FinishTransferCanceled(Event):
         return f"FinishTransferCanceled(transfer={print_uuid(self._uuid_slot)}, by_peer={self._by_peer})"
 
 
+class TransferRejected(Event):
+    def __init__(self, uuid_slot: int, by_peer: bool, reason: str):
+        self._uuid_slot = uuid_slot
+        self._by_peer = by_peer
+        self._reason = reason
+
+    def __eq__(self, rhs):
+        if not isinstance(rhs, TransferRejected):
+            return False
+        if self._uuid_slot != rhs._uuid_slot:
+            return False
+        if self._by_peer != rhs._by_peer:
+            return False
+        if self._reason != rhs._reason:
+            return False
+
+        return True
+
+    def __str__(self):
+        return f"TransferRejected(transfer={print_uuid(self._uuid_slot)}, by_peer={self._by_peer}, reason={self._reason})"
+
+
+class TransferExpired(Event):
+    def __init__(self, uuid_slot: int):
+        self._uuid_slot = uuid_slot
+
+    def __eq__(self, rhs):
+        if not isinstance(rhs, TransferExpired):
+            return False
+        if self._uuid_slot != rhs._uuid_slot:
+            return False
+
+        return True
+
+    def __str__(self):
+        return f"TransferExpired(transfer={print_uuid(self._uuid_slot)})"
+
+
+class TransferPaused(Event):
+    def __init__(self, uuid_slot: int):
+        self._uuid_slot = uuid_slot
+
+    def __eq__(self, rhs):
+        if not isinstance(rhs, TransferPaused):
+            return False
+        if self._uuid_slot != rhs._uuid_slot:
+            return False
+
+        return True
+
+    def __str__(self):
+        return f"TransferPaused(transfer={print_uuid(self._uuid_slot)})"
+
+
+class TransferResumed(Event):
+    def __init__(self, uuid_slot: int):
+        self._uuid_slot = uuid_slot
+
+    def __eq__(self, rhs):
+        if not isinstance(rhs, TransferResumed):
+            return False
+        if self._uuid_slot != rhs._uuid_slot:
+            return False
+
+        return True
+
+    def __str__(self):
+        return f"TransferResumed(transfer={print_uuid(self._uuid_slot)})"
+
+
+class ProtocolDowngraded(Event):
+    def __init__(self, uuid_slot: int, from_version: int, to_version: int):
+        self._uuid_slot = uuid_slot
+        self._from = from_version
+        self._to = to_version
+
+    def __eq__(self, rhs):
+        if not isinstance(rhs, ProtocolDowngraded):
+            return False
+        if self._uuid_slot != rhs._uuid_slot:
+            return False
+        if self._from != rhs._from:
+            return False
+        if self._to != rhs._to:
+            return False
+
+        return True
+
+    def __str__(self):
+        return f"ProtocolDowngraded(transfer={print_uuid(self._uuid_slot)}, from={self._from}, to={self._to})"
+
+
 class FinishFileUploaded(Event):
     def __init__(self, uuid_slot: int, file: str):
         self._uuid_slot = uuid_slot
@@ -246,6 +371,81 @@ class FinishFileDownloaded(Event):
         return f"FinishFileDownloaded(transfer={print_uuid(self._uuid_slot)}, file={self._file}, final_path={self._final_path})"
 
 
+class FileMissing(Event):
+    def __init__(self, uuid_slot: int, file: str, final_path: str):
+        self._uuid_slot = uuid_slot
+        self._file = file
+        self._final_path = final_path
+
+    def __eq__(self, rhs):
+        if not isinstance(rhs, FileMissing):
+            return False
+        if self._uuid_slot != rhs._uuid_slot:
+            return False
+        if self._file != rhs._file:
+            return False
+        if self._final_path != rhs._final_path:
+            return False
+
+        return True
+
+    def __str__(self):
+        return f"FileMissing(transfer={print_uuid(self._uuid_slot)}, file={self._file}, final_path={self._final_path})"
+
+
+class FileVerified(Event):
+    def __init__(self, uuid_slot: int, file: str, result):
+        self._uuid_slot = uuid_slot
+        self._file = file
+        self._result = result
+
+    def __eq__(self, rhs):
+        if not isinstance(rhs, FileVerified):
+            return False
+        if self._uuid_slot != rhs._uuid_slot:
+            return False
+        if self._file != rhs._file:
+            return False
+        if self._result != rhs._result:
+            return False
+
+        return True
+
+    def __str__(self):
+        return f"FileVerified(transfer={print_uuid(self._uuid_slot)}, file={self._file}, result={self._result})"
+
+
+class FileVerifyFailed(Event):
+    def __init__(
+        self,
+        uuid_slot: int,
+        file: str,
+        status: int,
+        os_err: typing.Optional[int] = None,
+    ):
+        self._uuid_slot = uuid_slot
+        self._file = file
+        self._status = status
+        self._os_err = os_err
+
+    def __eq__(self, rhs):
+        if not isinstance(rhs, FileVerifyFailed):
+            return False
+        if self._uuid_slot != rhs._uuid_slot:
+            return False
+        if self._file != rhs._file:
+            return False
+        if self._status != rhs._status:
+            return False
+        if self._os_err != rhs._os_err:
+            return False
+
+        return True
+
+    def __str__(self):
+        return f"FileVerifyFailed(transfer={print_uuid(self._uuid_slot)}, file={self._file}, status={self._status}, os_err={self._os_err})"
+
+
 class FinishFileRejected(Event):
     def __init__(self, uuid_slot: int, file: str, by_peer: bool):
         self._uuid_slot = uuid_slot
@@ -499,6 +699,43 @@ class RuntimeError(Event):
         return f"RuntimeError(status={self._status})"
 
 
+class ListenAddressChanged(Event):
+    def __init__(self, addr: str):
+        self._addr = addr
+
+    def __eq__(self, rhs):
+        if not isinstance(rhs, ListenAddressChanged):
+            return False
+        if self._addr != rhs._addr:
+            return False
+        return True
+
+    def __str__(self):
+        return f"ListenAddressChanged(addr={self._addr})"
+
+
+class StorageRecreated(Event):
+    def __init__(self):
+        pass
+
+    def __eq__(self, rhs):
+        return isinstance(rhs, StorageRecreated)
+
+    def __str__(self):
+        return "StorageRecreated()"
+
+
+class StorageInMemoryFallback(Event):
+    def __init__(self):
+        pass
+
+    def __eq__(self, rhs):
+        return isinstance(rhs, StorageInMemoryFallback)
+
+    def __str__(self):
+        return "StorageInMemoryFallback()"
+
+
 class TransferDeferred(Event):
     def __init__(
         self,
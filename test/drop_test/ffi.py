@@ -67,6 +67,9 @@ class EventQueue(norddrop.EventCallback):
 
     def on_event(self, event: norddrop.Event):
         event = new_event(event)
+        if event is None:
+            return
+
         if DEBUG_PRINT_EVENT:
             tprint(bcolors.HEADER + "--- event: ", event, bcolors.ENDC, flush=True)
 
@@ -299,10 +302,15 @@ class Drop:
     def reject_transfer_file(self, uuid: str, fid: str):
         self._instance.reject_file(uuid, fid)
 
+    def reject_transfer(self, uuid: str, reason: str):
+        self._instance.reject_transfer(uuid, reason)
+
     def get_transfers_since(
         self, since_timestamp: int
     ) -> typing.List[norddrop.TransferInfo]:
-        return self._instance.transfers_since(since_timestamp * 100)
+        return self._instance.transfers_since(
+            since_timestamp * 100, norddrop.TransferSort.CREATED_ASC
+        )
 
     def network_refresh(self):
         self._instance.network_refresh()
@@ -331,7 +339,36 @@ class Drop:
             storage_path=dbpath,
             checksum_events_size_threshold=checksum_events_size_threshold,
             checksum_events_granularity=checksum_events_granularity,
+            checksum_chunk_size=None,
+            progress_events_granularity=None,
+            progress_events_interval_ms=None,
             connection_retries=1,
+            storage_busy_timeout_ms=None,
+            connect_timeout_ms=None,
+            ws_send_timeout_ms=None,
+            ws_recv_timeout_ms=None,
+            ping_interval_ms=None,
+            ping_jitter=None,
+            truncate_long_filenames=None,
+            filename_sanitization=None,
+            flatten_incoming=None,
+            max_accept_bytes=None,
+            peer_resolution=None,
+            max_transfers_per_peer=None,
+            allow_legacy_protocols=None,
+            max_file_retries=None,
+            quarantine_origin=None,
+            incoming_offer_ttl_ms=None,
+            device_name=None,
+            app_version=None,
+            preallocate_downloads=None,
+            existing_file_policy=None,
+            max_path_components=None,
+            on_source_modified=None,
+            redownload_missing=None,
+            always_send_checksums=None,
+            log_redaction=None,
+            encrypt_contents=None,
         )
 
         self._instance.start(addr, cfg)
@@ -366,10 +403,23 @@ class IncomingRequest:
         return f"IncomingRequest(txid={self._txid}, peer={self._peer}, sender={self._sender}, data={repr(self._data)})"
 
 
-def new_event(ev: norddrop.Event) -> event.Event:
-    # Transfer slot correction
+def new_event(ev: norddrop.Event) -> typing.Optional[event.Event]:
     ev = ev.kind
 
+    # The scenarios track transfers by the request events, skip the manifest one
+    if ev.is_transfer_created():
+        return None
+
+    # The summary follows every transfer termination, the scenarios do not track it
+    if ev.is_transfer_summary():
+        return None
+
+    # Emitted on every connection, the scenarios do not track it
+    if ev.is_peer_info():
+        return None
+
+    # Transfer slot correction
+
     transfer_slot: int = 0
     if ev.is_request_received():
         with event.UUIDS_LOCK:
@@ -411,6 +461,12 @@ def new_event(ev: norddrop.Event) -> event.Event:
         return event.Throttled(transfer_slot, ev.file_id, ev.transferred)
     elif ev.is_file_pending():
         return event.Pending(transfer_slot, ev.file_id)
+    elif ev.is_file_missing():
+        return event.FileMissing(transfer_slot, ev.file_id, ev.final_path)
+    elif ev.is_insufficient_storage():
+        return event.InsufficientStorage(
+            transfer_slot, ev.base_dir, ev.required, ev.available
+        )
 
     elif ev.is_transfer_finalized():
         return event.FinishTransferCanceled(transfer_slot, ev.by_peer)
@@ -418,10 +474,20 @@ def new_event(ev: norddrop.Event) -> event.Event:
         return event.FinishFailedTransfer(
             transfer_slot, ev.status.status, ev.status.os_error_code
         )
+    elif ev.is_transfer_rejected():
+        return event.TransferRejected(transfer_slot, ev.by_peer, ev.reason)
+    elif ev.is_transfer_expired():
+        return event.TransferExpired(transfer_slot)
     elif ev.is_transfer_deferred():
         return event.TransferDeferred(
             transfer_slot, ev.peer, ev.status.status, ev.status.os_error_code
         )
+    elif ev.is_transfer_paused():
+        return event.TransferPaused(transfer_slot)
+    elif ev.is_transfer_resumed():
+        return event.TransferResumed(transfer_slot)
+    elif ev.is_protocol_downgraded():
+        return event.ProtocolDowngraded(transfer_slot, ev._from, ev.to)
 
     elif ev.is_finalize_checksum_progress():
         return event.FinalizeChecksumProgress(
@@ -440,9 +506,21 @@ def new_event(ev: norddrop.Event) -> event.Event:
         return event.VerifyChecksumStarted(transfer_slot, ev.file_id, ev.size)
     elif ev.is_verify_checksum_finished():
         return event.VerifyChecksumFinished(transfer_slot, ev.file_id)
+    elif ev.is_file_verified():
+        return event.FileVerified(transfer_slot, ev.file_id, ev.result)
+    elif ev.is_file_verify_failed():
+        return event.FileVerifyFailed(
+            transfer_slot, ev.file_id, ev.status.status, ev.status.os_error_code
+        )
 
     elif ev.is_runtime_error():
         return event.RuntimeError(ev.status)
+    elif ev.is_listen_address_changed():
+        return event.ListenAddressChanged(ev.addr)
+    elif ev.is_storage_recreated():
+        return event.StorageRecreated()
+    elif ev.is_storage_in_memory_fallback():
+        return event.StorageInMemoryFallback()
 
     else:
         raise Exception("Unknown event type")
@@ -12162,6 +12162,7 @@ scenarios = [
                         dbpath="/tmp/db/26-1-corrupted.sqlite",
                     ),
                     action.Wait(event.RuntimeError(norddrop.StatusCode.DB_LOST)),
+                    action.Wait(event.StorageRecreated()),
                     action.NoEvent(),
                     action.Stop(),
                     action.AssertMooseEvents(